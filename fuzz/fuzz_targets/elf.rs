@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rvirt::elf::Elf64;
+use rvirt::guestos;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(elf) = Elf64::parse(data) {
+        for ph in elf.program_headers() {
+            let _ = elf.segment_data(&ph);
+        }
+        let _ = elf.check_extensions(0);
+    }
+
+    // Looks through the notes and symbols of the image.
+    let _ = guestos::detect(None, data);
+});
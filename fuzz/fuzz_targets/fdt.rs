@@ -0,0 +1,33 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rvirt::fdt::{self, Fdt, GuestFdtConfig};
+
+fuzz_target!(|data: &[u8]| {
+    let mut blob = data.to_vec();
+    if let Ok(mut fdt) = Fdt::from_slice(&mut blob) {
+        let _ = fdt.parse();
+    }
+
+    // Guest device trees are built from the host's tree, which goes through the same checks.
+    let config = GuestFdtConfig {
+        guestid: 1,
+        overlay: None,
+        memory: &[(0x80000000, 0x10000000)],
+        numa_distances: &[],
+        reservations: &[],
+        isa_extensions: &["sstc"],
+        cbo_block_sizes: (None, None),
+        kernel_base: None,
+        rng_seed: None,
+        kaslr_seed: None,
+        info_page: Some(0x10100000),
+        test_device: Some(0x100000),
+        debug_log: None,
+        rtc: None,
+        harts: 1,
+        allowed_devices: &[],
+        env: &[],
+    };
+    let mut output = vec![0; 64 << 10];
+    let _ = fdt::build_guest_fdt(data, &config, &mut output);
+});
@@ -0,0 +1,135 @@
+//! Host support for the Advanced Interrupt Architecture.
+//!
+//! QEMU's virt machine started with `aia=aplic` or `aia=aplic-imsic` replaces the PLIC with an
+//! APLIC, which either signals harts directly through per-hart interrupt delivery controls (IDCs)
+//! or forwards interrupts as MSIs to each hart's IMSIC. Only the supervisor level interrupt domain
+//! is used, so firmware must already have delegated sources to it. Guests are unaffected and keep
+//! seeing an emulated PLIC.
+
+use crate::pmap::pa2va;
+use crate::riscv::csr;
+
+const DOMAINCFG: u64 = 0x0000;
+const SETIPNUM: u64 = 0x1cdc;
+const SETIE: u64 = 0x1e00;
+const SETIENUM: u64 = 0x1edc;
+const CLRIENUM: u64 = 0x1fdc;
+const TARGET: u64 = 0x3000;
+const IDC: u64 = 0x4000;
+
+const IDC_SIZE: u64 = 32;
+const IDC_IDELIVERY: u64 = 0x00;
+const IDC_ITHRESHOLD: u64 = 0x08;
+const IDC_CLAIMI: u64 = 0x1c;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+const SOURCECFG_LEVEL_HIGH: u32 = 6;
+const TARGET_HART_INDEX_SHIFT: u32 = 18;
+
+const ISELECT_EIDELIVERY: u64 = 0x70;
+const ISELECT_EITHRESHOLD: u64 = 0x72;
+const ISELECT_EIE0: u64 = 0xc0;
+
+#[derive(Copy, Clone)]
+pub struct Aplic {
+    base: u64,
+    msi: bool,
+}
+
+impl Aplic {
+    /// `msi` selects whether interrupts are forwarded to IMSICs rather than delivered directly.
+    pub const fn new(base: u64, msi: bool) -> Self {
+        Self { base, msi }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { core::ptr::read_volatile(pa2va(self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { core::ptr::write_volatile(pa2va(self.base + offset) as *mut u32, value) }
+    }
+
+    /// Configure sources 1 through `sources - 1` as level triggered and disabled, then enable the
+    /// domain. Writes to sources that weren't delegated to this domain are ignored by the hardware.
+    pub fn init(&self, sources: u32) {
+        self.write(DOMAINCFG, 0);
+        for irq in 1..sources {
+            self.write(4 * irq as u64, SOURCECFG_LEVEL_HIGH);
+            self.write(CLRIENUM, irq);
+        }
+        self.write(DOMAINCFG, DOMAINCFG_IE | if self.msi { DOMAINCFG_DM } else { 0 });
+    }
+
+    /// Prepare the IDC of a hart to take interrupts. Nothing is needed in MSI mode, where each hart
+    /// instead calls `imsic_init_hart`.
+    pub fn init_hart(&self, hart_index: u64) {
+        if !self.msi {
+            let idc = IDC + IDC_SIZE * hart_index;
+            self.write(idc + IDC_ITHRESHOLD, 0);
+            self.write(idc + IDC_IDELIVERY, 1);
+        }
+    }
+
+    /// Send interrupts from `irq` to the hart with the given index, and enable or disable it.
+    /// Forwarded interrupts use the source number as their MSI identity.
+    pub fn route(&self, irq: u32, hart_index: u64, enabled: bool) {
+        let target = ((hart_index as u32) << TARGET_HART_INDEX_SHIFT) | if self.msi { irq } else { 1 };
+        self.write(TARGET + 4 * irq as u64, target);
+        self.write(if enabled { SETIENUM } else { CLRIENUM }, irq);
+    }
+
+    /// Send every source below `sources` that targets the hart with index `from` to `to` instead,
+    /// leaving it enabled or disabled as it was. In MSI mode enabled sources are also made pending
+    /// again, since any interrupt already forwarded to the old hart's IMSIC stays there.
+    pub fn retarget(&self, sources: u32, from: u64, to: u64) {
+        for irq in 1..sources {
+            let target = self.read(TARGET + 4 * irq as u64);
+            if (target >> TARGET_HART_INDEX_SHIFT) as u64 != from {
+                continue;
+            }
+            let low = target & ((1 << TARGET_HART_INDEX_SHIFT) - 1);
+            self.write(TARGET + 4 * irq as u64, (to as u32) << TARGET_HART_INDEX_SHIFT | low);
+            if self.msi && self.read(SETIE + 4 * (irq / 32) as u64) & 1 << (irq % 32) != 0 {
+                self.write(SETIPNUM, irq);
+            }
+        }
+    }
+
+    /// Claim the highest priority interrupt pending for a hart. Returns zero if there is none.
+    pub fn claim(&self, hart_index: u64) -> u32 {
+        if self.msi {
+            let irq = imsic_claim();
+            // Sources stay asserted until the guest services the device, so have the APLIC resend
+            // the interrupt if the line is still high. This matches how claim and complete behave
+            // on the PLIC.
+            if irq != 0 {
+                self.write(SETIPNUM, irq);
+            }
+            irq
+        } else {
+            self.read(IDC + IDC_SIZE * hart_index + IDC_CLAIMI) >> 16
+        }
+    }
+}
+
+/// Enable interrupt identities 1 through 63 in the supervisor interrupt file of the current hart's
+/// IMSIC. Every hart has to do this for itself since the file is accessed through CSRs.
+pub fn imsic_init_hart() {
+    unsafe {
+        csrw!(siselect, ISELECT_EIDELIVERY);
+        csrw!(sireg, 1);
+        csrw!(siselect, ISELECT_EITHRESHOLD);
+        csrw!(sireg, 0);
+        csrw!(siselect, ISELECT_EIE0);
+        csrw!(sireg, !1);
+    }
+}
+
+/// Claim the highest priority pending identity of the current hart's interrupt file, or zero.
+fn imsic_claim() -> u32 {
+    let topei: u64;
+    unsafe { asm!("csrrw $0, $1, zero" : "=r"(topei) : "i"(csr::stopei) :: "volatile") };
+    (topei >> 16) as u32
+}
@@ -1,21 +1,21 @@
 use crate::context::Context;
-use crate::memory_region::MemoryRegion;
 use crate::riscv::bits;
 use crate::pmap;
 
-#[allow(unused)]
-pub unsafe fn print_guest_backtrace(guest_memory: &MemoryRegion, state: &mut Context, pc: u64) {
-    println!(" {:x}", pc);
+/// Print the guest's call stack, starting from `pc`, by following the chain of frame pointers.
+/// Addresses are shown along with the guest kernel function they fall in, when it is known.
+pub fn print_guest_backtrace(state: &Context, pc: u64) {
+    println!(" {}", state.symbols.symbolize(pc));
 
     let mut ra = state.saved_registers.get(1);
-    let mut sp = state.saved_registers.get(2);
     let mut fp = state.saved_registers.get(8);
 
     let page_table_ppn = state.csrs.satp & bits::SATP_PPN;
+    let guest_memory = &state.guest_memory;
 
     let mut old_fp = 0;
     while old_fp != fp {
-        println!(" {:x}", ra);
+        println!(" {}", state.symbols.symbolize(ra));
 
         ra = match fp.checked_sub(8).and_then(|a| pmap::read64(guest_memory, page_table_ppn, a)) {
             Some(v) => v,
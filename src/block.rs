@@ -0,0 +1,82 @@
+//! Storage addressed in sectors, as the qcow2 and overlay layers see it.
+//!
+//! Host virtio disks (see drivers/blk.rs) implement `BlockDevice`, and so do the layers stacked on
+//! top of them, so that each can sit on any of the others.
+
+use crate::error::Result;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Something that stores data in 512 byte sectors.
+pub trait BlockDevice {
+    /// Size of the device, in sectors.
+    fn sectors(&self) -> u64;
+    /// Fill `buf`, whose length must be a multiple of the sector size, starting at `sector`.
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()>;
+    /// Write `buf`, whose length must be a multiple of the sector size, starting at `sector`.
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()>;
+    /// Make every completed write durable.
+    fn flush(&mut self) -> Result<()>;
+    /// Keep every completed write from reaching stable storage after any write made later. Metadata
+    /// that points at data calls this between writing the data and writing the pointer.
+    fn barrier(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// An in-memory disk that records the writes, flushes and barriers made to it.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum Op {
+        /// A write of a number of sectors, starting at a sector.
+        Write(u64, u64),
+        Flush,
+        Barrier,
+    }
+
+    pub struct MockDisk {
+        pub data: Vec<u8>,
+        pub ops: Vec<Op>,
+    }
+
+    impl MockDisk {
+        pub fn new(sectors: u64) -> Self {
+            Self { data: vec![0; (sectors * SECTOR_SIZE) as usize], ops: Vec::new() }
+        }
+
+        fn range(&self, sector: u64, len: usize) -> core::ops::Range<usize> {
+            assert_eq!(len as u64 % SECTOR_SIZE, 0);
+            let start = (sector * SECTOR_SIZE) as usize;
+            assert!(start + len <= self.data.len());
+            start..(start + len)
+        }
+    }
+
+    impl BlockDevice for MockDisk {
+        fn sectors(&self) -> u64 {
+            self.data.len() as u64 / SECTOR_SIZE
+        }
+        fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+            let range = self.range(sector, buf.len());
+            buf.copy_from_slice(&self.data[range]);
+            Ok(())
+        }
+        fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+            let range = self.range(sector, buf.len());
+            self.data[range].copy_from_slice(buf);
+            self.ops.push(Op::Write(sector, buf.len() as u64 / SECTOR_SIZE));
+            Ok(())
+        }
+        fn flush(&mut self) -> Result<()> {
+            self.ops.push(Op::Flush);
+            Ok(())
+        }
+        fn barrier(&mut self) -> Result<()> {
+            self.ops.push(Op::Barrier);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! How far each guest's hart has got through boot, so that a guest that never starts is reported
+//! rather than just missing.
+//!
+//! The boot hart starts guests by sending their harts an IPI, which on its own says nothing about
+//! whether they got anywhere. So each hart records its progress in `SHARED_STATICS.boot_status`:
+//! `Starting` as soon as it takes the IPI, `GuestRunning` just before it enters the guest, or
+//! `Failed` with a reason if it gives up. Once every guest's hart has been sent its IPI, the boot
+//! hart waits up to `BOOT_TIMEOUT` for all of them to settle, and prints what became of any guest
+//! that didn't start: a hart still `Parked` never took its IPI, one still `Starting` got stuck or
+//! panicked before it could say why.
+
+use core::sync::atomic::Ordering;
+use crate::constants::TIMER_FREQUENCY;
+use crate::hart;
+use crate::statics::SHARED_STATICS;
+
+/// How long the boot hart waits for guests to start, in ticks of `time`. Loading a large kernel
+/// under emulation can take a while.
+const BOOT_TIMEOUT: u64 = 10 * TIMER_FREQUENCY;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BootFailure {
+    /// The guest kernel needs ISA extensions the host doesn't have.
+    IncompatibleKernel,
+    /// The guest kernel isn't a loadable ELF image, or doesn't fit.
+    KernelLoad,
+    /// The guest device tree couldn't be built or copied into guest memory.
+    DeviceTree,
+    /// The hart panicked while starting.
+    Panic,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BootStatus {
+    /// Not started, or not running a guest.
+    Parked,
+    Starting,
+    GuestRunning,
+    Failed(BootFailure),
+}
+
+impl BootStatus {
+    fn encode(self) -> u64 {
+        match self {
+            BootStatus::Parked => 0,
+            BootStatus::Starting => 1,
+            BootStatus::GuestRunning => 2,
+            BootStatus::Failed(reason) => 3 | (reason as u64) << 8,
+        }
+    }
+
+    fn decode(value: u64) -> Self {
+        match (value & 0xff, value >> 8) {
+            (1, _) => BootStatus::Starting,
+            (2, _) => BootStatus::GuestRunning,
+            (3, 0) => BootStatus::Failed(BootFailure::IncompatibleKernel),
+            (3, 1) => BootStatus::Failed(BootFailure::KernelLoad),
+            (3, 2) => BootStatus::Failed(BootFailure::DeviceTree),
+            (3, _) => BootStatus::Failed(BootFailure::Panic),
+            _ => BootStatus::Parked,
+        }
+    }
+}
+
+pub fn set(hart_index: usize, status: BootStatus) {
+    SHARED_STATICS.boot_status[hart_index].store(status.encode(), Ordering::SeqCst);
+}
+
+pub fn get(hart_index: usize) -> BootStatus {
+    BootStatus::decode(SHARED_STATICS.boot_status[hart_index].load(Ordering::SeqCst))
+}
+
+/// Record that this hart couldn't start its guest, and stop.
+pub fn fail(hart_index: usize, reason: BootFailure) -> ! {
+    set(hart_index, BootStatus::Failed(reason));
+    loop {}
+}
+
+/// Called on a panic, to mark the hart as failed if it panicked while starting its guest.
+pub fn panicked() {
+    if let Some(local) = hart::try_current() {
+        if get(local.hart_index) == BootStatus::Starting {
+            set(local.hart_index, BootStatus::Failed(BootFailure::Panic));
+        }
+    }
+}
+
+/// Wait for the harts of `guests`, given as (guestid, hartid, hart index), to start their guests
+/// or fail, and report any that didn't start.
+pub fn wait(guests: &[(u64, u64, usize)]) {
+    let settled = |index| match get(index) {
+        BootStatus::GuestRunning | BootStatus::Failed(_) => true,
+        BootStatus::Parked | BootStatus::Starting => false,
+    };
+    let deadline = csrr!(time) + BOOT_TIMEOUT;
+    while csrr!(time) < deadline && !guests.iter().all(|g| settled(g.2)) {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    let mut running = 0;
+    for &(guestid, hartid, index) in guests {
+        match get(index) {
+            BootStatus::GuestRunning => running += 1,
+            BootStatus::Parked => println!("Guest {} never booted: hart {} didn't respond to its IPI",
+                                           guestid, hartid),
+            BootStatus::Starting => println!("Guest {} never booted: hart {} is still starting after {}s",
+                                             guestid, hartid, BOOT_TIMEOUT / TIMER_FREQUENCY),
+            BootStatus::Failed(reason) => println!("Guest {} never booted: hart {} failed ({:?})",
+                                                   guestid, hartid, reason),
+        }
+    }
+    println!("{} of {} guests started", running, guests.len());
+}
@@ -0,0 +1,131 @@
+//! When each guest reached the milestones of its boot, for tuning boot latency.
+//!
+//! Each milestone is recorded the first time it happens, as host time, in
+//! `SHARED_STATICS.boot_timelines`. The monitor's `boottime` command prints a guest's timeline
+//! relative to when its hart was sent the IPI to start it. Restarting a guest starts a new timeline.
+//!
+//! The last milestone is up to the guest: calling the `boot_marker` function of the RVirt SBI
+//! extension, for instance from the first line of an init script, records that it has finished
+//! booting as far as its user is concerned.
+
+use arr_macro::arr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::constants::TIMER_FREQUENCY;
+use crate::hart;
+use crate::report::Record;
+use crate::sbi::SBI_SUCCESS;
+use crate::statics::SHARED_STATICS;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Milestone {
+    /// The hart that runs the guest was sent the IPI to start it.
+    IpiSent,
+    /// The hart took the IPI and started setting up the guest.
+    HartStarted,
+    /// The guest ran its first instruction.
+    GuestEntered,
+    FirstSbiCall,
+    FirstConsoleOutput,
+    /// The guest notified a virtio queue for the first time.
+    FirstVirtioRequest,
+    /// The guest called `boot_marker`.
+    BootMarker,
+}
+
+impl Milestone {
+    fn name(self) -> &'static str {
+        match self {
+            Milestone::IpiSent => "IPI sent",
+            Milestone::HartStarted => "hart started",
+            Milestone::GuestEntered => "guest entered",
+            Milestone::FirstSbiCall => "first SBI call",
+            Milestone::FirstConsoleOutput => "first console output",
+            Milestone::FirstVirtioRequest => "first virtio request",
+            Milestone::BootMarker => "boot marker",
+        }
+    }
+}
+
+const NUM_MILESTONES: usize = 7;
+
+const MILESTONES: [Milestone; NUM_MILESTONES] = [
+    Milestone::IpiSent, Milestone::HartStarted, Milestone::GuestEntered, Milestone::FirstSbiCall,
+    Milestone::FirstConsoleOutput, Milestone::FirstVirtioRequest, Milestone::BootMarker,
+];
+
+pub struct BootTimeline {
+    /// Host time at which each milestone was reached, or zero if it hasn't been yet.
+    times: [AtomicU64; NUM_MILESTONES],
+}
+
+impl BootTimeline {
+    pub const fn new() -> Self {
+        Self { times: arr![AtomicU64::new(0); 7] }
+    }
+}
+
+/// Record that `guestid` reached `milestone`, unless it already had.
+pub fn mark_for(guestid: u64, milestone: Milestone) {
+    let time = &SHARED_STATICS.boot_timelines[guestid as usize].times[milestone as usize];
+    if time.load(Ordering::Relaxed) == 0 {
+        time.store(csrr!(time).max(1), Ordering::Relaxed);
+    }
+}
+
+/// Record that the guest on this hart reached `milestone`, unless it already had.
+#[inline(always)]
+pub fn mark(milestone: Milestone) {
+    mark_for(hart::current().guest_index(), milestone);
+}
+
+/// Forget the timeline of the guest on this hart, before it is restarted.
+pub fn reset() {
+    let timeline = &SHARED_STATICS.boot_timelines[hart::current().guest_index() as usize];
+    for time in &timeline.times {
+        time.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Handle the `boot_marker` function of the RVirt SBI extension.
+pub fn handle_marker() -> (i64, u64) {
+    mark(Milestone::BootMarker);
+    (SBI_SUCCESS, 0)
+}
+
+/// Finish a record of the `report` command with the host time of each milestone `guestid` has
+/// reached.
+pub fn add_to_report(guestid: u64, record: &mut Record) {
+    let timeline = &SHARED_STATICS.boot_timelines[guestid as usize];
+    for &milestone in &MILESTONES {
+        match timeline.times[milestone as usize].load(Ordering::Relaxed) {
+            0 => {}
+            time => { record.number(milestone.name(), time); }
+        }
+    }
+    record.end();
+}
+
+/// Print the timeline of `guestid`, for the monitor's `boottime` command.
+pub fn report(guestid: u64) {
+    let timeline = match SHARED_STATICS.boot_timelines.get(guestid as usize) {
+        Some(timeline) => timeline,
+        None => {
+            println!("no guest {}", guestid);
+            return;
+        }
+    };
+    let start = timeline.times[Milestone::IpiSent as usize].load(Ordering::Relaxed);
+    if start == 0 {
+        println!("guest {} hasn't been started", guestid);
+        return;
+    }
+
+    let ms = |ticks: u64| ticks * 1000 / TIMER_FREQUENCY;
+    println!("guest {} started {} ms after reset", guestid, ms(start));
+    for &milestone in &MILESTONES[1..] {
+        match timeline.times[milestone as usize].load(Ordering::Relaxed) {
+            0 => println!("  {:<22} not yet", milestone.name()),
+            time => println!("  {:<22} +{} ms", milestone.name(), ms(time.saturating_sub(start))),
+        }
+    }
+}
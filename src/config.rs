@@ -0,0 +1,148 @@
+//! Settings kept on a dedicated virtio disk, so that a setup survives across runs without changing
+//! the host's device tree or rebuilding the hypervisor.
+//!
+//! The `rvirt,config-disk` property of the host's /chosen names a host virtio block device, as an
+//! index into the host's virtio devices, which is never given to a guest. Its first
+//! `CONFIG_SECTORS` sectors hold a flattened device tree whose root node has properties of the same
+//! names as those in /chosen: the `rvirt,*` settings, which say how many guests to start, how much
+//! memory each gets, which disks they are given and so on, and `bootargs`. Each one replaces the
+//! property of the same name in /chosen. A disk that doesn't start with a device tree, such as one
+//! that is all zeros, holds no settings.
+//!
+//! The boot hart reads the settings into `SHARED_STATICS.config` right after parsing the host's
+//! device tree, and every other hart applies them after parsing its own copy. The monitor's
+//! `config` command shows and changes them. Changes are written back to the disk straight away,
+//! and take effect the next time the hypervisor boots.
+
+use byteorder::{BigEndian, ByteOrder};
+use core::sync::atomic::Ordering;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::dma::DmaPool;
+use crate::drivers::blk::HostBlk;
+use crate::error::Result;
+use crate::fdt::{self, Fdt, MachineMeta};
+use crate::overlay::HYPERVISOR_OWNER;
+use crate::statics::SHARED_STATICS;
+
+/// Sectors at the start of the config disk that hold the settings.
+const CONFIG_SECTORS: u64 = 8;
+const CONFIG_SIZE: usize = (CONFIG_SECTORS * SECTOR_SIZE) as usize;
+
+pub struct Config {
+    disk: Option<HostBlk>,
+    /// The settings as they are on the disk.
+    blob: [u8; CONFIG_SIZE],
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self { disk: None, blob: [0; CONFIG_SIZE] }
+    }
+}
+
+/// Take over the config disk, if there is one, and apply the settings on it to `machine`. Called by
+/// the boot hart before it uses any of the settings.
+pub unsafe fn load(machine: &mut MachineMeta, dma: &mut DmaPool) {
+    let index = match machine.config_disk {
+        Some(index) => index as usize,
+        None => return,
+    };
+    let base_address = match machine.virtio.get(index) {
+        Some(device) => device.base_address,
+        None => {
+            println!("WARN: No virtio device {} to use as the config disk", index);
+            return;
+        }
+    };
+    SHARED_STATICS.virtio_owners[index].store(HYPERVISOR_OWNER, Ordering::SeqCst);
+
+    let result = {
+        let mut config = SHARED_STATICS.config.lock();
+        HostBlk::new(base_address, dma).and_then(|mut disk| {
+            disk.read(0, &mut config.blob)?;
+            config.disk = Some(disk);
+            Ok(())
+        })
+    };
+    match result {
+        Ok(()) => apply(machine),
+        Err(e) => println!("WARN: Unable to read the config disk: {:?}", e),
+    }
+}
+
+/// Apply the settings read from the config disk to `machine`, as parsed from the host's device tree.
+pub fn apply(machine: &mut MachineMeta) {
+    let mut config = SHARED_STATICS.config.lock();
+    if let Ok(mut settings) = Fdt::from_slice(&mut config.blob) {
+        settings.apply_settings(machine);
+    }
+}
+
+/// Print the settings on the config disk.
+pub fn show() {
+    let config = SHARED_STATICS.config.lock();
+    if config.disk.is_none() {
+        println!("no config disk (set rvirt,config-disk in /chosen)");
+        return;
+    }
+    match fdt::settings(&config.blob) {
+        Ok(ref settings) if !settings.is_empty() => {
+            for &(name, value) in settings {
+                print_setting(name, value);
+            }
+        }
+        _ => println!("no settings"),
+    };
+}
+
+fn print_setting(name: &str, value: &[u8]) {
+    let text = match value.split_last() {
+        Some((&0, text)) if text.iter().all(|&c| c >= 0x20 && c < 0x7f) => core::str::from_utf8(text).ok(),
+        _ => None,
+    };
+    if value.is_empty() {
+        println!("{}", name);
+    } else if let Some(text) = text {
+        println!("{} = \"{}\"", name, text);
+    } else if value.len() % 4 == 0 {
+        print!("{} = <", name);
+        for (i, cell) in value.chunks(4).enumerate() {
+            print!("{}{}", if i == 0 { "" } else { " " }, BigEndian::read_u32(cell));
+        }
+        println!(">");
+    } else {
+        println!("{} = {:x?}", name, value);
+    }
+}
+
+/// Change a setting on the config disk, or remove it if `value` is None, and write the settings
+/// back to the disk.
+pub fn set(name: &str, value: Option<&[u8]>) {
+    if name != "bootargs" && !name.starts_with("rvirt,") {
+        println!("'{}' is not a setting", name);
+        return;
+    }
+
+    let mut config = SHARED_STATICS.config.lock();
+    let config = &mut *config;
+    let disk = match config.disk.as_mut() {
+        Some(disk) => disk,
+        None => {
+            println!("no config disk (set rvirt,config-disk in /chosen)");
+            return;
+        }
+    };
+
+    let mut blob = [0; CONFIG_SIZE];
+    let result: Result<()> = fdt::edit_settings(&config.blob, name, value, &mut blob).and_then(|_| {
+        disk.write(0, &blob)?;
+        disk.flush()
+    });
+    match result {
+        Ok(()) => {
+            config.blob = blob;
+            println!("saved, takes effect on the next boot");
+        }
+        Err(e) => println!("unable to save settings: {:?}", e),
+    }
+}
@@ -0,0 +1,160 @@
+//! Routing of console input to guests.
+//!
+//! There is only one physical UART, so input typed into it goes to one guest at a time: the guest
+//! with focus, which is guest 1 until changed with the monitor's `focus` command. Whichever hart
+//! reads bytes from the UART places them in the focused guest's input ring, and each guest's
+//! emulated UART and SBI console calls consume from its own ring.
+//!
+//! When the host device tree routes the UART's interrupt through the PLIC (or APLIC), the interrupt
+//! is only enabled for the hart running the focused guest, so input reaches it without waiting for
+//! the next timer tick.
+
+use crate::aia::Aplic;
+use crate::constants::MAX_GUESTS;
+use crate::fdt::IrqChip;
+use crate::monitor::Console;
+use crate::pmap::pa2va;
+use crate::statics::SHARED_STATICS;
+
+const RING_SIZE: usize = 256;
+
+#[derive(Copy, Clone)]
+struct InputRing {
+    data: [u8; RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl InputRing {
+    const fn new() -> Self {
+        Self { data: [0; RING_SIZE], head: 0, len: 0 }
+    }
+
+    /// Add a byte, dropping the oldest one if the ring is full.
+    fn push(&mut self, byte: u8) {
+        if self.len == RING_SIZE {
+            self.head = (self.head + 1) % RING_SIZE;
+            self.len -= 1;
+        }
+        self.data[(self.head + self.len) % RING_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// Console input state shared by all harts. Protected by the lock in `SHARED_STATICS`.
+pub struct ConsoleInput {
+    focus: u64,
+    rings: [InputRing; MAX_GUESTS],
+
+    irqchip: IrqChip,
+    plic_address: u64,
+    uart_irq: Option<u32>,
+    /// PLIC context of the hart running each guest, indexed by guestid.
+    plic_contexts: [Option<u64>; MAX_GUESTS],
+}
+
+impl ConsoleInput {
+    pub const fn new() -> Self {
+        Self {
+            focus: 1,
+            rings: [InputRing::new(); MAX_GUESTS],
+            irqchip: IrqChip::Plic,
+            plic_address: 0,
+            uart_irq: None,
+            plic_contexts: [None; MAX_GUESTS],
+        }
+    }
+
+    /// Record where to route the UART interrupt. Called during boot, before guests start.
+    pub fn set_uart_irq(&mut self, irqchip: IrqChip, plic_address: u64, uart_irq: Option<u32>) {
+        self.irqchip = irqchip;
+        self.plic_address = plic_address;
+        self.uart_irq = uart_irq.filter(|&irq| irq < 32);
+    }
+
+    /// Record the PLIC context of the hart running `guestid`, and return the bits that should be
+    /// added to its interrupt enable mask.
+    pub fn register_guest(&mut self, guestid: u64, plic_context: u64) -> u32 {
+        self.plic_contexts[guestid as usize % MAX_GUESTS] = Some(plic_context);
+        match self.uart_irq {
+            Some(irq) if guestid == self.focus => 1 << irq,
+            _ => 0,
+        }
+    }
+
+    /// Choose the guest that gets input first. Called during boot, before guests are registered.
+    pub fn set_initial_focus(&mut self, guestid: u64) {
+        self.focus = guestid;
+    }
+
+    pub fn focus(&self) -> u64 {
+        self.focus
+    }
+
+    /// Direct input to another guest. Returns false if there is no such guest.
+    pub fn set_focus(&mut self, guestid: u64) -> bool {
+        let index = guestid as usize;
+        if index >= MAX_GUESTS || self.plic_contexts[index].is_none() {
+            return false;
+        }
+
+        if let Some(irq) = self.uart_irq {
+            if let Some(old) = self.plic_contexts[self.focus as usize] {
+                self.set_uart_irq_enabled(old, irq, false);
+            }
+            self.set_uart_irq_enabled(self.plic_contexts[index].unwrap(), irq, true);
+        }
+        self.focus = guestid;
+        true
+    }
+
+    fn set_uart_irq_enabled(&self, plic_context: u64, irq: u32, enabled: bool) {
+        match self.irqchip {
+            IrqChip::Plic => {
+                let enable = pa2va(self.plic_address + 0x2000 + 0x80 * plic_context) as *mut u32;
+                unsafe {
+                    let mask = core::ptr::read_volatile(enable);
+                    let mask = if enabled { mask | 1 << irq } else { mask & !(1 << irq) };
+                    core::ptr::write_volatile(enable, mask);
+                }
+            }
+            // The APLIC sends each source to a single hart, so enabling it for the new guest also
+            // takes it away from the old one.
+            IrqChip::AplicDirect | IrqChip::AplicMsi if enabled => {
+                Aplic::new(self.plic_address, self.irqchip == IrqChip::AplicMsi).route(irq, plic_context, true);
+            }
+            IrqChip::AplicDirect | IrqChip::AplicMsi => {}
+        }
+    }
+
+    /// Take the next byte of input for a guest.
+    pub fn pop(&mut self, guestid: u64) -> Option<u8> {
+        self.rings[guestid as usize % MAX_GUESTS].pop()
+    }
+}
+
+/// Read everything available from the UART. Bytes consumed by the monitor are handled on this
+/// hart; the rest are queued for the guest with focus.
+pub fn receive(monitor: &mut Console) {
+    loop {
+        let ch = match SHARED_STATICS.uart_writer.lock().getchar() {
+            Some(ch) => ch,
+            None => return,
+        };
+        if !monitor.intercept(ch) {
+            let mut input = SHARED_STATICS.console_input.lock();
+            let focus = input.focus as usize % MAX_GUESTS;
+            input.rings[focus].push(ch);
+        }
+    }
+}
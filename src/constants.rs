@@ -3,11 +3,64 @@
 /// symbols. This value must match the one used in the linker script (src/linker.ld).
 pub const SYMBOL_PA2VA_OFFSET: u64 = 0xffffffff40000000;
 
+/// Physical address the hypervisor is linked to run at, matching `.text.supervisor` in
+/// src/slinker.ld. It actually runs `shared_segments_shift` bytes higher (see layout.rs).
+pub const HYPERVISOR_LINK_PA: u64 = 0xffffffffc0000000 - SYMBOL_PA2VA_OFFSET;
+
+/// Exceptions and interrupts that M-mode delegates to the hypervisor. Other exceptions are taken in
+/// M-mode and forwarded from there. The delegation audit (see delegaudit.rs) checks these.
+pub const DELEGATED_EXCEPTIONS: u64 = 0xb1ff;
+pub const DELEGATED_INTERRUPTS: u64 = 0x0222;
+
 /// Maximum number of harts on the host. If the platform has more than this many harts, it might
 /// result in buffer overflows in various places.
 pub const MAX_HOST_HARTS: usize = 16;
 
 pub const MAX_GUEST_HARTS: usize = 8;
 
+/// Maximum number of guests, which sizes the per-guest state indexed by guestid rather than by
+/// hart. There is no scheduling of guests, so each one still needs a hart of its own and at most one
+/// fewer guest than there are host harts can run; `rvirt,max-guests` only lowers that. This is kept
+/// equal to `MAX_HOST_HARTS` so it never further limits the number of guests.
+pub const MAX_GUESTS: usize = 16;
+
 pub const MACHINE_SHARED_STATIC_ADDRESS: u64 = 0x80400000;
 pub const SUPERVISOR_SHARED_STATIC_ADDRESS: u64 = 0xffffffffc0200000;
+
+/// Location (relative to the start of physical memory) of the DMA pool for host devices that the
+/// hypervisor drives on behalf of all guests. Its size is `DMA_POOL_SIZE`.
+pub const SHARED_DMA_POOL_OFFSET: u64 = 512 << 20;
+
+/// Size of the DMA pool carved out of each hart's heap, and of the shared one.
+pub const DMA_POOL_SIZE: u64 = 256 << 10;
+
+/// Location (relative to the start of physical memory) and size of the area that holds the tail of
+/// the console output, after the DMA pool. See logtail.rs.
+pub const LOG_TAIL_OFFSET: u64 = SHARED_DMA_POOL_OFFSET + DMA_POOL_SIZE;
+pub const LOG_TAIL_SIZE: u64 = 64 << 10;
+
+/// Most 1GB segments of host memory that a single guest can be given. See `pmap::GuestMap`.
+pub const MAX_GUEST_SEGMENTS: usize = 4;
+
+/// Maximum number of NUMA nodes that can be emulated for a guest.
+pub const MAX_NUMA_NODES: usize = 8;
+
+/// Frequency of the `mtime` counter, in ticks per second. This matches QEMU's virt machine.
+pub const TIMER_FREQUENCY: u64 = 10_000_000;
+
+/// Where Spike's `tohost` is, relative to the start of memory. `fromhost` follows 64 bytes later.
+/// See htif.rs.
+pub const TOHOST_OFFSET: u64 = 0x1ff000;
+
+/// Largest `rng-seed` taken from the host, and the size of the one given to guests. See entropy.rs.
+pub const RNG_SEED_SIZE: usize = 64;
+
+/// Guest physical address and size of the debug log window, just after the hypervisor info page.
+/// See debuglog.rs.
+pub const DEBUG_LOG_BASE: u64 = 0x10110000;
+pub const DEBUG_LOG_SIZE: u64 = 0x4000;
+
+/// Values written to the test device (see testdev.rs).
+pub const FINISHER_FAIL: u32 = 0x3333;
+pub const FINISHER_PASS: u32 = 0x5555;
+pub const FINISHER_RESET: u32 = 0x7777;
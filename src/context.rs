@@ -1,38 +1,79 @@
 use arrayvec::ArrayVec;
-use spin::Mutex;
-use crate::fdt::MachineMeta;
+use core::sync::atomic::Ordering;
+use crate::aia::{self, Aplic};
+use crate::boottime::{self, Milestone};
+use crate::constants::{MAX_GUESTS, TIMER_FREQUENCY};
+use crate::deferred::DeferredWork;
+use crate::dirty::DirtyLog;
+use crate::dispatch::TrapHandlers;
+use crate::dma::{DmaBuffer, DmaPool};
+use crate::events::{self, EventKind};
+use crate::drivers::GuestDevice;
+use crate::drivers::blk::{BlkDriver, Disk};
+use crate::drivers::vsock::VsockDriver;
+use crate::exits::ExitReason;
+use crate::fdt::{IrqChip, MachineMeta};
+use crate::guestos::GuestOs;
+use crate::icache::IcacheSync;
+use crate::irqlatency::IrqLatency;
+use crate::irqrate::{self, IrqRates};
 use crate::memory_region::MemoryRegion;
+use crate::handoff::Handoff;
+use crate::mmio::WriteCombining;
+use crate::monitor::Console;
+use crate::options::Options;
+use crate::overlay::{Overlay, ReadOnlyDisk};
 use crate::plic::PlicState;
-use crate::pmap::{PageTables, PageTableRoot};
+use crate::pmap::{GuestMap, PageTables, PageTableRoot};
+use crate::pmu::Pmu;
+use crate::profile::Profile;
+use crate::restart::CrashPolicy;
+use crate::rtc::Rtc;
 use crate::riscv::bits::*;
-use crate::riscv::csr;
+use crate::spinlock::SpinLock;
 use crate::statics::SHARED_STATICS;
+use crate::steal::StealTime;
+use crate::symbols::SymbolTable;
+use crate::timer::{TimerEvent, TimerQueue};
 use crate::trap::U64Bits;
-use crate::{pmap, print, riscv, virtio};
+use crate::vcsr::CsrHistory;
+use crate::watch::Watches;
+use crate::{console, debuglog, fdt, hart, hvinfo, monitor, pmap, print, riscv, vcsr, virtio};
 
-pub static CONTEXT: Mutex<Option<Context>> = Mutex::new(None);
+pub static CONTEXT: SpinLock<Option<Context>> = SpinLock::new("CONTEXT", None);
 
-pub struct ControlRegisters {
-    // sedeleg: u64, -- Hard-wired to zero
-    // sideleg: u64, -- Hard-wired to zero
+/// How long a partial line of guest console output may wait before being written out: 10ms.
+const CONSOLE_FLUSH_DELAY: u64 = TIMER_FREQUENCY / 100;
 
+pub struct ControlRegisters {
     pub sstatus: u64,
     pub sie: u64,
     pub sip: u64,
     pub stvec: u64,
-    // scounteren: u64, -- Hard-wired to zero
     pub sscratch: u64,
     pub sepc: u64,
     pub scause: u64,
     pub stval: u64,
     pub satp: u64,
+    pub senvcfg: u64,
 
+    /// When the guest's timer fires, in host time.
     pub mtimecmp: u64,
+    /// Subtracted from host time to give the time seen by the guest.
+    pub time_offset: u64,
 }
 
 pub struct VirtIO {
     pub devices: ArrayVec<[virtio::Device; virtio::MAX_DEVICES]>,
     pub queue_guest_pages: ArrayVec<[u64; virtio::MAX_DEVICES * virtio::MAX_QUEUES]>,
+    /// The guest interrupt of each slot, as given by the guest device tree.
+    pub slot_irqs: [Option<u16>; virtio::MAX_DEVICES],
+    /// Every virtio device of the host, for devices added after boot.
+    pub host_devices: ArrayVec<[fdt::Device; 16]>,
+    /// I/O limits (requests per second, bytes per second) for the guest's devices.
+    pub io_limits: (u64, u64),
+    /// Features hidden from passthrough devices, from `rvirt,virtio-hide-features`.
+    pub hide_features: ArrayVec<[(u32, u32, u32); 16]>,
 }
 
 pub struct Uart {
@@ -41,13 +82,27 @@ pub struct Uart {
     pub divisor_latch: u16,
     pub interrupt_enable: u8,
 
+    /// When the transmit FIFO will have drained, in host time. Each byte written is queued behind
+    /// the ones still being sent, so the guest can write a FIFO's worth at once.
     pub next_interrupt_time: u64,
+    /// Whether bytes written by the guest are still being sent.
+    pub transmitting: bool,
+    /// Latched "transmit holding register empty" condition. As on a real 16550 it is set when the
+    /// FIFO drains or when the guest enables the interrupt while the FIFO is empty, and cleared by
+    /// writing a byte or by reading IIR while it is the reported cause.
+    pub thr_empty: bool,
 
     pub input_fifo: [u8; 16],
     pub input_bytes_ready: usize,
+    /// Whether a break condition is waiting to be received ahead of the bytes in the FIFO.
+    pub break_pending: bool,
 
+    /// Console output not yet written to the host UART. It is written a line at a time, and with a
+    /// single guest a partial line is also written once `CONSOLE_FLUSH_DELAY` has passed.
     pub line_buffer: ArrayVec<[u8; 256]>,
     pub guestid: Option<u64>,
+
+    pub monitor: Console,
 }
 
 pub enum HostClint {
@@ -57,8 +112,16 @@ pub enum HostClint {
     Sbi,
 }
 
-pub struct HostPlic {
-    pub claim_clear: MemoryRegion<u32>,
+pub enum HostIrqChip {
+    Plic {
+        claim_clear: MemoryRegion<u32>,
+        /// Interrupt enable bits of the hart's PLIC context.
+        enable: MemoryRegion<u32>,
+    },
+    Aplic {
+        aplic: Aplic,
+        hart_index: u64,
+    },
 }
 
 pub struct SavedRegisters {
@@ -68,6 +131,8 @@ pub struct SavedRegisters {
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum IrqMapping {
     Virtio { device_index: u8, guest_irq: u16 },
+    /// Input is available on the host UART.
+    Console,
     Ignored,
 }
 
@@ -80,15 +145,24 @@ pub struct Context {
     pub plic: PlicState,
     pub uart: Uart,
     pub virtio: VirtIO,
+    /// The guest's real time clock, see rtc.rs.
+    pub rtc: Rtc,
 
     pub saved_registers: SavedRegisters,
     pub guest_memory: MemoryRegion,
     pub shadow_page_tables: PageTables,
 
-    pub guest_shift: u64,
+    pub guest_map: GuestMap,
 
-    /// Whether the guest is in S-Mode.
-    pub smode: bool,
+    /// Memory for rings and buffers of host devices used on behalf of this guest.
+    pub dma: DmaPool,
+    /// Backing for the page that describes the hypervisor to the guest, see hvinfo.rs.
+    pub info_page: Option<DmaBuffer>,
+
+    /// The privilege level the guest is in. Only changed through `trap_to_guest` and `sret`.
+    privilege: PrivilegeState,
+    /// The guest's recent writes to the CSRs that say the most about where it is, see vcsr.rs.
+    pub csr_history: CsrHistory,
 
     /// If set, hypervisor exits do not need to check for pending interrupts
     pub no_interrupt: bool,
@@ -96,10 +170,70 @@ pub struct Context {
     pub tlb_caches_invalid_ptes: bool,
     pub consecutive_page_fault_count: u64,
 
+    /// In debug builds, verify the shadow page tables after this many page faults (zero to disable).
+    pub verify_interval: u64,
+    pub faults_since_verify: u64,
+
     pub host_clint: HostClint,
-    pub host_plic: HostPlic,
+    pub host_irqchip: HostIrqChip,
+    /// Whether the host timer can be programmed directly through stimecmp.
+    pub host_sstc: bool,
+    /// Whether memory types from guest PTEs can be carried over to shadow PTEs.
+    pub host_svpbmt: bool,
+    /// Bits of the host's senvcfg that the hypervisor manages for the guest, for the cache block
+    /// operations the host implements. Zero if it implements none, in which case the host may not
+    /// have senvcfg at all.
+    pub host_envcfg: u64,
+    /// Whether console input has to be polled for because the host UART can't interrupt this hart.
+    pub console_polled: bool,
+    /// Everything waiting on this hart's timer.
+    pub timers: TimerQueue,
+    /// Work left by trap handlers, done before returning to the guest.
+    pub deferred: DeferredWork,
+    /// Performance counters presented to the guest.
+    pub pmu: Pmu,
+    /// Time the guest's hart spent on interrupts rather than running it. See steal.rs.
+    pub steal: StealTime,
+    /// How often instruction fetches were fenced, see icache.rs.
+    pub icache: IcacheSync,
+    /// Emulated device regions mapped into the guest rather than trapped, see mmio.rs.
+    pub write_combining: WriteCombining,
+    /// What handles each kind of trap from the guest, see dispatch.rs.
+    pub trap_handlers: TrapHandlers,
+    /// How often each host interrupt source has interrupted, for masking noisy ones.
+    pub irq_rates: IrqRates,
+    /// Histograms of how long interrupts take to reach the guest, see irqlatency.rs.
+    pub irq_latency: IrqLatency,
+    /// Cycle histograms of hot paths, only filled in with the `profile` feature.
+    pub profile: Profile,
+    /// Ranges of guest memory whose writes are logged, see watch.rs.
+    pub watches: Watches,
+    /// Pages written since they were last copied, see dirty.rs.
+    pub dirty: DirtyLog,
+    /// A move to another hart that has been asked for, see handoff.rs.
+    pub handoff: Handoff,
+    /// Whether the SBI calls that `trap::strap_fast` can handle are taken off the full trap path.
+    /// Only turned off to measure the difference.
+    pub fast_sbi: bool,
+    /// Function symbols of the guest kernel, if its image wasn't stripped.
+    pub symbols: SymbolTable,
 
     pub test_finisher: Option<TestFinisher>,
+    /// Virtual address of Spike's `tohost`, which powers off the simulator when there is no test
+    /// finisher. See htif.rs.
+    pub htif_tohost: Option<u64>,
+    /// Exit code to report if this guest is the last to shut down.
+    pub shutdown_exit_code: u64,
+    /// What to do if the guest crashes.
+    pub crash_policy: CrashPolicy,
+    /// Switches from the hypervisor's command line, with trace targets changed by the monitor.
+    pub options: Options,
+    /// Whether this guest may read the event log.
+    pub control_guest: bool,
+    /// What the guest was identified as, and which quirks apply to it.
+    pub guest_os: GuestOs,
+    /// Translation modes the guest tried to select and was refused, one bit per value of satp.MODE.
+    pub satp_modes_refused: u16,
 
     /// Map from host external interrupt number to guest external interrupt nmuber
     pub irq_map: [IrqMapping; 512],
@@ -118,17 +252,97 @@ impl ControlRegisters {
     }
 }
 
+/// The privilege level the guest is emulated at. On the hardware it always runs in U-mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    User,
+    Supervisor,
+}
+
+/// What made the guest's privilege level change.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PrivilegeEvent {
+    /// An exception forwarded to the guest.
+    Exception,
+    /// An interrupt forwarded to the guest.
+    Interrupt,
+    /// The guest executed sret.
+    Sret,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PrivilegeTransition {
+    from: Privilege,
+    to: Privilege,
+    event: PrivilegeEvent,
+    /// Guest pc the transition was made from.
+    pc: u64,
+}
+
+/// Number of recent transitions kept for debugging.
+const PRIVILEGE_HISTORY: usize = 16;
+
+/// The guest's emulated privilege level, as a state machine with two transitions: a trap into the
+/// guest's supervisor enters S-mode from either level and saves the previous level in sstatus.SPP,
+/// and sret (only legal in S-mode) returns to the level in SPP and clears it. The last few
+/// transitions are kept so they can be shown when a guest crashes.
+struct PrivilegeState {
+    current: Privilege,
+    history: [Option<PrivilegeTransition>; PRIVILEGE_HISTORY],
+    next: usize,
+}
+
+impl PrivilegeState {
+    fn new() -> Self {
+        // Guests are started in S-mode, like a kernel started by firmware.
+        Self { current: Privilege::Supervisor, history: [None; PRIVILEGE_HISTORY], next: 0 }
+    }
+
+    fn transition(&mut self, to: Privilege, event: PrivilegeEvent, pc: u64) {
+        let transition = PrivilegeTransition { from: self.current, to, event, pc };
+        self.history[self.next] = Some(transition);
+        self.next = (self.next + 1) % PRIVILEGE_HISTORY;
+        self.current = to;
+    }
+}
+
 impl Uart {
     const IRQ: u32 = 10;
 
-    fn tx_interrupt(&self, current_time: u64) -> bool {
-        self.next_interrupt_time  <= current_time && self.interrupt_enable & 0x2 != 0
+    fn update_transmitter(&mut self, current_time: u64) {
+        if self.transmitting && self.next_interrupt_time <= current_time {
+            self.transmitting = false;
+            self.thr_empty = true;
+        }
+    }
+    fn tx_interrupt(&mut self, current_time: u64) -> bool {
+        self.update_transmitter(current_time);
+        self.thr_empty && self.interrupt_enable & Uart::IER_THR_EMPTY != 0
+    }
+    /// When the transmit interrupt will be raised, if the guest has enabled it and it isn't
+    /// already latched.
+    pub fn tx_interrupt_time(&self) -> Option<u64> {
+        if self.interrupt_enable & Uart::IER_THR_EMPTY != 0 && (self.transmitting || self.thr_empty) {
+            Some(self.next_interrupt_time)
+        } else {
+            None
+        }
     }
     fn rx_interrupt(&self) -> bool {
-        self.input_bytes_ready >= 1 && self.interrupt_enable & 0x1 != 0
+        (self.input_bytes_ready >= 1 || self.break_pending) && self.interrupt_enable & Uart::IER_RX_READY != 0
     }
-    pub fn timer(state: &mut Context, current_time: u64) {
+    /// Collect console input and raise the guest's UART interrupt if needed. Called from timer
+    /// events, and whenever the host UART signals that input is available.
+    pub fn poll(state: &mut Context, current_time: u64) {
         state.uart.fill_fifo();
+        monitor::poll(state);
+        Uart::update_interrupt(state, current_time);
+    }
+
+    /// Make the guest's UART interrupt pending if any condition it enabled holds. The interrupt is
+    /// level triggered, so this is checked again whenever the guest completes an interrupt at the
+    /// PLIC.
+    pub fn update_interrupt(state: &mut Context, current_time: u64) {
         if state.uart.tx_interrupt(current_time) || state.uart.rx_interrupt() {
             state.plic.set_pending(Uart::IRQ, true);
             state.no_interrupt = false;
@@ -136,16 +350,45 @@ impl Uart {
     }
 
     pub fn fill_fifo(&mut self) {
+        console::receive(&mut self.monitor);
+
+        let mut input = SHARED_STATICS.console_input.lock();
         while self.input_bytes_ready < self.input_fifo.len() {
-            if let Some(ch) = SHARED_STATICS.uart_writer.lock().getchar() {
-                self.input_fifo[self.input_bytes_ready] = ch;
-                self.input_bytes_ready += 1;
-            } else {
-                break;
+            match input.pop(self.guestid.unwrap_or(1)) {
+                Some(ch) => {
+                    self.input_fifo[self.input_bytes_ready] = ch;
+                    self.input_bytes_ready += 1;
+                }
+                None => break,
             }
         }
     }
 
+    /// Receive a break followed by `key`, ahead of any other input. Linux takes this as a magic
+    /// SysRq request.
+    pub fn inject_sysrq(&mut self, key: u8) {
+        let len = self.input_bytes_ready.min(self.input_fifo.len() - 1);
+        for i in (0..len).rev() {
+            self.input_fifo[i + 1] = self.input_fifo[i];
+        }
+        self.input_fifo[0] = key;
+        self.input_bytes_ready = len + 1;
+        self.break_pending = true;
+    }
+
+    /// Remove the next byte of console input destined for the guest.
+    pub fn take_input(&mut self) -> Option<u8> {
+        if self.input_bytes_ready == 0 {
+            return None;
+        }
+        let ret = self.input_fifo[0];
+        self.input_bytes_ready -= 1;
+        for i in 0..(self.input_bytes_ready) {
+            self.input_fifo[i] = self.input_fifo[i+1];
+        }
+        Some(ret)
+    }
+
     const TRANSMIT_HOLDING_REGISTER: u64 = 0x10000000;
     const RECEIVE_BUFFER_REGISTER: u64 = 0x10000000;
     const DIVISOR_LATCH_LSB: u64 = 0x10000000;
@@ -160,6 +403,10 @@ impl Uart {
     #[allow(unused)]
     const SCRATCH_REGISTER: u64 = 0x10000007;
 
+    // bits for interrupt enable register
+    const IER_RX_READY: u8 = 0x01;
+    const IER_THR_EMPTY: u8 = 0x02;
+
     // bits for interrupt identification register
     const IIR_FIFOS_ENABLED: u8 = 0xC0;
     const IIR_INTERRUPT_NOT_PENDING: u8 = 0x01; // set to zero for interrupt pending
@@ -173,7 +420,6 @@ impl Uart {
 
     // bits for line status register
     const LSR_DATA_READY: u8 = 0x01;
-    #[allow(unused)]
     const LSR_BREAK_INTERRUPT: u8 = 0x10;
     const LSR_TRANSMITTER_HAS_ROOM: u8 = 0x20;
     const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
@@ -187,18 +433,12 @@ impl Uart {
 
     pub fn read(&mut self, host_clint: &HostClint, addr: u64) -> u8 {
         match (self.dlab, addr) {
-            (false, Uart::RECEIVE_BUFFER_REGISTER) => {
-                if self.input_bytes_ready > 0 {
-                    let ret = self.input_fifo[0];
-                    self.input_bytes_ready -= 1;
-                    for i in 0..(self.input_bytes_ready) {
-                        self.input_fifo[i] = self.input_fifo[i+1];
-                    }
-                    ret
-                } else {
-                    0
-                }
+            (false, Uart::RECEIVE_BUFFER_REGISTER) if self.break_pending => {
+                // A break is received as a zero byte.
+                self.break_pending = false;
+                0
             }
+            (false, Uart::RECEIVE_BUFFER_REGISTER) => self.take_input().unwrap_or(0),
             (true, Uart::DIVISOR_LATCH_LSB) => (self.divisor_latch & 0xff) as u8,
             (true, Uart::DIVISOR_LATCH_MSB) => (self.divisor_latch >> 8) as u8,
             (false, Uart::INTERRUPT_ENABLE_REGISTER) => self.interrupt_enable, // (top four should always be zero)
@@ -206,6 +446,7 @@ impl Uart {
                 if self.rx_interrupt() {
                     Uart::IIR_FIFOS_ENABLED | Uart::IIR_RX_INTERRUPT
                 } else if self.tx_interrupt(host_clint.get_mtime()) {
+                    self.thr_empty = false;
                     Uart::IIR_FIFOS_ENABLED | Uart::IIR_TX_INTERRUPT
                 } else {
                     Uart::IIR_FIFOS_ENABLED | Uart::IIR_INTERRUPT_NOT_PENDING
@@ -217,10 +458,13 @@ impl Uart {
                 self.fill_fifo();
 
                 let mut lsr = 0;
-                if self.input_bytes_ready > 0 {
+                if self.break_pending {
+                    lsr |= Uart::LSR_DATA_READY | Uart::LSR_BREAK_INTERRUPT;
+                } else if self.input_bytes_ready > 0 {
                     lsr |= Uart::LSR_DATA_READY;
                 }
-                if host_clint.get_mtime() >= self.next_interrupt_time {
+                self.update_transmitter(host_clint.get_mtime());
+                if !self.transmitting {
                     lsr |= Uart::LSR_TRANSMITTER_HAS_ROOM | Uart::LSR_TRANSMITTER_EMPTY;
                 }
                 lsr
@@ -241,8 +485,15 @@ impl Uart {
                 let transmit_time = self.divisor_latch as u64 * 5;
                 self.next_interrupt_time =
                     self.next_interrupt_time.max(current_time) + transmit_time;
+                self.transmitting = true;
+                self.thr_empty = false;
             }
             (false, Uart::INTERRUPT_ENABLE_REGISTER, _) => {
+                self.update_transmitter(host_clint.get_mtime());
+                let enabled = value & !self.interrupt_enable;
+                if enabled & Uart::IER_THR_EMPTY != 0 && !self.transmitting {
+                    self.thr_empty = true;
+                }
                 self.interrupt_enable = value;
             }
             (true, Uart::DIVISOR_LATCH_LSB, _) => {
@@ -263,6 +514,7 @@ impl Uart {
     }
 
     pub fn output_byte(&mut self, value: u8) {
+        boottime::mark(Milestone::FirstConsoleOutput);
         if let Some(guestid) = self.guestid {
             let len = self.line_buffer.len();
             if len > 0 && self.line_buffer[len - 1] == '\r' as u8 && value != '\n' as u8 {
@@ -276,7 +528,27 @@ impl Uart {
                 self.line_buffer.push(value);
             }
         } else {
-            SHARED_STATICS.uart_writer.lock().putchar(value);
+            self.line_buffer.push(value);
+            if value == '\n' as u8 || self.line_buffer.is_full() {
+                self.flush_output();
+            }
+        }
+    }
+
+    /// Whether there is output waiting for `flush_output`. With several guests, lines are only
+    /// printed once complete so that their output doesn't get mixed up.
+    pub fn has_partial_line(&self) -> bool {
+        self.guestid.is_none() && !self.line_buffer.is_empty()
+    }
+
+    /// Write out buffered output of a single guest, taking the UART lock once for all of it.
+    pub fn flush_output(&mut self) {
+        if self.has_partial_line() {
+            let mut writer = SHARED_STATICS.uart_writer.lock();
+            for &b in &self.line_buffer {
+                writer.putchar(b);
+            }
+            self.line_buffer.clear();
         }
     }
 }
@@ -290,12 +562,66 @@ impl HostClint {
     }
 }
 
-impl HostPlic {
+impl HostIrqChip {
+    /// The interrupt controller as seen from the current hart, whose PLIC context or APLIC hart
+    /// index is `plic_context`.
+    pub fn new(machine: &MachineMeta, plic_context: u64) -> Self {
+        match machine.irqchip {
+            // The PLIC is in one of the device gigabytes that stay in the direct map (see
+            // layout.rs), and these registers belong to the context of a single hart.
+            IrqChip::Plic => unsafe {
+                HostIrqChip::Plic {
+                    claim_clear: MemoryRegion::with_base_address(
+                        pmap::pa2va(machine.plic_address + 0x200004 + 0x1000 * plic_context), 0, 8),
+                    enable: MemoryRegion::with_base_address(
+                        pmap::pa2va(machine.plic_address + 0x2000 + 0x80 * plic_context), 0, 0x80),
+                }
+            },
+            IrqChip::AplicDirect | IrqChip::AplicMsi => {
+                let aplic = Aplic::new(machine.plic_address, machine.irqchip == IrqChip::AplicMsi);
+                if machine.irqchip == IrqChip::AplicMsi {
+                    aia::imsic_init_hart();
+                }
+                HostIrqChip::Aplic { aplic, hart_index: plic_context }
+            }
+        }
+    }
+
     pub fn claim_and_clear(&mut self) -> u32 {
-        let claim = self.claim_clear[0];
-        riscv::barrier();
-        self.claim_clear[0] = claim;
-        claim
+        match *self {
+            HostIrqChip::Plic { ref mut claim_clear, .. } => {
+                let claim = claim_clear[0];
+                riscv::barrier();
+                claim_clear[0] = claim;
+                claim
+            }
+            HostIrqChip::Aplic { ref aplic, hart_index } => aplic.claim(hart_index),
+        }
+    }
+
+    /// Have host interrupt `irq` delivered to this hart.
+    pub fn enable(&mut self, irq: u32) {
+        match *self {
+            HostIrqChip::Plic { ref mut enable, .. } => {
+                // Switching the console focus changes these bits too, while holding this lock.
+                let _console = SHARED_STATICS.console_input.lock();
+                let word = (irq as u64 / 32) * 4;
+                enable[word] = enable[word] | 1 << (irq % 32);
+            }
+            HostIrqChip::Aplic { ref aplic, hart_index } => aplic.route(irq, hart_index, true),
+        }
+    }
+
+    /// Stop host interrupt `irq` from being delivered to this hart.
+    pub fn disable(&mut self, irq: u32) {
+        match *self {
+            HostIrqChip::Plic { ref mut enable, .. } => {
+                let _console = SHARED_STATICS.console_input.lock();
+                let word = (irq as u64 / 32) * 4;
+                enable[word] = enable[word] & !(1 << (irq % 32));
+            }
+            HostIrqChip::Aplic { ref aplic, hart_index } => aplic.route(irq, hart_index, false),
+        }
     }
 }
 
@@ -314,16 +640,14 @@ impl SavedRegisters {
     pub fn get(&self, reg: u32) -> u64 {
         match reg {
             0 => 0,
-            1 | 3..=31 => self.registers[reg as u64 * 8],
-            2 => csrr!(sscratch),
+            1..=31 => self.registers[reg as u64 * 8],
             _ => unreachable!(),
         }
     }
     pub fn set(&mut self, reg: u32, value: u64) {
         match reg {
             0 => {},
-            1 | 3..=31 => self.registers[reg as u64 * 8] = value,
-            2 => riscv::set_sscratch(value),
+            1..=31 => self.registers[reg as u64 * 8] = value,
             _ => unreachable!(),
         }
     }
@@ -331,98 +655,176 @@ impl SavedRegisters {
 
 impl Context {
     pub fn get_csr(&mut self, csr: u32) -> Option<u64> {
-        Some(match csr as u64 {
-            csr::sstatus => {
-                let real = csrr!(sstatus);
-                self.csrs.sstatus = (self.csrs.sstatus & !SSTATUS_DYNAMIC_MASK) | (real & SSTATUS_DYNAMIC_MASK);
-                self.csrs.sstatus
-            }
-            csr::satp => self.csrs.satp,
-            csr::sie => self.csrs.sie,
-            csr::stvec => self.csrs.stvec,
-            csr::sscratch => self.csrs.sscratch,
-            csr::sepc => self.csrs.sepc,
-            csr::scause => self.csrs.scause,
-            csr::stval => self.csrs.stval,
-            csr::sip => self.csrs.sip,
-            csr::sedeleg => 0,
-            csr::sideleg => 0,
-            csr::scounteren => 0,
-            csr::time if self.smode => self.host_clint.get_mtime(),
-            csr::time => unimplemented!(),
-            c => {
-                println!("Read from unrecognized CSR: {:#x}", c);
-                return None;
+        match vcsr::lookup(csr as u64) {
+            Some(descriptor) => Some(descriptor.read(self)),
+            None => {
+                println!("Read from unrecognized CSR: {:#x}", csr);
+                None
             }
-        })
+        }
     }
 
     pub fn set_csr(&mut self, csr: u32, value: u64) -> bool {
-        match csr as u64 {
-            csr::sstatus => {
-                // User interrupts not supported
-                let value = value & SSTATUS_WRITABLE_MASK;
+        match vcsr::lookup(csr as u64) {
+            Some(descriptor) => descriptor.write(self, value),
+            None => {
+                println!("Write to unrecognized CSR: {:#x}", csr);
+                false
+            }
+        }
+    }
 
-                let changed = self.csrs.sstatus ^ value;
-                self.csrs.sstatus = value;
+    /// The current time as seen by the guest.
+    pub fn guest_time(&self) -> u64 {
+        self.host_clint.get_mtime().wrapping_sub(self.csrs.time_offset)
+    }
 
-                if changed & STATUS_MXR != 0 {
-                    unimplemented!("STATUS.MXR");
-                }
-                if changed & STATUS_FS != 0 {
-                    riscv::set_sstatus_fs(value);
-                }
+    /// Arm the guest's timer for `guest_time`, as done by both the SBI set_timer call and writes to
+    /// stimecmp. Any pending timer interrupt is cleared.
+    pub fn set_guest_timer(&mut self, guest_time: u64) {
+        self.csrs.sip.set(IP_STIP, false);
+        self.csrs.mtimecmp = guest_time.saturating_add(self.csrs.time_offset);
+        let mtimecmp = self.csrs.mtimecmp;
+        self.schedule_timer(TimerEvent::GuestTimer, mtimecmp);
+    }
 
-                if changed.get(STATUS_SIE) && value.get(STATUS_SIE) {
-                    // Enabling interrupts might cause one to happen right away.
-                    self.no_interrupt = false;
-                }
-            }
-            csr::satp => {
-                let mode = (value & SATP_MODE) >> 60;
-                if mode == 0 || mode == 8 {
-                    self.csrs.satp = value & !SATP_ASID;
-                } else {
-                    println!("Attempted to install page table with unsupported mode");
-                }
-                // This should not be necessary. However, currently QEMU doesn't trap when
-                // sfence.vma is executed from user mode so flush here to compensate.
-                pmap::flush_shadow_page_table(&mut self.shadow_page_tables);
-            }
-            csr::sie => {
-                let value = value & (IE_SEIE | IE_STIE | IE_SSIE);
-                if !self.csrs.sie & value != 0 {
-                    self.no_interrupt = false;
-                }
-                self.csrs.sie = value;
-            }
-            csr::stvec => self.csrs.stvec = value & !0x2,
-            csr::sscratch => self.csrs.sscratch = value,
-            csr::sepc => self.csrs.sepc = value,
-            csr::scause => self.csrs.scause = value,
-            csr::stval => self.csrs.stval = value,
-            csr::sip => {
-                if value & IP_SSIP != 0 {
-                    self.no_interrupt = false;
-                }
-                self.csrs.sip = (self.csrs.sip & !IP_SSIP) | (value & IP_SSIP)
-            }
-            csr::sedeleg |
-            csr::sideleg |
-            csr::scounteren => {}
-            c => {
-                println!("Write to unrecognized CSR: {:#x}", c);
-                return false;
+    /// Make sure a partial line of console output gets written out even if the guest never finishes
+    /// it, as happens with shell prompts.
+    pub fn schedule_console_flush(&mut self) {
+        if self.uart.has_partial_line() && self.timers.deadline(TimerEvent::ConsoleFlush).is_none() {
+            let deadline = self.host_clint.get_mtime() + CONSOLE_FLUSH_DELAY;
+            self.schedule_timer(TimerEvent::ConsoleFlush, deadline);
+        }
+    }
+
+    /// Queue a timer event. The host timer is always armed for the earliest event in the queue, so
+    /// it only has to be reprogrammed if that changed.
+    pub fn schedule_timer(&mut self, event: TimerEvent, deadline: u64) {
+        let previous = self.timers.next_deadline();
+        self.timers.schedule(event, deadline);
+        let next = self.timers.next_deadline();
+        if next != previous {
+            self.set_host_timer(next);
+        }
+    }
+
+    /// Request a timer interrupt on this hart at `time`, which is in host time.
+    pub fn set_host_timer(&self, time: u64) {
+        if self.host_sstc {
+            unsafe { csrw!(stimecmp, time) };
+        } else {
+            riscv::sbi::set_timer(time);
+        }
+    }
+
+    /// Check that the guest physical range can be accessed through `guest_memory`, and if `write` is
+    /// set mark its pages dirty. Returns false if the range isn't entirely guest memory.
+    pub fn prepare_guest_access(&mut self, guest_pa: u64, len: u64, write: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let end = match guest_pa.checked_add(len - 1) {
+            Some(end) if self.guest_memory.in_region(guest_pa) && self.guest_memory.in_region(end) => end,
+            _ => return false,
+        };
+
+        let mut page = guest_pa & !0xfff;
+        while page <= end {
+            if write {
+                self.dirty.mark(&self.guest_memory, page);
             }
+            page += 0x1000;
+        }
+        true
+    }
+
+    /// Read guest physical memory from `guest_pa` up to the end of its page, or for `len` bytes if
+    /// that's less. None if it isn't guest memory.
+    pub fn read_guest_page(&mut self, guest_pa: u64, len: u64) -> Option<&[u8]> {
+        let len = len.min(0x1000 - (guest_pa & 0xfff));
+        if !self.prepare_guest_access(guest_pa, len, false) {
+            return None;
+        }
+        Some(self.guest_memory.slice(guest_pa, len))
+    }
+
+    /// Count a trap into the hypervisor against this guest.
+    pub fn record_exit(&self, reason: ExitReason) {
+        let guestid = hart::current().guest_index() as usize;
+        SHARED_STATICS.exit_stats[guestid % MAX_GUESTS].record(reason);
+    }
+
+    /// Whether the guest is in S-mode.
+    pub fn smode(&self) -> bool {
+        self.privilege.current == Privilege::Supervisor
+    }
+
+    /// Take a trap into the guest's supervisor: save the interrupted pc, cause and trap value, move
+    /// SIE to SPIE and the current privilege level to SPP, and enter S-mode. The caller is
+    /// responsible for resuming the guest at its trap handler.
+    pub fn trap_to_guest(&mut self, event: PrivilegeEvent, scause: u64, sepc: u64, stval: u64) {
+        assert!(event != PrivilegeEvent::Sret);
+        let from = self.privilege.current;
+        self.csrs.push_sie();
+        self.csrs.sepc = sepc;
+        self.csrs.scause = scause;
+        self.csrs.stval = stval;
+        self.csrs.sstatus.set(STATUS_SPP, from == Privilege::Supervisor);
+        self.privilege.transition(Privilege::Supervisor, event, sepc);
+        self.update_host_envcfg();
+
+        if cfg!(debug_assertions) {
+            assert!(!self.csrs.sstatus.get(STATUS_SIE));
+            assert_eq!(self.csrs.sstatus.get(STATUS_SPP), from == Privilege::Supervisor);
+        }
+    }
+
+    /// Emulate sret: restore SIE from SPIE and return to the privilege level in SPP, leaving SPP
+    /// cleared. Returns the guest pc to resume at.
+    pub fn sret(&mut self, pc: u64) -> u64 {
+        assert!(self.smode(), "sret emulated for a guest in U-mode (pc={:#x})", pc);
+        let to = if self.csrs.sstatus.get(STATUS_SPP) { Privilege::Supervisor } else { Privilege::User };
+        self.csrs.pop_sie();
+        self.csrs.sstatus.set(STATUS_SPP, false);
+        self.privilege.transition(to, PrivilegeEvent::Sret, pc);
+        self.update_host_envcfg();
+        self.csrs.sepc
+    }
+
+    /// Program the host's senvcfg, which governs cache block operations and FIOM for code running in
+    /// U-mode, i.e. for the guest at either of its privilege levels. While the guest is in S-mode it
+    /// may use every operation the host implements, as if its firmware had enabled them in menvcfg.
+    /// In U-mode it gets what its own senvcfg allows. FIOM is always set, since the guest's I/O
+    /// includes memory that host devices access by DMA.
+    ///
+    /// Invalidation is always performed as a flush. Otherwise the guest could discard writes the
+    /// hypervisor made to its memory, or to frames shared with other guests, that are still in the
+    /// cache.
+    pub fn update_host_envcfg(&self) {
+        if self.host_envcfg == 0 {
+            return;
         }
+        let mut value = if self.smode() { !0 } else { self.csrs.senvcfg | ENVCFG_FIOM };
+        if value & ENVCFG_CBIE == ENVCFG_CBIE_INVAL {
+            value = (value & !ENVCFG_CBIE) | ENVCFG_CBIE_FLUSH;
+        }
+        unsafe { csrw!(senvcfg, value & self.host_envcfg) };
+    }
 
-        return true;
+    /// Print the guest's privilege level and its most recent changes, oldest first.
+    pub fn dump_privilege_history(&self) {
+        println!("mode    = {}", if self.smode() { "S" } else { "U" });
+        for i in 0..PRIVILEGE_HISTORY {
+            if let Some(t) = self.privilege.history[(self.privilege.next + i) % PRIVILEGE_HISTORY] {
+                println!("  {:?} -> {:?} by {:?} at {}", t.from, t.to, t.event, self.symbols.symbolize(t.pc));
+            }
+        }
     }
 
     pub fn shadow(&self) -> PageTableRoot {
         if (self.csrs.satp & SATP_MODE) == 0 {
             PageTableRoot::MPA
-        } else if !self.smode {
+        } else if !self.smode() {
             PageTableRoot::UVA
         } else if self.csrs.sstatus & STATUS_SUM == 0 {
             PageTableRoot::KVA
@@ -432,19 +834,35 @@ impl Context {
     }
 }
 
+/// The first virtio slot without a device, and the guest interrupt of that slot.
+fn free_virtio_slot(devices: &[virtio::Device], guest_machine: &MachineMeta) -> Option<(usize, u16)> {
+    let slot = devices.iter().position(|d| match d {
+        virtio::Device::Unmapped => true,
+        _ => false,
+    })?;
+    let device = guest_machine.virtio.iter().find(|d| d.base_address == 0x10001000 + 0x1000 * slot as u64)?;
+    if device.irq > u16::max_value() as u64 {
+        return None;
+    }
+    Some((slot, device.irq as u16))
+}
+
 pub unsafe fn initialize(machine: &MachineMeta,
                          guest_machine: &MachineMeta,
                          shadow_page_tables: PageTables,
                          guest_memory: MemoryRegion,
-                         guest_shift: u64,
-                         hartid: u64,
-                         guestid: Option<u64>) {
+                         guest_map: GuestMap,
+                         dma_pool: MemoryRegion,
+                         symbols: SymbolTable,
+                         guest_os: GuestOs) {
+    let (hartid, guestid) = (hart::current().hartid, hart::current().guestid);
     let mut irq_map = [IrqMapping::Ignored; 512];
     let mut virtio_devices = ArrayVec::new();
+    let mut dma = DmaPool::new(dma_pool);
+    let (requests_per_sec, bytes_per_sec) = machine.io_limits(guestid.unwrap_or(1));
     for i in 0..4 {
         let index = (guestid.unwrap_or(1) as usize - 1) * 4 + i;
-        if index < machine.virtio.len() {
-            virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address));
+        if index < machine.virtio.len() && !machine.is_shared_device(index) {
             let host_irq = machine.virtio[index].irq;
             let mut guest_irq = None;
             for j in 0..4 {
@@ -453,6 +871,22 @@ pub unsafe fn initialize(machine: &MachineMeta,
                     break;
                 }
             }
+
+            // The hypervisor polls block backends, so their host interrupt stays unmapped.
+            if machine.blk_backends.contains(&(index as u32)) {
+                match Disk::open(machine.virtio[index].base_address, &mut dma) {
+                    Ok(disk) => {
+                        let device = GuestDevice::new(BlkDriver::new(disk));
+                        virtio_devices.push(virtio::Device::Blk(device, guest_irq.unwrap() as u16));
+                        continue;
+                    }
+                    Err(e) => println!("WARN: passing through virtio device {} instead of serving it: {:?}", index, e),
+                }
+            }
+
+            virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address,
+                                                    requests_per_sec, bytes_per_sec,
+                                                    &machine.virtio_hide_features));
             assert_eq!(irq_map[host_irq as usize], IrqMapping::Ignored);
             irq_map[host_irq as usize] = IrqMapping::Virtio {
                 device_index: i as u8,
@@ -463,23 +897,82 @@ pub unsafe fn initialize(machine: &MachineMeta,
         }
     }
 
+    // Emulated devices take the first slots without a passthrough device, copy-on-write disks
+    // first.
+    if let Some(overlay) = Overlay::new(guestid.unwrap_or(1)) {
+        match free_virtio_slot(&virtio_devices, guest_machine) {
+            Some((i, irq)) => {
+                let driver = BlkDriver::new(Disk::Overlay(overlay));
+                virtio_devices[i] = virtio::Device::Blk(GuestDevice::new(driver), irq);
+            }
+            None => println!("WARN: no free virtio slot for copy-on-write disk"),
+        }
+    }
+    for index in machine.readonly_disks(guestid.unwrap_or(1)) {
+        let disk = match ReadOnlyDisk::new(index) {
+            Some(disk) => disk,
+            None => continue,
+        };
+        match free_virtio_slot(&virtio_devices, guest_machine) {
+            Some((i, irq)) => {
+                let driver = BlkDriver::new(Disk::ReadOnly(disk));
+                virtio_devices[i] = virtio::Device::Blk(GuestDevice::new(driver), irq);
+            }
+            None => println!("WARN: no free virtio slot for read-only disk {}", index),
+        }
+    }
+
+    // Guest CIDs start at 3, since 0 to 2 are reserved.
+    if machine.vsock {
+        match free_virtio_slot(&virtio_devices, guest_machine) {
+            Some((i, irq)) => {
+                let driver = VsockDriver::new(2 + guestid.unwrap_or(1));
+                virtio_devices[i] = virtio::Device::Vsock(GuestDevice::new(driver), irq);
+            }
+            None => println!("WARN: no free virtio slot for vsock device"),
+        }
+    }
+
+    let mut slot_irqs = [None; virtio::MAX_DEVICES];
+    for (i, slot_irq) in slot_irqs.iter_mut().enumerate() {
+        *slot_irq = guest_machine.virtio.iter()
+            .find(|d| d.base_address == 0x10001000 + 0x1000 * i as u64)
+            .map(|d| d.irq as u16);
+    }
+
+    if let Some(irq) = machine.uart_irq {
+        if irq_map[irq as usize] == IrqMapping::Ignored {
+            irq_map[irq as usize] = IrqMapping::Console;
+        }
+    }
+
+    let mut host_envcfg = 0;
+    if machine.cbom_block_size.is_some() {
+        host_envcfg |= ENVCFG_FIOM | ENVCFG_CBIE | ENVCFG_CBCFE;
+    }
+    if machine.cboz_block_size.is_some() {
+        host_envcfg |= ENVCFG_FIOM | ENVCFG_CBZE;
+    }
+
     let plic_context = machine.harts.iter().find(|h| h.hartid == hartid).unwrap().plic_context;
 
+    let host_irqchip = HostIrqChip::new(machine, plic_context);
+
+    // Under other firmware the CLINT is likely to be protected by PMP.
     let host_clint = match machine.clint_address {
-        Some(address) => HostClint::Direct {
+        Some(address) if !cfg!(feature = "smode_only") => HostClint::Direct {
             mtime: MemoryRegion::with_base_address(pmap::pa2va(address + 0xbff8), 0, 8),
         },
-        None => HostClint::Sbi,
+        _ => HostClint::Sbi,
     };
 
-    let test_finisher = match (guestid, machine.test_finisher_address) {
-        (None, Some(pa)) => Some(TestFinisher {
-            registers: MemoryRegion::with_base_address(pmap::pa2va(pa), 0, 8)
-        }),
-        _ => None,
-    };
+    let rtc = Rtc::new(machine.rtc_address, host_clint.get_mtime());
+
+    let test_finisher = machine.test_finisher_address.map(|pa| TestFinisher {
+        registers: MemoryRegion::with_base_address(pmap::pa2va(pa), 0, 8)
+    });
 
-    let context = Context {
+    let mut context = Context {
         csrs: ControlRegisters {
             sstatus: 0,
             stvec: 0,
@@ -490,8 +983,10 @@ pub unsafe fn initialize(machine: &MachineMeta,
             scause: 0,
             stval: 0,
             satp: 0,
+            senvcfg: 0,
 
             mtimecmp: u64::max_value(),
+            time_offset: 0,
         },
         saved_registers: SavedRegisters {
             registers: MemoryRegion::with_base_address(SSTACK_BASE, 0, 32 * 8)
@@ -504,28 +999,78 @@ pub unsafe fn initialize(machine: &MachineMeta,
             interrupt_enable: 0,
             divisor_latch: 1,
             next_interrupt_time: 0,
+            transmitting: false,
+            thr_empty: false,
             input_fifo: [0; 16],
             input_bytes_ready: 0,
+            break_pending: false,
             line_buffer: ArrayVec::new(),
             guestid,
+            monitor: Console::new(),
         },
         virtio: VirtIO {
             devices: virtio_devices,
             queue_guest_pages: ArrayVec::new(),
+            slot_irqs,
+            host_devices: machine.virtio.clone(),
+            io_limits: (requests_per_sec, bytes_per_sec),
+            hide_features: machine.virtio_hide_features.clone(),
         },
-        guest_shift,
-        smode: true,
+        rtc,
+        guest_map,
+        dma,
+        privilege: PrivilegeState::new(),
+        csr_history: CsrHistory::new(),
         no_interrupt: true,
         host_clint,
-        host_plic: HostPlic {
-            claim_clear: MemoryRegion::with_base_address(
-                pmap::pa2va(machine.plic_address + 0x200004 + 0x1000 * plic_context), 0, 8),
-        },
+        host_irqchip,
+        host_sstc: machine.sstc,
+        host_svpbmt: machine.svpbmt,
+        host_envcfg,
+        console_polled: machine.uart_irq.map_or(true, |irq| irq >= 32),
+        timers: TimerQueue::new(),
+        deferred: DeferredWork::new(),
+        pmu: Pmu::new(machine.sscofpmf),
+        steal: StealTime::new(),
+        icache: IcacheSync::new(),
+        write_combining: WriteCombining::new(),
+        info_page: None,
+        trap_handlers: TrapHandlers::new(),
+        irq_rates: IrqRates::new(machine.irq_limit.unwrap_or(irqrate::DEFAULT_LIMIT)),
+        irq_latency: IrqLatency::new(machine.irq_latency),
+        profile: Profile::new(),
+        watches: Watches::new(),
+        dirty: DirtyLog::new(),
+        handoff: Handoff::new(),
+        fast_sbi: true,
+        symbols,
         consecutive_page_fault_count: 0,
         tlb_caches_invalid_ptes: false,
+        verify_interval: 0,
+        faults_since_verify: 0,
         test_finisher,
+        htif_tohost: machine.htif_address.map(pmap::pa2va),
+        shutdown_exit_code: machine.shutdown_exit_code as u64,
+        crash_policy: machine.crash_policy(guestid.unwrap_or(1)),
+        options: machine.options,
+        control_guest: machine.control_guest != 0 && machine.control_guest as u64 == guestid.unwrap_or(1),
+        guest_os,
+        satp_modes_refused: 0,
         irq_map,
     };
+    if context.console_polled {
+        context.schedule_timer(TimerEvent::ConsolePoll, 0);
+    }
+    context.update_host_envcfg();
+    hvinfo::init(&mut context, guestid.unwrap_or(1));
+    if machine.debug_log {
+        debuglog::init(&mut context);
+    }
+    let now = context.host_clint.get_mtime();
+    context.shadow_page_tables.sync.start(machine.shadow_sync_mode(guestid.unwrap_or(1)), now);
+
+    let restarts = SHARED_STATICS.guest_restarts[guestid.unwrap_or(1) as usize % MAX_GUESTS].load(Ordering::SeqCst);
+    events::record(&context, EventKind::Started, restarts);
 
     // Memory backing for CONTEXT might not be in a valid state, so force_unlock() first, and avoid
     // calling drop on the old contents. This is safe because no other hart will be trying to access
@@ -0,0 +1,171 @@
+//! Guest crash dumps, written as ELF core files that gdb and crash can open.
+//!
+//! When a guest whose crash policy is to dump crashes (see restart.rs), and the hypervisor was built
+//! with the `semihosting` feature, its registers and memory are written to `rvirt-guest<N>.core` on
+//! the host. The file holds:
+//!
+//!   * a PT_NOTE segment with an NT_PRSTATUS note giving the guest's pc and general purpose
+//!     registers, laid out as in a riscv64 Linux core file;
+//!   * a PT_LOAD segment with all of guest memory, at virtual addresses equal to its physical ones;
+//!   * if the guest has paging on, a PT_LOAD segment for each range mapped in the upper half of its
+//!     address space, where the kernel lives, pointing at the same bytes of the file as the memory
+//!     the range maps. This lets a debugger given the kernel's symbols follow its pointers.
+
+use arrayvec::{ArrayString, ArrayVec};
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt::Write;
+use crate::context::Context;
+use crate::hart;
+use crate::pmap::{PTE_EXECUTE, PTE_PPN_MASK, PTE_READ, PTE_VALID, PTE_WRITE};
+use crate::riscv::bits::{SATP_MODE, SATP_PPN};
+use crate::semihosting::HostFile;
+
+const ELF_TYPE_CORE: u16 = 4;
+const ELF_MACHINE_RISCV: u16 = 243;
+const ELF_PROG_LOAD: u32 = 1;
+const ELF_PROG_NOTE: u32 = 4;
+const ELF_PROG_FLAG_RWX: u32 = 7;
+const NT_PRSTATUS: u32 = 1;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Size of `struct elf_prstatus` on riscv64 Linux, and where its pid and registers are.
+const PRSTATUS_SIZE: usize = 376;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+/// The NT_PRSTATUS note: its header, the name "CORE" padded to 8 bytes, then the prstatus.
+const NOTE_SIZE: usize = 12 + 8 + PRSTATUS_SIZE;
+
+/// Guest memory starts at this offset in the file. The headers and the note come before it, which
+/// limits the number of segments.
+const DATA_OFFSET: usize = 4096;
+const MAX_VIRTUAL_SEGMENTS: usize = 48;
+
+const SATP_MODE_SV39: u64 = 8 << 60;
+
+/// A range of guest virtual memory mapped to contiguous guest physical memory.
+struct Segment {
+    va: u64,
+    pa: u64,
+    len: u64,
+}
+
+/// Write a core file for the guest on this hart to the host.
+pub fn write(state: &mut Context) {
+    let guestid = hart::current().guest_index();
+    let mut name = ArrayString::<[u8; 32]>::new();
+    let _ = write!(name, "rvirt-guest{}.core", guestid);
+    let mut file = match HostFile::create(&name) {
+        Some(file) => file,
+        None => {
+            println!("Unable to create {} on the host (core dumps need semihosting)", name);
+            return;
+        }
+    };
+
+    let (base, len) = (state.guest_memory.base(), state.guest_memory.len());
+    let segments = kernel_segments(state);
+    let phnum = 2 + segments.len();
+
+    let mut headers = [0u8; DATA_OFFSET];
+    headers[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    LittleEndian::write_u16(&mut headers[16..], ELF_TYPE_CORE);
+    LittleEndian::write_u16(&mut headers[18..], ELF_MACHINE_RISCV);
+    LittleEndian::write_u32(&mut headers[20..], 1);
+    LittleEndian::write_u64(&mut headers[32..], ELF_HEADER_SIZE as u64);
+    LittleEndian::write_u16(&mut headers[52..], ELF_HEADER_SIZE as u16);
+    LittleEndian::write_u16(&mut headers[54..], PROGRAM_HEADER_SIZE as u16);
+    LittleEndian::write_u16(&mut headers[56..], phnum as u16);
+
+    let notes_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+    let mut program_headers = headers[ELF_HEADER_SIZE..notes_offset].chunks_exact_mut(PROGRAM_HEADER_SIZE);
+    program_header(program_headers.next().unwrap(), ELF_PROG_NOTE, 0, notes_offset as u64, 0, 0, NOTE_SIZE as u64);
+    program_header(program_headers.next().unwrap(), ELF_PROG_LOAD, ELF_PROG_FLAG_RWX, DATA_OFFSET as u64,
+                   base, base, len);
+    for (ph, s) in program_headers.zip(&segments) {
+        program_header(ph, ELF_PROG_LOAD, ELF_PROG_FLAG_RWX, DATA_OFFSET as u64 + s.pa - base, s.va, s.pa, s.len);
+    }
+
+    let note = &mut headers[notes_offset..(notes_offset + NOTE_SIZE)];
+    LittleEndian::write_u32(&mut note[0..], 5);
+    LittleEndian::write_u32(&mut note[4..], PRSTATUS_SIZE as u32);
+    LittleEndian::write_u32(&mut note[8..], NT_PRSTATUS);
+    note[12..17].copy_from_slice(b"CORE\0");
+    let prstatus = &mut note[20..];
+    LittleEndian::write_u32(&mut prstatus[PRSTATUS_PID..], guestid as u32);
+    LittleEndian::write_u64(&mut prstatus[PRSTATUS_REGS..], csrr!(sepc));
+    for i in 1..32 {
+        LittleEndian::write_u64(&mut prstatus[(PRSTATUS_REGS + 8 * i)..], state.saved_registers.get(i as u32));
+    }
+
+    println!("Writing a core dump of guest {} to {} ({} MB)", guestid, name, len >> 20);
+    let mut ok = file.write(&headers);
+    let mut pa = base;
+    while ok && pa < base + len {
+        ok = match state.read_guest_page(pa, 0x1000) {
+            Some(page) => file.write(page),
+            None => false,
+        };
+        pa += 0x1000;
+    }
+    if !ok {
+        println!("Unable to write {}", name);
+    }
+}
+
+fn program_header(ph: &mut [u8], type_: u32, flags: u32, offset: u64, va: u64, pa: u64, size: u64) {
+    LittleEndian::write_u32(&mut ph[0..], type_);
+    LittleEndian::write_u32(&mut ph[4..], flags);
+    LittleEndian::write_u64(&mut ph[8..], offset);
+    LittleEndian::write_u64(&mut ph[16..], va);
+    LittleEndian::write_u64(&mut ph[24..], pa);
+    LittleEndian::write_u64(&mut ph[32..], size);
+    LittleEndian::write_u64(&mut ph[40..], size);
+    LittleEndian::write_u64(&mut ph[48..], 0x1000);
+}
+
+/// The ranges mapped in the upper half of the guest's current address space, as far as there is
+/// room for them.
+fn kernel_segments(state: &mut Context) -> ArrayVec<[Segment; MAX_VIRTUAL_SEGMENTS]> {
+    let mut segments = ArrayVec::new();
+    if state.csrs.satp & SATP_MODE == SATP_MODE_SV39 {
+        let root = (state.csrs.satp & SATP_PPN) << 12;
+        walk(state, root, 2, 0, &mut segments);
+    }
+    segments
+}
+
+fn walk(state: &mut Context, table: u64, level: u32, table_va: u64,
+        segments: &mut ArrayVec<[Segment; MAX_VIRTUAL_SEGMENTS]>) {
+    let first = if level == 2 { 256 } else { 0 };
+    for i in first..512 {
+        let pte = match state.read_guest_page(table + i * 8, 8) {
+            Some(bytes) => LittleEndian::read_u64(bytes),
+            None => return,
+        };
+        if pte & PTE_VALID == 0 {
+            continue;
+        }
+        // Sv39 addresses are sign extended from bit 38.
+        let va = ((((table_va + (i << (12 + 9 * level))) << 25) as i64) >> 25) as u64;
+        let pa = (pte & PTE_PPN_MASK) << 2;
+        if pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE) == 0 {
+            if level > 0 {
+                walk(state, pa, level - 1, va, segments);
+            }
+            continue;
+        }
+
+        let len = 1 << (12 + 9 * level);
+        if !state.guest_memory.in_region(pa) || !state.guest_memory.in_region(pa + len - 1) {
+            continue;
+        }
+        match segments.last_mut() {
+            Some(last) if last.va + last.len == va && last.pa + last.len == pa => last.len += len,
+            _ => if segments.try_push(Segment { va, pa, len }).is_err() {
+                return;
+            },
+        }
+    }
+}
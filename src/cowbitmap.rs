@@ -0,0 +1,130 @@
+//! The bitmaps of copy-on-write overlays (see overlay.rs), which record the sectors of the base
+//! disk that a guest has written.
+//!
+//! A bit may only be set once the sector it stands for holds the guest's data on the overlay disk,
+//! so `mark_written` puts a barrier between the two. Bits are never cleared.
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::error::Result;
+
+/// Sectors of the base disk covered by each sector of a bitmap.
+const SECTORS_PER_BITMAP_SECTOR: u64 = SECTOR_SIZE * 8;
+
+/// Sectors taken up by the bitmap of a base disk with `sectors` sectors.
+pub fn bitmap_sectors(sectors: u64) -> u64 {
+    (sectors + SECTORS_PER_BITMAP_SECTOR - 1) / SECTORS_PER_BITMAP_SECTOR
+}
+
+/// A bitmap held on a disk, starting at sector `start`, with the sector of it last used cached.
+pub struct CowBitmap {
+    start: u64,
+    /// The bitmap sector last used, and whether it has changes not yet written back.
+    cache: [u8; SECTOR_SIZE as usize],
+    cache_sector: Option<u64>,
+    cache_dirty: bool,
+}
+
+impl CowBitmap {
+    pub fn new(start: u64) -> Self {
+        Self { start, cache: [0; SECTOR_SIZE as usize], cache_sector: None, cache_dirty: false }
+    }
+
+    /// Load the bitmap sector covering `sector` into the cache, writing back the one there before.
+    fn load<D: BlockDevice>(&mut self, disk: &mut D, sector: u64) -> Result<()> {
+        let bitmap_sector = self.start + sector / SECTORS_PER_BITMAP_SECTOR;
+        if self.cache_sector != Some(bitmap_sector) {
+            self.store(disk)?;
+            self.cache_sector = None;
+            disk.read(bitmap_sector, &mut self.cache)?;
+            self.cache_sector = Some(bitmap_sector);
+        }
+        Ok(())
+    }
+
+    /// Write back the cached bitmap sector, if it has changed.
+    pub fn store<D: BlockDevice>(&mut self, disk: &mut D) -> Result<()> {
+        if let (true, Some(bitmap_sector)) = (self.cache_dirty, self.cache_sector) {
+            disk.write(bitmap_sector, &self.cache)?;
+            self.cache_dirty = false;
+        }
+        Ok(())
+    }
+
+    pub fn is_written<D: BlockDevice>(&mut self, disk: &mut D, sector: u64) -> Result<bool> {
+        self.load(disk, sector)?;
+        let bit = sector % SECTORS_PER_BITMAP_SECTOR;
+        Ok(self.cache[bit as usize / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Set the bits of `count` sectors from `sector`, whose contents have just been written to
+    /// `disk`, and write the bitmap back.
+    pub fn mark_written<D: BlockDevice>(&mut self, disk: &mut D, sector: u64, count: u64) -> Result<()> {
+        let mut ordered = false;
+        for s in sector..sector + count {
+            if !self.is_written(disk, s)? {
+                // Sectors already in the overlay only had their contents replaced, so the barrier
+                // is only needed before the first new bit.
+                if !ordered {
+                    disk.barrier()?;
+                    ordered = true;
+                }
+                let bit = s % SECTORS_PER_BITMAP_SECTOR;
+                self.cache[bit as usize / 8] |= 1 << (bit % 8);
+                self.cache_dirty = true;
+            }
+        }
+        self.store(disk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::{MockDisk, Op};
+
+    #[test]
+    fn bits_are_set_after_a_barrier() {
+        let mut disk = MockDisk::new(64);
+        let mut bitmap = CowBitmap::new(0);
+        disk.write(8, &[1; 1024]).unwrap();
+        bitmap.mark_written(&mut disk, 3, 2).unwrap();
+        assert_eq!(disk.ops, [Op::Write(8, 2), Op::Barrier, Op::Write(0, 1)]);
+        assert_eq!(disk.data[0], 0b11000);
+
+        // Rewriting sectors already in the overlay doesn't touch the bitmap.
+        disk.ops.clear();
+        disk.write(8, &[2; 512]).unwrap();
+        bitmap.mark_written(&mut disk, 3, 1).unwrap();
+        assert_eq!(disk.ops, [Op::Write(8, 1)]);
+
+        // Only the bit of the new sector is written, but after the barrier.
+        disk.ops.clear();
+        bitmap.mark_written(&mut disk, 4, 2).unwrap();
+        assert_eq!(disk.ops, [Op::Barrier, Op::Write(0, 1)]);
+        assert_eq!(disk.data[0], 0b111000);
+    }
+
+    #[test]
+    fn bits_spanning_bitmap_sectors() {
+        let mut disk = MockDisk::new(64);
+        let mut bitmap = CowBitmap::new(2);
+        let first = SECTORS_PER_BITMAP_SECTOR - 1;
+        bitmap.mark_written(&mut disk, first, 2).unwrap();
+        assert_eq!(disk.ops, [Op::Barrier, Op::Write(2, 1), Op::Write(3, 1)]);
+        assert_eq!(disk.data[3 * 512 - 1], 0x80);
+        assert_eq!(disk.data[3 * 512], 0x01);
+
+        // A fresh bitmap reads back what was stored.
+        let mut bitmap = CowBitmap::new(2);
+        let written: Vec<_> = (first - 1..first + 3).map(|s| bitmap.is_written(&mut disk, s).unwrap()).collect();
+        assert_eq!(written, [false, true, true, false]);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(bitmap_sectors(0), 0);
+        assert_eq!(bitmap_sectors(1), 1);
+        assert_eq!(bitmap_sectors(4096), 1);
+        assert_eq!(bitmap_sectors(4097), 2);
+    }
+}
@@ -0,0 +1,30 @@
+//! A write-only window that guests can print to without trapping on every byte.
+//!
+//! With the `rvirt,debug-log` property in the host's /chosen, every guest's device tree gets a
+//! `/debug-log` node, compatible with `"rvirt,debug-log"`, whose `reg` is `DEBUG_LOG_SIZE` bytes at
+//! `DEBUG_LOG_BASE`. Text the guest stores there goes to its console, just as if it had been written
+//! to the UART one byte at a time, but the window is write combined (see mmio.rs): each page traps
+//! once, and what the guest wrote is picked up at the next flush.
+//!
+//! The guest is expected to write its output sequentially, wrapping around at the end. Only bytes
+//! that differ from what the window held at the last flush are seen, so text rewritten at the same
+//! place before a flush goes unnoticed, and NUL bytes are dropped, which lets the guest clear the
+//! window without printing anything. Reads return whatever the guest itself last wrote.
+
+use crate::constants::{DEBUG_LOG_BASE, DEBUG_LOG_SIZE};
+use crate::context::Context;
+use crate::mmio;
+
+/// Register the window of the guest about to start. Without room for it, accesses fault like
+/// accesses to any other address without a device.
+pub fn init(state: &mut Context) {
+    if let Err(e) = mmio::register(state, DEBUG_LOG_BASE, DEBUG_LOG_SIZE, flush) {
+        println!("WARN: No room for the debug log window: {:?}", e);
+    }
+}
+
+fn flush(state: &mut Context, _offset: u64, bytes: &[u8]) {
+    for &b in bytes.iter().filter(|&&b| b != 0) {
+        state.uart.output_byte(b);
+    }
+}
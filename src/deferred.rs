@@ -0,0 +1,78 @@
+//! Work that trap handlers leave for later, to be done just before returning to the guest.
+//!
+//! Handling an interrupt or a device register access should only record what needs doing, so that
+//! the handler itself stays short. Everything recorded is then done in one pass at the end of the
+//! trap, which also batches requests: a device that interrupts several times before the pass, or a
+//! guest that notifies several throttled queues, is only processed once. Each hart runs a single
+//! guest with its own `Context`, so the queue needs no locking.
+
+use crate::context::Context;
+use crate::timer::TimerEvent;
+use crate::{trap, virtio};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Work {
+    /// An emulated device interrupted and its used buffers need processing.
+    DeviceInterrupt { device_index: usize, guest_irq: u16 },
+    /// An emulated device may have completed buffers and need its guest interrupt raised.
+    EmulatedInterrupts,
+    /// Queue notifications held back by I/O throttling may now be allowed through.
+    ThrottledNotifications,
+}
+
+const EMULATED_INTERRUPTS: u32 = 1 << 0;
+const THROTTLED_NOTIFICATIONS: u32 = 1 << 1;
+
+pub struct DeferredWork {
+    /// The guest interrupt of every virtio device with an interrupt to process.
+    devices: [Option<u16>; virtio::MAX_DEVICES],
+    /// Other kinds of work, one bit each.
+    other: u32,
+}
+
+impl DeferredWork {
+    pub const fn new() -> Self {
+        Self { devices: [None; virtio::MAX_DEVICES], other: 0 }
+    }
+
+    pub fn raise(&mut self, work: Work) {
+        match work {
+            Work::DeviceInterrupt { device_index, guest_irq } => self.devices[device_index] = Some(guest_irq),
+            Work::EmulatedInterrupts => self.other |= EMULATED_INTERRUPTS,
+            Work::ThrottledNotifications => self.other |= THROTTLED_NOTIFICATIONS,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.iter().all(Option::is_none) && self.other == 0
+    }
+}
+
+/// Do all outstanding work. Returns whether there was any, in which case a guest interrupt may have
+/// become pending.
+pub fn run(state: &mut Context) -> bool {
+    if state.deferred.is_empty() {
+        return false;
+    }
+
+    // Processing a device can leave emulated interrupts to raise, so this may take a second pass.
+    while !state.deferred.is_empty() {
+        let DeferredWork { devices, other } = core::mem::replace(&mut state.deferred, DeferredWork::new());
+
+        for (device_index, guest_irq) in devices.iter().enumerate() {
+            if let Some(guest_irq) = *guest_irq {
+                trap::process_device_interrupt(state, device_index, guest_irq);
+            }
+        }
+        if other & THROTTLED_NOTIFICATIONS != 0 {
+            let now = state.host_clint.get_mtime();
+            if let Some(retry) = virtio::poll_throttled(state, now) {
+                state.schedule_timer(TimerEvent::ThrottleRetry, retry);
+            }
+        }
+        if other & EMULATED_INTERRUPTS != 0 {
+            virtio::update_emulated_interrupts(state);
+        }
+    }
+    true
+}
@@ -0,0 +1,220 @@
+//! A check at boot that M-mode delegates what the hypervisor expects, and forwards the rest
+//! correctly, for builds with `RVIRT_DELEGATION_AUDIT=1`.
+//!
+//! The hypervisor only sees the exceptions that `mstart` delegates through medeleg, or that the
+//! M-mode trap handler forwards with `forward_exception`, so the masks in `constants` and the
+//! handlers registered in dispatch.rs have to agree. The audit first compares the delegation
+//! registers with both, and then takes each class of exception it can raise from S-mode twice:
+//! once delegated, and once with its medeleg bit cleared so that M-mode has to forward it. The
+//! `scause`, `sepc`, `stval` and previous privilege seen by the hypervisor must be the same.
+//!
+//! Environment calls from U-mode and access faults can't be raised from here, and neither can
+//! misaligned instruction fetches on harts with compressed instructions, so they are only covered
+//! by the comparison of the masks. Interrupts are never undelegated.
+//!
+//! Changing medeleg needs a call into the M-mode code of rvirt-bare-metal, so with any other
+//! firmware the audit is skipped. Any problem found panics, so that automated runs fail.
+
+use crate::constants::{DELEGATED_EXCEPTIONS, DELEGATED_INTERRUPTS};
+use crate::dispatch::TrapHandlers;
+use crate::riscv::bits::*;
+use crate::riscv::sbi;
+
+/// Not mapped in any page table, nor even canonical for Sv39 or Sv48.
+const UNMAPPED_ADDRESS: u64 = 0x4000_0000_0000_0000;
+
+/// What the hypervisor saw of a trap. `cause` is zero if nothing trapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Observed {
+    cause: u64,
+    epc: u64,
+    tval: u64,
+    /// The bits of `sstatus` that taking the trap sets.
+    sstatus: u64,
+}
+
+struct Check {
+    name: &'static str,
+    /// Causes the processor may report.
+    causes: &'static [u64],
+    trigger: unsafe fn() -> Observed,
+}
+
+const CHECKS: [Check; 6] = [
+    Check { name: "illegal instruction", causes: &[SCAUSE_ILLEGAL_INSN], trigger: illegal_instruction },
+    Check { name: "breakpoint", causes: &[SCAUSE_BREAKPOINT], trigger: breakpoint },
+    Check { name: "instruction page fault", causes: &[SCAUSE_INSN_PAGE_FAULT], trigger: instruction_page_fault },
+    Check { name: "load page fault", causes: &[SCAUSE_LOAD_PAGE_FAULT], trigger: load_page_fault },
+    Check { name: "store page fault", causes: &[SCAUSE_STORE_PAGE_FAULT], trigger: store_page_fault },
+    Check {
+        name: "misaligned atomic",
+        causes: &[SCAUSE_ATOMIC_MISALIGNED, SCAUSE_STORE_ACCESS_FAULT],
+        trigger: misaligned_atomic,
+    },
+];
+
+/// Run the audit. Must be called on the boot hart, with interrupts disabled, before any guest runs.
+pub fn run() {
+    let (medeleg, mideleg) = match sbi::update_medeleg(0, 0) {
+        Some(delegation) => delegation,
+        None => {
+            println!("Delegation audit: firmware can't change medeleg, skipping");
+            return;
+        }
+    };
+
+    let mut problems = 0;
+    if medeleg != DELEGATED_EXCEPTIONS {
+        println!("Delegation audit: medeleg is {:#x}, expected {:#x}", medeleg, DELEGATED_EXCEPTIONS);
+        problems += 1;
+    }
+    if mideleg != DELEGATED_INTERRUPTS {
+        println!("Delegation audit: mideleg is {:#x}, expected {:#x}", mideleg, DELEGATED_INTERRUPTS);
+        problems += 1;
+    }
+    let handled = TrapHandlers::new().exception_causes();
+    for cause in 0..64 {
+        if handled & 1 << cause != 0 && DELEGATED_EXCEPTIONS & 1 << cause == 0 {
+            println!("Delegation audit: cause {} has a handler but isn't delegated", cause);
+            problems += 1;
+        }
+    }
+
+    for check in &CHECKS {
+        let delegated = unsafe { (check.trigger)() };
+        if delegated.cause == 0 {
+            println!("Delegation audit: {} didn't trap, skipping", check.name);
+            continue;
+        }
+        if !check.causes.contains(&delegated.cause) {
+            println!("Delegation audit: {} trapped with cause {}", check.name, delegated.cause);
+            problems += 1;
+            continue;
+        }
+
+        let bit = 1 << delegated.cause;
+        sbi::update_medeleg(bit, 0);
+        let forwarded = unsafe { (check.trigger)() };
+        sbi::update_medeleg(0, medeleg & bit);
+
+        if forwarded != delegated {
+            println!("Delegation audit: {} forwarded as {:x?}, delegated as {:x?}", check.name, forwarded, delegated);
+            problems += 1;
+        } else if delegated.sstatus & STATUS_SPP == 0 {
+            println!("Delegation audit: {} taken as if from U-mode", check.name);
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        panic!("Delegation audit found {} problems", problems);
+    }
+    println!("Delegation audit passed");
+}
+
+// Each trigger points stvec just past the instruction that traps, so that the trap lands there
+// whether it was delegated or forwarded, and then reads back what the trap left in the CSRs.
+
+unsafe fn illegal_instruction() -> Observed {
+    let (cause, epc, tval, sstatus): (u64, u64, u64, u64);
+    asm!("lla t0, 1f
+          csrrw t0, stvec, t0
+          csrw scause, zero
+          csrr t1, mstatus
+          .align 2
+      1:  csrw stvec, t0
+          csrr $0, scause
+          csrr $1, sepc
+          csrr $2, stval
+          csrr $3, sstatus"
+         : "=r"(cause), "=r"(epc), "=r"(tval), "=r"(sstatus) :: "t0", "t1" : "volatile");
+    Observed { cause, epc, tval, sstatus: sstatus & (STATUS_SPP | STATUS_SPIE) }
+}
+
+unsafe fn breakpoint() -> Observed {
+    let (cause, epc, tval, sstatus): (u64, u64, u64, u64);
+    asm!("lla t0, 1f
+          csrrw t0, stvec, t0
+          csrw scause, zero
+          ebreak
+          .align 2
+      1:  csrw stvec, t0
+          csrr $0, scause
+          csrr $1, sepc
+          csrr $2, stval
+          csrr $3, sstatus"
+         : "=r"(cause), "=r"(epc), "=r"(tval), "=r"(sstatus) :: "t0" : "volatile");
+    Observed { cause, epc, tval, sstatus: sstatus & (STATUS_SPP | STATUS_SPIE) }
+}
+
+unsafe fn instruction_page_fault() -> Observed {
+    let (cause, epc, tval, sstatus): (u64, u64, u64, u64);
+    asm!("lla t0, 1f
+          csrrw t0, stvec, t0
+          csrw scause, zero
+          li t1, $4
+          jalr t1
+          .align 2
+      1:  csrw stvec, t0
+          csrr $0, scause
+          csrr $1, sepc
+          csrr $2, stval
+          csrr $3, sstatus"
+         : "=r"(cause), "=r"(epc), "=r"(tval), "=r"(sstatus) : "i"(UNMAPPED_ADDRESS) : "t0", "t1", "ra"
+         : "volatile");
+    Observed { cause, epc, tval, sstatus: sstatus & (STATUS_SPP | STATUS_SPIE) }
+}
+
+unsafe fn load_page_fault() -> Observed {
+    let (cause, epc, tval, sstatus): (u64, u64, u64, u64);
+    asm!("lla t0, 1f
+          csrrw t0, stvec, t0
+          csrw scause, zero
+          li t1, $4
+          ld t1, 0(t1)
+          .align 2
+      1:  csrw stvec, t0
+          csrr $0, scause
+          csrr $1, sepc
+          csrr $2, stval
+          csrr $3, sstatus"
+         : "=r"(cause), "=r"(epc), "=r"(tval), "=r"(sstatus) : "i"(UNMAPPED_ADDRESS) : "t0", "t1" : "volatile");
+    Observed { cause, epc, tval, sstatus: sstatus & (STATUS_SPP | STATUS_SPIE) }
+}
+
+unsafe fn store_page_fault() -> Observed {
+    let (cause, epc, tval, sstatus): (u64, u64, u64, u64);
+    asm!("lla t0, 1f
+          csrrw t0, stvec, t0
+          csrw scause, zero
+          li t1, $4
+          sd zero, 0(t1)
+          .align 2
+      1:  csrw stvec, t0
+          csrr $0, scause
+          csrr $1, sepc
+          csrr $2, stval
+          csrr $3, sstatus"
+         : "=r"(cause), "=r"(epc), "=r"(tval), "=r"(sstatus) : "i"(UNMAPPED_ADDRESS) : "t0", "t1" : "volatile");
+    Observed { cause, epc, tval, sstatus: sstatus & (STATUS_SPP | STATUS_SPIE) }
+}
+
+/// Harts may handle misaligned loads and stores, but atomics must trap. Only the address is used.
+static mut ATOMIC_TARGET: [u64; 2] = [0; 2];
+
+unsafe fn misaligned_atomic() -> Observed {
+    let (cause, epc, tval, sstatus): (u64, u64, u64, u64);
+    let address = ATOMIC_TARGET.as_ptr() as u64 + 1;
+    asm!("lla t0, 1f
+          csrrw t0, stvec, t0
+          csrw scause, zero
+          amoadd.w zero, zero, ($4)
+          .align 2
+      1:  csrw stvec, t0
+          csrr $0, scause
+          csrr $1, sepc
+          csrr $2, stval
+          csrr $3, sstatus"
+         : "=r"(cause), "=r"(epc), "=r"(tval), "=r"(sstatus) : "r"(address) : "t0", "memory" : "volatile");
+    Observed { cause, epc, tval, sstatus: sstatus & (STATUS_SPP | STATUS_SPIE) }
+}
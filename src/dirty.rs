@@ -0,0 +1,161 @@
+//! Tracking of which guest pages have been written, for copying the memory of a running guest in
+//! rounds, as pre-copy migration and incremental snapshots do.
+//!
+//! While tracking is on, shadow mappings of guest memory are only made writable for a store, which
+//! marks the page dirty in a bitmap. `fetch_and_clear` hands out the bitmap and clears it, removing
+//! every shadow mapping so that the next store to each page faults and marks it again. Stores that
+//! the hypervisor makes for the guest, whether emulated or through
+//! `Context::prepare_guest_access`, are marked as well. While the guest has paging off its memory is
+//! mapped writable up front, so every page counts as dirty until paging is back on.
+//!
+//! Stores made by devices, passed through or emulated, aren't seen, so they have to be idle before
+//! a copy of memory can be relied on.
+//!
+//! The monitor's `dirty` command turns tracking on and off and shows how quickly the guest is
+//! dirtying memory.
+
+use crate::constants::{MAX_GUEST_SEGMENTS, TIMER_FREQUENCY};
+use crate::context::Context;
+use crate::memory_region::MemoryRegion;
+use crate::pmap::{self, PageTableRoot};
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_GUEST_PAGES: usize = (pmap::HART_SEGMENT_SIZE / PAGE_SIZE) as usize * MAX_GUEST_SEGMENTS;
+/// Words of the bitmap, each covering 64 pages.
+pub const BITMAP_WORDS: usize = MAX_GUEST_PAGES / 64;
+
+pub struct DirtyLog {
+    enabled: bool,
+    bitmap: [u64; BITMAP_WORDS],
+    /// Pages marked since the current round started, and when it did. A round starts whenever the
+    /// bitmap is fetched from its first word.
+    round_pages: u64,
+    round_start: u64,
+    /// Pages per second marked during the last complete round.
+    last_rate: Option<u64>,
+}
+
+fn page_index(guest_memory: &MemoryRegion, guest_pa: u64) -> usize {
+    ((guest_pa - guest_memory.base()) / PAGE_SIZE) as usize
+}
+
+impl DirtyLog {
+    pub fn new() -> Self {
+        Self { enabled: false, bitmap: [0; BITMAP_WORDS], round_pages: 0, round_start: 0, last_rate: None }
+    }
+
+    #[inline(always)]
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Mark the page holding `guest_pa` as written, if tracking is on.
+    #[inline(always)]
+    pub fn mark(&mut self, guest_memory: &MemoryRegion, guest_pa: u64) {
+        if self.enabled && guest_memory.in_region(guest_pa) {
+            let index = page_index(guest_memory, guest_pa);
+            let word = &mut self.bitmap[index / 64];
+            if *word & 1 << (index % 64) == 0 {
+                *word |= 1 << (index % 64);
+                self.round_pages += 1;
+            }
+        }
+    }
+
+    fn mark_all(&mut self, guest_memory: &MemoryRegion) {
+        let pages = (guest_memory.len() / PAGE_SIZE) as usize;
+        for (i, word) in self.bitmap.iter_mut().enumerate().take((pages + 63) / 64) {
+            let remaining = pages - i * 64;
+            *word = if remaining >= 64 { u64::max_value() } else { (1 << remaining) - 1 };
+        }
+    }
+
+    /// Pages currently marked.
+    pub fn count(&self) -> u64 {
+        self.bitmap.iter().map(|w| w.count_ones() as u64).sum()
+    }
+}
+
+/// Start tracking with every page marked, since nothing has been copied yet.
+pub fn enable(state: &mut Context) {
+    let now = state.host_clint.get_mtime();
+    let log = &mut state.dirty;
+    log.enabled = true;
+    log.mark_all(&state.guest_memory);
+    log.round_pages = 0;
+    log.round_start = now;
+    log.last_rate = None;
+
+    // Existing writable mappings would let stores through unseen.
+    pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+}
+
+/// Stop tracking. Shadow mappings made from now on are writable as usual.
+pub fn disable(state: &mut Context) {
+    state.dirty.enabled = false;
+    for word in state.dirty.bitmap.iter_mut() {
+        *word = 0;
+    }
+}
+
+/// The guest has turned paging off, which maps all of its memory writable.
+pub fn paging_disabled(state: &mut Context) {
+    if state.dirty.enabled {
+        state.dirty.mark_all(&state.guest_memory);
+    }
+}
+
+/// Copy words of the bitmap, starting with word `first_word`, into `words` and clear them, so that
+/// only pages written from now on are marked. Bit `n` of word `w` is the page at `n + 64 * w`
+/// pages into guest memory. Returns how many words were copied, which is fewer than asked for at
+/// the end of the bitmap.
+pub fn fetch_and_clear(state: &mut Context, first_word: usize, words: &mut [u64]) -> usize {
+    let now = state.host_clint.get_mtime();
+    let paging = state.shadow() != PageTableRoot::MPA;
+    let log = &mut state.dirty;
+    if first_word == 0 {
+        let elapsed = now.saturating_sub(log.round_start);
+        if elapsed > 0 {
+            log.last_rate = Some(log.round_pages * TIMER_FREQUENCY / elapsed);
+        }
+        log.round_pages = 0;
+        log.round_start = now;
+    }
+
+    if first_word >= BITMAP_WORDS {
+        return 0;
+    }
+
+    let count = words.len().min(BITMAP_WORDS - first_word);
+    let mut cleared = false;
+    for (dst, word) in words.iter_mut().zip(log.bitmap[first_word..].iter_mut()).take(count) {
+        *dst = *word;
+        // Without paging every page may be written at any time, so they all stay marked.
+        if paging && *word != 0 {
+            *word = 0;
+            cleared = true;
+        }
+    }
+    if cleared {
+        pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+    }
+    count
+}
+
+/// Print whether tracking is on and how quickly pages are being dirtied, for the monitor's `dirty`
+/// command.
+pub fn report(state: &Context) {
+    let log = &state.dirty;
+    if !log.enabled {
+        println!("not tracking, use `dirty on` to start");
+        return;
+    }
+    let elapsed = state.host_clint.get_mtime().saturating_sub(log.round_start);
+    println!("{} pages dirty", log.count());
+    if elapsed > 0 {
+        println!("{} pages/s dirtied in this round", log.round_pages * TIMER_FREQUENCY / elapsed);
+    }
+    if let Some(rate) = log.last_rate {
+        println!("{} pages/s dirtied in the last round", rate);
+    }
+}
@@ -0,0 +1,132 @@
+//! Which code handles each kind of trap from the guest.
+//!
+//! Rather than one match over `scause` in `trap::strap`, every subsystem that handles exits
+//! registers a `TrapHandler` for the causes it cares about, with a priority. When a trap comes in,
+//! the handlers registered for its cause run in order of priority, lowest first, until one of them
+//! takes it. Any exception nobody takes is forwarded to the guest.
+//!
+//! A handler can decline a trap by returning false without having changed anything, which is how
+//! the semihosting handler leaves ordinary breakpoints to the guest. Anything new that needs to
+//! see a kind of exit, like breakpoints set by a debugger, can register ahead of the handlers that
+//! are already there instead of editing them.
+
+use arrayvec::ArrayVec;
+use crate::context::Context;
+use crate::error::Result;
+use crate::exits::ExitReason;
+use crate::riscv::bits::*;
+use crate::{emulate, pfault, sbi, semihosting, trap};
+
+const MAX_HANDLERS: usize = 16;
+
+/// Matches every interrupt, whatever its number.
+pub const ANY_INTERRUPT: u64 = 1 << 63;
+
+/// A trap taken while the guest was running.
+pub struct Trap {
+    pub cause: u64,
+    pub pc: u64,
+    /// The instruction that trapped and its length, for the causes where the processor must have
+    /// been able to fetch it. Reading it can still fail if the page is execute-only or the second
+    /// half of the instruction is on another page.
+    pub instruction: Option<Result<(u32, u64)>>,
+}
+
+impl Trap {
+    pub fn is_interrupt(&self) -> bool {
+        (self.cause as isize) < 0
+    }
+}
+
+/// Returns whether it took the trap.
+pub type HandlerFn = fn(&mut Context, &Trap) -> bool;
+
+#[derive(Copy, Clone)]
+pub struct TrapHandler {
+    pub cause: u64,
+    pub priority: u8,
+    pub name: &'static str,
+    pub handle: HandlerFn,
+}
+
+impl TrapHandler {
+    fn matches(&self, cause: u64) -> bool {
+        self.cause == cause || (self.cause == ANY_INTERRUPT && (cause as isize) < 0)
+    }
+}
+
+pub struct TrapHandlers {
+    /// Kept sorted by priority.
+    handlers: ArrayVec<[TrapHandler; MAX_HANDLERS]>,
+}
+
+impl TrapHandlers {
+    /// The handlers every guest has.
+    pub fn new() -> Self {
+        let mut handlers = Self { handlers: ArrayVec::new() };
+        handlers.insert(TrapHandler {
+            cause: ANY_INTERRUPT, priority: 100, name: "interrupts", handle: trap::handle_interrupt_trap,
+        });
+        for &cause in &[SCAUSE_INSN_PAGE_FAULT, SCAUSE_LOAD_PAGE_FAULT, SCAUSE_STORE_PAGE_FAULT] {
+            handlers.insert(TrapHandler { cause, priority: 100, name: "page faults", handle: pfault::handle_trap });
+        }
+        handlers.insert(TrapHandler {
+            cause: SCAUSE_ILLEGAL_INSN, priority: 100, name: "instruction emulation", handle: emulate::handle_trap,
+        });
+        handlers.insert(TrapHandler { cause: SCAUSE_ENV_CALL, priority: 100, name: "sbi", handle: sbi::handle_trap });
+        handlers.insert(TrapHandler {
+            cause: SCAUSE_BREAKPOINT, priority: 100, name: "semihosting", handle: semihosting::handle_trap,
+        });
+        handlers
+    }
+
+    /// The exceptions that have a handler, one bit per cause, which M-mode has to delegate for them
+    /// to reach the hypervisor.
+    pub fn exception_causes(&self) -> u64 {
+        self.handlers.iter().filter(|h| h.cause < 64).fold(0, |causes, h| causes | 1 << h.cause)
+    }
+
+    /// Add `handler` after any others of the same or lower priority. Returns false if the table is
+    /// full.
+    fn insert(&mut self, handler: TrapHandler) -> bool {
+        if self.handlers.is_full() {
+            return false;
+        }
+        let index = self.handlers.iter().position(|h| h.priority > handler.priority).unwrap_or(self.handlers.len());
+        self.handlers.insert(index, handler);
+        true
+    }
+}
+
+/// Register `handler` with this hart's guest. Returns false if there are already too many.
+pub fn register(state: &mut Context, handler: TrapHandler) -> bool {
+    state.trap_handlers.insert(handler)
+}
+
+/// Run the handlers for `trap` until one takes it, and forward it to the guest if none does.
+pub fn dispatch(state: &mut Context, trap: &Trap) {
+    for i in 0..state.trap_handlers.handlers.len() {
+        let handler = state.trap_handlers.handlers[i];
+        if handler.matches(trap.cause) && (handler.handle)(state, trap) {
+            return;
+        }
+    }
+
+    assert!(!trap.is_interrupt());
+    state.record_exit(ExitReason::ForwardedException);
+    if trap.cause != SCAUSE_ENV_CALL { // no need to print anything for guest syscalls...
+        println!("Forward exception (cause = {}, smode={})!", trap.cause, state.smode());
+    }
+    trap::forward_exception(state, trap.cause, trap.pc);
+}
+
+/// Print the registered handlers, for the monitor's `handlers` command.
+pub fn report(state: &Context) {
+    for handler in &state.trap_handlers.handlers {
+        if handler.cause == ANY_INTERRUPT {
+            println!("{:>3} interrupts  {}", handler.priority, handler.name);
+        } else {
+            println!("{:>3} cause {:<4} {}", handler.priority, handler.cause, handler.name);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Physically contiguous memory for device rings and bounce buffers.
+//!
+//! Each hart sets aside a small part of its heap as a DMA pool. Allocations are made in units of
+//! cache lines, so a buffer never shares a line with its neighbours, and can be given any
+//! power-of-two alignment. Every allocation is tagged with the name of its owner so that buffers
+//! which are never returned can be tracked down.
+
+use arrayvec::ArrayVec;
+use crate::error::{Error, Result};
+use crate::memory_region::MemoryRegion;
+use crate::pmap;
+
+const CACHE_LINE: u64 = 64;
+const MAX_LINES: usize = 8192;
+const MAX_ALLOCATIONS: usize = 64;
+
+/// A buffer handed out by a `DmaPool`. Buffers are not returned automatically; pass them back to
+/// `DmaPool::free` once the device no longer uses them.
+#[derive(Debug, Eq, PartialEq)]
+pub struct DmaBuffer {
+    pa: u64,
+    len: u64,
+}
+
+impl DmaBuffer {
+    /// Host physical address of the buffer, as programmed into devices.
+    pub fn pa(&self) -> u64 {
+        self.pa
+    }
+
+    /// Length of the buffer in bytes, including any padding up to a whole cache line.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(pmap::pa2va(self.pa) as *const u8, self.len as usize) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(pmap::pa2va(self.pa) as *mut u8, self.len as usize) }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Allocation {
+    pa: u64,
+    len: u64,
+    owner: &'static str,
+}
+
+pub struct DmaPool {
+    region: MemoryRegion,
+    lines: usize,
+    bitmap: [u64; MAX_LINES / 64],
+    allocations: ArrayVec<[Allocation; MAX_ALLOCATIONS]>,
+}
+
+impl DmaPool {
+    pub fn new(region: MemoryRegion) -> Self {
+        assert_eq!(region.base() % CACHE_LINE, 0);
+        Self {
+            lines: ((region.len() / CACHE_LINE) as usize).min(MAX_LINES),
+            region,
+            bitmap: [0; MAX_LINES / 64],
+            allocations: ArrayVec::new(),
+        }
+    }
+
+    /// Allocate a zeroed buffer of at least `len` bytes whose physical address is a multiple of
+    /// `align`, which must be a power of two.
+    pub fn alloc(&mut self, len: u64, align: u64, owner: &'static str) -> Result<DmaBuffer> {
+        assert!(align.is_power_of_two());
+        if len == 0 || self.allocations.is_full() {
+            return Err(Error::OutOfMemory);
+        }
+
+        let needed = ((len + CACHE_LINE - 1) / CACHE_LINE) as usize;
+        let align = (align.max(CACHE_LINE) / CACHE_LINE) as usize;
+        let first = (self.region.base() / CACHE_LINE) as usize;
+
+        let mut start = (first + align - 1) / align * align - first;
+        while start + needed <= self.lines {
+            match (start..start + needed).find(|&i| self.is_used(i)) {
+                Some(used) => start = (first + used + align) / align * align - first,
+                None => {
+                    for i in start..start + needed {
+                        self.bitmap[i / 64] |= 1 << (i % 64);
+                    }
+                    let pa = self.region.base() + start as u64 * CACHE_LINE;
+                    let len = needed as u64 * CACHE_LINE;
+                    for byte in self.region.slice_mut(pa, len) {
+                        *byte = 0;
+                    }
+                    self.allocations.push(Allocation { pa, len, owner });
+                    return Ok(DmaBuffer { pa, len });
+                }
+            }
+        }
+        Err(Error::OutOfMemory)
+    }
+
+    /// Return a buffer to the pool.
+    pub fn free(&mut self, buffer: DmaBuffer) {
+        let index = self.allocations.iter().position(|a| a.pa == buffer.pa && a.len == buffer.len)
+            .expect("freeing DMA buffer that wasn't allocated from this pool");
+        self.allocations.swap_remove(index);
+
+        let start = ((buffer.pa - self.region.base()) / CACHE_LINE) as usize;
+        for i in start..start + (buffer.len / CACHE_LINE) as usize {
+            self.bitmap[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    /// Number of bytes currently allocated to `owner`.
+    pub fn outstanding(&self, owner: &str) -> u64 {
+        self.allocations.iter().filter(|a| a.owner == owner).map(|a| a.len).sum()
+    }
+
+    /// Print every live allocation.
+    pub fn report(&self) {
+        let used: u64 = self.allocations.iter().map(|a| a.len).sum();
+        println!("dma: {} of {} bytes in use by {} buffers", used, self.lines as u64 * CACHE_LINE,
+                 self.allocations.len());
+        for a in &self.allocations {
+            println!("  {:#x}..{:#x} {}", a.pa, a.pa + a.len, a.owner);
+        }
+    }
+
+    fn is_used(&self, line: usize) -> bool {
+        self.bitmap[line / 64] & (1 << (line % 64)) != 0
+    }
+}
@@ -0,0 +1,400 @@
+//! Emulated virtio-blk device backed by a host virtio-blk device that the hypervisor drives itself.
+//!
+//! Passing a host disk straight through to a guest is fastest, but leaves the hypervisor unable to
+//! do anything with the data. When the `rvirt,blk-backend` property of the host's /chosen lists a
+//! host virtio device, the guest instead gets an emulated device in that slot, and the hypervisor
+//! becomes the host device's only driver. Requests are handled one at a time when the guest
+//! notifies its queue: each is copied through a bounce buffer to or from the host device, which is
+//! polled until it completes. A disk that holds a qcow2 image is presented as the image's virtual
+//! disk rather than as raw sectors.
+
+use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::dma::{DmaBuffer, DmaPool};
+use crate::error::{Error, Result};
+use crate::memory_region::MemoryRegion;
+use crate::overlay::{Overlay, ReadOnlyDisk};
+use crate::qcow2::{self, Qcow2};
+use crate::{pmap, riscv};
+use super::*;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_HEADER_SIZE: u64 = 16;
+
+/// Data segments per request offered to the guest. `next_chain` returns chains of up to 16
+/// descriptors, and two of them hold the header and the status.
+const SEG_MAX: u32 = 14;
+
+/// Serial number reported for GET_ID requests.
+const DEVICE_ID: &[u8] = b"rvirt-blk";
+
+/// Requests are made to the host device one at a time, which takes three descriptors.
+const HOST_QUEUE_SIZE: u32 = 4;
+/// Largest transfer made to the host device in one request.
+const BOUNCE_SIZE: u64 = 16 << 10;
+/// How long to wait for the host device to complete a request before giving up on it.
+const REQUEST_TIMEOUT_SPINS: u64 = 1 << 32;
+
+/// Driver for a legacy (version 1) virtio-mmio block device of the host.
+pub struct HostBlk {
+    registers: MemoryRegion<u32>,
+    /// Descriptor table and available ring in the first page, used ring in the second.
+    queue: DmaBuffer,
+    /// Request header, followed by the status byte.
+    header: DmaBuffer,
+    bounce: DmaBuffer,
+    sectors: u64,
+    flush_supported: bool,
+    /// Whether there have been writes since the last flush. A device without a volatile write cache
+    /// doesn't offer VIRTIO_BLK_F_FLUSH, and then never needs flushing.
+    unflushed: bool,
+    used_idx: u16,
+}
+
+impl HostBlk {
+    /// Take over the host virtio device whose registers are at `base_address`, which has to be a
+    /// block device.
+    pub unsafe fn new(base_address: u64, dma: &mut DmaPool) -> Result<Self> {
+        let mut registers = MemoryRegion::<u32>::with_base_address(pmap::pa2va(base_address), 0, 0x1000);
+        if registers[REG_MAGIC_VALUE] != MAGIC_VALUE || registers[REG_VERSION] != 1
+            || registers[REG_DEVICE_ID] != VIRTIO_ID_BLOCK {
+            return Err(Error::DeviceIo);
+        }
+
+        registers[REG_STATUS] = 0;
+        registers[REG_STATUS] = STATUS_ACKNOWLEDGE;
+        registers[REG_STATUS] = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        registers[REG_HOST_FEATURES_SEL] = 0;
+        let features = registers[REG_HOST_FEATURES] as u64 & VIRTIO_BLK_F_FLUSH;
+        registers[REG_GUEST_FEATURES_SEL] = 0;
+        registers[REG_GUEST_FEATURES] = features as u32;
+        registers[REG_GUEST_PAGE_SIZE] = 4096;
+        registers[REG_QUEUE_SEL] = 0;
+        if registers[REG_QUEUE_NUM_MAX] < HOST_QUEUE_SIZE {
+            registers[REG_STATUS] = STATUS_FAILED;
+            return Err(Error::DeviceIo);
+        }
+
+        let queue = dma.alloc(8192, 4096, "blk queue");
+        let header = dma.alloc(REQUEST_HEADER_SIZE + 1, 64, "blk request header");
+        let bounce = dma.alloc(BOUNCE_SIZE, 64, "blk bounce buffer");
+        let (queue, header, bounce) = match (queue, header, bounce) {
+            (Ok(queue), Ok(header), Ok(bounce)) => (queue, header, bounce),
+            (queue, header, bounce) => {
+                for buffer in queue.into_iter().chain(header).chain(bounce) {
+                    dma.free(buffer);
+                }
+                registers[REG_STATUS] = STATUS_FAILED;
+                return Err(Error::OutOfMemory);
+            }
+        };
+
+        let mut driver = Self { registers, queue, header, bounce, sectors: 0, flush_supported: features != 0,
+                               unflushed: false, used_idx: 0 };
+        // The device is polled, so ask it not to interrupt. The available ring follows the
+        // descriptor table.
+        let avail = 16 * HOST_QUEUE_SIZE as usize;
+        LittleEndian::write_u16(&mut driver.queue.as_mut_slice()[avail..], VIRTQ_AVAIL_F_NO_INTERRUPT);
+        driver.registers[REG_QUEUE_NUM] = HOST_QUEUE_SIZE;
+        driver.registers[REG_QUEUE_ALIGN] = 4096;
+        driver.registers[REG_QUEUE_PFN] = (driver.queue.pa() >> 12) as u32;
+        driver.registers[REG_STATUS] = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK;
+        driver.sectors = driver.registers[REG_CONFIG] as u64 | (driver.registers[REG_CONFIG + 4] as u64) << 32;
+        Ok(driver)
+    }
+
+    /// Return the driver's buffers to the pool they were allocated from, after resetting the device.
+    fn release(mut self, dma: &mut DmaPool) {
+        self.registers[REG_STATUS] = 0;
+        dma.free(self.queue);
+        dma.free(self.header);
+        dma.free(self.bounce);
+    }
+
+    /// Make a request of the host device and wait for it to complete. Data goes through the bounce
+    /// buffer, of which the first `len` bytes are used.
+    fn request(&mut self, type_: u32, sector: u64, len: u64) -> Result<()> {
+        assert!(len <= BOUNCE_SIZE);
+        let header = self.header.as_mut_slice();
+        LittleEndian::write_u32(&mut header[0..], type_);
+        LittleEndian::write_u32(&mut header[4..], 0);
+        LittleEndian::write_u64(&mut header[8..], sector);
+        header[REQUEST_HEADER_SIZE as usize] = 0xff;
+
+        let (header_pa, bounce_pa) = (self.header.pa(), self.bounce.pa());
+        let device_writes = type_ != VIRTIO_BLK_T_OUT;
+        let queue = self.queue.as_mut_slice();
+        let mut write_desc = |index: usize, addr: u64, len: u64, flags: u16, next: u16| {
+            let desc = &mut queue[16 * index..];
+            LittleEndian::write_u64(&mut desc[0..], addr);
+            LittleEndian::write_u32(&mut desc[8..], len as u32);
+            LittleEndian::write_u16(&mut desc[12..], flags);
+            LittleEndian::write_u16(&mut desc[14..], next);
+        };
+        let status_desc = if len > 0 { 2 } else { 1 };
+        write_desc(0, header_pa, REQUEST_HEADER_SIZE, VIRTQ_DESC_F_NEXT, 1);
+        if len > 0 {
+            let flags = VIRTQ_DESC_F_NEXT | if device_writes { VIRTQ_DESC_F_WRITE } else { 0 };
+            write_desc(1, bounce_pa, len, flags, 2);
+        }
+        write_desc(status_desc, header_pa + REQUEST_HEADER_SIZE, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        // Publish the chain in the available ring, which follows the descriptor table.
+        let avail = 16 * HOST_QUEUE_SIZE as usize;
+        let avail_idx = LittleEndian::read_u16(&queue[avail + 2..]);
+        LittleEndian::write_u16(&mut queue[avail + 4 + 2 * (avail_idx as usize % HOST_QUEUE_SIZE as usize)..], 0);
+        riscv::fence();
+        LittleEndian::write_u16(&mut queue[avail + 2..], avail_idx.wrapping_add(1));
+        riscv::fence();
+        self.registers[REG_QUEUE_NOTIFY] = 0;
+
+        let used_idx = self.queue.as_slice()[4096 + 2..].as_ptr() as *const u16;
+        let mut spins = 0u64;
+        while unsafe { core::ptr::read_volatile(used_idx) } == self.used_idx {
+            spins += 1;
+            if spins == REQUEST_TIMEOUT_SPINS {
+                println!("WARN: host block device stopped responding");
+                return Err(Error::DeviceIo);
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+        riscv::fence();
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let interrupt_status = self.registers[REG_INTERRUPT_STATUS];
+        self.registers[REG_INTERRUPT_ACK] = interrupt_status;
+
+        match self.header.as_slice()[REQUEST_HEADER_SIZE as usize] {
+            VIRTIO_BLK_S_OK => Ok(()),
+            _ => Err(Error::DeviceIo),
+        }
+    }
+}
+
+impl BlockDevice for HostBlk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, mut sector: u64, buf: &mut [u8]) -> Result<()> {
+        for chunk in buf.chunks_mut(BOUNCE_SIZE as usize) {
+            self.request(VIRTIO_BLK_T_IN, sector, chunk.len() as u64)?;
+            chunk.copy_from_slice(&self.bounce.as_slice()[..chunk.len()]);
+            sector += chunk.len() as u64 / SECTOR_SIZE;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut sector: u64, buf: &[u8]) -> Result<()> {
+        for chunk in buf.chunks(BOUNCE_SIZE as usize) {
+            self.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.unflushed = self.flush_supported;
+            self.request(VIRTIO_BLK_T_OUT, sector, chunk.len() as u64)?;
+            sector += chunk.len() as u64 / SECTOR_SIZE;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.unflushed {
+            self.request(VIRTIO_BLK_T_FLUSH, 0, 0)?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+}
+
+/// What the guest's disk is stored as.
+pub enum Disk {
+    Raw(HostBlk),
+    Qcow2(Qcow2<HostBlk>),
+    /// This guest's copy-on-write view of a disk shared by all guests.
+    Overlay(Overlay),
+    /// A disk shared with other guests that no guest can write.
+    ReadOnly(ReadOnlyDisk),
+}
+
+impl Disk {
+    /// Take over the host block device at `base_address`. Its contents are used as is, unless they
+    /// are a qcow2 image.
+    pub unsafe fn open(base_address: u64, dma: &mut DmaPool) -> Result<Self> {
+        let mut host = HostBlk::new(base_address, dma)?;
+        match qcow2::Header::read(&mut host) {
+            Ok(None) => Ok(Disk::Raw(host)),
+            Ok(Some(header)) => Ok(Disk::Qcow2(Qcow2::new(host, header))),
+            Err(e) => {
+                host.release(dma);
+                Err(e)
+            }
+        }
+    }
+
+    fn device(&mut self) -> &mut dyn BlockDevice {
+        match *self {
+            Disk::Raw(ref mut host) => host,
+            Disk::Qcow2(ref mut image) => image,
+            Disk::Overlay(ref mut overlay) => overlay,
+            Disk::ReadOnly(ref mut disk) => disk,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        match *self {
+            Disk::ReadOnly(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sectors(&self) -> u64 {
+        match *self {
+            Disk::Raw(ref host) => host.sectors(),
+            Disk::Qcow2(ref image) => image.sectors(),
+            Disk::Overlay(ref overlay) => overlay.sectors(),
+            Disk::ReadOnly(ref disk) => disk.sectors(),
+        }
+    }
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.device().read(sector, buf)
+    }
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.device().write(sector, buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.device().flush()
+    }
+}
+
+pub struct BlkDriver {
+    disk: Disk,
+}
+
+impl BlkDriver {
+    pub fn new(disk: Disk) -> Self {
+        Self { disk }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.disk.flush()
+    }
+
+    /// Carry out one request. `header` is the guest address of its header, and `data` the
+    /// (guest address, length, device writable) ranges between the header and the status byte.
+    /// Returns the status, and how many bytes were written to the guest besides the status byte.
+    fn handle_request(&mut self, guest_memory: &mut MemoryRegion, header: u64, data: &[(u64, u64, bool)]) -> (u8, u32) {
+        let header = guest_memory.slice(header, REQUEST_HEADER_SIZE);
+        let type_ = LittleEndian::read_u32(header);
+        let mut sector = LittleEndian::read_u64(&header[8..]);
+
+        match type_ {
+            VIRTIO_BLK_T_OUT if self.disk.is_read_only() => (VIRTIO_BLK_S_IOERR, 0),
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                let total: u64 = data.iter().map(|d| d.1).sum();
+                if total % SECTOR_SIZE != 0 || sector.saturating_add(total / SECTOR_SIZE) > self.disk.sectors() {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+                for &(addr, len, writable) in data {
+                    if len % SECTOR_SIZE != 0 || writable != (type_ == VIRTIO_BLK_T_IN) {
+                        return (VIRTIO_BLK_S_IOERR, 0);
+                    }
+                    let result = if type_ == VIRTIO_BLK_T_IN {
+                        self.disk.read(sector, guest_memory.slice_mut(addr, len))
+                    } else {
+                        self.disk.write(sector, guest_memory.slice(addr, len))
+                    };
+                    if result.is_err() {
+                        return (VIRTIO_BLK_S_IOERR, 0);
+                    }
+                    sector += len / SECTOR_SIZE;
+                }
+                match type_ {
+                    VIRTIO_BLK_T_IN => (VIRTIO_BLK_S_OK, total as u32),
+                    _ => (VIRTIO_BLK_S_OK, 0),
+                }
+            }
+            // Requests are carried out one at a time in the order the guest made them available, and
+            // each is complete before the next starts, so this covers every write before it.
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID if !data.is_empty() && data[0].2 => {
+                let len = (data[0].1 as usize).min(DEVICE_ID.len());
+                guest_memory.slice_mut(data[0].0, len as u64).copy_from_slice(&DEVICE_ID[..len]);
+                (VIRTIO_BLK_S_OK, len as u32)
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+    }
+}
+
+impl Driver for BlkDriver {
+    const DEVICE_ID: u32 = VIRTIO_ID_BLOCK;
+    const FEATURES: u64 = VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH;
+    const QUEUE_NUM_MAX: u32 = 32;
+    const NUM_QUEUES: u32 = 1;
+
+    fn features(device: &GuestDevice<Self>) -> u64 {
+        if device.host_driver.disk.is_read_only() {
+            Self::FEATURES | VIRTIO_BLK_F_RO
+        } else {
+            Self::FEATURES
+        }
+    }
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        while let Some((id, ranges)) = device.next_chain(guest_memory, queue) {
+            let (header_addr, header_len, _) = ranges[0];
+            let (status_addr, status_len, status_writable) = ranges[ranges.len() - 1];
+            if ranges.len() < 2 || header_len as u64 != REQUEST_HEADER_SIZE || !status_writable {
+                // Without somewhere to put the status there's no way to report the error either.
+                device.complete_chain(guest_memory, queue, id, 0);
+                continue;
+            }
+
+            // The status byte is the last byte of the chain, which usually has a descriptor of its
+            // own but may share one with the data.
+            let mut data = ArrayVec::<[(u64, u64, bool); 16]>::new();
+            for &(addr, len, writable) in &ranges[1..ranges.len() - 1] {
+                data.push((addr, len as u64, writable));
+            }
+            if status_len > 1 {
+                data.push((status_addr, status_len as u64 - 1, status_writable));
+            }
+
+            let (status, written) = device.host_driver.handle_request(guest_memory, header_addr, &data);
+            guest_memory.slice_mut(status_addr + status_len as u64 - 1, 1)[0] = status;
+            device.complete_chain(guest_memory, queue, id, written + 1);
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=7 => device.host_driver.disk.sectors().to_le_bytes()[offset as usize],
+            12..=15 => SEG_MAX.to_le_bytes()[offset as usize - 12],
+            _ => 0,
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
@@ -7,6 +7,8 @@
 
 #![allow(unused)]
 
+use crate::dma::{DmaBuffer, DmaPool};
+use crate::error::Result;
 use crate::memory_region::MemoryRegion;
 use super::*;
 
@@ -16,26 +18,48 @@ const GEM_DMACFG_ADDR_64B: u32 = 1 << 30;
 
 const VIRTIO_MTU: u16 = 2048;
 
-#[repr(transparent)]
-struct RxDesc([u32; 4]);
-#[repr(transparent)]
-struct TxDesc([u32; 4]);
+const RING_SIZE: u64 = 8;
+const BUFFER_SIZE: u64 = 2048;
+/// Descriptors are four words each when 64-bit addressing is enabled.
+const DESC_SIZE: u64 = 16;
 
 /// Driver for the Cadence GEM Ethernet device.
 pub struct MacbDriver {
     control_registers: MemoryRegion<u32>,
     mac: [u8; 6],
 
-    rx_buffers: [[u8; 2048]; 8],
-    rx_queue: [RxDesc; 8],
-    tx_buffers: [[u8; 2048]; 8],
-    tx_queue: [TxDesc; 8],
+    rx_buffers: DmaBuffer,
+    rx_queue: DmaBuffer,
+    tx_buffers: DmaBuffer,
+    tx_queue: DmaBuffer,
+}
+
+impl MacbDriver {
+    pub fn new(control_registers: MemoryRegion<u32>, mac: [u8; 6], dma: &mut DmaPool) -> Result<Self> {
+        Ok(Self {
+            control_registers,
+            mac,
+            rx_buffers: dma.alloc(RING_SIZE * BUFFER_SIZE, 64, "macb rx buffers")?,
+            rx_queue: dma.alloc(RING_SIZE * DESC_SIZE, 64, "macb rx ring")?,
+            tx_buffers: dma.alloc(RING_SIZE * BUFFER_SIZE, 64, "macb tx buffers")?,
+            tx_queue: dma.alloc(RING_SIZE * DESC_SIZE, 64, "macb tx ring")?,
+        })
+    }
+
+    /// Return the driver's rings and buffers to the pool they were allocated from.
+    pub fn release(self, dma: &mut DmaPool) {
+        dma.free(self.rx_buffers);
+        dma.free(self.rx_queue);
+        dma.free(self.tx_buffers);
+        dma.free(self.tx_queue);
+    }
 }
 
 impl Driver for MacbDriver {
     const DEVICE_ID: u32 = 1;
     const FEATURES: u64 = VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU;
     const QUEUE_NUM_MAX: u32 = 2;
+    const NUM_QUEUES: u32 = 2;
 
     fn interrupt(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
         false
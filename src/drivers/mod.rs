@@ -4,7 +4,11 @@ use arrayvec::ArrayVec;
 use byteorder::{ByteOrder, LittleEndian};
 use crate::memory_region::MemoryRegion;
 
+#[cfg(target_arch = "riscv64")]
+pub mod blk;
+#[cfg(target_arch = "riscv64")]
 pub mod macb;
+pub mod vsock;
 
 #[allow(unused)]
 mod constants {
@@ -29,6 +33,7 @@ mod constants {
     pub const REG_INTERRUPT_STATUS: u64 = 0x060;
     pub const REG_INTERRUPT_ACK: u64 = 0x064;
     pub const REG_STATUS: u64 = 0x070;
+    pub const REG_CONFIG: u64 = 0x100;
 
     pub const STATUS_ACKNOWLEDGE: u32 = 1;
     pub const STATUS_DRIVER: u32 = 2;
@@ -40,10 +45,15 @@ mod constants {
     pub const VIRTIO_NET_F_MTU: u64 = 1 << 3;
     pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
 
+    pub const INTERRUPT_USED_BUFFER: u32 = 1;
+
     pub const VIRTQ_DESC_F_NEXT: u16 = 1;
     pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+    pub const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
 
-    pub const MAX_QUEUES: usize = 4;
+    /// Most queues any emulated device can have. Enough for a console with several ports, each of
+    /// which needs a receive and a transmit queue.
+    pub const MAX_QUEUES: usize = 16;
 }
 pub use constants::*;
 
@@ -51,6 +61,14 @@ pub trait Driver: Sized {
     const DEVICE_ID: u32;
     const FEATURES: u64;
     const QUEUE_NUM_MAX: u32;
+    /// Number of queues the device has, at most `MAX_QUEUES`. The guest sees a QueueNumMax of zero
+    /// for any queue past these.
+    const NUM_QUEUES: u32;
+
+    /// Features offered to the guest, for drivers whose features depend on how they were set up.
+    fn features(_device: &GuestDevice<Self>) -> u64 {
+        Self::FEATURES
+    }
 
     fn interrupt(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion) -> bool;
     fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32);
@@ -140,8 +158,8 @@ impl<D: Driver> GuestDevice<D> {
     }
 
     pub fn read_u8(&mut self, guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
-        if offset > 0x100 {
-            D::read_config_u8(self, guest_memory, offset)
+        if offset >= REG_CONFIG {
+            D::read_config_u8(self, guest_memory, offset - REG_CONFIG)
         } else {
             0
         }
@@ -152,8 +170,8 @@ impl<D: Driver> GuestDevice<D> {
             return 0;
         }
 
-        if offset > 0x100 {
-            return D::read_config_u32(self, guest_memory, offset);
+        if offset >= REG_CONFIG {
+            return D::read_config_u32(self, guest_memory, offset - REG_CONFIG);
         }
 
         match offset {
@@ -161,20 +179,21 @@ impl<D: Driver> GuestDevice<D> {
             REG_VERSION => 1,
             REG_DEVICE_ID => D::DEVICE_ID,
             REG_VENDOR_ID => VENDOR_ID,
-            REG_HOST_FEATURES if self.host_features_sel == 0 => (D::FEATURES & 0xffffffff) as u32,
-            REG_HOST_FEATURES if self.host_features_sel == 1 => ((D::FEATURES >> 32) & 0xffffffff) as u32,
+            REG_HOST_FEATURES if self.host_features_sel == 0 => (D::features(self) & 0xffffffff) as u32,
+            REG_HOST_FEATURES if self.host_features_sel == 1 => ((D::features(self) >> 32) & 0xffffffff) as u32,
             REG_HOST_FEATURES => 0,
             REG_HOST_FEATURES_SEL => self.host_features_sel,
             REG_GUEST_FEATURES => 0,
             REG_GUEST_FEATURES_SEL => self.guest_features_sel,
             REG_GUEST_PAGE_SIZE => self.guest_page_size,
             REG_QUEUE_SEL => self.queue_sel,
-            REG_QUEUE_NUM_MAX => D::QUEUE_NUM_MAX,
-            REG_QUEUE_NUM => self.queue_num[self.queue_sel as usize],
-            REG_QUEUE_ALIGN => self.queue_align[self.queue_sel as usize],
-            REG_QUEUE_PFN => self.queue_pfn[self.queue_sel as usize],
+            REG_QUEUE_NUM_MAX if self.selected_queue().is_some() => D::QUEUE_NUM_MAX,
+            REG_QUEUE_NUM_MAX => 0,
+            REG_QUEUE_NUM => self.selected_queue().map(|q| self.queue_num[q]).unwrap_or(0),
+            REG_QUEUE_ALIGN => self.selected_queue().map(|q| self.queue_align[q]).unwrap_or(0),
+            REG_QUEUE_PFN => self.selected_queue().map(|q| self.queue_pfn[q]).unwrap_or(0),
             REG_QUEUE_NOTIFY => 0,
-            REG_INTERRUPT_STATUS => 0,
+            REG_INTERRUPT_STATUS => self.interrupt_status,
             REG_INTERRUPT_ACK => 0,
             REG_STATUS => self.status,
             _ => 0,
@@ -182,8 +201,8 @@ impl<D: Driver> GuestDevice<D> {
     }
 
     pub fn write_u8(&mut self, guest_memory: &mut MemoryRegion, offset: u64, value: u8)  {
-        if offset > 0x100 {
-            D::write_config_u8(self, guest_memory, offset, value);
+        if offset >= REG_CONFIG {
+            D::write_config_u8(self, guest_memory, offset - REG_CONFIG, value);
         }
     }
 
@@ -192,8 +211,8 @@ impl<D: Driver> GuestDevice<D> {
             return;
         }
 
-        if offset > 0x100 {
-            D::write_config_u32(self, guest_memory, offset, value);
+        if offset >= REG_CONFIG {
+            D::write_config_u32(self, guest_memory, offset - REG_CONFIG, value);
             return;
         }
 
@@ -204,10 +223,10 @@ impl<D: Driver> GuestDevice<D> {
             REG_GUEST_FEATURES_SEL => self.guest_features_sel = value,
             REG_GUEST_PAGE_SIZE => self.guest_page_size = value,
             REG_QUEUE_SEL => self.queue_sel = value,
-            REG_QUEUE_NUM => self.queue_num[self.queue_sel as usize] = value,
-            REG_QUEUE_ALIGN => self.queue_align[self.queue_sel as usize] = value,
-            REG_QUEUE_PFN => self.queue_pfn[self.queue_sel as usize] = value,
-            REG_QUEUE_NOTIFY => D::doorbell(self, guest_memory, value),
+            REG_QUEUE_NUM => if let Some(q) = self.selected_queue() { self.queue_num[q] = value },
+            REG_QUEUE_ALIGN => if let Some(q) = self.selected_queue() { self.queue_align[q] = value },
+            REG_QUEUE_PFN => if let Some(q) = self.selected_queue() { self.queue_pfn[q] = value },
+            REG_QUEUE_NOTIFY if (value as usize) < Self::num_queues() => D::doorbell(self, guest_memory, value),
             REG_INTERRUPT_ACK => self.interrupt_status &= !value,
             REG_STATUS => {
                 if value == 0 {
@@ -226,6 +245,28 @@ impl<D: Driver> GuestDevice<D> {
         D::interrupt(self, guest_memory)
     }
 
+    /// Whether the device has an interrupt that the guest hasn't acknowledged yet.
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    pub fn host_driver(&mut self) -> &mut D {
+        &mut self.host_driver
+    }
+
+    fn num_queues() -> usize {
+        (D::NUM_QUEUES as usize).min(MAX_QUEUES)
+    }
+
+    /// Index of the queue selected by QueueSel, if the device has that many queues.
+    fn selected_queue(&self) -> Option<usize> {
+        if (self.queue_sel as usize) < Self::num_queues() {
+            Some(self.queue_sel as usize)
+        } else {
+            None
+        }
+    }
+
     fn reset(&mut self) {
         self.host_features_sel = 0;
         self.guest_features_sel = 0;
@@ -240,36 +281,75 @@ impl<D: Driver> GuestDevice<D> {
         self.interrupt_status = 0;
     }
 
-    fn with_buffer<F: FnOnce(&[&[u8]]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) {
-        let dt = self.get_queue(guest_memory, queue);
-
+    /// The descriptor chain at the head of a queue's available ring, as a list of (guest address,
+    /// length, device writable) ranges. Returns None if the guest hasn't made any buffers
+    /// available, or if the chain is malformed.
+    fn next_chain(&mut self, guest_memory: &mut MemoryRegion, queue: u32) -> Option<(u16, ArrayVec<[(u64, u32, bool); 16]>)> {
+        let dt = self.get_queue(guest_memory, queue)?;
         if dt.avail_idx() == dt.used_idx() {
-            return;
+            return None;
         }
 
-        let mut ranges = ArrayVec::<[(u64, u32); 16]>::new();
-
-        let idx = (dt.used_idx() as usize + 1) % dt.queue_size;
-        let id = dt.avail_ring(idx) as usize;
-
+        let id = dt.avail_ring(dt.used_idx() as usize % dt.queue_size) as usize;
+        let mut ranges = ArrayVec::new();
         let mut flags = VIRTQ_DESC_F_NEXT;
         let mut next_id = id;
         while flags & VIRTQ_DESC_F_NEXT != 0 {
+            if next_id >= dt.queue_size {
+                return None;
+            }
             let addr = dt.desc_addr(next_id);
             let len = dt.desc_len(next_id);
             flags = dt.desc_flags(next_id);
             next_id = dt.desc_next(next_id) as usize;
 
-            ranges.push((addr, len));
+            if len == 0 {
+                return None;
+            }
+            ranges.try_push((addr, len, flags & VIRTQ_DESC_F_WRITE != 0)).ok()?;
         }
 
-        // Handling the borrow checker is a bit tricky here. At this point, we let the lifetime of
-        // `dt` end so that its borrow of `guest_memory` ends. Then we borrow a bunch of slices from
+        // The descriptor table borrows guest memory, so the ranges can only be checked against it
+        // once the whole chain has been read.
+        for &(addr, len, _) in &ranges {
+            if !guest_memory.in_region(addr) || !guest_memory.in_region(addr + len as u64 - 1) {
+                return None;
+            }
+        }
+        Some((id as u16, ranges))
+    }
+
+    /// Return the chain at the head of a queue to the guest, reporting that `len` bytes were
+    /// written into it. Raises the device's interrupt unless the guest has asked not to be
+    /// interrupted about this queue.
+    fn complete_chain(&mut self, guest_memory: &mut MemoryRegion, queue: u32, id: u16, len: u32) {
+        if let Some(mut dt) = self.get_queue(guest_memory, queue) {
+            let idx = dt.used_idx() as usize % dt.queue_size;
+            dt.set_used_ring_id(idx, id as u32);
+            dt.set_used_ring_len(idx, len);
+            dt.set_used_idx(dt.used_idx().wrapping_add(1));
+            if dt.avail_flags() & VIRTQ_AVAIL_F_NO_INTERRUPT != 0 {
+                return;
+            }
+        }
+        self.interrupt_status |= INTERRUPT_USED_BUFFER;
+    }
+
+    /// Pass the buffers at the head of a queue to `f`. If it returns Some(len) the buffers are
+    /// returned to the guest with `len` as the number of bytes written, otherwise they are left in
+    /// the queue.
+    fn with_buffer<F: FnOnce(&[&[u8]]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) {
+        let (id, ranges) = match self.next_chain(guest_memory, queue) {
+            Some(chain) => chain,
+            None => return,
+        };
+
+        // Handling the borrow checker is a bit tricky here. We borrow a bunch of slices from
         // `guest_memory` and pass them to `f`. Once that function returns, we have `buffers` go out
         // of scope so that we can borrow `guest_memory` again to make a DescriptorTable.
         let consume_buffers = {
             let mut buffers = ArrayVec::<[&[u8]; 16]>::new();
-            for (addr, len) in ranges {
+            for (addr, len, _) in ranges {
                 buffers.push(guest_memory.slice(addr, len as u64));
             }
 
@@ -277,34 +357,241 @@ impl<D: Driver> GuestDevice<D> {
         };
 
         if let Some(len) = consume_buffers {
-            let mut dt = self.get_queue(guest_memory, queue);
-            dt.set_used_ring_id(idx, id as u32);
-            dt.set_used_ring_len(idx, len);
-            dt.set_used_idx(dt.used_idx().wrapping_add(1));
+            self.complete_chain(guest_memory, queue, id, len);
+        }
+    }
+
+    /// Copy `data` into the device writable buffers at the head of a queue and return them to the
+    /// guest. Returns false if no buffer is available or the data doesn't fit.
+    fn fill_buffer(&mut self, guest_memory: &mut MemoryRegion, queue: u32, data: &[&[u8]]) -> bool {
+        let (id, ranges) = match self.next_chain(guest_memory, queue) {
+            Some(chain) => chain,
+            None => return false,
+        };
+
+        let total: usize = data.iter().map(|d| d.len()).sum();
+        let space: usize = ranges.iter().filter(|r| r.2).map(|r| r.1 as usize).sum();
+        if total > space {
+            return false;
+        }
+
+        let mut ranges = ranges.iter().filter(|r| r.2);
+        let (mut addr, mut left) = match ranges.next() {
+            Some(&(addr, len, _)) => (addr, len as usize),
+            None => return total == 0,
+        };
+        for mut chunk in data.iter().cloned() {
+            while !chunk.is_empty() {
+                if left == 0 {
+                    let &(a, l, _) = ranges.next().unwrap();
+                    addr = a;
+                    left = l as usize;
+                }
+                let n = chunk.len().min(left);
+                guest_memory.slice_mut(addr, n as u64).copy_from_slice(&chunk[..n]);
+                chunk = &chunk[n..];
+                addr += n as u64;
+                left -= n;
+            }
         }
+
+        self.complete_chain(guest_memory, queue, id, total as u32);
+        true
     }
 
-    fn get_queue<'a>(&'a mut self, guest_memory: &'a mut MemoryRegion, queue: u32) -> DescriptorTable<'a> {
-        let pfn = self.queue_pfn[queue as usize];
-        let queue_size = self.queue_num[queue as usize] as usize;
-        let align = self.queue_align[queue as usize] as usize;
+    fn get_queue<'a>(&'a mut self, guest_memory: &'a mut MemoryRegion, queue: u32) -> Option<DescriptorTable<'a>> {
+        let queue = queue as usize;
+        if queue >= Self::num_queues() || self.queue_pfn[queue] == 0 || self.queue_num[queue] == 0 {
+            return None;
+        }
+
+        let base = self.queue_pfn[queue] as u64 * self.guest_page_size as u64;
+        let queue_size = self.queue_num[queue] as usize;
+        let align = (self.queue_align[queue] as usize).max(1);
+        if !align.is_power_of_two() || queue_size > D::QUEUE_NUM_MAX as usize {
+            return None;
+        }
 
         let desc_size = 16 * queue_size;
         let avail_size = 6 + 2 * queue_size;
         let used_size = 6 + 8 * queue_size;
+        let used_start = (desc_size + avail_size + (align - 1)) & !(align - 1);
 
-        let used_start = ((desc_size + avail_size + (align - 1)) % align) - align;
+        let total = (used_start + used_size) as u64;
+        if !guest_memory.in_region(base) || !guest_memory.in_region(base + total - 1) {
+            return None;
+        }
 
-        let slice = guest_memory.slice_mut(pfn as u64 * 4096, (used_start + used_size) as u64);
+        let slice = guest_memory.slice_mut(base, total);
         let (desc, slice) = slice.split_at_mut(desc_size);
-        let (avail, slice) = slice.split_at_mut(used_size);
+        let (avail, slice) = slice.split_at_mut(avail_size);
         let (_, used) = slice.split_at_mut(used_start - desc_size - avail_size);
 
-        DescriptorTable {
+        Some(DescriptorTable {
             desc,
             avail,
             used,
             queue_size
-        }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x8000_0000;
+    const QUEUE_SIZE: u64 = 8;
+    /// Where the available and used rings of the legacy queue at `BASE` start.
+    const AVAIL: u64 = BASE + QUEUE_SIZE * 16;
+    const USED: u64 = BASE + 0x1000;
+    const BUFFERS: u64 = BASE + 0x2000;
+
+    struct TestDriver;
+    impl Driver for TestDriver {
+        const DEVICE_ID: u32 = 0xffff;
+        const FEATURES: u64 = 0;
+        const QUEUE_NUM_MAX: u32 = QUEUE_SIZE as u32;
+        const NUM_QUEUES: u32 = 1;
+
+        fn interrupt(_: &mut GuestDevice<Self>, _: &mut MemoryRegion) -> bool { false }
+        fn doorbell(_: &mut GuestDevice<Self>, _: &mut MemoryRegion, _: u32) {}
+        fn read_config_u8(_: &GuestDevice<Self>, _: &mut MemoryRegion, _: u64) -> u8 { 0 }
+        fn write_config_u8(_: &mut GuestDevice<Self>, _: &mut MemoryRegion, _: u64, _: u8) {}
+        fn reset(_: &mut GuestDevice<Self>, _: &mut MemoryRegion) {}
+    }
+
+    fn memory(buffer: &mut Vec<u64>) -> MemoryRegion {
+        unsafe { MemoryRegion::with_base_address(buffer.as_mut_ptr() as u64, BASE, buffer.len() as u64 * 8) }
+    }
+
+    /// A device whose first queue is set up the way a legacy driver does it, at `pfn`.
+    fn set_up_device(guest_memory: &mut MemoryRegion, pfn: u32, align: u32) -> GuestDevice<TestDriver> {
+        let mut device = GuestDevice::new(TestDriver);
+        device.write_u32(guest_memory, REG_GUEST_PAGE_SIZE, 4096);
+        device.write_u32(guest_memory, REG_QUEUE_SEL, 0);
+        device.write_u32(guest_memory, REG_QUEUE_NUM, QUEUE_SIZE as u32);
+        device.write_u32(guest_memory, REG_QUEUE_ALIGN, align);
+        device.write_u32(guest_memory, REG_QUEUE_PFN, pfn);
+        device
+    }
+
+    fn set_descriptor(guest_memory: &mut MemoryRegion, index: u64, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = guest_memory.slice_mut(BASE + index * 16, 16);
+        LittleEndian::write_u64(&mut desc[0..], addr);
+        LittleEndian::write_u32(&mut desc[8..], len);
+        LittleEndian::write_u16(&mut desc[12..], flags);
+        LittleEndian::write_u16(&mut desc[14..], next);
+    }
+
+    fn make_available(guest_memory: &mut MemoryRegion, head: u16) {
+        let avail = guest_memory.slice_mut(AVAIL, 4 + 2 * QUEUE_SIZE);
+        let idx = LittleEndian::read_u16(&avail[2..]);
+        LittleEndian::write_u16(&mut avail[4 + 2 * (idx as usize % QUEUE_SIZE as usize)..], head);
+        LittleEndian::write_u16(&mut avail[2..], idx.wrapping_add(1));
+    }
+
+    fn used_ring(guest_memory: &MemoryRegion) -> (u16, u32, u32) {
+        let used = guest_memory.slice(USED, 12);
+        (LittleEndian::read_u16(&used[2..]), LittleEndian::read_u32(&used[4..]), LittleEndian::read_u32(&used[8..]))
+    }
+
+    #[test]
+    fn chain_round_trip() {
+        let mut buffer = vec![0u64; 4 * 512];
+        let mut guest_memory = memory(&mut buffer);
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32, 4096);
+        assert!(device.next_chain(&mut guest_memory, 0).is_none());
+
+        set_descriptor(&mut guest_memory, 2, BUFFERS, 16, VIRTQ_DESC_F_NEXT, 5);
+        set_descriptor(&mut guest_memory, 5, BUFFERS + 0x100, 32, VIRTQ_DESC_F_WRITE, 0);
+        make_available(&mut guest_memory, 2);
+        let (id, ranges) = device.next_chain(&mut guest_memory, 0).unwrap();
+        assert_eq!(id, 2);
+        assert_eq!(&ranges[..], &[(BUFFERS, 16, false), (BUFFERS + 0x100, 32, true)]);
+
+        device.complete_chain(&mut guest_memory, 0, id, 5);
+        assert_eq!(used_ring(&guest_memory), (1, 2, 5));
+        assert!(device.interrupt_pending());
+        assert!(device.next_chain(&mut guest_memory, 0).is_none());
+
+        device.write_u32(&mut guest_memory, REG_INTERRUPT_ACK, INTERRUPT_USED_BUFFER);
+        assert!(!device.interrupt_pending());
+    }
+
+    #[test]
+    fn interrupts_can_be_suppressed() {
+        let mut buffer = vec![0u64; 4 * 512];
+        let mut guest_memory = memory(&mut buffer);
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32, 4096);
+        LittleEndian::write_u16(guest_memory.slice_mut(AVAIL, 2), VIRTQ_AVAIL_F_NO_INTERRUPT);
+
+        set_descriptor(&mut guest_memory, 0, BUFFERS, 16, 0, 0);
+        make_available(&mut guest_memory, 0);
+        device.complete_chain(&mut guest_memory, 0, 0, 16);
+        assert_eq!(used_ring(&guest_memory), (1, 0, 16));
+        assert!(!device.interrupt_pending());
+    }
+
+    #[test]
+    fn malformed_chains() {
+        let mut buffer = vec![0u64; 4 * 512];
+        let mut guest_memory = memory(&mut buffer);
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32, 4096);
+        make_available(&mut guest_memory, 0);
+
+        // Empty, past the end of guest memory, linked past the end of the table, and a loop.
+        set_descriptor(&mut guest_memory, 0, BUFFERS, 0, 0, 0);
+        assert!(device.next_chain(&mut guest_memory, 0).is_none());
+        set_descriptor(&mut guest_memory, 0, BUFFERS + 0x1ff0, 0x11, 0, 0);
+        assert!(device.next_chain(&mut guest_memory, 0).is_none());
+        set_descriptor(&mut guest_memory, 0, BUFFERS, 16, VIRTQ_DESC_F_NEXT, QUEUE_SIZE as u16);
+        assert!(device.next_chain(&mut guest_memory, 0).is_none());
+        set_descriptor(&mut guest_memory, 0, BUFFERS, 16, VIRTQ_DESC_F_NEXT, 0);
+        assert!(device.next_chain(&mut guest_memory, 0).is_none());
+
+        set_descriptor(&mut guest_memory, 0, BUFFERS + 0x1ff0, 0x10, 0, 0);
+        assert!(device.next_chain(&mut guest_memory, 0).is_some());
+    }
+
+    #[test]
+    fn fill_writable_buffers() {
+        let mut buffer = vec![0u64; 4 * 512];
+        let mut guest_memory = memory(&mut buffer);
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32, 4096);
+        set_descriptor(&mut guest_memory, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 1);
+        set_descriptor(&mut guest_memory, 1, BUFFERS + 0x10, 4, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 2);
+        set_descriptor(&mut guest_memory, 2, BUFFERS + 0x20, 8, VIRTQ_DESC_F_WRITE, 0);
+        make_available(&mut guest_memory, 0);
+
+        // Twelve bytes is more than fits, and leaves the chain where it is.
+        assert!(!device.fill_buffer(&mut guest_memory, 0, &[b"abcdefghijkl", b"m"]));
+        assert_eq!(used_ring(&guest_memory).0, 0);
+
+        assert!(device.fill_buffer(&mut guest_memory, 0, &[b"abc", b"defghi"]));
+        assert_eq!(guest_memory.slice(BUFFERS + 0x10, 4), b"abcd");
+        assert_eq!(guest_memory.slice(BUFFERS + 0x20, 8), b"efghi\0\0\0");
+        assert_eq!(guest_memory.slice(BUFFERS, 4), b"\0\0\0\0");
+        assert_eq!(used_ring(&guest_memory), (1, 0, 9));
+    }
+
+    #[test]
+    fn queue_must_be_in_guest_memory() {
+        let mut buffer = vec![0u64; 4 * 512];
+        let mut guest_memory = memory(&mut buffer);
+
+        // The used ring would start past the end of guest memory.
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32 + 3, 4096);
+        assert!(device.get_queue(&mut guest_memory, 0).is_none());
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32 + 2, 4096);
+        assert!(device.get_queue(&mut guest_memory, 0).is_some());
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32 - 1, 4096);
+        assert!(device.get_queue(&mut guest_memory, 0).is_none());
+        let mut device = set_up_device(&mut guest_memory, (BASE >> 12) as u32, 3000);
+        assert!(device.get_queue(&mut guest_memory, 0).is_none());
+        assert!(device.get_queue(&mut guest_memory, 1).is_none());
+
+        let mut device = GuestDevice::new(TestDriver);
+        assert!(device.get_queue(&mut guest_memory, 0).is_none());
     }
 }
@@ -0,0 +1,330 @@
+//! Emulated virtio-vsock device.
+//!
+//! The hypervisor itself plays the part of the host (CID 2), which lets someone at the monitor
+//! console talk to agents running inside a guest without configuring any networking. Connections
+//! can be opened from either side: guests may connect to any port on the host, and the monitor's
+//! `vsock connect` command connects to a port in the guest. Data received from the guest is printed
+//! to the console, and `vsock send` sends a line of text back.
+
+use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::memory_region::MemoryRegion;
+use super::*;
+
+const VIRTIO_ID_VSOCK: u32 = 19;
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+pub const HOST_CID: u64 = 2;
+
+const HEADER_SIZE: usize = 44;
+const TYPE_STREAM: u16 = 1;
+
+const OP_REQUEST: u16 = 1;
+const OP_RESPONSE: u16 = 2;
+const OP_RST: u16 = 3;
+const OP_SHUTDOWN: u16 = 4;
+const OP_RW: u16 = 5;
+const OP_CREDIT_UPDATE: u16 = 6;
+const OP_CREDIT_REQUEST: u16 = 7;
+
+/// Receive buffer space advertised to the guest. Data is printed as soon as it arrives, so this
+/// only determines how often credit updates are sent.
+const BUFFER_SIZE: u32 = 64 << 10;
+
+const MAX_CONNECTIONS: usize = 8;
+const MAX_PENDING_PACKETS: usize = 16;
+pub const MAX_SEND: usize = 64;
+
+#[derive(Copy, Clone)]
+pub struct Connection {
+    pub guest_port: u32,
+    pub host_port: u32,
+    /// False while waiting for the guest to accept a connection opened from the host.
+    pub established: bool,
+
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Bytes sent to the guest.
+    tx_cnt: u32,
+    /// Bytes received from the guest, and the value last reported back to it.
+    fwd_cnt: u32,
+    reported_fwd_cnt: u32,
+}
+
+struct Packet {
+    header: [u8; HEADER_SIZE],
+    data: ArrayVec<[u8; MAX_SEND]>,
+}
+
+pub struct VsockDriver {
+    guest_cid: u64,
+    connections: ArrayVec<[Connection; MAX_CONNECTIONS]>,
+    /// Packets waiting for the guest to post receive buffers.
+    pending: ArrayVec<[Packet; MAX_PENDING_PACKETS]>,
+    next_host_port: u32,
+}
+
+impl VsockDriver {
+    pub fn new(guest_cid: u64) -> Self {
+        Self {
+            guest_cid,
+            connections: ArrayVec::new(),
+            pending: ArrayVec::new(),
+            next_host_port: 1024,
+        }
+    }
+
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    pub fn connections(&self) -> &[Connection] {
+        &self.connections
+    }
+
+    /// Open a connection to `guest_port`. Returns false if too many connections are open, or the
+    /// request couldn't be queued.
+    pub fn connect(&mut self, guest_port: u32) -> bool {
+        let host_port = self.next_host_port;
+        let connection = Connection {
+            guest_port,
+            host_port,
+            established: false,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+        };
+        if self.connections.try_push(connection).is_err() {
+            return false;
+        }
+        self.next_host_port = self.next_host_port.wrapping_add(1).max(1024);
+        if !self.queue_packet(&connection, OP_REQUEST, &[]) {
+            self.connections.pop();
+            return false;
+        }
+        true
+    }
+
+    /// Send data on an established connection. Returns false if the guest doesn't have room for it.
+    pub fn send(&mut self, index: usize, data: &[u8]) -> bool {
+        let connection = match self.connections.get_mut(index) {
+            Some(c) if c.established => c,
+            _ => return false,
+        };
+        let in_flight = connection.tx_cnt.wrapping_sub(connection.peer_fwd_cnt);
+        if data.len() > MAX_SEND || connection.peer_buf_alloc.saturating_sub(in_flight) < data.len() as u32 {
+            return false;
+        }
+        connection.tx_cnt = connection.tx_cnt.wrapping_add(data.len() as u32);
+        let connection = *connection;
+        self.queue_packet(&connection, OP_RW, data)
+    }
+
+    /// Reset a connection.
+    pub fn close(&mut self, index: usize) -> bool {
+        if index >= self.connections.len() {
+            return false;
+        }
+        let connection = self.connections.remove(index);
+        self.queue_packet(&connection, OP_RST, &[])
+    }
+
+    /// Deliver as many pending packets as the guest has posted receive buffers for.
+    pub fn flush(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion) {
+        while !device.host_driver.pending.is_empty() {
+            let delivered = {
+                let packet = &device.host_driver.pending[0];
+                let (header, data) = (packet.header, packet.data.clone());
+                device.fill_buffer(guest_memory, RX_QUEUE, &[&header[..], &data[..]])
+            };
+            if !delivered {
+                break;
+            }
+            device.host_driver.pending.remove(0);
+        }
+    }
+
+    fn queue_packet(&mut self, connection: &Connection, op: u16, data: &[u8]) -> bool {
+        let mut header = [0; HEADER_SIZE];
+        LittleEndian::write_u64(&mut header[0..], HOST_CID);
+        LittleEndian::write_u64(&mut header[8..], self.guest_cid);
+        LittleEndian::write_u32(&mut header[16..], connection.host_port);
+        LittleEndian::write_u32(&mut header[20..], connection.guest_port);
+        LittleEndian::write_u32(&mut header[24..], data.len() as u32);
+        LittleEndian::write_u16(&mut header[28..], TYPE_STREAM);
+        LittleEndian::write_u16(&mut header[30..], op);
+        LittleEndian::write_u32(&mut header[36..], BUFFER_SIZE);
+        LittleEndian::write_u32(&mut header[40..], connection.fwd_cnt);
+
+        let mut packet = Packet { header, data: ArrayVec::new() };
+        packet.data.extend(data.iter().cloned());
+        self.pending.try_push(packet).is_ok()
+    }
+
+    /// Reply to a packet that doesn't belong to any connection.
+    fn reset(&mut self, src_port: u32, dst_port: u32) {
+        let connection = Connection {
+            guest_port: src_port,
+            host_port: dst_port,
+            established: false,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+        };
+        self.queue_packet(&connection, OP_RST, &[]);
+    }
+
+    fn handle_packet(&mut self, header: &[u8; HEADER_SIZE], len: u32) {
+        let src_cid = LittleEndian::read_u64(&header[0..]);
+        let dst_cid = LittleEndian::read_u64(&header[8..]);
+        let src_port = LittleEndian::read_u32(&header[16..]);
+        let dst_port = LittleEndian::read_u32(&header[20..]);
+        let ty = LittleEndian::read_u16(&header[28..]);
+        let op = LittleEndian::read_u16(&header[30..]);
+        let buf_alloc = LittleEndian::read_u32(&header[36..]);
+        let fwd_cnt = LittleEndian::read_u32(&header[40..]);
+
+        if src_cid != self.guest_cid || dst_cid != HOST_CID || ty != TYPE_STREAM {
+            if op != OP_RST {
+                self.reset(src_port, dst_port);
+            }
+            return;
+        }
+
+        let index = self.connections.iter()
+            .position(|c| c.guest_port == src_port && c.host_port == dst_port);
+        let index = match (index, op) {
+            (Some(index), _) => index,
+            (None, OP_REQUEST) => {
+                let connection = Connection {
+                    guest_port: src_port,
+                    host_port: dst_port,
+                    established: true,
+                    peer_buf_alloc: buf_alloc,
+                    peer_fwd_cnt: fwd_cnt,
+                    tx_cnt: 0,
+                    fwd_cnt: 0,
+                    reported_fwd_cnt: 0,
+                };
+                if self.connections.try_push(connection).is_ok() {
+                    println!("vsock: guest port {} connected to host port {} (connection {})",
+                             src_port, dst_port, self.connections.len() - 1);
+                    self.queue_packet(&connection, OP_RESPONSE, &[]);
+                } else {
+                    self.reset(src_port, dst_port);
+                }
+                return;
+            }
+            (None, OP_RST) => return,
+            (None, _) => {
+                self.reset(src_port, dst_port);
+                return;
+            }
+        };
+
+        let connection = &mut self.connections[index];
+        connection.peer_buf_alloc = buf_alloc;
+        connection.peer_fwd_cnt = fwd_cnt;
+        match op {
+            OP_RESPONSE => {
+                connection.established = true;
+                println!("vsock: connection {} to guest port {} established", index,
+                         connection.guest_port);
+            }
+            OP_RW => {
+                connection.fwd_cnt = connection.fwd_cnt.wrapping_add(len);
+                if connection.fwd_cnt.wrapping_sub(connection.reported_fwd_cnt) >= BUFFER_SIZE / 2 {
+                    connection.reported_fwd_cnt = connection.fwd_cnt;
+                    let connection = *connection;
+                    self.queue_packet(&connection, OP_CREDIT_UPDATE, &[]);
+                }
+            }
+            OP_CREDIT_REQUEST => {
+                connection.reported_fwd_cnt = connection.fwd_cnt;
+                let connection = *connection;
+                self.queue_packet(&connection, OP_CREDIT_UPDATE, &[]);
+            }
+            OP_SHUTDOWN | OP_RST => {
+                let connection = self.connections.remove(index);
+                println!("vsock: connection {} closed by guest", index);
+                if op == OP_SHUTDOWN {
+                    self.queue_packet(&connection, OP_RST, &[]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Driver for VsockDriver {
+    const DEVICE_ID: u32 = VIRTIO_ID_VSOCK;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 128;
+    // Receive, transmit and event queues. Nothing is ever sent on the event queue.
+    const NUM_QUEUES: u32 = 3;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if queue == TX_QUEUE {
+            loop {
+                let mut consumed = false;
+                let mut packet = None;
+                device.with_buffer(guest_memory, TX_QUEUE, |buffers| {
+                    let mut header = [0; HEADER_SIZE];
+                    let mut offset = 0;
+                    for buffer in buffers {
+                        let mut buffer = *buffer;
+                        if offset < HEADER_SIZE {
+                            let n = buffer.len().min(HEADER_SIZE - offset);
+                            header[offset..][..n].copy_from_slice(&buffer[..n]);
+                            offset += n;
+                            buffer = &buffer[n..];
+                        }
+                        if offset == HEADER_SIZE && !buffer.is_empty() &&
+                            LittleEndian::read_u16(&header[30..]) == OP_RW {
+                            let port = LittleEndian::read_u32(&header[16..]);
+                            print!("vsock:{}> {}", port,
+                                   core::str::from_utf8(buffer).unwrap_or("<binary data>\n"));
+                        }
+                    }
+                    if offset == HEADER_SIZE {
+                        packet = Some(header);
+                    }
+                    consumed = true;
+                    Some(0)
+                });
+
+                if !consumed {
+                    break;
+                }
+                if let Some(header) = packet {
+                    let len = LittleEndian::read_u32(&header[24..]);
+                    device.host_driver.handle_packet(&header, len);
+                }
+            }
+        }
+        Self::flush(device, guest_memory);
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=7 => device.host_driver.guest_cid.to_le_bytes()[offset as usize],
+            _ => 0,
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {
+        device.host_driver.connections.clear();
+        device.host_driver.pending.clear();
+    }
+}
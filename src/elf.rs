@@ -1,7 +1,31 @@
 #![allow(unused)]
 
+use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+use crate::error::{Error, Result};
+#[cfg(target_arch = "riscv64")]
+use crate::sum;
+
 // Values for ProgramHeader::type_
 const ELF_PROG_LOAD: u32 = 1;
+const ELF_PROG_DYNAMIC: u32 = 2;
+const ELF_PROG_INTERP: u32 = 3;
+const ELF_PROG_NOTE: u32 = 4;
+
+// Values for the type field of the ELF header
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_TYPE_DYN: u16 = 3;
+
+// Tags of entries in the dynamic section
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+// Relocation types
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_RELATIVE: u64 = 3;
 
 // Flag bits for ProgramHeader::flags
 const ELF_PROG_FLAG_EXEC: u32 = 1;
@@ -17,83 +41,653 @@ const ELF_SHT_STRTAB: u32 = 3;
 // Values for SectionHeader::name
 const ELF_SHN_UNDEF: u32 = 0;
 
-const ELF_MAGIC: u32 = 0;
-
-#[repr(C)]
-#[derive(Debug)]
-pub struct Ident {
-    magic: u32,
-    class: u8,
-    data: u8,
-    version: u8,
-    osabi: u8,
-    abiversion: u8,
-    padding: [u8; 7],
-}
-
-#[repr(C)]
-#[derive(Debug)]
-pub struct Elf64 {
-    ident: Ident,
-    type_: u16,
-	machine: u16,
-	version: u32,
-	entry: u64,
-	phoff: u64,
-	shoff: u64,
-	flags: u32,
-	ehsize: u16,
-	phentsize: u16,
-	phnum: u16,
-	shentsize: u16,
-	shnum: u16,
-	shstrndx: u16,
-}
-
-#[repr(C)]
-#[derive(Debug)]
+const ELF_MAGIC: u32 = 0x464C457F;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ELF_SHT_RISCV_ATTRIBUTES: u32 = 0x70000003;
+const TAG_RISCV_ARCH: u64 = 5;
+
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_BIG_ENDIAN: u8 = 2;
+const ELF_MACHINE_RISCV: u16 = 243;
+
+/// Offset and value of the magic number in the header of a flat RISC-V Linux `Image`.
+const LINUX_IMAGE_MAGIC_OFFSET: usize = 56;
+const LINUX_IMAGE_MAGIC: u32 = 0x05435352;
+
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const DYNAMIC_ENTRY_SIZE: usize = 16;
+const RELA_ENTRY_SIZE: usize = 24;
+
+/// A program header, decoded from its on-disk representation.
+#[derive(Copy, Clone, Debug)]
 pub struct ProgramHeader64 {
-    type_: u32,
-    flags: u32,
-    offset: u64,
-    va: u64,
-    pa: u64,
-    file_size: u64,
-    memory_size: u64,
-    align: u64,
-}
-
-// Returns (program entry point, max_address)
-pub unsafe fn load_elf(data: *const u8, base_address: *mut u8) -> (u64, u64) {
-    let elf = &*(data as *const Elf64);
-    assert_eq!(elf.ident.magic, 0x464C457F);
-    assert_eq!(elf.ident.class, 2); // 64-bit
-    assert_eq!(elf.ident.data, 1); // Little endian
-    assert_eq!(elf.machine, 243); // Machine = RISCV
-    assert_eq!(elf.type_, 2); // 64-bit
-    assert_eq!(elf.version, 1);
-
-    let mut max_addr = 0;
-    for i in 0..(elf.phnum as usize) {
-        let ph = &*(data.add(elf.phoff as usize + i * elf.phentsize as usize) as *const ProgramHeader64);
-
-        if ph.type_ == ELF_PROG_LOAD {
-            if ph.file_size > 0 {
-                let dst = base_address.add(ph.pa as usize);
-                let src = data.add(ph.offset as usize);
-                core::ptr::copy(src, dst, ph.file_size as usize);
+    pub type_: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub va: u64,
+    pub pa: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+/// Reasons a guest image can't run on this machine, as opposed to being malformed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Incompatibility {
+    /// A flat Linux `Image` rather than an ELF file.
+    RawImage,
+    BigEndian,
+    /// Built for another architecture, identified by its `e_machine` value.
+    Machine(u16),
+    /// Built for RV32.
+    Rv32,
+    /// The image's RISC-V attributes require a single letter extension the host doesn't have.
+    MissingExtension(char),
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Incompatibility::RawImage => write!(f, "it is a flat Linux Image; use the vmlinux ELF file instead"),
+            Incompatibility::BigEndian => write!(f, "it is big endian, but RISC-V is little endian"),
+            Incompatibility::Machine(machine) => write!(f, "it was built for ELF machine {}, not RISC-V", machine),
+            Incompatibility::Rv32 => write!(f, "it was built for RV32, but only RV64 guests are supported"),
+            Incompatibility::MissingExtension(ext) => {
+                write!(f, "it requires the '{}' extension, which this machine lacks", ext)
             }
-            if ph.memory_size > ph.file_size {
-                let dst = base_address.add((ph.pa + ph.file_size) as usize);
-                core::ptr::write_bytes(dst, 0, (ph.memory_size - ph.file_size) as usize);
+        }
+    }
+}
+
+/// Parse the single letter extensions of an ISA string like `rv64imafdc` or
+/// `rv64i2p1_m2p0_a2p1_zicsr2p0` into a mask with bit 0 for 'a', bit 1 for 'b' and so on. Version
+/// numbers and multi-letter extensions are skipped, and 'g' stands for "imafd".
+pub fn isa_letters(isa: &[u8]) -> u32 {
+    let isa = match isa.get(..4) {
+        Some(b"rv64") | Some(b"rv32") => &isa[4..],
+        _ => return 0,
+    };
+
+    let mut letters = 0;
+    for (n, component) in isa.split(|&c| c == b'_').enumerate() {
+        // Multi-letter extensions start with 'z', 's' or 'x', and only come after the first '_'.
+        if n > 0 && component.first().map_or(true, |c| b"zsx".contains(c)) {
+            continue;
+        }
+        let mut i = 0;
+        while i < component.len() {
+            let c = component[i];
+            i += 1;
+            match c {
+                b'g' => letters |= isa_letters(b"rv64imafd"),
+                b'a'..=b'z' => letters |= 1 << (c - b'a'),
+                _ => continue,
             }
+            // Skip a version number like "2" or "2p1".
+            while i < component.len() && component[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i + 1 < component.len() && component[i] == b'p' && component[i + 1].is_ascii_digit() {
+                i += 1;
+                while i < component.len() && component[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+        }
+    }
+    letters
+}
+
+/// Read an unsigned LEB128 number, advancing `data` past it.
+fn read_uleb128(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
 
-            if max_addr < ph.pa + ph.memory_size {
-                max_addr = ph.pa + ph.memory_size;
+/// An entry of a note segment.
+#[derive(Copy, Clone, Debug)]
+pub struct Note<'a> {
+    /// The owner, such as `b"Linux"`, without its terminating NUL.
+    pub name: &'a [u8],
+    pub type_: u32,
+    pub desc: &'a [u8],
+}
+
+/// Iterator over the entries of a note segment. Stops at the first malformed entry.
+struct Notes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Notes<'a> {
+    type Item = Note<'a>;
+    fn next(&mut self) -> Option<Note<'a>> {
+        let header = self.data.get(..12)?;
+        let namesz = LittleEndian::read_u32(header) as usize;
+        let descsz = LittleEndian::read_u32(&header[4..]) as usize;
+        let desc_start = 12 + ((namesz + 3) & !3);
+        let end = desc_start.checked_add((descsz + 3) & !3)?;
+        let (name, desc) = match (self.data.get(12..(12 + namesz)), self.data.get(desc_start..(desc_start + descsz))) {
+            (Some(name), Some(desc)) => (name, desc),
+            _ => {
+                self.data = &[];
+                return None;
             }
+        };
+        let type_ = LittleEndian::read_u32(&header[8..]);
+        self.data = self.data.get(end..).unwrap_or(&[]);
+        Some(Note { name: name.split(|&c| c == 0).next().unwrap_or(&[]), type_, desc })
+    }
+}
+
+/// An entry of the symbol table.
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol64<'a> {
+    /// The name, without its terminating NUL. Empty if it couldn't be found in the string table.
+    pub name: &'a [u8],
+    /// Low four bits of st_info, such as STT_FUNC.
+    pub type_: u8,
+    pub section: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+/// A 64-bit little endian RISC-V executable held in a byte slice. Headers are decoded with bounds
+/// checks rather than by casting pointers into the image, so images from untrusted sources can be
+/// inspected safely.
+pub struct Elf64<'a> {
+    data: &'a [u8],
+    /// Whether this is a position independent (ET_DYN) image rather than an executable.
+    pub relocatable: bool,
+    pub entry: u64,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf64<'a> {
+    /// Check the ELF header and that the program header table lies within `data`.
+    /// Images for other machines are reported as `Error::IncompatibleElf` rather than as invalid.
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let incompatible = |reason| Err(Error::IncompatibleElf(reason));
+        let header = data.get(..ELF_HEADER_SIZE).ok_or(Error::InvalidElf)?;
+        if LittleEndian::read_u32(header) != ELF_MAGIC {
+            if LittleEndian::read_u32(&header[LINUX_IMAGE_MAGIC_OFFSET..]) == LINUX_IMAGE_MAGIC {
+                return incompatible(Incompatibility::RawImage);
+            }
+            return Err(Error::InvalidElf);
+        }
+        if header[5] == ELF_DATA_BIG_ENDIAN {
+            return incompatible(Incompatibility::BigEndian);
+        }
+        let machine = LittleEndian::read_u16(&header[18..]);
+        if machine != ELF_MACHINE_RISCV {
+            return incompatible(Incompatibility::Machine(machine));
+        }
+        if header[4] == ELF_CLASS_32 {
+            return incompatible(Incompatibility::Rv32);
+        }
+
+        let type_ = LittleEndian::read_u16(&header[16..]);
+        if header[4] != 2 // 64-bit
+            || header[5] != 1 // Little endian
+            || (type_ != ELF_TYPE_EXEC && type_ != ELF_TYPE_DYN)
+            || LittleEndian::read_u32(&header[20..]) != 1 {
+            return Err(Error::InvalidElf);
+        }
+
+        let elf = Self {
+            data,
+            relocatable: type_ == ELF_TYPE_DYN,
+            entry: LittleEndian::read_u64(&header[24..]),
+            phoff: LittleEndian::read_u64(&header[32..]) as usize,
+            phentsize: LittleEndian::read_u16(&header[54..]) as usize,
+            phnum: LittleEndian::read_u16(&header[56..]) as usize,
+        };
+        let table_size = elf.phentsize * elf.phnum;
+        if (elf.phnum > 0 && elf.phentsize < PROGRAM_HEADER_SIZE)
+            || elf.phoff.checked_add(table_size).map_or(true, |end| end > data.len()) {
+            return Err(Error::InvalidElf);
+        }
+        Ok(elf)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader64> + 'a {
+        let (data, phoff, phentsize) = (self.data, self.phoff, self.phentsize);
+        (0..self.phnum).map(move |i| {
+            let ph = &data[(phoff + i * phentsize)..];
+            ProgramHeader64 {
+                type_: LittleEndian::read_u32(ph),
+                flags: LittleEndian::read_u32(&ph[4..]),
+                offset: LittleEndian::read_u64(&ph[8..]),
+                va: LittleEndian::read_u64(&ph[16..]),
+                pa: LittleEndian::read_u64(&ph[24..]),
+                file_size: LittleEndian::read_u64(&ph[32..]),
+                memory_size: LittleEndian::read_u64(&ph[40..]),
+                align: LittleEndian::read_u64(&ph[48..]),
+            }
+        })
+    }
+
+    /// The file contents of a segment, or an error if they extend past the end of the image.
+    pub fn segment_data(&self, ph: &ProgramHeader64) -> Result<&'a [u8]> {
+        let start = ph.offset as usize;
+        let end = start.checked_add(ph.file_size as usize).ok_or(Error::InvalidElf)?;
+        self.data.get(start..end).ok_or(Error::InvalidElf)
+    }
+
+    /// The entries of every note segment.
+    pub fn notes(&self) -> impl Iterator<Item = Note<'a>> + 'a {
+        let data = self.data;
+        self.program_headers().filter(|ph| ph.type_ == ELF_PROG_NOTE).flat_map(move |ph| {
+            let start = ph.offset as usize;
+            let segment = start.checked_add(ph.file_size as usize).and_then(|end| data.get(start..end));
+            Notes { data: segment.unwrap_or(&[]) }
+        })
+    }
+
+    /// The file contents at virtual address `va`, up to the end of the segment holding it.
+    pub fn data_from(&self, va: u64) -> Option<&'a [u8]> {
+        let ph = self.program_headers()
+            .find(|ph| ph.type_ == ELF_PROG_LOAD && va >= ph.va && va - ph.va < ph.file_size)?;
+        self.segment_data(&ph).ok().map(|data| &data[(va - ph.va) as usize..])
+    }
+
+    /// The contents of section `index`, or an error if it doesn't exist or extends past the end of
+    /// the image. Also returns the section's type and link field.
+    fn section(&self, index: usize) -> Result<(u32, u32, &'a [u8])> {
+        let header = &self.data[..ELF_HEADER_SIZE];
+        let shoff = LittleEndian::read_u64(&header[40..]) as usize;
+        let shentsize = LittleEndian::read_u16(&header[58..]) as usize;
+        let shnum = LittleEndian::read_u16(&header[60..]) as usize;
+        if index >= shnum || shentsize < SECTION_HEADER_SIZE {
+            return Err(Error::InvalidElf);
         }
+
+        let start = index.checked_mul(shentsize).and_then(|o| o.checked_add(shoff)).ok_or(Error::InvalidElf)?;
+        let sh = self.data.get(start..).and_then(|d| d.get(..SECTION_HEADER_SIZE)).ok_or(Error::InvalidElf)?;
+        let (type_, offset, size) = (LittleEndian::read_u32(&sh[4..]),
+                                     LittleEndian::read_u64(&sh[24..]) as usize,
+                                     LittleEndian::read_u64(&sh[32..]) as usize);
+        let end = offset.checked_add(size).ok_or(Error::InvalidElf)?;
+        Ok((type_, LittleEndian::read_u32(&sh[40..]), self.data.get(offset..end).ok_or(Error::InvalidElf)?))
     }
 
-    //    base_address.add(elf.entry as usize)
-    (0x80000000, 0x80000000 + max_addr)
+    /// The entries of the symbol table. Stripped images, and images whose symbol or string table
+    /// doesn't fit in the file, have none.
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol64<'a>> + 'a {
+        let shnum = LittleEndian::read_u16(&self.data[60..]) as usize;
+        let tables = (0..shnum).filter_map(|i| self.section(i).ok())
+            .find(|&(type_, _, _)| type_ == ELF_SHT_SYMTAB)
+            .and_then(|(_, link, symtab)| match self.section(link as usize) {
+                Ok((ELF_SHT_STRTAB, _, strtab)) => Some((symtab, strtab)),
+                _ => None,
+            });
+        let (symtab, strtab): (&'a [u8], &'a [u8]) = tables.unwrap_or((&[], &[]));
+
+        symtab.chunks_exact(SYMBOL_SIZE).map(move |sym| {
+            let name = strtab.get(LittleEndian::read_u32(sym) as usize..).unwrap_or(&[]);
+            Symbol64 {
+                name: name.split(|&c| c == 0).next().unwrap_or(&[]),
+                type_: sym[4] & 0xf,
+                section: LittleEndian::read_u16(&sym[6..]),
+                value: LittleEndian::read_u64(&sym[8..]),
+                size: LittleEndian::read_u64(&sym[16..]),
+            }
+        })
+    }
+
+    /// The `Tag_RISCV_arch` string from the image's RISC-V attributes section, if it has one.
+    pub fn arch_attribute(&self) -> Option<&'a [u8]> {
+        let shnum = LittleEndian::read_u16(&self.data[60..]) as usize;
+        let (_, _, section) = (0..shnum).filter_map(|i| self.section(i).ok())
+            .find(|&(type_, _, _)| type_ == ELF_SHT_RISCV_ATTRIBUTES)?;
+
+        // A version byte, then a subsection for the "riscv" vendor, holding a Tag_File
+        // sub-subsection that lists attributes as (ULEB128 tag, value) pairs. Values of odd
+        // numbered tags are strings, and those of even numbered tags are ULEB128 numbers.
+        let (&version, mut rest) = section.split_first()?;
+        if version != b'A' {
+            return None;
+        }
+        while rest.len() >= 4 {
+            let len = LittleEndian::read_u32(rest) as usize;
+            let subsection = rest.get(4..len)?;
+            rest = &rest[len..];
+            let vendor_len = subsection.iter().position(|&c| c == 0)?;
+            if &subsection[..vendor_len] != b"riscv" {
+                continue;
+            }
+
+            let mut attributes = subsection.get((vendor_len + 1)..)?;
+            // Skip the Tag_File tag and size.
+            attributes = attributes.get(5..)?;
+            while !attributes.is_empty() {
+                let tag = read_uleb128(&mut attributes)?;
+                if tag % 2 == 1 {
+                    let len = attributes.iter().position(|&c| c == 0)?;
+                    if tag == TAG_RISCV_ARCH {
+                        return Some(&attributes[..len]);
+                    }
+                    attributes = &attributes[(len + 1)..];
+                } else {
+                    read_uleb128(&mut attributes)?;
+                }
+            }
+        }
+        None
+    }
+
+    /// Check that every single letter extension listed in the image's RISC-V attributes is in
+    /// `available`, a mask as returned by `isa_letters`. Images without attributes, and hosts whose
+    /// extensions aren't known (`available` is zero), always pass.
+    pub fn check_extensions(&self, available: u32) -> Result<()> {
+        let required = self.arch_attribute().map_or(0, isa_letters);
+        match (0..26).find(|&i| available != 0 && required & !available & (1 << i) != 0) {
+            Some(i) => Err(Error::IncompatibleElf(Incompatibility::MissingExtension((b'a' + i as u8) as char))),
+            None => Ok(()),
+        }
+    }
+
+    /// The `len` bytes of file contents mapped at virtual address `va` by some PT_LOAD segment.
+    fn data_at(&self, va: u64, len: u64) -> Result<&'a [u8]> {
+        for ph in self.program_headers().filter(|ph| ph.type_ == ELF_PROG_LOAD) {
+            if va >= ph.va && va - ph.va <= ph.file_size && len <= ph.file_size - (va - ph.va) {
+                let data = self.segment_data(&ph)?;
+                let start = (va - ph.va) as usize;
+                return Ok(&data[start..(start + len as usize)]);
+            }
+        }
+        Err(Error::InvalidElf)
+    }
+
+    /// The (address, size, entry size) of the RELA relocation table listed in the dynamic
+    /// section, if there is one.
+    fn rela_table(&self) -> Result<Option<(u64, u64, u64)>> {
+        let dynamic = match self.program_headers().find(|ph| ph.type_ == ELF_PROG_DYNAMIC) {
+            Some(ph) => self.segment_data(&ph)?,
+            None => return Ok(None),
+        };
+
+        let (mut rela, mut relasz, mut relaent) = (None, 0, RELA_ENTRY_SIZE as u64);
+        for entry in dynamic.chunks_exact(DYNAMIC_ENTRY_SIZE) {
+            let value = LittleEndian::read_u64(&entry[8..]);
+            match LittleEndian::read_u64(entry) {
+                DT_NULL => break,
+                DT_RELA => rela = Some(value),
+                DT_RELASZ => relasz = value,
+                DT_RELAENT => relaent = value,
+                _ => {}
+            }
+        }
+        if relaent < RELA_ENTRY_SIZE as u64 {
+            return Err(Error::InvalidElf);
+        }
+        Ok(rela.map(|rela| (rela, relasz, relaent)))
+    }
+}
+
+/// Most PT_LOAD segments an image may have. Kernels normally have two or three.
+const MAX_LOAD_SEGMENTS: usize = 16;
+
+/// Relocatable images are placed on 2MB boundaries, which is what RISC-V Linux expects of the
+/// address it is loaded at.
+const RELOCATABLE_ALIGN: u64 = 2 << 20;
+
+/// Where raw images are placed in guest memory, which is also where firmware like OpenSBI expects
+/// to find its payload.
+const FLAT_LOAD_OFFSET: u64 = 2 << 20;
+
+pub struct LoadedElf {
+    /// Guest physical address to start executing at.
+    pub entry: u64,
+    /// Guest physical address just past the end of the image.
+    pub max_addr: u64,
+    /// For relocatable images, the guest physical address the image was placed at.
+    pub load_base: Option<u64>,
+}
+
+/// Load an executable into guest memory, which starts at `base_address` and is `guest_size` bytes
+/// long. Every segment is checked before anything is copied: segment contents must lie within
+/// `data`, segments must fit in guest memory without overlapping each other, and images that need
+/// a program interpreter are rejected. The part of each segment beyond its file contents is zeroed,
+/// since guest memory may hold data left from earlier use.
+///
+/// Executables are placed according to the physical addresses of their segments, relative to the
+/// start of guest memory. Relocatable (ET_DYN) images can go anywhere: they are placed at a 2MB
+/// aligned offset picked by `seed` out of every offset at which they fit, and their R_RISCV_RELATIVE
+/// relocations are applied for that address. A seed of zero puts them at the start of memory.
+#[cfg(target_arch = "riscv64")]
+pub unsafe fn load_elf(data: &[u8], base_address: *mut u8, guest_size: u64, seed: u64) -> Result<LoadedElf> {
+    let elf = Elf64::parse(data)?;
+
+    // Offsets into guest memory are computed from physical addresses for executables, but from
+    // virtual addresses for relocatable images, whose relocations are in terms of the latter.
+    let link_base = if elf.relocatable {
+        let lowest = elf.program_headers().filter(|ph| ph.type_ == ELF_PROG_LOAD).map(|ph| ph.va).min();
+        lowest.unwrap_or(0) & !(RELOCATABLE_ALIGN - 1)
+    } else {
+        0
+    };
+    let image_offset = |ph: &ProgramHeader64| {
+        if elf.relocatable { ph.va - link_base } else { ph.pa }
+    };
+
+    let mut segments = ArrayVec::<[(u64, u64); MAX_LOAD_SEGMENTS]>::new();
+    for ph in elf.program_headers() {
+        match ph.type_ {
+            ELF_PROG_LOAD => {
+                elf.segment_data(&ph)?;
+                let start = image_offset(&ph);
+                let end = match start.checked_add(ph.memory_size) {
+                    Some(end) if ph.memory_size >= ph.file_size && end <= guest_size => end,
+                    _ => return Err(Error::InvalidElf),
+                };
+                if ph.memory_size == 0 {
+                    continue;
+                }
+                if segments.iter().any(|&(s, e)| start < e && s < end) {
+                    return Err(Error::InvalidElf);
+                }
+                segments.try_push((start, end)).map_err(|_| Error::InvalidElf)?;
+            }
+            ELF_PROG_INTERP => return Err(Error::UnsupportedElf),
+            _ => {}
+        }
+    }
+    if segments.is_empty() {
+        return Err(Error::InvalidElf);
+    }
+    let entry = elf.entry.wrapping_sub(link_base);
+    if elf.relocatable {
+        if !segments.iter().any(|&(s, e)| entry >= s && entry < e) {
+            return Err(Error::InvalidElf);
+        }
+        for_each_relocation(&elf, link_base, &segments, |_, _| Ok(()))?;
+    }
+    let image_end = segments.iter().map(|&(_, end)| end).max().unwrap_or(0);
+
+    let load_offset = if elf.relocatable {
+        let slots = (guest_size - image_end) / RELOCATABLE_ALIGN + 1;
+        (seed % slots) * RELOCATABLE_ALIGN
+    } else {
+        0
+    };
+    // Guest physical address of image offset zero.
+    let load_base = 0x80000000 + load_offset;
+
+    for ph in elf.program_headers().filter(|ph| ph.type_ == ELF_PROG_LOAD && ph.memory_size > 0) {
+        let offset = load_offset + image_offset(&ph);
+        if ph.file_size > 0 {
+            let dst = base_address.add(offset as usize);
+            sum::copy_to_guest(dst as u64, elf.segment_data(&ph)?)?;
+        }
+        if ph.memory_size > ph.file_size {
+            let dst = base_address.add((offset + ph.file_size) as usize);
+            sum::zero_guest(dst as u64, (ph.memory_size - ph.file_size) as usize)?;
+        }
+    }
+
+    if !elf.relocatable {
+        return Ok(LoadedElf { entry: 0x80000000, max_addr: 0x80000000 + image_end, load_base: None });
+    }
+
+    // The image will run at load_base rather than link_base, so every address it holds has to be
+    // moved by the difference.
+    let delta = load_base.wrapping_sub(link_base);
+    for_each_relocation(&elf, link_base, &segments, |offset, addend| {
+        let dst = base_address.add((load_offset + offset) as usize);
+        sum::copy_to_guest(dst as u64, &addend.wrapping_add(delta).to_le_bytes())
+    })?;
+
+    Ok(LoadedElf { entry: load_base + entry, max_addr: load_base + image_end, load_base: Some(load_base) })
+}
+
+/// Whether `data` starts with the ELF magic number. Anything else is loaded as a raw image.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= 4 && LittleEndian::read_u32(data) == ELF_MAGIC
+}
+
+/// Load a raw image, as unikernels and firmware payloads are often built, `FLAT_LOAD_OFFSET` bytes
+/// into guest memory and start it at its first byte. Nothing is known about how much memory the
+/// image needs beyond its own size, so the rest of guest memory is left as it is.
+#[cfg(target_arch = "riscv64")]
+pub unsafe fn load_flat(data: &[u8], base_address: *mut u8, guest_size: u64) -> Result<LoadedElf> {
+    let end = FLAT_LOAD_OFFSET + data.len() as u64;
+    if data.is_empty() || end > guest_size {
+        return Err(Error::InvalidElf);
+    }
+    sum::copy_to_guest(base_address.add(FLAT_LOAD_OFFSET as usize) as u64, data)?;
+    Ok(LoadedElf { entry: 0x80000000 + FLAT_LOAD_OFFSET, max_addr: 0x80000000 + end, load_base: None })
+}
+
+/// Call `f` with the image offset and addend of each R_RISCV_RELATIVE relocation of a relocatable
+/// image, after checking that it lies within one of `segments`. Other relocation types can't be
+/// resolved without a symbol table, so they make the image unsupported.
+fn for_each_relocation<F>(elf: &Elf64, link_base: u64, segments: &[(u64, u64)], mut f: F) -> Result<()>
+    where F: FnMut(u64, u64) -> Result<()> {
+    let (rela, relasz, relaent) = match elf.rela_table()? {
+        Some(table) => table,
+        None => return Ok(()),
+    };
+    let table = elf.data_at(rela, relasz)?;
+    for entry in table.chunks(relaent as usize).filter(|e| e.len() >= RELA_ENTRY_SIZE) {
+        let (target, info, addend) = (LittleEndian::read_u64(entry),
+                                      LittleEndian::read_u64(&entry[8..]),
+                                      LittleEndian::read_u64(&entry[16..]));
+        match info & 0xffffffff {
+            R_RISCV_NONE => continue,
+            R_RISCV_RELATIVE => {}
+            _ => return Err(Error::UnsupportedElf),
+        }
+
+        let offset = target.wrapping_sub(link_base);
+        if !segments.iter().any(|&(s, e)| offset >= s && offset < e && e - offset >= 8) {
+            return Err(Error::InvalidElf);
+        }
+        f(offset, addend)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little endian RV64 executable with a single PT_LOAD segment holding `contents`.
+    fn image(contents: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE];
+        LittleEndian::write_u32(&mut data, ELF_MAGIC);
+        data[4] = 2;
+        data[5] = 1;
+        LittleEndian::write_u16(&mut data[16..], ELF_TYPE_EXEC);
+        LittleEndian::write_u16(&mut data[18..], ELF_MACHINE_RISCV);
+        LittleEndian::write_u32(&mut data[20..], 1);
+        LittleEndian::write_u64(&mut data[24..], 0x80200000);
+        LittleEndian::write_u64(&mut data[32..], ELF_HEADER_SIZE as u64);
+        LittleEndian::write_u16(&mut data[54..], PROGRAM_HEADER_SIZE as u16);
+        LittleEndian::write_u16(&mut data[56..], 1);
+
+        let ph = &mut data[ELF_HEADER_SIZE..];
+        LittleEndian::write_u32(ph, ELF_PROG_LOAD);
+        LittleEndian::write_u64(&mut ph[8..], (ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64);
+        LittleEndian::write_u64(&mut ph[16..], 0x80200000);
+        LittleEndian::write_u64(&mut ph[24..], 0x80200000);
+        LittleEndian::write_u64(&mut ph[32..], contents.len() as u64);
+        LittleEndian::write_u64(&mut ph[40..], contents.len() as u64);
+        data.extend_from_slice(contents);
+        data
+    }
+
+    #[test]
+    fn parse_executable() {
+        let data = image(b"\x73\x00\x50\x10");
+        let elf = Elf64::parse(&data).unwrap();
+        assert!(!elf.relocatable);
+        assert_eq!(elf.entry, 0x80200000);
+
+        let headers: Vec<_> = elf.program_headers().collect();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(elf.segment_data(&headers[0]).unwrap(), b"\x73\x00\x50\x10");
+        assert_eq!(elf.data_from(0x80200002), Some(&b"\x50\x10"[..]));
+        assert_eq!(elf.data_from(0x80200004), None);
+    }
+
+    #[test]
+    fn reject_truncated_images() {
+        let data = image(b"\x73\x00\x50\x10");
+        assert_eq!(Elf64::parse(&data[..ELF_HEADER_SIZE - 1]).err(), Some(Error::InvalidElf));
+        assert_eq!(Elf64::parse(&data[..ELF_HEADER_SIZE + 8]).err(), Some(Error::InvalidElf));
+
+        // The header table fits, but the segment it describes doesn't.
+        let truncated = &data[..data.len() - 1];
+        let elf = Elf64::parse(truncated).unwrap();
+        let ph = elf.program_headers().next().unwrap();
+        assert_eq!(elf.segment_data(&ph).err(), Some(Error::InvalidElf));
+        assert_eq!(elf.data_from(0x80200000), None);
+    }
+
+    #[test]
+    fn reject_incompatible_images() {
+        let incompatible = |data: &[u8]| match Elf64::parse(data) {
+            Err(Error::IncompatibleElf(reason)) => Some(reason),
+            _ => None,
+        };
+
+        let mut raw = vec![0; ELF_HEADER_SIZE];
+        LittleEndian::write_u32(&mut raw[LINUX_IMAGE_MAGIC_OFFSET..], LINUX_IMAGE_MAGIC);
+        assert_eq!(incompatible(&raw), Some(Incompatibility::RawImage));
+
+        let mut big_endian = image(&[]);
+        big_endian[5] = ELF_DATA_BIG_ENDIAN;
+        assert_eq!(incompatible(&big_endian), Some(Incompatibility::BigEndian));
+
+        let mut x86 = image(&[]);
+        LittleEndian::write_u16(&mut x86[18..], 62);
+        assert_eq!(incompatible(&x86), Some(Incompatibility::Machine(62)));
+
+        let mut rv32 = image(&[]);
+        rv32[4] = ELF_CLASS_32;
+        assert_eq!(incompatible(&rv32), Some(Incompatibility::Rv32));
+    }
+
+    #[test]
+    fn isa_strings() {
+        let letters = |s: &str| s.chars().fold(0, |mask, c| mask | 1 << (c as u8 - b'a'));
+        assert_eq!(isa_letters(b"rv64imafdc"), letters("imafdc"));
+        assert_eq!(isa_letters(b"rv64gc"), letters("imafdc"));
+        assert_eq!(isa_letters(b"rv64i2p1_m2p0_a2p1_c2p0_zicsr2p0_sstc"), letters("imac"));
+        assert_eq!(isa_letters(b"rv64imac_xtheadba_h"), letters("imach"));
+        assert_eq!(isa_letters(b"rv64"), 0);
+        assert_eq!(isa_letters(b"imac"), 0);
+    }
 }
@@ -0,0 +1,112 @@
+//! Emulation of the privileged instructions that trap when the guest kernel runs them.
+//!
+//! The guest's supervisor mode actually runs in user mode, so sret, sfence.vma, wfi and every
+//! access to a supervisor CSR raise an illegal instruction exception. They are carried out here
+//! against the guest's virtual CSRs in `Context`. Illegal instructions from guest user mode, and
+//! anything that isn't one of these, are left to the guest.
+
+use riscv_decode::Instruction;
+use crate::context::Context;
+use crate::dispatch::Trap;
+use crate::exits::ExitReason;
+use crate::riscv::bits::*;
+use crate::trap::U64Bits;
+use crate::{irqlatency, memusage, pmap, riscv, trap};
+
+/// Handle an illegal instruction exception. Only takes the ones from the guest kernel.
+pub fn handle_trap(state: &mut Context, trap: &Trap) -> bool {
+    if !state.smode() {
+        return false;
+    }
+
+    let pc = trap.pc;
+    let (instruction, len) = match trap.instruction.unwrap() {
+        Ok(instruction) => instruction,
+        Err(e) => trap::terminate_guest(state, e),
+    };
+    let mut advance_pc = true;
+    let decoded = riscv_decode::decode(instruction).ok();
+    state.record_exit(match decoded {
+        Some(Instruction::Sret) => ExitReason::Sret,
+        Some(Instruction::SfenceVma(_)) => ExitReason::SfenceVma,
+        Some(Instruction::Wfi) => ExitReason::Wfi,
+        Some(Instruction::Csrrw(_)) | Some(Instruction::Csrrs(_)) | Some(Instruction::Csrrc(_)) |
+        Some(Instruction::Csrrwi(_)) | Some(Instruction::Csrrsi(_)) | Some(Instruction::Csrrci(_)) => {
+            ExitReason::CsrAccess
+        }
+        _ => ExitReason::IllegalInstruction,
+    });
+    match decoded {
+        Some(Instruction::Sret) => {
+            if !state.csrs.sstatus.get(STATUS_SIE) && state.csrs.sstatus.get(STATUS_SPIE) {
+                state.no_interrupt = false;
+            }
+            let resume = state.sret(pc);
+            riscv::set_sepc(resume);
+            irqlatency::returned(state);
+            advance_pc = false;
+
+            if !state.smode() {
+                state.no_interrupt = false;
+            }
+        }
+        Some(Instruction::SfenceVma(rtype)) => pmap::handle_sfence_vma(state, rtype),
+        Some(Instruction::Csrrw(i)) => if let Some(prev) = state.get_csr(i.csr()) {
+            let value = state.saved_registers.get(i.rs1());
+            state.set_csr(i.csr(), value);
+            state.saved_registers.set(i.rd(), prev);
+        }
+        Some(Instruction::Csrrs(i)) => if let Some(prev) = state.get_csr(i.csr()) {
+            let mask = state.saved_registers.get(i.rs1());
+            if mask != 0 {
+                state.set_csr(i.csr(), prev | mask);
+            }
+            state.saved_registers.set(i.rd(), prev);
+        }
+        Some(Instruction::Csrrc(i)) => if let Some(prev) = state.get_csr(i.csr()) {
+            let mask = state.saved_registers.get(i.rs1());
+            if mask != 0 {
+                state.set_csr(i.csr(), prev & !mask);
+            }
+            state.saved_registers.set(i.rd(), prev);
+        }
+        Some(Instruction::Csrrwi(i)) => if let Some(prev) = state.get_csr(i.csr()) {
+            state.set_csr(i.csr(), i.zimm() as u64);
+            state.saved_registers.set(i.rd(), prev);
+        }
+        Some(Instruction::Csrrsi(i)) => if let Some(prev) = state.get_csr(i.csr()) {
+            let mask = i.zimm() as u64;
+            if mask != 0 {
+                state.set_csr(i.csr(), prev | mask);
+            }
+            state.saved_registers.set(i.rd(), prev);
+        }
+        Some(Instruction::Csrrci(i)) => if let Some(prev) = state.get_csr(i.csr()) {
+            let mask = i.zimm() as u64;
+            if mask != 0 {
+                state.set_csr(i.csr(), prev & !mask);
+            }
+            state.saved_registers.set(i.rd(), prev);
+        }
+        Some(Instruction::Wfi) => {
+            memusage::publish(&state);
+            trap::idle(state);
+        }
+        Some(decoded) => {
+            println!("Unrecognized instruction! {:?} @ pc={:#x}", decoded, pc);
+            trap::forward_exception(state, trap.cause, pc);
+            advance_pc = false;
+        }
+        None => {
+            println!("Unrecognized instruction {:#x} @ pc={:#x}", instruction, pc);
+            trap::forward_exception(state, trap.cause, pc);
+            advance_pc = false;
+        }
+    }
+
+    if advance_pc {
+        riscv::set_sepc(pc + len);
+    }
+    trap::maybe_forward_interrupt(state, csrr!(sepc));
+    true
+}
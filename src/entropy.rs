@@ -0,0 +1,84 @@
+//! Random seeds for guests.
+//!
+//! Guest kernels want entropy before they have drivers for any random number generator: Linux reads
+//! `/chosen/rng-seed` to seed its pool and `/chosen/kaslr-seed` to pick where to place itself. Each
+//! guest gets its own seeds, derived from whatever the hypervisor can find:
+//!
+//!   * the `rng-seed` that firmware (QEMU, for instance) put in the host's /chosen, if any;
+//!   * jitter in how long a stretch of memory accesses takes, sampled many times;
+//!   * the hart id and the time counters.
+//!
+//! These are mixed into a small pool from which seeds are drawn. The mixing isn't cryptographic,
+//! and without a seed from firmware the jitter of a simple in-order core or an emulator may carry
+//! little entropy, so guests should treat the seeds as a supplement to a real entropy source.
+
+/// Number of timing samples mixed into the pool.
+const JITTER_SAMPLES: usize = 256;
+
+pub struct Entropy {
+    state: [u64; 4],
+    counter: u64,
+}
+
+/// Scramble the bits of `x` (the SplitMix64 finalizer).
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Time a short walk over a buffer on the stack. The low bits vary with cache and pipeline state.
+fn jitter_sample(buffer: &mut [u64; 64], round: usize) -> u64 {
+    let start = csrr!(cycle);
+    for i in 0..buffer.len() {
+        let j = (i * 7 + round) % buffer.len();
+        buffer[j] = buffer[j].wrapping_add(buffer[i] ^ start).rotate_left(1);
+    }
+    csrr!(cycle).wrapping_sub(start) ^ buffer[round % buffer.len()]
+}
+
+impl Entropy {
+    /// Gather entropy for the guest on hart `hartid`, starting from the host's `rng-seed`.
+    pub fn gather(host_seed: &[u8], hartid: u64) -> Self {
+        let mut pool = Self {
+            state: [0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1],
+            counter: 0,
+        };
+        for chunk in host_seed.chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            pool.absorb(u64::from_le_bytes(bytes));
+        }
+        pool.absorb(hartid);
+        pool.absorb(csrr!(time));
+
+        let mut buffer = [0u64; 64];
+        for round in 0..JITTER_SAMPLES {
+            pool.absorb(jitter_sample(&mut buffer, round));
+        }
+        pool.absorb(csrr!(cycle));
+        pool
+    }
+
+    fn absorb(&mut self, value: u64) {
+        let i = self.counter as usize % self.state.len();
+        let next = self.state[(i + 1) % self.state.len()];
+        self.state[i] = mix(self.state[i] ^ value ^ next.rotate_left(23));
+        self.counter += 1;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.counter += 1;
+        let x = self.state.iter().fold(self.counter.wrapping_mul(0x9e3779b97f4a7c15), |x, &s| mix(x ^ s));
+        // Feed the output back, so earlier outputs can't be recomputed from the state.
+        self.absorb(x);
+        x
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Errors produced while initializing a guest or emulating its accesses.
+//!
+//! `Error::GuestFault` means the guest did something its own kernel should hear about, and is
+//! reflected back into it as an exception. Every other kind of error ends the guest (see
+//! `trap::terminate_guest`). Panics are reserved for violations of the hypervisor's own
+//! invariants.
+
+use crate::elf::Incompatibility;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The access should raise an exception inside the guest.
+    GuestFault,
+    /// The guest used an instruction that can't be emulated in this context.
+    UnsupportedInstruction(u32),
+    /// The guest programmed an emulated device in a way that isn't supported.
+    UnsupportedDeviceAccess(u64),
+    /// The guest made an SBI call that isn't implemented.
+    UnsupportedSbiCall(u64),
+    /// The guest tried to access addresses reserved for the hypervisor.
+    ReservedAddress(u64),
+    /// The hypervisor faulted while accessing guest memory at this address.
+    InaccessibleGuestMemory(u64),
+    /// There is no space left for shadow page tables.
+    OutOfMemory,
+    /// A device tree blob is malformed or uses an unsupported version.
+    InvalidFdt,
+    /// The guest kernel image is not a valid RISC-V ELF executable or raw image, or doesn't fit in
+    /// guest memory.
+    InvalidElf,
+    /// The guest kernel image is valid but needs features the loader lacks, like an interpreter.
+    UnsupportedElf,
+    /// The guest kernel image was built for a different kind of machine.
+    IncompatibleElf(Incompatibility),
+    /// A host device failed a request or didn't behave as its driver expects.
+    DeviceIo,
+    /// A disk image is malformed or uses features that aren't supported.
+    InvalidDiskImage,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,177 @@
+//! Log of guest lifecycle events, readable by a control guest.
+//!
+//! Events are appended to a ring in the shared statics page by whichever hart they happen on, and
+//! numbered with a sequence number that never repeats. The guest named by the `rvirt,control-guest`
+//! property of the host's /chosen can copy them out with the RVirt SBI extension, so that a
+//! management agent running in it can notice crashes and throttling without parsing the console.
+//! Readers that fall more than `LOG_SIZE` events behind lose the oldest ones, which shows up as a
+//! gap in the sequence numbers. The monitor's `events` command prints the log too.
+
+use crate::context::Context;
+use crate::hart;
+use crate::sbi::*;
+use crate::statics::SHARED_STATICS;
+
+const LOG_SIZE: usize = 256;
+
+/// Size of an `Event` as copied into guest memory.
+pub const EVENT_SIZE: u64 = 32;
+
+/// Largest number of events copied by one call, to bound the time spent in it.
+const MAX_READ: u64 = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum EventKind {
+    /// The guest was started, at boot or after a restart. Data: the number of earlier restarts.
+    Started = 1,
+    /// The guest hit an error it can't recover from. Data: the guest pc.
+    Crashed = 2,
+    /// The guest is about to be restarted by its crash policy. Data: the restart delay in ticks.
+    Restarting = 3,
+    /// The guest shut down. Data: its exit code.
+    Exited = 4,
+    /// A queue notification was held back by I/O throttling. Data: the device index in the upper
+    /// 32 bits and the queue index in the lower.
+    Throttled = 5,
+    /// The guest accessed a device in a way that isn't supported. Data: the guest physical address.
+    DeviceError = 6,
+    /// A virtio device was added to the guest while it was running. Data: the slot in the upper 32
+    /// bits and the index of the host device in the lower.
+    DeviceAdded = 7,
+    /// The guest was asked to power off because the machine is shutting down. Data: the host time
+    /// by which it will be stopped if it hasn't.
+    ShutdownRequested = 8,
+    /// The guest asked to be reset, and is about to be restarted. Data: the reason it gave (see
+    /// `restart::ResetRequest`).
+    Rebooting = 9,
+    /// The guest was handed over to another hart. Data: the hartid it left in the upper 32 bits and
+    /// the one it now runs on in the lower.
+    MovedHart = 10,
+}
+
+/// One entry of the log, in the layout that is copied into guest memory.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Event {
+    pub sequence: u64,
+    /// Host `mtime` when the event happened.
+    pub time: u64,
+    pub kind: u16,
+    pub guestid: u16,
+    _reserved: u32,
+    pub data: u64,
+}
+
+const EMPTY_EVENT: Event = Event { sequence: 0, time: 0, kind: 0, guestid: 0, _reserved: 0, data: 0 };
+
+pub struct EventLog {
+    events: [Event; LOG_SIZE],
+    /// Sequence number of the next event to be added. Event `n` is in slot `n % LOG_SIZE`.
+    next: u64,
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        Self { events: [EMPTY_EVENT; LOG_SIZE], next: 0 }
+    }
+
+    fn push(&mut self, time: u64, kind: EventKind, guestid: u64, data: u64) {
+        self.events[self.next as usize % LOG_SIZE] = Event {
+            sequence: self.next,
+            time,
+            kind: kind as u16,
+            guestid: guestid as u16,
+            _reserved: 0,
+            data,
+        };
+        self.next += 1;
+    }
+
+    /// Sequence number of the oldest event still in the log.
+    fn oldest(&self) -> u64 {
+        self.next.saturating_sub(LOG_SIZE as u64)
+    }
+
+    /// The events with sequence numbers of at least `sequence`, oldest first.
+    pub fn since(&self, sequence: u64) -> impl Iterator<Item = &Event> {
+        (sequence.max(self.oldest())..self.next).map(move |i| &self.events[i as usize % LOG_SIZE])
+    }
+
+    pub fn next_sequence(&self) -> u64 {
+        self.next
+    }
+}
+
+/// Add an event about the guest running on this hart.
+pub fn record(state: &Context, kind: EventKind, data: u64) {
+    let guestid = hart::current().guest_index();
+    let time = state.host_clint.get_mtime();
+    SHARED_STATICS.events.lock().push(time, kind, guestid, data);
+}
+
+/// Handle a call to one of the event functions of the RVirt SBI extension, returning (error,
+/// value). Only the control guest may use them.
+pub fn handle_call(state: &mut Context, function: u64) -> (i64, u64) {
+    if !state.control_guest {
+        return (SBI_ERR_DENIED, 0);
+    }
+
+    match function {
+        RVIRT_EVENT_NEXT_SEQUENCE => (SBI_SUCCESS, SHARED_STATICS.events.lock().next_sequence()),
+        // event_read(sequence, max_events, base_addr): copy events numbered `sequence` and later
+        // into guest memory, returning how many were copied.
+        RVIRT_EVENT_READ => {
+            let sequence = state.saved_registers.get(10);
+            let count = state.saved_registers.get(11).min(MAX_READ);
+            let addr = state.saved_registers.get(12);
+            if !state.prepare_guest_access(addr, count * EVENT_SIZE, true) {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+
+            // Copy to a local buffer first, so the lock isn't held while writing guest memory.
+            let mut buffer = [EMPTY_EVENT; MAX_READ as usize];
+            let mut copied = 0;
+            for (slot, event) in buffer.iter_mut().zip(SHARED_STATICS.events.lock().since(sequence)).take(count as usize) {
+                *slot = *event;
+                copied += 1;
+            }
+            for (i, event) in buffer[..copied].iter().enumerate() {
+                let dst = state.guest_memory.slice_mut(addr + i as u64 * EVENT_SIZE, EVENT_SIZE);
+                dst[0..8].copy_from_slice(&event.sequence.to_le_bytes());
+                dst[8..16].copy_from_slice(&event.time.to_le_bytes());
+                dst[16..18].copy_from_slice(&event.kind.to_le_bytes());
+                dst[18..20].copy_from_slice(&event.guestid.to_le_bytes());
+                dst[20..24].copy_from_slice(&[0; 4]);
+                dst[24..32].copy_from_slice(&event.data.to_le_bytes());
+            }
+            (SBI_SUCCESS, copied as u64)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn kind_name(kind: u16) -> &'static str {
+    match kind {
+        1 => "started",
+        2 => "crashed",
+        3 => "restarting",
+        4 => "exited",
+        5 => "throttled",
+        6 => "device-error",
+        7 => "device-added",
+        8 => "shutdown-requested",
+        9 => "rebooting",
+        10 => "moved-hart",
+        _ => "?",
+    }
+}
+
+/// Print the events still in the log, for the monitor.
+pub fn print_log() {
+    let log = SHARED_STATICS.events.lock();
+    for event in log.since(0) {
+        println!("{:>6} {:>16} guest {:<2} {:<12} {:#x}",
+                 event.sequence, event.time, event.guestid, kind_name(event.kind), event.data);
+    }
+}
@@ -0,0 +1,151 @@
+//! Counts of guest exits, broken down by reason.
+//!
+//! Every trap taken while a guest runs is classified once, by the code that handles it, and counted
+//! against that guest. The counters live in the shared statics page rather than in `Context`, so
+//! that the monitor can show every guest's counts no matter which hart runs the command. Each guest
+//! runs on a single hart, so there is only ever one writer per counter.
+
+use arr_macro::arr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::report::Record;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    TimerInterrupt,
+    ExternalInterrupt,
+    SoftwareInterrupt,
+    /// Page fault resolved by adding a shadow mapping.
+    ShadowFill,
+    /// Page fault that was the guest's own, and was forwarded to it.
+    GuestPageFault,
+    UartAccess,
+    PlicAccess,
+    VirtioAccess,
+    /// Access to the test device, which guests use to power off or reset.
+    TestDeviceAccess,
+    RtcAccess,
+    /// Access to a write combined page of an emulated device or the hypervisor info page, which was
+    /// mapped into the guest, or an ignored write to the info page.
+    MmioMap,
+    /// Access to a page holding a virtqueue, which is never mapped into the guest.
+    VirtqueueAccess,
+    /// Cache block management instruction on a page that isn't mapped for it, done by the
+    /// hypervisor.
+    CacheBlockOp,
+    /// Store to a page of the guest's page tables, emulated so that it stays write-protected.
+    PageTableWrite,
+    /// Store to a page with watched bytes, emulated so that it can be logged. See watch.rs.
+    WatchedWrite,
+    CsrAccess,
+    Sret,
+    SfenceVma,
+    Wfi,
+    /// Illegal instruction that isn't emulated and was forwarded to the guest.
+    IllegalInstruction,
+    SbiTimer,
+    SbiConsole,
+    /// Legacy calls to send and clear IPIs. Calls to the IPI extension count as `SbiExtension`.
+    SbiIpi,
+    SbiFence,
+    SbiShutdown,
+    /// A call to one of the SBI extensions with an extension ID, like HSM or PMU.
+    SbiExtension,
+    Semihosting,
+    /// Any other exception, like an ecall from U-mode, forwarded to the guest.
+    ForwardedException,
+}
+
+const NUM_REASONS: usize = 28;
+
+const REASONS: [ExitReason; NUM_REASONS] = [
+    ExitReason::TimerInterrupt, ExitReason::ExternalInterrupt, ExitReason::SoftwareInterrupt,
+    ExitReason::ShadowFill, ExitReason::GuestPageFault, ExitReason::UartAccess,
+    ExitReason::PlicAccess, ExitReason::VirtioAccess, ExitReason::TestDeviceAccess, ExitReason::RtcAccess,
+    ExitReason::MmioMap, ExitReason::VirtqueueAccess, ExitReason::CacheBlockOp, ExitReason::PageTableWrite,
+    ExitReason::WatchedWrite, ExitReason::CsrAccess, ExitReason::Sret, ExitReason::SfenceVma, ExitReason::Wfi,
+    ExitReason::IllegalInstruction, ExitReason::SbiTimer, ExitReason::SbiConsole, ExitReason::SbiIpi,
+    ExitReason::SbiFence, ExitReason::SbiShutdown, ExitReason::SbiExtension,
+    ExitReason::Semihosting, ExitReason::ForwardedException,
+];
+
+pub struct ExitCounters {
+    counts: [AtomicU64; NUM_REASONS],
+}
+
+impl ExitCounters {
+    pub const fn new() -> Self {
+        Self { counts: arr![AtomicU64::new(0); 28] }
+    }
+
+    /// Count an exit. Only the hart running the guest calls this, so a plain load and store is
+    /// enough.
+    pub fn record(&self, reason: ExitReason) {
+        let counter = &self.counts[reason as usize];
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn reset(&self) {
+        for counter in &self.counts {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Finish a record of the `report` command with the count of every reason.
+    pub fn add_to_report(&self, record: &mut Record) {
+        record.number("total", self.total());
+        for (&reason, counter) in REASONS.iter().zip(self.counts.iter()) {
+            record.number(name(reason), counter.load(Ordering::Relaxed));
+        }
+        record.end();
+    }
+
+    /// Print the total and the count of every reason that occurred.
+    pub fn report(&self, guestid: usize) {
+        let total = self.total();
+        println!("guest {}: {} exits", guestid, total);
+        for (&reason, counter) in REASONS.iter().zip(self.counts.iter()) {
+            let count = counter.load(Ordering::Relaxed);
+            if count > 0 {
+                println!("  {:<20} {:>12} {:>3}%", name(reason), count, count * 100 / total);
+            }
+        }
+    }
+}
+
+/// Name of a reason, as a `&str` so that it can be padded when printed.
+fn name(reason: ExitReason) -> &'static str {
+    match reason {
+        ExitReason::TimerInterrupt => "TimerInterrupt",
+        ExitReason::ExternalInterrupt => "ExternalInterrupt",
+        ExitReason::SoftwareInterrupt => "SoftwareInterrupt",
+        ExitReason::ShadowFill => "ShadowFill",
+        ExitReason::GuestPageFault => "GuestPageFault",
+        ExitReason::UartAccess => "UartAccess",
+        ExitReason::PlicAccess => "PlicAccess",
+        ExitReason::VirtioAccess => "VirtioAccess",
+        ExitReason::TestDeviceAccess => "TestDeviceAccess",
+        ExitReason::RtcAccess => "RtcAccess",
+        ExitReason::MmioMap => "MmioMap",
+        ExitReason::VirtqueueAccess => "VirtqueueAccess",
+        ExitReason::CacheBlockOp => "CacheBlockOp",
+        ExitReason::PageTableWrite => "PageTableWrite",
+        ExitReason::WatchedWrite => "WatchedWrite",
+        ExitReason::CsrAccess => "CsrAccess",
+        ExitReason::Sret => "Sret",
+        ExitReason::SfenceVma => "SfenceVma",
+        ExitReason::Wfi => "Wfi",
+        ExitReason::IllegalInstruction => "IllegalInstruction",
+        ExitReason::SbiTimer => "SbiTimer",
+        ExitReason::SbiConsole => "SbiConsole",
+        ExitReason::SbiIpi => "SbiIpi",
+        ExitReason::SbiFence => "SbiFence",
+        ExitReason::SbiShutdown => "SbiShutdown",
+        ExitReason::SbiExtension => "SbiExtension",
+        ExitReason::Semihosting => "Semihosting",
+        ExitReason::ForwardedException => "ForwardedException",
+    }
+}
@@ -1,6 +1,16 @@
-use arrayvec::{ArrayString, ArrayVec};
+use arrayvec::{Array, ArrayString, ArrayVec};
 use byteorder::{BigEndian, ByteOrder};
+use core::fmt::Write;
 use core::slice;
+use crate::constants::{self, MAX_GUEST_SEGMENTS, MAX_NUMA_NODES, RNG_SEED_SIZE};
+use crate::elf;
+use crate::error::{Error, Result};
+use crate::guestos::{self, GuestOs};
+use crate::options::Options;
+#[cfg(target_arch = "riscv64")]
+use crate::ptsync::SyncMode;
+#[cfg(target_arch = "riscv64")]
+use crate::restart::CrashPolicy;
 
 const FDT_BEGIN_NODE: u32 = 0x01;
 const FDT_END_NODE: u32 = 0x02;
@@ -8,27 +18,197 @@ const FDT_PROP: u32 = 0x03;
 const FDT_NOP: u32 = 0x04;
 const FDT_END: u32 = 0x09;
 
-#[derive(Default)]
-struct AddressMap(ArrayVec<[u64; Self::MAX_LEN]>);
-impl AddressMap {
-    const MAX_LEN: usize = 16;
-    fn index_of(&mut self, value: u64) -> usize {
-        for i in 0..self.0.len() {
-            if value == self.0[i] {
-                return i;
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+
+const MAX_NODES: usize = 128;
+/// Deepest nesting of nodes that can be walked, counting the root node.
+const MAX_DEPTH: usize = 32;
+
+/// The properties of a device tree node that are relevant to the hypervisor.
+struct Node {
+    parent: Option<usize>,
+    disabled: bool,
+
+    /// Cell counts used by the `reg` properties of this node's children.
+    address_cells: u32,
+    size_cells: u32,
+
+    phandle: Option<u32>,
+    interrupt_parent: Option<u32>,
+    msi_parent: Option<u32>,
+
+    compatible: ArrayVec<[u8; 64]>,
+    device_type: ArrayString<[u8; 16]>,
+
+    reg: ArrayVec<[u32; 16]>,
+    ranges: Option<ArrayVec<[u32; 24]>>,
+    /// The first cell of `interrupts`. Only UARTs and virtio devices are looked at, and they have
+    /// a single interrupt.
+    interrupt: Option<u32>,
+    interrupts_extended: ArrayVec<[u32; 64]>,
+
+    /// Whether a cpu node has an MMU, which is taken to mean that it implements supervisor mode.
+    mmu: bool,
+    /// Extensions listed by a cpu node that the hypervisor can make use of.
+    sstc: bool,
+    svpbmt: bool,
+    sscofpmf: bool,
+    zicbom: bool,
+    zicboz: bool,
+    /// Cache block sizes for the Zicbom and Zicboz instructions, in bytes.
+    cbom_block_size: Option<u32>,
+    cboz_block_size: Option<u32>,
+    /// Baud rate of a serial port.
+    current_speed: Option<u32>,
+    /// Single letter extensions of a cpu node, as returned by `elf::isa_letters`.
+    isa_letters: u32,
+}
+impl Node {
+    fn new(parent: Option<usize>) -> Self {
+        Self {
+            parent,
+            disabled: false,
+            address_cells: 2,
+            size_cells: 1,
+            phandle: None,
+            interrupt_parent: None,
+            msi_parent: None,
+            compatible: ArrayVec::new(),
+            device_type: ArrayString::new(),
+            reg: ArrayVec::new(),
+            ranges: None,
+            interrupt: None,
+            interrupts_extended: ArrayVec::new(),
+            mmu: false,
+            sstc: false,
+            svpbmt: false,
+            sscofpmf: false,
+            zicbom: false,
+            zicboz: false,
+            cbom_block_size: None,
+            cboz_block_size: None,
+            current_speed: None,
+            isa_letters: 0,
+        }
+    }
+
+    fn add_isa_extension(&mut self, name: &[u8]) {
+        match name {
+            b"sstc" => self.sstc = true,
+            b"svpbmt" => self.svpbmt = true,
+            b"sscofpmf" => self.sscofpmf = true,
+            b"zicbom" => self.zicbom = true,
+            b"zicboz" => self.zicboz = true,
+            &[c] if c.is_ascii_lowercase() => self.isa_letters |= 1 << (c - b'a'),
+            _ => {}
+        }
+    }
+
+    fn is_compatible(&self, value: &str) -> bool {
+        self.compatible.split(|&c| c == 0).any(|c| c == value.as_bytes())
+    }
+}
+
+/// Replace the contents of `dest` with `items`. Returns false if they don't all fit.
+fn fill<A: Array, I: IntoIterator<Item = A::Item>>(dest: &mut ArrayVec<A>, items: I) -> bool {
+    dest.clear();
+    items.into_iter().all(|item| dest.try_push(item).is_ok())
+}
+
+struct Tree {
+    nodes: ArrayVec<[Node; MAX_NODES]>,
+}
+impl Tree {
+    fn by_phandle(&self, phandle: u32) -> Option<usize> {
+        self.nodes.iter().position(|n| n.phandle == Some(phandle))
+    }
+
+    /// Phandle of the interrupt controller for a node, which is inherited from its ancestors.
+    fn interrupt_parent(&self, mut node: usize) -> Option<u32> {
+        loop {
+            if let Some(phandle) = self.nodes[node].interrupt_parent {
+                return Some(phandle);
             }
+            node = self.nodes[node].parent?;
         }
+    }
 
-        self.0.push(value);
-        self.0.len() - 1
+    /// Return the `index`-th (address, size) pair from a node's `reg` property, with the address
+    /// translated into the root address space.
+    fn reg(&self, node: usize, index: usize) -> Option<(u64, u64)> {
+        let parent = self.nodes[node].parent?;
+        let address_cells = self.nodes[parent].address_cells as usize;
+        let size_cells = self.nodes[parent].size_cells as usize;
+
+        let start = index * (address_cells + size_cells);
+        let reg = self.nodes[node].reg.get(start..(start + address_cells + size_cells))?;
+        let address = read_cells(&reg[..address_cells]);
+        let size = read_cells(&reg[address_cells..]);
+        Some((self.translate(parent, address)?, size))
+    }
+
+    /// Translate an address on the bus formed by the children of `bus` into the root address
+    /// space. Returns None if one of the enclosing `ranges` properties doesn't cover the address.
+    fn translate(&self, mut bus: usize, mut address: u64) -> Option<u64> {
+        while let Some(parent) = self.nodes[bus].parent {
+            // A missing ranges property technically means the bus isn't memory mapped at all, but
+            // it is commonly left out in place of an empty (identity) one.
+            match self.nodes[bus].ranges {
+                Some(ref ranges) if !ranges.is_empty() => {
+                    let child_cells = self.nodes[bus].address_cells as usize;
+                    let parent_cells = self.nodes[parent].address_cells as usize;
+                    let entry_cells = child_cells + parent_cells + self.nodes[bus].size_cells as usize;
+                    if entry_cells == 0 {
+                        return None;
+                    }
+
+                    address = ranges.chunks_exact(entry_cells).filter_map(|entry| {
+                        let child = read_cells(&entry[..child_cells]);
+                        let parent = read_cells(&entry[child_cells..][..parent_cells]);
+                        let size = read_cells(&entry[(child_cells + parent_cells)..]);
+                        if address >= child && address - child < size {
+                            Some((address - child).wrapping_add(parent))
+                        } else {
+                            None
+                        }
+                    }).next()?;
+                }
+                _ => {}
+            }
+            bus = parent;
+        }
+        Some(address)
     }
 }
 
+/// Combine big endian cells into a single value. Only the low 64 bits are kept.
+fn read_cells(cells: &[u32]) -> u64 {
+    cells.iter().fold(0, |value, &cell| (value << 32) | cell as u64)
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum UartType {
     Ns16550a,
     SiFive,
+    /// Spike's HTIF console, at `tohost`. See htif.rs.
+    Htif,
+}
+
+/// The host's interrupt controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IrqChip {
+    Plic,
+    /// An APLIC delivering interrupts directly to harts.
+    AplicDirect,
+    /// An APLIC forwarding interrupts as MSIs to the IMSIC of each hart.
+    AplicMsi,
+}
+
+impl Default for IrqChip {
+    fn default() -> Self {
+        IrqChip::Plic
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -41,33 +221,379 @@ pub struct Device {
 #[derive(Clone, Debug)]
 pub struct Hart {
     pub hartid: u64,
+    /// PLIC context that delivers supervisor external interrupts to the hart. With an APLIC, this
+    /// is instead the index of the hart's IDC or IMSIC.
     pub plic_context: u64,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MachineMeta {
+    /// The first range of physical memory. The hypervisor and all guests live inside of it.
     pub physical_memory_offset: u64,
     pub physical_memory_size: u64,
 
+    /// (start, size) of every range of physical memory, sorted by address.
+    pub memory_ranges: ArrayVec<[(u64, u64); 8]>,
+
     pub harts: ArrayVec<[Hart; 16]>,
 
     pub uart_type: Option<UartType>,
     pub uart_address: u64,
+    pub uart_irq: Option<u32>,
+    pub uart_baud: Option<u32>,
 
+    pub irqchip: IrqChip,
+    /// Address of the PLIC, or of the APLIC's supervisor level domain.
     pub plic_address: u64,
     pub clint_address: Option<u64>,
 
     pub test_finisher_address: Option<u64>,
+    /// Goldfish real time clock, which guest clocks are set from (see rtc.rs).
+    pub rtc_address: Option<u64>,
+    /// Physical address of `tohost`, if the simulator has an HTIF (see htif.rs).
+    pub htif_address: Option<u64>,
 
     pub virtio: ArrayVec<[Device; 16]>,
 
+    /// Whether every hart implements the Sstc extension, letting the hypervisor program its timer
+    /// through the stimecmp CSR rather than with an SBI call.
+    pub sstc: bool,
+    /// Whether every hart implements Svpbmt, so that guests can pick memory types for their pages.
+    pub svpbmt: bool,
+    /// Whether every hart implements Sscofpmf, in which case guests are offered counter overflow
+    /// interrupts for profiling.
+    pub sscofpmf: bool,
+    /// Cache block sizes of the Zicbom and Zicboz extensions, if every hart implements them and
+    /// gives the same size. Guests are offered only the extensions that are set.
+    pub cbom_block_size: Option<u32>,
+    pub cboz_block_size: Option<u32>,
+    /// Single letter extensions implemented by every usable hart, as returned by
+    /// `elf::isa_letters`. Zero if the device tree doesn't say.
+    pub isa_letters: u32,
+
+    /// (start, size) of memory ranges that firmware has asked not to be touched, taken from both
+    /// the memory reservation block and /reserved-memory.
+    pub reserved_memory: ArrayVec<[(u64, u64); 16]>,
+
+    /// The command line for guests, without the hypervisor's own switches.
     pub bootargs: ArrayString<[u8; 256]>,
+    /// Switches for the hypervisor taken from bootargs. See options.rs.
+    pub options: Options,
+
+    /// Exit code reported when the last guest shuts itself down through SBI. Set with the
+    /// `rvirt,exit-code` property of /chosen.
+    pub shutdown_exit_code: u32,
+
+    /// Number of NUMA nodes to present to each guest, and optionally the distances between them.
+    /// Set with the `rvirt,numa-nodes` and `rvirt,numa-distances` properties of /chosen.
+    pub guest_numa_nodes: u32,
+    pub guest_numa_distances: ArrayVec<[u32; MAX_NUMA_NODES * MAX_NUMA_NODES]>,
+
+    /// (guestid, requests per second, bytes per second) limits on the I/O of each guest's virtio
+    /// devices, where a guestid of zero applies to every guest without its own entry. Set with the
+    /// `rvirt,io-limits` property of /chosen.
+    pub io_limits: ArrayVec<[(u32, u32, u32); 16]>,
+
+    /// (device ID, low word, high word) feature bits to hide from passthrough virtio devices of
+    /// that type, where a device ID of zero applies to every device. Set with the
+    /// `rvirt,virtio-hide-features` property of /chosen.
+    pub virtio_hide_features: ArrayVec<[(u32, u32, u32); 16]>,
+
+    /// (guestid, policy, restart limit) entries saying what to do when a guest crashes, where a
+    /// guestid of zero applies to every guest without its own entry. Set with the
+    /// `rvirt,crash-policy` property of /chosen. See restart.rs.
+    pub crash_policies: ArrayVec<[(u32, u32, u32); 16]>,
+
+    /// (guestid, gigabytes) entries giving guests more than one segment of memory, where a guestid
+    /// of zero applies to every guest without its own entry. Set with the `rvirt,guest-memory`
+    /// property of /chosen.
+    pub guest_memory: ArrayVec<[(u32, u32); 16]>,
+
+    /// (guestid, mode) entries picking how each guest's shadow page tables follow changes to its
+    /// page tables, where a guestid of zero applies to every guest without its own entry. Set with
+    /// the `rvirt,shadow-sync` property of /chosen. See ptsync.rs.
+    pub shadow_sync: ArrayVec<[(u32, u32); 16]>,
+
+    /// Interrupts per second a host interrupt source may raise before it is masked and polled for a
+    /// while, or zero for no limit. Set by the `rvirt,irq-limit` property of /chosen. See irqrate.rs.
+    pub irq_limit: Option<u32>,
+
+    /// Guest allowed to read the event log, or zero for none. Set by the `rvirt,control-guest`
+    /// property of /chosen.
+    pub control_guest: u32,
+
+    /// What the guests run, if set by the `rvirt,guest-os` property of /chosen. See guestos.rs.
+    pub guest_os: Option<GuestOs>,
+
+    /// Host virtio block devices to serve to their guests through an emulated device instead of
+    /// passing them through, so that they can hold qcow2 images. Set by the `rvirt,blk-backend`
+    /// property of /chosen, which lists indices into `virtio`.
+    pub blk_backends: ArrayVec<[u32; 16]>,
+
+    /// (base, overlay) host virtio block devices behind the copy-on-write disk of every guest. Set
+    /// by the `rvirt,blk-cow` property of /chosen. See overlay.rs.
+    pub blk_cow: Option<(u32, u32)>,
+
+    /// (guestid, host virtio device) pairs giving guests read-only access to a host block device,
+    /// which any number of guests can share, where a guestid of zero applies to every guest. Set by
+    /// the `rvirt,blk-readonly` property of /chosen. See overlay.rs.
+    pub blk_readonly: ArrayVec<[(u32, u32); 16]>,
+
+    /// Host virtio block device holding the hypervisor's settings, which override those of /chosen.
+    /// Set by the `rvirt,config-disk` property of /chosen. See config.rs.
+    pub config_disk: Option<u32>,
+
+    /// Whether to give each guest an emulated vsock device. Set by the `rvirt,vsock` property of
+    /// /chosen.
+    pub vsock: bool,
+
+    /// Whether to give each guest a write combined debug log window. Set by the `rvirt,debug-log`
+    /// property of /chosen. See debuglog.rs.
+    pub debug_log: bool,
+
+    /// Upper limit on the number of guests to start, or zero to start one for every hart that isn't
+    /// running the hypervisor. Set by the `rvirt,max-guests` property of /chosen.
+    pub max_guests: u32,
+
+    /// Whether to load relocatable guest kernels at the start of guest memory instead of at a
+    /// randomized address. Set by the `rvirt,no-kaslr` property of /chosen.
+    pub no_kaslr: bool,
+
+    /// Whether interrupts should enter the hypervisor through a vector table rather than the
+    /// single trap entry point. Set by the `rvirt,vectored-traps` property of /chosen.
+    pub vectored_traps: bool,
+
+    /// Compatible strings of devices to leave enabled in guest device trees, as a string list in
+    /// which each entry may be prefixed with a guestid and a colon to apply to that guest only, and
+    /// where "*" turns off hiding altogether. Set by the `rvirt,fdt-allow` property of /chosen.
+    pub fdt_allow: ArrayVec<[u8; 256]>,
+
+    /// Metadata for the guests' own provisioning scripts, as a string list of `key=value` entries
+    /// which may each be prefixed with a guestid and a colon like those of `fdt_allow`. Set by the
+    /// `rvirt,guest-env` property of /chosen, and passed on in each guest's /chosen/rvirt node.
+    pub guest_env: ArrayVec<[u8; 1024]>,
+
+    /// Whether to start measuring how long interrupts take to reach the guests. Set by the
+    /// `rvirt,irq-latency` property of /chosen. See irqlatency.rs.
+    pub irq_latency: bool,
+
+    /// How many KB of console output to keep a copy of in memory, for reading after a crash. Set
+    /// by the `rvirt,log-tail` property of /chosen. See logtail.rs.
+    pub log_tail_kb: u32,
+
+    /// Random bytes left by firmware in the `rng-seed` property of /chosen. See entropy.rs.
+    pub rng_seed: ArrayVec<[u8; RNG_SEED_SIZE]>,
 
     pub initrd_start: u64,
     pub initrd_end: u64,
 }
 
-#[repr(C)]
+impl MachineMeta {
+    /// Apply one of the hypervisor's settings, given as a property of the host's /chosen or of the
+    /// configuration on the config disk, replacing whatever an earlier one of the same name set.
+    /// Returns false if `name` isn't a setting.
+    pub fn apply_setting(&mut self, name: &str, prop: &mut Property) -> bool {
+        match name {
+            "rvirt,exit-code" => self.shutdown_exit_code = prop.first_cell().unwrap_or(0),
+            "rvirt,numa-nodes" => self.guest_numa_nodes = prop.first_cell().unwrap_or(1),
+            "rvirt,numa-distances" => {
+                self.guest_numa_distances.clear();
+                self.guest_numa_distances.extend(prop.cells_iter());
+            }
+            "rvirt,vsock" => self.vsock = true,
+            "rvirt,debug-log" => self.debug_log = true,
+            "rvirt,blk-backend" => {
+                self.blk_backends.clear();
+                self.blk_backends.extend(prop.cells_iter());
+            }
+            "rvirt,blk-cow" => {
+                self.blk_cow = match prop.cells() {
+                    2 => Some((prop.read_cell(0), prop.read_cell(1))),
+                    _ => None,
+                };
+                if self.blk_cow.is_none() {
+                    println!("WARN: rvirt,blk-cow should be <base overlay>");
+                }
+            }
+            "rvirt,blk-readonly" => {
+                self.blk_readonly.clear();
+                self.blk_readonly.extend(prop.cell_pairs());
+            }
+            "rvirt,max-guests" => self.max_guests = prop.first_cell().unwrap_or(0),
+            "rvirt,no-kaslr" => self.no_kaslr = true,
+            "rvirt,vectored-traps" => self.vectored_traps = true,
+            "rvirt,control-guest" => self.control_guest = prop.first_cell().unwrap_or(0),
+            "rvirt,irq-limit" => self.irq_limit = prop.first_cell(),
+            "rvirt,irq-latency" => self.irq_latency = true,
+            "rvirt,log-tail" => self.log_tail_kb = prop.first_cell().unwrap_or(0),
+            "rvirt,fdt-allow" => {
+                self.fdt_allow.clear();
+                let len = prop.len().min(self.fdt_allow.capacity());
+                self.fdt_allow.extend(prop.value_slice()[..len].iter().cloned());
+            }
+            "rvirt,guest-env" => {
+                self.guest_env.clear();
+                let len = prop.len().min(self.guest_env.capacity());
+                self.guest_env.extend(prop.value_slice()[..len].iter().cloned());
+                if len < prop.len() {
+                    println!("WARN: rvirt,guest-env is longer than {} bytes, ignoring the rest", len);
+                }
+                for entry in self.guest_env.split(|&b| b == 0).filter(|e| !e.is_empty()) {
+                    match core::str::from_utf8(entry) {
+                        Ok(entry) if parse_env_entry(entry).is_some() => {}
+                        Ok(entry) => println!("WARN: ignoring rvirt,guest-env entry {:?}", entry),
+                        Err(_) => println!("WARN: ignoring rvirt,guest-env entry that isn't UTF-8"),
+                    }
+                }
+            }
+            "rvirt,guest-os" => {
+                self.guest_os = prop.value_str().and_then(guestos::parse_config);
+                if self.guest_os.is_none() {
+                    println!("WARN: unrecognized rvirt,guest-os");
+                }
+            }
+            "rvirt,io-limits" => {
+                self.io_limits.clear();
+                self.io_limits.extend(prop.cell_triples());
+            }
+            "rvirt,virtio-hide-features" => {
+                self.virtio_hide_features.clear();
+                self.virtio_hide_features.extend(prop.cell_triples());
+            }
+            "rvirt,crash-policy" => {
+                self.crash_policies.clear();
+                self.crash_policies.extend(prop.cell_triples());
+            }
+            "rvirt,shadow-sync" => {
+                self.shadow_sync.clear();
+                self.shadow_sync.extend(prop.cell_pairs());
+            }
+            "rvirt,guest-memory" => {
+                self.guest_memory.clear();
+                self.guest_memory.extend(prop.cell_pairs());
+            }
+            "bootargs" => {
+                self.bootargs.clear();
+                match prop.value_str() {
+                    Some(bootargs) if self.bootargs.try_push_str(bootargs).is_ok() => {}
+                    _ => println!("WARN: Ignoring bootargs that aren't printable or are over {} bytes",
+                                  self.bootargs.capacity()),
+                }
+                self.options = Options::parse(&mut self.bootargs);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Whether any part of the given range overlaps reserved memory.
+    pub fn is_reserved(&self, start: u64, size: u64) -> bool {
+        self.reserved_memory.iter().any(|&(s, len)| s < start.saturating_add(size) && start < s.saturating_add(len))
+    }
+
+    /// The (requests per second, bytes per second) limits for a guest's devices, zero if unlimited.
+    pub fn io_limits(&self, guestid: u64) -> (u64, u64) {
+        self.io_limits.iter().find(|l| l.0 as u64 == guestid)
+            .or_else(|| self.io_limits.iter().find(|l| l.0 == 0))
+            .map(|l| (l.1 as u64, l.2 as u64))
+            .unwrap_or((0, 0))
+    }
+
+    /// Whether a host virtio device is kept by the hypervisor to back copy-on-write or read-only
+    /// disks or to hold its configuration, rather than given to a guest.
+    pub fn is_shared_device(&self, index: usize) -> bool {
+        self.blk_cow.map_or(false, |(base, overlay)| index == base as usize || index == overlay as usize)
+            || self.blk_readonly.iter().any(|r| r.1 as usize == index)
+            || self.config_disk == Some(index as u32)
+    }
+
+    /// The host virtio devices a guest gets read-only access to.
+    pub fn readonly_disks<'a>(&'a self, guestid: u64) -> impl Iterator<Item = u32> + 'a {
+        self.blk_readonly.iter().filter(move |r| r.0 == 0 || r.0 as u64 == guestid).map(|r| r.1)
+    }
+
+    /// What to do when a guest crashes. Guests are left stopped unless configured otherwise.
+    #[cfg(target_arch = "riscv64")]
+    pub fn crash_policy(&self, guestid: u64) -> CrashPolicy {
+        self.crash_policies.iter().find(|p| p.0 as u64 == guestid)
+            .or_else(|| self.crash_policies.iter().find(|p| p.0 == 0))
+            .map(|p| CrashPolicy::from_config(p.1, p.2))
+            .unwrap_or(CrashPolicy::Halt)
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    pub fn shadow_sync_mode(&self, guestid: u64) -> SyncMode {
+        self.shadow_sync.iter().find(|m| m.0 as u64 == guestid)
+            .or_else(|| self.shadow_sync.iter().find(|m| m.0 == 0))
+            .and_then(|m| SyncMode::from_config(m.1))
+            .unwrap_or(SyncMode::Lazy)
+    }
+
+    /// The compatible strings of devices that `guestid` may see besides those the hypervisor
+    /// provides. See `build_guest_fdt`.
+    pub fn fdt_allowlist(&self, guestid: u64) -> ArrayVec<[&str; 16]> {
+        let mut allowed = ArrayVec::new();
+        for entry in self.fdt_allow.split(|&b| b == 0).filter_map(|e| core::str::from_utf8(e).ok()) {
+            let mut parts = entry.splitn(2, ':');
+            let entry = match (parts.next(), parts.next()) {
+                (Some(id), Some(compatible)) if id.parse::<u64>().ok() == Some(guestid) => compatible,
+                (Some(compatible), None) if !compatible.is_empty() => compatible,
+                _ => continue,
+            };
+            let _ = allowed.try_push(entry);
+        }
+        allowed
+    }
+
+    /// The metadata to give `guestid`, as (key, value) pairs. Entries for the guest take the place
+    /// of those for every guest with the same key.
+    pub fn guest_env(&self, guestid: u64) -> ArrayVec<[(&str, &str); 32]> {
+        let mut env: ArrayVec<[(&str, &str); 32]> = ArrayVec::new();
+        let entries = self.guest_env.split(|&b| b == 0).filter_map(|e| core::str::from_utf8(e).ok());
+        for (id, key, value) in entries.filter_map(parse_env_entry) {
+            if id.map_or(false, |id| id != guestid) {
+                continue;
+            }
+            match env.iter().position(|e| e.0 == key) {
+                Some(i) if id.is_some() => env[i].1 = value,
+                Some(_) => {}
+                None => {
+                    let _ = env.try_push((key, value));
+                }
+            }
+        }
+        env
+    }
+
+    /// How many 1GB segments of host memory a guest should get. The first of them also holds the
+    /// hypervisor's data for the guest's hart, so the guest sees a little less than that.
+    pub fn guest_segments(&self, guestid: u64) -> u64 {
+        self.guest_memory.iter().find(|m| m.0 as u64 == guestid)
+            .or_else(|| self.guest_memory.iter().find(|m| m.0 == 0))
+            .map(|m| (m.1 as u64).max(1).min(MAX_GUEST_SEGMENTS as u64))
+            .unwrap_or(1)
+    }
+}
+
+/// Split an `rvirt,guest-env` entry into the guestid it is for, if it names one, its key and its
+/// value. The key must be usable as a property name, so at most 31 characters from the set the
+/// device tree specification allows.
+fn parse_env_entry(entry: &str) -> Option<(Option<u64>, &str, &str)> {
+    let equals = entry.find('=')?;
+    let (target, value) = (&entry[..equals], &entry[equals + 1..]);
+    let (id, key) = match target.find(':') {
+        Some(colon) => (Some(target[..colon].parse::<u64>().ok()?), &target[colon + 1..]),
+        None => (None, target),
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || ",._+?#-".contains(c);
+    if key.is_empty() || key.len() > 31 || !key.chars().all(valid) {
+        return None;
+    }
+    Some((id, key, value))
+}
+
+/// Header of a device tree blob, with fields converted to native byte order.
+#[derive(Copy, Clone)]
 struct FdtHeader {
     magic: u32,
     total_size: u32,
@@ -82,59 +608,94 @@ struct FdtHeader {
 }
 
 pub struct Fdt<'a>{
-    header: &'a mut FdtHeader,
+    header: FdtHeader,
     strings: &'a [u8],
     nodes: &'a mut [u8],
+    rsvmap: &'a [u8],
 }
 
 #[allow(unused)]
 impl<'a> Fdt<'a> {
-    pub unsafe fn new(addr: u64) -> Self {
-        let header = &mut *(addr as *mut FdtHeader);
-        let total_size = header.total_size.swap_bytes() as usize;
-
-        let off_dt_strings = header.off_dt_strings.swap_bytes() as u64;
-        let size_dt_strings = header.size_dt_strings.swap_bytes() as usize;
-        assert!(off_dt_strings as usize + size_dt_strings <= total_size);
+    /// Validate the header of the device tree blob at `addr`. Only version 17 is supported.
+    ///
+    /// The caller must make sure that as many bytes as the header claims the blob occupies can be
+    /// accessed at `addr`.
+    pub unsafe fn new(addr: u64) -> Result<Self> {
+        let header = slice::from_raw_parts(addr as *const u8, FDT_HEADER_SIZE);
+        if BigEndian::read_u32(header) != FDT_MAGIC {
+            return Err(Error::InvalidFdt);
+        }
+        let total_size = BigEndian::read_u32(&header[4..]) as usize;
+        Self::from_slice(slice::from_raw_parts_mut(addr as *mut u8, total_size))
+    }
 
-        let off_dt_struct = header.off_dt_struct.swap_bytes() as u64;
-        let size_dt_struct = header.size_dt_struct.swap_bytes() as usize;
-        assert!(off_dt_struct as usize + size_dt_struct <= total_size);
+    /// Validate a device tree blob held in `blob`. All later accesses stay within the slice, so
+    /// this is safe to use on untrusted input. The memory reservation block, structure block and
+    /// strings block have to appear in that order, which is how dtc lays them out.
+    pub fn from_slice(blob: &'a mut [u8]) -> Result<Self> {
+        if blob.len() < FDT_HEADER_SIZE {
+            return Err(Error::InvalidFdt);
+        }
+        let field = |i: usize| BigEndian::read_u32(&blob[(i * 4)..]);
+        let header = FdtHeader {
+            magic: field(0),
+            total_size: field(1),
+            off_dt_struct: field(2),
+            off_dt_strings: field(3),
+            off_mem_rsvmap: field(4),
+            version: field(5),
+            last_comp_version: field(6),
+            boot_cpuid_phys: field(7),
+            size_dt_strings: field(8),
+            size_dt_struct: field(9),
+        };
+        if header.magic != FDT_MAGIC || header.version < 17 || header.last_comp_version > 17 {
+            return Err(Error::InvalidFdt);
+        }
 
-        let strings = slice::from_raw_parts_mut((addr + off_dt_strings) as *mut u8, size_dt_strings);
-        let nodes = slice::from_raw_parts_mut((addr + off_dt_struct) as *mut u8, size_dt_struct);
+        let total_size = header.total_size as usize;
+        let off_mem_rsvmap = header.off_mem_rsvmap as usize;
+        let (off_dt_struct, size_dt_struct) = (header.off_dt_struct as usize, header.size_dt_struct as usize);
+        let (off_dt_strings, size_dt_strings) = (header.off_dt_strings as usize, header.size_dt_strings as usize);
+        if total_size > blob.len() || off_mem_rsvmap < FDT_HEADER_SIZE || off_mem_rsvmap > off_dt_struct
+            || off_dt_struct + size_dt_struct > off_dt_strings
+            || off_dt_strings + size_dt_strings > total_size {
+            return Err(Error::InvalidFdt);
+        }
 
-        Self {
+        let (front, strings) = blob[..(off_dt_strings + size_dt_strings)].split_at_mut(off_dt_strings);
+        let (front, nodes) = front[..(off_dt_struct + size_dt_struct)].split_at_mut(off_dt_struct);
+        Ok(Self {
             header,
             strings,
             nodes,
-        }
+            rsvmap: &front[off_mem_rsvmap..],
+        })
     }
 
     pub fn magic_valid(&self) -> bool {
-        self.header.magic == 0xedfe0dd0
-    }
-    pub fn total_size(&self) -> u32 { self.header.total_size.swap_bytes() }
-    pub fn off_dt_struct(&self) -> u32 { self.header.off_dt_struct.swap_bytes() }
-    pub fn off_dt_strings(&self) -> u32 { self.header.off_dt_strings.swap_bytes() }
-    pub fn off_mem_rsvmap(&self) -> u32 { self.header.off_mem_rsvmap.swap_bytes() }
-    pub fn version(&self) -> u32 { self.header.version.swap_bytes() }
-    pub fn last_comp_version(&self) -> u32 { self.header.last_comp_version.swap_bytes() }
-    pub fn boot_cpuid_phys(&self) -> u32 { self.header.boot_cpuid_phys.swap_bytes() }
-    pub fn size_dt_strings(&self) -> u32 { self.header.size_dt_strings.swap_bytes() }
-    pub fn size_dt_struct(&self) -> u32 { self.header.size_dt_struct.swap_bytes() }
+        self.header.magic == FDT_MAGIC
+    }
+    pub fn total_size(&self) -> u32 { self.header.total_size }
+    pub fn off_dt_struct(&self) -> u32 { self.header.off_dt_struct }
+    pub fn off_dt_strings(&self) -> u32 { self.header.off_dt_strings }
+    pub fn off_mem_rsvmap(&self) -> u32 { self.header.off_mem_rsvmap }
+    pub fn version(&self) -> u32 { self.header.version }
+    pub fn last_comp_version(&self) -> u32 { self.header.last_comp_version }
+    pub fn boot_cpuid_phys(&self) -> u32 { self.header.boot_cpuid_phys }
+    pub fn size_dt_strings(&self) -> u32 { self.header.size_dt_strings }
+    pub fn size_dt_struct(&self) -> u32 { self.header.size_dt_struct }
 
+    /// Names that are out of bounds or aren't valid UTF-8 come back empty, so they won't match
+    /// any property the hypervisor looks for.
     pub fn get_string(strings: &[u8], offset: usize) -> &str {
-        let mut end = offset;
-        while end < strings.len() && strings[end] != 0 {
-            end += 1;
-        }
-
-        core::str::from_utf8(&strings[offset..end]).expect("FDT contained invalid string")
+        let tail = strings.get(offset..).unwrap_or(&[]);
+        let len = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+        core::str::from_utf8(&tail[..len]).unwrap_or("")
     }
 
     pub fn print(&mut self) {
-        self.walk(|path, unit_addresses, v| match v {
+        let _ = self.walk(|path, unit_addresses, v| match v {
             FdtVisit::Property { name, prop } => {
                 if path != "/" {
                     let mut depth = 0;
@@ -155,10 +716,9 @@ impl<'a> Fdt<'a> {
                     print!("{}", name);
                 }
 
-                if prop.len() == 4 || prop.len() == 8 {
-                    println!("={:#x}", prop.read_int());
-                } else if prop.len() == 16 {
-                    let range = prop.read_range();
+                if let Some(value) = prop.read_int() {
+                    println!("={:#x}", value);
+                } else if let Some(range) = prop.read_range() {
                     println!("={:x}:{:x}", range.0, range.1);
                 } else if prop.len() != 0 {
                     if let Some(value) = prop.value_str() {
@@ -174,120 +734,319 @@ impl<'a> Fdt<'a> {
         });
     }
 
-    pub fn parse(&mut self) -> MachineMeta {
+    /// Extract information about the machine from the device tree.
+    ///
+    /// Devices are identified by their `compatible` or `device_type` properties rather than by
+    /// their location in the tree, and `reg` properties are decoded according to the
+    /// `#address-cells` and `#size-cells` of the parent node and then translated through the
+    /// `ranges` of every enclosing bus. Nodes that aren't recognized are ignored, and so are values
+    /// that are malformed. Fails if the structure block is malformed, if a property the hypervisor
+    /// keeps is larger than it has room for, or if there is no usable interrupt controller, since
+    /// nothing else can work without one.
+    pub fn parse(&mut self) -> Result<MachineMeta> {
         let mut initrd_start: Option<u64> = None;
         let mut initrd_end: Option<u64> = None;
-        let mut plic: Option<u64> = None;
 
         let mut meta = MachineMeta::default();
+        let mut tree = Tree { nodes: ArrayVec::new() };
+        let mut stack = ArrayVec::<[usize; MAX_DEPTH]>::new();
+        let mut overflow = false;
+        let mut too_large = false;
+        let mut reserved_nodes = ArrayVec::<[usize; 16]>::new();
 
-        let mut virtio_address_map = AddressMap::default();
-        let mut virtio = [(None, None); AddressMap::MAX_LEN];
-
-        // (hartid, phandle)
-        let mut cpus = [(None, None); AddressMap::MAX_LEN];
-        let mut cpu_address_map = AddressMap::default();
-
-        // hart phandle for each plic S-mode context
-        let mut plic_context_phandles = [None; 64];
-
-        self.walk(|path, unit_addresses, v| {
-            match v {
-                FdtVisit::Property { name, prop } => match (path, name) {
-                    ("/chosen", "linux,initrd-end") => initrd_end = Some(prop.read_int()),
-                    ("/chosen", "linux,initrd-start") => initrd_start = Some(prop.read_int()),
-                    ("/chosen", "bootargs") => {
-                        meta.bootargs.push_str(prop.value_str()
-                                               .expect("Unable to parse bootargs string"))
+        self.walk(|path, unit_addresses, v| match v {
+            FdtVisit::Node { .. } => {
+                stack.truncate(unit_addresses.len() - 1);
+                let parent = stack.last().cloned().filter(|&p| p < tree.nodes.len());
+                let index = match tree.nodes.try_push(Node::new(parent)) {
+                    Ok(()) => tree.nodes.len() - 1,
+                    Err(_) => {
+                        overflow = true;
+                        usize::max_value()
                     }
-                    ("/memory", "reg") => {
-                        let region = prop.read_range();
-                        meta.physical_memory_offset = region.0;
-                        meta.physical_memory_size = region.1;
+                };
+                // The walk never goes deeper than the stack holds.
+                if stack.try_push(index).is_err() {
+                    too_large = true;
+                }
+
+                if unit_addresses.len() == 3 && path.starts_with("/reserved-memory/") {
+                    let _ = reserved_nodes.try_push(index);
+                }
+            }
+            FdtVisit::Property { name, prop } => {
+                if path == "/chosen" {
+                    match name {
+                        "linux,initrd-end" => initrd_end = prop.read_int(),
+                        "linux,initrd-start" => initrd_start = prop.read_int(),
+                        "rng-seed" => meta.rng_seed.extend(prop.value_slice().iter().cloned().take(RNG_SEED_SIZE)),
+                        "rvirt,config-disk" => meta.config_disk = prop.first_cell(),
+                        _ => {
+                            meta.apply_setting(name, prop);
+                        }
                     }
-                    ("/uart", "reg") |
-                    ("/soc/uart", "reg") |
-                    ("/soc/serial", "reg") => if meta.uart_address == 0 {
-                        meta.uart_address = prop.read_range().0
+                }
+
+                let node = match stack.last() {
+                    Some(&index) if index < tree.nodes.len() => &mut tree.nodes[index],
+                    _ => return,
+                };
+                let mut fits = true;
+                match name {
+                    "#address-cells" => node.address_cells = prop.first_cell().unwrap_or(2),
+                    "#size-cells" => node.size_cells = prop.first_cell().unwrap_or(1),
+                    "phandle" | "linux,phandle" => node.phandle = prop.first_cell(),
+                    "interrupt-parent" => node.interrupt_parent = prop.first_cell(),
+                    "msi-parent" => node.msi_parent = prop.first_cell(),
+                    "reg" => fits = fill(&mut node.reg, prop.cells_iter()),
+                    "ranges" => fits = fill(node.ranges.get_or_insert_with(ArrayVec::new), prop.cells_iter()),
+                    "interrupts" => node.interrupt = prop.first_cell(),
+                    "interrupts-extended" => fits = fill(&mut node.interrupts_extended, prop.cells_iter()),
+                    "compatible" => {
+                        let len = prop.len();
+                        fits = fill(&mut node.compatible, prop.value_slice()[..len].iter().cloned());
                     }
-                    ("/uart", "compatible") |
-                    ("/soc/uart", "compatible") |
-                    ("/soc/serial", "compatible") => if meta.uart_type.is_none() {
-                        match prop.value_str().map(|s| s.trim_end_matches('\0')) {
-                            Some("ns16550a") => meta.uart_type = Some(UartType::Ns16550a),
-                            Some("sifive,uart0") => meta.uart_type = Some(UartType::SiFive),
-                            _ => {},
-                        }
+                    "device_type" => {
+                        let _ = node.device_type.try_push_str(prop.value_str().unwrap_or(""));
                     }
-                    ("/soc/clint", "reg") => meta.clint_address = Some(prop.read_range().0),
-                    ("/test", "reg") => meta.test_finisher_address = Some(prop.read_range().0),
-                    ("/soc/interrupt-controller", "reg") => plic = Some(prop.read_range().0),
-                    ("/soc/interrupt-controller", "interrupts-extended") => {
-                        let cells = prop.cells();
-                        for i in (0..cells).step_by(2) {
-                            let irq = prop.read_cell(i + 1);
-                            if irq == 9 {
-                                plic_context_phandles[i/2] = Some(prop.read_cell(i));
+                    "mmu-type" => node.mmu = prop.value_str().map(|s| s != "riscv,none").unwrap_or(false),
+                    "riscv,isa" => {
+                        // Multi-letter extensions follow the single letter ones, separated by '_'.
+                        if let Some(isa) = prop.value_str() {
+                            node.isa_letters |= elf::isa_letters(isa.as_bytes());
+                            for extension in isa.split('_').skip(1) {
+                                node.add_isa_extension(extension.as_bytes());
                             }
                         }
                     }
-                    ("/virtio_mmio", "reg") => {
-                        let index = virtio_address_map.index_of(unit_addresses[1].unwrap_or(0));
-                        virtio[index].0 = Some(prop.read_range());
-                    }
-                    ("/virtio_mmio", "interrupts") => {
-                        let index = virtio_address_map.index_of(unit_addresses[1].unwrap_or(0));
-                        virtio[index].1 = Some(prop.read_int());
-                    }
-                    ("/cpus/cpu", "reg") => {
-                        let index = virtio_address_map.index_of(unit_addresses[2].unwrap_or(0));
-                        cpus[index].0 = Some(prop.read_int());
+                    "riscv,isa-extensions" => {
+                        let len = prop.len();
+                        for extension in prop.value_slice()[..len].split(|&c| c == 0) {
+                            node.add_isa_extension(extension);
+                        }
                     }
-                    ("/cpus/cpu/interrupt-controller", "phandle") => {
-                        let index = virtio_address_map.index_of(unit_addresses[2].unwrap_or(0));
-                        cpus[index].1 = Some(prop.read_int());
+                    "riscv,cbom-block-size" => node.cbom_block_size = prop.cells_iter().next(),
+                    "riscv,cboz-block-size" => node.cboz_block_size = prop.cells_iter().next(),
+                    "current-speed" => node.current_speed = prop.first_cell(),
+                    "status" => {
+                        node.disabled = prop.value_str().map(|s| s != "okay" && s != "ok").unwrap_or(false);
                     }
-                    _ => {},
+                    _ => {}
+                }
+                if !fits {
+                    println!("Property {} of device tree node {} is too large", name, path);
+                    too_large = true;
                 }
-                FdtVisit::Node { .. } => {}
             }
-        });
+        })?;
+
+        if too_large {
+            return Err(Error::InvalidFdt);
+        }
+        if overflow {
+            println!("WARN: Device tree has more than {} nodes, ignoring the rest", MAX_NODES);
+        }
 
         if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
             meta.initrd_start = start;
             meta.initrd_end = end;
         }
 
-        meta.plic_address = plic.expect("PLIC address not specified");
+        let mut dropped_reservations = false;
+        for entry in self.rsvmap.chunks_exact(16) {
+            let (start, size) = (BigEndian::read_u64(entry), BigEndian::read_u64(&entry[8..]));
+            if start == 0 && size == 0 {
+                break;
+            }
+            dropped_reservations |= meta.reserved_memory.try_push((start, size)).is_err();
+        }
+        // Reserved regions without a reg property are allocated dynamically by the OS, so only
+        // static ones need to be avoided.
+        for &i in reserved_nodes.iter().filter(|&&i| i < tree.nodes.len() && !tree.nodes[i].disabled) {
+            let mut index = 0;
+            while let Some(region) = tree.reg(i, index) {
+                dropped_reservations |= meta.reserved_memory.try_push(region).is_err();
+                index += 1;
+            }
+        }
+        if dropped_reservations {
+            println!("WARN: Device tree has more than {} reserved memory regions, ignoring the rest",
+                     meta.reserved_memory.capacity());
+        }
+
+        let nodes = &tree.nodes;
+        let mut plic = None;
+        let mut aplics = ArrayVec::<[usize; 4]>::new();
+        let mut imsics = ArrayVec::<[usize; 4]>::new();
+        let mut uart = None;
+        let mut htif = false;
+        for i in 0..nodes.len() {
+            let node = &nodes[i];
+            if node.disabled {
+                continue;
+            }
+
+            if &*node.device_type == "memory" {
+                let mut index = 0;
+                while let Some(range) = tree.reg(i, index) {
+                    let _ = meta.memory_ranges.try_push(range);
+                    index += 1;
+                }
+            } else if node.is_compatible("ns16550a") || node.is_compatible("sifive,uart0") {
+                if let (None, Some((base, _))) = (meta.uart_type, tree.reg(i, 0)) {
+                    meta.uart_address = base;
+                    meta.uart_baud = node.current_speed;
+                    uart = Some(i);
+                    meta.uart_type = Some(if node.is_compatible("ns16550a") {
+                        UartType::Ns16550a
+                    } else {
+                        UartType::SiFive
+                    });
+                }
+            } else if node.is_compatible("riscv,clint0") {
+                meta.clint_address = meta.clint_address.or(tree.reg(i, 0).map(|r| r.0));
+            } else if node.is_compatible("sifive,test0") {
+                meta.test_finisher_address = meta.test_finisher_address.or(tree.reg(i, 0).map(|r| r.0));
+            } else if node.is_compatible("google,goldfish-rtc") {
+                meta.rtc_address = meta.rtc_address.or(tree.reg(i, 0).map(|r| r.0));
+            } else if node.is_compatible("ucb,htif0") {
+                htif = true;
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+                plic = plic.or(Some(i));
+            } else if node.is_compatible("riscv,aplic") {
+                let _ = aplics.try_push(i);
+            } else if node.is_compatible("riscv,imsics") {
+                let _ = imsics.try_push(i);
+            }
+        }
+
+        meta.memory_ranges.retain(|r| r.1 > 0);
+        meta.memory_ranges.sort_unstable_by_key(|r| r.0);
+        if let Some(&(base, size)) = meta.memory_ranges.first() {
+            meta.physical_memory_offset = base;
+            meta.physical_memory_size = size;
+        }
+
+        // HTIF is only used as the console if there is no UART, or the build asks for it. When the
+        // hypervisor runs on top of other firmware, the firmware owns the memory where `tohost`
+        // would be and the simulator's HTIF with it.
+        let tohost = meta.physical_memory_offset.wrapping_add(constants::TOHOST_OFFSET);
+        if htif && !meta.is_reserved(tohost, 0x1000) {
+            meta.htif_address = Some(tohost);
+            if meta.uart_type.is_none() || cfg!(feature = "htif_console") {
+                meta.uart_type = Some(UartType::Htif);
+                meta.uart_address = tohost;
+                uart = None;
+            }
+        }
+
+        // With AIA there are separate machine and supervisor level APLIC domains (and IMSICs). The
+        // supervisor level ones are those wired to supervisor external interrupts (9) or, for an
+        // APLIC in MSI mode, sending its MSIs to the supervisor level IMSIC.
+        let supervisor_level = |i: usize| nodes[i].interrupts_extended.chunks(2).any(|p| p.len() == 2 && p[1] == 9);
+        let imsic = imsics.iter().cloned().find(|&i| supervisor_level(i));
+        let aplic = aplics.iter().cloned().find(|&i| {
+            supervisor_level(i) || (nodes[i].msi_parent.is_some() &&
+                                    nodes[i].msi_parent == imsic.and_then(|m| nodes[m].phandle))
+        });
+
+        // `plic` is the node that devices send their interrupts to, and `contexts` the one whose
+        // interrupts-extended property lists the harts that interrupts can be delivered to.
+        let (plic, contexts) = match (plic, aplic, imsic) {
+            (Some(plic), _, _) => (plic, plic),
+            (None, Some(aplic), _) if supervisor_level(aplic) => {
+                meta.irqchip = IrqChip::AplicDirect;
+                (aplic, aplic)
+            }
+            (None, Some(aplic), Some(imsic)) => {
+                meta.irqchip = IrqChip::AplicMsi;
+                (aplic, imsic)
+            }
+            _ => {
+                println!("PLIC not found in device tree");
+                return Err(Error::InvalidFdt);
+            }
+        };
+        meta.plic_address = match tree.reg(plic, 0) {
+            Some((address, _)) => address,
+            None => {
+                println!("PLIC address not specified");
+                return Err(Error::InvalidFdt);
+            }
+        };
 
-        for &c in cpus.iter() {
-            if let (Some(hartid), Some(phandle)) = c {
-                if let Some(plic_context) = plic_context_phandles.iter().position(|&p| p == Some(phandle as u32)) {
-                    meta.harts.push(Hart {
-                        hartid,
-                        plic_context: plic_context as u64,
-                    })
+        // Console input falls back to polling unless the UART's interrupt goes to the PLIC.
+        meta.uart_irq = uart.filter(|&i| tree.interrupt_parent(i) == nodes[plic].phandle)
+            .and_then(|i| nodes[i].interrupt);
+
+        let (mut sstc, mut svpbmt, mut sscofpmf, mut isa_letters) = (true, true, true, !0);
+        let mut dropped_harts = false;
+        let mut cbo_block_sizes: Option<(Option<u32>, Option<u32>)> = None;
+        // Each pair in interrupts-extended names the local interrupt controller of a hart and the
+        // interrupt line on it. Only contexts that deliver supervisor external interrupts (9) are
+        // of interest, and only harts that can run in supervisor mode can be used. Some SoCs (like
+        // the FU540) have a monitor core without an MMU that still gets a supervisor context.
+        for (context, pair) in nodes[contexts].interrupts_extended.chunks(2).enumerate() {
+            if pair.len() != 2 || pair[1] != 9 {
+                continue;
+            }
+
+            let cpu = tree.by_phandle(pair[0]).and_then(|intc| nodes[intc].parent);
+            if let Some(cpu) = cpu {
+                if &*nodes[cpu].device_type == "cpu" && !nodes[cpu].disabled && nodes[cpu].mmu {
+                    if let Some((hartid, _)) = tree.reg(cpu, 0) {
+                        sstc &= nodes[cpu].sstc;
+                        svpbmt &= nodes[cpu].svpbmt;
+                        sscofpmf &= nodes[cpu].sscofpmf;
+                        isa_letters &= nodes[cpu].isa_letters;
+                        let sizes = (nodes[cpu].cbom_block_size.filter(|_| nodes[cpu].zicbom),
+                                     nodes[cpu].cboz_block_size.filter(|_| nodes[cpu].zicboz));
+                        cbo_block_sizes = Some(match cbo_block_sizes {
+                            None => sizes,
+                            Some((cbom, cboz)) => (cbom.filter(|&s| sizes.0 == Some(s)),
+                                                   cboz.filter(|&s| sizes.1 == Some(s))),
+                        });
+                        dropped_harts |= meta.harts.try_push(Hart {
+                            hartid,
+                            plic_context: context as u64,
+                        }).is_err();
+                    }
                 }
             }
         }
+        if dropped_harts {
+            println!("WARN: Device tree has more than {} harts, ignoring the rest", meta.harts.capacity());
+        }
         meta.harts.sort_unstable_by_key(|h|h.hartid);
+        meta.sstc = sstc && !meta.harts.is_empty();
+        meta.svpbmt = svpbmt && !meta.harts.is_empty();
+        meta.sscofpmf = sscofpmf && !meta.harts.is_empty();
+        meta.cbom_block_size = cbo_block_sizes.and_then(|s| s.0);
+        meta.cboz_block_size = cbo_block_sizes.and_then(|s| s.1);
+        meta.isa_letters = if meta.harts.is_empty() { 0 } else { isa_letters };
+
+        // Virtio devices are only usable if their interrupts are routed through the PLIC.
+        for i in 0..nodes.len() {
+            if !nodes[i].is_compatible("virtio,mmio") || nodes[i].disabled
+                || tree.interrupt_parent(i) != nodes[plic].phandle {
+                continue;
+            }
 
-        for &v in virtio.iter().rev() {
-            if let (Some((base_address, size)), Some(irq)) = v {
-                meta.virtio.push(Device {
+            if let (Some((base_address, size)), Some(irq)) = (tree.reg(i, 0), nodes[i].interrupt) {
+                let _ = meta.virtio.try_push(Device {
                     base_address,
                     size,
-                    irq
-                })
+                    irq: irq as u64,
+                });
             }
         }
         meta.virtio.sort_unstable_by_key(|v| v.base_address);
 
-        meta
+        Ok(meta)
     }
 
-    pub fn initialize_guest(&mut self, guest_memory_size: u64, bootargs: &str) {
-        self.walk(|path, unit_addresses, v| match v {
+    pub fn initialize_guest(&mut self, bootargs: &str) {
+        let _ = self.walk(|path, unit_addresses, v| match v {
             FdtVisit::Property { name, prop } => match (path, name) {
                 ("/chosen", "bootargs") => {
                     let s = prop.value_slice();
@@ -297,55 +1056,64 @@ impl<'a> Fdt<'a> {
                         s[i] = bootargs.as_bytes()[i];
                     }
                 }
-                ("/memory", "reg") => {
-                    let region = prop.read_range();
-                    let mut new_region = [0; 16];
-                    BigEndian::write_u64(&mut new_region, region.0);
-                    BigEndian::write_u64(&mut new_region[8..], guest_memory_size);
-                    prop.set(&new_region);
-                }
                 _ => {},
             }
             FdtVisit::Node { .. } => {}
         });
     }
 
+    /// Apply the settings held as properties of the root node, as they are on the config disk.
+    pub fn apply_settings(&mut self, meta: &mut MachineMeta) {
+        let _ = self.walk(|path, _, v| {
+            if let FdtVisit::Property { name, prop } = v {
+                if path == "/" && !meta.apply_setting(name, prop) {
+                    println!("WARN: Ignoring unknown setting {} on the config disk", name);
+                }
+            }
+        });
+    }
+
     // Mask out entries from FDT and return some information about the machine.
-    fn walk<F>(&mut self, mut visit: F) where
+    //
+    // A malformed structure block ends the walk early with `Error::InvalidFdt` rather than causing
+    // a panic: names that are too long are truncated, and the walk stops at the first token that
+    // runs past the end of the block, doesn't fit the nesting seen so far or nests deeper than
+    // `MAX_DEPTH`, or at the end of the block if there is no FDT_END token.
+    fn walk<F>(&mut self, mut visit: F) -> Result<()> where
         F: FnMut(&str, &[Option<u64>], FdtVisit),
     {
         let mut mask_node = 0;
 
         let mut path = ArrayString::<[_; 1024]>::new();
-        let mut unit_addresses = ArrayVec::<[Option<u64>; 32]>::new();
+        let mut unit_addresses = ArrayVec::<[Option<u64>; MAX_DEPTH]>::new();
 
         let mut i = 0;
-        while i < self.nodes.len() {
+        while i + 4 <= self.nodes.len() {
             let old_i = i;
-            assert_eq!(i % 4, 0);
             match BigEndian::read_u32(&self.nodes[i..]) {
                 FDT_END => {
-                    break;
+                    return Ok(());
                 }
                 FDT_BEGIN_NODE => {
                     i += 4;
 
-                    // Root node is weird: name will be empty so its children should not prepend
-                    // another slash.
-                    if path.len() != 1 {
-                        path.push('/');
-                    }
-
                     let mut full_name = ArrayString::<[_;48]>::new();
-                    while self.nodes[i] != 0 {
-                        full_name.push(self.nodes[i] as char);
+                    while i < self.nodes.len() && self.nodes[i] != 0 {
+                        let _ = full_name.try_push(self.nodes[i] as char);
                         i += 1;
                     }
-                    i = round4(i);
+                    i = round4(i + 1);
 
+                    // Root node is weird: name will be empty so its children should not prepend
+                    // another slash.
                     let mut name_parts = full_name.split('@');
-                    path.push_str(name_parts.next().unwrap_or(""));
-                    unit_addresses.push(name_parts.next().and_then(|a| u64::from_str_radix(a, 16).ok()));
+                    let separator = if path.len() != 1 { "/" } else { "" };
+                    if path.try_push_str(separator).is_err()
+                        || path.try_push_str(name_parts.next().unwrap_or("")).is_err()
+                        || unit_addresses.try_push(name_parts.next()
+                                                   .and_then(|a| u64::from_str_radix(a, 16).ok())).is_err() {
+                        return Err(Error::InvalidFdt);
+                    }
 
                     if mask_node > 0 {
                         mask_node += 1;
@@ -358,23 +1126,35 @@ impl<'a> Fdt<'a> {
                     }
                 }
                 FDT_END_NODE => {
+                    if unit_addresses.pop().is_none() {
+                        return Err(Error::InvalidFdt);
+                    }
                     if mask_node > 0 {
                         BigEndian::write_u32(&mut self.nodes[i..], FDT_NOP);
                         mask_node = mask_node - 1;
                     }
 
-                    let mut index = path.rfind('/').unwrap();
+                    let mut index = path.rfind('/').unwrap_or(0);
                     if index == 0 && path.len() > 1 {
                         index = 1;
                     }
                     path.truncate(index);
-                    unit_addresses.pop();
                     i += 4;
                 }
                 FDT_PROP => {
-                    let mut prop = Property::from_slice(&mut self.nodes[i..]).0;
+                    if self.nodes.len() - i < 12 {
+                        return Err(Error::InvalidFdt);
+                    }
+                    let len = 12 + round4(BigEndian::read_u32(&self.nodes[(i + 4)..]) as usize);
+                    if len > self.nodes.len() - i {
+                        return Err(Error::InvalidFdt);
+                    }
+                    let mut prop = match Property::from_slice(&mut self.nodes[i..]) {
+                        Some((prop, _)) => prop,
+                        None => return Err(Error::InvalidFdt),
+                    };
                     let prop_name = Self::get_string(self.strings, prop.name_offset());
-                    i += 12 + round4(prop.len());
+                    i += len;
                     visit(&path, &unit_addresses, FdtVisit::Property{ name: prop_name, prop: &mut prop });
                 }
                 FDT_NOP | _ => {
@@ -383,11 +1163,12 @@ impl<'a> Fdt<'a> {
             }
 
             if mask_node > 0 {
-                for j in (old_i..i).step_by(4) {
+                for j in (old_i..i.min(self.nodes.len() & !3)).step_by(4) {
                     BigEndian::write_u32(&mut self.nodes[j..], FDT_NOP);
                 }
             }
         }
+        Err(Error::InvalidFdt)
     }
 }
 
@@ -409,13 +1190,20 @@ impl MemoryRegion {
 #[repr(C)]
 pub struct Property<'a>(&'a mut [u8]);
 impl<'a> Property<'a> {
-    pub fn from_slice(s: &'a mut [u8]) -> (Self, &mut [u8]) {
-        assert_eq!(BigEndian::read_u32(s), FDT_PROP);
+    /// Split the property at the start of `s` from whatever follows it. None if `s` doesn't start
+    /// with a property or is too short to hold all of it.
+    pub fn from_slice(s: &'a mut [u8]) -> Option<(Self, &mut [u8])> {
+        if s.len() < 12 || BigEndian::read_u32(s) != FDT_PROP {
+            return None;
+        }
 
         let len = 12 + round4(BigEndian::read_u32(&s[4..]) as usize);
-        let split = s.split_at_mut(len as usize);
+        if len > s.len() {
+            return None;
+        }
+        let split = s.split_at_mut(len);
 
-        (Self(split.0), split.1)
+        Some((Self(split.0), split.1))
     }
 
     pub fn len(&self) -> usize {
@@ -425,17 +1213,20 @@ impl<'a> Property<'a> {
         BigEndian::read_u32(&self.0[8..][..4]) as usize
     }
 
-    pub fn read_int(&self) -> u64 {
+    /// The value as a 32 or 64 bit integer, or None if it is some other size.
+    pub fn read_int(&self) -> Option<u64> {
         match self.len() {
-            4 => BigEndian::read_u32(&self.0[12..][..4]) as u64,
-            8 => BigEndian::read_u64(&self.0[12..][..8]),
-            _ => unreachable!(),
+            4 => Some(BigEndian::read_u32(&self.0[12..][..4]) as u64),
+            8 => Some(BigEndian::read_u64(&self.0[12..][..8])),
+            _ => None,
         }
     }
-    pub fn read_range(&self) -> (u64, u64) {
-        assert_eq!(self.len(), 16);
-
-        (BigEndian::read_u64(&self.0[12..20]), BigEndian::read_u64(&self.0[20..28]))
+    /// The value as a pair of 64 bit integers, or None if it is some other size.
+    pub fn read_range(&self) -> Option<(u64, u64)> {
+        if self.len() != 16 {
+            return None;
+        }
+        Some((BigEndian::read_u64(&self.0[12..20]), BigEndian::read_u64(&self.0[20..28])))
     }
     pub fn mask(&mut self) {
         for i in (0..self.0.len()).step_by(4) {
@@ -451,7 +1242,7 @@ impl<'a> Property<'a> {
                 return None;
             }
         }
-        Some(core::str::from_utf8(&self.0[12..][..(self.len() - 1)]).unwrap())
+        core::str::from_utf8(&self.0[12..][..(self.len() - 1)]).ok()
     }
     pub fn value_slice(&mut self) -> &mut [u8] {
         &mut self.0[12..]
@@ -460,13 +1251,30 @@ impl<'a> Property<'a> {
     pub fn cells(&self) -> usize {
         self.len() / 4
     }
+    pub fn cells_iter<'b>(&'b self) -> impl Iterator<Item = u32> + 'b {
+        (0..self.cells()).map(move |i| self.read_cell(i))
+    }
+    pub fn cell_pairs<'b>(&'b self) -> impl Iterator<Item = (u32, u32)> + 'b {
+        (0..self.cells() / 2).map(move |i| (self.read_cell(2*i), self.read_cell(2*i + 1)))
+    }
+    pub fn cell_triples<'b>(&'b self) -> impl Iterator<Item = (u32, u32, u32)> + 'b {
+        (0..self.cells() / 3).map(move |i| (self.read_cell(3*i), self.read_cell(3*i + 1), self.read_cell(3*i + 2)))
+    }
+    pub fn first_cell(&self) -> Option<u32> {
+        if self.cells() > 0 { Some(self.read_cell(0)) } else { None }
+    }
     pub fn read_cell(&self, i: usize) -> u32 {
         BigEndian::read_u32(&self.0[(12 + 4*i)..])
     }
 
-    pub fn set(&mut self, value: &[u8]) {
-        assert_eq!(value.len(), self.len());
-        self.0[12..].copy_from_slice(value);
+    /// Overwrite the value, which must keep its length. Returns false, leaving the value alone, if
+    /// `value` has a different length.
+    pub fn set(&mut self, value: &[u8]) -> bool {
+        if value.len() != self.len() {
+            return false;
+        }
+        self.0[12..][..value.len()].copy_from_slice(value);
+        true
     }
 }
 
@@ -482,3 +1290,976 @@ enum FdtVisit<'a> {
 const fn round4(i: usize) -> usize {
     4 * ((i + 3) / 4)
 }
+
+/// Read-only view of a device tree blob used as input when building a new tree. Unlike `Fdt`, all
+/// accesses are bounds checked since the contents may come from outside of the hypervisor.
+struct Blob<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    rsvmap: &'a [u8],
+}
+
+pub type Properties<'a> = ArrayVec<[(&'a str, &'a [u8]); 64]>;
+type Children = ArrayVec<[usize; 64]>;
+
+impl<'a> Blob<'a> {
+    fn new(data: &'a [u8]) -> Result<Self> {
+        if data.len() < FDT_HEADER_SIZE || BigEndian::read_u32(data) != FDT_MAGIC {
+            return Err(Error::InvalidFdt);
+        }
+        let field = |i: usize| BigEndian::read_u32(&data[(i * 4)..]) as usize;
+        let (total_size, off_dt_struct, off_dt_strings, off_mem_rsvmap) = (field(1), field(2), field(3), field(4));
+        let (version, size_dt_strings, size_dt_struct) = (field(5), field(8), field(9));
+        if total_size > data.len() || version < 17 {
+            return Err(Error::InvalidFdt);
+        }
+
+        // The reservation block ends with an all zero entry, which is included in the slice.
+        let mut rsvmap_end = off_mem_rsvmap;
+        loop {
+            let entry = data.get(rsvmap_end..(rsvmap_end + 16)).ok_or(Error::InvalidFdt)?;
+            rsvmap_end += 16;
+            if entry.iter().all(|&b| b == 0) {
+                break;
+            }
+        }
+
+        Ok(Self {
+            structs: data.get(off_dt_struct..(off_dt_struct + size_dt_struct)).ok_or(Error::InvalidFdt)?,
+            strings: data.get(off_dt_strings..(off_dt_strings + size_dt_strings)).ok_or(Error::InvalidFdt)?,
+            rsvmap: &data[off_mem_rsvmap..rsvmap_end],
+        })
+    }
+
+    fn token(&self, offset: usize) -> Result<u32> {
+        self.structs.get(offset..(offset + 4)).map(BigEndian::read_u32).ok_or(Error::InvalidFdt)
+    }
+    fn skip_nops(&self, mut offset: usize) -> usize {
+        while let Ok(FDT_NOP) = self.token(offset) {
+            offset += 4;
+        }
+        offset
+    }
+
+    fn root(&self) -> Result<usize> {
+        let root = self.skip_nops(0);
+        match self.token(root)? {
+            FDT_BEGIN_NODE => Ok(root),
+            _ => Err(Error::InvalidFdt),
+        }
+    }
+
+    /// Returns the name of the node starting at `node` and the offset of the token after it.
+    fn node_name(&self, node: usize) -> Result<(&'a str, usize)> {
+        let name = self.structs.get((node + 4)..).ok_or(Error::InvalidFdt)?;
+        let len = name.iter().position(|&c| c == 0).ok_or(Error::InvalidFdt)?;
+        let name = core::str::from_utf8(&name[..len]).map_err(|_| Error::InvalidFdt)?;
+        Ok((name, round4(node + 4 + len + 1)))
+    }
+
+    /// Returns the name and value of the property starting at `offset`, and the offset of the token
+    /// after it.
+    fn property(&self, offset: usize) -> Result<(&'a str, &'a [u8], usize)> {
+        let len = self.token(offset + 4)? as usize;
+        let name_offset = self.token(offset + 8)? as usize;
+        let value = self.structs.get((offset + 12)..(offset + 12 + len)).ok_or(Error::InvalidFdt)?;
+
+        let name = self.strings.get(name_offset..).ok_or(Error::InvalidFdt)?;
+        let name_len = name.iter().position(|&c| c == 0).ok_or(Error::InvalidFdt)?;
+        let name = core::str::from_utf8(&name[..name_len]).map_err(|_| Error::InvalidFdt)?;
+        Ok((name, value, offset + 12 + round4(len)))
+    }
+
+    /// Visit the properties and children of a node, returning the offset just past its end.
+    fn scan(&self, node: usize, properties: &mut Properties<'a>, children: &mut Children) -> Result<usize> {
+        let mut offset = self.node_name(node)?.1;
+        loop {
+            offset = self.skip_nops(offset);
+            match self.token(offset)? {
+                FDT_PROP => {
+                    let (name, value, next) = self.property(offset)?;
+                    properties.try_push((name, value)).map_err(|_| Error::OutOfMemory)?;
+                    offset = next;
+                }
+                FDT_BEGIN_NODE => {
+                    children.try_push(offset).map_err(|_| Error::OutOfMemory)?;
+                    offset = self.scan(offset, &mut Properties::new(), &mut Children::new())?;
+                }
+                FDT_END_NODE => return Ok(offset + 4),
+                _ => return Err(Error::InvalidFdt),
+            }
+        }
+    }
+    fn properties(&self, node: usize) -> Result<Properties<'a>> {
+        let mut properties = Properties::new();
+        self.scan(node, &mut properties, &mut Children::new())?;
+        Ok(properties)
+    }
+    fn children(&self, node: usize) -> Result<Children> {
+        let mut children = Children::new();
+        self.scan(node, &mut Properties::new(), &mut children)?;
+        Ok(children)
+    }
+
+    /// Find a descendant of `node` by its path relative to that node.
+    fn lookup(&self, mut node: usize, path: &str) -> Result<Option<usize>> {
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let mut found = None;
+            for child in self.children(node)? {
+                if self.node_name(child)?.0 == component {
+                    found = Some(child);
+                    break;
+                }
+            }
+            match found {
+                Some(child) => node = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(node))
+    }
+
+    /// Search the subtree rooted at `node` (whose path is `path`) for the node with the given
+    /// phandle. On success `path` is updated to the path of that node.
+    fn find_phandle(&self, node: usize, phandle: u32, path: &mut Path) -> Result<bool> {
+        for (name, value) in self.properties(node)? {
+            if (name == "phandle" || name == "linux,phandle") && value.len() == 4
+                && BigEndian::read_u32(value) == phandle {
+                return Ok(true);
+            }
+        }
+
+        let len = path.len();
+        for child in self.children(node)? {
+            push_path(path, self.node_name(child)?.0)?;
+            if self.find_phandle(child, phandle, path)? {
+                return Ok(true);
+            }
+            path.truncate(len);
+        }
+        Ok(false)
+    }
+}
+
+type Path = ArrayString<[u8; 256]>;
+
+fn push_path(path: &mut Path, name: &str) -> Result<()> {
+    if path.len() > 1 {
+        path.try_push('/').map_err(|_| Error::OutOfMemory)?;
+    }
+    path.try_push_str(name).map_err(|_| Error::OutOfMemory)
+}
+
+/// Serializes a new device tree blob into a buffer.
+struct Writer<'a> {
+    output: &'a mut [u8],
+    offset: usize,
+    strings: ArrayVec<[u8; 4096]>,
+}
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, data: &[u8]) -> Result<()> {
+        let end = self.offset + round4(data.len());
+        let output = self.output.get_mut(self.offset..end).ok_or(Error::OutOfMemory)?;
+        output[..data.len()].copy_from_slice(data);
+        for b in &mut output[data.len()..] {
+            *b = 0;
+        }
+        self.offset = end;
+        Ok(())
+    }
+    fn u32(&mut self, value: u32) -> Result<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn string_offset(&mut self, name: &str) -> Result<u32> {
+        let mut offset = 0;
+        for s in self.strings.split(|&c| c == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return Ok(offset as u32);
+            }
+            offset += s.len() + 1;
+        }
+
+        let offset = self.strings.len();
+        if offset + name.len() + 1 > self.strings.capacity() {
+            return Err(Error::OutOfMemory);
+        }
+        self.strings.extend(name.bytes().chain(Some(0)));
+        Ok(offset as u32)
+    }
+
+    fn begin_node(&mut self, name: &str) -> Result<()> {
+        self.u32(FDT_BEGIN_NODE)?;
+        let start = self.offset;
+        self.bytes(name.as_bytes())?;
+        // Names are NUL terminated, so an extra word is needed if the name fills the last one.
+        if self.offset - start == name.len() {
+            self.u32(0)?;
+        }
+        Ok(())
+    }
+    fn property(&mut self, name: &str, value: &[u8]) -> Result<()> {
+        let name_offset = self.string_offset(name)?;
+        self.u32(FDT_PROP)?;
+        self.u32(value.len() as u32)?;
+        self.u32(name_offset)?;
+        self.bytes(value)
+    }
+    fn end_node(&mut self) -> Result<()> {
+        self.u32(FDT_END_NODE)
+    }
+
+    /// Append the strings block and fill in the header, given where the structure block starts.
+    /// Returns the size of the tree.
+    fn finish(mut self, off_dt_struct: usize) -> Result<usize> {
+        let size_dt_struct = self.offset - off_dt_struct;
+        let off_dt_strings = self.offset;
+        let strings = self.strings.clone();
+        self.bytes(&strings)?;
+        let total_size = self.offset;
+
+        let header = [FDT_MAGIC, total_size as u32, off_dt_struct as u32, off_dt_strings as u32, 40, 17, 16,
+                      0, strings.len() as u32, size_dt_struct as u32];
+        for (i, &field) in header.iter().enumerate() {
+            BigEndian::write_u32(&mut self.output[(i * 4)..], field);
+        }
+        Ok(total_size)
+    }
+
+    fn copy_node(&mut self, blob: &Blob, node: usize) -> Result<()> {
+        self.begin_node(blob.node_name(node)?.0)?;
+        for (name, value) in blob.properties(node)? {
+            self.property(name, value)?;
+        }
+        for child in blob.children(node)? {
+            self.copy_node(blob, child)?;
+        }
+        self.end_node()
+    }
+}
+
+/// A piece of an overlay: the contents of its `__overlay__` node get merged into the node at
+/// `target` in the base tree.
+struct Fragment {
+    target: Path,
+    overlay: usize,
+}
+
+/// Path of `path` relative to `target`, if it is inside of it.
+fn relative_path<'b>(path: &'b str, target: &str) -> Option<&'b str> {
+    if path == target {
+        Some("")
+    } else if target == "/" {
+        Some(&path[1..])
+    } else if path.starts_with(target) && path[target.len()..].starts_with('/') {
+        Some(&path[(target.len() + 1)..])
+    } else {
+        None
+    }
+}
+
+/// State for combining the base tree, overlay and guest specific changes into a new tree.
+struct Merge<'a, 'b> {
+    base: &'b Blob<'a>,
+    overlay: &'b Blob<'a>,
+    fragments: &'b [Fragment],
+    config: &'b GuestFdtConfig<'b>,
+
+    /// Set once the memory nodes have been written, so that any other memory nodes in the base
+    /// tree are dropped.
+    memory_written: bool,
+    /// Phandle of the PLIC, once its node has been written, for the devices added to the tree.
+    plic_phandle: Option<u32>,
+}
+
+impl<'a, 'b> Merge<'a, 'b> {
+    /// Write out a node of the base tree. `parent_cells` holds the `#address-cells` and
+    /// `#size-cells` of its parent.
+    fn node(&mut self, writer: &mut Writer, node: usize, path: &mut Path, parent_cells: (u32, u32))
+            -> Result<()> {
+        let (base, overlay) = (self.base, self.overlay);
+
+        let mut matches = ArrayVec::<[usize; 16]>::new();
+        for fragment in self.fragments {
+            if let Some(relative) = relative_path(&path[..], &fragment.target) {
+                if let Some(overlay_node) = overlay.lookup(fragment.overlay, relative)? {
+                    let _ = matches.try_push(overlay_node);
+                }
+            }
+        }
+
+        // Properties from the overlay replace those of the same name in the base tree. If several
+        // fragments set the same property, the last one wins.
+        let mut properties = base.properties(node)?;
+        for &overlay_node in &matches {
+            for (name, value) in overlay.properties(overlay_node)? {
+                match properties.iter().position(|p| p.0 == name) {
+                    Some(i) => properties[i].1 = value,
+                    None => properties.try_push((name, value)).map_err(|_| Error::OutOfMemory)?,
+                }
+            }
+        }
+        let property = |name: &str| properties.iter().find(|p| p.0 == name).map(|p| p.1);
+
+        let numa = !self.config.numa_distances.is_empty();
+        let ranges = self.config.memory;
+        if property("device_type") == Some(&b"memory\0"[..]) && !ranges.is_empty() {
+            if !self.memory_written {
+                self.memory_written = true;
+                if numa {
+                    for (i, range) in ranges.chunks(1).enumerate() {
+                        memory_node(writer, &properties, range, Some(i as u32), parent_cells)?;
+                    }
+                } else {
+                    memory_node(writer, &properties, ranges, None, parent_cells)?;
+                }
+            }
+            return Ok(());
+        }
+
+        writer.begin_node(base.node_name(node)?.0)?;
+        let is_cpu = property("device_type") == Some(&b"cpu\0"[..]);
+        let is_chosen = &path[..] == "/chosen";
+        let hidden = self.hidden(&properties, is_cpu, &path[..]);
+        let is_plic = property("compatible").map_or(false, |c| {
+            c.split(|&b| b == 0).any(|c| c == b"riscv,plic0" || c == b"sifive,plic-1.0.0")
+        });
+        if is_plic {
+            self.plic_phandle = property("phandle").filter(|v| v.len() == 4).map(BigEndian::read_u32);
+        }
+        if hidden {
+            println!("Hiding {} from guest {}", &path[..], self.config.guestid);
+            writer.property("status", b"disabled\0")?;
+        }
+        for &(name, value) in &properties {
+            match name {
+                "status" if hidden => {}
+                "rng-seed" if is_chosen && self.config.rng_seed.is_some() => {}
+                "kaslr-seed" if is_chosen && self.config.kaslr_seed.is_some() => {}
+                "riscv,cbom-block-size" if is_cpu && self.config.cbo_block_sizes.0.is_some() => {}
+                "riscv,cboz-block-size" if is_cpu && self.config.cbo_block_sizes.1.is_some() => {}
+                "riscv,isa" | "riscv,isa-extensions" if is_cpu && !self.config.isa_extensions.is_empty() => {
+                    let value = isa_with_extensions(value, self.config.isa_extensions, name == "riscv,isa")?;
+                    writer.property(name, &value)?;
+                }
+                _ => writer.property(name, value)?,
+            }
+        }
+        if is_cpu {
+            let (cbom, cboz) = self.config.cbo_block_sizes;
+            for &(name, size) in &[("riscv,cbom-block-size", cbom), ("riscv,cboz-block-size", cboz)] {
+                if let Some(size) = size {
+                    writer.property(name, &size.to_be_bytes())?;
+                }
+            }
+        }
+        if numa && is_cpu && property("numa-node-id").is_none() {
+            writer.property("numa-node-id", &0u32.to_be_bytes())?;
+        }
+        if is_chosen {
+            if let Some(kernel_base) = self.config.kernel_base {
+                writer.property("rvirt,kernel-base", &kernel_base.to_be_bytes())?;
+            }
+            if let Some(seed) = self.config.rng_seed {
+                writer.property("rng-seed", seed)?;
+            }
+            if let Some(seed) = self.config.kaslr_seed {
+                writer.property("kaslr-seed", &seed.to_be_bytes())?;
+            }
+            if !self.config.env.is_empty() {
+                writer.begin_node("rvirt")?;
+                for &(key, value) in self.config.env {
+                    let mut string = ArrayVec::<[u8; 1024]>::new();
+                    for &b in value.as_bytes().iter().chain(&[0]) {
+                        string.try_push(b).map_err(|_| Error::OutOfMemory)?;
+                    }
+                    writer.property(key, &string)?;
+                }
+                writer.end_node()?;
+            }
+        }
+
+        let cell_count = |name, default| {
+            property(name).filter(|v| v.len() == 4).map(BigEndian::read_u32).unwrap_or(default)
+        };
+        let cells = (cell_count("#address-cells", 2), cell_count("#size-cells", 1));
+        let children = base.children(node)?;
+        let len = path.len();
+        for &child in &children {
+            push_path(path, base.node_name(child)?.0)?;
+            self.node(writer, child, path, cells)?;
+            path.truncate(len);
+        }
+
+        if numa && &path[..] == "/" {
+            let nodes = ranges.len() as u32;
+            let mut matrix = ArrayVec::<[u8; 768]>::new();
+            for (i, &distance) in self.config.numa_distances.iter().enumerate() {
+                for &cell in &[i as u32 / nodes, i as u32 % nodes, distance] {
+                    for &b in &cell.to_be_bytes() {
+                        matrix.try_push(b).map_err(|_| Error::OutOfMemory)?;
+                    }
+                }
+            }
+            writer.begin_node("distance-map")?;
+            writer.property("compatible", b"numa-distance-map-v1\0")?;
+            writer.property("distance-matrix", &matrix)?;
+            writer.end_node()?;
+        }
+
+        if let (Some(address), "/") = (self.config.info_page, &path[..]) {
+            let mut reg = ArrayVec::<[u8; 256]>::new();
+            push_cells(&mut reg, address, cells.0)?;
+            push_cells(&mut reg, 0x1000, cells.1)?;
+            let mut name = ArrayString::<[u8; 32]>::new();
+            let _ = write!(name, "hypervisor@{:x}", address);
+            writer.begin_node(&name)?;
+            writer.property("compatible", b"rvirt,hypervisor-info\0")?;
+            writer.property("reg", &reg)?;
+            writer.end_node()?;
+        }
+
+        if let (Some(address), "/") = (self.config.test_device, &path[..]) {
+            test_device_nodes(writer, address, cells)?;
+        }
+
+        if let (Some(address), "/") = (self.config.debug_log, &path[..]) {
+            let mut reg = ArrayVec::<[u8; 256]>::new();
+            push_cells(&mut reg, address, cells.0)?;
+            push_cells(&mut reg, constants::DEBUG_LOG_SIZE, cells.1)?;
+            let mut name = ArrayString::<[u8; 32]>::new();
+            let _ = write!(name, "debug-log@{:x}", address);
+            writer.begin_node(&name)?;
+            writer.property("compatible", b"rvirt,debug-log\0")?;
+            writer.property("reg", &reg)?;
+            writer.end_node()?;
+        }
+
+        if let (Some((address, irq)), "/") = (self.config.rtc, &path[..]) {
+            let mut reg = ArrayVec::<[u8; 256]>::new();
+            push_cells(&mut reg, address, cells.0)?;
+            push_cells(&mut reg, 0x1000, cells.1)?;
+            let mut name = ArrayString::<[u8; 32]>::new();
+            let _ = write!(name, "rtc@{:x}", address);
+            writer.begin_node(&name)?;
+            writer.property("compatible", b"google,goldfish-rtc\0")?;
+            writer.property("reg", &reg)?;
+            if let Some(plic) = self.plic_phandle {
+                writer.property("interrupts", &irq.to_be_bytes())?;
+                writer.property("interrupt-parent", &plic.to_be_bytes())?;
+            }
+            writer.end_node()?;
+        }
+
+        // Nodes that only exist in the overlay are copied over as a whole.
+        let mut added = ArrayVec::<[&str; 64]>::new();
+        for &overlay_node in &matches {
+            for overlay_child in overlay.children(overlay_node)? {
+                let name = overlay.node_name(overlay_child)?.0;
+                let mut exists = added.contains(&name);
+                for &child in &children {
+                    exists = exists || base.node_name(child)?.0 == name;
+                }
+                if !exists {
+                    writer.copy_node(overlay, overlay_child)?;
+                    let _ = added.try_push(name);
+                }
+            }
+        }
+
+        writer.end_node()
+    }
+
+    /// Whether the node at `path`, with `properties`, is a device that the guest wasn't given.
+    fn hidden(&self, properties: &Properties, is_cpu: bool, path: &str) -> bool {
+        let allowed = self.config.allowed_devices;
+        if path == "/" || allowed.contains(&"*") {
+            return false;
+        }
+        let property = |name: &str| properties.iter().find(|p| p.0 == name).map(|p| p.1);
+        if property("status").map_or(false, |s| s != b"okay\0" && s != b"ok\0") {
+            return false;
+        }
+
+        if is_cpu {
+            let hartid = property("reg").map(|reg| match reg.len() {
+                8 => BigEndian::read_u64(reg),
+                n if n >= 4 => BigEndian::read_u32(reg) as u64,
+                _ => 0,
+            });
+            return hartid.map_or(false, |id| id >= self.config.harts);
+        }
+
+        match property("compatible") {
+            Some(compatible) => !compatible.split(|&b| b == 0).filter_map(|c| core::str::from_utf8(c).ok())
+                .any(|c| PROVIDED_DEVICES.contains(&c) || allowed.contains(&c)),
+            None => false,
+        }
+    }
+}
+
+/// Write the nodes for the test device at `address`, laid out as QEMU's virt machine does so that
+/// guests bind the same poweroff and reboot drivers to it.
+fn test_device_nodes(writer: &mut Writer, address: u64, cells: (u32, u32)) -> Result<()> {
+    let mut reg = ArrayVec::<[u8; 256]>::new();
+    push_cells(&mut reg, address, cells.0)?;
+    push_cells(&mut reg, 0x1000, cells.1)?;
+    let mut name = ArrayString::<[u8; 32]>::new();
+    let _ = write!(name, "test@{:x}", address);
+    writer.begin_node(&name)?;
+    writer.property("compatible", b"sifive,test1\0sifive,test0\0syscon\0")?;
+    writer.property("reg", &reg)?;
+    writer.property("phandle", &TEST_DEVICE_PHANDLE.to_be_bytes())?;
+    writer.end_node()?;
+
+    for &(name, compatible, value) in &[("poweroff", &b"syscon-poweroff\0"[..], constants::FINISHER_PASS),
+                                        ("reboot", &b"syscon-reboot\0"[..], constants::FINISHER_RESET)] {
+        writer.begin_node(name)?;
+        writer.property("compatible", compatible)?;
+        writer.property("regmap", &TEST_DEVICE_PHANDLE.to_be_bytes())?;
+        writer.property("offset", &0u32.to_be_bytes())?;
+        writer.property("value", &value.to_be_bytes())?;
+        writer.end_node()?;
+    }
+    Ok(())
+}
+
+/// Add `extensions` to the value of a cpu node's `riscv,isa` property (if `isa_string` is set) or
+/// `riscv,isa-extensions` string list, skipping any that are already present.
+fn isa_with_extensions(value: &[u8], extensions: &[&str], isa_string: bool) -> Result<ArrayVec<[u8; 256]>> {
+    let mut output = ArrayVec::<[u8; 256]>::new();
+    let value = match value.split_last() {
+        Some((&0, rest)) => rest,
+        _ => value,
+    };
+    let separator = if isa_string { b'_' } else { 0 };
+    for &b in value {
+        output.try_push(b).map_err(|_| Error::OutOfMemory)?;
+    }
+
+    for extension in extensions {
+        let mut existing = value.split(|&c| c == separator);
+        if isa_string {
+            existing.next();
+        }
+        if existing.any(|e| e == extension.as_bytes()) {
+            continue;
+        }
+        if !output.is_empty() {
+            output.try_push(separator).map_err(|_| Error::OutOfMemory)?;
+        }
+        for &b in extension.as_bytes() {
+            output.try_push(b).map_err(|_| Error::OutOfMemory)?;
+        }
+    }
+    output.try_push(0).map_err(|_| Error::OutOfMemory)?;
+    Ok(output)
+}
+
+/// Write a memory node describing `ranges`, based on the properties of an existing memory node.
+fn memory_node(writer: &mut Writer, properties: &Properties, ranges: &[(u64, u64)], numa_node: Option<u32>,
+               parent_cells: (u32, u32)) -> Result<()> {
+    let mut reg = ArrayVec::<[u8; 256]>::new();
+    for &(start, size) in ranges {
+        push_cells(&mut reg, start, parent_cells.0)?;
+        push_cells(&mut reg, size, parent_cells.1)?;
+    }
+
+    let mut name = ArrayString::<[u8; 32]>::new();
+    let _ = write!(name, "memory@{:x}", ranges[0].0);
+    writer.begin_node(&name)?;
+    for &(name, value) in properties.iter() {
+        match name {
+            "reg" | "numa-node-id" => {}
+            _ => writer.property(name, value)?,
+        }
+    }
+    writer.property("reg", &reg)?;
+    if let Some(numa_node) = numa_node {
+        writer.property("numa-node-id", &numa_node.to_be_bytes())?;
+    }
+    writer.end_node()
+}
+
+/// Append `value` encoded as `cells` big endian cells.
+fn push_cells(output: &mut ArrayVec<[u8; 256]>, value: u64, cells: u32) -> Result<()> {
+    if cells == 0 || (cells == 1 && value > u32::max_value() as u64) {
+        return Err(Error::InvalidFdt);
+    }
+    for i in (0..cells).rev() {
+        let cell = if i < 2 { (value >> (32 * i)) as u32 } else { 0 };
+        for &b in &cell.to_be_bytes() {
+            output.try_push(b).map_err(|_| Error::OutOfMemory)?;
+        }
+    }
+    Ok(())
+}
+
+/// Guest specific parts of a device tree generated by `build_guest_fdt`.
+pub struct GuestFdtConfig<'a> {
+    pub guestid: u64,
+    pub overlay: Option<&'a [u8]>,
+    /// Ranges of guest physical memory.
+    pub memory: &'a [(u64, u64)],
+    /// Distances between emulated NUMA nodes as a row major matrix. If this isn't empty, each range
+    /// in `memory` is described as a separate NUMA node.
+    pub numa_distances: &'a [u32],
+    /// Ranges to add to the memory reservation block.
+    pub reservations: &'a [(u64, u64)],
+    /// Extensions to add to the ISA of every cpu node, for those emulated by the hypervisor.
+    pub isa_extensions: &'a [&'a str],
+    /// Cache block sizes written to every cpu node for the Zicbom and Zicboz extensions.
+    pub cbo_block_sizes: (Option<u32>, Option<u32>),
+    /// Address a relocatable guest kernel was loaded at, reported in `/chosen/rvirt,kernel-base`.
+    pub kernel_base: Option<u64>,
+    /// Seeds for the guest's entropy pool and for its kernel's own address randomization, written
+    /// to `/chosen/rng-seed` and `/chosen/kaslr-seed` in place of any the base tree has.
+    pub rng_seed: Option<&'a [u8]>,
+    pub kaslr_seed: Option<u64>,
+    /// Guest physical address of the hypervisor info page, described by a `/hypervisor` node.
+    pub info_page: Option<u64>,
+    /// Guest physical address of the emulated test device, described by a `/test` node and the
+    /// `/poweroff` and `/reboot` nodes that refer to it.
+    pub test_device: Option<u64>,
+    /// Guest physical address of the debug log window, described by a `/debug-log` node.
+    pub debug_log: Option<u64>,
+    /// Guest physical address and interrupt of the emulated real time clock, described by a `/rtc`
+    /// node.
+    pub rtc: Option<(u64, u32)>,
+    /// Number of harts the guest has. Cpu nodes for any others are disabled.
+    pub harts: u64,
+    /// Compatible strings of devices to leave enabled besides those in `PROVIDED_DEVICES`, or "*"
+    /// to leave every device as it is.
+    pub allowed_devices: &'a [&'a str],
+    /// Metadata written as string properties of a `/chosen/rvirt` node, which is left out if this
+    /// is empty.
+    pub env: &'a [(&'a str, &'a str)],
+}
+
+/// Compatible strings of the devices the hypervisor emulates or passes through, and of the nodes
+/// that only hold other nodes.
+const PROVIDED_DEVICES: &[&str] = &[
+    "ns16550a", "virtio,mmio", "riscv,plic0", "sifive,plic-1.0.0", "riscv,clint0", "sifive,clint0",
+    "riscv,cpu-intc", "simple-bus", "rvirt,hypervisor-info", "numa-distance-map-v1", "sifive,test0",
+    "syscon-poweroff", "syscon-reboot", "google,goldfish-rtc", "rvirt,debug-log",
+];
+
+/// Phandle of the `/test` node, chosen to be well clear of those `dtc` assigns from 1 upwards.
+const TEST_DEVICE_PHANDLE: u32 = 0x52560001;
+
+/// Write a guest device tree into `output`, returning its size. The tree is a copy of `base` with
+/// the overlay (if any) applied, the extra reservations added and the first memory node replaced
+/// by nodes describing the configured memory. The `reg` properties of those nodes are encoded using
+/// the cell counts of their parent, and any further memory nodes are removed.
+///
+/// With NUMA emulation, every cpu node is placed in the first NUMA node and a `/distance-map` node
+/// is added. The load address of a relocatable kernel and the random seeds are added to `/chosen`,
+/// which must exist, and a `/hypervisor` node is added for the info page. So are nodes for the
+/// test device, the real time clock and the debug log window, if there are any.
+///
+/// Devices of the base tree that the guest wasn't given are hidden from it by setting their
+/// `status` to "disabled", which keeps any phandles pointing at them valid. That covers every node
+/// with a `compatible` property matching neither `PROVIDED_DEVICES` nor `allowed_devices`, and cpu
+/// nodes beyond the guest's harts. Nodes added by the overlay are left alone, since adding them is
+/// how a device is given to a guest.
+///
+/// Overlay fragments are located with either a `target-path` or a `target` phandle property. As an
+/// extension, a fragment can be limited to particular guests by listing their ids in a
+/// `rvirt,guests` property. Symbol fixups are not supported, so overlays can't reference labels
+/// from the base tree and any phandles they define must not collide with those of the base tree.
+pub fn build_guest_fdt(base: &[u8], config: &GuestFdtConfig, output: &mut [u8]) -> Result<usize> {
+    let guestid = config.guestid;
+    let base = Blob::new(base)?;
+    let overlay = match config.overlay {
+        Some(overlay) => Blob::new(overlay)?,
+        None => Blob { structs: &[], strings: &[], rsvmap: &[] },
+    };
+
+    let mut fragments = ArrayVec::<[Fragment; 16]>::new();
+    let overlay_fragments = if overlay.structs.is_empty() {
+        Children::new()
+    } else {
+        overlay.children(overlay.root()?)?
+    };
+    for fragment in overlay_fragments {
+        let name = overlay.node_name(fragment)?.0;
+        if name == "__fixups__" {
+            println!("WARN: Device tree overlay references symbols, which is not supported");
+            continue;
+        } else if name.starts_with("__") {
+            continue;
+        }
+
+        let mut target = None;
+        let mut selected = true;
+        for (name, value) in overlay.properties(fragment)? {
+            match name {
+                "target-path" => {
+                    let value = core::str::from_utf8(value).map_err(|_| Error::InvalidFdt)?;
+                    let mut path = Path::new();
+                    path.try_push_str(value.trim_end_matches('\0')).map_err(|_| Error::OutOfMemory)?;
+                    target = Some(path);
+                }
+                "target" if value.len() == 4 => {
+                    let mut path = Path::new();
+                    path.push('/');
+                    if base.find_phandle(base.root()?, BigEndian::read_u32(value), &mut path)? {
+                        target = Some(path);
+                    }
+                }
+                "rvirt,guests" => {
+                    selected = value.chunks(4).any(|g| g.len() == 4 && BigEndian::read_u32(g) as u64 == guestid);
+                }
+                _ => {}
+            }
+        }
+
+        match (selected, target, overlay.lookup(fragment, "__overlay__")?) {
+            (false, _, _) => {}
+            (true, Some(target), Some(overlay_node)) => {
+                fragments.try_push(Fragment { target, overlay: overlay_node }).map_err(|_| Error::OutOfMemory)?;
+            }
+            _ => println!("WARN: Ignoring device tree overlay fragment '{}' with no valid target", name),
+        }
+    }
+
+    let mut writer = Writer { output, offset: 40, strings: ArrayVec::new() };
+    writer.bytes(&base.rsvmap[..(base.rsvmap.len() - 16)])?;
+    for &(start, size) in config.reservations {
+        writer.bytes(&start.to_be_bytes())?;
+        writer.bytes(&size.to_be_bytes())?;
+    }
+    writer.bytes(&[0; 16])?;
+
+    let off_dt_struct = writer.offset;
+    let mut path = Path::new();
+    path.push('/');
+    let mut merge = Merge {
+        base: &base,
+        overlay: &overlay,
+        fragments: &fragments,
+        config,
+        memory_written: false,
+        plic_phandle: None,
+    };
+    merge.node(&mut writer, base.root()?, &mut path, (2, 1))?;
+    writer.u32(FDT_END)?;
+    writer.finish(off_dt_struct)
+}
+
+/// The properties of the root node of `blob`, which is how settings are stored on the config disk.
+pub fn settings(blob: &[u8]) -> Result<Properties> {
+    let blob = Blob::new(blob)?;
+    blob.properties(blob.root()?)
+}
+
+/// Build a device tree in `output` holding the settings in `blob`, if it is a valid device tree,
+/// with `name` set to `value`, or removed if `value` is None. Returns the size of the new tree.
+pub fn edit_settings(blob: &[u8], name: &str, value: Option<&[u8]>, output: &mut [u8]) -> Result<usize> {
+    let mut writer = Writer { output, offset: 40, strings: ArrayVec::new() };
+    writer.bytes(&[0; 16])?;
+
+    let off_dt_struct = writer.offset;
+    writer.begin_node("")?;
+    for (n, v) in settings(blob).unwrap_or_else(|_| ArrayVec::new()) {
+        if n != name {
+            writer.property(n, v)?;
+        }
+    }
+    if let Some(value) = value {
+        writer.property(name, value)?;
+    }
+    writer.end_node()?;
+    writer.u32(FDT_END)?;
+    writer.finish(off_dt_struct)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HIFIVE_UNLEASHED: &[u8] = include_bytes!("../hifive_u540.dtb");
+
+    fn guest_config<'a>(memory: &'a [(u64, u64)]) -> GuestFdtConfig<'a> {
+        GuestFdtConfig {
+            guestid: 1,
+            overlay: None,
+            memory,
+            numa_distances: &[],
+            reservations: &[],
+            isa_extensions: &[],
+            cbo_block_sizes: (None, None),
+            kernel_base: None,
+            rng_seed: None,
+            kaslr_seed: None,
+            info_page: None,
+            test_device: None,
+            debug_log: None,
+            rtc: None,
+            harts: 1,
+            allowed_devices: &[],
+            env: &[],
+        }
+    }
+
+    #[test]
+    fn parse_hifive_unleashed() {
+        let mut blob = HIFIVE_UNLEASHED.to_vec();
+        let meta = Fdt::from_slice(&mut blob).unwrap().parse().unwrap();
+
+        assert_eq!(meta.physical_memory_offset, 0x80000000);
+        assert_eq!(meta.physical_memory_size, 8 << 30);
+        // Hart 0 is the E51 monitor core, which has no MMU.
+        let harts: Vec<_> = meta.harts.iter().map(|h| (h.hartid, h.plic_context)).collect();
+        assert_eq!(harts, [(1, 2), (2, 4), (3, 6), (4, 8)]);
+        assert_eq!(meta.uart_type, Some(UartType::SiFive));
+        assert_eq!(meta.uart_address, 0x10010000);
+        assert_eq!(meta.irqchip, IrqChip::Plic);
+        assert_eq!(meta.plic_address, 0xc000000);
+        assert_eq!(&*meta.bootargs, "debug console=ttySIF0 console=tty0 root=/dev/nfs ip=dhcp");
+    }
+
+    #[test]
+    fn reject_bad_headers() {
+        let mut short = HIFIVE_UNLEASHED[..FDT_HEADER_SIZE - 1].to_vec();
+        assert!(Fdt::from_slice(&mut short).is_err());
+
+        let mut truncated = HIFIVE_UNLEASHED[..HIFIVE_UNLEASHED.len() - 1].to_vec();
+        assert!(Fdt::from_slice(&mut truncated).is_err());
+
+        let mut bad_magic = HIFIVE_UNLEASHED.to_vec();
+        bad_magic[0] ^= 1;
+        assert!(Fdt::from_slice(&mut bad_magic).is_err());
+
+        let mut bad_version = HIFIVE_UNLEASHED.to_vec();
+        BigEndian::write_u32(&mut bad_version[20..], 16);
+        assert!(Fdt::from_slice(&mut bad_version).is_err());
+    }
+
+    #[test]
+    fn parse_corrupted_structure_block() {
+        let off_dt_struct = BigEndian::read_u32(&HIFIVE_UNLEASHED[8..]) as usize;
+        let off_dt_strings = BigEndian::read_u32(&HIFIVE_UNLEASHED[12..]) as usize;
+        for offset in (off_dt_struct..off_dt_strings).step_by(4) {
+            for &word in &[0, 1, 2, 3, 9, 0xffff, 0xffffffff] {
+                let mut blob = HIFIVE_UNLEASHED.to_vec();
+                BigEndian::write_u32(&mut blob[offset..], word);
+                // Anything may come of it, as long as it isn't a panic.
+                let _ = Fdt::from_slice(&mut blob).unwrap().parse();
+            }
+        }
+    }
+
+    #[test]
+    fn parse_without_interrupt_controller() {
+        let mut blob = vec![0; 4096];
+        let mut writer = Writer { output: &mut blob, offset: 40, strings: ArrayVec::new() };
+        writer.bytes(&[0; 16]).unwrap();
+        writer.begin_node("").unwrap();
+        writer.property("#address-cells", &2u32.to_be_bytes()).unwrap();
+        writer.property("#size-cells", &2u32.to_be_bytes()).unwrap();
+        writer.begin_node("memory@80000000").unwrap();
+        writer.property("device_type", b"memory\0").unwrap();
+        writer.property("reg", &[0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0]).unwrap();
+        writer.end_node().unwrap();
+        writer.end_node().unwrap();
+        writer.u32(FDT_END).unwrap();
+        writer.finish(56).unwrap();
+
+        assert_eq!(Fdt::from_slice(&mut blob).unwrap().parse().unwrap_err(), Error::InvalidFdt);
+    }
+
+    /// A tree with nothing but a PLIC, and whatever `extra` adds to the root node.
+    fn plic_only_tree<F: FnOnce(&mut Writer) -> Result<()>>(extra: F) -> Vec<u8> {
+        let mut blob = vec![0; 4096];
+        let mut writer = Writer { output: &mut blob, offset: 40, strings: ArrayVec::new() };
+        writer.bytes(&[0; 16]).unwrap();
+        writer.begin_node("").unwrap();
+        writer.property("#address-cells", &2u32.to_be_bytes()).unwrap();
+        writer.property("#size-cells", &2u32.to_be_bytes()).unwrap();
+        writer.begin_node("plic@c000000").unwrap();
+        writer.property("compatible", b"riscv,plic0\0").unwrap();
+        writer.property("reg", &[0, 0, 0, 0, 0x0c, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0]).unwrap();
+        writer.end_node().unwrap();
+        extra(&mut writer).unwrap();
+        writer.end_node().unwrap();
+        writer.u32(FDT_END).unwrap();
+        writer.finish(56).unwrap();
+        blob
+    }
+
+    fn parse_plic_only_tree<F: FnOnce(&mut Writer) -> Result<()>>(extra: F) -> Result<MachineMeta> {
+        Fdt::from_slice(&mut plic_only_tree(extra)).unwrap().parse()
+    }
+
+    #[test]
+    fn reject_oversized_properties() {
+        assert_eq!(parse_plic_only_tree(|_| Ok(())).unwrap().plic_address, 0xc000000);
+
+        // A device with a `len` byte long property.
+        let node = |name: &'static str, len: usize| move |w: &mut Writer| {
+            w.begin_node("device@10000000")?;
+            w.property(name, &vec![b'a'; len])?;
+            w.end_node()
+        };
+        // Properties that just fit are kept whole.
+        assert!(parse_plic_only_tree(node("compatible", 64)).is_ok());
+        assert!(parse_plic_only_tree(node("ranges", 24 * 4)).is_ok());
+        assert!(parse_plic_only_tree(node("interrupts-extended", 64 * 4)).is_ok());
+
+        for &(name, len) in &[("compatible", 65), ("ranges", 25 * 4), ("interrupts-extended", 65 * 4),
+                              ("reg", 17 * 4)] {
+            assert_eq!(parse_plic_only_tree(node(name, len)).unwrap_err(), Error::InvalidFdt, "{}", name);
+        }
+    }
+
+    #[test]
+    fn reject_deep_nesting() {
+        let nest = |depth: usize| move |w: &mut Writer| {
+            for _ in 0..depth {
+                w.begin_node("bus")?;
+            }
+            for _ in 0..depth {
+                w.end_node()?;
+            }
+            Ok(())
+        };
+        // The root node counts towards the depth.
+        assert!(parse_plic_only_tree(nest(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(parse_plic_only_tree(nest(MAX_DEPTH)).unwrap_err(), Error::InvalidFdt);
+        assert_eq!(parse_plic_only_tree(nest(100)).unwrap_err(), Error::InvalidFdt);
+    }
+
+    #[test]
+    fn guest_fdt_round_trip() {
+        let memory = [(0x80000000, 256 << 20)];
+        let mut config = guest_config(&memory);
+        config.harts = 2;
+        config.rng_seed = Some(&[7; RNG_SEED_SIZE]);
+        let mut output = vec![0; 64 << 10];
+        let size = build_guest_fdt(HIFIVE_UNLEASHED, &config, &mut output).unwrap();
+
+        let meta = Fdt::from_slice(&mut output[..size]).unwrap().parse().unwrap();
+        assert_eq!(meta.physical_memory_offset, 0x80000000);
+        assert_eq!(meta.physical_memory_size, 256 << 20);
+        // Cpu nodes from hart 2 up are disabled, and hart 0 has no MMU.
+        let harts: Vec<_> = meta.harts.iter().map(|h| h.hartid).collect();
+        assert_eq!(harts, [1]);
+        assert_eq!(&*meta.rng_seed, &[7; RNG_SEED_SIZE][..]);
+    }
+
+    #[test]
+    fn edit_and_read_settings() {
+        let mut first = vec![0; 4096];
+        let size = edit_settings(&[], "rvirt,max-guests", Some(&2u32.to_be_bytes()), &mut first).unwrap();
+        let mut second = vec![0; 4096];
+        let size = edit_settings(&first[..size], "rvirt,no-kaslr", Some(b""), &mut second).unwrap();
+
+        let names: Vec<_> = settings(&second[..size]).unwrap().iter().map(|p| p.0).collect();
+        assert_eq!(names, ["rvirt,max-guests", "rvirt,no-kaslr"]);
+
+        let size = edit_settings(&second[..size], "rvirt,max-guests", None, &mut first).unwrap();
+        let names: Vec<_> = settings(&first[..size]).unwrap().iter().map(|p| p.0).collect();
+        assert_eq!(names, ["rvirt,no-kaslr"]);
+    }
+}
@@ -0,0 +1,282 @@
+//! Working out which operating system a guest runs, so that known problems can be worked around.
+//!
+//! The guest is identified, most trusted first, by:
+//!
+//!   * the `rvirt,guest-os` property of the host's /chosen, such as `"linux-5.4"` or `"freebsd"`,
+//!     which applies to every guest;
+//!   * the notes of the kernel image: FreeBSD kernels carry their `__FreeBSD_version`, and Linux
+//!     kernels have notes owned by "Linux" and a `linux_banner` symbol giving the version;
+//!   * a banner the guest passes to the `RVIRT_IDENTIFY` call, in the format of `/proc/version` or
+//!     `uname -sr`. This overrides the others, since the guest knows best, but only quirks that
+//!     act at run time take effect.
+//!
+//! Each identified guest gets the quirks of every entry of `QUIRKS` that matches it. What was found
+//! is recorded in the shared statics page and shown by the monitor's `list` command.
+
+use core::fmt;
+#[cfg(target_arch = "riscv64")]
+use crate::constants::MAX_GUESTS;
+#[cfg(target_arch = "riscv64")]
+use crate::context::Context;
+use crate::elf::Elf64;
+#[cfg(target_arch = "riscv64")]
+use crate::hart;
+#[cfg(target_arch = "riscv64")]
+use crate::restart;
+#[cfg(target_arch = "riscv64")]
+use crate::sbi::*;
+#[cfg(target_arch = "riscv64")]
+use crate::statics::SHARED_STATICS;
+
+/// Only offer the legacy v0.1 SBI calls, failing every other extension as firmware from before
+/// v0.2 would.
+pub const QUIRK_LEGACY_SBI: u32 = 1 << 0;
+/// Leave Sstc out of the guest's `riscv,isa`, so that it programs its timer through SBI. Only
+/// takes effect if the guest is identified before it boots.
+pub const QUIRK_NO_SSTC: u32 = 1 << 1;
+
+/// Longest banner accepted by `RVIRT_IDENTIFY`.
+#[cfg(target_arch = "riscv64")]
+const MAX_BANNER: u64 = 256;
+
+// Type of the FreeBSD ABI tag note, whose contents are the kernel's `__FreeBSD_version`.
+const NT_FREEBSD_ABI_TAG: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OsKind {
+    Unknown,
+    Linux,
+    FreeBsd,
+}
+
+/// Where the identification of a guest came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    None,
+    Config,
+    ElfNotes,
+    Hypercall,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GuestOs {
+    pub kind: OsKind,
+    /// Major, minor and patch version, or zeroes if unknown.
+    pub version: (u32, u32, u32),
+    pub source: Source,
+    pub quirks: u32,
+}
+
+impl GuestOs {
+    pub const UNKNOWN: Self = Self { kind: OsKind::Unknown, version: (0, 0, 0), source: Source::None, quirks: 0 };
+
+    fn new(kind: OsKind, version: (u32, u32, u32), source: Source) -> Self {
+        let quirks = QUIRKS.iter()
+            .filter(|q| q.kind == kind && version != (0, 0, 0) && version < q.before)
+            .fold(0, |quirks, q| quirks | q.quirks);
+        Self { kind, version, source, quirks }
+    }
+
+    pub fn has_quirk(&self, quirk: u32) -> bool {
+        self.quirks & quirk != 0
+    }
+}
+
+impl fmt::Display for GuestOs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            OsKind::Unknown => return write!(f, "unknown"),
+            OsKind::Linux => write!(f, "Linux")?,
+            OsKind::FreeBsd => write!(f, "FreeBSD")?,
+        }
+        let (major, minor, patch) = self.version;
+        if self.version != (0, 0, 0) {
+            write!(f, " {}.{}.{}", major, minor, patch)?;
+        }
+        let source = match self.source {
+            Source::None => return Ok(()),
+            Source::Config => "configuration",
+            Source::ElfNotes => "kernel image",
+            Source::Hypercall => "guest",
+        };
+        write!(f, " (from {})", source)
+    }
+}
+
+/// Quirks applied to every version of `kind` older than `before`.
+struct QuirkEntry {
+    kind: OsKind,
+    before: (u32, u32, u32),
+    quirks: u32,
+}
+
+const QUIRKS: &[QuirkEntry] = &[
+    // SBI v0.2 support arrived in Linux 5.7, and Sstc support in 6.4.
+    QuirkEntry { kind: OsKind::Linux, before: (5, 7, 0), quirks: QUIRK_LEGACY_SBI },
+    QuirkEntry { kind: OsKind::Linux, before: (6, 4, 0), quirks: QUIRK_NO_SSTC },
+    // FreeBSD 13 is the first release to probe for SBI extensions.
+    QuirkEntry { kind: OsKind::FreeBsd, before: (13, 0, 0), quirks: QUIRK_LEGACY_SBI | QUIRK_NO_SSTC },
+];
+
+/// Parse a version like "5.10.0-rc1" into its first three numbers.
+fn parse_version(s: &str) -> (u32, u32, u32) {
+    let mut numbers = s.split(|c: char| !c.is_ascii_digit()).map(|n| n.parse().unwrap_or(0));
+    (numbers.next().unwrap_or(0), numbers.next().unwrap_or(0), numbers.next().unwrap_or(0))
+}
+
+fn parse_kind(name: &str) -> OsKind {
+    if name.eq_ignore_ascii_case("linux") {
+        OsKind::Linux
+    } else if name.eq_ignore_ascii_case("freebsd") {
+        OsKind::FreeBsd
+    } else {
+        OsKind::Unknown
+    }
+}
+
+/// Parse the value of `rvirt,guest-os`: an OS name, optionally followed by a dash and a version.
+pub fn parse_config(value: &str) -> Option<GuestOs> {
+    let mut parts = value.splitn(2, '-');
+    let kind = parse_kind(parts.next()?);
+    if kind == OsKind::Unknown {
+        return None;
+    }
+    Some(GuestOs::new(kind, parts.next().map_or((0, 0, 0), parse_version), Source::Config))
+}
+
+/// Parse a banner like "Linux version 6.1.0 (...)" or "FreeBSD 13.2-RELEASE".
+fn parse_banner(banner: &str, source: Source) -> Option<GuestOs> {
+    let mut words = banner.split_whitespace();
+    let kind = parse_kind(words.next()?);
+    let version = match words.next()? {
+        "version" => words.next()?,
+        version => version,
+    };
+    match kind {
+        OsKind::Unknown => None,
+        kind => Some(GuestOs::new(kind, parse_version(version), source)),
+    }
+}
+
+/// Identify the guest from its kernel image.
+fn detect_from_elf(elf: &Elf64) -> Option<GuestOs> {
+    let mut linux = false;
+    for note in elf.notes() {
+        match note.name {
+            b"FreeBSD" if note.type_ == NT_FREEBSD_ABI_TAG && note.desc.len() >= 4 => {
+                // __FreeBSD_version is MMmmRRR, e.g. 1302001 for 13.2.
+                let v = u32::from_le_bytes([note.desc[0], note.desc[1], note.desc[2], note.desc[3]]);
+                return Some(GuestOs::new(OsKind::FreeBsd, (v / 100_000, v / 1000 % 100, 0), Source::ElfNotes));
+            }
+            b"Linux" => linux = true,
+            _ => {}
+        }
+    }
+    if !linux {
+        return None;
+    }
+
+    let banner = elf.symbols().find(|s| s.name == b"linux_banner")
+        .and_then(|s| elf.data_from(s.value))
+        .and_then(|data| core::str::from_utf8(data.split(|&c| c == 0).next()?).ok());
+    Some(banner.and_then(|b| parse_banner(b, Source::ElfNotes))
+         .unwrap_or(GuestOs::new(OsKind::Linux, (0, 0, 0), Source::ElfNotes)))
+}
+
+/// Identify the guest about to be booted from `kernel`, and record the result for this hart's
+/// guest.
+pub fn detect(config: Option<GuestOs>, kernel: &[u8]) -> GuestOs {
+    let os = config
+        .or_else(|| Elf64::parse(kernel).ok().and_then(|elf| detect_from_elf(&elf)))
+        .unwrap_or(GuestOs::UNKNOWN);
+    #[cfg(target_arch = "riscv64")]
+    record(os);
+    os
+}
+
+#[cfg(target_arch = "riscv64")]
+fn record(os: GuestOs) {
+    let guestid = hart::current().guest_index() as usize;
+    *SHARED_STATICS.guest_os[guestid % MAX_GUESTS].lock() = os;
+}
+
+/// identify(banner_addr, banner_len): let the guest say what it is.
+#[cfg(target_arch = "riscv64")]
+pub fn identify(state: &mut Context) -> (i64, u64) {
+    let addr = state.saved_registers.get(10);
+    let len = state.saved_registers.get(11).min(MAX_BANNER);
+    if !state.prepare_guest_access(addr, len, false) {
+        return (SBI_ERR_INVALID_PARAM, 0);
+    }
+    let banner = state.guest_memory.slice(addr, len);
+    match core::str::from_utf8(banner).ok().and_then(|b| parse_banner(b, Source::Hypercall)) {
+        Some(os) => {
+            state.guest_os = os;
+            record(os);
+            (SBI_SUCCESS, 0)
+        }
+        None => (SBI_ERR_INVALID_PARAM, 0),
+    }
+}
+
+/// Print what is known about each guest, for the monitor.
+#[cfg(target_arch = "riscv64")]
+pub fn print_guests() {
+    for guestid in 1..MAX_GUESTS {
+        let os = *SHARED_STATICS.guest_os[guestid].lock();
+        if os == GuestOs::UNKNOWN && SHARED_STATICS.exit_stats[guestid].total() == 0 {
+            continue;
+        }
+        print!("guest {}: {}", guestid, os);
+        if os.has_quirk(QUIRK_LEGACY_SBI) {
+            print!(", legacy SBI only");
+        }
+        if os.has_quirk(QUIRK_NO_SSTC) {
+            print!(", no Sstc");
+        }
+        if let Some(request) = restart::last_request(guestid as u64) {
+            print!(", last asked for {}", request);
+        }
+        println!("");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_os() {
+        let os = parse_config("linux-5.4.0").unwrap();
+        assert_eq!((os.kind, os.version, os.source), (OsKind::Linux, (5, 4, 0), Source::Config));
+        assert!(os.has_quirk(QUIRK_LEGACY_SBI) && os.has_quirk(QUIRK_NO_SSTC));
+
+        let os = parse_config("Linux-6.1").unwrap();
+        assert_eq!(os.version, (6, 1, 0));
+        assert_eq!(os.quirks, QUIRK_NO_SSTC);
+
+        // Without a version, nothing is known to need working around.
+        assert_eq!(parse_config("freebsd").unwrap().quirks, 0);
+        assert_eq!(parse_config("windows-11"), None);
+    }
+
+    #[test]
+    fn banners() {
+        let os = parse_banner("Linux version 6.6.0-rc1 (builder@host) #1 SMP", Source::Hypercall).unwrap();
+        assert_eq!((os.kind, os.version, os.quirks), (OsKind::Linux, (6, 6, 0), 0));
+
+        let os = parse_banner("FreeBSD 12.4-RELEASE", Source::Hypercall).unwrap();
+        assert_eq!((os.kind, os.version), (OsKind::FreeBsd, (12, 4, 0)));
+        assert!(os.has_quirk(QUIRK_LEGACY_SBI));
+
+        assert_eq!(parse_banner("Linux", Source::Hypercall), None);
+        assert_eq!(parse_banner("Plan9 4", Source::Hypercall), None);
+    }
+
+    #[test]
+    fn display() {
+        let os = parse_config("linux-5.10.2").unwrap();
+        assert_eq!(format!("{}", os), "Linux 5.10.2 (from configuration)");
+        assert_eq!(format!("{}", GuestOs::UNKNOWN), "unknown");
+    }
+}
@@ -0,0 +1,357 @@
+//! Handing a running guest over to another hart of the same machine.
+//!
+//! Guests run one per hart, and a hart without a guest waits in `hart_entry` for one: harts beyond
+//! the number of guests configured, and harts whose guest has moved away. The monitor's `move`
+//! command lists them, and `move <hartid>` hands the guest it is talking to over to one of them.
+//!
+//! Everything the hypervisor keeps for a guest is in the guest's segments of memory, including its
+//! `Context`, shadow page tables and DMA pool, and every hart can reach every segment. So nothing is
+//! copied, and a move costs the guest no more than a short pause. The next time the guest traps
+//! through `trap::strap`, its hart saves what only the hardware holds into the segment: the trap
+//! CSRs, the floating point registers and the values of the hardware performance counters. It then
+//! IPIs the new hart, switches to page tables outside the segment and parks. The new hart waits for
+//! it to be gone before installing the segment's page tables, takes over the guest's host
+//! interrupts and timer, and returns to the guest where it left off.
+//!
+//! This is not migration: the guest stays in the same memory, and only the hart running it changes.
+//! Moving a guest to another segment, or to another machine, would mean copying its memory while
+//! it runs, with dirty.rs, and then its state. That isn't done, since the state held in a segment
+//! includes host physical addresses handed to devices and built into page tables.
+//!
+//! Harts are assumed to be alike, with the same extensions. Guests using the vector unit can't be
+//! moved, since its state isn't saved.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::Ordering;
+use crate::bootstatus::{self, BootStatus};
+use crate::context::{Context, HostIrqChip, CONTEXT};
+use crate::events::{self, EventKind};
+use crate::fdt::{Fdt, MachineMeta};
+use crate::riscv::bits::{IE_SSIE, SSTACK_BASE, STATUS_FS, STATUS_SIE, STATUS_VS};
+use crate::statics::{IpiReason, SHARED_STATICS};
+use crate::{config, hart, pmap, pmu, riscv, shutdown};
+
+const PARK_STACK_SIZE: usize = 4096;
+/// Interrupt sources that may be routed to a hart, as programmed at boot.
+const HOST_IRQ_SOURCES: u32 = 127;
+
+/// A stack for a parked hart to take IPIs on. It lives in the shared statics, since the hart's
+/// segment may be in use by the hart its guest moved to.
+#[repr(C, align(16))]
+pub struct ParkStack(UnsafeCell<[u8; PARK_STACK_SIZE]>);
+
+unsafe impl Sync for ParkStack {}
+
+impl ParkStack {
+    pub const fn new() -> Self {
+        ParkStack(UnsafeCell::new([0; PARK_STACK_SIZE]))
+    }
+
+    fn top(&self) -> u64 {
+        self.0.get() as u64 + PARK_STACK_SIZE as u64
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HandoffError {
+    NoSuchHart,
+    SameHart,
+    /// The hart runs a guest, or hasn't reached the point where it waits for one.
+    HartBusy,
+    AlreadyMoving,
+    VectorState,
+    ShuttingDown,
+}
+
+pub struct Handoff {
+    /// Hartid and index of the hart the guest is moving to.
+    target: Option<(u64, usize)>,
+    /// The hart the guest last moved from.
+    from_hartid: u64,
+    /// Host CSRs as they were when the guest left its last hart.
+    sepc: u64,
+    sstatus: u64,
+    sie: u64,
+    stvec: u64,
+    /// The guest's floating point registers and `fcsr`, if it had them on.
+    fp: [u64; 33],
+}
+
+impl Handoff {
+    pub fn new() -> Self {
+        Self { target: None, from_hartid: 0, sepc: 0, sstatus: 0, sie: 0, stvec: 0, fp: [0; 33] }
+    }
+
+    /// Whether the guest is waiting to be moved, which `trap::strap` does before it next returns.
+    #[inline(always)]
+    pub fn pending(&self) -> bool {
+        self.target.is_some()
+    }
+}
+
+extern {
+    fn hart_entry();
+}
+
+/// Ask for the guest to be moved to hart `hartid`, which is set aside for it straight away.
+pub fn request(state: &mut Context, hartid: u64) -> Result<(), HandoffError> {
+    if shutdown::in_progress() {
+        return Err(HandoffError::ShuttingDown);
+    }
+    if csrr!(sstatus) & STATUS_VS != 0 {
+        return Err(HandoffError::VectorState);
+    }
+    let index = SHARED_STATICS.hart_index(hartid).ok_or(HandoffError::NoSuchHart)?;
+    if index == hart::current().hart_index {
+        return Err(HandoffError::SameHart);
+    }
+    if state.handoff.target.is_some() {
+        return Err(HandoffError::AlreadyMoving);
+    }
+    if SHARED_STATICS.parked_harts.fetch_and(!(1 << index), Ordering::SeqCst) & 1 << index == 0 {
+        return Err(HandoffError::HartBusy);
+    }
+    state.handoff.target = Some((hartid, index));
+    Ok(())
+}
+
+/// List the harts a guest can be moved to, for the monitor's `move` command.
+pub fn print_parked() {
+    let parked = SHARED_STATICS.parked_harts.load(Ordering::SeqCst);
+    if parked == 0 {
+        println!("no harts are free");
+    }
+    for (index, hartid) in SHARED_STATICS.hart_ids.iter().enumerate() {
+        if parked & 1 << index != 0 {
+            println!("hart {} is free", hartid.load(Ordering::SeqCst));
+        }
+    }
+}
+
+/// Hand the guest over to the hart it is moving to, and park this one. Called on the way out of a
+/// trap, with every register of the guest saved and the lock on `CONTEXT` held, which the new hart
+/// takes over. Returns only if the guest can't be moved after all.
+pub fn hand_off(state: &mut Context) {
+    let (hartid, index) = state.handoff.target.take().unwrap();
+    let local = hart::current();
+    let sstatus = csrr!(sstatus);
+    if sstatus & STATUS_VS != 0 {
+        println!("Not moving guest {}, since it has turned on the vector unit", local.guest_index());
+        SHARED_STATICS.parked_harts.fetch_or(1 << index, Ordering::SeqCst);
+        return;
+    }
+
+    let handoff = &mut state.handoff;
+    handoff.from_hartid = local.hartid;
+    handoff.sepc = csrr!(sepc);
+    handoff.sstatus = sstatus;
+    handoff.sie = csrr!(sie);
+    handoff.stvec = csrr!(stvec);
+    if sstatus & STATUS_FS != 0 {
+        unsafe { riscv::save_fp(&mut handoff.fp) };
+    }
+    state.pmu.suspend();
+    state.set_host_timer(u64::max_value());
+
+    let extra = SHARED_STATICS.extra_segments[local.hart_index].swap(0, Ordering::SeqCst);
+    SHARED_STATICS.extra_segments[index].store(extra, Ordering::SeqCst);
+    *SHARED_STATICS.ipi_reason_array[index].lock() = Some(IpiReason::ResumeGuest {
+        from_index: local.hart_index,
+        satp: csrr!(satp),
+    });
+    riscv::sbi::send_ipi_to_hart(hartid);
+
+    // The boot page table of the first hart to enter the supervisor maps everything a parked hart
+    // uses, and nothing writes to it after boot.
+    let boot_page_table = pmap::sa2pa(SHARED_STATICS.boot_page_tables[0].as_ptr() as u64)
+        + local.shared_segments_shift;
+    unsafe {
+        csrw!(sie, 0);
+        leave_segment(local.hart_index, SHARED_STATICS.park_stacks[local.hart_index].top(),
+                      8 << 60 | boot_page_table >> 12)
+    }
+}
+
+/// Stop using the segment: switch to page tables and a stack outside it, and go on to
+/// `handoff_parked`.
+#[naked]
+#[inline(never)]
+unsafe fn leave_segment(_hart_index: usize, _stack_top: u64, _satp: u64) -> ! {
+    asm!("csrw satp, a2
+          sfence.vma
+          mv sp, a1
+          li tp, 0
+          j handoff_parked" :::: "volatile");
+    unreachable!()
+}
+
+#[no_mangle]
+unsafe fn handoff_parked(hart_index: usize) -> ! {
+    // This is what the new hart waits for before it uses the segment.
+    bootstatus::set(hart_index, BootStatus::Parked);
+    wait(hart_index)
+}
+
+/// Go back to waiting for a guest after an IPI that didn't bring one, starting over at the top of
+/// the hart's parking stack.
+pub unsafe fn rest(hart_index: usize) -> ! {
+    rest_on(hart_index, SHARED_STATICS.park_stacks[hart_index].top())
+}
+
+#[naked]
+#[inline(never)]
+unsafe fn rest_on(_hart_index: usize, _stack_top: u64) -> ! {
+    asm!("mv sp, a1
+          j handoff_wait" :::: "volatile");
+    unreachable!()
+}
+
+#[no_mangle]
+unsafe fn handoff_wait(hart_index: usize) -> ! {
+    wait(hart_index)
+}
+
+/// Wait in `hart_entry` for a guest to be moved to this hart.
+pub unsafe fn wait(hart_index: usize) -> ! {
+    SHARED_STATICS.parked_harts.fetch_or(1 << hart_index, Ordering::SeqCst);
+    csrw!(stvec, hart_entry as u64);
+    csrw!(sscratch, SHARED_STATICS.hart_ids[hart_index].load(Ordering::SeqCst));
+    csrw!(sie, IE_SSIE);
+    csrs!(sstatus, STATUS_SIE);
+    loop {
+        riscv::wfi();
+    }
+}
+
+/// Take over the guest of the hart with index `from_index`, once that hart has left its segment.
+/// Called from `hart_entry2` on the parked hart that is to run the guest.
+pub unsafe fn resume(hartid: u64, hart_index: usize, from_index: usize, satp: u64) -> ! {
+    while bootstatus::get(from_index) != BootStatus::Parked {
+        core::sync::atomic::spin_loop_hint();
+    }
+    csrw!(satp, satp);
+    riscv::sfence_vma();
+    enter_segment(hartid, hart_index, SSTACK_BASE)
+}
+
+#[naked]
+#[inline(never)]
+unsafe fn enter_segment(_hartid: u64, _hart_index: usize, _stack: u64) -> ! {
+    asm!("mv sp, a2
+          j handoff_resume" :::: "volatile");
+    unreachable!()
+}
+
+#[no_mangle]
+unsafe fn handoff_resume(hartid: u64, hart_index: usize) -> ! {
+    hart::adopt(hartid, hart_index);
+    // The old hart left holding the lock.
+    CONTEXT.force_unlock();
+    {
+        let mut state = CONTEXT.lock();
+        let state = (&mut *state).as_mut().unwrap();
+        let local = hart::current();
+
+        let handoff = &state.handoff;
+        csrw!(stvec, handoff.stvec);
+        csrw!(sstatus, handoff.sstatus);
+        csrw!(sepc, handoff.sepc);
+        pmu::trap_counter_reads();
+        if state.host_sstc {
+            riscv::sbi::enable_sstc();
+        }
+        if handoff.sstatus & STATUS_FS != 0 {
+            riscv::restore_fp(&handoff.fp);
+        }
+        state.update_host_envcfg();
+
+        let mut fdt = Fdt::new(pmap::pa2va(local.segment_pa + pmap::FDT_OFFSET)).expect("Invalid host device tree");
+        let mut machine = fdt.parse().expect("Invalid host device tree");
+        config::apply(&mut machine);
+        let plic_context = machine.harts.iter().find(|h| h.hartid == hartid).unwrap().plic_context;
+        move_interrupts(state, &machine, local.guest_index(), plic_context);
+
+        let now = state.host_clint.get_mtime();
+        state.pmu.resume(now);
+        state.set_host_timer(state.timers.next_deadline());
+        let from_hartid = state.handoff.from_hartid;
+        events::record(state, EventKind::MovedHart, from_hartid << 32 | hartid);
+        if state.options.loglevel >= 1 {
+            println!("Guest {} moved from hart {} to hart {}", local.guest_index(), from_hartid, hartid);
+        }
+
+        // The guest's code may be stale in this hart's instruction cache.
+        riscv::fence_i();
+        riscv::sbi::clear_ipi();
+        csrw!(sie, state.handoff.sie);
+        bootstatus::set(hart_index, BootStatus::GuestRunning);
+    }
+    return_to_guest()
+}
+
+/// Have host interrupts for the guest delivered to this hart, whose PLIC context or APLIC hart
+/// index is `plic_context`, rather than the one it left.
+fn move_interrupts(state: &mut Context, machine: &MachineMeta, guestid: u64, plic_context: u64) {
+    let mut irqchip = HostIrqChip::new(machine, plic_context);
+
+    // Switching the console focus changes the same enable bits, while holding this lock.
+    let mut console = SHARED_STATICS.console_input.lock();
+    match (&mut irqchip, &mut state.host_irqchip) {
+        (HostIrqChip::Plic { enable, .. }, HostIrqChip::Plic { enable: old, .. }) => {
+            unsafe { *(pmap::pa2va(machine.plic_address + 0x200000 + 0x1000 * plic_context) as *mut u32) = 0 };
+            for word in (0..0x80).step_by(4) {
+                enable[word] = old[word];
+                old[word] = 0;
+            }
+        }
+        (HostIrqChip::Aplic { aplic, hart_index }, HostIrqChip::Aplic { hart_index: old, .. }) => {
+            aplic.init_hart(*hart_index);
+            aplic.retarget(HOST_IRQ_SOURCES, *old, *hart_index);
+        }
+        _ => unreachable!(),
+    }
+    console.register_guest(guestid, plic_context);
+    drop(console);
+    state.host_irqchip = irqchip;
+}
+
+/// Restore the guest's registers from the save area and return to it.
+#[naked]
+#[inline(never)]
+unsafe fn return_to_guest() -> ! {
+    asm!("li sp, $0
+          csrw sscratch, sp
+          ld ra, 1*8(sp)
+          ld gp, 3*8(sp)
+          ld tp, 4*8(sp)
+          ld t0, 5*8(sp)
+          ld t1, 6*8(sp)
+          ld t2, 7*8(sp)
+          ld s0, 8*8(sp)
+          ld s1, 9*8(sp)
+          ld a0, 10*8(sp)
+          ld a1, 11*8(sp)
+          ld a2, 12*8(sp)
+          ld a3, 13*8(sp)
+          ld a4, 14*8(sp)
+          ld a5, 15*8(sp)
+          ld a6, 16*8(sp)
+          ld a7, 17*8(sp)
+          ld s2, 18*8(sp)
+          ld s3, 19*8(sp)
+          ld s4, 20*8(sp)
+          ld s5, 21*8(sp)
+          ld s6, 22*8(sp)
+          ld s7, 23*8(sp)
+          ld s8, 24*8(sp)
+          ld s9, 25*8(sp)
+          ld s10, 26*8(sp)
+          ld s11, 27*8(sp)
+          ld t3, 28*8(sp)
+          ld t4, 29*8(sp)
+          ld t5, 30*8(sp)
+          ld t6, 31*8(sp)
+          ld sp, 2*8(sp)
+          sret" :: "i"(SSTACK_BASE) : "memory" : "volatile");
+    unreachable!()
+}
@@ -0,0 +1,83 @@
+//! Data private to each hart, reached through the `tp` register.
+//!
+//! Every hart keeps a `HartLocal` at the bottom of the stack region of its segment. Once the
+//! hypervisor's page tables are installed, that region is mapped at the same virtual address on
+//! every hart, each to its own memory, so `tp` always holds `HART_LOCAL_VA`. `strap_entry` saves the
+//! guest's `tp` and loads that address on every trap, so code running in the hypervisor can find
+//! its hart's identity without recomputing it from the segment layout.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::pmap;
+
+/// Virtual address of the current hart's `HartLocal`: the lowest address of its stack mapping,
+/// far below anything the stack grows into.
+pub const HART_LOCAL_VA: u64 = 0xffffffffc0800000;
+
+#[repr(C)]
+pub struct HartLocal {
+    pub hartid: u64,
+    /// Position of this hart in per-hart arrays like `SHARED_STATICS.ipi_reason_array`.
+    pub hart_index: usize,
+    /// Guest this hart runs, or None when there is only one guest.
+    pub guestid: Option<u64>,
+    /// Physical address of the segment holding this hart's hypervisor data and guest memory.
+    pub segment_pa: u64,
+    /// Offset of the hypervisor's shared code and data from where they were linked to run.
+    pub shared_segments_shift: u64,
+    /// Number of traps taken from the guest.
+    pub traps: AtomicU64,
+}
+
+impl HartLocal {
+    /// The guestid used to index per-guest state, which is 1 when there is only one guest.
+    pub fn guest_index(&self) -> u64 {
+        self.guestid.unwrap_or(1)
+    }
+
+    pub fn count_trap(&self) {
+        self.traps.store(self.traps.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+}
+
+/// Fill in this hart's `HartLocal` and point `tp` at it. Must be called after `pmap::init` has
+/// installed page tables that map the hart's own stack region.
+pub unsafe fn init(hartid: u64, hart_index: usize, guestid: Option<u64>, segment_pa: u64,
+                   shared_segments_shift: u64) {
+    debug_assert_eq!(pmap::translate_host_address(HART_LOCAL_VA).map(|w| w.pa),
+                     Some(segment_pa + pmap::STACK_OFFSET));
+    core::ptr::write(HART_LOCAL_VA as *mut HartLocal, HartLocal {
+        hartid,
+        hart_index,
+        guestid,
+        segment_pa,
+        shared_segments_shift,
+        traps: AtomicU64::new(0),
+    });
+    asm!("mv tp, $0" :: "r"(HART_LOCAL_VA) :: "volatile");
+}
+
+/// Take over the `HartLocal` of a segment whose guest was just moved to this hart from another,
+/// keeping everything but the hart's identity, and point `tp` at it. Must be called once the
+/// segment's page tables are installed. See handoff.rs.
+pub unsafe fn adopt(hartid: u64, hart_index: usize) {
+    let local = &mut *(HART_LOCAL_VA as *mut HartLocal);
+    local.hartid = hartid;
+    local.hart_index = hart_index;
+    asm!("mv tp, $0" :: "r"(HART_LOCAL_VA) :: "volatile");
+}
+
+/// This hart's data, or None during boot before `init` has run.
+pub fn try_current() -> Option<&'static HartLocal> {
+    let tp: u64;
+    unsafe { asm!("mv $0, tp" : "=r"(tp)) };
+    if tp == HART_LOCAL_VA {
+        Some(unsafe { &*(tp as *const HartLocal) })
+    } else {
+        None
+    }
+}
+
+/// This hart's data. Panics if called before `init`.
+pub fn current() -> &'static HartLocal {
+    try_current().expect("hart-local data used before initialization")
+}
@@ -0,0 +1,81 @@
+//! The Host-Target Interface of the Spike simulator (riscv-isa-sim).
+//!
+//! Spike has no test device, and older versions have no UART either. Instead the simulated program
+//! talks to the simulator through two words of memory, `tohost` and `fromhost`, which Spike finds
+//! by looking up those symbols in the ELF file it loads. A request written to `tohost` names a
+//! device and a command in its top 16 bits, with the rest as the payload. Spike clears `tohost` once
+//! it has taken the request, and answers in `fromhost`, which the program clears after reading.
+//!
+//! The words are defined in mcode.S and placed `constants::TOHOST_OFFSET` bytes into memory by
+//! mlinker.ld. The hypervisor uses them as its console (see print.rs) when the device tree has an
+//! HTIF node and no UART, or always when built with the `htif_console` feature, and to power off
+//! the simulator.
+
+use core::ptr;
+
+const FROMHOST: usize = 8;
+
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+fn request(device: u64, command: u64, payload: u64) -> u64 {
+    device << 56 | command << 48 | payload
+}
+
+/// The console device, which Spike connects to its own terminal.
+#[derive(Copy, Clone, Debug)]
+pub struct HtifConsole {
+    /// Whether a read has been asked for and not answered yet. Spike answers it once a key is
+    /// pressed.
+    read_pending: bool,
+    /// A character that arrived while waiting to write one.
+    input: Option<u8>,
+}
+
+impl HtifConsole {
+    pub const fn new() -> Self {
+        Self { read_pending: false, input: None }
+    }
+
+    pub unsafe fn putchar(&mut self, tohost: *mut u64, ch: u8) {
+        while ptr::read_volatile(tohost) != 0 {
+            self.poll(tohost);
+        }
+        ptr::write_volatile(tohost, request(DEVICE_CONSOLE, CONSOLE_PUTCHAR, ch as u64));
+    }
+
+    pub unsafe fn getchar(&mut self, tohost: *mut u64) -> Option<u8> {
+        self.poll(tohost);
+        if let Some(ch) = self.input.take() {
+            return Some(ch);
+        }
+        if !self.read_pending && ptr::read_volatile(tohost) == 0 {
+            ptr::write_volatile(tohost, request(DEVICE_CONSOLE, CONSOLE_GETCHAR, 0));
+            self.read_pending = true;
+        }
+        None
+    }
+
+    /// Take Spike's answer to an earlier request, if there is one. Only answers to reads carry
+    /// anything.
+    unsafe fn poll(&mut self, tohost: *mut u64) {
+        let fromhost = tohost.add(FROMHOST);
+        let answer = ptr::read_volatile(fromhost);
+        if answer == 0 {
+            return;
+        }
+        ptr::write_volatile(fromhost, 0);
+        if answer >> 48 == request(DEVICE_CONSOLE, CONSOLE_GETCHAR, 0) >> 48 {
+            self.read_pending = false;
+            self.input = Some(answer as u8);
+        }
+    }
+}
+
+/// Stop the simulator, which exits with `code`.
+pub unsafe fn exit(tohost: *mut u64, code: u64) -> ! {
+    while ptr::read_volatile(tohost) != 0 {}
+    ptr::write_volatile(tohost, code << 1 | 1);
+    loop {}
+}
@@ -0,0 +1,93 @@
+//! A read-only page describing the hypervisor to the guest.
+//!
+//! Every guest's device tree has a `/hypervisor` node, compatible with `"rvirt,hypervisor-info"`,
+//! whose `reg` is a page at `INFO_PAGE_BASE`. Tooling in the guest can look for the node to find out
+//! that it runs under RVirt, and read the page to learn what it was given, without making any SBI
+//! calls. The page holds little endian fields at fixed offsets:
+//!
+//!   * 0x00: `INFO_MAGIC`
+//!   * 0x08: `INFO_VERSION`, bumped whenever fields are added
+//!   * 0x10: the guest's id
+//!   * 0x18: bytes of memory assigned to the guest
+//!   * 0x20: number of harts the guest has
+//!   * 0x28: `FEATURE_*` flags
+//!   * 0x40: the RVirt version, as a NUL terminated string
+//!
+//! The page is filled in when the guest starts and mapped into it on the first read, so reading it
+//! costs a single page fault. Writes to it are ignored.
+
+use crate::context::Context;
+use crate::guestos;
+
+/// Guest physical address of the page, in the part of the address space used for emulated devices.
+pub const INFO_PAGE_BASE: u64 = 0x10100000;
+const PAGE_SIZE: u64 = 4096;
+
+/// "RVIRTINF" in ASCII.
+pub const INFO_MAGIC: u64 = 0x464e495452495652;
+pub const INFO_VERSION: u64 = 1;
+
+/// The guest can use the RVirt SBI extension.
+pub const FEATURE_RVIRT_SBI: u64 = 1 << 0;
+/// The guest is the control guest, and may use the calls reserved for it.
+pub const FEATURE_CONTROL_GUEST: u64 = 1 << 1;
+/// The guest's ebreak based semihosting calls are carried out.
+pub const FEATURE_SEMIHOSTING: u64 = 1 << 2;
+/// The hypervisor records cycle histograms that the control guest can read.
+pub const FEATURE_PROFILE: u64 = 1 << 3;
+/// The guest was told about Sstc and can program its timer directly.
+pub const FEATURE_SSTC: u64 = 1 << 4;
+
+const VERSION_OFFSET: usize = 0x40;
+
+/// Whether `guest_pa` is in the info page.
+pub fn is_info_access(guest_pa: u64) -> bool {
+    guest_pa >= INFO_PAGE_BASE && guest_pa < INFO_PAGE_BASE + PAGE_SIZE
+}
+
+/// Fill in the info page of the guest about to start. Without room for it, reading the page faults
+/// like reading any other address without a device.
+pub fn init(state: &mut Context, guestid: u64) {
+    let mut page = match state.dma.alloc(PAGE_SIZE, PAGE_SIZE, "hypervisor info page") {
+        Ok(page) => page,
+        Err(e) => {
+            println!("WARN: No room for the hypervisor info page: {:?}", e);
+            return;
+        }
+    };
+
+    let mut features = FEATURE_RVIRT_SBI;
+    if state.control_guest {
+        features |= FEATURE_CONTROL_GUEST;
+    }
+    if cfg!(feature = "semihosting") {
+        features |= FEATURE_SEMIHOSTING;
+    }
+    if cfg!(feature = "profile") {
+        features |= FEATURE_PROFILE;
+    }
+    if !state.guest_os.has_quirk(guestos::QUIRK_NO_SSTC) {
+        features |= FEATURE_SSTC;
+    }
+
+    let fields = [INFO_MAGIC, INFO_VERSION, guestid, state.guest_memory.len(), 1, features];
+    let bytes = page.as_mut_slice();
+    for b in bytes.iter_mut() {
+        *b = 0;
+    }
+    for (i, field) in fields.iter().enumerate() {
+        bytes[i * 8..][..8].copy_from_slice(&field.to_le_bytes());
+    }
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    bytes[VERSION_OFFSET..][..version.len()].copy_from_slice(version);
+
+    state.info_page = Some(page);
+}
+
+/// The host physical address to map at `guest_pa` if it is in the info page.
+pub fn map_page(state: &Context, guest_pa: u64) -> Option<u64> {
+    if !is_info_access(guest_pa) {
+        return None;
+    }
+    state.info_page.as_ref().map(|page| page.pa())
+}
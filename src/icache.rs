@@ -0,0 +1,34 @@
+//! Keeping the guest's instruction fetches coherent with memory.
+//!
+//! A guest that writes code, whether loading a module or JIT compiling a BPF program, executes
+//! fence.i before running it. Each guest runs on a single hart, so its requests through the SBI
+//! remote fence.i call can only name that hart, and are done locally. The hypervisor itself only
+//! stores code into guest memory when it loads the kernel, and fences before entering it.
+
+use crate::context::Context;
+use crate::pmu::FirmwareEvent;
+use crate::riscv;
+
+pub struct IcacheSync {
+    /// Fences done because the guest asked.
+    guest_fences: u64,
+}
+
+impl IcacheSync {
+    pub const fn new() -> Self {
+        Self { guest_fences: 0 }
+    }
+}
+
+/// Handle the guest's SBI remote fence.i call.
+pub fn remote_fence_i(state: &mut Context) {
+    riscv::fence_i();
+    state.icache.guest_fences += 1;
+    state.pmu.record(FirmwareEvent::FenceISent);
+    state.pmu.record(FirmwareEvent::FenceIReceived);
+}
+
+/// Print how often instruction fetches were fenced, for the monitor's `icache` command.
+pub fn report(state: &Context) {
+    println!("{} fence.i requested by the guest", state.icache.guest_fences);
+}
@@ -0,0 +1,75 @@
+//! Inter-processor interrupts sent by the guest.
+//!
+//! Every guest hart runs on a host hart of its own, which nothing else is scheduled on, so an IPI
+//! never has to wait for its target to be switched in, and the only question is how to get it
+//! there. Guests have a single hart, so the only hart an IPI can name is the one sending it, and
+//! delivering it is just making the guest's supervisor software interrupt pending before returning
+//! to it. That is quicker than going through the CLINT: there is no host IPI to take and no trip
+//! through M-mode.
+//!
+//! IPIs can be sent through the legacy `send_ipi` call, which takes a pointer to a hart mask, and
+//! the `send_ipi` function of the IPI extension, which takes the mask itself. Naming any hart that
+//! doesn't exist fails the call, as the SBI specification asks, without sending anything.
+
+use crate::context::Context;
+use crate::pmu::FirmwareEvent;
+use crate::riscv::bits::IP_SSIP;
+use crate::sbi::{SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS};
+use crate::sum;
+
+/// Whether `hart_mask`, whose bit 0 is the hart with id `hart_mask_base`, names the guest's hart.
+/// A base of all ones names every hart. Fails if the mask names a hart the guest doesn't have.
+fn targets(hart_mask: u64, hart_mask_base: u64) -> Result<bool, ()> {
+    if hart_mask_base == u64::max_value() {
+        return Ok(true);
+    }
+    match (hart_mask_base, hart_mask) {
+        (_, 0) => Ok(false),
+        (0, 1) => Ok(true),
+        _ => Err(()),
+    }
+}
+
+fn deliver(state: &mut Context) {
+    state.csrs.sip |= IP_SSIP;
+    state.no_interrupt = false;
+    state.pmu.record(FirmwareEvent::IpiSent);
+    state.pmu.record(FirmwareEvent::IpiReceived);
+}
+
+/// Handle a call to the IPI extension, returning (error, value).
+pub fn handle_call(state: &mut Context, function: u64) -> (i64, u64) {
+    if function != 0 {
+        return (SBI_ERR_NOT_SUPPORTED, 0);
+    }
+    match targets(state.saved_registers.get(10), state.saved_registers.get(11)) {
+        Ok(true) => {
+            deliver(state);
+            (SBI_SUCCESS, 0)
+        }
+        Ok(false) => (SBI_SUCCESS, 0),
+        Err(()) => (SBI_ERR_INVALID_PARAM, 0),
+    }
+}
+
+/// Handle the legacy `send_ipi` call. A null pointer names every hart.
+pub fn legacy_send(state: &mut Context) {
+    let pointer = state.saved_registers.get(10);
+    let hart_mask = if pointer == 0 {
+        u64::max_value()
+    } else {
+        let mut mask = [0; 8];
+        if sum::copy_from_guest(&mut mask, pointer).is_err() {
+            return;
+        }
+        u64::from_le_bytes(mask)
+    };
+    if hart_mask & 1 != 0 {
+        deliver(state);
+    }
+}
+
+/// Handle the legacy `clear_ipi` call.
+pub fn legacy_clear(state: &mut Context) {
+    state.csrs.sip &= !IP_SSIP;
+}
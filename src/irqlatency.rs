@@ -0,0 +1,201 @@
+//! How long external interrupts take to reach the guest, for tuning interrupt delivery.
+//!
+//! While measuring is on, the hypervisor notes the time at which it claims each host interrupt that
+//! it passes on to the guest, and then how long it took the guest to get through each stage of
+//! handling it:
+//!
+//!   * `Delivered`: the guest took the virtual external interrupt, which can be held back by the
+//!     guest having interrupts disabled as well as by the hypervisor;
+//!   * `Claimed`: the guest claimed the interrupt from its PLIC;
+//!   * `Returned`: the guest's next `sret` after taking it, normally the end of its trap handler;
+//!   * `Acked`: a test guest called the `irq_ack(irq)` function of the RVirt SBI extension, which
+//!     lets it mark any point it likes, such as the wakeup of the thread that handles the device.
+//!
+//! Latencies are in ticks of the `time` counter and go into histograms with power of two buckets,
+//! printed by the monitor's `irqlatency` command. Measuring is off unless turned on by the
+//! `rvirt,irq-latency` property of /chosen or by `irqlatency on`.
+//!
+//! Interrupts that are raised again before the guest takes the first are measured from the first,
+//! and the `Returned` stage can be cut short if the guest takes some other trap while handling the
+//! interrupt, so the numbers are best read from a guest doing nothing but waiting for a device.
+
+use arrayvec::ArrayVec;
+use crate::context::Context;
+use crate::profile::Histogram;
+use crate::sbi::{SBI_ERR_INVALID_PARAM, SBI_SUCCESS};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Delivered,
+    Claimed,
+    Returned,
+    Acked,
+}
+
+const STAGES: [Stage; 4] = [Stage::Delivered, Stage::Claimed, Stage::Returned, Stage::Acked];
+
+/// Interrupts followed at once. Beyond that the oldest is forgotten.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// An interrupt on its way to the guest.
+#[derive(Copy, Clone)]
+struct InFlight {
+    guest_irq: u16,
+    /// Time the hypervisor claimed it from the host PLIC.
+    arrival: u64,
+    /// Stages already measured, one bit each.
+    reached: u8,
+}
+
+impl InFlight {
+    fn reached(&self, stage: Stage) -> bool {
+        self.reached & (1 << stage as u8) != 0
+    }
+}
+
+pub struct IrqLatency {
+    enabled: bool,
+    /// Time of the last host interrupt claimed, which is when any guest interrupt it raises arrived.
+    last_claim: u64,
+    in_flight: ArrayVec<[InFlight; MAX_IN_FLIGHT]>,
+    histograms: [Histogram; 4],
+    /// Interrupts forgotten before the guest returned from handling them.
+    dropped: u64,
+}
+
+impl IrqLatency {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            last_claim: 0,
+            in_flight: ArrayVec::new(),
+            histograms: [Histogram::new(); 4],
+            dropped: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.in_flight.clear();
+    }
+
+    pub fn reset(&mut self) {
+        self.in_flight.clear();
+        self.histograms = [Histogram::new(); 4];
+        self.dropped = 0;
+    }
+
+    fn record(&mut self, index: usize, stage: Stage, now: u64) {
+        let entry = &mut self.in_flight[index];
+        if !entry.reached(stage) {
+            entry.reached |= 1 << stage as u8;
+            self.histograms[stage as usize].record(now.wrapping_sub(entry.arrival));
+        }
+    }
+}
+
+/// Note the time at which a host interrupt was claimed.
+#[inline(always)]
+pub fn host_claimed(state: &mut Context) {
+    if state.irq_latency.enabled {
+        state.irq_latency.last_claim = csrr!(time);
+    }
+}
+
+/// Start following `guest_irq`, which the host interrupt claimed last has just made pending.
+pub fn raised(state: &mut Context, guest_irq: u16) {
+    let latency = &mut state.irq_latency;
+    if !latency.enabled {
+        return;
+    }
+
+    let arrival = latency.last_claim;
+    if let Some(entry) = latency.in_flight.iter_mut().find(|e| e.guest_irq == guest_irq) {
+        if entry.reached(Stage::Delivered) {
+            *entry = InFlight { guest_irq, arrival, reached: 0 };
+        }
+        return;
+    }
+    if latency.in_flight.is_full() {
+        if !latency.in_flight[0].reached(Stage::Returned) {
+            latency.dropped += 1;
+        }
+        latency.in_flight.remove(0);
+    }
+    latency.in_flight.push(InFlight { guest_irq, arrival, reached: 0 });
+}
+
+/// The guest has just taken a virtual external interrupt.
+#[inline(always)]
+pub fn delivered(state: &mut Context) {
+    if state.irq_latency.enabled {
+        let now = csrr!(time);
+        for i in 0..state.irq_latency.in_flight.len() {
+            state.irq_latency.record(i, Stage::Delivered, now);
+        }
+    }
+}
+
+/// The guest has claimed `guest_irq` from its PLIC.
+#[inline(always)]
+pub fn claimed(state: &mut Context, guest_irq: u32) {
+    if state.irq_latency.enabled {
+        reached(state, guest_irq, Stage::Claimed);
+    }
+}
+
+/// The guest has executed `sret`, which finishes handling every interrupt it has taken.
+#[inline(always)]
+pub fn returned(state: &mut Context) {
+    if state.irq_latency.enabled && !state.irq_latency.in_flight.is_empty() {
+        let now = csrr!(time);
+        let latency = &mut state.irq_latency;
+        for i in 0..latency.in_flight.len() {
+            if latency.in_flight[i].reached(Stage::Delivered) {
+                latency.record(i, Stage::Returned, now);
+            }
+        }
+    }
+}
+
+fn reached(state: &mut Context, guest_irq: u32, stage: Stage) -> bool {
+    let now = csrr!(time);
+    let latency = &mut state.irq_latency;
+    match latency.in_flight.iter().position(|e| e.guest_irq as u32 == guest_irq) {
+        Some(index) => {
+            latency.record(index, stage, now);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Handle the `irq_ack` function of the RVirt SBI extension. Fails if `irq` isn't being followed,
+/// including when measuring is off.
+pub fn handle_ack(state: &mut Context) -> (i64, u64) {
+    let guest_irq = state.saved_registers.get(10);
+    if state.irq_latency.enabled && guest_irq <= u32::max_value() as u64
+        && reached(state, guest_irq as u32, Stage::Acked) {
+        (SBI_SUCCESS, 0)
+    } else {
+        (SBI_ERR_INVALID_PARAM, 0)
+    }
+}
+
+/// Print the histograms, for the monitor's `irqlatency` command.
+pub fn report(state: &Context) {
+    let latency = &state.irq_latency;
+    if !latency.enabled {
+        println!("not measuring, use `irqlatency on` to start");
+    }
+    if latency.dropped > 0 {
+        println!("{} interrupts forgotten before the guest handled them", latency.dropped);
+    }
+    for &stage in &STAGES {
+        let histogram = &latency.histograms[stage as usize];
+        if histogram.samples() > 0 {
+            println!("{:?}:", stage);
+            histogram.print("ticks");
+        }
+    }
+}
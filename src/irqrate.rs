@@ -0,0 +1,156 @@
+//! Limits on how often a host interrupt source can interrupt a guest's hart.
+//!
+//! A device that interrupts faster than its guest can keep up with, whether because it is broken or
+//! because the guest is making it, keeps the hart in the hypervisor's interrupt path and starves
+//! the guest, and with it anything else the hart does for the rest of the system. So every source
+//! mapped to the guest is counted in windows of `WINDOW` ticks. One that goes over the limit in a
+//! window is masked at the host interrupt controller for `MITIGATION_PERIOD`, during which its
+//! device is polled every `POLL_INTERVAL` instead, and then unmasked again. Each time a source is
+//! masked or unmasked a line is printed on the console.
+//!
+//! The limit is set in interrupts per second by the `rvirt,irq-limit` property of the host's
+//! /chosen, where zero turns mitigation off.
+
+use arrayvec::ArrayVec;
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::{Context, IrqMapping};
+use crate::timer::TimerEvent;
+use crate::trap;
+
+/// Interrupts per second a source may raise when `rvirt,irq-limit` isn't given.
+pub const DEFAULT_LIMIT: u32 = 50_000;
+
+const WINDOW: u64 = TIMER_FREQUENCY / 100;
+const MITIGATION_PERIOD: u64 = TIMER_FREQUENCY / 10;
+const POLL_INTERVAL: u64 = TIMER_FREQUENCY / 1000;
+
+/// Most sources tracked for one guest: its virtio devices and the console.
+const MAX_SOURCES: usize = 8;
+
+struct Source {
+    irq: u32,
+    window_start: u64,
+    window_count: u64,
+    /// When the source is due to be unmasked, if it is masked.
+    masked_until: Option<u64>,
+    total: u64,
+    polls: u64,
+    mitigations: u64,
+}
+
+pub struct IrqRates {
+    /// Interrupts allowed in each window, or zero if unlimited.
+    window_limit: u64,
+    sources: ArrayVec<[Source; MAX_SOURCES]>,
+}
+
+impl IrqRates {
+    pub fn new(limit_per_sec: u32) -> Self {
+        let window_limit = match limit_per_sec {
+            0 => 0,
+            limit => (limit as u64 * WINDOW / TIMER_FREQUENCY).max(1),
+        };
+        Self { window_limit, sources: ArrayVec::new() }
+    }
+
+    fn source(&mut self, irq: u32) -> Option<&mut Source> {
+        if let Some(index) = self.sources.iter().position(|s| s.irq == irq) {
+            return Some(&mut self.sources[index]);
+        }
+        let source = Source {
+            irq,
+            window_start: 0,
+            window_count: 0,
+            masked_until: None,
+            total: 0,
+            polls: 0,
+            mitigations: 0,
+        };
+        self.sources.try_push(source).ok()?;
+        self.sources.last_mut()
+    }
+}
+
+/// Count an interrupt from host source `irq`, masking the source if it has gone over the limit.
+pub fn account(state: &mut Context, irq: u32, now: u64) {
+    if irq == 0 || state.irq_map[irq as usize] == IrqMapping::Ignored {
+        return;
+    }
+    let limit = state.irq_rates.window_limit;
+    let source = match state.irq_rates.source(irq) {
+        Some(source) => source,
+        None => return,
+    };
+    source.total += 1;
+    if now.wrapping_sub(source.window_start) >= WINDOW {
+        source.window_start = now;
+        source.window_count = 0;
+    }
+    source.window_count += 1;
+    if limit == 0 || source.window_count <= limit || source.masked_until.is_some() {
+        return;
+    }
+
+    source.masked_until = Some(now + MITIGATION_PERIOD);
+    source.mitigations += 1;
+    println!("Host interrupt {} raised more than {} interrupts in {}us, polling it for {}ms",
+             irq, limit, WINDOW * 1_000_000 / TIMER_FREQUENCY, MITIGATION_PERIOD * 1000 / TIMER_FREQUENCY);
+    state.host_irqchip.disable(irq);
+    if state.timers.deadline(TimerEvent::IrqPoll).is_none() {
+        state.schedule_timer(TimerEvent::IrqPoll, now + POLL_INTERVAL);
+    }
+}
+
+/// Poll the devices of masked sources, and unmask the ones whose time is up. Called from the timer.
+pub fn poll(state: &mut Context, now: u64) {
+    let mut masked = ArrayVec::<[(u32, bool); MAX_SOURCES]>::new();
+    for source in state.irq_rates.sources.iter_mut() {
+        if let Some(until) = source.masked_until {
+            source.polls += 1;
+            let expired = until <= now;
+            if expired {
+                source.masked_until = None;
+                source.window_start = now;
+                source.window_count = 0;
+            }
+            masked.push((source.irq, expired));
+        }
+    }
+
+    for &(irq, expired) in &masked {
+        let pending = match state.irq_map[irq as usize] {
+            IrqMapping::Virtio { device_index, .. } => state.virtio.devices[device_index as usize].interrupt_pending(),
+            IrqMapping::Console => true,
+            IrqMapping::Ignored => false,
+        };
+        if pending {
+            trap::deliver_host_irq(state, irq);
+        }
+        if expired {
+            println!("Host interrupt {} unmasked", irq);
+            state.host_irqchip.enable(irq);
+        }
+    }
+
+    if masked.iter().any(|&(_, expired)| !expired) {
+        state.schedule_timer(TimerEvent::IrqPoll, now + POLL_INTERVAL);
+    }
+}
+
+/// Print how much each source has interrupted, for the monitor's `irqrate` command.
+pub fn report(state: &Context) {
+    match state.irq_rates.window_limit {
+        0 => println!("no limit"),
+        limit => println!("limit {} per second", limit * TIMER_FREQUENCY / WINDOW),
+    }
+    let now = state.host_clint.get_mtime();
+    for source in &state.irq_rates.sources {
+        print!("irq {:>3}: {} interrupts, masked {} times, {} polls", source.irq, source.total,
+               source.mitigations, source.polls);
+        match source.masked_until {
+            Some(until) => println!(" (masked for another {}us)",
+                                    until.saturating_sub(now) * 1_000_000 / TIMER_FREQUENCY),
+            None => println!(""),
+        }
+    }
+}
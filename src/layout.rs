@@ -0,0 +1,71 @@
+//! Where things are in host physical memory.
+//!
+//! None of this assumes QEMU's virt machine. The start and size of memory and the addresses of
+//! devices come from the host device tree, and where the hypervisor itself is comes from the address
+//! it was linked at plus the shift it was loaded with (`shared_segments_shift`). Every hart works out
+//! the layout after parsing the device tree, and passes it to the code that places things in memory
+//! or maps them.
+
+use crate::constants::{HYPERVISOR_LINK_PA, LOG_TAIL_OFFSET, SHARED_DMA_POOL_OFFSET};
+use crate::fdt::MachineMeta;
+use crate::pmap::{DIRECT_MAP_PAGES, HART_SEGMENT_SIZE};
+
+#[derive(Clone, Debug)]
+pub struct MachineLayout {
+    /// Start and size of the range of host memory that the hypervisor and its guests live in. Memory
+    /// is handed out in segments, so the start is aligned to one.
+    pub memory_base: u64,
+    pub memory_size: u64,
+    /// Physical address of the hypervisor's code, which its shared data follows.
+    pub hypervisor_base: u64,
+    /// Physical address of the DMA pool for host devices that the hypervisor keeps for itself (see
+    /// overlay.rs).
+    pub shared_dma_pool: u64,
+    /// Physical address of the copy of recent console output (see logtail.rs).
+    pub log_tail: u64,
+    /// Bitmap of the gigabytes of physical address space that hold host devices, which have to stay
+    /// mapped through the direct map while guests run.
+    pub device_gigabytes: u64,
+}
+
+impl MachineLayout {
+    pub fn new(machine: &MachineMeta, shared_segments_shift: u64) -> Self {
+        let memory_base = machine.physical_memory_offset;
+        assert_eq!(memory_base % HART_SEGMENT_SIZE, 0, "Host memory must start on a 1GB boundary");
+
+        let devices = [Some(machine.plic_address), machine.clint_address, Some(machine.uart_address),
+                       machine.test_finisher_address, machine.rtc_address];
+        let mut device_gigabytes = 0;
+        for address in devices.iter().flatten().chain(machine.virtio.iter().map(|d| &d.base_address)) {
+            if address >> 30 < DIRECT_MAP_PAGES {
+                device_gigabytes |= 1u64 << (address >> 30);
+            } else {
+                println!("WARN: Device at {:#x} is beyond the direct map and can't be used", address);
+            }
+        }
+
+        Self {
+            memory_base,
+            memory_size: machine.physical_memory_size,
+            hypervisor_base: HYPERVISOR_LINK_PA + shared_segments_shift,
+            shared_dma_pool: memory_base + SHARED_DMA_POOL_OFFSET,
+            log_tail: memory_base + LOG_TAIL_OFFSET,
+            device_gigabytes,
+        }
+    }
+
+    /// Number of whole segments of memory, including the first, which holds the hypervisor.
+    pub fn segments(&self) -> u64 {
+        (self.memory_size / HART_SEGMENT_SIZE).min(64)
+    }
+
+    /// Physical address of segment `i`.
+    pub fn segment_base(&self, i: u64) -> u64 {
+        self.memory_base + HART_SEGMENT_SIZE * i
+    }
+
+    /// Index of the segment holding `pa`.
+    pub fn segment_index(&self, pa: u64) -> u64 {
+        (pa - self.memory_base) / HART_SEGMENT_SIZE
+    }
+}
@@ -12,6 +12,9 @@
 //! ## Physical memory layout according to machine-mode
 //!   (see also linker.ld, pmap.rs, qemu riscv/virt.c @ 4717595)
 //!   note: although only 36 bits are described here, the address space is wider.
+//!   note: these are the addresses on QEMU's virt machine. Only the start of memory is fixed (in
+//!   mlinker.ld); everything else is placed relative to the memory and devices in the device tree,
+//!   as worked out in layout.rs.
 //! ```text
 //!  START      - END         REGION
 //!  0x        0 - 0x      100  QEMU VIRT_DEBUG
@@ -41,6 +44,7 @@
 //!  0x 80830000 - 0x 80840000  hart 3 M-mode stack
 //!  0x 808xxxxx - 0x 808xxxxx  ...
 //!  0x 808f0000 - 0x 80900000  hart 15 M-mode stack
+//!  0x 80900000 - 0x 80a00000  S-mode boot stacks, 64KB for each boot slot (see scode.S)
 //!  0x c0000000 - 0x c0200000  hart 1 stack
 //!  0x c0200000 - 0x c0400000  hart 1 data segment
 //!  0x c0400000 - 0x c4000000  hart 1 heap
@@ -61,12 +65,29 @@
 //! ## Initial supervisor virtual memory layout (boot page table)
 //!    note: the Sv39 addressing mode is in use here
 //! ```text
-//!  VIRTUAL START      - VIRTUAL END          PHYS START   PHYS END     MODE   REGION
-//!  0x        00000000 - 0x        40000000   0x00000000 - 0x40000000   RWX    QEMU memory sections
-//!  0x        80000000 - 0x        c0000000   0x80000000 - 0xC0000000   RWX    hypervisor memory
-//!  0xffffffffc0000000 - 0xffffffffffffffff   0x80000000 - 0xC0000000   RWX    hypervisor memory
+//!  VIRTUAL START      - VIRTUAL END          PHYS START   PHYS END      MODE   REGION
+//!  0xfffffffc00000000 - 0xfffffffe00000000   0x00000000 - 0x200000000   RW     direct map
+//!  0xffffffffc0000000 - 0xffffffffc0200000   0x80000000 - 0x80200000    RX     hypervisor code
+//!  0xffffffffc0200000 - 0xffffffffffffffff   0x80200000 - 0xC0000000    RW     hypervisor data
 //! ```
 //!
+//! Nothing is mapped below the top 16GB, not even the code that switches to this page table: its
+//! next instruction fetch faults, and the trap handler it set up beforehand continues at the
+//! high address.
+//!
+//! ## Supervisor virtual memory layout while running a guest (see pmap::init)
+//! ```text
+//!  VIRTUAL START      - VIRTUAL END          REGION
+//!  0x0000000000000000 - 0xfffffffc00000000   guest mappings only (shadow page tables)
+//!  0xfffffffc00000000 - 0xfffffffe00000000   direct map (devices, first GB of memory, own segment)
+//!  0xfffffffe00000000 - 0xffffffff00000000   guest memory window (one GB per segment)
+//!  0xffffffffc0000000 - 0xffffffffffffffff   hypervisor code, shared data, data and stack
+//! ```
+//!
+//! Every mapping below the hypervisor's region belongs to the guest and is a user mapping, so the
+//! guest is free to lay out its address space however it likes. When the hypervisor has to reach
+//! guest memory through the guest's own addresses, it does so with SUM set (see sum.rs).
+//!
 //! ## Linux address space layout (with Sv39 addressing)
 //!
 //! In this addressing mode, Linux does not reserve any address space for a hypervisor. However, the
@@ -81,7 +102,7 @@
 //!  0xffffffdfffffffff - 0xffffffffffffffff   Direct map region
 //! ```
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(asm)]
 #![feature(const_fn)]
 #![feature(const_raw_ptr_deref)]
@@ -94,29 +115,159 @@
 #![feature(start)]
 #![feature(try_blocks)]
 
+// The guest's floating point and vector registers stay in the hardware across traps instead of
+// being saved and restored: each hart only ever runs one guest, and the hypervisor doesn't touch
+// them. That only holds as long as the compiler can't use them either.
+#[cfg(any(target_feature = "f", target_feature = "d", target_feature = "v"))]
+compile_error!("rvirt must be built for a target without floating point or vector registers");
+
 #[macro_use]
 pub mod riscv;
+#[cfg(target_arch = "riscv64")]
 #[macro_use]
 pub mod print;
 
+// Without a console, whatever the modules built for the host print is dropped.
+#[cfg(not(target_arch = "riscv64"))]
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ({ let _ = format_args!($($arg)*); });
+}
+#[cfg(not(target_arch = "riscv64"))]
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => ({ let _ = format_args!($($arg)*); });
+}
+
+// Only the modules that don't touch the hardware are built for other targets, so that their unit
+// tests can run on the build machine with `cargo test --lib`.
+#[cfg(target_arch = "riscv64")]
+pub mod aia;
+#[cfg(target_arch = "riscv64")]
 pub mod backtrace;
+pub mod block;
+#[cfg(target_arch = "riscv64")]
+pub mod bootstatus;
+#[cfg(target_arch = "riscv64")]
+pub mod boottime;
+#[cfg(target_arch = "riscv64")]
+pub mod config;
+#[cfg(target_arch = "riscv64")]
+pub mod console;
 pub mod constants;
+#[cfg(target_arch = "riscv64")]
 pub mod context;
+#[cfg(target_arch = "riscv64")]
+pub mod coredump;
+pub mod cowbitmap;
+#[cfg(target_arch = "riscv64")]
+pub mod debuglog;
+#[cfg(target_arch = "riscv64")]
+pub mod deferred;
+#[cfg(target_arch = "riscv64")]
+pub mod delegaudit;
+#[cfg(target_arch = "riscv64")]
+pub mod dirty;
+#[cfg(target_arch = "riscv64")]
+pub mod dispatch;
+#[cfg(target_arch = "riscv64")]
+pub mod dma;
 pub mod drivers;
 pub mod elf;
+#[cfg(target_arch = "riscv64")]
+pub mod emulate;
+#[cfg(target_arch = "riscv64")]
+pub mod entropy;
+pub mod error;
+#[cfg(target_arch = "riscv64")]
+pub mod events;
+#[cfg(target_arch = "riscv64")]
+pub mod exits;
 pub mod fdt;
+pub mod guestos;
+#[cfg(target_arch = "riscv64")]
+pub mod handoff;
+#[cfg(target_arch = "riscv64")]
+pub mod hart;
+pub mod htif;
+#[cfg(target_arch = "riscv64")]
+pub mod hvinfo;
+#[cfg(target_arch = "riscv64")]
+pub mod icache;
+#[cfg(target_arch = "riscv64")]
+pub mod ipi;
+#[cfg(target_arch = "riscv64")]
+pub mod irqlatency;
+#[cfg(target_arch = "riscv64")]
+pub mod irqrate;
+pub mod layout;
+#[cfg(target_arch = "riscv64")]
+pub mod logtail;
 pub mod memory_region;
+#[cfg(target_arch = "riscv64")]
+pub mod memusage;
+#[cfg(target_arch = "riscv64")]
+pub mod mmio;
+#[cfg(target_arch = "riscv64")]
+pub mod monitor;
+pub mod options;
+#[cfg(target_arch = "riscv64")]
+pub mod overlay;
+#[cfg(target_arch = "riscv64")]
 pub mod pfault;
+#[cfg(target_arch = "riscv64")]
 pub mod plic;
 pub mod pmap;
+#[cfg(target_arch = "riscv64")]
+pub mod pmu;
+#[cfg(target_arch = "riscv64")]
+pub mod profile;
+pub mod ptsync;
+#[cfg(target_arch = "riscv64")]
+pub mod ptverify;
+pub mod qcow2;
+#[cfg(target_arch = "riscv64")]
+pub mod report;
+#[cfg(target_arch = "riscv64")]
+pub mod restart;
+#[cfg(target_arch = "riscv64")]
+pub mod rtc;
+pub mod satp;
+#[cfg(target_arch = "riscv64")]
+pub mod sbi;
+#[cfg(target_arch = "riscv64")]
+pub mod semihosting;
+#[cfg(target_arch = "riscv64")]
+pub mod shutdown;
+#[cfg(target_arch = "riscv64")]
+pub mod spinlock;
+#[cfg(target_arch = "riscv64")]
 pub mod statics;
+#[cfg(target_arch = "riscv64")]
+pub mod steal;
+#[cfg(target_arch = "riscv64")]
 pub mod sum;
+#[cfg(target_arch = "riscv64")]
+pub mod symbols;
+#[cfg(target_arch = "riscv64")]
+pub mod testdev;
+pub mod throttle;
+pub mod timer;
+#[cfg(target_arch = "riscv64")]
 pub mod trap;
+#[cfg(target_arch = "riscv64")]
+pub mod vcsr;
+#[cfg(target_arch = "riscv64")]
 pub mod virtio;
+pub mod virtqueue;
+#[cfg(target_arch = "riscv64")]
+pub mod watch;
 
 pub use core::sync::atomic::{AtomicBool, Ordering};
 pub use constants::SYMBOL_PA2VA_OFFSET;
 pub use fdt::*;
 pub use riscv::bits::*;
+#[cfg(target_arch = "riscv64")]
 pub use pmap::{pa2va};
+#[cfg(target_arch = "riscv64")]
 pub use statics::{__SHARED_STATICS_IMPL, IpiReason, SHARED_STATICS};
@@ -0,0 +1,94 @@
+//! A copy of the most recent console output, kept in memory where it outlives the UART.
+//!
+//! With `rvirt,log-tail = <KB>` in /chosen, everything the hypervisor prints and every line of guest
+//! console output is also written to a ring of that many KB (up to 64 less a header) at
+//! `LOG_TAIL_OFFSET` into host memory. When the machine stops before the UART has sent everything,
+//! or the console isn't being captured, the tail of the log can still be read from memory with a
+//! debugger, a JTAG probe, or QEMU's `pmemsave`.
+//!
+//! Memory usually keeps its contents across a reset that doesn't cut power, such as QEMU's
+//! `system_reset` or a watchdog, so at boot a ring left by the previous run is printed before
+//! anything is written over it.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::constants::LOG_TAIL_SIZE;
+use crate::pmap;
+use crate::statics::SHARED_STATICS;
+
+/// "rlogtail", marking an area that holds a ring.
+const MAGIC: u64 = 0x6c69_6174_676f_6c72;
+/// Size of the header, which the ring follows.
+const HEADER_SIZE: u64 = 64;
+
+#[repr(C)]
+struct Header {
+    magic: AtomicU64,
+    /// Bytes in the ring.
+    size: AtomicU64,
+    /// Bytes ever written, of which the last `size` are in the ring.
+    written: AtomicU64,
+}
+
+/// Where the area at `pa` can be reached in the current address space.
+fn address(pa: u64) -> u64 {
+    if cfg!(feature = "physical_symbol_addresses") {
+        pa
+    } else {
+        pmap::pa2va(pa)
+    }
+}
+
+/// Print any ring left in the area at `pa` by the previous run, and then start a new one of `kb` KB
+/// unless it is zero. Must be called by the boot hart before any other hart prints.
+pub unsafe fn init(pa: u64, kb: u32) {
+    let base = address(pa);
+    let header = &*(base as *const Header);
+    if header.magic.load(Ordering::Relaxed) == MAGIC {
+        print_previous(base, header);
+        header.magic.store(0, Ordering::Relaxed);
+    }
+    if kb == 0 {
+        return;
+    }
+
+    header.size.store((kb as u64 * 1024).min(LOG_TAIL_SIZE - HEADER_SIZE), Ordering::Relaxed);
+    header.written.store(0, Ordering::Relaxed);
+    header.magic.store(MAGIC, Ordering::Relaxed);
+    SHARED_STATICS.log_tail.store(pa, Ordering::SeqCst);
+}
+
+unsafe fn print_previous(base: u64, header: &Header) {
+    let size = header.size.load(Ordering::Relaxed);
+    let written = header.written.load(Ordering::Relaxed);
+    if size == 0 || size > LOG_TAIL_SIZE - HEADER_SIZE {
+        return;
+    }
+
+    println!("Console output from before the last reset:");
+    let data = (base + HEADER_SIZE) as *const u8;
+    let mut writer = SHARED_STATICS.uart_writer.lock();
+    for i in written.saturating_sub(size)..written {
+        writer.putchar(ptr::read_volatile(data.add((i % size) as usize)));
+    }
+    drop(writer);
+    println!("\nEnd of console output from before the last reset");
+}
+
+/// Copy `bytes` into the ring, if there is one. Harts writing at once each get their own part of
+/// it, even if their output was interleaved on the UART.
+pub fn write(bytes: &[u8]) {
+    let pa = SHARED_STATICS.log_tail.load(Ordering::Relaxed);
+    if pa == 0 || bytes.is_empty() {
+        return;
+    }
+
+    let base = address(pa);
+    let header = unsafe { &*(base as *const Header) };
+    let size = header.size.load(Ordering::Relaxed);
+    let start = header.written.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    let data = (base + HEADER_SIZE) as *mut u8;
+    for (i, &byte) in bytes.iter().enumerate() {
+        unsafe { ptr::write_volatile(data.add(((start + i as u64) % size) as usize), byte) }
+    }
+}
@@ -5,17 +5,24 @@
 #![feature(naked_functions)]
 #![feature(start)]
 
+use core::sync::atomic::AtomicU64;
 use rvirt::*;
+use rvirt::constants::MAX_HOST_HARTS;
 
 // mandatory rust environment setup
 #[lang = "eh_personality"] extern fn eh_personality() {}
-#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! { println!("{}", info); loop {}}
+#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! { print::print_panic(info); loop {}}
 #[start] fn start(_argc: isize, _argv: *const *const u8) -> isize {0}
 #[no_mangle] fn abort() -> ! { println!("Abort!"); loop {}}
 
-const M_MODE_STACK_BASE: u64 = 0x80810000;
+/// Size of each hart's M-mode stack. The stacks start at `_m_mode_stacks`, from mlinker.ld.
 const M_MODE_STACK_STRIDE: u64 = 0x10000;
 
+/// Number of harts that have claimed an M-mode stack. Stacks are handed out in the order harts
+/// arrive rather than by hartid, since hartids may be sparse.
+#[no_mangle]
+static M_MODE_STACK_SLOTS: AtomicU64 = AtomicU64::new(0);
+
 #[link_section = ".payload"]
 static PAYLOAD: [u8; include_bytes!(concat!("../", env!("PAYLOAD"))).len()] =
     *include_bytes!(concat!("../", env!("PAYLOAD")));
@@ -26,32 +33,61 @@ global_asm!(include_str!("mcode.S"));
 #[no_mangle]
 #[link_section = ".text.entrypoint"]
 unsafe fn _start(hartid: u64, device_tree_blob: u64) {
-    asm!("li sp, $0
+    // Harts without supervisor mode (like the E51 monitor core of the FU540) trap on the write to
+    // stvec and are parked, as are harts arriving once all M-mode stacks are taken. This has to
+    // happen before a stack is claimed so that parked harts don't use one up.
+    asm!("lla t0, 3f
+          csrw mtvec, t0
+          csrw stvec, zero
+
+          lla t0, M_MODE_STACK_SLOTS
+          li t1, 1
+          amoadd.d t2, t1, (t0)
           li t1, $1
-          mul t0, a0, t1
-          add sp, sp, t0" :: "i"(M_MODE_STACK_BASE), "i"(M_MODE_STACK_STRIDE) :: "volatile");
+          bltu t2, t1, 2f
 
-    // Simple trick to loop forever if this hart does not support supervisor mode.
-    csrw!(mtvec, 0x80000000);
-    csrw!(stvec, 0);
+          .align 2
+      3:  wfi
+          j 3b
+
+      2:  lla sp, _m_mode_stacks
+          addi t2, t2, 1
+          li t1, $0
+          mul t0, t2, t1
+          add sp, sp, t0
+          csrw mscratch, sp"
+         :: "i"(M_MODE_STACK_STRIDE), "i"(MAX_HOST_HARTS) : "t0", "t1", "t2" : "volatile");
 
     mstart(hartid, device_tree_blob);
 }
 
 #[inline(never)]
 unsafe fn mstart(hartid: u64, device_tree_blob: u64) {
-    csrs!(mideleg, 0x0222);
-    csrs!(medeleg, 0xb1ff);
+    csrs!(mideleg, constants::DELEGATED_INTERRUPTS);
+    csrs!(medeleg, constants::DELEGATED_EXCEPTIONS);
     csrw!(mie, 0x088);
     csrc!(mstatus, STATUS_MPP_M);
     csrs!(mstatus, STATUS_MPP_S);
     csrw!(mepc, PAYLOAD.as_ptr() as u64);
     csrw!(mcounteren, 0xffffffff);
-    csrw!(mscratch, M_MODE_STACK_BASE + M_MODE_STACK_STRIDE * hartid);
     csrw!(pmpaddr0, 0xffffffffffffffff);
     csrw!(pmpcfg0, csrr!(pmpcfg0) | 0x1f);
     csrw!(satp, 0);
 
+    // Let the supervisor hand cache block operations down to U-mode (with invalidation performed as
+    // a flush) if the hart implements Zicbom or Zicboz. Bits for missing extensions are WARL and
+    // stay clear. Harts without menvcfg trap on the access, and the handler installed here just
+    // skips over it. STCE is left clear: the supervisor only programs stimecmp if the device tree
+    // lists Sstc, and asks for it then (see `riscv::sbi::enable_sstc`), since otherwise mip.STIP
+    // would be read-only and timer interrupts couldn't be forwarded.
+    asm!("lla t0, 1f
+          csrrw t0, mtvec, t0
+          li t1, 0xd0
+          csrs $0, t1
+          .align 2
+      1:  csrw mtvec, t0"
+         :: "i"(riscv::csr::menvcfg) : "t0", "t1" : "volatile");
+
     asm!("lla t0, mtrap_entry
           csrw mtvec, t0"
          ::: "t0" : "volatile");
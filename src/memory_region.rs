@@ -116,6 +116,7 @@ impl<T: Copy> IndexMut<u64> for MemoryRegion<T> {
 /// addresses* to simplify usage.
 pub struct PageTableRegion {
     region: MemoryRegion,
+    start_pa: u64,
     end_pa: u64,
 }
 impl PageTableRegion {
@@ -123,10 +124,12 @@ impl PageTableRegion {
         assert_eq!((region.ptr as u64) % 4096, 0);
         assert_eq!(region.length_bytes % 4096, 0);
 
-        let end_pa = pmap::va2pa(region.ptr as u64) + region.length_bytes;
+        let start_pa = region.base_address;
+        let end_pa = start_pa + region.length_bytes;
 
         Self {
             region,
+            start_pa,
             end_pa,
         }
     }
@@ -155,10 +158,11 @@ impl PageTableRegion {
     // Returns a conservative answer of whether the pte could map some memory that overlapped this
     // region.
     fn inside_region(&self, pte: u64) -> bool {
-        // since we don't know page size (and because we know all mappings will point to physical
-        // addresses larger than the end of this region) we only check that the start of the page is
-        // beyond the end of this region.
-        ((pte >> 10) << 12) < self.end_pa
+        // since we don't know page size we rely on the fact that huge page mappings only ever point
+        // to guest memory, which is located after this region. Other mappings (like the hypervisor
+        // info page, which comes from the DMA pool) are 4KB and may also be located before it.
+        let pa = (pte & pmap::PTE_PPN_MASK) << 2;
+        pa >= self.start_pa && pa < self.end_pa
     }
 }
 
@@ -0,0 +1,80 @@
+//! How much host memory each guest is using, and what happens when there isn't enough.
+//!
+//! Every guest has whole segments of host memory set aside for it when it starts, and on top of
+//! that the hart uses memory for the guest's shadow page tables. Each guest's hart publishes these
+//! numbers to `SHARED_STATICS.memory_usage` whenever the guest goes idle, so that the monitor's
+//! `memory` command can show every guest.
+//!
+//! Running out of segments at boot isn't fatal. Every guest that can be started is given its first
+//! segment before any guest gets more, and guests left without one aren't started. If the rest
+//! can't cover every guest that wants more than one, they are refused a segment at a time to the
+//! guest that would otherwise have the most (see `pmap::share_segments`). The console says which
+//! guests weren't started or got less than they asked for, and the `memory` command shows how much
+//! each was refused.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::context::Context;
+use crate::hart;
+use crate::report::Record;
+use crate::statics::SHARED_STATICS;
+
+const PAGE_SIZE: u64 = 4096;
+
+pub struct MemoryUsage {
+    /// Bytes of host memory set aside for the guest, and bytes it asked for but was refused.
+    reserved: AtomicU64,
+    refused: AtomicU64,
+    /// Pages of the hart's shadow page table region in use.
+    page_table_pages: AtomicU64,
+}
+
+impl MemoryUsage {
+    pub const fn new() -> Self {
+        Self {
+            reserved: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            page_table_pages: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Record how much host memory was set aside for `guestid` when it was started, and how much more
+/// it would have had if there had been enough.
+pub fn set_reserved(guestid: u64, bytes: u64, refused: u64) {
+    let usage = &SHARED_STATICS.memory_usage[guestid as usize];
+    usage.reserved.store(bytes, Ordering::Relaxed);
+    usage.refused.store(refused, Ordering::Relaxed);
+}
+
+/// Publish this hart's guest's memory usage. Only the hart running the guest calls this.
+pub fn publish(state: &Context) {
+    let usage = &SHARED_STATICS.memory_usage[hart::current().guest_index() as usize];
+    let tables = &state.shadow_page_tables.stats;
+    usage.page_table_pages.store(tables.total_pages - tables.free_pages, Ordering::Relaxed);
+}
+
+/// Finish a record of the `report` command with the memory usage of `guestid`, in bytes.
+pub fn add_to_report(guestid: u64, record: &mut Record) {
+    let usage = &SHARED_STATICS.memory_usage[guestid as usize];
+    record.number("reserved", usage.reserved.load(Ordering::Relaxed))
+        .number("refused", usage.refused.load(Ordering::Relaxed))
+        .number("page_tables", usage.page_table_pages.load(Ordering::Relaxed) * PAGE_SIZE)
+        .end();
+}
+
+/// Print every guest's memory usage, for the monitor's `memory` command.
+pub fn report() {
+    let mb = |bytes: u64| bytes >> 20;
+    for (guestid, usage) in SHARED_STATICS.memory_usage.iter().enumerate() {
+        let reserved = usage.reserved.load(Ordering::Relaxed);
+        if reserved == 0 {
+            continue;
+        }
+        let refused = usage.refused.load(Ordering::Relaxed);
+        print!("guest {}: {} MB set aside", guestid, mb(reserved));
+        if refused != 0 {
+            print!(" ({} MB refused)", mb(refused));
+        }
+        println!(", {} KB of shadow page tables", usage.page_table_pages.load(Ordering::Relaxed) * PAGE_SIZE >> 10);
+    }
+}
@@ -0,0 +1,169 @@
+//! Write combining for emulated device regions that take bulk writes.
+//!
+//! Guest accesses to emulated devices normally trap, one exit per load or store (see pfault.rs).
+//! That is fine for registers, but not for something like a framebuffer, where the guest writes
+//! long runs of memory and only the result matters. A device can instead register part of its
+//! region as write combined. The first access to each page of it maps a page of host memory into
+//! the guest in its place, so that further stores run at full speed, and the hypervisor finds out
+//! what changed by comparing that memory against a copy taken at the last flush. Changes are passed
+//! to the device in runs of contiguous bytes:
+//!
+//!   * every `FLUSH_INTERVAL` while any page is mapped, and
+//!   * before any trapped access to an emulated device, so that a register write such as a
+//!     doorbell is never seen before the data written ahead of it.
+//!
+//! Which mode applies is up to each device: regions it hasn't registered keep trapping.
+//!
+//! Windows are write-only as far as the device is concerned. The memory behind them starts out
+//! zeroed and the device has no way to change it, so loads only ever return what the guest itself
+//! stored. That suits output such as a framebuffer or a log (see debuglog.rs), but not registers the
+//! guest reads back.
+
+use arrayvec::ArrayVec;
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::Context;
+use crate::dma::DmaBuffer;
+use crate::error::{Error, Result};
+use crate::timer::TimerEvent;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_WINDOWS: usize = 4;
+
+/// How often changes to mapped pages are passed on (10ms).
+const FLUSH_INTERVAL: u64 = TIMER_FREQUENCY / 100;
+
+/// Bytes of changes passed to a device in a single call.
+const MAX_RUN: usize = 256;
+
+/// Called with the offset into the window of a run of changed bytes, and their new values.
+pub type FlushFn = fn(&mut Context, u64, &[u8]);
+
+struct Window {
+    guest_pa: u64,
+    len: u64,
+    /// Memory the guest's accesses go to, and its contents as of the last flush.
+    backing: DmaBuffer,
+    snapshot: DmaBuffer,
+    flush: FlushFn,
+    /// Pages of the window mapped into the guest, one bit each.
+    mapped: u64,
+}
+
+pub struct WriteCombining {
+    windows: ArrayVec<[Window; MAX_WINDOWS]>,
+    pub pages_mapped: u64,
+    pub flushes: u64,
+    pub bytes_flushed: u64,
+}
+
+impl WriteCombining {
+    pub fn new() -> Self {
+        Self { windows: ArrayVec::new(), pages_mapped: 0, flushes: 0, bytes_flushed: 0 }
+    }
+
+    fn mapped(&self) -> bool {
+        self.windows.iter().any(|w| w.mapped != 0)
+    }
+}
+
+/// Register `len` bytes of guest physical memory at `guest_pa`, which must be page aligned and
+/// belong to an emulated device, as write combined. At most 64 pages. The window is write-only:
+/// `flush` is told about stores, and loads see the guest's own stores.
+pub fn register(state: &mut Context, guest_pa: u64, len: u64, flush: FlushFn) -> Result<()> {
+    assert_eq!(guest_pa % PAGE_SIZE, 0);
+    let len = (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    if len == 0 || len > 64 * PAGE_SIZE || state.write_combining.windows.is_full() {
+        return Err(Error::OutOfMemory);
+    }
+
+    let backing = state.dma.alloc(len, PAGE_SIZE, "mmio write combining")?;
+    let snapshot = match state.dma.alloc(len, 8, "mmio write combining") {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            state.dma.free(backing);
+            return Err(e);
+        }
+    };
+    state.write_combining.windows.push(Window { guest_pa, len, backing, snapshot, flush, mapped: 0 });
+    Ok(())
+}
+
+/// If `guest_pa` is in a write combined window, return the host physical address of the page that
+/// should be mapped in its place, and start the periodic flush.
+pub fn map_page(state: &mut Context, guest_pa: u64) -> Option<u64> {
+    let window = state.write_combining.windows.iter_mut()
+        .find(|w| guest_pa >= w.guest_pa && guest_pa < w.guest_pa + w.len)?;
+    let page = (guest_pa - window.guest_pa) / PAGE_SIZE;
+    window.mapped |= 1 << page;
+    let host_pa = window.backing.pa() + page * PAGE_SIZE;
+    state.write_combining.pages_mapped += 1;
+
+    if state.timers.deadline(TimerEvent::MmioFlush).is_none() {
+        let deadline = state.host_clint.get_mtime() + FLUSH_INTERVAL;
+        state.schedule_timer(TimerEvent::MmioFlush, deadline);
+    }
+    Some(host_pa)
+}
+
+/// Pass every change to mapped pages on to the devices they belong to.
+pub fn flush(state: &mut Context) {
+    for i in 0..state.write_combining.windows.len() {
+        let mut offset = 0;
+        while let Some((start, run)) = next_run(&mut state.write_combining.windows[i], &mut offset) {
+            let flush = state.write_combining.windows[i].flush;
+            state.write_combining.flushes += 1;
+            state.write_combining.bytes_flushed += run.len() as u64;
+            flush(state, start, &run);
+        }
+    }
+}
+
+/// Find the next run of changed bytes at or after `offset` in the window's mapped pages, copy it
+/// out and update the snapshot.
+fn next_run(window: &mut Window, offset: &mut u64) -> Option<(u64, ArrayVec<[u8; MAX_RUN]>)> {
+    let mapped = window.mapped;
+    let backing = window.backing.as_slice();
+    let snapshot = window.snapshot.as_mut_slice();
+
+    let is_mapped = |offset: u64| mapped & (1 << (offset / PAGE_SIZE)) != 0;
+    let mut start = *offset as usize;
+    while start < window.len as usize && (!is_mapped(start as u64) || backing[start] == snapshot[start]) {
+        start += 1;
+    }
+    if start == window.len as usize {
+        return None;
+    }
+
+    let mut run = ArrayVec::new();
+    let mut end = start;
+    while end < window.len as usize && !run.is_full() && is_mapped(end as u64) && backing[end] != snapshot[end] {
+        run.push(backing[end]);
+        snapshot[end] = backing[end];
+        end += 1;
+    }
+    *offset = end as u64;
+    Some((start as u64, run))
+}
+
+/// Handle the periodic flush timer, rearming it while any page is still mapped.
+pub fn flush_tick(state: &mut Context, time: u64) {
+    flush(state);
+    if state.write_combining.mapped() {
+        state.schedule_timer(TimerEvent::MmioFlush, time + FLUSH_INTERVAL);
+    }
+}
+
+/// Print how write combining has been used, for the monitor's `mmio` command.
+pub fn report(state: &Context) {
+    let wc = &state.write_combining;
+    if wc.windows.is_empty() {
+        println!("no write combined regions");
+        return;
+    }
+    for window in &wc.windows {
+        println!("{:#x}-{:#x}: {} pages mapped", window.guest_pa, window.guest_pa + window.len,
+                 window.mapped.count_ones());
+    }
+    println!("{} pages mapped in total, {} bytes passed on in {} runs",
+             wc.pages_mapped, wc.bytes_flushed, wc.flushes);
+}
@@ -0,0 +1,616 @@
+//! Hypervisor monitor console.
+//!
+//! Pressing Ctrl-] on the console switches input from the guest to the monitor. Characters typed
+//! while in monitor mode are buffered into a command line which is then executed on the hart that
+//! received it. Pressing Ctrl-] again returns input to the guest.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::Ordering;
+use crate::constants::MAX_GUESTS;
+use crate::context::Context;
+use crate::drivers::vsock::VsockDriver;
+use crate::exits::ExitCounters;
+use crate::statics::SHARED_STATICS;
+use crate::riscv::bits::{SATP_MODE, SATP_PPN};
+use crate::satp::SatpMode;
+use crate::vcsr;
+use crate::{backtrace, boottime, config, dirty, dispatch, events, guestos, handoff, hart, icache, irqlatency, irqrate,
+            memusage, mmio, options, overlay, pmap, ptsync, ptverify, report, shutdown, trap, virtio, watch};
+
+const ESCAPE: u8 = 0x1d; // Ctrl-]
+const BACKSPACE: u8 = 0x7f;
+
+/// Largest range the `x`, `xp` and `search` commands will look at.
+const MAX_DUMP_LEN: u64 = 4096;
+const MAX_SEARCH_LEN: u64 = 64 << 20;
+const MAX_SEARCH_MATCHES: usize = 16;
+
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+pub type Line = ArrayVec<[u8; 64]>;
+
+pub struct Console {
+    active: bool,
+    line: Line,
+    pending: Option<Line>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            active: false,
+            line: ArrayVec::new(),
+            pending: None,
+        }
+    }
+
+    /// Process a character received from the host UART. Returns true if the character was consumed
+    /// by the monitor and should not be forwarded to the guest.
+    pub fn intercept(&mut self, ch: u8) -> bool {
+        if ch == ESCAPE {
+            self.active = !self.active;
+            self.line.clear();
+            if self.active {
+                print!("\n(rvirt) ");
+            } else {
+                println!("");
+            }
+            return true;
+        }
+        if !self.active {
+            return false;
+        }
+
+        let mut writer = SHARED_STATICS.uart_writer.lock();
+        match ch {
+            b'\r' | b'\n' => {
+                writer.putchar(b'\n');
+                if self.pending.is_none() {
+                    self.pending = Some(self.line.clone());
+                }
+                self.line.clear();
+            }
+            BACKSPACE | 0x08 => {
+                if self.line.pop().is_some() {
+                    writer.putchar(0x08);
+                    writer.putchar(b' ');
+                    writer.putchar(0x08);
+                }
+            }
+            ch if ch >= 0x20 && ch < 0x7f => {
+                if self.line.try_push(ch).is_ok() {
+                    writer.putchar(ch);
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Take the most recently completed command line, if any.
+    pub fn take_command(&mut self) -> Option<Line> {
+        self.pending.take()
+    }
+}
+
+/// Run any command that was entered since the last call.
+pub fn poll(state: &mut Context) {
+    if let Some(line) = state.uart.monitor.take_command() {
+        match core::str::from_utf8(&line) {
+            Ok(line) => execute(state, line.trim()),
+            Err(_) => println!("invalid input"),
+        }
+        if state.uart.monitor.active {
+            print!("(rvirt) ");
+        }
+    }
+}
+
+fn execute(state: &mut Context, line: &str) {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return,
+    };
+
+    match command {
+        "help" => {
+            println!("help                 show this message");
+            println!("attach [n]           list host virtio devices, or give device n to this guest");
+            println!("bt                   show the guest's call stack");
+            println!("config               show the settings on the config disk");
+            println!("config set <name> [value]");
+            println!("                     change a setting, given as numbers or as text");
+            println!("config unset <name>  remove a setting");
+            println!("dumpregs             show the guest's registers");
+            println!("csrlog               show the guest's recent writes to satp, stvec, sstatus and sie");
+            println!("dma                  list buffers allocated from the DMA pool");
+            println!("exits [guest|reset]  show why guests trapped into the hypervisor");
+            println!("events               show the log of guest lifecycle events");
+            println!("focus [guest]        show or change which guest receives console input");
+            println!("list                 show what operating system each guest runs");
+            println!("iostat               show I/O counters and limits for each device");
+            println!("memory               show how much host memory each guest is using");
+            println!("irqrate              show how often each host interrupt source has fired");
+            println!("irqlatency [reset]   show or clear how long interrupts take to reach the guest");
+            println!("irqlatency on|off    start or stop measuring it");
+            println!("iolimit <dev> <requests/s> <bytes/s>");
+            println!("                     limit a device's I/O rate (0 for no limit)");
+            println!("vsock                list vsock connections");
+            println!("vsock connect <port> connect to a port in the guest");
+            println!("vsock send <n> <text>");
+            println!("                     send a line of text on connection n");
+            println!("vsock close <n>      reset connection n");
+            println!("gva2gpa <va>         translate a guest virtual address");
+            println!("gpa2hpa <pa>         show where a guest physical address is stored");
+            println!("x <va> [len]         hexdump guest virtual memory");
+            println!("xp <pa> [len]        hexdump guest physical memory");
+            println!("search <pa> <len> <text | 0xhex>");
+            println!("                     find a byte pattern in guest physical memory");
+            println!("ptcheck [repair]     verify shadow page tables against guest page tables");
+            println!("ptcheck every <n>    verify after every n page faults (debug builds only)");
+            println!("ptmem                show how much shadow page table memory is in use");
+            println!("ptsync [mode]        show how guest page table changes were synced, or switch");
+            println!("                     to mode flush, lazy or trap");
+            println!("shutdown [seconds]   ask every guest to power off, then power off the machine");
+            println!("timers               list pending timer events on this hart");
+            println!("icache               show how often instruction fetches were fenced");
+            println!("boottime [guest]     show when a guest reached each milestone of its boot");
+            println!("mmio                 show write combined device regions");
+            println!("fastsbi [on | off]   show or switch the fast path for common SBI calls");
+            println!("report               dump the state of every guest as JSON, one object per line");
+            println!("handlers             list what handles each kind of trap from the guest");
+            println!("trapmode [mode]      show how traps enter the hypervisor, or switch to mode");
+            println!("                     direct or vectored");
+            println!("profile [reset]      show or clear cycle histograms (profile builds only)");
+            println!("profile start|stop   resume or pause recording them");
+            println!("dirty [on|off|clear] show how quickly the guest dirties memory, start or stop");
+            println!("                     tracking it, or clear the dirty pages to start a new round");
+            println!("watch                list write watches and the stores they caught");
+            println!("watch <pa> <len>     log stores to a range of guest physical memory");
+            println!("unwatch <n>          remove write watch n");
+            println!("move [hartid]        list free harts, or hand this guest over to one");
+            println!("trace [target,...]   show or change what is traced for this guest, out of exceptions,");
+            println!("                     interrupts, sbi, csr and plic, or all or none of them");
+        }
+        "attach" => match words.next().map(|w| w.parse::<usize>()) {
+            None => {
+                for (i, device) in state.virtio.host_devices.iter().enumerate() {
+                    match SHARED_STATICS.virtio_owners[i].load(Ordering::SeqCst) {
+                        0 => println!("{:>2} {:#x} free", i, device.base_address),
+                        overlay::HYPERVISOR_OWNER => println!("{:>2} {:#x} shared disks", i, device.base_address),
+                        owner => println!("{:>2} {:#x} guest {}", i, device.base_address, owner),
+                    }
+                }
+            }
+            Some(Ok(index)) => match virtio::hot_add(state, index) {
+                Ok(slot) => println!("added as {:x}.virtio_mmio", 0x10001000 + 0x1000 * slot),
+                Err(reason) => println!("can't add device {}: {}", index, reason),
+            },
+            Some(Err(_)) => println!("usage: attach [n]"),
+        },
+        "bt" => backtrace::print_guest_backtrace(state, csrr!(sepc)),
+        "config" => config_command(line),
+        "dma" => state.dma.report(),
+        "dumpregs" => dump_registers(state),
+        "csrlog" => vcsr::dump_history(state),
+        "events" => events::print_log(),
+        "exits" => exits_command(words.next()),
+        "focus" => {
+            let mut input = SHARED_STATICS.console_input.lock();
+            match words.next().map(|w| w.parse()) {
+                None => println!("console input goes to guest {}", input.focus()),
+                Some(Ok(guestid)) if input.set_focus(guestid) => {}
+                Some(_) => println!("no such guest"),
+            }
+        }
+        "list" => guestos::print_guests(),
+        "gva2gpa" => match words.next().and_then(parse_number) {
+            Some(va) => match guest_translate(state, va) {
+                Some(pa) => println!("{:#x} -> {:#x}", va, pa),
+                None => println!("{:#x} is not mapped", va),
+            },
+            None => println!("usage: gva2gpa <va>"),
+        },
+        "gpa2hpa" => match words.next().and_then(parse_number) {
+            Some(pa) if state.guest_memory.in_region(pa) => {
+                if let Some(host_pa) = state.guest_map.host_pa(pa) {
+                    println!("{:#x} -> {:#x}", pa, host_pa);
+                }
+            }
+            Some(pa) => println!("{:#x} is not guest memory", pa),
+            None => println!("usage: gpa2hpa <pa>"),
+        },
+        "x" | "xp" => {
+            let (address, len) = (words.next().and_then(parse_number), words.next().map(parse_number));
+            match (address, len.unwrap_or(Some(64))) {
+                (Some(address), Some(len)) => hexdump(state, address, len.min(MAX_DUMP_LEN), command == "x"),
+                _ => println!("usage: {} <address> [len]", command),
+            }
+        }
+        "search" => {
+            let args = (words.next().and_then(parse_number), words.next().and_then(parse_number), words.next());
+            match args {
+                (Some(start), Some(len), Some(pattern)) => search(state, start, len, pattern),
+                _ => println!("usage: search <pa> <len> <text | 0xhex>"),
+            }
+        }
+        "iostat" => {
+            for (i, device) in state.virtio.devices.iter_mut().enumerate() {
+                if let Some(t) = device.throttle() {
+                    let (requests_per_sec, bytes_per_sec) = t.limits();
+                    println!("virtio{}: {} requests, {} bytes, {} delayed (limits {}/s, {} bytes/s)",
+                             i, t.requests_completed, t.bytes_completed, t.notifications_delayed,
+                             requests_per_sec, bytes_per_sec);
+                }
+            }
+        }
+        "irqrate" => irqrate::report(state),
+        "irqlatency" => match words.next() {
+            None => irqlatency::report(state),
+            Some("on") => state.irq_latency.set_enabled(true),
+            Some("off") => state.irq_latency.set_enabled(false),
+            Some("reset") => state.irq_latency.reset(),
+            Some(_) => println!("usage: irqlatency [on | off | reset]"),
+        },
+        "boottime" => match words.next().map(|w| w.parse::<u64>()) {
+            None => boottime::report(hart::current().guest_index()),
+            Some(Ok(guestid)) => boottime::report(guestid),
+            Some(Err(_)) => println!("usage: boottime [guest]"),
+        },
+        "memory" => {
+            memusage::publish(state);
+            memusage::report();
+        }
+        "iolimit" => {
+            let args = (words.next().and_then(|w| w.parse::<usize>().ok()),
+                        words.next().and_then(|w| w.parse().ok()),
+                        words.next().and_then(|w| w.parse().ok()));
+            match args {
+                (Some(dev), Some(requests_per_sec), Some(bytes_per_sec)) => {
+                    match state.virtio.devices.get_mut(dev).and_then(virtio::Device::throttle) {
+                        Some(t) => t.set_limits(requests_per_sec, bytes_per_sec),
+                        None => println!("no passthrough device virtio{}", dev),
+                    }
+                }
+                _ => println!("usage: iolimit <dev> <requests/s> <bytes/s>"),
+            }
+        }
+        "ptcheck" => match (words.next(), words.next()) {
+            (None, _) => { ptverify::verify(state, false); }
+            (Some("repair"), _) => { ptverify::verify(state, true); }
+            (Some("every"), Some(n)) => match n.parse() {
+                Ok(n) if cfg!(debug_assertions) => state.verify_interval = n,
+                Ok(_) => println!("periodic verification requires a debug build"),
+                Err(_) => println!("invalid interval '{}'", n),
+            },
+            _ => println!("usage: ptcheck [repair | every <n>]"),
+        },
+        "ptmem" => pmap::print_page_table_stats(&state.shadow_page_tables),
+        "ptsync" => match words.next() {
+            None => ptsync::report(state),
+            Some(name) => match ptsync::SyncMode::parse(name) {
+                Some(mode) => ptsync::set_mode(state, mode),
+                None => println!("usage: ptsync [flush | lazy | trap]"),
+            },
+        },
+        "profile" => match words.next() {
+            _ if !cfg!(feature = "profile") => println!("profiling requires building with RVIRT_PROFILE=1"),
+            None => state.profile.report(),
+            Some("reset") => state.profile.reset(),
+            Some("start") => state.profile.set_enabled(true),
+            Some("stop") => state.profile.set_enabled(false),
+            Some(_) => println!("usage: profile [reset | start | stop]"),
+        },
+        "shutdown" => match words.next().map(|w| w.parse::<u64>()) {
+            None => shutdown::request(state, shutdown::DEFAULT_TIMEOUT_SECS),
+            Some(Ok(seconds)) => shutdown::request(state, seconds),
+            Some(Err(_)) => println!("usage: shutdown [seconds]"),
+        },
+        "trapmode" => match words.next() {
+            None => println!("{:?}", trap::TrapVectoring::current()),
+            Some(name) => match trap::TrapVectoring::parse(name) {
+                Some(mode) => println!("now {:?}", trap::set_trap_vectoring(mode)),
+                None => println!("usage: trapmode [direct | vectored]"),
+            },
+        },
+        "fastsbi" => match words.next() {
+            None => println!("{}", if state.fast_sbi { "on" } else { "off" }),
+            Some("on") => state.fast_sbi = true,
+            Some("off") => state.fast_sbi = false,
+            Some(_) => println!("usage: fastsbi [on | off]"),
+        },
+        "icache" => icache::report(state),
+        "mmio" => mmio::report(state),
+        "handlers" => dispatch::report(state),
+        "report" => report::print(state),
+        "timers" => {
+            let now = state.host_clint.get_mtime();
+            for &(deadline, event) in state.timers.iter() {
+                println!("{:?}: in {} ticks", event, deadline.saturating_sub(now));
+            }
+        }
+        "vsock" => vsock_command(state, line),
+        "dirty" => match words.next() {
+            None => dirty::report(state),
+            Some("on") => dirty::enable(state),
+            Some("off") => dirty::disable(state),
+            Some("clear") if state.dirty.enabled() => {
+                let mut bitmap = [0; 64];
+                let (mut first, mut pages) = (0, 0);
+                loop {
+                    let count = dirty::fetch_and_clear(state, first, &mut bitmap);
+                    if count == 0 {
+                        break;
+                    }
+                    pages += bitmap[..count].iter().map(|w| w.count_ones()).sum::<u32>();
+                    first += count;
+                }
+                println!("{} pages were dirty", pages);
+            }
+            Some("clear") => println!("not tracking, use `dirty on` to start"),
+            Some(_) => println!("usage: dirty [on | off | clear]"),
+        },
+        "watch" => match (words.next().map(parse_number), words.next().map(parse_number)) {
+            (None, _) => watch::report(state),
+            (Some(Some(pa)), Some(Some(len))) => match watch::add(state, pa, len) {
+                Ok(index) => println!("watch {} set", index),
+                Err(e) => println!("can't watch {:#x}: {:?}", pa, e),
+            },
+            _ => println!("usage: watch [<pa> <len>]"),
+        },
+        "unwatch" => match words.next().and_then(|w| w.parse::<usize>().ok()) {
+            Some(index) if watch::remove(state, index) => {}
+            Some(index) => println!("no watch {}", index),
+            None => println!("usage: unwatch <n>"),
+        },
+        "move" => match words.next().map(|w| w.parse::<u64>().ok()) {
+            None => handoff::print_parked(),
+            Some(Some(hartid)) => match handoff::request(state, hartid) {
+                Ok(()) => println!("moving guest to hart {}", hartid),
+                Err(e) => println!("can't move guest to hart {}: {:?}", hartid, e),
+            },
+            Some(None) => println!("usage: move [hartid]"),
+        },
+        "trace" => match words.next().map(options::parse_targets) {
+            None => options::print_targets(state.options.trace),
+            Some(Some(targets)) => state.options.trace = targets,
+            Some(None) => println!("usage: trace [exceptions,interrupts,sbi,csr,plic | all | none]"),
+        },
+        _ => println!("unknown command '{}' (try 'help')", command),
+    }
+}
+
+/// Print the guest's program counter, general purpose registers and supervisor CSRs. Values that
+/// fall within a function of the guest kernel are shown with its name.
+pub fn dump_registers(state: &Context) {
+    let symbols = &state.symbols;
+    println!("pc      = {}", symbols.symbolize(csrr!(sepc)));
+    println!("ra      = {}", symbols.symbolize(state.saved_registers.get(1)));
+    for i in 2..32 {
+        println!("{:<7} = {:#x}", REGISTER_NAMES[i], state.saved_registers.get(i as u32));
+    }
+    println!("sstatus = {:#x}", state.csrs.sstatus);
+    println!("stvec   = {}", symbols.symbolize(state.csrs.stvec));
+    println!("sepc    = {}", symbols.symbolize(state.csrs.sepc));
+    println!("scause  = {:#x}", state.csrs.scause);
+    println!("stval   = {:#x}", state.csrs.stval);
+    println!("satp    = {:#x} ({:?})", state.csrs.satp, SatpMode::from_satp(state.csrs.satp));
+    state.dump_privilege_history();
+}
+
+fn exits_command(arg: Option<&str>) {
+    let stats = &SHARED_STATICS.exit_stats;
+    match arg {
+        None => {
+            for (guestid, counters) in stats.iter().enumerate().filter(|(_, c)| c.total() > 0) {
+                counters.report(guestid);
+            }
+        }
+        Some("reset") => stats.iter().for_each(ExitCounters::reset),
+        Some(guest) => match guest.parse::<usize>() {
+            Ok(guestid) if guestid < MAX_GUESTS => stats[guestid].report(guestid),
+            _ => println!("usage: exits [guest|reset]"),
+        },
+    }
+}
+
+/// Show or change the settings on the config disk. A value made up of numbers is stored as cells,
+/// and anything else as a string.
+fn config_command(line: &str) {
+    let mut words = line.splitn(4, ' ').skip(1);
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => config::show(),
+        (Some("set"), Some(name), value) => {
+            let value = value.unwrap_or("").trim();
+            let is_cell = |w: &str| parse_number(w).map_or(false, |n| n <= u32::max_value() as u64);
+            let mut bytes = ArrayVec::<[u8; 128]>::new();
+            if value.split_whitespace().all(is_cell) {
+                for n in value.split_whitespace().filter_map(parse_number) {
+                    bytes.extend((n as u32).to_be_bytes().iter().cloned());
+                }
+            } else {
+                bytes.extend(value.bytes().chain(Some(0)));
+            }
+            config::set(name, Some(&bytes));
+        }
+        (Some("unset"), Some(name), None) => config::set(name, None),
+        _ => println!("usage: config [set <name> [value] | unset <name>]"),
+    }
+}
+
+/// Parse a number, which is hexadecimal if it starts with `0x` and decimal otherwise.
+fn parse_number(word: &str) -> Option<u64> {
+    if word.starts_with("0x") {
+        u64::from_str_radix(&word[2..], 16).ok()
+    } else {
+        word.parse().ok()
+    }
+}
+
+/// Translate a guest virtual address using the guest's current page tables. Addresses are
+/// unchanged while the guest has paging turned off.
+fn guest_translate(state: &Context, va: u64) -> Option<u64> {
+    if state.csrs.satp & SATP_MODE == 0 {
+        return Some(va);
+    }
+    let root = (state.csrs.satp & SATP_PPN) << 12;
+    pmap::translate_guest_address(&state.guest_memory, root, va).map(|t| t.guest_pa)
+}
+
+/// Print guest memory as hex and ASCII, 16 bytes to a line. With `is_virtual` set, `address` is
+/// translated through the guest's page tables one page at a time.
+fn hexdump(state: &mut Context, address: u64, len: u64, is_virtual: bool) {
+    let mut line = ArrayVec::<[u8; 16]>::new();
+    let mut line_start = address;
+    let mut offset = 0;
+    while offset < len {
+        let current = address.wrapping_add(offset);
+        let pa = if is_virtual { guest_translate(state, current) } else { Some(current) };
+        let bytes = match pa.and_then(|pa| state.read_guest_page(pa, len - offset)) {
+            Some(bytes) => bytes,
+            None => {
+                println!("{:#x} is not accessible", current);
+                break;
+            }
+        };
+        for &b in bytes {
+            line.push(b);
+            if line.is_full() {
+                print_hex_line(line_start, &line);
+                line_start += 16;
+                line.clear();
+            }
+        }
+        offset += bytes.len() as u64;
+    }
+    if !line.is_empty() {
+        print_hex_line(line_start, &line);
+    }
+}
+
+fn print_hex_line(address: u64, bytes: &[u8]) {
+    print!("{:016x}: ", address);
+    for i in 0..16 {
+        match bytes.get(i) {
+            Some(b) => print!("{:02x} ", b),
+            None => print!("   "),
+        }
+    }
+    for &b in bytes {
+        print!("{}", if b >= 0x20 && b < 0x7f { b as char } else { '.' });
+    }
+    println!("");
+}
+
+/// Print the guest physical addresses at which `pattern` occurs within a range. Patterns starting
+/// with `0x` are hex strings, anything else is searched for as text.
+fn search(state: &mut Context, start: u64, len: u64, pattern: &str) {
+    let mut needle = ArrayVec::<[u8; 32]>::new();
+    if pattern.starts_with("0x") {
+        let hex = &pattern[2..];
+        if hex.is_empty() || hex.len() % 2 != 0 || hex.len() / 2 > needle.capacity() {
+            println!("hex patterns need an even number of digits, at most 64");
+            return;
+        }
+        for i in (0..hex.len()).step_by(2) {
+            match u8::from_str_radix(&hex[i..(i + 2)], 16) {
+                Ok(b) => needle.push(b),
+                Err(_) => {
+                    println!("invalid hex pattern '{}'", pattern);
+                    return;
+                }
+            }
+        }
+    } else {
+        for &b in pattern.as_bytes().iter().take(needle.capacity()) {
+            needle.push(b);
+        }
+    }
+
+    let end = start.saturating_add(len.min(MAX_SEARCH_LEN));
+    let mut matches = 0;
+    // Each page is searched together with the last few bytes of the one before it, so that
+    // matches spanning pages are found.
+    let mut window = [0u8; 4096 + 32];
+    let mut window_len = 0;
+    let mut pa = start;
+    while pa < end {
+        let bytes = match state.read_guest_page(pa, end - pa) {
+            Some(bytes) => bytes,
+            None => {
+                println!("{:#x} is not guest memory", pa);
+                break;
+            }
+        };
+        let window_start = pa - window_len as u64;
+        window[window_len..][..bytes.len()].copy_from_slice(bytes);
+        window_len += bytes.len();
+        pa += bytes.len() as u64;
+
+        for (i, candidate) in window[..window_len].windows(needle.len()).enumerate() {
+            if candidate == &needle[..] {
+                println!("{:#x}", window_start + i as u64);
+                matches += 1;
+                if matches == MAX_SEARCH_MATCHES {
+                    println!("(stopping after {} matches)", MAX_SEARCH_MATCHES);
+                    return;
+                }
+            }
+        }
+
+        let keep = (needle.len() - 1).min(window_len);
+        window.copy_within(window_len - keep..window_len, 0);
+        window_len = keep;
+    }
+    if matches == 0 {
+        println!("not found");
+    }
+}
+
+fn vsock_command(state: &mut Context, line: &str) {
+    let device = state.virtio.devices.iter_mut().filter_map(|d| match d {
+        virtio::Device::Vsock(device, _) => Some(device),
+        _ => None,
+    }).next();
+    let device = match device {
+        Some(device) => device,
+        None => {
+            println!("no vsock device (set rvirt,vsock in /chosen)");
+            return;
+        }
+    };
+
+    let mut words = line.splitn(4, ' ').skip(1);
+    let driver = device.host_driver();
+    let ok = match (words.next(), words.next().and_then(|w| w.parse().ok()), words.next()) {
+        (None, _, _) => {
+            println!("guest cid {}", driver.guest_cid());
+            for (i, c) in driver.connections().iter().enumerate() {
+                println!("{}: host port {} <-> guest port {}{}", i, c.host_port, c.guest_port,
+                         if c.established { "" } else { " (connecting)" });
+            }
+            true
+        }
+        (Some("connect"), Some(port), None) => driver.connect(port),
+        (Some("close"), Some(n), None) => driver.close(n as usize),
+        (Some("send"), Some(n), Some(text)) => {
+            let mut data: ArrayVec<[u8; 64]> = text.bytes().take(63).collect();
+            data.push(b'\n');
+            driver.send(n as usize, &data)
+        }
+        _ => {
+            println!("usage: vsock [connect <port> | send <n> <text> | close <n>]");
+            true
+        }
+    };
+    if !ok {
+        println!("vsock: request failed");
+    }
+
+    VsockDriver::flush(device, &mut state.guest_memory);
+    virtio::update_emulated_interrupts(state);
+}
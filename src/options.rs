@@ -0,0 +1,172 @@
+//! Switches for debugging the hypervisor, given on its own command line.
+//!
+//! Words of the host's /chosen bootargs, which QEMU sets with `-append`, that start with `rvirt.`
+//! are meant for the hypervisor rather than for guests. They are taken out of the command line
+//! that guests are given and collected in `Options`, so that trying a different setting doesn't
+//! mean rebuilding the hypervisor or its device tree:
+//!
+//!   * `rvirt.loglevel=<n>`: at 0, messages about guests asking to be reset, being restarted and
+//!     moving between harts are left out. 1, the default, prints them, and 2 also shows where each
+//!     guest was placed at boot.
+//!   * `rvirt.trace=<target>[,<target>...]`: print a line for every event of the given kinds, out
+//!     of `exceptions`, `interrupts`, `sbi`, `csr` and `plic`, or `all` of them. The monitor's
+//!     `trace` command changes this for a running guest.
+//!   * `rvirt.guests=<n>`: start at most n guests, or one for every free hart if n is 0, overriding
+//!     the `rvirt,max-guests` property.
+//!   * `rvirt.focus=<guestid>`: send console input to this guest from the start, rather than to
+//!     guest 1.
+//!
+//! Switches that aren't recognized are reported and otherwise ignored.
+
+use arrayvec::ArrayString;
+
+/// Exceptions forwarded to the guest.
+pub const TRACE_EXCEPTIONS: u32 = 1 << 0;
+/// Interrupts forwarded to the guest.
+pub const TRACE_INTERRUPTS: u32 = 1 << 1;
+/// SBI calls made by the guest.
+pub const TRACE_SBI: u32 = 1 << 2;
+/// Writes to emulated CSRs.
+pub const TRACE_CSR: u32 = 1 << 3;
+/// Loads and stores to the emulated PLIC.
+pub const TRACE_PLIC: u32 = 1 << 4;
+
+pub const TRACE_TARGETS: [(&str, u32); 5] = [
+    ("exceptions", TRACE_EXCEPTIONS),
+    ("interrupts", TRACE_INTERRUPTS),
+    ("sbi", TRACE_SBI),
+    ("csr", TRACE_CSR),
+    ("plic", TRACE_PLIC),
+];
+
+const PREFIX: &str = "rvirt.";
+
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+    pub loglevel: u32,
+    /// What to trace, as a set of `TRACE_*` bits.
+    pub trace: u32,
+    /// Most guests to start, if limited.
+    pub guests: Option<u32>,
+    /// Guest to receive console input first.
+    pub focus: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { loglevel: 1, trace: 0, guests: None, focus: None }
+    }
+}
+
+impl Options {
+    /// Take the hypervisor's switches out of `bootargs`, leaving the rest of the command line for
+    /// guests.
+    pub fn parse(bootargs: &mut ArrayString<[u8; 256]>) -> Self {
+        let mut options = Self::default();
+        if !bootargs.split_whitespace().any(|word| word.starts_with(PREFIX)) {
+            return options;
+        }
+
+        let mut rest = ArrayString::<[u8; 256]>::new();
+        for word in bootargs.split_whitespace() {
+            if word.starts_with(PREFIX) {
+                options.apply(&word[PREFIX.len()..]);
+            } else {
+                if !rest.is_empty() {
+                    rest.push(' ');
+                }
+                rest.push_str(word);
+            }
+        }
+        *bootargs = rest;
+        options
+    }
+
+    fn apply(&mut self, switch: &str) {
+        let mut parts = switch.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let value = parts.next().unwrap_or("");
+        let valid = match name {
+            "loglevel" => value.parse().map(|n| self.loglevel = n).is_ok(),
+            "trace" => parse_targets(value).map(|t| self.trace = t).is_some(),
+            "guests" => value.parse().map(|n| self.guests = Some(n)).is_ok(),
+            "focus" => value.parse().map(|g| self.focus = Some(g)).is_ok(),
+            _ => false,
+        };
+        if !valid {
+            println!("WARN: ignoring bootargs switch {}{}", PREFIX, switch);
+        }
+    }
+}
+
+/// Parse a comma separated list of trace targets into a set of `TRACE_*` bits. `all` stands for
+/// every target and `none` for no targets.
+pub fn parse_targets(list: &str) -> Option<u32> {
+    let mut targets = 0;
+    for name in list.split(',') {
+        targets |= match name {
+            "all" => TRACE_TARGETS.iter().fold(0, |all, &(_, bit)| all | bit),
+            "none" => 0,
+            _ => TRACE_TARGETS.iter().find(|&&(n, _)| n == name)?.1,
+        };
+    }
+    Some(targets)
+}
+
+/// Print the names of the trace targets in `targets`.
+pub fn print_targets(targets: u32) {
+    if targets == 0 {
+        println!("not tracing");
+    }
+    for &(name, bit) in TRACE_TARGETS.iter() {
+        if targets & bit != 0 {
+            println!("tracing {}", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bootargs: &str) -> (Options, ArrayString<[u8; 256]>) {
+        let mut bootargs = ArrayString::from(bootargs).unwrap();
+        let options = Options::parse(&mut bootargs);
+        (options, bootargs)
+    }
+
+    #[test]
+    fn switches_are_taken_out() {
+        let (options, rest) = parse("console=ttyS0 rvirt.loglevel=2  rvirt.guests=3 root=/dev/vda rvirt.focus=2");
+        assert_eq!(&*rest, "console=ttyS0 root=/dev/vda");
+        assert_eq!(options.loglevel, 2);
+        assert_eq!(options.guests, Some(3));
+        assert_eq!(options.focus, Some(2));
+        assert_eq!(options.trace, 0);
+    }
+
+    #[test]
+    fn bootargs_without_switches_are_untouched() {
+        let (options, rest) = parse(" console=ttyS0  quiet ");
+        assert_eq!(&*rest, " console=ttyS0  quiet ");
+        assert_eq!(options.loglevel, Options::default().loglevel);
+    }
+
+    #[test]
+    fn invalid_switches_are_ignored() {
+        let (options, rest) = parse("rvirt.loglevel=high rvirt.bogus rvirt.trace=sbi,nothing quiet");
+        assert_eq!(&*rest, "quiet");
+        assert_eq!(options.loglevel, 1);
+        assert_eq!(options.trace, 0);
+    }
+
+    #[test]
+    fn trace_targets() {
+        assert_eq!(parse_targets("sbi,csr"), Some(TRACE_SBI | TRACE_CSR));
+        let all = TRACE_EXCEPTIONS | TRACE_INTERRUPTS | TRACE_SBI | TRACE_CSR | TRACE_PLIC;
+        assert_eq!(parse_targets("all"), Some(all));
+        assert_eq!(parse_targets("none"), Some(0));
+        assert_eq!(parse_targets(""), None);
+        assert_eq!(parse("rvirt.trace=plic").0.trace, TRACE_PLIC);
+    }
+}
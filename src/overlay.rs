@@ -0,0 +1,227 @@
+//! Copy-on-write disk overlays, letting every guest boot from one shared base disk.
+//!
+//! The `rvirt,blk-cow` property of the host's /chosen names two host virtio block devices, as
+//! `<base overlay>` indices into the host's virtio devices. Neither is given to any guest. Instead,
+//! the boot hart takes them over and every guest gets an emulated virtio-blk device in its first
+//! free slot, which shows the contents of the base disk (or of the qcow2 image on it) as modified by
+//! that guest's own writes. The base disk is never written.
+//!
+//! The overlay disk is split evenly between the guests. Each guest's region starts with a bitmap
+//! holding one bit per sector of the base disk (see cowbitmap.rs), followed by one sector for each
+//! sector of the base disk. Writes go to the guest's region and then set their bits, with a barrier
+//! in between, so a sector is read from the overlay only once its new contents are there, even
+//! after a crash. Regions are kept across restarts of the guest and of the hypervisor; zeroing the
+//! overlay disk discards them. Sparse files are a good backing for the overlay disk, since most of
+//! every region is never written.
+//!
+//! Host disks can also be shared without an overlay, by listing `<guestid device>` pairs in the
+//! `rvirt,blk-readonly` property of /chosen (a guestid of zero meaning every guest). Each listed
+//! guest gets an emulated virtio-blk device that offers VIRTIO_BLK_F_RO and fails every write.
+//!
+//! Shared disks are polled by whichever hart makes a request, with the lock on `SHARED_STATICS`
+//! held, and their rings and buffers come from a DMA pool that no guest owns, so that restarting a
+//! guest doesn't take them away.
+
+use core::sync::atomic::Ordering;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::constants::DMA_POOL_SIZE;
+use crate::cowbitmap::{self, CowBitmap};
+use crate::dma::DmaPool;
+use crate::drivers::blk::{Disk, HostBlk};
+use crate::error::{Error, Result};
+use crate::fdt::MachineMeta;
+use crate::layout::MachineLayout;
+use crate::memory_region::MemoryRegion;
+use crate::pmap;
+use crate::statics::SHARED_STATICS;
+
+/// Value of `SHARED_STATICS.virtio_owners` for the host devices behind shared disks.
+pub const HYPERVISOR_OWNER: u64 = u64::max_value();
+
+/// Most host disks that can be shared read-only.
+const MAX_READONLY_DISKS: usize = 4;
+
+/// The disks shared read-only, with the index of the host virtio device behind each.
+pub type ReadOnlyDisks = [Option<(u32, Disk)>; MAX_READONLY_DISKS];
+
+/// The disks shared by all guests.
+pub struct CowDisks {
+    base: Disk,
+    overlay: HostBlk,
+    /// Size of each guest's region of the overlay disk.
+    region_sectors: u64,
+}
+
+/// The DMA pool for the host devices that the hypervisor keeps for itself: the disks shared by
+/// guests and the config disk. None if there are none, or if the pool would overlap reserved memory.
+pub unsafe fn shared_dma_pool(machine: &MachineMeta, layout: &MachineLayout) -> Option<DmaPool> {
+    if machine.blk_cow.is_none() && machine.blk_readonly.is_empty() && machine.config_disk.is_none() {
+        return None;
+    }
+    let pool_pa = layout.shared_dma_pool;
+    if machine.is_reserved(pool_pa, DMA_POOL_SIZE) {
+        println!("WARN: Shared DMA pool overlaps reserved memory, disabling shared and config disks");
+        return None;
+    }
+    Some(DmaPool::new(MemoryRegion::new(pmap::pa2va(pool_pa), DMA_POOL_SIZE)))
+}
+
+/// Take over the base and overlay disks named by `rvirt,blk-cow`, splitting the overlay between
+/// `guests` guests, and the disks named by `rvirt,blk-readonly`. Called by the boot hart before any
+/// guest starts.
+pub unsafe fn init(machine: &MachineMeta, guests: u64, dma: &mut DmaPool) {
+    if machine.blk_cow.is_none() && machine.blk_readonly.is_empty() {
+        return;
+    }
+    for (index, owner) in SHARED_STATICS.virtio_owners.iter().enumerate() {
+        if machine.is_shared_device(index) {
+            owner.store(HYPERVISOR_OWNER, Ordering::SeqCst);
+        }
+    }
+
+    if let Some((base, overlay)) = machine.blk_cow {
+        match open(machine, base, overlay, guests, dma) {
+            Ok(disks) => *SHARED_STATICS.cow_disks.lock() = Some(disks),
+            Err(e) => println!("WARN: Unable to set up copy-on-write disks: {:?}", e),
+        }
+    }
+
+    let mut readonly = SHARED_STATICS.readonly_disks.lock();
+    for &(_, index) in &machine.blk_readonly {
+        if readonly.iter().flatten().any(|d| d.0 == index) {
+            continue;
+        }
+        let slot = match readonly.iter_mut().find(|d| d.is_none()) {
+            Some(slot) => slot,
+            None => {
+                println!("WARN: Only {} disks can be shared read-only", MAX_READONLY_DISKS);
+                break;
+            }
+        };
+        let disk = machine.virtio.get(index as usize).ok_or(Error::DeviceIo)
+            .and_then(|d| Disk::open(d.base_address, dma));
+        match disk {
+            Ok(disk) => *slot = Some((index, disk)),
+            Err(e) => println!("WARN: Unable to share virtio device {} read-only: {:?}", index, e),
+        }
+    }
+}
+
+unsafe fn open(machine: &MachineMeta, base: u32, overlay: u32, guests: u64, dma: &mut DmaPool) -> Result<CowDisks> {
+    let address = |index: u32| machine.virtio.get(index as usize).map(|d| d.base_address).ok_or(Error::DeviceIo);
+    let base = Disk::open(address(base)?, dma)?;
+    let overlay = HostBlk::new(address(overlay)?, dma)?;
+
+    let region_sectors = overlay.sectors() / guests.max(1);
+    if cowbitmap::bitmap_sectors(base.sectors()) + base.sectors() > region_sectors {
+        println!("Overlay disk needs {} sectors for each of {} guests, but only has {} in all",
+                 cowbitmap::bitmap_sectors(base.sectors()) + base.sectors(), guests, overlay.sectors());
+        return Err(Error::DeviceIo);
+    }
+    Ok(CowDisks { base, overlay, region_sectors })
+}
+
+/// One guest's view of the shared base disk.
+pub struct Overlay {
+    sectors: u64,
+    /// The guest's bitmap on the overlay disk, and the first sector of its copy of the base disk.
+    bitmap: CowBitmap,
+    data_start: u64,
+}
+
+impl Overlay {
+    /// The overlay of guest `guestid`, or None if copy-on-write disks aren't set up.
+    pub fn new(guestid: u64) -> Option<Self> {
+        let shared = SHARED_STATICS.cow_disks.lock();
+        let disks = shared.as_ref()?;
+        let sectors = disks.base.sectors();
+        let bitmap_start = (guestid - 1) * disks.region_sectors;
+        Some(Self {
+            sectors,
+            bitmap: CowBitmap::new(bitmap_start),
+            data_start: bitmap_start + cowbitmap::bitmap_sectors(sectors),
+        })
+    }
+}
+
+impl BlockDevice for Overlay {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let mut shared = SHARED_STATICS.cow_disks.lock();
+        let disks = shared.as_mut().ok_or(Error::DeviceIo)?;
+
+        // Read runs of sectors that come from the same disk together.
+        let count = buf.len() as u64 / SECTOR_SIZE;
+        let mut i = 0;
+        while i < count {
+            let written = self.bitmap.is_written(&mut disks.overlay, sector + i)?;
+            let mut n = 1;
+            while i + n < count && self.bitmap.is_written(&mut disks.overlay, sector + i + n)? == written {
+                n += 1;
+            }
+            let chunk = &mut buf[(i * SECTOR_SIZE) as usize..((i + n) * SECTOR_SIZE) as usize];
+            if written {
+                disks.overlay.read(self.data_start + sector + i, chunk)?;
+            } else {
+                disks.base.read(sector + i, chunk)?;
+            }
+            i += n;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        let mut shared = SHARED_STATICS.cow_disks.lock();
+        let disks = shared.as_mut().ok_or(Error::DeviceIo)?;
+
+        disks.overlay.write(self.data_start + sector, buf)?;
+        self.bitmap.mark_written(&mut disks.overlay, sector, buf.len() as u64 / SECTOR_SIZE)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut shared = SHARED_STATICS.cow_disks.lock();
+        let disks = shared.as_mut().ok_or(Error::DeviceIo)?;
+        self.bitmap.store(&mut disks.overlay)?;
+        disks.overlay.flush()
+    }
+}
+
+/// A guest's view of a disk shared read-only.
+pub struct ReadOnlyDisk {
+    /// Host virtio device behind the disk.
+    index: u32,
+    sectors: u64,
+}
+
+impl ReadOnlyDisk {
+    /// Access to the shared disk on host virtio device `index`, or None if it isn't set up.
+    pub fn new(index: u32) -> Option<Self> {
+        let shared = SHARED_STATICS.readonly_disks.lock();
+        let &(_, ref disk) = shared.iter().flatten().find(|d| d.0 == index)?;
+        Some(Self { index, sectors: disk.sectors() })
+    }
+}
+
+impl BlockDevice for ReadOnlyDisk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let mut shared = SHARED_STATICS.readonly_disks.lock();
+        let &mut (_, ref mut disk) = shared.iter_mut().flatten().find(|d| d.0 == self.index)
+            .ok_or(Error::DeviceIo)?;
+        disk.read(sector, buf)
+    }
+
+    fn write(&mut self, _sector: u64, _buf: &[u8]) -> Result<()> {
+        Err(Error::DeviceIo)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -245,4 +245,5 @@ pub fn debug_paging() {
         println!("VALID R W X USER GLOBAL ACC DIRTY RSW   VIRTUAL (low)      VIRTUAL (high)     PHYSICAL (low)     PHYSICAL (high)  TRAVERSAL-ERROR");
     }
     println!("====================================================== END PAGE TABLE STATE ======================================================");
+    print::flush();
 }
@@ -1,17 +1,44 @@
 use crate::context::Context;
-use crate::riscv::bits::SATP_PPN;
-use crate::{pmap::*, riscv, virtio};
+use crate::dispatch::Trap;
+use crate::error::{Error, Result};
+use crate::exits::ExitReason;
+use crate::options::TRACE_PLIC;
+use crate::profile::{self, Probe};
+use crate::riscv::bits::{SATP_PPN, SCAUSE_INSN_ACCESS_FAULT, SCAUSE_INSN_PAGE_FAULT, SCAUSE_LOAD_ACCESS_FAULT,
+                         SCAUSE_LOAD_PAGE_FAULT, SCAUSE_STORE_ACCESS_FAULT, SCAUSE_STORE_PAGE_FAULT};
+use crate::timer::TimerEvent;
+use crate::{hvinfo, irqlatency, mmio, pmap::*, ptsync, ptverify, riscv, rtc, testdev, trap, virtio, watch};
 use riscv_decode::Instruction;
 
-/// Perform any handling required in response to a guest page fault. Returns true if the fault could
-/// be handled, or false if it should be forwarded on to the guest.
-pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u32>) -> bool {
-    let shadow = state.shadow();
-    if shadow == PageTableRoot::MPA {
-        println!("Page fault without guest paging enabled?");
-        return false;
+/// Handle a page fault trap, forwarding it to the guest if its own page tables don't allow the
+/// access.
+pub fn handle_trap(state: &mut Context, trap: &Trap) -> bool {
+    let instruction = trap.instruction.as_ref().and_then(|i| i.as_ref().ok()).map(|i| i.0);
+    let start = profile::start();
+    let result = handle_page_fault(state, trap.cause, instruction);
+    state.profile.record(Probe::PageFault, start);
+    match result {
+        Ok(()) => trap::maybe_forward_interrupt(state, trap.pc),
+        Err(Error::GuestFault) => {
+            // Without paging there are no page faults, so the guest is told of an access fault.
+            let cause = match (state.shadow(), trap.cause) {
+                (PageTableRoot::MPA, SCAUSE_INSN_PAGE_FAULT) => SCAUSE_INSN_ACCESS_FAULT,
+                (PageTableRoot::MPA, SCAUSE_LOAD_PAGE_FAULT) => SCAUSE_LOAD_ACCESS_FAULT,
+                (PageTableRoot::MPA, SCAUSE_STORE_PAGE_FAULT) => SCAUSE_STORE_ACCESS_FAULT,
+                (_, cause) => cause,
+            };
+            state.record_exit(ExitReason::GuestPageFault);
+            trap::forward_exception(state, cause, trap.pc)
+        }
+        Err(e) => trap::terminate_guest(state, e),
     }
+    true
+}
 
+/// Perform any handling required in response to a guest page fault. Returns `Error::GuestFault` if
+/// the fault should be forwarded on to the guest.
+pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u32>) -> Result<()> {
+    let shadow = state.shadow();
     let guest_va = csrr!(stval);
     //assert!((guest_va & SV39_MASK) < (511 << 30));
 
@@ -22,24 +49,56 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
         _ => unreachable!(),
     };
 
-    let page = guest_va & !0xfff;
-    if let Some(translation) = translate_guest_address(&state.guest_memory, (state.csrs.satp & SATP_PPN) << 12, page) {
-        // Check R/W/X bits
-        if translation.pte_value & access == 0 {
-            return false;
+    // Without paging, addresses are guest physical addresses. Guest memory is mapped up front by
+    // the MPA table, so only device accesses fault.
+    if shadow == PageTableRoot::MPA {
+        if state.guest_map.host_pa(guest_va).is_some() || access == PTE_EXECUTE || guest_va >> 38 != 0 {
+            return Err(Error::GuestFault);
         }
+        return handle_device_fault(state, shadow, guest_va, guest_va, access, PTE_READ | PTE_WRITE, instruction);
+    }
+
+    // The top 16GB of every shadow address space belong to the hypervisor, so a guest mapping
+    // there can never be honored. As far as the guest can tell, the access simply faults, which
+    // testguests/hvwindow.S checks.
+    let page = guest_va & !0xfff;
+    if page >= DIRECT_MAP_OFFSET {
+        return Err(Error::GuestFault);
+    }
 
+    let root = (state.csrs.satp & SATP_PPN) << 12;
+    if let Some(translation) = translate_guest_address(&state.guest_memory, root, page) {
         // Check U bit
         match shadow {
-            PageTableRoot::UVA => if translation.pte_value & PTE_USER == 0 { return false; }
-            PageTableRoot::KVA => if translation.pte_value & PTE_USER != 0 { return false; }
+            PageTableRoot::UVA => if translation.pte_value & PTE_USER == 0 { return Err(Error::GuestFault); }
+            PageTableRoot::KVA => if translation.pte_value & PTE_USER != 0 { return Err(Error::GuestFault); }
             PageTableRoot::MVA => {}
             _ => unreachable!(),
         }
 
-        if state.guest_memory.in_region(translation.guest_pa) {
-            let host_pa = translation.guest_pa + state.guest_shift;
+        // Cache block management instructions fault like stores, but only need the page to be
+        // readable or writable. Rather than mapping the page, which the guest may have made
+        // read-only and which may hold a virtqueue, the operation is applied to the frame behind it.
+        if let Some(op) = instruction.and_then(decode_cache_block_management) {
+            if translation.pte_value & (PTE_READ | PTE_WRITE) == 0 {
+                return Err(Error::GuestFault);
+            }
+            let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+            return handle_cache_block_management(state, guest_pa, op);
+        }
+
+        // Check R/W/X bits
+        if translation.pte_value & access == 0 {
+            return Err(Error::GuestFault);
+        }
+
+        let reserved_bits = match translation.level {
+            PageTableLevel::Level4KB => 0x000,
+            PageTableLevel::Level2MB => 0x100,
+            PageTableLevel::Level1GB => 0x200,
+        };
 
+        if let Some(host_pa) = state.guest_map.host_pa(translation.guest_pa) {
             // Set A and D bits
             let new_pte = if (translation.pte_value & PTE_DIRTY) == 0 && access == PTE_WRITE {
                 translation.pte_value | PTE_DIRTY | PTE_ACCESSED
@@ -63,18 +122,51 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
             if virtio::is_queue_access(state, translation.guest_pa) {
                 let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
                 let host_pa = (host_pa & !0xfff) | (guest_va & 0xfff);
-                let instruction = instruction.expect("attempted to execute code from virtio queue page");
+                let instruction = instruction.ok_or(Error::UnsupportedDeviceAccess(guest_pa))?;
+                state.record_exit(ExitReason::VirtqueueAccess);
                 return virtio::handle_queue_access(state, guest_pa, host_pa, instruction);
             }
 
-            let reserved_bits = match translation.level {
-                PageTableLevel::Level4KB => 0x000,
-                PageTableLevel::Level2MB => 0x100,
-                PageTableLevel::Level1GB => 0x200,
+            // Pages with watched bytes stay read-only too, unless a store to them couldn't be
+            // emulated. See watch.rs.
+            let watched = state.watches.covers_page(translation.guest_pa);
+            if access == PTE_WRITE && watched {
+                let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+                if watch::emulate_write(state, guest_pa, instruction) {
+                    state.record_exit(ExitReason::WatchedWrite);
+                    return Ok(());
+                }
+            }
+
+            // Pages of the guest's page tables stay read-only, so that changes to them are noticed.
+            // See ptsync.rs.
+            if access == PTE_WRITE && state.shadow_page_tables.sync.traps_writes(translation.guest_pa) {
+                let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+                if ptsync::emulate_write(state, guest_pa, instruction) {
+                    state.record_exit(ExitReason::PageTableWrite);
+                    return Ok(());
+                }
+            }
+            for (depth, &table) in translation.tables.iter().enumerate() {
+                state.shadow_page_tables.sync.track(&state.guest_memory, table, page, depth);
+            }
+            let perm = state.shadow_page_tables.sync.filter_permissions(
+                &state.guest_memory, translation.guest_pa, access == PTE_WRITE, perm);
+            let perm = if (watched || state.dirty.enabled()) && access != PTE_WRITE { perm & !PTE_WRITE } else { perm };
+            if perm & PTE_WRITE != 0 {
+                state.dirty.mark(&state.guest_memory, translation.guest_pa);
+            }
+
+            // Guests are only told about Svpbmt when the host has it. Otherwise the memory type
+            // bits are reserved, and are dropped rather than being passed to the hardware.
+            let memory_type = if state.host_svpbmt {
+                translation.pte_value & PTE_PBMT_MASK
+            } else {
+                0
             };
 
-            let new_shadow_pte = (host_pa >> 2) | reserved_bits | perm | PTE_AD | PTE_USER | PTE_VALID;
-            let old_shadow_pte = state.shadow_page_tables.rmw_mapping(shadow, page, new_shadow_pte);
+            let new_shadow_pte = (host_pa >> 2) | memory_type | reserved_bits | perm | PTE_AD | PTE_USER | PTE_VALID;
+            let old_shadow_pte = install_shadow_mapping(state, shadow, page, new_shadow_pte)?;
 
             // Flushing the TLB entry for a virtual address can be very expensive and we only need
             // to do one here if the processor cache invalid TLB entries. The logic below attempts
@@ -91,33 +183,155 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
                 state.consecutive_page_fault_count = 1;
             }
 
-            return true;
-        } else if access != PTE_EXECUTE && state.smode {
-            let pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
-            if let Some(instruction) = instruction {
-                if is_uart_access(pa) {
-                    return handle_uart_access(state, pa, instruction);
+            if cfg!(debug_assertions) && state.verify_interval != 0 {
+                state.faults_since_verify += 1;
+                if state.faults_since_verify >= state.verify_interval {
+                    state.faults_since_verify = 0;
+                    ptverify::verify(state, false);
                 }
+            }
 
-                if is_plic_access(pa) {
-                    return handle_plic_access(state, pa, instruction)
-                }
+            state.record_exit(ExitReason::ShadowFill);
+            return Ok(());
+        } else if access != PTE_EXECUTE {
+            let pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+            let pte_flags = reserved_bits | (translation.pte_value & (PTE_READ | PTE_WRITE));
+            return handle_device_fault(state, shadow, guest_va, pa, access, pte_flags, instruction);
+        }
+    }
 
-                if virtio::is_device_access(state, pa) {
-                    return virtio::handle_device_access(state, pa, instruction);
-                }
+    Err(Error::GuestFault)
+}
+
+/// Handle a fault on a guest physical address outside guest memory, which belongs to an emulated
+/// device or to nothing at all. `pte_flags` are the permission and reserved bits for any mapping
+/// made in place of trapping.
+fn handle_device_fault(state: &mut Context, shadow: PageTableRoot, guest_va: u64, pa: u64, access: u64,
+                       pte_flags: u64, instruction: Option<u32>) -> Result<()> {
+    let page = guest_va & !0xfff;
+
+    // The hypervisor info page is mapped read-only, so that only writes to it trap.
+    if let Some(host_pa) = hvinfo::map_page(state, pa).filter(|_| access == PTE_READ) {
+        let new_shadow_pte = (host_pa >> 2) | (pte_flags & !PTE_WRITE) | PTE_READ | PTE_AD | PTE_USER | PTE_VALID;
+        install_shadow_mapping(state, shadow, page, new_shadow_pte)?;
+        if state.tlb_caches_invalid_ptes {
+            riscv::sfence_vma_addr(guest_va);
+        }
+        state.record_exit(ExitReason::MmioMap);
+        return Ok(());
+    }
+
+    // Write combined device pages are backed by host memory, which is mapped like any other.
+    if let Some(host_pa) = mmio::map_page(state, pa) {
+        let new_shadow_pte = (host_pa >> 2) | pte_flags | PTE_AD | PTE_USER | PTE_VALID;
+        install_shadow_mapping(state, shadow, page, new_shadow_pte)?;
+        if state.tlb_caches_invalid_ptes {
+            riscv::sfence_vma_addr(guest_va);
+        }
+        state.record_exit(ExitReason::MmioMap);
+        return Ok(());
+    }
+
+    // Data the guest wrote to write combined pages must reach devices before any register
+    // access that may depend on it.
+    mmio::flush(state);
+    if let Some(instruction) = instruction.filter(|_| state.smode()) {
+        if is_uart_access(pa) {
+            state.record_exit(ExitReason::UartAccess);
+            return handle_uart_access(state, pa, instruction);
+        }
+
+        if is_plic_access(pa) {
+            state.record_exit(ExitReason::PlicAccess);
+            return handle_plic_access(state, pa, instruction)
+        }
+
+        if virtio::is_device_access(state, pa) {
+            state.record_exit(ExitReason::VirtioAccess);
+            return virtio::handle_device_access(state, pa, instruction);
+        }
+
+        if testdev::is_test_access(pa) {
+            state.record_exit(ExitReason::TestDeviceAccess);
+            return testdev::handle_access(state, pa, instruction);
+        }
+
+        if rtc::is_rtc_access(pa) {
+            state.record_exit(ExitReason::RtcAccess);
+            return rtc::handle_access(state, pa, instruction);
+        }
+
+        if hvinfo::is_info_access(pa) && access == PTE_WRITE {
+            state.record_exit(ExitReason::MmioMap);
+            riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+            return Ok(());
+        }
+    }
+
+    Err(Error::GuestFault)
+}
+
+/// Set a shadow page table entry, returning the old one.
+fn install_shadow_mapping(state: &mut Context, shadow: PageTableRoot, page: u64, pte: u64) -> Result<u64> {
+    match state.shadow_page_tables.rmw_mapping(shadow, page, pte) {
+        // Shadow page tables are only a cache, so running out of space for them can be handled by
+        // throwing them all away. Tables that no longer map anything go first, and if that frees
+        // enough for this mapping the rest are kept.
+        Err(Error::OutOfMemory) => {
+            if state.shadow_page_tables.reclaim_empty_tables() < 2 {
+                flush_shadow_page_table(&mut state.shadow_page_tables);
+            } else {
+                riscv::sfence_vma();
             }
+            state.shadow_page_tables.rmw_mapping(shadow, page, pte)
         }
+        result => result,
     }
+}
 
-    false
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CacheBlockOp {
+    Clean,
+    Flush,
+    Inval,
+}
+
+/// Decode cbo.clean, cbo.flush and cbo.inval, which only differ in their immediate. cbo.zero is a
+/// store like any other and isn't included.
+fn decode_cache_block_management(instruction: u32) -> Option<CacheBlockOp> {
+    // MISC-MEM opcode, funct3 = 2 and rd = 0.
+    if instruction & 0x7fff != 0x200f {
+        return None;
+    }
+    match instruction >> 20 {
+        0 => Some(CacheBlockOp::Inval),
+        1 => Some(CacheBlockOp::Clean),
+        2 => Some(CacheBlockOp::Flush),
+        _ => None,
+    }
+}
+
+/// Apply a cache block operation to the host frame behind `guest_pa`, so that a device doing DMA
+/// without cache coherence sees (or the guest later reads) what is really in memory. Invalidation
+/// is done as a flush, as it is when guests run these instructions directly. Blocks outside guest
+/// memory belong to emulated devices, which have no cache to maintain.
+fn handle_cache_block_management(state: &mut Context, guest_pa: u64, op: CacheBlockOp) -> Result<()> {
+    if let Some(host_pa) = state.guest_map.host_pa(guest_pa) {
+        match op {
+            CacheBlockOp::Clean => riscv::cbo_clean(pa2va(host_pa)),
+            CacheBlockOp::Flush | CacheBlockOp::Inval => riscv::cbo_flush(pa2va(host_pa)),
+        }
+    }
+    state.record_exit(ExitReason::CacheBlockOp);
+    riscv::set_sepc(csrr!(sepc) + 4);
+    Ok(())
 }
 
 #[inline(always)]
 fn is_uart_access(guest_pa: u64) -> bool {
     guest_pa >= 0x10000000 && guest_pa < 0x10000100
 }
-fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<()> {
     match riscv_decode::decode(instruction).ok() {
         Some(Instruction::Lb(i)) => {
             let value = state.uart.read(&state.host_clint, guest_pa) as u64;
@@ -126,48 +340,57 @@ fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> b
         Some(Instruction::Sb(i)) => {
             let value = (state.saved_registers.get(i.rs2()) & 0xff) as u8;
             state.uart.write(&state.host_clint, guest_pa, value);
+            state.schedule_console_flush();
+            if let Some(deadline) = state.uart.tx_interrupt_time() {
+                state.schedule_timer(TimerEvent::UartTransmit, deadline);
+            }
         }
         Some(instr) => {
             println!("UART: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-            loop {}
+            return Err(Error::UnsupportedInstruction(instruction));
         }
-        _ => return false,
+        _ => return Err(Error::GuestFault),
     }
     riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
+    Ok(())
 }
 
 #[inline(always)]
 fn is_plic_access(guest_pa: u64) -> bool {
     guest_pa >= 0x0c000000 && guest_pa < 0x10000000
 }
-fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<()> {
     match riscv_decode::decode(instruction).ok() {
         Some(Instruction::Lw(i)) => {
             let value = state.plic.read_u32(guest_pa) as i32 as i64 as u64;
-            // println!("PLIC: Read value {:#x} at address {:#x}", value, guest_pa);
+            if value != 0 && state.plic.is_claim_register(guest_pa) {
+                irqlatency::claimed(state, value as u32);
+            }
+            trace!(state, TRACE_PLIC, "PLIC read {:#x} from {:#x}", value, guest_pa);
             state.saved_registers.set(i.rd(), value)
         }
         Some(Instruction::Sw(i)) => {
             let value = state.saved_registers.get(i.rs2()) as u32;
-            // println!("PLIC: Writing {:#x} to address {:#x}", value, guest_pa);
+            trace!(state, TRACE_PLIC, "PLIC write {:#x} to {:#x}", value, guest_pa);
 
             let mut clear_seip = false;
             state.plic.write_u32(guest_pa, value, &mut clear_seip);
             if clear_seip {
                 state.csrs.sip &= !0x200;
+                let time = state.host_clint.get_mtime();
+                crate::context::Uart::update_interrupt(state, time);
             }
             state.no_interrupt = false;
         }
         Some(instr) => {
             println!("PLIC: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-            loop {}
+            return Err(Error::UnsupportedInstruction(instruction));
         }
         _ => {
             println!("Unrecognized instruction targetting PLIC {:#x} at {:#x}!", instruction, csrr!(sepc));
-            loop {}
+            return Err(Error::UnsupportedInstruction(instruction));
         }
     }
     riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
+    Ok(())
 }
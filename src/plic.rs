@@ -1,5 +1,6 @@
 
 use crate::constants::MAX_GUEST_HARTS;
+use crate::report::Record;
 
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have one M-mode context and one S-mode context.
@@ -75,6 +76,12 @@ impl PlicState {
         }
     }
 
+    /// Whether `addr` is the claim/complete register of any context.
+    pub fn is_claim_register(&self, addr: u64) -> bool {
+        let offset = addr.wrapping_sub(self.base);
+        offset >= 0x200000 && offset < 0x200000 + 0x1000 * MAX_CONTEXTS as u64 && offset & 0xfff == 4
+    }
+
     pub fn write_u32(&mut self, addr: u64, value: u32, clear_seip: &mut bool) {
         let offset = addr.wrapping_sub(self.base);
         if offset <= 0x800 {
@@ -114,6 +121,25 @@ impl PlicState {
         }
     }
 
+    /// Print the state of the emulated PLIC as a record of the `report` command. Only the guest's
+    /// S-mode context is included, and only sources with a priority set.
+    pub fn add_to_report(&self) {
+        const CONTEXT: usize = 1;
+
+        Record::new("plic")
+            .number("threshold", self.thresholds[CONTEXT] as u64)
+            .number("claimed", self.claim_complete[CONTEXT] as u64)
+            .hex_list("pending", self.pending.iter().map(|&p| p as u64))
+            .hex_list("enabled", self.enable[CONTEXT].iter().map(|&e| e as u64))
+            .end();
+        for (source, &priority) in self.source_priority.iter().enumerate().filter(|p| *p.1 != 0) {
+            Record::new("plic_source")
+                .number("source", source as u64)
+                .number("priority", priority as u64)
+                .end();
+        }
+    }
+
     pub fn interrupt_pending(&self) -> bool {
         const CONTEXT: usize = 1; // TODO: shouldn't be a constant
 
@@ -1,11 +1,20 @@
 use crate::fdt::MachineMeta;
+use crate::layout::MachineLayout;
+#[cfg(target_arch = "riscv64")]
 use crate::context::Context;
-use crate::constants::SYMBOL_PA2VA_OFFSET;
+use crate::constants::{HYPERVISOR_LINK_PA, MAX_GUEST_SEGMENTS, MAX_NUMA_NODES, SYMBOL_PA2VA_OFFSET};
+use crate::error::{Error, Result};
 use crate::memory_region::{MemoryRegion, PageTableRegion};
+#[cfg(target_arch = "riscv64")]
+use crate::ptsync;
+use crate::ptsync::PageTableSync;
+#[cfg(target_arch = "riscv64")]
 use crate::riscv;
 use arr_macro::arr;
 use arrayvec::ArrayVec;
+#[cfg(target_arch = "riscv64")]
 use core::ptr;
+#[cfg(target_arch = "riscv64")]
 use riscv_decode::types::RType;
 
 const PAGE_SIZE: u64 = 4096;
@@ -16,8 +25,13 @@ mod segment_layout {
     pub const HART_SEGMENT_SIZE: u64 = 1 << 30; // 1 GB
     pub const DATA_OFFSET: u64 = 0;
     pub const DATA_SIZE: u64 = 2 << 20;
+    /// Also holds the hart's `HartLocal` at its lowest address. See hart.rs.
     pub const STACK_OFFSET: u64 = DATA_OFFSET + DATA_SIZE;
     pub const STACK_SIZE: u64 = 2 << 20;
+    /// Copy of the host device tree, just above the `HartLocal`. It can't go in the data region,
+    /// which the hypervisor's .data and .bss overwrite, because restarting a guest reads it again.
+    pub const FDT_OFFSET: u64 = STACK_OFFSET + 4096;
+    pub const FDT_SIZE: u64 = 64 * 1024;
     pub const HEAP_OFFSET: u64 = STACK_OFFSET + STACK_SIZE;
     pub const HEAP_SIZE: u64 = 28 << 20;
     pub const PT_REGION_OFFSET: u64 = HEAP_OFFSET + HEAP_SIZE;
@@ -26,6 +40,70 @@ mod segment_layout {
 }
 pub use segment_layout::*;
 
+/// Hands out the segments of host memory that guests run in. Segment zero holds the hypervisor
+/// itself, and segments that overlap memory reserved by firmware are never used.
+pub struct SegmentPool {
+    /// Bit `i` is set if segment `i` is unavailable.
+    used: u64,
+    count: u64,
+}
+
+impl SegmentPool {
+    pub fn new(machine: &MachineMeta, layout: &MachineLayout) -> Self {
+        let count = layout.segments();
+        let mut used = 1;
+        for i in 1..count {
+            if machine.is_reserved(layout.segment_base(i), HART_SEGMENT_SIZE) {
+                used |= 1 << i;
+            }
+        }
+        Self { used, count }
+    }
+
+    /// Number of segments that haven't been handed out.
+    pub fn free_count(&self) -> u64 {
+        (0..self.count).filter(|&i| self.used & (1 << i) == 0).count() as u64
+    }
+
+    /// Returns the physical address of a free segment, or None if memory is exhausted.
+    pub fn allocate(&mut self, layout: &MachineLayout) -> Option<u64> {
+        let segment = (0..self.count).find(|&i| self.used & (1 << i) == 0)?;
+        self.used |= 1 << segment;
+        Some(layout.segment_base(segment))
+    }
+
+    /// Take up to `count` more free segments, returning a bitmap of the ones taken. Fewer are
+    /// returned if memory runs out.
+    pub fn allocate_extra(&mut self, count: u64) -> u64 {
+        let mut taken = 0;
+        for segment in (0..self.count).filter(|&i| self.used & (1 << i) == 0).take(count as usize) {
+            taken |= 1 << segment;
+        }
+        self.used |= taken;
+        taken
+    }
+
+    pub fn free(&mut self, layout: &MachineLayout, base_pa: u64) {
+        let segment = layout.segment_index(base_pa);
+        assert!(segment != 0 && segment < self.count);
+        self.used &= !(1 << segment);
+    }
+}
+
+/// Cut down the numbers of extra segments that guests want, indexed by guest, until they add up to
+/// no more than `free`. Each segment that is short is refused to whichever guest would otherwise have
+/// the most, the later one on a tie, so that one large guest can't take what the others asked for.
+/// Returns how many were refused.
+pub fn share_segments(wanted: &mut [u64], free: u64) -> u64 {
+    let mut refused = 0;
+    while wanted.iter().sum::<u64>() > free {
+        let greediest = (0..wanted.len()).max_by_key(|&i| wanted[i]).unwrap();
+        wanted[greediest] -= 1;
+        refused += 1;
+    }
+    refused
+}
+
 #[allow(unused)]
 pub mod pte_flags {
     pub const PTE_VALID: u64 = 0x1;
@@ -37,6 +115,11 @@ pub mod pte_flags {
     pub const PTE_ACCESSED: u64 = 0x40;
     pub const PTE_DIRTY: u64 = 0x80;
     pub const PTE_RSV_MASK: u64 = 0x300;
+    pub const PTE_PPN_MASK: u64 = 0x003f_ffff_ffff_fc00;
+    /// Svpbmt memory type: PMA (zero), non-cacheable or I/O.
+    pub const PTE_PBMT_MASK: u64 = 3 << 61;
+    pub const PTE_PBMT_NC: u64 = 1 << 61;
+    pub const PTE_PBMT_IO: u64 = 2 << 61;
 
     pub const PTE_AD: u64 = PTE_ACCESSED | PTE_DIRTY;
     pub const PTE_RWV: u64 = PTE_READ | PTE_WRITE | PTE_VALID;
@@ -49,6 +132,10 @@ mod page_table_constants {
     pub const DIRECT_MAP_PT_INDEX: u64 = 0xf80;
     pub const DIRECT_MAP_OFFSET: u64 = DIRECT_MAP_PT_INDEX << 27 | ((!0) << 39);
     pub const DIRECT_MAP_PAGES: u64 = 8; // Uses 1 GB pages
+    /// Guest memory is also mapped here, one 1 GB page per segment, so that the hypervisor sees it
+    /// as contiguous even when the segments backing it aren't.
+    pub const GUEST_MAP_PT_INDEX: u64 = 0xfc0;
+    pub const GUEST_MAP_OFFSET: u64 = GUEST_MAP_PT_INDEX << 27 | ((!0) << 39);
 }
 pub use page_table_constants::*;
 
@@ -63,8 +150,8 @@ pub const fn make_boot_page_table(base_pa: u64) -> [u64; 1024] {
         let possible_values = [
             0,
             ((base_pa + 4096) >> 2) | 0x01,
-            0x20000000 | 0xcb,
-            (0x20000000 + (i.wrapping_sub(512) << 19)) | 0xc7,
+            (HYPERVISOR_LINK_PA >> 2) | 0xcb,
+            ((HYPERVISOR_LINK_PA >> 2) + (i.wrapping_sub(512) << 19)) | 0xc7,
             ((i - DIRECT_MAP_PT_INDEX/8) << 28) | PTE_AD | PTE_RWXV,
         ];
 
@@ -81,6 +168,15 @@ pub const fn make_boot_page_table(base_pa: u64) -> [u64; 1024] {
     arr![pte(base_pa, {i += 1; i - 1}); 1024]
 }
 
+/// Write the page table a hart starts out on to the first page of its segment, adjusted for where
+/// the hypervisor's shared segments were loaded.
+pub unsafe fn write_boot_page_table(hart_base_pa: u64, shared_segments_shift: u64) {
+    (*(pa2va(hart_base_pa) as *mut [u64; 1024])) = make_boot_page_table(hart_base_pa);
+    for i in 512..1024 {
+        *(pa2va(hart_base_pa + i * 8) as *mut u64) += shared_segments_shift >> 2;
+    }
+}
+
 // conversions between machine-physical addresses and supervisor-virtual address
 #[allow(unused)]
 pub fn pa2sa(pa: u64) -> u64 {
@@ -107,10 +203,38 @@ use PageTableRoot::*;
 
 const NULL_PAGE_PTR: u64 = 2;
 
+/// With the `sanitize` feature, every word of a free page table page other than the free list link
+/// holds this value. It has the valid bit clear so that a stale pointer into a freed page can't be
+/// followed by the page table walker.
+const FREED_PAGE_POISON: u64 = 0xdead_f5ee_dead_f5e0;
+
+/// Number of freed pages held back before they can be reused, so that a use-after-free has a chance
+/// to be noticed before the page is handed out again.
+const QUARANTINE_PAGES: usize = 32;
+
+/// How the pages of a hart's shadow page table region are being used.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PageTableStats {
+    /// Pages in the region that can hold page tables, and how many of them are free (including any
+    /// in quarantine).
+    pub total_pages: u64,
+    pub free_pages: u64,
+    /// The most pages that have been in use at once.
+    pub peak_pages: u64,
+    /// Page tables freed because every entry in them had become invalid.
+    pub reclaimed: u64,
+    /// Times a page table was needed and none were free.
+    pub exhausted: u64,
+}
+
 pub struct PageTables {
     region: PageTableRegion,
     root_page_tables: [u64; 4],
     free_list_head: u64,
+    quarantine: ArrayVec<[u64; QUARANTINE_PAGES]>,
+    /// Which guest page table pages the shadow mappings were derived from. See ptsync.rs.
+    pub sync: PageTableSync,
+    pub stats: PageTableStats,
 }
 impl PageTables {
     /// Create a set of page tables from a memory region.
@@ -127,6 +251,9 @@ impl PageTables {
             region,
             root_page_tables: [0, 0, 0, 0],
             free_list_head: NULL_PAGE_PTR,
+            quarantine: ArrayVec::new(),
+            sync: PageTableSync::new(),
+            stats: PageTableStats::default(),
         };
 
         // initialize free list
@@ -139,10 +266,11 @@ impl PageTables {
 
             addr += PAGE_SIZE;
         }
+        ret.stats.total_pages = ret.stats.free_pages;
 
         // initialize root page tables
         for i in 0..4 {
-            ret.root_page_tables[i] = ret.alloc_page();
+            ret.root_page_tables[i] = ret.alloc_page().unwrap();
         }
 
         ret
@@ -158,6 +286,7 @@ impl PageTables {
         self.root_page_tables[i]
     }
 
+    #[cfg(target_arch = "riscv64")]
     pub fn install_root(&self, root: PageTableRoot) {
         let new_satp = (8 << 60) | (self.root_pa(root) >> 12);
         if csrr!(satp) != new_satp {
@@ -166,23 +295,144 @@ impl PageTables {
         }
     }
 
-    pub fn rmw_mapping(&mut self, root: PageTableRoot, va: u64, pte: u64) -> u64 {
+    pub fn rmw_mapping(&mut self, root: PageTableRoot, va: u64, pte: u64) -> Result<u64> {
         if va >= DIRECT_MAP_OFFSET {
-            panic!("Guest attempted to access reserved virtual address: {:x}", va);
+            return Err(Error::ReservedAddress(va));
         }
 
-        let pte_addr = self.pte_for_addr(root, va);
+        let pte_addr = self.pte_for_addr(root, va)?;
         let old = self.region[pte_addr];
         self.region.set_leaf_pte(pte_addr, pte);
-        old
+        Ok(old)
+    }
+
+    /// Call `f(va, pte_addr, pte)` for every valid leaf mapping below the direct map in one of the
+    /// shadow page tables.
+    pub fn for_each_mapping<F: FnMut(u64, u64, u64)>(&self, root: PageTableRoot, mut f: F) {
+        assert!(root != PageTableRoot::MPA);
+        self.for_each_mapping_in(self.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8, 0, 0, &mut f);
+    }
+    fn for_each_mapping_in<F: FnMut(u64, u64, u64)>(&self, table: u64, start_index: u64, end_index: u64,
+                                                    level: u64, base_va: u64, f: &mut F) {
+        for i in start_index..end_index {
+            let pte_addr = table + i * 8;
+            let pte = self.region[pte_addr];
+            let mut va = base_va | (i << (30 - 9 * level));
+            if level == 0 && i >= 256 {
+                va |= !0 << 38;
+            }
+
+            if pte & PTE_RWXV == PTE_VALID {
+                self.for_each_mapping_in((pte >> 10) << 12, 0, 512, level + 1, va, f);
+            } else if pte & PTE_VALID != 0 {
+                f(va, pte_addr, pte);
+            }
+        }
+    }
+
+    /// Remove a single leaf mapping. The caller is responsible for flushing the TLB.
+    pub fn clear_mapping(&mut self, pte_addr: u64) {
+        self.region.set_invalid_pte(pte_addr, 0);
+    }
+
+    /// Remove write permission from a leaf mapping. The caller is responsible for flushing the TLB.
+    pub fn write_protect(&mut self, pte_addr: u64) {
+        let pte = self.region[pte_addr];
+        self.region.set_leaf_pte(pte_addr, pte & !PTE_WRITE);
+    }
+
+    /// Remove every mapping in the `1 << shift` byte range starting at `va`, which covers one entry
+    /// of the root page table (a shift of 30), of a second level table (21) or of a leaf table (12).
+    /// Tables left with nothing in them are freed. The caller is responsible for flushing the TLB.
+    pub fn clear_range(&mut self, root: PageTableRoot, va: u64, shift: u32) {
+        assert!(shift == 30 || shift == 21 || shift == 12);
+        if (va >> 30) & 0x1ff >= DIRECT_MAP_PT_INDEX / 8 {
+            return;
+        }
+
+        let mut table = self.root_pa(root);
+        for &level_shift in &[30, 21, 12] {
+            let index = (va >> level_shift) & 0x1ff;
+            if level_shift == shift {
+                self.clear_page_table_range(table, index, index + 1);
+                self.reclaim_path(root, va);
+                return;
+            }
+            let pte = self.region[table + index * 8];
+            if pte & PTE_RWXV != PTE_VALID {
+                // Nothing mapped below here, or a superpage that covers the whole range.
+                if pte & PTE_VALID != 0 {
+                    self.clear_page_table_range(table, index, index + 1);
+                }
+                return;
+            }
+            table = (pte >> 10) << 12;
+        }
+    }
+
+    /// Free the tables on the way to `va` that no longer hold any valid entries, starting from the
+    /// leaf table. The caller is responsible for flushing the TLB before the pages can be reused.
+    fn reclaim_path(&mut self, root: PageTableRoot, va: u64) {
+        let mut path = [0u64; 2];
+        let mut depth = 0;
+        let mut table = self.root_pa(root);
+        for &level_shift in &[30, 21] {
+            let pte_addr = table + ((va >> level_shift) & 0x1ff) * 8;
+            let pte = self.region[pte_addr];
+            if pte & PTE_RWXV != PTE_VALID {
+                break;
+            }
+            path[depth] = pte_addr;
+            depth += 1;
+            table = (pte >> 10) << 12;
+        }
+
+        for &pte_addr in path[..depth].iter().rev() {
+            let table = (self.region[pte_addr] >> 10) << 12;
+            if !self.is_empty_table(table) {
+                return;
+            }
+            self.region.set_invalid_pte(pte_addr, 0);
+            self.free_page(table);
+            self.stats.reclaimed += 1;
+        }
+    }
+
+    /// Free every table below the direct map that no longer holds any valid entries. Returns how
+    /// many were freed. The caller is responsible for flushing the TLB before the pages can be reused.
+    pub fn reclaim_empty_tables(&mut self) -> u64 {
+        let before = self.stats.reclaimed;
+        for &root in &[UVA, KVA, MVA] {
+            self.reclaim_in(self.root_pa(root), DIRECT_MAP_PT_INDEX/8);
+        }
+        self.stats.reclaimed - before
+    }
+    fn reclaim_in(&mut self, table: u64, end_index: u64) {
+        for i in 0..end_index {
+            let pte_addr = table + i * 8;
+            let pte = self.region[pte_addr];
+            if pte & PTE_RWXV == PTE_VALID {
+                let child = (pte >> 10) << 12;
+                self.reclaim_in(child, 512);
+                if self.is_empty_table(child) {
+                    self.region.set_invalid_pte(pte_addr, 0);
+                    self.free_page(child);
+                    self.stats.reclaimed += 1;
+                }
+            }
+        }
+    }
+
+    fn is_empty_table(&self, table: u64) -> bool {
+        (0..PAGE_SIZE).step_by(8).all(|offset| self.region[table + offset] & PTE_VALID == 0)
     }
 
     // Returns the physical address of the pte for a given virtual address.
-    fn pte_for_addr(&mut self, root: PageTableRoot, va: u64) -> u64 {
-        // These ranges use huge pages...
+    fn pte_for_addr(&mut self, root: PageTableRoot, va: u64) -> Result<u64> {
+        // These ranges use huge pages... So does guest memory in the MPA table, where only device
+        // pages outside it are mapped this way.
         assert!(va < DIRECT_MAP_OFFSET);
         assert!(is_sv39(va));
-        assert!(root != PageTableRoot::MPA);
 
         let mut page_table = self.root_pa(root);
         for level in 0..2 {
@@ -194,12 +444,12 @@ impl PageTables {
                 assert_eq!(pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE), 0);
                 page_table = (pte >> 10) << 12;
             } else {
-                let page = self.alloc_page();
+                let page = self.alloc_page()?;
                 self.region.set_nonleaf_pte(pte_addr, (page >> 2) | PTE_VALID);
                 page_table = page;
             }
         }
-        page_table + ((va >> 12) & 0x1ff) * 8
+        Ok(page_table + ((va >> 12) & 0x1ff) * 8)
     }
 
     pub fn clear_page_table(&mut self, pa: u64) {
@@ -220,13 +470,28 @@ impl PageTables {
         }
     }
 
-    fn alloc_page(&mut self) -> u64 {
+    fn alloc_page(&mut self) -> Result<u64> {
         if self.free_list_head == NULL_PAGE_PTR {
-            panic!("Out of hypervisor memory for page tables");
+            if self.quarantine.is_empty() {
+                self.stats.exhausted += 1;
+                return Err(Error::OutOfMemory);
+            }
+            let page = self.quarantine.remove(0);
+            self.push_free_page(page);
         }
 
         let free = self.free_list_head;
         self.free_list_head = self.region[free];
+        self.stats.free_pages -= 1;
+        self.stats.peak_pages = self.stats.peak_pages.max(self.stats.total_pages - self.stats.free_pages);
+
+        if cfg!(feature = "sanitize") {
+            if let Some(offset) = (8..PAGE_SIZE).step_by(8)
+                .find(|offset| self.region[free + offset] != FREED_PAGE_POISON) {
+                panic!("page table page {:#x} modified after free (offset {:#x} holds {:#x})",
+                       free, offset, self.region[free + offset]);
+            }
+        }
 
         let mut addr = free;
         while addr < free + PAGE_SIZE {
@@ -234,13 +499,42 @@ impl PageTables {
             addr += 8;
         }
 
-        free
+        Ok(free)
     }
 
     fn free_page(&mut self, page: u64) {
+        self.stats.free_pages += 1;
+        if !cfg!(feature = "sanitize") {
+            self.push_free_page(page);
+            return;
+        }
+
+        if self.quarantine.contains(&page) || self.is_poisoned(page) {
+            panic!("page table page {:#x} freed twice", page);
+        }
+        let mut addr = page + 8;
+        while addr < page + PAGE_SIZE {
+            self.region.set_invalid_pte(addr, FREED_PAGE_POISON);
+            addr += 8;
+        }
+
+        if self.quarantine.is_full() {
+            let oldest = self.quarantine.remove(0);
+            self.push_free_page(oldest);
+        }
+        self.quarantine.push(page);
+    }
+
+    fn push_free_page(&mut self, page: u64) {
         self.region.set_invalid_pte(page, self.free_list_head);
         self.free_list_head = page;
     }
+
+    /// Whether a page looks like it is already on the free list. Live page tables only ever hold
+    /// zero in their invalid entries, so they can't match.
+    fn is_poisoned(&self, page: u64) -> bool {
+        (8..PAGE_SIZE).step_by(8).all(|offset| self.region[page + offset] == FREED_PAGE_POISON)
+    }
 }
 
 pub fn pa2va(pa: u64) -> u64 { pa + DIRECT_MAP_OFFSET }
@@ -284,13 +578,13 @@ pub fn walk_page_table<R: Fn(u64) -> Option<u64>>(root: u64, va: u64, read_pte:
             return None;
         } else if pte & (PTE_READ | PTE_EXECUTE) != 0 {
             let pa = match level {
-                PageTableLevel::Level4KB => ((pte >> 10) << 12) | (va & 0xfff),
-                PageTableLevel::Level2MB => ((pte >> 19) << 21) | (va & 0x1fffff),
-                PageTableLevel::Level1GB => ((pte >> 28) << 30) | (va & 0x3fffffff),
+                PageTableLevel::Level4KB => ((pte & PTE_PPN_MASK) << 2) | (va & 0xfff),
+                PageTableLevel::Level2MB => (((pte & PTE_PPN_MASK) >> 19) << 21) | (va & 0x1fffff),
+                PageTableLevel::Level1GB => (((pte & PTE_PPN_MASK) >> 28) << 30) | (va & 0x3fffffff),
             };
             return Some(PageTableWalk{path, pa});
         } else {
-            page_table = (pte >> 10) << 12;
+            page_table = (pte & PTE_PPN_MASK) << 2;
         }
     }
     return None;
@@ -314,6 +608,8 @@ pub struct AddressTranslation {
     pub pte_addr: u64,
     pub guest_pa: u64,
     pub level: PageTableLevel,
+    /// Guest physical addresses of the page table pages walked, starting with the root.
+    pub tables: ArrayVec<[u64; 3]>,
 }
 
 pub fn translate_guest_address(guest_memory: &MemoryRegion, root_page_table: u64, addr: u64)
@@ -324,9 +620,11 @@ pub fn translate_guest_address(guest_memory: &MemoryRegion, root_page_table: u64
             pte_addr: t.path[t.path.len() - 1].addr,
             level: t.path[t.path.len() - 1].level,
             guest_pa: t.pa,
+            tables: t.path.iter().map(|pte| pte.addr & !(PAGE_SIZE - 1)).collect(),
         }
     })
 }
+#[cfg(target_arch = "riscv64")]
 pub fn translate_host_address(addr: u64) -> Option<PageTableWalk> {
     // The currently installed page table should always have all of its pages mapped in the direct
     // map region, thus deferencing pointers during a page table walk should always be safe.
@@ -334,41 +632,128 @@ pub fn translate_host_address(addr: u64) -> Option<PageTableWalk> {
     walk_page_table(root_page_table, addr, |pa| Some(unsafe { *(pa2va(pa) as *const u64) }))
 }
 
-pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &MachineMeta) -> (PageTables, MemoryRegion, u64) {
+/// Split guest memory into (at most `MAX_NUMA_NODES`) ranges of roughly equal size, one for each
+/// emulated NUMA node. Guest memory is backed by a single flat region regardless, so this only
+/// changes how the memory is described to the guest.
+pub fn split_numa_nodes(guest_memory: &MemoryRegion, nodes: u32) -> ArrayVec<[(u64, u64); MAX_NUMA_NODES]> {
+    let nodes = (nodes as u64).max(1).min(MAX_NUMA_NODES as u64);
+    let node_size = (guest_memory.len() / nodes) & !(HPAGE_SIZE - 1);
+    (0..nodes).map(|i| {
+        let start = guest_memory.base() + i * node_size;
+        let size = if i == nodes - 1 { guest_memory.len() - i * node_size } else { node_size };
+        (start, size)
+    }).collect()
+}
+
+/// Where the memory of a guest is in host physical memory.
+///
+/// Guest memory is contiguous in guest physical address space, and starts with what is left of the
+/// hart's own segment after the hypervisor's reservation. A large guest continues into further
+/// segments, which can be anywhere in host memory. The hypervisor accesses all of it through the
+/// `GUEST_MAP_OFFSET` window, while the guest and any devices passed through to it use the host
+/// physical addresses given here.
+#[derive(Clone)]
+pub struct GuestMap {
+    /// Guest physical address of the start of guest memory.
+    base: u64,
+    /// Host physical address of each segment, in the order they appear in guest memory.
+    segments: ArrayVec<[u64; MAX_GUEST_SEGMENTS]>,
+}
+
+impl GuestMap {
+    pub fn new(base: u64, hart_base_pa: u64, extra_segments: &[u64]) -> Self {
+        let mut segments = ArrayVec::new();
+        segments.push(hart_base_pa);
+        segments.extend(extra_segments.iter().cloned().take(MAX_GUEST_SEGMENTS - 1));
+        Self { base, segments }
+    }
+
+    /// Size of guest memory.
+    pub fn len(&self) -> u64 {
+        self.segments.len() as u64 * HART_SEGMENT_SIZE - VM_RESERVATION_SIZE
+    }
+
+    /// Host physical address of each segment of guest memory, in order.
+    pub fn segments<'a>(&'a self) -> impl Iterator<Item = u64> + 'a {
+        self.segments.iter().cloned()
+    }
+
+    /// The host physical address backing `guest_pa`, or None if it isn't in guest memory.
+    pub fn host_pa(&self, guest_pa: u64) -> Option<u64> {
+        let offset = guest_pa.checked_sub(self.base)? + VM_RESERVATION_SIZE;
+        let segment = self.segments.get((offset / HART_SEGMENT_SIZE) as usize)?;
+        Some(segment + offset % HART_SEGMENT_SIZE)
+    }
+
+    /// Like `host_pa`, but only succeeds if all `len` bytes starting at `guest_pa` are contiguous in
+    /// host memory.
+    pub fn host_range(&self, guest_pa: u64, len: u64) -> Option<u64> {
+        let start = self.host_pa(guest_pa)?;
+        match self.host_pa(guest_pa + len.max(1) - 1) {
+            Some(end) if end - start == len.max(1) - 1 => Some(start),
+            _ => None,
+        }
+    }
+
+    /// The guest physical address backed by `host_pa`, or None if it isn't part of guest memory.
+    pub fn guest_pa(&self, host_pa: u64) -> Option<u64> {
+        let i = self.segments.iter().position(|&s| host_pa >= s && host_pa < s + HART_SEGMENT_SIZE)?;
+        let offset = i as u64 * HART_SEGMENT_SIZE + host_pa % HART_SEGMENT_SIZE;
+        Some(self.base + offset.checked_sub(VM_RESERVATION_SIZE)?)
+    }
+}
+
+/// Host physical addresses of the segments in a bitmap returned by `SegmentPool::allocate_extra`.
+pub fn segment_addresses(layout: &MachineLayout, mask: u64) -> ArrayVec<[u64; MAX_GUEST_SEGMENTS]> {
+    (0..64).filter(|i| mask & (1u64 << i) != 0)
+        .map(|i| layout.segment_base(i))
+        .take(MAX_GUEST_SEGMENTS - 1)
+        .collect()
+}
+
+#[cfg(target_arch = "riscv64")]
+pub unsafe fn init(hart_base_pa: u64, extra_segments: &[u64], layout: &MachineLayout,
+                   machine: &MachineMeta) -> (PageTables, MemoryRegion, GuestMap) {
     assert_eq!(hart_base_pa % HART_SEGMENT_SIZE, 0);
 
-    let gpm_offset = machine.physical_memory_offset;
-    let gpm_size = HART_SEGMENT_SIZE.checked_sub(VM_RESERVATION_SIZE).unwrap();
-    let guest_shift = VM_RESERVATION_SIZE + hart_base_pa.checked_sub(machine.physical_memory_offset).unwrap();
-    assert_eq!(gpm_offset, 0x80000000);
+    let gpm_offset = layout.memory_base;
+    let guest_map = GuestMap::new(gpm_offset, hart_base_pa, extra_segments);
+    let gpm_size = guest_map.len();
     assert!(gpm_size > 64 * 1024 * 1024);
 
     // Create guest memory region
-    let guest_memory = MemoryRegion::with_base_address(pa2va(gpm_offset + guest_shift), machine.physical_memory_offset, gpm_size);
+    let guest_memory = MemoryRegion::with_base_address(GUEST_MAP_OFFSET + VM_RESERVATION_SIZE,
+                                                       gpm_offset, gpm_size);
 
     // Create shadow page tables
     let memory_region = MemoryRegion::new(pa2va(hart_base_pa + PT_REGION_OFFSET), PT_REGION_SIZE);
     let mut shadow_page_tables = PageTables::new(memory_region, machine.initrd_start, machine.initrd_end);
 
-    let sshift = shared_segments_shift >> 2;
+    let hypervisor_ppn = layout.hypervisor_base >> 2;
 
     // Initialize shadow page tables
     for &root in &[MPA, UVA, KVA, MVA] {
         let va = pa2va(shadow_page_tables.root_pa(root));
         ptr::write_bytes(va as *mut u8, 0, PAGE_SIZE as usize);
 
-        *((va + DIRECT_MAP_PT_INDEX + 0 * 8) as *mut u64) = (0 << 28) | PTE_AD | PTE_RWV;
-        *((va + DIRECT_MAP_PT_INDEX + 1 * 8) as *mut u64) = (1 << 28) | PTE_AD | PTE_RWV;
+        for gigabyte in (0..DIRECT_MAP_PAGES).filter(|g| layout.device_gigabytes & (1u64 << g) != 0) {
+            *((va + DIRECT_MAP_PT_INDEX + gigabyte * 8) as *mut u64) = (gigabyte << 28) | PTE_AD | PTE_RWV;
+        }
         *((va + DIRECT_MAP_PT_INDEX + (hart_base_pa >> 30) * 8) as *mut u64) = (hart_base_pa >> 2) | PTE_AD | PTE_RWV;
+        // The first gigabyte of memory holds the shared DMA pool and the copy of console output.
+        *((va + DIRECT_MAP_PT_INDEX + (gpm_offset >> 30) * 8) as *mut u64) = (gpm_offset >> 2) | PTE_AD | PTE_RWV;
+        for (i, &segment) in guest_map.segments.iter().enumerate() {
+            *((va + GUEST_MAP_PT_INDEX + i as u64 * 8) as *mut u64) = (segment >> 2) | PTE_AD | PTE_RWV;
+        }
 
         // Hypervisor code + data
         let hp = 2 << 18;
-        let page = shadow_page_tables.alloc_page();
+        let page = shadow_page_tables.alloc_page().unwrap();
         *((va + 0xff8) as *mut u64) = (page >> 2) | PTE_VALID;
         shadow_page_tables.region.set_pte_unchecked(
-            page, (0x20000000+sshift) | PTE_AD | PTE_RXV);       // Code + read only data
+            page, hypervisor_ppn | PTE_AD | PTE_RXV);             // Code + read only data
         shadow_page_tables.region.set_pte_unchecked(
-            page+8, (0x20000000+sshift+hp) | PTE_AD | PTE_RWV);  // Shared data
+            page+8, (hypervisor_ppn+hp) | PTE_AD | PTE_RWV);      // Shared data
         shadow_page_tables.region.set_pte_unchecked(
             page+16, ((hart_base_pa>>2)) | PTE_AD | PTE_RWV);    // Data
         shadow_page_tables.region.set_pte_unchecked(
@@ -382,7 +767,7 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
     let npages = gpm_size / HPAGE_SIZE;
     for p in 0..npages  {
         let va = gpm_offset + p * HPAGE_SIZE;
-        let pa = va + guest_shift;
+        let pa = guest_map.host_pa(va).unwrap();
 
         let pte_index = va >> 30;
         let pte_addr = root_pa + pte_index * 8;
@@ -391,7 +776,7 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
             assert_eq!(pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE), 0);
             (pte >> 10) << 12
         } else {
-            let page = shadow_page_tables.alloc_page();
+            let page = shadow_page_tables.alloc_page().unwrap();
             shadow_page_tables.region.set_nonleaf_pte(pte_addr, (page >> 2) | PTE_VALID);
             page
         };
@@ -399,7 +784,7 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
                                                (pa >> 2) | PTE_AD | PTE_USER | PTE_RWXV);
     }
 
-    (shadow_page_tables, guest_memory, guest_shift)
+    (shadow_page_tables, guest_memory, guest_map)
 }
 
 #[allow(unused)]
@@ -439,7 +824,7 @@ pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, b
 
         if pte & PTE_RWXV == PTE_VALID {
             assert!(level != 0);
-            let child = (pte >> 10) << 12;
+            let child = (pte & PTE_PPN_MASK) << 2;
             if !guest_memory.in_region(child) {
                 println!("{:#x}: {:#x} (bad ppn)", addr, pte);
             } else {
@@ -448,30 +833,45 @@ pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, b
                 //break;
             }
         } else if pte & PTE_VALID != 0 {
-            println!("{:#x} -> {:#x}", addr, (pte >> 10) << 12);
+            println!("{:#x} -> {:#x}", addr, (pte & PTE_PPN_MASK) << 2);
         } else if pte != 0 {
             println!("{:#x}: {:#x} (not valid)", addr, pte);
         }
     }
 }
 
+/// Print how much of the shadow page table region is in use, for the monitor's `ptmem` command.
+pub fn print_page_table_stats(shadow_page_tables: &PageTables) {
+    let stats = &shadow_page_tables.stats;
+    let in_use = stats.total_pages - stats.free_pages;
+    println!("{} of {} page table pages in use ({} KB of {} KB), at most {}", in_use, stats.total_pages,
+             in_use * PAGE_SIZE / 1024, stats.total_pages * PAGE_SIZE / 1024, stats.peak_pages);
+    println!("{} empty tables reclaimed, ran out of pages {} times", stats.reclaimed, stats.exhausted);
+}
+
+#[cfg(target_arch = "riscv64")]
 pub fn flush_shadow_page_table(shadow_page_tables: &mut PageTables) {
     for &root in &[UVA, KVA, MVA] {
         shadow_page_tables.clear_page_table_range(shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8);
     }
+    shadow_page_tables.sync.flushed();
 
     riscv::sfence_vma();
 }
 
+#[cfg(target_arch = "riscv64")]
 #[inline]
 pub fn handle_sfence_vma(state: &mut Context, instruction: RType) {
     if instruction.rs1() == 0 {
-        flush_shadow_page_table(&mut state.shadow_page_tables);
+        ptsync::fence(state);
     } else {
         let va = state.saved_registers.get(instruction.rs1());
         if va < DIRECT_MAP_OFFSET {
             for &root in &[UVA, KVA, MVA] {
-                let pte_addr = state.shadow_page_tables.pte_for_addr(root, va);
+                let pte_addr = match state.shadow_page_tables.pte_for_addr(root, va) {
+                    Ok(pte_addr) => pte_addr,
+                    Err(_) => return flush_shadow_page_table(&mut state.shadow_page_tables),
+                };
 
                 match (state.shadow_page_tables.region[pte_addr] >> 8) & 0x3 {
                     0 => state.shadow_page_tables.region.set_invalid_pte(pte_addr, 0),
@@ -482,6 +882,7 @@ pub fn handle_sfence_vma(state: &mut Context, instruction: RType) {
                     _ => state.shadow_page_tables.clear_page_table_range(
                         state.shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8),
                 }
+                state.shadow_page_tables.reclaim_path(root, va);
             }
             riscv::sfence_vma_addr(va);
         }
@@ -498,3 +899,162 @@ pub fn read64(guest_memory: &MemoryRegion, page_table_ppn: u64, guest_va: u64) -
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION_PA: u64 = 0x8000_0000;
+    const REGION_PAGES: usize = 16;
+
+    #[repr(C, align(4096))]
+    #[derive(Copy, Clone)]
+    struct Page([u64; 512]);
+
+    /// Shadow page tables in a buffer of host memory, which they see as `REGION_PAGES` pages of
+    /// physical memory at `REGION_PA`.
+    fn page_tables(buffer: &mut Vec<Page>) -> PageTables {
+        let region = unsafe {
+            MemoryRegion::with_base_address(buffer.as_mut_ptr() as u64, REGION_PA, buffer.len() as u64 * PAGE_SIZE)
+        };
+        PageTables::new(region, 0, 0)
+    }
+
+    fn mappings(tables: &PageTables, root: PageTableRoot) -> Vec<(u64, u64)> {
+        let mut mappings = Vec::new();
+        tables.for_each_mapping(root, |va, _, pte| mappings.push((va, pte)));
+        mappings
+    }
+
+    #[test]
+    fn map_and_unmap() {
+        let mut buffer = vec![Page([0; 512]); REGION_PAGES];
+        let mut tables = page_tables(&mut buffer);
+        assert_eq!(tables.stats.total_pages, REGION_PAGES as u64);
+        assert_eq!(tables.stats.free_pages, REGION_PAGES as u64 - 4);
+
+        let pte = (0x1_0000_0000 >> 2) | PTE_AD | PTE_USER | PTE_RWV;
+        assert_eq!(tables.rmw_mapping(UVA, 0x1000, pte).unwrap(), 0);
+        assert_eq!(tables.rmw_mapping(UVA, 0xffff_ffc0_0000_2000, pte).unwrap(), 0);
+        assert_eq!(tables.rmw_mapping(UVA, 0x1000, pte | PTE_EXECUTE).unwrap(), pte);
+        // Two tables below the root for each mapping.
+        assert_eq!(tables.stats.free_pages, REGION_PAGES as u64 - 8);
+        assert_eq!(mappings(&tables, UVA), vec![(0x1000, pte | PTE_EXECUTE), (0xffff_ffc0_0000_2000, pte)]);
+        assert!(mappings(&tables, KVA).is_empty());
+
+        tables.clear_range(UVA, 0x1000, 12);
+        assert_eq!(mappings(&tables, UVA), vec![(0xffff_ffc0_0000_2000, pte)]);
+        assert_eq!(tables.stats.reclaimed, 2);
+        tables.clear_range(UVA, 0xffff_ffc0_0000_0000, 30);
+        assert!(mappings(&tables, UVA).is_empty());
+        assert_eq!(tables.stats.free_pages, REGION_PAGES as u64 - 4);
+    }
+
+    #[test]
+    fn reserved_addresses() {
+        let mut buffer = vec![Page([0; 512]); REGION_PAGES];
+        let mut tables = page_tables(&mut buffer);
+        match tables.rmw_mapping(UVA, DIRECT_MAP_OFFSET, PTE_RWV) {
+            Err(Error::ReservedAddress(va)) => assert_eq!(va, DIRECT_MAP_OFFSET),
+            _ => panic!("mapped an address in the direct map"),
+        }
+    }
+
+    #[test]
+    fn run_out_of_pages() {
+        let mut buffer = vec![Page([0; 512]); REGION_PAGES];
+        let mut tables = page_tables(&mut buffer);
+        let pte = (0x1_0000_0000 >> 2) | PTE_AD | PTE_RWV;
+
+        // Each gigabyte needs two more tables, and 12 pages are left after the roots.
+        for gigabyte in 0..6 {
+            tables.rmw_mapping(UVA, gigabyte << 30, pte).unwrap();
+        }
+        match tables.rmw_mapping(UVA, 6 << 30, pte) {
+            Err(Error::OutOfMemory) => {}
+            _ => panic!("page tables didn't run out"),
+        }
+        assert_eq!(tables.stats.exhausted, 1);
+        assert_eq!(tables.stats.peak_pages, REGION_PAGES as u64);
+
+        // Nothing is empty, so nothing can be reclaimed until mappings are removed.
+        assert_eq!(tables.reclaim_empty_tables(), 0);
+        for gigabyte in 0..3 {
+            let pte_addr = tables.pte_for_addr(UVA, gigabyte << 30).unwrap();
+            tables.clear_mapping(pte_addr);
+        }
+        assert_eq!(tables.reclaim_empty_tables(), 6);
+        tables.rmw_mapping(UVA, 6 << 30, pte).unwrap();
+    }
+
+    #[test]
+    fn walk() {
+        let mut memory = vec![0u64; 3 * 512];
+        let root = 0x8000_0000;
+        // A 1GB page at 0, and a table for the next gigabyte holding a 2MB page and a table of 4KB
+        // pages.
+        memory[0] = (0x4000_0000 >> 2) | PTE_RWXV;
+        memory[1] = ((root + 0x1000) >> 2) | PTE_VALID;
+        memory[512] = (0x2_0000_0000 >> 2) | PTE_RXV;
+        memory[513] = ((root + 0x2000) >> 2) | PTE_VALID;
+        memory[1024 + 5] = (0x3_0000_0000 >> 2) | PTE_READ | PTE_VALID;
+        memory[1024 + 6] = (0x3_0000_0000 >> 2) | PTE_WRITE | PTE_VALID;
+        memory[2] = (0x9000_0000 >> 2) | PTE_VALID;
+        let read_pte = |pa: u64| memory.get(((pa - root) / 8) as usize).cloned();
+
+        let walk = walk_page_table(root, 0x1234_5678, read_pte).unwrap();
+        assert_eq!(walk.pa, 0x5234_5678);
+        assert_eq!(walk.path.len(), 1);
+        assert_eq!(walk.path[0].level, PageTableLevel::Level1GB);
+
+        let walk = walk_page_table(root, 0x4012_3456, read_pte).unwrap();
+        assert_eq!(walk.pa, 0x2_0012_3456);
+        assert_eq!(walk.path[1].level, PageTableLevel::Level2MB);
+
+        let walk = walk_page_table(root, 0x4020_5abc, read_pte).unwrap();
+        assert_eq!(walk.pa, 0x3_0000_0abc);
+        assert_eq!(walk.path.iter().map(|pte| pte.addr).collect::<Vec<_>>(),
+                   vec![root + 8, root + 0x1000 + 8, root + 0x2000 + 5 * 8]);
+
+        // Not mapped, writable without being readable, outside memory and not sign extended.
+        assert!(walk_page_table(root, 0x4020_4000, read_pte).is_none());
+        assert!(walk_page_table(root, 0x4020_6000, read_pte).is_none());
+        assert!(walk_page_table(root, 0x8000_0000, read_pte).is_none());
+        assert!(walk_page_table(root, 1 << 40, read_pte).is_none());
+    }
+
+    #[test]
+    fn share_segments_with_the_greediest_refused() {
+        let mut wanted = [0, 3, 1, 3];
+        assert_eq!(share_segments(&mut wanted, 10), 0);
+        assert_eq!(wanted, [0, 3, 1, 3]);
+
+        assert_eq!(share_segments(&mut wanted, 4), 3);
+        assert_eq!(wanted, [0, 2, 1, 1]);
+        assert_eq!(share_segments(&mut wanted, 2), 2);
+        assert_eq!(wanted, [0, 1, 1, 0]);
+        assert_eq!(share_segments(&mut wanted, 0), 2);
+        assert_eq!(wanted, [0, 0, 0, 0]);
+        assert_eq!(share_segments(&mut [], 0), 0);
+    }
+
+    #[test]
+    fn guest_map() {
+        let map = GuestMap::new(0x8000_0000, 0x1_0000_0000, &[0x3_0000_0000, 0x2_0000_0000]);
+        assert_eq!(map.len(), 3 * HART_SEGMENT_SIZE - VM_RESERVATION_SIZE);
+
+        let second = 0x8000_0000 + HART_SEGMENT_SIZE - VM_RESERVATION_SIZE;
+        assert_eq!(map.host_pa(0x8000_0000), Some(0x1_0000_0000 + VM_RESERVATION_SIZE));
+        assert_eq!(map.host_pa(second), Some(0x3_0000_0000));
+        assert_eq!(map.host_pa(second + HART_SEGMENT_SIZE + 8), Some(0x2_0000_0008));
+        assert_eq!(map.host_pa(0x7fff_f000), None);
+        assert_eq!(map.host_pa(0x8000_0000 + map.len()), None);
+
+        assert_eq!(map.guest_pa(0x3_0000_0010), Some(second + 0x10));
+        assert_eq!(map.guest_pa(0x1_0000_0000), None);
+        assert_eq!(map.guest_pa(0x4_0000_0000), None);
+
+        assert_eq!(map.host_range(second - 0x1000, 0x1000), Some(0x1_0000_0000 + HART_SEGMENT_SIZE - 0x1000));
+        assert_eq!(map.host_range(second - 0x1000, 0x1001), None);
+    }
+}
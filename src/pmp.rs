@@ -213,4 +213,5 @@ pub fn debug_pmp() {
         lastaddress = address;
     }
     println!("================================== END CONFIGURATION STATE ==================================");
+    print::flush();
 }
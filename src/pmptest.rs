@@ -12,8 +12,8 @@ pub unsafe fn pmptest_mstart(hartid: u64, device_tree_blob: u64) {
     }
 
     // Initialize some control registers
-    csrs!(mideleg, 0x0222);
-    csrs!(medeleg, 0xb1ff);
+    csrs!(mideleg, constants::DELEGATED_INTERRUPTS);
+    csrs!(medeleg, constants::DELEGATED_EXCEPTIONS);
     csrw!(mie, 0x888);
     csrs!(mstatus, STATUS_MPP_S);
     // csrw!(mepc, sstart as u64); -- TODO!!!!!!!!!!!!!!1
@@ -0,0 +1,398 @@
+//! Performance counters for guests, exposed through the SBI PMU extension.
+//!
+//! Guests see the usual layout of logical counters: `cycle`, `time` and `instret` at indices 0 to
+//! 2, backed by the host's counters of the same name, followed by a bank of firmware counters. The
+//! host's M-mode gives no way to program `mhpmevent` selectors, so cache and branch events aren't
+//! available; asking for them fails with `SBI_ERR_NOT_SUPPORTED`. Firmware counters count events
+//! the hypervisor handles on the guest's behalf, such as timer, IPI and fence SBI calls.
+//!
+//! Each guest has a hart of its own, so the hardware counters only ever count work done for one
+//! guest (including time the hypervisor spends handling its traps). The CY and IR bits of the
+//! host's `scounteren` are cleared before a hart enters its guest (see `trap_counter_reads`), so
+//! reads of `cycle` and `instret` by the guest trap and are answered from the virtualized values,
+//! and stopping a counter or giving it an initial value only affects what that guest sees.
+//!
+//! If the host's harts implement Sscofpmf, guests are offered it as well, so that they can sample
+//! with `cycle` and `instret`: a counter raises a local counter overflow interrupt when it wraps
+//! past zero, and its bit in `scountovf` is set until it is started again. The host's overflow
+//! interrupts only come from the programmable counters, which are out of reach, so overflows are
+//! found by polling instead. From the rate at which a counter advanced since the last check, the
+//! hypervisor estimates when it will wrap and checks again then, which means the interrupt arrives
+//! somewhat after the overflow rather than right on it.
+
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::Context;
+use crate::riscv::bits::{COUNTEREN_CY, COUNTEREN_IR, IP_LCOFIP};
+use crate::sbi::*;
+use crate::timer::TimerEvent;
+
+const COUNTER_CYCLE: usize = 0;
+const COUNTER_INSTRET: usize = 2;
+const HW_COUNTERS: usize = 3;
+const FW_COUNTERS: usize = 8;
+const NUM_COUNTERS: usize = HW_COUNTERS + FW_COUNTERS;
+
+const EVENT_TYPE_HW: u64 = 0;
+const EVENT_TYPE_FW: u64 = 15;
+const HW_CPU_CYCLES: u64 = 1;
+const HW_INSTRUCTIONS: u64 = 2;
+/// Firmware event codes defined by the SBI specification go up to the HFENCE events.
+const FW_MAX_EVENT: u64 = 21;
+
+const CFG_FLAG_SKIP_MATCH: u64 = 1 << 0;
+const CFG_FLAG_CLEAR_VALUE: u64 = 1 << 1;
+const CFG_FLAG_AUTO_START: u64 = 1 << 2;
+const START_FLAG_SET_INIT_VALUE: u64 = 1 << 0;
+const STOP_FLAG_RESET: u64 = 1 << 0;
+
+/// Bounds on the time between checks for overflow, in ticks of `mtime` (10us and 10ms).
+const MIN_OVERFLOW_POLL: u64 = TIMER_FREQUENCY / 100_000;
+const MAX_OVERFLOW_POLL: u64 = TIMER_FREQUENCY / 100;
+
+/// Make the guest's reads of `cycle` and `instret` trap, whatever firmware left in `scounteren`.
+/// Called on each hart before it first enters a guest.
+pub fn trap_counter_reads() {
+    unsafe { csrc!(scounteren, COUNTEREN_CY | COUNTEREN_IR) };
+}
+
+/// Events counted by firmware counters, numbered as in the SBI specification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FirmwareEvent {
+    IllegalInsn = 4,
+    SetTimer = 5,
+    IpiSent = 6,
+    IpiReceived = 7,
+    FenceISent = 8,
+    FenceIReceived = 9,
+    SfenceVmaSent = 10,
+}
+
+#[derive(Copy, Clone)]
+struct Counter {
+    /// Event the guest configured the counter for, if any.
+    event: Option<u64>,
+    running: bool,
+    /// For firmware counters, the count. For hardware counters, the value the counter was frozen at
+    /// while stopped, or the offset from the host counter while running.
+    value: u64,
+    /// For hardware counters that will raise an overflow interrupt, how far the counter had to go
+    /// to wrap past zero when it was started.
+    period: Option<u64>,
+}
+
+pub struct Pmu {
+    counters: [Counter; NUM_COUNTERS],
+    /// Whether the guest was offered Sscofpmf.
+    overflow_interrupts: bool,
+    /// Counters that have overflowed, with the bit layout of `scountovf`.
+    overflowed: u64,
+    /// The value of `mtime` at the last check for overflow, and of each host counter polled then.
+    last_check: (u64, [u64; HW_COUNTERS]),
+}
+
+impl Pmu {
+    pub fn new(overflow_interrupts: bool) -> Self {
+        // Like the hardware counters they stand in for, cycle and instret count from reset.
+        let mut counters = [Counter { event: None, running: false, value: 0, period: None }; NUM_COUNTERS];
+        counters[COUNTER_CYCLE].running = true;
+        counters[COUNTER_INSTRET].running = true;
+        Self { counters, overflow_interrupts, overflowed: 0, last_check: (0, [0; HW_COUNTERS]) }
+    }
+
+    pub fn overflow_interrupts(&self) -> bool {
+        self.overflow_interrupts
+    }
+
+    /// Value of the guest's `scountovf`.
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// Count an occurrence of `event` on every running firmware counter configured for it.
+    pub fn record(&mut self, event: FirmwareEvent) {
+        let event_idx = EVENT_TYPE_FW << 16 | event as u64;
+        for counter in &mut self.counters[HW_COUNTERS..] {
+            if counter.running && counter.event == Some(event_idx) {
+                counter.value = counter.value.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Value of the guest's `cycle` counter.
+    pub fn cycle(&self) -> u64 {
+        self.read_hw(COUNTER_CYCLE, csrr!(cycle))
+    }
+
+    /// Value of the guest's `instret` counter.
+    pub fn instret(&self) -> u64 {
+        self.read_hw(COUNTER_INSTRET, csrr!(instret))
+    }
+
+    /// Freeze the running hardware counters at their current counts, because the guest is about
+    /// to move to another host hart (see handoff.rs). `resume` continues them from there.
+    pub fn suspend(&mut self) {
+        self.rebase();
+    }
+
+    /// Continue the hardware counters frozen by `suspend`, counting from this hart's counters.
+    pub fn resume(&mut self, time: u64) {
+        self.rebase();
+        self.last_check = (time, [csrr!(cycle), 0, csrr!(instret)]);
+    }
+
+    /// Swap the counts of running hardware counters with their offsets from the host's counters.
+    /// Since each is the host's count less the other, the same subtraction goes either way.
+    fn rebase(&mut self) {
+        for &index in &[COUNTER_CYCLE, COUNTER_INSTRET] {
+            let counter = &mut self.counters[index];
+            if counter.running {
+                counter.value = Self::host_value(index).wrapping_sub(counter.value);
+            }
+        }
+    }
+
+    fn read_hw(&self, index: usize, host: u64) -> u64 {
+        let counter = &self.counters[index];
+        if counter.running {
+            host.wrapping_sub(counter.value)
+        } else {
+            counter.value
+        }
+    }
+
+    fn host_value(index: usize) -> u64 {
+        match index {
+            COUNTER_CYCLE => csrr!(cycle),
+            COUNTER_INSTRET => csrr!(instret),
+            _ => unreachable!(),
+        }
+    }
+
+    fn start(&mut self, index: usize, initial: Option<u64>) {
+        let counter = &mut self.counters[index];
+        let value = initial.unwrap_or(counter.value);
+        counter.running = true;
+        counter.value = if index < HW_COUNTERS {
+            // `value` is the frozen count unless an initial value was given.
+            Self::host_value(index).wrapping_sub(value)
+        } else {
+            value
+        };
+
+        // A counter starting from zero would take centuries to overflow.
+        if index < HW_COUNTERS && self.overflow_interrupts {
+            counter.period = if value == 0 { None } else { Some(value.wrapping_neg()) };
+            self.overflowed &= !(1 << index);
+        }
+    }
+
+    fn stop(&mut self, index: usize) {
+        if index < HW_COUNTERS {
+            let value = self.read_hw(index, Self::host_value(index));
+            self.note_overflow(index, value);
+            self.counters[index].value = value;
+            self.counters[index].period = None;
+        }
+        self.counters[index].running = false;
+    }
+
+    /// Record an overflow of hardware counter `index` if `value` is past the end of its period.
+    /// Returns whether it overflowed just now.
+    fn note_overflow(&mut self, index: usize, value: u64) -> bool {
+        let counter = &mut self.counters[index];
+        match counter.period {
+            // The counter started at -period, so it has counted value + period since.
+            Some(period) if value.wrapping_add(period) >= period => {
+                counter.period = None;
+                self.overflowed |= 1 << index;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Check the running hardware counters for overflow at `time`. Returns whether any of them
+    /// overflowed, and when the next check is due, if one is needed.
+    fn poll(&mut self, time: u64) -> (bool, Option<u64>) {
+        let (last_time, last_host) = self.last_check;
+        let (mut overflowed, mut next) = (false, None);
+        for index in 0..HW_COUNTERS {
+            let period = match self.counters[index].period {
+                Some(period) if self.counters[index].running => period,
+                _ => continue,
+            };
+            let host = Self::host_value(index);
+            let value = self.read_hw(index, host);
+            self.last_check.1[index] = host;
+            if self.note_overflow(index, value) {
+                overflowed = true;
+                continue;
+            }
+
+            // Assume the counter keeps the rate it had since the last check.
+            let remaining = period - value.wrapping_add(period);
+            let rate = host.wrapping_sub(last_host[index]) / time.wrapping_sub(last_time).max(1);
+            let wait = (remaining / rate.max(1)).max(MIN_OVERFLOW_POLL).min(MAX_OVERFLOW_POLL);
+            next = Some(next.map_or(time + wait, |n: u64| n.min(time + wait)));
+        }
+        self.last_check.0 = time;
+        (overflowed, next)
+    }
+
+    fn supports(index: usize, event_idx: u64) -> bool {
+        let (ty, code) = ((event_idx >> 16) & 0xf, event_idx & 0xffff);
+        match (index, ty, code) {
+            (COUNTER_CYCLE, EVENT_TYPE_HW, HW_CPU_CYCLES) => true,
+            (COUNTER_INSTRET, EVENT_TYPE_HW, HW_INSTRUCTIONS) => true,
+            (i, EVENT_TYPE_FW, code) => i >= HW_COUNTERS && code <= FW_MAX_EVENT,
+            _ => false,
+        }
+    }
+}
+
+/// Expand a (counter_idx_base, counter_idx_mask) pair into counter indices. Returns None if any of
+/// them doesn't exist.
+fn selected_counters(base: u64, mask: u64) -> Option<impl Iterator<Item = usize>> {
+    let selected = (0..64).filter(move |&i| mask & (1 << i) != 0).map(move |i| base.wrapping_add(i));
+    if selected.clone().any(|i| i >= NUM_COUNTERS as u64) {
+        return None;
+    }
+    Some(selected.map(|i| i as usize))
+}
+
+/// Check the counters the guest is sampling with, raising a counter overflow interrupt if any of
+/// them overflowed, and queue the next check. Called for `TimerEvent::CounterOverflow`.
+pub fn check_overflow(state: &mut Context, time: u64) {
+    let (overflowed, next) = state.pmu.poll(time);
+    if overflowed {
+        state.csrs.sip |= IP_LCOFIP;
+        state.no_interrupt = false;
+    }
+    if let Some(deadline) = next {
+        state.timers.schedule(TimerEvent::CounterOverflow, deadline);
+    }
+}
+
+/// Handle a call to the PMU extension, returning (error, value).
+pub fn handle_call(state: &mut Context, function: u64) -> (i64, u64) {
+    let result = dispatch_call(state, function);
+
+    // Counters that were just started need checking for overflow.
+    if state.pmu.counters.iter().any(|c| c.period.is_some()) {
+        let soon = state.host_clint.get_mtime() + MIN_OVERFLOW_POLL;
+        let deadline = state.timers.deadline(TimerEvent::CounterOverflow).map_or(soon, |d| d.min(soon));
+        state.schedule_timer(TimerEvent::CounterOverflow, deadline);
+    }
+    result
+}
+
+fn dispatch_call(state: &mut Context, function: u64) -> (i64, u64) {
+    let args = [state.saved_registers.get(10), state.saved_registers.get(11),
+                state.saved_registers.get(12), state.saved_registers.get(13),
+                state.saved_registers.get(14)];
+    let pmu = &mut state.pmu;
+
+    match function {
+        // num_counters()
+        0 => (SBI_SUCCESS, NUM_COUNTERS as u64),
+        // counter_get_info(counter_idx)
+        1 => match args[0] as usize {
+            i if i < HW_COUNTERS => {
+                // CSR number in bits 0-11 and the counter width minus one in bits 12-17.
+                (SBI_SUCCESS, (crate::riscv::csr::cycle + i as u64) | 63 << 12)
+            }
+            i if i < NUM_COUNTERS => (SBI_SUCCESS, 1 << 63),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        // counter_config_matching(counter_idx_base, counter_idx_mask, config_flags, event_idx,
+        //                         event_data)
+        2 => {
+            let (flags, event_idx) = (args[2], args[3]);
+            let mut candidates = match selected_counters(args[0], args[1]) {
+                Some(candidates) => candidates,
+                None => return (SBI_ERR_INVALID_PARAM, 0),
+            };
+            let index = if flags & CFG_FLAG_SKIP_MATCH != 0 {
+                match candidates.next() {
+                    Some(i) if pmu.counters[i].event.is_some() => i,
+                    _ => return (SBI_ERR_INVALID_PARAM, 0),
+                }
+            } else {
+                match candidates.find(|&i| pmu.counters[i].event.is_none() && Pmu::supports(i, event_idx)) {
+                    Some(i) => i,
+                    None => return (SBI_ERR_NOT_SUPPORTED, 0),
+                }
+            };
+
+            if flags & CFG_FLAG_SKIP_MATCH == 0 {
+                pmu.counters[index].event = Some(event_idx);
+            }
+            if flags & CFG_FLAG_CLEAR_VALUE != 0 {
+                let running = pmu.counters[index].running;
+                pmu.stop(index);
+                pmu.counters[index].value = 0;
+                if running {
+                    pmu.start(index, None);
+                }
+            }
+            if flags & CFG_FLAG_AUTO_START != 0 && !pmu.counters[index].running {
+                pmu.start(index, None);
+            }
+            (SBI_SUCCESS, index as u64)
+        }
+        // counter_start(counter_idx_base, counter_idx_mask, start_flags, initial_value)
+        3 => {
+            let initial = if args[2] & START_FLAG_SET_INIT_VALUE != 0 { Some(args[3]) } else { None };
+            let counters = match selected_counters(args[0], args[1]) {
+                Some(counters) => counters,
+                None => return (SBI_ERR_INVALID_PARAM, 0),
+            };
+            let mut error = SBI_SUCCESS;
+            for i in counters {
+                if pmu.counters[i].event.is_none() {
+                    error = SBI_ERR_INVALID_PARAM;
+                } else if pmu.counters[i].running {
+                    // Still apply the initial value, as hardware counters start out running.
+                    if let Some(value) = initial {
+                        pmu.stop(i);
+                        pmu.start(i, Some(value));
+                    }
+                    error = SBI_ERR_ALREADY_STARTED;
+                } else {
+                    pmu.start(i, initial);
+                }
+            }
+            (error, 0)
+        }
+        // counter_stop(counter_idx_base, counter_idx_mask, stop_flags)
+        4 => {
+            let counters = match selected_counters(args[0], args[1]) {
+                Some(counters) => counters,
+                None => return (SBI_ERR_INVALID_PARAM, 0),
+            };
+            let mut error = SBI_SUCCESS;
+            for i in counters {
+                if pmu.counters[i].running {
+                    pmu.stop(i);
+                } else {
+                    error = SBI_ERR_ALREADY_STOPPED;
+                }
+                if args[2] & STOP_FLAG_RESET != 0 {
+                    pmu.counters[i].event = None;
+                }
+            }
+            (error, 0)
+        }
+        // counter_fw_read(counter_idx)
+        5 => match args[0] as usize {
+            i if i >= HW_COUNTERS && i < NUM_COUNTERS => (SBI_SUCCESS, pmu.counters[i].value),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        // counter_fw_read_hi(counter_idx) is only meaningful on RV32.
+        6 => match args[0] as usize {
+            i if i >= HW_COUNTERS && i < NUM_COUNTERS => (SBI_SUCCESS, 0),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
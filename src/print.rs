@@ -1,15 +1,27 @@
 use core::{fmt, ptr};
-use spin::MutexGuard;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::constants::TIMER_FREQUENCY;
 use crate::statics::SHARED_STATICS;
 use crate::fdt::UartType;
-use crate::pmap;
+use crate::htif::HtifConsole;
+use crate::{logtail, pmap};
+use crate::spinlock::SpinLockGuard;
 
 // see https://github.com/riscv/riscv-pk/blob/master/machine/uart16550.c
 // see: https://os.phil-opp.com/printing-to-screen
 
+/// Baud rate assumed when the UART's device tree node doesn't give one.
+pub const DEFAULT_BAUD: u64 = 115200;
+/// Depth of the transmit FIFO of a SiFive UART, which has no flag saying when it has drained.
+const SIFIVE_TX_FIFO_DEPTH: u64 = 8;
+/// How many times to poll a UART that is being flushed before giving up on it.
+const FLUSH_POLLS: usize = 1_000_000;
+
 pub enum UartWriterInner {
     Ns16550a { initialized: bool },
     SiFive,
+    /// Spike's HTIF console. The writer's address is that of `tohost`.
+    Htif(HtifConsole),
 }
 
 pub struct UartWriter {
@@ -51,6 +63,62 @@ impl UartWriterInner {
                     }
                     ptr::write_volatile(base_address, ch as u32)
                 }
+                UartWriterInner::Htif(ref mut console) => console.putchar(base_address as *mut u64, ch),
+            }
+        }
+    }
+
+    /// Wait until everything written has gone out on the wire. Gives up after `FLUSH_POLLS` polls
+    /// rather than hanging on a UART that never drains.
+    fn flush(&mut self, base_address: u64, baud: u64) {
+        unsafe {
+            match *self {
+                // Until the UART is initialized nothing can have been written to it.
+                UartWriterInner::Ns16550a { initialized: false } => {}
+                UartWriterInner::Ns16550a { initialized: true } => {
+                    // Wait for the transmitter to be empty: both the FIFO and the shift register.
+                    let base_address = base_address as *mut u8;
+                    for _ in 0..FLUSH_POLLS {
+                        if ptr::read_volatile(base_address.offset(5)) & 0x40 != 0 {
+                            break;
+                        }
+                    }
+                }
+                UartWriterInner::SiFive => {
+                    let base_address = base_address as *mut u32;
+                    for _ in 0..FLUSH_POLLS {
+                        if ptr::read_volatile(base_address) & 0x80000000 == 0 {
+                            break;
+                        }
+                    }
+                    wait_for_characters(SIFIVE_TX_FIFO_DEPTH + 1, baud);
+                }
+                // Each character is handed to the host before putchar returns.
+                UartWriterInner::Htif(_) => {}
+            }
+        }
+    }
+
+    /// Have the UART raise an interrupt whenever received data is available.
+    fn enable_rx_interrupt(&mut self, base_address: u64) {
+        unsafe {
+            match *self {
+                UartWriterInner::Ns16550a { ref mut initialized } => {
+                    let base_address = base_address as *mut u8;
+                    if !*initialized {
+                        Self::initialize_ns16550a(base_address);
+                        *initialized = true;
+                    }
+                    ptr::write_volatile(base_address.offset(1), 0x01);
+                }
+                UartWriterInner::SiFive => {
+                    // Interrupt when the receive FIFO holds more than zero entries.
+                    let base_address = base_address as *mut u32;
+                    ptr::write_volatile(base_address.offset(3), 1);
+                    ptr::write_volatile(base_address.offset(4), 0x2);
+                }
+                // HTIF has no interrupts, so input is always polled.
+                UartWriterInner::Htif(_) => {}
             }
         }
     }
@@ -73,14 +141,16 @@ impl UartWriterInner {
                     }
                 }
                 UartWriterInner::SiFive => {
+                    // The top bit of rxdata is set when the receive FIFO is empty.
                     let base_address = base_address as *mut u32;
-                    let rxdata = ptr::read_volatile(base_address);
-                    if rxdata & 0x80000000 != 0 {
+                    let rxdata = ptr::read_volatile(base_address.offset(1));
+                    if rxdata & 0x80000000 == 0 {
                         Some(rxdata as u8)
                     } else {
                         None
                     }
                 }
+                UartWriterInner::Htif(ref mut console) => console.getchar(base_address as *mut u64),
             }
         }
     }
@@ -96,11 +166,26 @@ impl UartWriter {
         self.inner.putchar(self.pa, ch);
     }
 
+    #[cfg(not(feature = "physical_symbol_addresses"))]
+    pub fn flush(&mut self) {
+        self.inner.flush(pmap::pa2va(self.pa), SHARED_STATICS.uart_baud.load(Ordering::Relaxed));
+    }
+
+    #[cfg(feature = "physical_symbol_addresses")]
+    pub fn flush(&mut self) {
+        self.inner.flush(self.pa, SHARED_STATICS.uart_baud.load(Ordering::Relaxed));
+    }
+
     pub fn getchar(&mut self) -> Option<u8> {
         self.inner.getchar(pmap::pa2va(self.pa))
     }
 
-    pub unsafe fn init(&mut self, address: u64, ty: UartType) {
+    pub fn enable_rx_interrupt(&mut self) {
+        self.inner.enable_rx_interrupt(pmap::pa2va(self.pa))
+    }
+
+    pub unsafe fn init(&mut self, address: u64, ty: UartType, baud: Option<u32>) {
+        SHARED_STATICS.uart_baud.store(baud.map_or(DEFAULT_BAUD, |b| b as u64), Ordering::Relaxed);
         if let UartWriterInner::Ns16550a { initialized: true } = self.inner {
             assert_eq!(self.pa, address);
             assert_eq!(ty, UartType::Ns16550a);
@@ -110,9 +195,20 @@ impl UartWriter {
                     initialized: false,
                 },
                 UartType::SiFive => UartWriterInner::SiFive,
+                UartType::Htif => UartWriterInner::Htif(HtifConsole::new()),
             };
             self.pa = address;
         }
+        self.update_snapshot();
+    }
+
+    fn update_snapshot(&self) {
+        let flag = match self.inner {
+            UartWriterInner::Ns16550a { .. } => 0,
+            UartWriterInner::SiFive => SNAPSHOT_SIFIVE,
+            UartWriterInner::Htif(_) => SNAPSHOT_HTIF,
+        };
+        SHARED_STATICS.uart_snapshot.store(self.pa | flag, Ordering::Release);
     }
 }
 impl fmt::Write for UartWriter {
@@ -125,28 +221,123 @@ impl fmt::Write for UartWriter {
 }
 unsafe impl Send for UartWriter {}
 
+/// Set in `Shared::uart_snapshot` if the UART is a SiFive one, or the console is HTIF. Physical
+/// addresses never have the top bits set.
+const SNAPSHOT_SIFIVE: u64 = 1 << 63;
+const SNAPSHOT_HTIF: u64 = 1 << 62;
+
+/// Wait for long enough to send `count` characters of ten bits each (with start and stop bits) at
+/// `baud`. In M-mode the `time` CSR may not be readable, so there is no wait.
+fn wait_for_characters(count: u64, baud: u64) {
+    if cfg!(feature = "physical_symbol_addresses") || baud == 0 {
+        return;
+    }
+    let deadline = csrr!(time) + count * 10 * TIMER_FREQUENCY / baud + 1;
+    while csrr!(time) < deadline {}
+}
+
+/// Writes to the UART without going through the lock in `SHARED_STATICS`, for when that lock might
+/// never be released: by a hart that panicked, or by this hart if it was interrupted partway
+/// through printing. Output may get interleaved with that of other harts.
+pub struct EmergencyWriter(UartWriter);
+impl EmergencyWriter {
+    pub fn new() -> Self {
+        let snapshot = SHARED_STATICS.uart_snapshot.load(Ordering::Acquire);
+        let inner = if snapshot & SNAPSHOT_SIFIVE != 0 {
+            UartWriterInner::SiFive
+        } else if snapshot & SNAPSHOT_HTIF != 0 {
+            UartWriterInner::Htif(HtifConsole::new())
+        } else {
+            UartWriterInner::Ns16550a { initialized: true }
+        };
+        EmergencyWriter(UartWriter { pa: snapshot & !(SNAPSHOT_SIFIVE | SNAPSHOT_HTIF), inner })
+    }
+}
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s)
+    }
+}
+
+/// Whether this hart is in the middle of `print!`. Each hart has its own copy of the hypervisor's
+/// .data section (see pmap::init), so this is per hart. In machine mode it is shared between harts,
+/// which at worst sends some output down the emergency path.
+static PRINTING: AtomicBool = AtomicBool::new(false);
+
+/// Passes everything written on to another writer, and copies it into the log tail.
+struct Mirrored<'a, W: fmt::Write>(&'a mut W);
+impl<'a, W: fmt::Write> fmt::Write for Mirrored<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        logtail::write(s.as_bytes());
+        self.0.write_str(s)
+    }
+}
+
+fn write_colored<W: fmt::Write>(writer: &mut W, args: fmt::Arguments) {
+    let color = if cfg!(feature = "physical_symbol_addresses") { "\u{1b}[31m" } else { "\u{1b}[33m" };
+    let _ = writer.write_str(color);
+    let _ = fmt::Write::write_fmt(&mut Mirrored(writer), args);
+    let _ = writer.write_str("\u{1b}[0m");
+}
+
+/// Implementation of `print!`. A nested call on the same hart (from a panic or trap while already
+/// printing) would deadlock on the UART lock, so it uses the emergency path instead.
+#[doc(hidden)]
+pub fn print_fmt(args: fmt::Arguments) {
+    if PRINTING.swap(true, Ordering::Acquire) {
+        write_colored(&mut EmergencyWriter::new(), args);
+        return;
+    }
+    write_colored(&mut *SHARED_STATICS.uart_writer.lock(), args);
+    PRINTING.store(false, Ordering::Release);
+}
+
+/// Print the message for a panic. Waits a bounded amount of time for the UART lock, since its
+/// holder could be stuck, and falls back to the emergency path if it isn't released. Returns once
+/// the message has left the UART.
+pub fn print_panic(info: &core::panic::PanicInfo) {
+    print_panic_message(info);
+    flush();
+}
+
+fn print_panic_message(info: &core::panic::PanicInfo) {
+    if !PRINTING.load(Ordering::Acquire) {
+        for _ in 0..1_000_000 {
+            if let Some(mut writer) = SHARED_STATICS.uart_writer.try_lock() {
+                write_colored(&mut *writer, format_args!("{}\n", info));
+                return;
+            }
+        }
+    }
+    write_colored(&mut EmergencyWriter::new(), format_args!("{}\n", info));
+}
+
+/// Wait until everything printed so far has left the UART, before the machine is stopped or reset
+/// and anything still in its FIFO would be lost. Doesn't take the UART lock, which a panicking hart
+/// may hold.
+pub fn flush() {
+    EmergencyWriter::new().0.flush();
+}
+
 #[macro_use]
 pub mod macros {
     #[macro_export]
     macro_rules! print {
-        ($($arg:tt)*) => ({
-            use core::fmt::Write;
-            use crate::SHARED_STATICS;
-            let mut writer = SHARED_STATICS.uart_writer.lock();
-            if cfg!(feature = "physical_symbol_addresses") {
-                writer.write_str("\u{1b}[31m").unwrap();
-            } else {
-                writer.write_str("\u{1b}[33m").unwrap();
-            }
-            writer.write_fmt(format_args!($($arg)*)).unwrap();
-            writer.write_str("\u{1b}[0m").unwrap();
-        });
+        ($($arg:tt)*) => (crate::print::print_fmt(format_args!($($arg)*)));
     }
     #[macro_export]
     macro_rules! println {
         ($fmt:expr) => (crate::print!(concat!($fmt, "\n")));
         ($fmt:expr, $($arg:tt)*) => (crate::print!(concat!($fmt, "\n"), $($arg)*));
     }
+    /// Print a line about the guest running on this hart if it is tracing `$target`, one of the
+    /// `options::TRACE_*` bits.
+    #[macro_export]
+    macro_rules! trace {
+        ($state:expr, $target:expr, $fmt:expr, $($arg:tt)*) => (if $state.options.trace & $target != 0 {
+            crate::println!(concat!("guest {}: ", $fmt), crate::hart::current().guest_index(), $($arg)*)
+        });
+    }
 }
 
 pub fn guest_println(guestid: u64, line: &[u8]) {
@@ -158,18 +349,37 @@ pub fn guest_println(guestid: u64, line: &[u8]) {
         _ => writer.write_str("\u{1b}[33m").unwrap(),
     }
     writer.write_str("\u{1b}[1m").unwrap();
-    writer.write_fmt(format_args!("[{}] ", guestid)).unwrap();
+    Mirrored(&mut *writer).write_fmt(format_args!("[{}] ", guestid)).unwrap();
     writer.write_str("\u{1b}[0m").unwrap();
     for &b in line {
         writer.putchar(b);
     }
     writer.write_str("\n").unwrap();
+    logtail::write(line);
+    logtail::write(b"\n");
 }
 
-pub fn mwriter<'a>() -> Option<MutexGuard<'a, UartWriter>> {
+pub fn mwriter<'a>() -> Option<SpinLockGuard<'a, UartWriter>> {
     SHARED_STATICS.uart_writer.try_lock()
 }
 
+/// The console used until the device tree has been parsed: QEMU's UART, or with the `htif_console`
+/// feature, Spike's HTIF.
+#[cfg(not(feature = "htif_console"))]
+pub const EARLY_UART: UartWriter = UartWriter {
+    pa: 0x10000000,
+    inner: UartWriterInner::Ns16550a { initialized: false },
+};
+#[cfg(feature = "htif_console")]
+pub const EARLY_UART: UartWriter = UartWriter {
+    pa: 0x80000000 + crate::constants::TOHOST_OFFSET,
+    inner: UartWriterInner::Htif(HtifConsole::new()),
+};
+#[cfg(not(feature = "htif_console"))]
+pub const EARLY_UART_SNAPSHOT: u64 = 0x10000000;
+#[cfg(feature = "htif_console")]
+pub const EARLY_UART_SNAPSHOT: u64 = (0x80000000 + crate::constants::TOHOST_OFFSET) | SNAPSHOT_HTIF;
+
 const QEMU_VENDOR_ID: u64 = 0x00000000;
 
 // guess whether we're likely a SiFive board or a QEMU board, for the sake of having early-boot
@@ -177,10 +387,8 @@ const QEMU_VENDOR_ID: u64 = 0x00000000;
 pub fn early_guess_uart() {
     if csrr!(mvendorid) == QEMU_VENDOR_ID {
         let mut writer = SHARED_STATICS.uart_writer.lock();
-        *writer = UartWriter {
-            pa: 0x10000000,
-            inner: UartWriterInner::Ns16550a { initialized: false },
-        }
+        *writer = EARLY_UART;
+        writer.update_snapshot();
     } else {
         // probably SiFive; just use the value already configured.
     }
@@ -0,0 +1,198 @@
+//! Cycle count histograms for the hypervisor's hot paths.
+//!
+//! With the `profile` feature, the time spent dispatching each trap, resolving each page fault and
+//! handling each virtio queue notification is measured with the `cycle` counter and recorded in
+//! per-hart histograms with power of two buckets. The monitor's `profile` command prints them.
+//! Without the feature, `start` and `Profile::record` compile to nothing.
+//!
+//! Interrupts are also measured separately for each way of entering the hypervisor (see
+//! `trap::TrapVectoring`), so that the two can be compared: run a workload with the monitor's
+//! `trapmode direct`, then again after `profile reset` and `trapmode vectored`. Likewise the SBI
+//! calls that `trap::strap_fast` handles are measured on whichever path took them, and the monitor's
+//! `fastsbi off` sends them all through `strap` instead. Neither includes saving and restoring
+//! registers, which the fast path does less of: 24 loads and stores fewer on every call.
+//!
+//! The control guest can also drive profiling itself through the RVirt SBI extension, so that a
+//! benchmark harness running in it can measure exactly the region it cares about:
+//!
+//!   * `profile_start(reset)` starts recording, first clearing the histograms if `reset` is set;
+//!   * `profile_stop()` stops recording, leaving the histograms as they are;
+//!   * `profile_read(probe, base_addr)` copies the histogram of a probe, numbered in the order of
+//!     `Probe`, to guest memory as 36 little endian u64s: samples, total cycles, min, max and then
+//!     the `BUCKETS` counts. It returns the number of samples.
+//!
+//! The histograms only cover work done on the guest's own hart.
+
+use crate::context::Context;
+use crate::sbi::{RVIRT_PROFILE_READ, RVIRT_PROFILE_START, RVIRT_PROFILE_STOP};
+use crate::sbi::{SBI_ERR_DENIED, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Probe {
+    /// Everything done in `strap`, from entry to just before returning to the guest. With vectored
+    /// traps, that is only exceptions.
+    TrapDispatch,
+    /// Resolving a guest page fault, including MMIO emulation.
+    PageFault,
+    /// Handling a write to the QueueNotify register of a passthrough virtio device.
+    VirtioNotify,
+    /// Everything done for an interrupt that entered through `strap`, which is part of
+    /// `TrapDispatch` too.
+    DirectInterrupt,
+    /// Everything done for an interrupt that entered through the vector table.
+    VectoredInterrupt,
+    /// Everything done in `strap` for an SBI call that `strap_fast` could have handled, which is
+    /// part of `TrapDispatch` too.
+    SbiCall,
+    /// Everything done in `strap_fast`.
+    FastSbiCall,
+}
+
+const PROBES: [Probe; 7] = [Probe::TrapDispatch, Probe::PageFault, Probe::VirtioNotify,
+                            Probe::DirectInterrupt, Probe::VectoredInterrupt, Probe::SbiCall,
+                            Probe::FastSbiCall];
+
+/// Bucket `i` counts samples that took between 2^i and 2^(i+1) - 1 cycles.
+const BUCKETS: usize = 32;
+
+/// Size of a histogram as copied to guest memory by `profile_read`.
+const HISTOGRAM_SIZE: u64 = (4 + BUCKETS as u64) * 8;
+
+/// Counts of samples in power of two buckets, also used for interrupt latencies (see irqlatency.rs).
+#[derive(Copy, Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    samples: u64,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self { buckets: [0; BUCKETS], samples: 0, total: 0, min: u64::max_value(), max: 0 }
+    }
+
+    pub fn record(&mut self, cycles: u64) {
+        let bucket = (64 - cycles.leading_zeros() as usize).saturating_sub(1).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.samples += 1;
+        self.total = self.total.saturating_add(cycles);
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn print(&self, unit: &str) {
+        println!("  {} samples, min {}, mean {}, max {} {}",
+                 self.samples, self.min, self.total / self.samples, self.max, unit);
+        let largest = self.buckets.iter().cloned().max().unwrap_or(1).max(1);
+        for (i, &count) in self.buckets.iter().enumerate().filter(|&(_, &c)| c > 0) {
+            print!("  {:>10} - {:<10} {:>8} ", 1u64 << i, (1u64 << (i + 1)) - 1, count);
+            for _ in 0..((count * 40 + largest - 1) / largest) {
+                print!("#");
+            }
+            println!("");
+        }
+    }
+}
+
+pub struct Profile {
+    histograms: [Histogram; 7],
+    /// Cleared while the guest has stopped profiling.
+    enabled: bool,
+}
+
+/// Read the cycle counter at the start of a measured section.
+#[inline(always)]
+pub fn start() -> u64 {
+    if cfg!(feature = "profile") {
+        csrr!(cycle)
+    } else {
+        0
+    }
+}
+
+impl Profile {
+    pub const fn new() -> Self {
+        Self { histograms: [Histogram::new(); 7], enabled: true }
+    }
+
+    /// Record the cycles taken since `start`, which should come from `profile::start`.
+    #[inline(always)]
+    pub fn record(&mut self, probe: Probe, start: u64) {
+        if cfg!(feature = "profile") && self.enabled {
+            let end = self::start();
+            self.histograms[probe as usize].record(end.wrapping_sub(start));
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.histograms = [Histogram::new(); 7];
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn report(&self) {
+        if !self.enabled {
+            println!("stopped by the guest");
+        }
+        for &probe in &PROBES {
+            let histogram = &self.histograms[probe as usize];
+            if histogram.samples > 0 {
+                println!("{:?}:", probe);
+                histogram.print("cycles");
+            }
+        }
+    }
+}
+
+/// Handle a call to one of the profiling functions of the RVirt SBI extension, returning (error,
+/// value). Only the control guest may use them.
+pub fn handle_call(state: &mut Context, function: u64) -> (i64, u64) {
+    if !cfg!(feature = "profile") {
+        return (SBI_ERR_NOT_SUPPORTED, 0);
+    }
+    if !state.control_guest {
+        return (SBI_ERR_DENIED, 0);
+    }
+
+    match function {
+        RVIRT_PROFILE_START => {
+            if state.saved_registers.get(10) != 0 {
+                state.profile.reset();
+            }
+            state.profile.set_enabled(true);
+            (SBI_SUCCESS, 0)
+        }
+        RVIRT_PROFILE_STOP => {
+            state.profile.set_enabled(false);
+            (SBI_SUCCESS, 0)
+        }
+        RVIRT_PROFILE_READ => {
+            let probe = state.saved_registers.get(10) as usize;
+            let addr = state.saved_registers.get(11);
+            if probe >= PROBES.len() {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+            if !state.prepare_guest_access(addr, HISTOGRAM_SIZE, true) {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+
+            let histogram = state.profile.histograms[probe];
+            let min = if histogram.samples == 0 { 0 } else { histogram.min };
+            let header = [histogram.samples, histogram.total, min, histogram.max];
+            for (i, value) in header.iter().chain(histogram.buckets.iter()).enumerate() {
+                let dst = state.guest_memory.slice_mut(addr + i as u64 * 8, 8);
+                dst.copy_from_slice(&value.to_le_bytes());
+            }
+            (SBI_SUCCESS, histogram.samples)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
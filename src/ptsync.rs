@@ -0,0 +1,421 @@
+//! Lazy syncing of the shadow page tables with the guest's page tables.
+//!
+//! The guest changes its page tables with ordinary stores, and the hypervisor only finds out when
+//! the sfence.vma that has to follow arrives. Without more information, a global fence has to throw
+//! away every shadow mapping, and process startup or fork then pays for refilling all of them, even
+//! though only a few page table pages changed.
+//!
+//! Instead, every guest page that a shadow mapping was derived from (each page of the guest page
+//! table walk that produced it) is tracked, and is only ever mapped read-only in the shadow page
+//! tables. The first store to a tracked page traps; the page is logged as modified and mapped
+//! writable, so further stores to it run at full speed. A global fence then removes only the shadow
+//! mappings in the address ranges that modified pages translate, write-protects those pages again
+//! and leaves every other mapping in place.
+//!
+//! A page of the guest's root page table affects every address, and so does a page found at more
+//! than one place in the guest's page tables, so modifying one of those still flushes everything.
+//! So does running out of room to track pages, or modifying more than `MAX_RANGES` pages between
+//! fences, at which point refilling the shadow page tables is no more expensive than the bookkeeping.
+//!
+//! Which workloads gain depends on how they change their page tables, so each guest picks one of
+//! three modes (`SyncMode`), at boot with `rvirt,shadow-sync` or later from the monitor:
+//!
+//!   * `Flush` doesn't track anything, and every global fence flushes the shadow page tables.
+//!   * `Lazy` is the scheme above.
+//!   * `Trap` keeps page table pages write-protected all the time. Every store to one is emulated,
+//!     and the shadow mappings that the modified entry affected are removed right away, so fences
+//!     have nothing left to do. That suits guests that change a few entries between many fences,
+//!     but each store costs a trap. Stores that can't be emulated fall back to `Lazy` for the page.
+//!
+//! Counters are kept separately for each mode, along with how long the guest spent in it, so the
+//! modes can be compared on the same workload.
+
+use arrayvec::ArrayVec;
+use crate::constants::MAX_GUEST_SEGMENTS;
+#[cfg(target_arch = "riscv64")]
+use crate::constants::TIMER_FREQUENCY;
+#[cfg(target_arch = "riscv64")]
+use crate::context::Context;
+use crate::memory_region::MemoryRegion;
+use crate::pmap::{self, *};
+#[cfg(target_arch = "riscv64")]
+use crate::riscv;
+#[cfg(target_arch = "riscv64")]
+use riscv_decode::Instruction;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_GUEST_PAGES: usize = (pmap::HART_SEGMENT_SIZE / PAGE_SIZE) as usize * MAX_GUEST_SEGMENTS;
+
+/// Number of guest page table pages that can be tracked at once.
+const MAX_TRACKED_PAGES: usize = 256;
+/// Most address ranges invalidated by one fence before falling back to a full flush.
+#[cfg(target_arch = "riscv64")]
+const MAX_RANGES: usize = 32;
+/// Most writable shadow mappings of tracked pages write-protected by one fence.
+#[cfg(target_arch = "riscv64")]
+const MAX_WRITABLE_MAPPINGS: usize = 64;
+
+/// Size (as a shift) of the range translated through a page of the guest's root page table.
+const WHOLE_ADDRESS_SPACE: u32 = 39;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    Flush,
+    Lazy,
+    Trap,
+}
+
+impl SyncMode {
+    /// Decode the mode numbers used by `rvirt,shadow-sync`.
+    pub fn from_config(mode: u32) -> Option<Self> {
+        match mode {
+            0 => Some(SyncMode::Flush),
+            1 => Some(SyncMode::Lazy),
+            2 => Some(SyncMode::Trap),
+            _ => None,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "flush" => Some(SyncMode::Flush),
+            "lazy" => Some(SyncMode::Lazy),
+            "trap" => Some(SyncMode::Trap),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PageState {
+    /// Every shadow mapping of the page is read-only.
+    Protected,
+    /// The page was given a writable shadow mapping before it was tracked, so it may have been
+    /// modified without trapping.
+    Unprotected,
+    /// The guest wrote to the page since the last fence.
+    Modified,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct TrackedPage {
+    guest_pa: u64,
+    /// Start of the range of addresses translated through this page, and log2 of its size. None if
+    /// the page was found at more than one place, so that modifying it can affect any address.
+    range: Option<(u64, u32)>,
+    state: PageState,
+}
+
+#[derive(Default)]
+pub struct SyncStats {
+    /// Time spent in the mode, in ticks of `mtime`, not counting the current stretch.
+    pub time: u64,
+    /// Shadow mappings created by page faults.
+    pub shadow_fills: u64,
+    /// Stores that trapped because they hit a write-protected page table page.
+    pub protection_faults: u64,
+    /// Stores to page table pages emulated in `Trap` mode.
+    pub emulated_writes: u64,
+    /// Global fences that found no page table page modified.
+    pub clean_fences: u64,
+    /// Global fences handled by invalidating only some address ranges.
+    pub partial_fences: u64,
+    /// Global fences that flushed the shadow page tables.
+    pub full_flushes: u64,
+    pub ranges_invalidated: u64,
+    /// Shadow mappings left in place by fences that didn't flush everything. Each would otherwise
+    /// have been refilled by a page fault.
+    pub mappings_kept: u64,
+}
+
+pub struct PageTableSync {
+    mode: SyncMode,
+    /// When the current mode was entered.
+    mode_since: u64,
+    /// Sorted by `guest_pa`.
+    pages: ArrayVec<[TrackedPage; MAX_TRACKED_PAGES]>,
+    /// Guest pages given a writable shadow mapping since the shadow page tables were last flushed.
+    writable: [u64; MAX_GUEST_PAGES / 64],
+    /// Set if a page couldn't be tracked, in which case the next fence has to flush everything.
+    overflowed: bool,
+    /// Indexed by `SyncMode`.
+    stats: [SyncStats; 3],
+}
+
+fn page_index(guest_memory: &MemoryRegion, guest_pa: u64) -> usize {
+    ((guest_pa - guest_memory.base()) / PAGE_SIZE) as usize
+}
+
+impl PageTableSync {
+    pub fn new() -> Self {
+        Self {
+            mode: SyncMode::Lazy,
+            mode_since: 0,
+            pages: ArrayVec::new(),
+            writable: [0; MAX_GUEST_PAGES / 64],
+            overflowed: false,
+            stats: [SyncStats::default(), SyncStats::default(), SyncStats::default()],
+        }
+    }
+
+    /// Set the mode the guest starts in, at time `now`.
+    pub fn start(&mut self, mode: SyncMode, now: u64) {
+        self.mode = mode;
+        self.mode_since = now;
+    }
+
+    fn stats(&mut self) -> &mut SyncStats {
+        &mut self.stats[self.mode as usize]
+    }
+
+    /// Whether a store to `guest_pa` has to be emulated by `emulate_write`.
+    pub fn traps_writes(&self, guest_pa: u64) -> bool {
+        self.mode == SyncMode::Trap
+            && self.find(guest_pa).map(|i| self.pages[i].state != PageState::Modified).unwrap_or(false)
+    }
+
+    /// Whether the page holding `guest_pa` is one of the guest's page tables.
+    pub fn tracks(&self, guest_pa: u64) -> bool {
+        self.find(guest_pa).is_ok()
+    }
+
+    fn find(&self, guest_pa: u64) -> core::result::Result<usize, usize> {
+        self.pages.binary_search_by_key(&(guest_pa & !(PAGE_SIZE - 1)), |p| p.guest_pa)
+    }
+
+    fn was_writable(&self, guest_memory: &MemoryRegion, guest_pa: u64) -> bool {
+        let index = page_index(guest_memory, guest_pa);
+        self.writable[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Track the guest page table page at `table_pa`, found at `depth` (zero for the root) while
+    /// translating `va`.
+    pub fn track(&mut self, guest_memory: &MemoryRegion, table_pa: u64, va: u64, depth: usize) {
+        if self.mode == SyncMode::Flush {
+            return;
+        }
+        let shift = WHOLE_ADDRESS_SPACE - 9 * depth as u32;
+        let va = if shift == WHOLE_ADDRESS_SPACE { 0 } else { va & !((1 << shift) - 1) };
+        match self.find(table_pa) {
+            Ok(i) => {
+                let page = &mut self.pages[i];
+                if page.range != Some((va, shift)) {
+                    page.range = None;
+                }
+            }
+            Err(_) if self.pages.is_full() => self.overflowed = true,
+            Err(i) => {
+                let state = if self.was_writable(guest_memory, table_pa) {
+                    PageState::Unprotected
+                } else {
+                    PageState::Protected
+                };
+                let guest_pa = table_pa & !(PAGE_SIZE - 1);
+                self.pages.insert(i, TrackedPage { guest_pa, range: Some((va, shift)), state });
+            }
+        }
+    }
+
+    /// Adjust the permissions `perm` of a new shadow mapping of `guest_pa`: tracked pages are
+    /// mapped read-only unless this is a store to one, which marks it as modified.
+    pub fn filter_permissions(&mut self, guest_memory: &MemoryRegion, guest_pa: u64, write: bool, perm: u64) -> u64 {
+        self.stats().shadow_fills += 1;
+        if self.mode == SyncMode::Flush {
+            return perm;
+        }
+        let perm = match self.find(guest_pa) {
+            Ok(i) if write => {
+                if self.pages[i].state != PageState::Modified {
+                    self.pages[i].state = PageState::Modified;
+                    self.stats().protection_faults += 1;
+                }
+                perm
+            }
+            Ok(i) if self.pages[i].state != PageState::Modified => perm & !PTE_WRITE,
+            _ => perm,
+        };
+        if perm & PTE_WRITE != 0 {
+            let index = page_index(guest_memory, guest_pa);
+            self.writable[index / 64] |= 1 << (index % 64);
+        }
+        perm
+    }
+
+    /// Called when every shadow mapping has been removed, leaving nothing to write-protect.
+    pub fn flushed(&mut self) {
+        if self.overflowed {
+            self.pages.clear();
+            self.overflowed = false;
+        }
+        for page in self.pages.iter_mut() {
+            page.state = PageState::Protected;
+        }
+        for word in self.writable.iter_mut() {
+            *word = 0;
+        }
+    }
+}
+
+/// Switch the guest to another mode. The shadow page tables are flushed, since the new mode can't
+/// rely on what the old one tracked.
+#[cfg(target_arch = "riscv64")]
+pub fn set_mode(state: &mut Context, mode: SyncMode) {
+    let now = state.host_clint.get_mtime();
+    let sync = &mut state.shadow_page_tables.sync;
+    let since = sync.mode_since;
+    sync.stats().time += now.saturating_sub(since);
+    sync.mode = mode;
+    sync.mode_since = now;
+    sync.pages.clear();
+    sync.overflowed = false;
+    flush_shadow_page_table(&mut state.shadow_page_tables);
+}
+
+/// In `Trap` mode, apply a store to the guest page table page at `guest_pa` and remove the shadow
+/// mappings derived from the entry it modified. Returns false if the instruction can't be emulated,
+/// in which case the caller maps the page writable and the page is handled as in `Lazy` mode.
+#[cfg(target_arch = "riscv64")]
+pub fn emulate_write(state: &mut Context, guest_pa: u64, instruction: Option<u32>) -> bool {
+    let instruction = match instruction {
+        Some(instruction) if guest_pa % 8 == 0 => instruction,
+        _ => return false,
+    };
+    let old = state.guest_memory[guest_pa];
+    let (new, rd) = match riscv_decode::decode(instruction) {
+        Ok(Instruction::Sd(i)) => (state.saved_registers.get(i.rs2()), None),
+        Ok(Instruction::AmoswapD(i)) => (state.saved_registers.get(i.rs2()), Some(i.rd())),
+        Ok(Instruction::AmoandD(i)) => (old & state.saved_registers.get(i.rs2()), Some(i.rd())),
+        Ok(Instruction::AmoorD(i)) => (old | state.saved_registers.get(i.rs2()), Some(i.rd())),
+        _ => return false,
+    };
+    state.guest_memory[guest_pa] = new;
+    state.dirty.mark(&state.guest_memory, guest_pa);
+    if let Some(rd) = rd {
+        state.saved_registers.set(rd, old);
+    }
+
+    let tables = &mut state.shadow_page_tables;
+    let page = tables.sync.find(guest_pa).ok().map(|i| tables.sync.pages[i]);
+    tables.sync.stats().emulated_writes += 1;
+    if old != new {
+        match page.and_then(|p| p.range) {
+            Some((va, shift)) => {
+                let entry_shift = shift - 9;
+                let mut entry_va = va | (((guest_pa & 0xfff) / 8) << entry_shift);
+                if entry_va & (1 << 38) != 0 {
+                    entry_va |= !0 << 39;
+                }
+                for &root in &[PageTableRoot::UVA, PageTableRoot::KVA, PageTableRoot::MVA] {
+                    tables.clear_range(root, entry_va, entry_shift);
+                }
+                riscv::sfence_vma();
+            }
+            None => flush_shadow_page_table(tables),
+        }
+    }
+
+    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+    true
+}
+
+/// Handle a global sfence.vma from the guest.
+#[cfg(target_arch = "riscv64")]
+pub fn fence(state: &mut Context) {
+    let guest_map = &state.guest_map;
+    let guest_memory = &state.guest_memory;
+    let tables = &mut state.shadow_page_tables;
+
+    let mut ranges = ArrayVec::<[(u64, u32); MAX_RANGES]>::new();
+    let mut flush = tables.sync.overflowed;
+    for page in tables.sync.pages.iter().filter(|p| p.state != PageState::Protected) {
+        match page.range {
+            Some((va, shift)) if shift < WHOLE_ADDRESS_SPACE && ranges.try_push((va, shift)).is_ok() => {}
+            _ => {
+                flush = true;
+                break;
+            }
+        }
+    }
+    if tables.sync.mode == SyncMode::Flush {
+        flush = true;
+    } else if !flush && ranges.is_empty() {
+        tables.sync.stats().clean_fences += 1;
+        return;
+    }
+
+    if !flush {
+        for &root in &[PageTableRoot::UVA, PageTableRoot::KVA, PageTableRoot::MVA] {
+            for &(va, shift) in &ranges {
+                tables.clear_range(root, va, shift);
+            }
+        }
+
+        // Find the writable mappings of the pages that are about to be protected again.
+        let mut writable = ArrayVec::<[u64; MAX_WRITABLE_MAPPINGS]>::new();
+        let mut mappings = 0;
+        let sync = &tables.sync;
+        for &root in &[PageTableRoot::UVA, PageTableRoot::KVA, PageTableRoot::MVA] {
+            tables.for_each_mapping(root, |_, pte_addr, pte| {
+                mappings += 1;
+                if pte & PTE_WRITE == 0 {
+                    return;
+                }
+                let tracked = guest_map.guest_pa((pte & PTE_PPN_MASK) << 2)
+                    .and_then(|guest_pa| sync.find(guest_pa).ok());
+                if tracked.is_some() && writable.try_push(pte_addr).is_err() {
+                    flush = true;
+                }
+            });
+        }
+
+        if !flush {
+            for &pte_addr in &writable {
+                tables.write_protect(pte_addr);
+            }
+            for page in tables.sync.pages.iter_mut() {
+                page.state = PageState::Protected;
+            }
+            for i in 0..tables.sync.pages.len() {
+                let index = page_index(guest_memory, tables.sync.pages[i].guest_pa);
+                tables.sync.writable[index / 64] &= !(1 << (index % 64));
+            }
+            let stats = tables.sync.stats();
+            stats.partial_fences += 1;
+            stats.ranges_invalidated += ranges.len() as u64;
+            stats.mappings_kept += mappings;
+            riscv::sfence_vma();
+            return;
+        }
+    }
+
+    tables.sync.stats().full_flushes += 1;
+    flush_shadow_page_table(tables);
+}
+
+/// Print the counters of every mode the guest has spent time in, with rates per second so that
+/// modes tried for different lengths of time can be compared.
+#[cfg(target_arch = "riscv64")]
+pub fn report(state: &Context) {
+    let sync = &state.shadow_page_tables.sync;
+    let now = state.host_clint.get_mtime();
+    println!("mode: {:?}, {}/{} page table pages tracked", sync.mode, sync.pages.len(), MAX_TRACKED_PAGES);
+    for &mode in &[SyncMode::Flush, SyncMode::Lazy, SyncMode::Trap] {
+        let stats = &sync.stats[mode as usize];
+        let mut time = stats.time;
+        if mode == sync.mode {
+            time += now.saturating_sub(sync.mode_since);
+        }
+        if time == 0 {
+            continue;
+        }
+        let per_second = |count: u64| count * TIMER_FREQUENCY / time;
+        let fences = stats.clean_fences + stats.partial_fences + stats.full_flushes;
+        println!("{:?}: {} ms", mode, time * 1000 / TIMER_FREQUENCY);
+        println!("  shadow fills:      {} ({}/s)", stats.shadow_fills, per_second(stats.shadow_fills));
+        println!("  protection faults: {} ({}/s)", stats.protection_faults, per_second(stats.protection_faults));
+        println!("  emulated writes:   {} ({}/s)", stats.emulated_writes, per_second(stats.emulated_writes));
+        println!("  fences:            {} ({}/s): {} clean, {} partial, {} full", fences, per_second(fences),
+                 stats.clean_fences, stats.partial_fences, stats.full_flushes);
+        println!("  ranges invalidated: {}, mappings kept: {}", stats.ranges_invalidated, stats.mappings_kept);
+    }
+}
@@ -0,0 +1,118 @@
+//! Consistency checks for the shadow page tables.
+//!
+//! Every leaf in the UVA, KVA and MVA shadow page tables should be derived from a mapping in the
+//! guest's own page tables, as created by `pfault::handle_page_fault`. The verifier walks the shadow
+//! tables and re-translates each mapped address through the guest page tables to make sure that is
+//! still the case.
+
+use arrayvec::ArrayVec;
+use crate::context::Context;
+use crate::pmap::{self, *};
+use crate::riscv::bits::{SATP_MODE, SATP_PPN};
+
+/// Maximum number of problems reported (and repaired) by a single pass.
+const MAX_ISSUES: usize = 64;
+
+#[derive(Copy, Clone, Debug)]
+enum Problem {
+    GuestPagingDisabled,
+    NoGuestMapping,
+    NotGuestMemory,
+    ExcessPermissions,
+    UserBitMismatch,
+    WrongTarget,
+    SupervisorMapping,
+}
+
+struct Issue {
+    root: PageTableRoot,
+    va: u64,
+    pte_addr: u64,
+    pte: u64,
+    problem: Problem,
+}
+
+/// Check every shadow mapping of the current guest, printing any problems found. If `repair` is
+/// set, offending mappings are removed so that they get recreated by the next page fault. Returns
+/// the number of problems found.
+pub fn verify(state: &mut Context, repair: bool) -> usize {
+    let mut issues: ArrayVec<[Issue; MAX_ISSUES]> = ArrayVec::new();
+    let mut mappings = 0;
+    let mut found = 0;
+
+    for &root in &[PageTableRoot::UVA, PageTableRoot::KVA, PageTableRoot::MVA] {
+        state.shadow_page_tables.for_each_mapping(root, |va, pte_addr, pte| {
+            mappings += 1;
+            match check_mapping(state, root, va, pte) {
+                Ok(()) => {}
+                Err(problem) => {
+                    found += 1;
+                    let _ = issues.try_push(Issue { root, va, pte_addr, pte, problem });
+                }
+            }
+        });
+    }
+
+    for issue in &issues {
+        println!("ptcheck: {:?} va={:#x} pte={:#x}: {:?}", issue.root, issue.va, issue.pte, issue.problem);
+    }
+    if found > issues.len() {
+        println!("ptcheck: {} more problems not shown", found - issues.len());
+    }
+    println!("ptcheck: {} mappings checked, {} problems", mappings, found);
+
+    if repair && !issues.is_empty() {
+        for issue in &issues {
+            state.shadow_page_tables.clear_mapping(issue.pte_addr);
+        }
+        crate::riscv::sfence_vma();
+        println!("ptcheck: removed {} mappings", issues.len());
+    }
+
+    found
+}
+
+fn check_mapping(state: &Context, root: PageTableRoot, va: u64, pte: u64) -> Result<(), Problem> {
+    // The hypervisor only maps itself in the top 16GB of the address space. Anything below that
+    // without the U bit would be hypervisor memory exposed to the guest's range of addresses.
+    if pte & PTE_USER == 0 {
+        return Err(Problem::SupervisorMapping);
+    }
+    if state.csrs.satp & SATP_MODE == 0 {
+        return Err(Problem::GuestPagingDisabled);
+    }
+
+    let guest_root = (state.csrs.satp & SATP_PPN) << 12;
+    let walk = match pmap::walk_page_table(guest_root, va, |pa| state.guest_memory.get(pa)) {
+        Some(walk) => walk,
+        None => return Err(Problem::NoGuestMapping),
+    };
+    let guest_pte = walk.path[walk.path.len() - 1].value;
+    let guest_pa = walk.pa & !0xfff;
+
+    if !state.guest_memory.in_region(guest_pa) {
+        return Err(Problem::NotGuestMemory);
+    }
+
+    let mut allowed = guest_pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE);
+    if guest_pte & PTE_DIRTY == 0 {
+        allowed &= !PTE_WRITE;
+    }
+    if pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE) & !allowed != 0 {
+        return Err(Problem::ExcessPermissions);
+    }
+
+    let user = guest_pte & PTE_USER != 0;
+    match root {
+        PageTableRoot::UVA if !user => return Err(Problem::UserBitMismatch),
+        PageTableRoot::KVA if user => return Err(Problem::UserBitMismatch),
+        _ => {}
+    }
+
+    let host_pa = (pte & PTE_PPN_MASK) << 2;
+    if Some(host_pa) != state.guest_map.host_pa(guest_pa) {
+        return Err(Problem::WrongTarget);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,463 @@
+//! Reading and writing qcow2 disk images, so guests can boot from the images that cloud providers
+//! and QEMU tooling produce without converting them to raw disks first.
+//!
+//! A qcow2 image maps the guest's virtual disk onto clusters of the host disk through a two level
+//! table: each L1 entry points to an L2 table, and each L2 entry to the cluster holding the data.
+//! Clusters without an L2 entry read as zeroes. Every cluster in use has a reference count, kept in
+//! refcount blocks that are themselves found through the refcount table.
+//!
+//! Images with backing files, encryption, compression or any incompatible feature other than the
+//! dirty bit are refused. Images with internal snapshots, a set dirty bit or refcounts other than
+//! 16 bits wide can only be read. Writes allocate clusters so that a crash at any point leaves an
+//! image that is consistent, though perhaps with leaked clusters: the refcount of a new cluster is
+//! set first, then the cluster is zeroed, and only then is it linked into the tables. A barrier
+//! on the host disk comes before the link, since its write cache could otherwise reorder them.
+//!
+//! Metadata is read and written a sector at a time through a one sector cache, which is written
+//! through so that the host disk always holds the latest metadata.
+
+use byteorder::{BigEndian, ByteOrder};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::error::{Error, Result};
+
+const MAGIC: u32 = 0x514649fb;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+
+/// Bits 9 to 55 of table entries hold the offset of a cluster in the image.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Set in L1 and L2 entries whose cluster has a refcount of exactly one.
+const ENTRY_COPIED: u64 = 1 << 63;
+const L2_ENTRY_COMPRESSED: u64 = 1 << 62;
+/// Set in L2 entries of clusters that read as zeroes, whether or not they have a host cluster.
+const L2_ENTRY_ZERO: u64 = 1 << 0;
+
+const HEADER_AUTOCLEAR_FEATURES: u64 = 88;
+
+/// Source of zeroes for filling new clusters.
+static ZEROES: [u8; 4096] = [0; 4096];
+
+/// Layout of an image, as read from its header.
+pub struct Header {
+    cluster_bits: u32,
+    size: u64,
+    l1_table_offset: u64,
+    l1_size: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u64,
+    autoclear_features: u64,
+    writable: bool,
+}
+
+impl Header {
+    /// Read the header at the start of `disk`. Returns None if the disk doesn't hold a qcow2 image.
+    pub fn read<D: BlockDevice>(disk: &mut D) -> Result<Option<Self>> {
+        let mut header = [0u8; SECTOR_SIZE as usize];
+        disk.read(0, &mut header)?;
+        if BigEndian::read_u32(&header[0..]) != MAGIC {
+            return Ok(None);
+        }
+
+        let version = BigEndian::read_u32(&header[4..]);
+        let backing_file_offset = BigEndian::read_u64(&header[8..]);
+        let cluster_bits = BigEndian::read_u32(&header[20..]);
+        let size = BigEndian::read_u64(&header[24..]);
+        let crypt_method = BigEndian::read_u32(&header[32..]);
+        let l1_size = BigEndian::read_u32(&header[36..]) as u64;
+        let l1_table_offset = BigEndian::read_u64(&header[40..]);
+        let refcount_table_offset = BigEndian::read_u64(&header[48..]);
+        let refcount_table_clusters = BigEndian::read_u32(&header[56..]) as u64;
+        let nb_snapshots = BigEndian::read_u32(&header[60..]);
+        let (incompatible_features, autoclear_features, refcount_order) = match version {
+            2 => (0, 0, 4),
+            3 => (BigEndian::read_u64(&header[72..]), BigEndian::read_u64(&header[HEADER_AUTOCLEAR_FEATURES as usize..]),
+                  BigEndian::read_u32(&header[96..])),
+            _ => {
+                println!("qcow2: unsupported version {}", version);
+                return Err(Error::InvalidDiskImage);
+            }
+        };
+
+        let unsupported = if backing_file_offset != 0 {
+            Some("backing files")
+        } else if crypt_method != 0 {
+            Some("encryption")
+        } else if incompatible_features & !INCOMPAT_DIRTY != 0 {
+            Some("incompatible features")
+        } else if cluster_bits < 9 || cluster_bits > 21 {
+            Some("cluster size")
+        } else {
+            None
+        };
+        if let Some(feature) = unsupported {
+            println!("qcow2: image uses unsupported {}", feature);
+            return Err(Error::InvalidDiskImage);
+        }
+
+        // Each L2 table holds one cluster's worth of 8 byte entries.
+        let l2_coverage = 1u64 << (2 * cluster_bits - 3);
+        let cluster_mask = (1u64 << cluster_bits) - 1;
+        if l1_size < (size + l2_coverage - 1) / l2_coverage || l1_table_offset & cluster_mask != 0
+            || refcount_table_offset & cluster_mask != 0 || l1_table_offset == 0 || refcount_table_offset == 0 {
+            println!("qcow2: malformed header");
+            return Err(Error::InvalidDiskImage);
+        }
+
+        let read_only_reason = if incompatible_features & INCOMPAT_DIRTY != 0 {
+            Some("its refcounts need repair")
+        } else if nb_snapshots != 0 {
+            Some("it has snapshots")
+        } else if refcount_order != 4 {
+            Some("its refcounts aren't 16 bits wide")
+        } else {
+            None
+        };
+        if let Some(reason) = read_only_reason {
+            println!("qcow2: image is read-only because {}", reason);
+        }
+
+        Ok(Some(Self {
+            cluster_bits,
+            size,
+            l1_table_offset,
+            l1_size,
+            refcount_table_offset,
+            refcount_table_clusters,
+            autoclear_features,
+            writable: read_only_reason.is_none(),
+        }))
+    }
+}
+
+/// Where a cluster of the virtual disk is stored.
+enum Mapping {
+    Unallocated,
+    Zero,
+    Compressed,
+    Data(u64),
+}
+
+pub struct Qcow2<D: BlockDevice> {
+    disk: D,
+    header: Header,
+    /// Cluster to start looking for free clusters from. Clusters are never freed, so everything
+    /// below it is in use.
+    next_free: u64,
+    cache: [u8; SECTOR_SIZE as usize],
+    cache_sector: Option<u64>,
+}
+
+impl<D: BlockDevice> Qcow2<D> {
+    pub fn new(disk: D, header: Header) -> Self {
+        Self { disk, header, next_free: 0, cache: [0; SECTOR_SIZE as usize], cache_sector: None }
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.header.cluster_bits
+    }
+
+    /// Load the sector holding `offset` into the cache, returning the offset within it.
+    fn load(&mut self, offset: u64) -> Result<usize> {
+        let sector = offset / SECTOR_SIZE;
+        if self.cache_sector != Some(sector) {
+            self.cache_sector = None;
+            self.disk.read(sector, &mut self.cache)?;
+            self.cache_sector = Some(sector);
+        }
+        Ok((offset % SECTOR_SIZE) as usize)
+    }
+
+    fn store(&mut self) -> Result<()> {
+        let sector = self.cache_sector.unwrap();
+        let result = self.disk.write(sector, &self.cache);
+        if result.is_err() {
+            self.cache_sector = None;
+        }
+        result
+    }
+
+    fn read_u64(&mut self, offset: u64) -> Result<u64> {
+        let i = self.load(offset)?;
+        Ok(BigEndian::read_u64(&self.cache[i..]))
+    }
+
+    fn write_u64(&mut self, offset: u64, value: u64) -> Result<()> {
+        let i = self.load(offset)?;
+        BigEndian::write_u64(&mut self.cache[i..], value);
+        self.store()
+    }
+
+    fn read_u16(&mut self, offset: u64) -> Result<u16> {
+        let i = self.load(offset)?;
+        Ok(BigEndian::read_u16(&self.cache[i..]))
+    }
+
+    fn write_u16(&mut self, offset: u64, value: u16) -> Result<()> {
+        let i = self.load(offset)?;
+        BigEndian::write_u16(&mut self.cache[i..], value);
+        self.store()
+    }
+
+    /// Offset of the L1 entry and of the L2 entry within its table for a virtual disk offset.
+    fn table_offsets(&self, offset: u64) -> Result<(u64, u64)> {
+        let bits = self.header.cluster_bits;
+        let l1_index = offset >> (2 * bits - 3);
+        if l1_index >= self.header.l1_size {
+            return Err(Error::InvalidDiskImage);
+        }
+        let l2_index = (offset >> bits) & ((1 << (bits - 3)) - 1);
+        Ok((self.header.l1_table_offset + 8 * l1_index, 8 * l2_index))
+    }
+
+    fn lookup(&mut self, offset: u64) -> Result<Mapping> {
+        let (l1_entry, l2_entry) = self.table_offsets(offset)?;
+        let l2_table = self.read_u64(l1_entry)? & OFFSET_MASK;
+        if l2_table == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        let entry = self.read_u64(l2_table + l2_entry)?;
+        Ok(if entry & L2_ENTRY_COMPRESSED != 0 {
+            Mapping::Compressed
+        } else if entry & L2_ENTRY_ZERO != 0 {
+            Mapping::Zero
+        } else if entry & OFFSET_MASK == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data(entry & OFFSET_MASK)
+        })
+    }
+
+    /// Offset of the 16 bit refcount of a cluster, or None if no refcount block covers it yet.
+    fn refcount_offset(&mut self, cluster: u64) -> Result<Option<u64>> {
+        let per_block_bits = self.header.cluster_bits - 1;
+        let block_index = cluster >> per_block_bits;
+        if block_index >= self.header.refcount_table_clusters << (self.header.cluster_bits - 3) {
+            // Growing the refcount table would mean moving it.
+            println!("qcow2: refcount table is full");
+            return Err(Error::InvalidDiskImage);
+        }
+        let block = self.read_u64(self.header.refcount_table_offset + 8 * block_index)? & OFFSET_MASK;
+        Ok(match block {
+            0 => None,
+            block => Some(block + 2 * (cluster & ((1 << per_block_bits) - 1))),
+        })
+    }
+
+    fn zero_cluster(&mut self, offset: u64) -> Result<()> {
+        let sectors = self.cluster_size() / SECTOR_SIZE;
+        let sector = offset / SECTOR_SIZE;
+        for s in (sector..sector + sectors).step_by(ZEROES.len() / SECTOR_SIZE as usize) {
+            let n = (sector + sectors - s).min(ZEROES.len() as u64 / SECTOR_SIZE);
+            self.disk.write(s, &ZEROES[..(n * SECTOR_SIZE) as usize])?;
+        }
+        if self.cache_sector.map_or(false, |s| s >= sector && s < sector + sectors) {
+            self.cache_sector = None;
+        }
+        Ok(())
+    }
+
+    /// Claim a free cluster of the host disk and fill it with zeroes, returning its offset.
+    fn allocate_cluster(&mut self) -> Result<u64> {
+        loop {
+            let cluster = self.next_free;
+            let offset = cluster << self.header.cluster_bits;
+            if offset + self.cluster_size() > self.disk.sectors() * SECTOR_SIZE {
+                println!("qcow2: host disk is full");
+                return Err(Error::DeviceIo);
+            }
+            self.next_free += 1;
+
+            match self.refcount_offset(cluster)? {
+                Some(refcount) => {
+                    if self.read_u16(refcount)? == 0 {
+                        self.write_u16(refcount, 1)?;
+                        self.zero_cluster(offset)?;
+                        self.disk.barrier()?;
+                        return Ok(offset);
+                    }
+                }
+                None => {
+                    // Turn the cluster into the missing refcount block, counting itself, and keep
+                    // looking.
+                    let per_block_bits = self.header.cluster_bits - 1;
+                    let table_entry = self.header.refcount_table_offset + 8 * (cluster >> per_block_bits);
+                    self.zero_cluster(offset)?;
+                    self.write_u16(offset + 2 * (cluster & ((1 << per_block_bits) - 1)), 1)?;
+                    self.disk.barrier()?;
+                    self.write_u64(table_entry, offset)?;
+                }
+            }
+        }
+    }
+
+    /// Offset of the host cluster backing the cluster of the virtual disk at `offset`, allocating
+    /// it and any L2 table it needs.
+    fn cluster_for_write(&mut self, offset: u64) -> Result<u64> {
+        let (l1_entry, l2_entry) = self.table_offsets(offset)?;
+        let mut l2_table = self.read_u64(l1_entry)? & OFFSET_MASK;
+        if l2_table == 0 {
+            l2_table = self.allocate_cluster()?;
+            self.write_u64(l1_entry, l2_table | ENTRY_COPIED)?;
+        }
+
+        let entry = self.read_u64(l2_table + l2_entry)?;
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            println!("qcow2: writing to compressed clusters isn't supported");
+            return Err(Error::InvalidDiskImage);
+        }
+        if entry & L2_ENTRY_ZERO == 0 && entry & OFFSET_MASK != 0 {
+            return Ok(entry & OFFSET_MASK);
+        }
+
+        // A cluster preallocated for a zero entry is left behind rather than zeroed in place, which
+        // only leaks it.
+        let cluster = self.allocate_cluster()?;
+        self.write_u64(l2_table + l2_entry, cluster | ENTRY_COPIED)?;
+        Ok(cluster)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Qcow2<D> {
+    fn sectors(&self) -> u64 {
+        self.header.size / SECTOR_SIZE
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = sector * SECTOR_SIZE + done as u64;
+            let in_cluster = offset & (self.cluster_size() - 1);
+            let n = (self.cluster_size() - in_cluster).min((buf.len() - done) as u64) as usize;
+            let chunk = &mut buf[done..done + n];
+            match self.lookup(offset)? {
+                Mapping::Data(cluster) => self.disk.read((cluster + in_cluster) / SECTOR_SIZE, chunk)?,
+                Mapping::Unallocated | Mapping::Zero => {
+                    for byte in chunk {
+                        *byte = 0;
+                    }
+                }
+                Mapping::Compressed => {
+                    println!("qcow2: compressed clusters aren't supported");
+                    return Err(Error::InvalidDiskImage);
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        if !self.header.writable {
+            return Err(Error::InvalidDiskImage);
+        }
+        // Features this code doesn't know about have to be marked as invalid once the image is
+        // changed.
+        if self.header.autoclear_features != 0 {
+            self.write_u64(HEADER_AUTOCLEAR_FEATURES, 0)?;
+            self.header.autoclear_features = 0;
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = sector * SECTOR_SIZE + done as u64;
+            let in_cluster = offset & (self.cluster_size() - 1);
+            let n = (self.cluster_size() - in_cluster).min((buf.len() - done) as u64) as usize;
+            let cluster = self.cluster_for_write(offset)?;
+            self.disk.write((cluster + in_cluster) / SECTOR_SIZE, &buf[done..done + n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::{MockDisk, Op};
+
+    /// A version 3 image with 512 byte clusters and a 64KB virtual disk, on a 64 sector host disk.
+    /// Clusters 0 to 3 hold the header, the L1 table, the refcount table and the one refcount block.
+    fn image() -> MockDisk {
+        let mut disk = MockDisk::new(64);
+        let header = &mut disk.data[..512];
+        BigEndian::write_u32(&mut header[0..], MAGIC);
+        BigEndian::write_u32(&mut header[4..], 3);
+        BigEndian::write_u32(&mut header[20..], 9);
+        BigEndian::write_u64(&mut header[24..], 64 << 10);
+        BigEndian::write_u32(&mut header[36..], 2);
+        BigEndian::write_u64(&mut header[40..], 512);
+        BigEndian::write_u64(&mut header[48..], 1024);
+        BigEndian::write_u32(&mut header[56..], 1);
+        BigEndian::write_u32(&mut header[96..], 4);
+        BigEndian::write_u64(&mut disk.data[1024..], 1536);
+        for cluster in 0..4 {
+            BigEndian::write_u16(&mut disk.data[1536 + 2 * cluster..], 1);
+        }
+        disk
+    }
+
+    fn open(disk: MockDisk) -> Qcow2<MockDisk> {
+        let mut disk = disk;
+        let header = Header::read(&mut disk).unwrap().unwrap();
+        Qcow2::new(disk, header)
+    }
+
+    #[test]
+    fn clusters_are_linked_after_a_barrier() {
+        let mut qcow2 = open(image());
+        qcow2.write(3, &[0xab; 512]).unwrap();
+
+        // The L2 table goes in cluster 4 and the data in cluster 5. Each has its refcount set and
+        // is zeroed before the barrier, and only then linked into the L1 or L2 table.
+        assert_eq!(qcow2.disk.ops, [
+            Op::Write(3, 1), Op::Write(4, 1), Op::Barrier, Op::Write(1, 1),
+            Op::Write(3, 1), Op::Write(5, 1), Op::Barrier, Op::Write(4, 1),
+            Op::Write(5, 1),
+        ]);
+        assert_eq!(BigEndian::read_u64(&qcow2.disk.data[512..]), 2048 | ENTRY_COPIED);
+        assert_eq!(BigEndian::read_u64(&qcow2.disk.data[2048 + 8 * 3..]), 2560 | ENTRY_COPIED);
+        assert_eq!(BigEndian::read_u16(&qcow2.disk.data[1536 + 2 * 5..]), 1);
+
+        // Writing the cluster again needs no allocation.
+        qcow2.disk.ops.clear();
+        qcow2.write(3, &[0xcd; 512]).unwrap();
+        assert_eq!(qcow2.disk.ops, [Op::Write(5, 1)]);
+
+        let mut buf = [0; 1024];
+        qcow2.read(2, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0));
+        assert!(buf[512..].iter().all(|&b| b == 0xcd));
+    }
+
+    #[test]
+    fn full_host_disk() {
+        let mut qcow2 = open(image());
+        // Every 512 byte cluster of the virtual disk after the first L2 table needs a host cluster
+        // of its own, and only 59 are free.
+        for sector in 0..59 {
+            qcow2.write(sector, &[1; 512]).unwrap();
+        }
+        assert_eq!(qcow2.write(59, &[1; 512]), Err(Error::DeviceIo));
+    }
+
+    #[test]
+    fn unsupported_images() {
+        let mut disk = MockDisk::new(64);
+        assert!(Header::read(&mut disk).unwrap().is_none());
+
+        let mut backing_file = image();
+        BigEndian::write_u64(&mut backing_file.data[8..], 4096);
+        assert_eq!(Header::read(&mut backing_file).err(), Some(Error::InvalidDiskImage));
+
+        // Snapshots make the image read-only.
+        let mut snapshots = image();
+        BigEndian::write_u32(&mut snapshots.data[60..], 1);
+        let mut qcow2 = open(snapshots);
+        assert_eq!(qcow2.write(0, &[1; 512]), Err(Error::InvalidDiskImage));
+        assert!(qcow2.disk.ops.is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+//! A dump of everything the hypervisor knows, in one go, for attaching to bug reports.
+//!
+//! The monitor's `report` command prints one JSON object per line, each with a `"type"` saying
+//! what it describes, between a `report` record and an `end` record. Unlike the other monitor
+//! commands, whose output is meant for people and changes as they are improved, the records are
+//! meant to be read by scripts: fields are only ever added, and `REPORT_VERSION` is bumped if any
+//! change meaning.
+//!
+//! Every guest's shared statistics are included, but a hart can only see the full state of its own
+//! guest, so the registers, memory map, PLIC and virtio records are for the guest that has console
+//! input. Running `report` after `focus` on each guest in turn covers the rest.
+//!
+//! Numbers are printed as decimal, except for addresses and register values, which are strings of
+//! hex so that scripts in languages without 64 bit integers don't lose precision.
+
+use arrayvec::ArrayString;
+use core::fmt::{self, Write};
+use crate::constants::MAX_GUESTS;
+use crate::context::Context;
+use crate::monitor::REGISTER_NAMES;
+use crate::statics::SHARED_STATICS;
+use crate::satp::SatpMode;
+use crate::{boottime, hart, memusage, restart, virtio};
+
+pub const REPORT_VERSION: u64 = 1;
+
+/// One line of the report. Fields are printed as they are added, and the line is finished by `end`.
+pub struct Record(());
+
+impl Record {
+    pub fn new(kind: &str) -> Self {
+        print!("{{\"type\":\"{}\"", kind);
+        Record(())
+    }
+
+    pub fn number(&mut self, key: &str, value: u64) -> &mut Self {
+        print!(",\"{}\":{}", key, value);
+        self
+    }
+
+    pub fn hex(&mut self, key: &str, value: u64) -> &mut Self {
+        print!(",\"{}\":\"{:#x}\"", key, value);
+        self
+    }
+
+    /// Add a string, escaping anything that JSON requires to be.
+    pub fn string(&mut self, key: &str, value: &str) -> &mut Self {
+        print!(",\"{}\":\"", key);
+        for c in value.chars() {
+            match c {
+                '"' | '\\' => print!("\\{}", c),
+                c if (c as u32) < 0x20 => print!("\\u{:04x}", c as u32),
+                c => print!("{}", c),
+            }
+        }
+        print!("\"");
+        self
+    }
+
+    /// Add the `Display` form of `value`, cut short if it is very long.
+    pub fn display<T: fmt::Display>(&mut self, key: &str, value: T) -> &mut Self {
+        let mut text = ArrayString::<[u8; 128]>::new();
+        let _ = write!(text, "{}", value);
+        self.string(key, &text)
+    }
+
+    /// Add a list of addresses or register values.
+    pub fn hex_list<I: Iterator<Item = u64>>(&mut self, key: &str, values: I) -> &mut Self {
+        print!(",\"{}\":[", key);
+        for (i, value) in values.enumerate() {
+            print!("{}\"{:#x}\"", if i == 0 { "" } else { "," }, value);
+        }
+        print!("]");
+        self
+    }
+
+    pub fn end(&mut self) {
+        println!("}}");
+    }
+}
+
+/// Print the whole report, for the monitor's `report` command.
+pub fn print(state: &mut Context) {
+    let guestid = hart::current().guest_index();
+    Record::new("report")
+        .number("version", REPORT_VERSION)
+        .number("hart", hart::current().hartid)
+        .number("guest", guestid)
+        .number("time", state.host_clint.get_mtime())
+        .end();
+
+    memusage::publish(state);
+    for guestid in 1..MAX_GUESTS {
+        let exits = &SHARED_STATICS.exit_stats[guestid];
+        if exits.total() == 0 {
+            continue;
+        }
+        let mut record = Record::new("guest");
+        record.number("id", guestid as u64).display("os", *SHARED_STATICS.guest_os[guestid].lock());
+        if let Some(request) = restart::last_request(guestid as u64) {
+            record.display("last_reset_request", request);
+        }
+        record.end();
+        exits.add_to_report(Record::new("exits").number("guest", guestid as u64));
+        memusage::add_to_report(guestid as u64, Record::new("memory").number("guest", guestid as u64));
+        boottime::add_to_report(guestid as u64, Record::new("boottime").number("guest", guestid as u64));
+    }
+
+    guest_state(state);
+    Record::new("end").end();
+}
+
+/// The records only the guest's own hart can produce.
+fn guest_state(state: &mut Context) {
+    let mut record = Record::new("registers");
+    record.hex("pc", csrr!(sepc));
+    for (i, name) in REGISTER_NAMES.iter().enumerate().skip(1) {
+        record.hex(name, state.saved_registers.get(i as u32));
+    }
+    record.end();
+
+    Record::new("csrs")
+        .string("mode", if state.smode() { "S" } else { "U" })
+        .hex("sstatus", state.csrs.sstatus)
+        .hex("sie", state.csrs.sie)
+        .hex("sip", state.csrs.sip)
+        .hex("stvec", state.csrs.stvec)
+        .hex("sepc", state.csrs.sepc)
+        .hex("scause", state.csrs.scause)
+        .hex("stval", state.csrs.stval)
+        .hex("satp", state.csrs.satp)
+        .display("translation", DebugName(SatpMode::from_satp(state.csrs.satp)))
+        .end();
+
+    let now = state.host_clint.get_mtime();
+    for &(deadline, event) in state.timers.iter() {
+        Record::new("timer")
+            .display("event", DebugName(event))
+            .number("ticks", deadline.saturating_sub(now))
+            .end();
+    }
+
+    Record::new("memory_map")
+        .hex("guest_base", state.guest_memory.base())
+        .number("guest_size", state.guest_memory.len())
+        .hex_list("segments", state.guest_map.segments())
+        .hex("shadow_root", state.shadow_page_tables.root_pa(state.shadow()))
+        .display("shadow", DebugName(state.shadow()))
+        .end();
+
+    let stats = &state.shadow_page_tables.stats;
+    Record::new("shadow_tables")
+        .number("total_pages", stats.total_pages)
+        .number("free_pages", stats.free_pages)
+        .number("peak_pages", stats.peak_pages)
+        .number("reclaimed", stats.reclaimed)
+        .number("exhausted", stats.exhausted)
+        .end();
+
+    state.plic.add_to_report();
+    virtio::add_to_report(state);
+}
+
+/// Shows a value with its `Debug` formatting through `Record::display`.
+struct DebugName<T>(T);
+
+impl<T: fmt::Debug> fmt::Display for DebugName<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
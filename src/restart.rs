@@ -0,0 +1,263 @@
+//! What happens to a guest after it crashes.
+//!
+//! By default a crashed guest is left stopped, but the `rvirt,crash-policy` property of the host's
+//! /chosen can pick a policy per guest, as triples of `<guestid policy limit>`:
+//!
+//!   * 0: halt.
+//!   * 1: print the guest's registers and a backtrace, write a core dump to the host if built with
+//!     semihosting (see coredump.rs), then halt.
+//!   * 2: restart the guest, waiting one second before the first restart and twice as long before
+//!     each one after that (up to about a minute). A non-zero limit is how many times the guest
+//!     may be restarted before it is left stopped.
+//!
+//! A guest can also ask to power off or be reset, through the SBI or its test device (see
+//! testdev.rs), giving a reason: the SRST extension's reset reason, or the exit code written to the
+//! test device. The last request of each guest is kept and shown by the monitor's `list` command.
+//! An orderly reboot restarts the guest straight away whatever its policy, but one that gives a
+//! system failure (or a non-zero code) as its reason is handled as a crash, so that a guest that
+//! reboots itself after a panic is held to its crash policy.
+//!
+//! Restarting goes through the same path that started the guest at boot: the hart sends itself an
+//! IPI with a `TriggerHartEntry` request and `hart_entry4` rebuilds everything from the kernel
+//! image and device tree still held in the hart's segment.
+
+use core::fmt;
+use core::sync::atomic::Ordering;
+use crate::constants::{MAX_GUESTS, TIMER_FREQUENCY};
+use crate::context::Context;
+use crate::events::{self, EventKind};
+use crate::riscv::bits::{IE_SSIE, IE_STIE, STATUS_SIE};
+use crate::statics::{IpiReason, SHARED_STATICS};
+use crate::boottime::{self, Milestone};
+use crate::{backtrace, coredump, hart, pmap, print, riscv, trap, virtio};
+
+const INITIAL_BACKOFF: u64 = TIMER_FREQUENCY;
+const MAX_BACKOFF_SHIFT: u64 = 6;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CrashPolicy {
+    Halt,
+    DumpAndHalt,
+    /// Restart the guest, at most `limit` times if there is one.
+    Restart { limit: Option<u32> },
+}
+
+impl CrashPolicy {
+    pub fn from_config(policy: u32, limit: u32) -> Self {
+        match policy {
+            1 => CrashPolicy::DumpAndHalt,
+            2 => CrashPolicy::Restart { limit: if limit == 0 { None } else { Some(limit) } },
+            _ => CrashPolicy::Halt,
+        }
+    }
+}
+
+/// The reasons the SRST extension defines. Reasons from 0xe0000000 up are specific to the SBI
+/// implementation, and from 0xf0000000 to the vendor.
+pub const SRST_REASON_NONE: u32 = 0;
+pub const SRST_REASON_SYSTEM_FAILURE: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetSource {
+    LegacySbi = 1,
+    Srst = 2,
+    TestDevice = 3,
+}
+
+/// What the guest asked for, numbered like the SRST extension's reset types.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+/// A guest's request to power off or be reset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ResetRequest {
+    pub source: ResetSource,
+    pub kind: ResetType,
+    /// The SRST reset reason, or for the test device the exit code.
+    pub reason: u32,
+}
+
+impl ResetRequest {
+    /// Whether the guest gave a failure as its reason.
+    pub fn is_failure(&self) -> bool {
+        match self.source {
+            ResetSource::LegacySbi => false,
+            ResetSource::Srst => self.reason == SRST_REASON_SYSTEM_FAILURE,
+            ResetSource::TestDevice => self.reason != 0,
+        }
+    }
+
+    fn pack(&self) -> u64 {
+        (self.source as u64) << 40 | (self.kind as u64) << 32 | self.reason as u64
+    }
+
+    fn unpack(packed: u64) -> Option<Self> {
+        let source = match packed >> 40 {
+            1 => ResetSource::LegacySbi,
+            2 => ResetSource::Srst,
+            3 => ResetSource::TestDevice,
+            _ => return None,
+        };
+        let kind = match (packed >> 32) & 0xff {
+            0 => ResetType::Shutdown,
+            1 => ResetType::ColdReboot,
+            _ => ResetType::WarmReboot,
+        };
+        Some(Self { source, kind, reason: packed as u32 })
+    }
+}
+
+impl fmt::Display for ResetRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self.source {
+            ResetSource::LegacySbi => "legacy SBI",
+            ResetSource::Srst => "SBI",
+            ResetSource::TestDevice => "test device",
+        };
+        let kind = match self.kind {
+            ResetType::Shutdown => "shutdown",
+            ResetType::ColdReboot => "reboot",
+            ResetType::WarmReboot => "warm reboot",
+        };
+        write!(f, "{} {}", source, kind)?;
+        match (self.source, self.reason) {
+            (_, 0) => Ok(()),
+            (ResetSource::TestDevice, code) => write!(f, " (exit code {})", code),
+            (_, SRST_REASON_SYSTEM_FAILURE) => write!(f, " (system failure)"),
+            (_, reason) if reason >= 0xf0000000 => write!(f, " (vendor reason {:#x})", reason),
+            (_, reason) if reason >= 0xe0000000 => write!(f, " (SBI implementation reason {:#x})", reason),
+            (_, reason) => write!(f, " (reason {:#x})", reason),
+        }
+    }
+}
+
+/// The last request to power off or reset made by `guestid`.
+pub fn last_request(guestid: u64) -> Option<ResetRequest> {
+    ResetRequest::unpack(SHARED_STATICS.reset_requests[guestid as usize % MAX_GUESTS].load(Ordering::SeqCst))
+}
+
+extern {
+    fn hart_entry();
+}
+
+/// Apply the guest's crash policy. Returns only if the guest should stay stopped.
+pub fn handle_crash(state: &mut Context) {
+    let guestid = hart::current().guest_index();
+    match state.crash_policy {
+        CrashPolicy::Halt => {}
+        CrashPolicy::DumpAndHalt => {
+            crate::monitor::dump_registers(state);
+            println!("backtrace:");
+            backtrace::print_guest_backtrace(state, csrr!(sepc));
+            if cfg!(feature = "semihosting") {
+                coredump::write(state);
+            }
+        }
+        CrashPolicy::Restart { .. } if crate::shutdown::in_progress() => {
+            println!("Not restarting guest {}, since the machine is shutting down", guestid);
+        }
+        CrashPolicy::Restart { limit } => {
+            let counter = &SHARED_STATICS.guest_restarts[guestid as usize % MAX_GUESTS];
+            let restarts = counter.load(Ordering::SeqCst);
+            if limit.map_or(false, |limit| restarts >= limit as u64) {
+                println!("Guest {} has already been restarted {} times, leaving it stopped", guestid, restarts);
+                return;
+            }
+            counter.store(restarts + 1, Ordering::SeqCst);
+
+            let delay = INITIAL_BACKOFF << restarts.min(MAX_BACKOFF_SHIFT);
+            if state.options.loglevel >= 1 {
+                println!("Restarting guest {} in {} ms", guestid, delay * 1000 / TIMER_FREQUENCY);
+            }
+            events::record(state, EventKind::Restarting, delay);
+            let deadline = state.host_clint.get_mtime() + delay;
+            while state.host_clint.get_mtime() < deadline {
+                state.set_host_timer(deadline);
+                unsafe { csrw!(sie, IE_STIE) };
+                riscv::wfi();
+            }
+            state.set_host_timer(u64::max_value());
+            restart()
+        }
+    }
+}
+
+/// Power off or reset the guest at its own request. A failure is handled as a crash, and anything
+/// else stops the guest with the exit code for shutdowns or restarts it straight away.
+pub fn requested(state: &mut Context, request: ResetRequest) -> ! {
+    let guestid = hart::current().guest_index();
+    SHARED_STATICS.reset_requests[guestid as usize % MAX_GUESTS].store(request.pack(), Ordering::SeqCst);
+    state.uart.flush_output();
+    if state.options.loglevel >= 1 {
+        println!("Guest {} asked for {}", guestid, request);
+    }
+
+    match request.kind {
+        ResetType::Shutdown => {
+            let code = match request.source {
+                ResetSource::TestDevice if request.is_failure() => request.reason as u64,
+                _ if request.is_failure() => 1,
+                _ => state.shutdown_exit_code,
+            };
+            trap::guest_exited(state, code)
+        }
+        _ if request.is_failure() => {
+            events::record(state, EventKind::Crashed, csrr!(sepc));
+            handle_crash(state);
+            trap::guest_exited(state, 1)
+        }
+        _ => reboot(state, request),
+    }
+}
+
+/// Reset the guest at its own request, without counting it as a crash. While the machine is
+/// shutting down the guest is stopped instead.
+fn reboot(state: &mut Context, request: ResetRequest) -> ! {
+    if crate::shutdown::in_progress() {
+        let code = state.shutdown_exit_code;
+        trap::guest_exited(state, code)
+    }
+    virtio::flush_backends(state);
+    events::record(state, EventKind::Rebooting, request.reason as u64);
+    restart()
+}
+
+/// Throw away the guest's state and start it again.
+fn restart() -> ! {
+    print::flush();
+    boottime::reset();
+    boottime::mark(Milestone::IpiSent);
+    unsafe { restart_hart() }
+}
+
+/// Start the guest on this hart over again, as if it were being started at boot.
+unsafe fn restart_hart() -> ! {
+    let local = hart::current();
+    let (hartid, segment_pa) = (local.hartid, local.segment_pa);
+
+    // The boot page table shares the data region with the hypervisor's .data and .bss, so it has
+    // to be rebuilt. Nothing in .data may be used after this.
+    pmap::write_boot_page_table(segment_pa, local.shared_segments_shift);
+    *SHARED_STATICS.ipi_reason_array[local.hart_index].lock() = Some(IpiReason::TriggerHartEntry {
+        a0: hartid,
+        a1: segment_pa + pmap::FDT_OFFSET,
+        a2: local.shared_segments_shift,
+        a3: segment_pa,
+        a4: local.guestid.unwrap_or(u64::max_value()),
+        sp: segment_pa + (4<<20) + pmap::DIRECT_MAP_OFFSET,
+        satp: 8 << 60 | (segment_pa >> 12),
+    });
+
+    csrw!(stvec, hart_entry as u64);
+    csrw!(sscratch, hartid);
+    csrw!(sie, IE_SSIE);
+    riscv::sbi::send_ipi_to_hart(hartid);
+    csrs!(sstatus, STATUS_SIE);
+    loop {
+        riscv::wfi();
+    }
+}
@@ -1,11 +1,17 @@
 pub const TVEC_MODE: u64 = 0x3;
 pub const TVEC_BASE: u64 = !TVEC_MODE;
+pub const TVEC_MODE_VECTORED: u64 = 0x1;
+
+pub const COUNTEREN_CY: u64 = 1 << 0;
+pub const COUNTEREN_IR: u64 = 1 << 2;
 
 pub const STATUS_UIE: u64 = 1 << 0;
 pub const STATUS_SIE: u64 = 1 << 1;
+pub const STATUS_MIE: u64 = 1 << 3;
 pub const STATUS_UPIE: u64 = 1 << 4;
 pub const STATUS_SPIE: u64 = 1 << 5;
 pub const STATUS_SPP: u64 = 1 << 8;
+pub const STATUS_VS: u64 = 3 << 9;
 pub const STATUS_FS: u64 = 3 << 13;
 pub const STATUS_XS: u64 = 3 << 15;
 pub const STATUS_SUM: u64 = 1 << 18;
@@ -16,23 +22,33 @@ pub const STATUS_MPP_M: u64 = 3 << 11;
 pub const STATUS_MPP_S: u64 = 1 << 11;
 pub const STATUS_MPP_U: u64 = 0 << 11;
 
-// Mask of writable bits in sstatus.
+// Mask of writable bits in sstatus. MXR isn't among them, since shadow page tables don't make
+// execute-only pages readable, so it stays zero.
 pub const SSTATUS_WRITABLE_MASK: u64 =
-    STATUS_MXR |
 STATUS_SUM |
 STATUS_FS |
+STATUS_VS |
 STATUS_SPP |
 STATUS_SPIE |
 STATUS_SIE;
-pub const SSTATUS_DYNAMIC_MASK: u64 = STATUS_SD | STATUS_FS;
+pub const SSTATUS_DYNAMIC_MASK: u64 = STATUS_SD | STATUS_FS | STATUS_VS;
 
 pub const IP_SSIP: u64 = 1 << 1;
 pub const IP_STIP: u64 = 1 << 5;
 pub const IP_SEIP: u64 = 1 << 9;
+pub const IP_LCOFIP: u64 = 1 << 13;
 
 pub const IE_SSIE: u64 = 1 << 1;
 pub const IE_STIE: u64 = 1 << 5;
 pub const IE_SEIE: u64 = 1 << 9;
+pub const IE_LCOFIE: u64 = 1 << 13;
+
+pub const ENVCFG_FIOM: u64 = 1 << 0;
+pub const ENVCFG_CBIE: u64 = 0x3 << 4;
+pub const ENVCFG_CBIE_FLUSH: u64 = 0x1 << 4;
+pub const ENVCFG_CBIE_INVAL: u64 = 0x3 << 4;
+pub const ENVCFG_CBCFE: u64 = 1 << 6;
+pub const ENVCFG_CBZE: u64 = 1 << 7;
 
 pub const SATP_MODE: u64 = 0xf << 60;
 pub const SATP_ASID: u64 = 0xffff << 44;
@@ -91,6 +91,7 @@ pub const mideleg: u64 = 0x303;
 pub const mie: u64 = 0x304;
 pub const mtvec: u64 = 0x305;
 pub const mcounteren: u64 = 0x306;
+pub const menvcfg: u64 = 0x30a;
 pub const mtvt: u64 = 0x307;
 pub const mucounteren: u64 = 0x320;
 pub const mscounteren: u64 = 0x321;
@@ -109,6 +110,7 @@ pub const sideleg: u64 = 0x103;
 pub const sie: u64 = 0x104;
 pub const stvec: u64 = 0x105;
 pub const scounteren: u64 = 0x106;
+pub const senvcfg: u64 = 0x10a;
 pub const stvt: u64 = 0x107;
 pub const sscratch: u64 = 0x140;
 pub const sepc: u64 = 0x141;
@@ -119,8 +121,13 @@ pub const sip: u64 = 0x144;
 pub const snxti: u64 = 0x145;
 pub const sintstatus: u64 = 0x146;
 pub const sscratchcsw: u64 = 0x148;
+pub const stimecmp: u64 = 0x14d;
+pub const siselect: u64 = 0x150;
+pub const sireg: u64 = 0x151;
+pub const stopei: u64 = 0x15c;
 pub const sptbr: u64 = 0x180;
 pub const satp: u64 = 0x180;
+pub const scountovf: u64 = 0xda0;
 pub const pmpcfg0: u64 = 0x3a0;
 pub const pmpcfg1: u64 = 0x3a1;
 pub const pmpcfg2: u64 = 0x3a2;
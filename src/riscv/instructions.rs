@@ -1,5 +1,5 @@
 
-use crate::riscv::bits::STATUS_FS;
+use crate::riscv::bits::{STATUS_FS, STATUS_VS};
 
 /// atomic read from CSR
 #[macro_export]
@@ -78,10 +78,28 @@ pub fn barrier() {
     unsafe { asm!("" ::: "memory" : "volatile") }
 }
 
+/// Order all earlier memory and I/O accesses before later ones, as needed when handing buffers to a
+/// device.
+pub fn fence() {
+    unsafe { asm!("fence iorw, iorw" ::: "memory" : "volatile") }
+}
+
 pub fn fence_i() {
     unsafe { asm!("fence.i" :::: "volatile") }
 }
 
+/// Write back the cache block containing `vaddr`, if it is dirty (Zicbom's cbo.clean). The
+/// assembler doesn't know the Zicbom instructions, so they are encoded by hand with rs1 = a0.
+pub fn cbo_clean(vaddr: u64) {
+    unsafe { asm!(".word 0x0015200f" :: "{a0}"(vaddr) : "memory" : "volatile") }
+}
+
+/// Write back the cache block containing `vaddr` if it is dirty, and then drop it from the cache
+/// (cbo.flush).
+pub fn cbo_flush(vaddr: u64) {
+    unsafe { asm!(".word 0x0025200f" :: "{a0}"(vaddr) : "memory" : "volatile") }
+}
+
 pub fn wfi() {
     unsafe { asm!("wfi" :::: "volatile") }
 }
@@ -94,11 +112,6 @@ pub fn set_sepc(value: u64) {
     unsafe { csrw!(sepc, value) }
 }
 
-/// Set the `sscratch` CSR. This is safe because `sscratch` does not impact processor execution.
-pub fn set_sscratch(value: u64) {
-    unsafe { csrw!(sscratch, value) }
-}
-
 /// Clear the indicated bits of `sip`. This is safe because interrupt state is not used to enforce
 /// safety invariants.
 pub fn clear_sip(mask: u64) {
@@ -110,3 +123,40 @@ pub fn clear_sip(mask: u64) {
 pub fn set_sstatus_fs(new: u64) {
     unsafe { csrw!(sstatus, (new & STATUS_FS) | (csrr!(sstatus) & !STATUS_FS)) }
 }
+
+/// Set the VS bits of `sstatus`. Like `set_sstatus_fs`, this relies on rvirt never using the
+/// vector unit itself. On harts without the vector extension the bits are read-only zero, so the
+/// guest sees vector state as always off.
+pub fn set_sstatus_vs(new: u64) {
+    unsafe { csrw!(sstatus, (new & STATUS_VS) | (csrr!(sstatus) & !STATUS_VS)) }
+}
+
+/// Save the floating point registers and `fcsr` to `area`: f0 to f31 first, then `fcsr`. The FS
+/// bits of `sstatus` must not be Off. The hypervisor is built without the F extension, so the
+/// stores are encoded by hand, as `fsd fN, 8*N(a0)`.
+pub unsafe fn save_fp(area: &mut [u64; 33]) {
+    asm!(".word 0x00053027, 0x00153427, 0x00253827, 0x00353c27
+          .word 0x02453027, 0x02553427, 0x02653827, 0x02753c27
+          .word 0x04853027, 0x04953427, 0x04a53827, 0x04b53c27
+          .word 0x06c53027, 0x06d53427, 0x06e53827, 0x06f53c27
+          .word 0x09053027, 0x09153427, 0x09253827, 0x09353c27
+          .word 0x0b453027, 0x0b553427, 0x0b653827, 0x0b753c27
+          .word 0x0d853027, 0x0d953427, 0x0da53827, 0x0db53c27
+          .word 0x0fc53027, 0x0fd53427, 0x0fe53827, 0x0ff53c27
+          csrr t0, 0x003
+          sd t0, 32*8(a0)" :: "{a0}"(area.as_mut_ptr()) : "t0", "memory" : "volatile")
+}
+
+/// Load the registers saved by `save_fp`, with `fld fN, 8*N(a0)`.
+pub unsafe fn restore_fp(area: &[u64; 33]) {
+    asm!(".word 0x00053007, 0x00853087, 0x01053107, 0x01853187
+          .word 0x02053207, 0x02853287, 0x03053307, 0x03853387
+          .word 0x04053407, 0x04853487, 0x05053507, 0x05853587
+          .word 0x06053607, 0x06853687, 0x07053707, 0x07853787
+          .word 0x08053807, 0x08853887, 0x09053907, 0x09853987
+          .word 0x0a053a07, 0x0a853a87, 0x0b053b07, 0x0b853b87
+          .word 0x0c053c07, 0x0c853c87, 0x0d053d07, 0x0d853d87
+          .word 0x0e053e07, 0x0e853e87, 0x0f053f07, 0x0f853f87
+          ld t0, 32*8(a0)
+          csrw 0x003, t0" :: "{a0}"(area.as_ptr()) : "t0", "memory" : "volatile")
+}
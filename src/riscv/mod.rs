@@ -1,10 +1,13 @@
+#[cfg(target_arch = "riscv64")]
 #[macro_use]
 pub mod instructions;
 
 pub mod csr;
 pub mod bits;
+#[cfg(target_arch = "riscv64")]
 pub mod sbi;
 
+#[cfg(target_arch = "riscv64")]
 pub use instructions::*;
 
 pub const CAUSE_STRINGS: [&str; 16] = [
@@ -1,3 +1,18 @@
+//! Calls into the SBI firmware below the hypervisor.
+//!
+//! The M-mode code of rvirt-bare-metal only implements the legacy calls, so that is what is used,
+//! except in rvirt-smode (built with the `smode_only` feature) which is meant for other firmware
+//! such as OpenSBI, where the legacy calls may be left out. It uses the TIME, IPI, HSM and SRST
+//! extensions instead.
+
+use crate::riscv::bits::IP_SSIP;
+use crate::sbi::{SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS};
+
+const EXT_TIME: u64 = 0x54494d45;
+const EXT_IPI: u64 = 0x735049;
+const EXT_HSM: u64 = 0x48534d;
+const EXT_SRST: u64 = 0x53525354;
+
 #[naked]
 #[inline(never)]
 fn ecall(_a0: u64, _a1: u64, _a2: u64, _a3: u64, _a4: u64, _a5: u64, _a6: u64, _a7: u64) {
@@ -5,11 +20,20 @@ fn ecall(_a0: u64, _a1: u64, _a2: u64, _a3: u64, _a4: u64, _a5: u64, _a6: u64, _
 }
 
 pub fn set_timer(stime_value: u64) {
-    ecall(stime_value, 0, 0, 0, 0, 0, 0, 0);
+    if cfg!(feature = "smode_only") {
+        ecall(stime_value, 0, 0, 0, 0, 0, 0, EXT_TIME);
+    } else {
+        ecall(stime_value, 0, 0, 0, 0, 0, 0, 0);
+    }
 }
 
 pub fn clear_ipi() {
-    ecall(0, 0, 0, 0, 0, 0, 0, 3);
+    if cfg!(feature = "smode_only") {
+        // With the IPI extension the supervisor clears its own software interrupt.
+        unsafe { csrc!(sip, IP_SSIP) };
+    } else {
+        ecall(0, 0, 0, 0, 0, 0, 0, 3);
+    }
 }
 
 pub fn send_ipi(hart_mask_pointer: u64) {
@@ -17,10 +41,64 @@ pub fn send_ipi(hart_mask_pointer: u64) {
 }
 
 pub fn shutdown() {
-    ecall(0, 0, 0, 0, 0, 0, 0, 8);
+    if cfg!(feature = "smode_only") {
+        // A system reset of type 0, shutdown, for no particular reason.
+        ecall(0, 0, 0, 0, 0, 0, 0, EXT_SRST);
+    } else {
+        ecall(0, 0, 0, 0, 0, 0, 0, 8);
+    }
+}
+
+/// Have the firmware start `hartid` in S-mode at the physical address `start_address`, with a0 set
+/// to its hartid and a1 to `opaque`. Firmware without the HSM extension is taken to have started
+/// every hart already, as BBL does. Returns the SBI error if the hart couldn't be started.
+pub fn hart_start(hartid: u64, start_address: u64, opaque: u64) -> Result<(), i64> {
+    let (error, _value): (i64, u64);
+    unsafe {
+        asm!("ecall"
+             : "={a0}"(error), "={a1}"(_value)
+             : "{a0}"(hartid), "{a1}"(start_address), "{a2}"(opaque), "{a6}"(0), "{a7}"(EXT_HSM)
+             : "memory" : "volatile");
+    }
+    match error {
+        SBI_SUCCESS | SBI_ERR_ALREADY_AVAILABLE | SBI_ERR_NOT_SUPPORTED => Ok(()),
+        error => Err(error),
+    }
+}
+
+/// Clear the bits of medeleg in `clear` and then set those in `set`, through the M-mode code of
+/// rvirt-bare-metal. Returns the previous medeleg and mideleg, or None if the firmware is something
+/// else. Only for the delegation audit.
+pub fn update_medeleg(clear: u64, set: u64) -> Option<(u64, u64)> {
+    let (error, medeleg, mideleg): (u64, u64, u64);
+    unsafe {
+        asm!("ecall"
+             : "={a0}"(error), "={a1}"(medeleg), "={a2}"(mideleg)
+             : "{a0}"(clear), "{a1}"(set), "{a6}"(0x100), "{a7}"(0x0a005256)
+             : "memory" : "volatile");
+    }
+    if error == 0 {
+        Some((medeleg, mideleg))
+    } else {
+        None
+    }
+}
+
+/// Have the M-mode code of rvirt-bare-metal set menvcfg.STCE on this hart, so that stimecmp can be
+/// used. Only to be called if the hart implements Sstc. Other firmware enables it by itself.
+pub fn enable_sstc() {
+    if !cfg!(feature = "smode_only") {
+        ecall(0, 0, 0, 0, 0, 0, 0x101, 0x0a005256);
+    }
 }
 
 pub fn send_ipi_to_hart(hart: u64) {
-    let mask: u64 = 1 << hart;
-    send_ipi(&mask as *const u64 as u64);
+    if hart < 64 && !cfg!(feature = "smode_only") {
+        let mask: u64 = 1 << hart;
+        send_ipi(&mask as *const u64 as u64);
+    } else {
+        // The legacy call takes a bitmap starting at hartid zero. The IPI extension instead takes
+        // the hartid that the bitmap starts from.
+        ecall(1, hart, 0, 0, 0, 0, 0, EXT_IPI);
+    }
 }
@@ -0,0 +1,170 @@
+//! Emulation of the Goldfish real time clock, which QEMU's virt machine has and guests probe.
+//!
+//! Each guest has its own clock at `RTC_BASE`, counting nanoseconds since the Unix epoch. It runs
+//! from the host `mtime` plus an offset per guest, which starts out matching the host's own
+//! Goldfish RTC if it has one and otherwise counts from the epoch at reset. Setting the clock, as
+//! `hwclock --systohc` does, only moves that guest's offset.
+//!
+//! The alarm is kept on the hart's timer queue, and raises `RTC_IRQ` on the guest's PLIC when it
+//! goes off while the guest has its interrupt enabled.
+
+use core::ptr;
+use riscv_decode::Instruction;
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::timer::TimerEvent;
+use crate::{pmap, riscv};
+
+/// Guest physical address of the device, where QEMU's virt machine has it.
+pub const RTC_BASE: u64 = 0x101000;
+pub const RTC_SIZE: u64 = 0x1000;
+/// Guest interrupt the alarm raises.
+pub const RTC_IRQ: u32 = 11;
+
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0c;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1c;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+pub struct Rtc {
+    /// Nanoseconds to add to host time to get the guest's time.
+    offset: u64,
+    /// Upper half of the time, latched when the lower half is read, and of values being written.
+    time_high: u32,
+    alarm_high: u32,
+    /// Guest time at which the alarm goes off.
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    /// Set when the alarm goes off, until the guest clears the interrupt.
+    irq_pending: bool,
+}
+
+impl Rtc {
+    /// A clock matching the host's Goldfish RTC at `host_rtc`, if there is one.
+    pub fn new(host_rtc: Option<u64>, mtime: u64) -> Self {
+        let offset = host_rtc.map_or(0, |pa| read_host_time(pa).wrapping_sub(ticks_to_nanos(mtime)));
+        Self { offset, time_high: 0, alarm_high: 0, alarm: None, irq_enabled: false, irq_pending: false }
+    }
+
+    fn time(&self, mtime: u64) -> u64 {
+        ticks_to_nanos(mtime).wrapping_add(self.offset)
+    }
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks / TIMER_FREQUENCY) * NANOS_PER_SEC + (ticks % TIMER_FREQUENCY) * NANOS_PER_SEC / TIMER_FREQUENCY
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos / NANOS_PER_SEC) * TIMER_FREQUENCY + (nanos % NANOS_PER_SEC) * TIMER_FREQUENCY / NANOS_PER_SEC
+}
+
+/// Read the host's clock. Reading the lower half latches the upper half.
+fn read_host_time(pa: u64) -> u64 {
+    let base = pmap::pa2va(pa);
+    unsafe {
+        let low = ptr::read_volatile((base + TIME_LOW) as *const u32) as u64;
+        let high = ptr::read_volatile((base + TIME_HIGH) as *const u32) as u64;
+        high << 32 | low
+    }
+}
+
+#[inline(always)]
+pub fn is_rtc_access(guest_pa: u64) -> bool {
+    guest_pa >= RTC_BASE && guest_pa < RTC_BASE + RTC_SIZE
+}
+
+pub fn handle_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<()> {
+    let now = state.host_clint.get_mtime();
+    let rtc = &mut state.rtc;
+    match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Lw(i)) | Some(Instruction::Lwu(i)) => {
+            let value = match guest_pa - RTC_BASE {
+                TIME_LOW => {
+                    let time = rtc.time(now);
+                    rtc.time_high = (time >> 32) as u32;
+                    time as u32
+                }
+                TIME_HIGH => rtc.time_high,
+                ALARM_LOW => rtc.alarm.unwrap_or(0) as u32,
+                ALARM_HIGH => (rtc.alarm.unwrap_or(0) >> 32) as u32,
+                IRQ_ENABLED => rtc.irq_enabled as u32,
+                ALARM_STATUS => rtc.alarm.is_some() as u32,
+                _ => 0,
+            };
+            state.saved_registers.set(i.rd(), value as i32 as i64 as u64);
+        }
+        Some(Instruction::Sw(i)) => {
+            let value = state.saved_registers.get(i.rs2()) as u32;
+            match guest_pa - RTC_BASE {
+                TIME_LOW => {
+                    let time = (rtc.time_high as u64) << 32 | value as u64;
+                    rtc.offset = time.wrapping_sub(ticks_to_nanos(now));
+                    if rtc.alarm.is_some() {
+                        schedule_alarm(state, now);
+                    }
+                }
+                TIME_HIGH => rtc.time_high = value,
+                ALARM_LOW => {
+                    rtc.alarm = Some((rtc.alarm_high as u64) << 32 | value as u64);
+                    schedule_alarm(state, now);
+                }
+                ALARM_HIGH => rtc.alarm_high = value,
+                IRQ_ENABLED => {
+                    rtc.irq_enabled = value & 1 != 0;
+                    update_interrupt(state);
+                }
+                CLEAR_ALARM => {
+                    rtc.alarm = None;
+                    state.timers.cancel(TimerEvent::RtcAlarm);
+                }
+                CLEAR_INTERRUPT => {
+                    rtc.irq_pending = false;
+                    update_interrupt(state);
+                }
+                _ => {}
+            }
+        }
+        _ => return Err(Error::UnsupportedDeviceAccess(guest_pa)),
+    }
+    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+    Ok(())
+}
+
+/// Put the alarm on the timer queue, or set it off straight away if its time has passed.
+fn schedule_alarm(state: &mut Context, now: u64) {
+    let alarm = match state.rtc.alarm {
+        Some(alarm) => alarm,
+        None => return,
+    };
+    let time = state.rtc.time(now);
+    if alarm <= time {
+        alarm_expired(state);
+    } else {
+        state.schedule_timer(TimerEvent::RtcAlarm, now + nanos_to_ticks(alarm - time));
+    }
+}
+
+/// Handle the alarm going off.
+pub fn alarm_expired(state: &mut Context) {
+    state.rtc.alarm = None;
+    state.rtc.irq_pending = true;
+    state.timers.cancel(TimerEvent::RtcAlarm);
+    update_interrupt(state);
+}
+
+/// Make the guest interrupt pending if the alarm has gone off and the guest has it enabled.
+fn update_interrupt(state: &mut Context) {
+    let pending = state.rtc.irq_pending && state.rtc.irq_enabled;
+    state.plic.set_pending(RTC_IRQ, pending);
+    if pending {
+        state.no_interrupt = false;
+    }
+}
@@ -0,0 +1,111 @@
+//! Decoding the guest's satp, and deciding which values it may hold.
+//!
+//! satp is WARL: a write selecting a translation mode the hypervisor can't provide has no effect
+//! at all, rather than faulting. Kernels probe for the deepest mode by writing satp and reading it
+//! back, so `vcsr.rs` reports each refused mode only once.
+
+use crate::riscv::bits::SATP_MODE;
+
+/// The translation modes that satp.MODE can select.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SatpMode {
+    Bare,
+    Sv39,
+    Sv48,
+    Sv57,
+    Sv64,
+    /// Values the specification reserves.
+    Reserved(u64),
+}
+
+impl SatpMode {
+    pub fn from_satp(satp: u64) -> Self {
+        match mode_field(satp) {
+            0 => SatpMode::Bare,
+            8 => SatpMode::Sv39,
+            9 => SatpMode::Sv48,
+            10 => SatpMode::Sv57,
+            11 => SatpMode::Sv64,
+            mode => SatpMode::Reserved(mode),
+        }
+    }
+
+    /// Whether guests can use the mode. Bare runs on the hypervisor's own identity mappings of
+    /// guest memory, and Sv39 through shadow page tables, which can't yet translate the deeper
+    /// tables of the other modes.
+    pub fn supported(self) -> bool {
+        self == SatpMode::Bare || self == SatpMode::Sv39
+    }
+}
+
+fn mode_field(satp: u64) -> u64 {
+    (satp & SATP_MODE) >> 60
+}
+
+/// The value satp holds after the guest writes `new` over `old`.
+pub fn legalize(old: u64, new: u64) -> u64 {
+    if SatpMode::from_satp(new).supported() {
+        new
+    } else {
+        old
+    }
+}
+
+/// Record in `refused`, a set with one bit per value of satp.MODE, that the mode of `satp` was
+/// refused. Returns whether it hadn't been before.
+pub fn refuse(refused: &mut u16, satp: u64) -> bool {
+    let bit = 1 << mode_field(satp);
+    let first = *refused & bit == 0;
+    *refused |= bit;
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PPN: u64 = 0x80123;
+    const ASID: u64 = 5 << 44;
+
+    fn satp(mode: u64) -> u64 {
+        mode << 60 | ASID | PPN
+    }
+
+    #[test]
+    fn every_mode() {
+        let expected = [
+            SatpMode::Bare, SatpMode::Reserved(1), SatpMode::Reserved(2), SatpMode::Reserved(3),
+            SatpMode::Reserved(4), SatpMode::Reserved(5), SatpMode::Reserved(6), SatpMode::Reserved(7),
+            SatpMode::Sv39, SatpMode::Sv48, SatpMode::Sv57, SatpMode::Sv64,
+            SatpMode::Reserved(12), SatpMode::Reserved(13), SatpMode::Reserved(14), SatpMode::Reserved(15),
+        ];
+        for (mode, &expected) in expected.iter().enumerate() {
+            assert_eq!(SatpMode::from_satp(satp(mode as u64)), expected);
+            assert_eq!(expected.supported(), mode == 0 || mode == 8);
+        }
+    }
+
+    #[test]
+    fn unsupported_modes_keep_the_old_value() {
+        let sv39 = satp(8);
+        for mode in 0..16 {
+            let new = satp(mode) + 1;
+            let expected = if mode == 0 || mode == 8 { new } else { sv39 };
+            assert_eq!(legalize(sv39, new), expected, "mode {}", mode);
+        }
+        // Leaving paging on or off is up to the guest.
+        assert_eq!(legalize(0, sv39), sv39);
+        assert_eq!(legalize(sv39, 0), 0);
+        assert_eq!(legalize(0, satp(9)), 0);
+    }
+
+    #[test]
+    fn refused_once() {
+        let mut refused = 0;
+        assert!(refuse(&mut refused, satp(9)));
+        assert!(!refuse(&mut refused, satp(9) | PPN));
+        assert!(refuse(&mut refused, satp(10)));
+        assert!(refuse(&mut refused, satp(15)));
+        assert_eq!(refused, 1 << 9 | 1 << 10 | 1 << 15);
+    }
+}
@@ -0,0 +1,258 @@
+//! SBI extensions implemented for guests.
+//!
+//! The legacy calls (extension IDs below 0x10) are handled by `handle_trap`, or by
+//! `handle_fast_call` straight from `trap::strap_fast` for the most frequent ones. Everything else
+//! uses the v0.2 calling convention: a7 selects the extension, a6 the function within it, and the
+//! call returns an error code in a0 and a value in a1. Unknown extensions and functions return
+//! `SBI_ERR_NOT_SUPPORTED` rather than ending the guest, so that kernels can probe for them.
+
+use crate::boottime::{self, Milestone};
+use crate::context::Context;
+use crate::dispatch::Trap;
+use crate::error::Error;
+use crate::exits::ExitReason;
+use crate::options::TRACE_SBI;
+use crate::pmu::FirmwareEvent;
+use crate::restart::{self, ResetRequest, ResetSource, ResetType, SRST_REASON_NONE};
+use crate::{events, guestos, icache, ipi, irqlatency, pmu, profile, ptsync, riscv, steal, trap, watch};
+
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_FAILED: i64 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_DENIED: i64 = -4;
+pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+pub const SBI_ERR_ALREADY_STARTED: i64 = -7;
+pub const SBI_ERR_ALREADY_STOPPED: i64 = -8;
+
+pub const EXT_BASE: u64 = 0x10;
+pub const EXT_IPI: u64 = 0x735049;
+pub const EXT_DBCN: u64 = 0x4442434e;
+pub const EXT_PMU: u64 = 0x504d55;
+pub const EXT_STA: u64 = 0x535441;
+pub const EXT_SRST: u64 = 0x53525354;
+/// Calls specific to RVirt, numbered in the range set aside for firmware specific extensions.
+pub const EXT_RVIRT: u64 = 0x0a000000 | IMPL_ID;
+
+pub const RVIRT_EVENT_NEXT_SEQUENCE: u64 = 0;
+pub const RVIRT_EVENT_READ: u64 = 1;
+pub const RVIRT_IDENTIFY: u64 = 2;
+pub const RVIRT_PROFILE_START: u64 = 3;
+pub const RVIRT_PROFILE_STOP: u64 = 4;
+pub const RVIRT_PROFILE_READ: u64 = 5;
+pub const RVIRT_BOOT_MARKER: u64 = 6;
+pub const RVIRT_IRQ_ACK: u64 = 7;
+pub const RVIRT_WATCH_ADD: u64 = 8;
+pub const RVIRT_WATCH_REMOVE: u64 = 9;
+pub const RVIRT_WATCH_READ: u64 = 10;
+
+/// Version 2.0 of the SBI specification.
+const SPEC_VERSION: u64 = 2 << 24;
+/// RVirt doesn't have an implementation ID assigned, so it uses one outside the registered range.
+const IMPL_ID: u64 = 0x5256;
+const IMPL_VERSION: u64 = 1;
+
+/// Largest number of bytes transferred by a single debug console read or write. Guests have to
+/// handle partial transfers anyway.
+const DBCN_MAX_TRANSFER: u64 = 1024;
+
+/// Handle an ecall from the guest kernel. Ecalls from guest user mode are system calls for the guest
+/// kernel, so they are left to be forwarded.
+pub fn handle_trap(state: &mut Context, trap: &Trap) -> bool {
+    if !state.smode() {
+        return false;
+    }
+
+    let call = state.saved_registers.get(17);
+    state.record_exit(exit_reason(call));
+    boottime::mark(Milestone::FirstSbiCall);
+    if !is_fast_call(call) {
+        trace!(state, TRACE_SBI, "SBI call {:#x} function {} at {:#x}", call, state.saved_registers.get(16), trap.pc);
+    }
+    match call {
+        call if is_fast_call(call) => handle_fast_call(state, call),
+        2 => {
+            // Returns -1 if no input is available, as the guest is expected to poll.
+            state.uart.fill_fifo();
+            let value = state.uart.take_input().map(|ch| ch as u64).unwrap_or(u64::max_value());
+            state.saved_registers.set(10, value);
+        }
+        3 => ipi::legacy_clear(state),
+        4 => ipi::legacy_send(state),
+        8 => restart::requested(state, ResetRequest {
+            source: ResetSource::LegacySbi, kind: ResetType::Shutdown, reason: SRST_REASON_NONE,
+        }),
+        extension if extension >= EXT_BASE => {
+            let function = state.saved_registers.get(16);
+            let (error, value) = handle_call(state, extension, function);
+            trace!(state, TRACE_SBI, "SBI call {:#x} returned ({}, {:#x})", extension, error, value);
+            state.saved_registers.set(10, error as u64);
+            state.saved_registers.set(11, value);
+        }
+        i => trap::terminate_guest(state, Error::UnsupportedSbiCall(i)),
+    }
+    riscv::set_sepc(trap.pc + 4);
+    true
+}
+
+/// Whether `call` is one of the legacy calls that `trap::strap_fast` handles.
+pub fn is_fast_call(call: u64) -> bool {
+    match call {
+        0 | 1 | 5 | 6 | 7 => true,
+        _ => false,
+    }
+}
+
+pub fn exit_reason(call: u64) -> ExitReason {
+    match call {
+        0 => ExitReason::SbiTimer,
+        1 | 2 => ExitReason::SbiConsole,
+        3 | 4 => ExitReason::SbiIpi,
+        5 | 6 | 7 => ExitReason::SbiFence,
+        8 => ExitReason::SbiShutdown,
+        _ => ExitReason::SbiExtension,
+    }
+}
+
+/// Handle one of the legacy SBI calls for which `is_fast_call` is true.
+pub fn handle_fast_call(state: &mut Context, call: u64) {
+    trace!(state, TRACE_SBI, "SBI call {} ({:#x}) at {:#x}", call, state.saved_registers.get(10), csrr!(sepc));
+    match call {
+        0 => {
+            let time = state.saved_registers.get(10);
+            state.set_guest_timer(time);
+            state.pmu.record(FirmwareEvent::SetTimer);
+        }
+        1 => {
+            let value = state.saved_registers.get(10) as u8;
+            state.uart.output_byte(value);
+            state.schedule_console_flush();
+        }
+        5 => icache::remote_fence_i(state),
+        6 | 7 => {
+            // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
+            // a result, this function ignores the arguments and just does a global fence. This
+            // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
+            ptsync::fence(state);
+            state.pmu.record(FirmwareEvent::SfenceVmaSent);
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Handle a call to an extension other than the legacy ones, returning (error, value).
+pub fn handle_call(state: &mut Context, extension: u64, function: u64) -> (i64, u64) {
+    // The RVirt extension stays available, so that a guest can still correct its identification.
+    if state.guest_os.has_quirk(guestos::QUIRK_LEGACY_SBI) && extension != EXT_RVIRT {
+        return (SBI_ERR_NOT_SUPPORTED, 0);
+    }
+
+    match extension {
+        EXT_BASE => base(state, function),
+        EXT_IPI => ipi::handle_call(state, function),
+        EXT_DBCN => debug_console(state, function),
+        EXT_PMU => pmu::handle_call(state, function),
+        EXT_STA => steal::handle_call(state, function),
+        EXT_SRST => system_reset(state, function),
+        EXT_RVIRT => rvirt(state, function),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn base(state: &mut Context, function: u64) -> (i64, u64) {
+    match function {
+        0 => (SBI_SUCCESS, SPEC_VERSION),
+        1 => (SBI_SUCCESS, IMPL_ID),
+        2 => (SBI_SUCCESS, IMPL_VERSION),
+        3 => {
+            let supported = match state.saved_registers.get(10) {
+                EXT_BASE | EXT_IPI | EXT_DBCN | EXT_PMU | EXT_STA | EXT_SRST | EXT_RVIRT => 1,
+                0..=8 => 1,
+                _ => 0,
+            };
+            (SBI_SUCCESS, supported)
+        }
+        // mvendorid, marchid and mimpid aren't passed through.
+        4 | 5 | 6 => (SBI_SUCCESS, 0),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// Handle the `system_reset` function of the SRST extension, which only returns on a bad argument.
+fn system_reset(state: &mut Context, function: u64) -> (i64, u64) {
+    if function != 0 {
+        return (SBI_ERR_NOT_SUPPORTED, 0);
+    }
+    let kind = match state.saved_registers.get(10) {
+        0 => ResetType::Shutdown,
+        1 => ResetType::ColdReboot,
+        2 => ResetType::WarmReboot,
+        _ => return (SBI_ERR_INVALID_PARAM, 0),
+    };
+    let reason = match state.saved_registers.get(11) {
+        reason if reason <= u32::max_value() as u64 => reason as u32,
+        _ => return (SBI_ERR_INVALID_PARAM, 0),
+    };
+    restart::requested(state, ResetRequest { source: ResetSource::Srst, kind, reason })
+}
+
+fn debug_console(state: &mut Context, function: u64) -> (i64, u64) {
+    let len = state.saved_registers.get(10).min(DBCN_MAX_TRANSFER);
+    let addr = state.saved_registers.get(11);
+    if state.saved_registers.get(12) != 0 && function != 2 {
+        return (SBI_ERR_INVALID_PARAM, 0);
+    }
+
+    match function {
+        // console_write(num_bytes, base_addr_lo, base_addr_hi)
+        0 => {
+            if !state.prepare_guest_access(addr, len, false) {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+            for i in 0..len {
+                let byte = state.guest_memory.slice(addr + i, 1)[0];
+                state.uart.output_byte(byte);
+            }
+            // Whatever the guest passed in one call is meant to be seen together.
+            state.uart.flush_output();
+            (SBI_SUCCESS, len)
+        }
+        // console_read(num_bytes, base_addr_lo, base_addr_hi)
+        1 => {
+            if !state.prepare_guest_access(addr, len, true) {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+            state.uart.fill_fifo();
+            let mut read = 0;
+            while read < len {
+                match state.uart.take_input() {
+                    Some(byte) => state.guest_memory.slice_mut(addr + read, 1)[0] = byte,
+                    None => break,
+                }
+                read += 1;
+            }
+            (SBI_SUCCESS, read)
+        }
+        // console_write_byte(byte)
+        2 => {
+            let byte = state.saved_registers.get(10) as u8;
+            state.uart.output_byte(byte);
+            state.schedule_console_flush();
+            (SBI_SUCCESS, 0)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn rvirt(state: &mut Context, function: u64) -> (i64, u64) {
+    match function {
+        RVIRT_EVENT_NEXT_SEQUENCE | RVIRT_EVENT_READ => events::handle_call(state, function),
+        RVIRT_IDENTIFY => guestos::identify(state),
+        RVIRT_PROFILE_START | RVIRT_PROFILE_STOP | RVIRT_PROFILE_READ => profile::handle_call(state, function),
+        RVIRT_BOOT_MARKER => boottime::handle_marker(),
+        RVIRT_IRQ_ACK => irqlatency::handle_ack(state),
+        RVIRT_WATCH_ADD | RVIRT_WATCH_REMOVE | RVIRT_WATCH_READ => watch::handle_call(state, function),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
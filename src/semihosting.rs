@@ -0,0 +1,155 @@
+//! RISC-V semihosting.
+//!
+//! When built with the `semihosting` feature and run by an emulator with semihosting enabled (for
+//! QEMU, `-semihosting-config enable=on`), requests can be made of the host through a special
+//! `ebreak` sequence. The hypervisor uses this to report exit codes from automated runs and to write
+//! guest core dumps to the host, and guests running in supervisor mode can issue the same calls to
+//! write files on the host or to exit.
+//!
+//! Without the feature the sequence isn't recognized and guests just see a breakpoint exception.
+
+use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::context::Context;
+use crate::dispatch::Trap;
+use crate::exits::ExitReason;
+use crate::{riscv, sum, trap};
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Mode of SYS_OPEN that creates or truncates a file for writing, as fopen's "wb".
+const OPEN_MODE_WB: u64 = 5;
+
+// The instructions surrounding the ebreak that mark it as a semihosting call.
+const SLLI_ZERO_ZERO_31: u32 = 0x01f01013;
+const EBREAK: u32 = 0x00100073;
+const SRAI_ZERO_ZERO_7: u32 = 0x40705013;
+
+/// Make a semihosting call. Pointers in `param` are resolved by the host through the currently
+/// installed page table.
+#[cfg(feature = "semihosting")]
+unsafe fn call(op: u64, param: u64) -> u64 {
+    let result: u64;
+    asm!(".option push
+          .option norvc
+          .align 4
+          slli zero, zero, 0x1f
+          ebreak
+          srai zero, zero, 7
+          .option pop" : "={a0}"(result) : "{a0}"(op), "{a1}"(param) : "memory" : "volatile");
+    result
+}
+
+#[cfg(not(feature = "semihosting"))]
+unsafe fn call(_op: u64, _param: u64) -> u64 {
+    unreachable!()
+}
+
+/// Ask the host to exit with the given status. Returns if semihosting isn't enabled.
+pub fn exit(code: u64) {
+    if cfg!(feature = "semihosting") {
+        let block = [ADP_STOPPED_APPLICATION_EXIT, code];
+        unsafe { call(SYS_EXIT, block.as_ptr() as u64) };
+    }
+}
+
+/// A file on the host, opened for writing.
+pub struct HostFile(u64);
+
+impl HostFile {
+    /// Create the file `name` on the host, or truncate it if it exists. None if semihosting isn't
+    /// enabled or the host refuses.
+    pub fn create(name: &str) -> Option<Self> {
+        if !cfg!(feature = "semihosting") {
+            return None;
+        }
+        let mut path = ArrayVec::<[u8; 64]>::new();
+        if name.len() >= path.capacity() {
+            return None;
+        }
+        path.extend(name.bytes().chain(Some(0)));
+        let block = [path.as_ptr() as u64, OPEN_MODE_WB, name.len() as u64];
+        match unsafe { call(SYS_OPEN, block.as_ptr() as u64) } as i64 {
+            -1 => None,
+            handle => Some(HostFile(handle as u64)),
+        }
+    }
+
+    /// Append `data` to the file. Returns false if it couldn't all be written.
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        let block = [self.0, data.as_ptr() as u64, data.len() as u64];
+        unsafe { call(SYS_WRITE, block.as_ptr() as u64) == 0 }
+    }
+}
+
+impl Drop for HostFile {
+    fn drop(&mut self) {
+        let block = [self.0];
+        unsafe { call(SYS_CLOSE, block.as_ptr() as u64) };
+    }
+}
+
+/// Handle a breakpoint trap if it is a semihosting call.
+pub fn handle_trap(state: &mut Context, trap: &Trap) -> bool {
+    if !handle_guest_call(state, trap.pc) {
+        return false;
+    }
+    state.record_exit(ExitReason::Semihosting);
+    trap::maybe_forward_interrupt(state, csrr!(sepc));
+    true
+}
+
+/// Check whether the breakpoint at `sepc` is a semihosting call from the guest kernel and if so
+/// carry it out. Returns false if the breakpoint should be forwarded to the guest instead.
+pub fn handle_guest_call(state: &mut Context, sepc: u64) -> bool {
+    if !cfg!(feature = "semihosting") || !state.smode() {
+        return false;
+    }
+
+    let mut sequence = [0; 12];
+    if sum::copy_from_guest(&mut sequence, sepc.wrapping_sub(4)).is_err() {
+        return false;
+    }
+    if LittleEndian::read_u32(&sequence) != SLLI_ZERO_ZERO_31
+        || LittleEndian::read_u32(&sequence[4..]) != EBREAK
+        || LittleEndian::read_u32(&sequence[8..]) != SRAI_ZERO_ZERO_7 {
+        return false;
+    }
+
+    let op = state.saved_registers.get(10);
+    let param = state.saved_registers.get(11);
+    let result = match op {
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            let mut block = [0; 16];
+            let code = match sum::copy_from_guest(&mut block, param) {
+                Ok(()) if LittleEndian::read_u64(&block) == ADP_STOPPED_APPLICATION_EXIT => {
+                    LittleEndian::read_u64(&block[8..])
+                }
+                _ => 1,
+            };
+            trap::guest_exited(state, code);
+        }
+        // Buffers are passed through as guest virtual addresses, which the host can resolve
+        // because the guest's shadow page table is installed.
+        SYS_OPEN | SYS_CLOSE | SYS_WRITEC | SYS_WRITE0 | SYS_WRITE | SYS_READ | SYS_ISTTY |
+        SYS_SEEK | SYS_FLEN | SYS_ERRNO => unsafe { call(op, param) },
+        _ => u64::max_value(),
+    };
+
+    state.saved_registers.set(10, result);
+    riscv::set_sepc(sepc + 8);
+    true
+}
@@ -0,0 +1,67 @@
+//! Shutting down the whole machine without pulling the plug on the guests.
+//!
+//! The monitor's `shutdown [seconds]` command asks every guest to power off and gives them that
+//! long (`DEFAULT_TIMEOUT_SECS` unless given) to do so. The request reaches each guest through its
+//! console, as a serial break followed by `o`, which Linux treats as the magic SysRq for an orderly
+//! poweroff if SysRq is enabled, and through a `ShutdownRequested` event for any agent watching the
+//! event log. Guests that are still running when the time is up are stopped. Either way, each
+//! guest's emulated block devices are flushed as it stops, and once the last guest has stopped the
+//! machine powers off just as it does when the guests shut down of their own accord.
+//!
+//! Other harts learn of the request through an IPI, and crashed guests aren't restarted while a
+//! shutdown is in progress.
+
+use core::sync::atomic::Ordering;
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::{Context, Uart};
+use crate::events::{self, EventKind};
+use crate::statics::SHARED_STATICS;
+use crate::timer::TimerEvent;
+use crate::{hart, riscv, trap};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Whether the machine is being shut down.
+pub fn in_progress() -> bool {
+    SHARED_STATICS.shutdown_deadline.load(Ordering::SeqCst) != 0
+}
+
+/// Ask every guest to power off within `timeout_secs` seconds.
+pub fn request(state: &mut Context, timeout_secs: u64) {
+    let deadline = state.host_clint.get_mtime() + timeout_secs * TIMER_FREQUENCY;
+    if SHARED_STATICS.shutdown_deadline.compare_and_swap(0, deadline, Ordering::SeqCst) != 0 {
+        println!("Already shutting down");
+        return;
+    }
+    println!("Shutting down, giving guests {}s to power off", timeout_secs);
+
+    let current = hart::current().hartid;
+    for hartid in SHARED_STATICS.hart_ids.iter().map(|h| h.load(Ordering::SeqCst)) {
+        if hartid != u64::max_value() && hartid != current {
+            riscv::sbi::send_ipi_to_hart(hartid);
+        }
+    }
+    check(state);
+}
+
+/// Pass a shutdown request on to this hart's guest, unless it already has it. Called when an IPI
+/// arrives.
+pub fn check(state: &mut Context) {
+    let deadline = SHARED_STATICS.shutdown_deadline.load(Ordering::SeqCst);
+    if deadline == 0 || state.timers.deadline(TimerEvent::ShutdownDeadline).is_some() {
+        return;
+    }
+
+    state.uart.inject_sysrq(b'o');
+    let now = state.host_clint.get_mtime();
+    Uart::update_interrupt(state, now);
+    events::record(state, EventKind::ShutdownRequested, deadline);
+    state.schedule_timer(TimerEvent::ShutdownDeadline, deadline);
+}
+
+/// Stop a guest that didn't power off in time. Called from the timer.
+pub fn deadline_passed(state: &mut Context) -> ! {
+    println!("Guest {} didn't power off in time, stopping it", hart::current().guest_index());
+    let code = state.shutdown_exit_code;
+    trap::guest_exited(state, code)
+}
@@ -0,0 +1,141 @@
+//! Spinlock that keeps interrupts disabled while it is held.
+//!
+//! Locks in `SHARED_STATICS` are taken both by ordinary code and from trap handlers. If an
+//! interrupt arrived while its hart held one of them and the handler then took the same lock, the
+//! hart would spin forever on a lock that only it could release. Taking a `SpinLock` therefore
+//! disables interrupts for the current privilege mode (`sstatus.SIE` in the hypervisor, or
+//! `mstatus.MIE` in machine mode) until the guard is dropped.
+//!
+//! Each lock also records which hart holds it. Taking a lock this hart already holds panics rather
+//! than hanging, and a hart that spins for a long time prints who it is waiting on.
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::hart;
+use crate::print::EmergencyWriter;
+use crate::riscv::bits::{STATUS_MIE, STATUS_SIE};
+
+/// Spins before a waiting hart reports that the lock may be deadlocked. It keeps waiting after that.
+const DEADLOCK_SPINS: u64 = 1 << 28;
+
+pub struct SpinLock<T> {
+    name: &'static str,
+    locked: AtomicBool,
+    /// Token of the hart that holds the lock (see `owner_token`), or zero.
+    owner: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    /// Whether interrupts were enabled before the lock was taken.
+    interrupts: bool,
+}
+
+/// Identifies the current hart while it holds a lock: one more than its hartid. During boot, before
+/// the hypervisor has set up its hart-local data, it can't know the hartid and uses `satp` instead,
+/// since no two harts ever share a page table root.
+fn owner_token() -> u64 {
+    if cfg!(feature = "physical_symbol_addresses") {
+        csrr!(mhartid) + 1
+    } else {
+        hart::try_current().map(|h| h.hartid + 1).unwrap_or_else(|| csrr!(satp))
+    }
+}
+
+/// Disable interrupts, returning whether they were enabled.
+fn disable_interrupts() -> bool {
+    if cfg!(feature = "physical_symbol_addresses") {
+        let status = csrr!(mstatus);
+        unsafe { csrc!(mstatus, STATUS_MIE) };
+        status & STATUS_MIE != 0
+    } else {
+        let status = csrr!(sstatus);
+        unsafe { csrci!(sstatus, STATUS_SIE) };
+        status & STATUS_SIE != 0
+    }
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        if cfg!(feature = "physical_symbol_addresses") {
+            unsafe { csrs!(mstatus, STATUS_MIE) };
+        } else {
+            unsafe { csrsi!(sstatus, STATUS_SIE) };
+        }
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let interrupts = disable_interrupts();
+        let token = owner_token();
+        let mut spins = 0u64;
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            if spins == 0 && token != 0 && self.owner.load(Ordering::Relaxed) == token {
+                panic!("deadlock: lock `{}` is already held by this hart", self.name);
+            }
+            spins += 1;
+            if spins == DEADLOCK_SPINS {
+                // Printing normally takes the UART lock, which may be the one that is stuck.
+                let _ = writeln!(EmergencyWriter::new(),
+                                 "WARN: possible deadlock on lock `{}`: waiter {:#x}, holder {:#x}",
+                                 self.name, token, self.owner.load(Ordering::Relaxed));
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+        self.owner.store(token, Ordering::Relaxed);
+        SpinLockGuard { lock: self, interrupts }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let interrupts = disable_interrupts();
+        if self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            restore_interrupts(interrupts);
+            return None;
+        }
+        self.owner.store(owner_token(), Ordering::Relaxed);
+        Some(SpinLockGuard { lock: self, interrupts })
+    }
+
+    /// Release the lock without a guard, for when its holder will never release it. Interrupts are
+    /// left as they are.
+    pub unsafe fn force_unlock(&self) {
+        self.owner.store(0, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(0, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+        restore_interrupts(self.interrupts);
+    }
+}
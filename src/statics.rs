@@ -1,9 +1,18 @@
 use arr_macro::arr;
-use core::sync::atomic::AtomicBool;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::boottime::BootTimeline;
+use crate::config::Config;
+use crate::console::ConsoleInput;
+use crate::events::EventLog;
+use crate::exits::ExitCounters;
+use crate::guestos::GuestOs;
 use crate::constants::*;
+use crate::memusage::MemoryUsage;
+use crate::handoff::ParkStack;
+use crate::overlay::{CowDisks, ReadOnlyDisks};
 use crate::print::{self, UartWriter};
 use crate::pmap;
+use crate::spinlock::SpinLock;
 
 #[derive(Copy, Clone, Debug)]
 pub enum IpiReason {
@@ -15,15 +24,88 @@ pub enum IpiReason {
         a4: u64,
         sp: u64,
         satp: u64,
-    }
+    },
+    /// Take over the running guest of the hart with index `from_index`, switching to the page
+    /// tables at `satp`. See handoff.rs.
+    ResumeGuest {
+        from_index: usize,
+        satp: u64,
+    },
 }
 
 #[repr(C,align(4096))]
 pub struct Shared {
     pub boot_page_tables: [[u64; 1024]; MAX_HOST_HARTS],
-    pub ipi_reason_array: [Mutex<Option<IpiReason>>; MAX_HOST_HARTS],
-    pub uart_writer: Mutex<UartWriter>,
+    /// Number of harts that have entered the supervisor. Each one takes the next boot page table
+    /// and early stack, so hartids don't need to be small or contiguous. Accessed from scode.S.
+    pub boot_slots: AtomicU64,
+    /// Hartids of the harts listed in the device tree. The position of a hartid in this array is
+    /// the index used for that hart's entries in other per-hart arrays. Unused entries hold
+    /// `u64::max_value()`.
+    pub hart_ids: [AtomicU64; MAX_HOST_HARTS],
+    pub ipi_reason_array: [SpinLock<Option<IpiReason>>; MAX_HOST_HARTS],
+    /// Segments of memory given to each hart's guest beyond the hart's own, as a bitmap of segment
+    /// numbers (see `pmap::SegmentPool`). Indexed like `hart_ids`.
+    pub extra_segments: [AtomicU64; MAX_HOST_HARTS],
+    /// How far each hart has got in starting its guest, indexed like `hart_ids`. See bootstatus.rs.
+    pub boot_status: [AtomicU64; MAX_HOST_HARTS],
+    pub uart_writer: SpinLock<UartWriter>,
+    /// Copy of the UART configuration that can be read without taking the lock on `uart_writer`.
+    /// See `print::EmergencyWriter`.
+    pub uart_snapshot: AtomicU64,
+    /// Baud rate of the UART, for waiting for it to drain. See `print::flush`.
+    pub uart_baud: AtomicU64,
+    /// Physical address of the copy of recent console output, or zero if there isn't one. See
+    /// logtail.rs.
+    pub log_tail: AtomicU64,
     pub hart_lottery: AtomicBool,
+    /// Number of guests that haven't exited yet, and the first non-zero exit code reported by one
+    /// that has. See `trap::guest_exited`.
+    pub guests_running: AtomicU64,
+    pub exit_code: AtomicU64,
+    /// When guests have to have powered off by, in host time, once a shutdown of the whole machine
+    /// has been requested. Zero otherwise. See shutdown.rs.
+    pub shutdown_deadline: AtomicU64,
+    pub console_input: SpinLock<ConsoleInput>,
+    /// Why each guest's hart has trapped into the hypervisor, indexed by guestid.
+    pub exit_stats: [ExitCounters; MAX_GUESTS],
+    /// How many times each guest has been restarted after crashing, indexed by guestid. Kept here
+    /// because everything in a guest's own segment is rebuilt when it restarts.
+    pub guest_restarts: [AtomicU64; MAX_GUESTS],
+    /// The last time each guest asked to power off or reset, packed by `restart::ResetRequest`, or
+    /// zero if it hasn't. Indexed by guestid.
+    pub reset_requests: [AtomicU64; MAX_GUESTS],
+    /// How much host memory each guest is using, indexed by guestid. See memusage.rs.
+    pub memory_usage: [MemoryUsage; MAX_GUESTS],
+    /// When each guest reached each milestone of its boot, indexed by guestid. See boottime.rs.
+    pub boot_timelines: [BootTimeline; MAX_GUESTS],
+    /// Lifecycle events of every guest. See events.rs.
+    pub events: SpinLock<EventLog>,
+    /// What each guest was identified as, indexed by guestid. See guestos.rs.
+    pub guest_os: [SpinLock<GuestOs>; MAX_GUESTS],
+    /// Guest using each of the host's virtio devices, in the order of `MachineMeta::virtio`, or zero
+    /// if it is free to be added to a guest.
+    pub virtio_owners: [AtomicU64; 16],
+    /// Base and overlay disks behind every guest's copy-on-write disk. See overlay.rs.
+    pub cow_disks: SpinLock<Option<CowDisks>>,
+    /// Host disks that guests share read-only. See overlay.rs.
+    pub readonly_disks: SpinLock<ReadOnlyDisks>,
+    /// Settings kept on the config disk. See config.rs.
+    pub config: SpinLock<Config>,
+    /// Harts without a guest that are waiting in `hart_entry` to be given one, as a bitmap of hart
+    /// indices. See handoff.rs.
+    pub parked_harts: AtomicU64,
+    /// Stacks for harts to wait on once their guest has moved away from them, indexed like
+    /// `hart_ids`.
+    pub park_stacks: [ParkStack; MAX_HOST_HARTS],
+}
+
+impl Shared {
+    /// Look up the index of a hart in per-hart arrays. Only valid once the boot hart has filled in
+    /// `hart_ids`.
+    pub fn hart_index(&self, hartid: u64) -> Option<usize> {
+        self.hart_ids.iter().position(|id| id.load(Ordering::SeqCst) == hartid)
+    }
 }
 
 pub struct ConditionalPointer(u64);
@@ -43,6 +125,14 @@ impl core::ops::Deref for ConditionalPointer {
     }
 }
 
+/// Physical address of `SHARED_STATICS.boot_slots`, which scode.S increments before paging is
+/// enabled. It follows the boot page tables, the first field of `Shared`.
+pub const BOOT_SLOTS_ADDRESS: u64 = SUPERVISOR_SHARED_STATIC_ADDRESS - SYMBOL_PA2VA_OFFSET
+    + core::mem::size_of::<[[u64; 1024]; MAX_HOST_HARTS]>() as u64;
+
+// scode.S has this address hard coded, so fail to build if it moves.
+const _: [(); 1] = [(); (BOOT_SLOTS_ADDRESS == 0x80220000) as usize];
+
 const fn make_boot_page_tables_array() -> [[u64; 1024]; MAX_HOST_HARTS] {
     const BASE: u64 = SUPERVISOR_SHARED_STATIC_ADDRESS - SYMBOL_PA2VA_OFFSET;
     const STRIDE: u64 = 1024 * 8;
@@ -61,11 +151,32 @@ const fn make_boot_page_tables_array() -> [[u64; 1024]; MAX_HOST_HARTS] {
 #[link_section = ".shared.data"]
 pub static __SHARED_STATICS_IMPL: Shared = Shared {
     boot_page_tables: make_boot_page_tables_array(),
-    ipi_reason_array: arr![Mutex::new(None); 16],
+    boot_slots: AtomicU64::new(0),
+    hart_ids: arr![AtomicU64::new(u64::max_value()); 16],
+    ipi_reason_array: arr![SpinLock::new("ipi_reason", None); 16],
+    extra_segments: arr![AtomicU64::new(0); 16],
+    boot_status: arr![AtomicU64::new(0); 16],
     // see also: print::early_guess_uart
-    uart_writer: Mutex::new(UartWriter {
-        pa: 0x10000000,
-        inner: print::UartWriterInner::Ns16550a { initialized: false },
-    }),
+    uart_writer: SpinLock::new("uart_writer", print::EARLY_UART),
+    uart_snapshot: AtomicU64::new(print::EARLY_UART_SNAPSHOT),
+    uart_baud: AtomicU64::new(print::DEFAULT_BAUD),
+    log_tail: AtomicU64::new(0),
     hart_lottery: AtomicBool::new(true),
+    guests_running: AtomicU64::new(0),
+    exit_code: AtomicU64::new(0),
+    shutdown_deadline: AtomicU64::new(0),
+    console_input: SpinLock::new("console_input", ConsoleInput::new()),
+    exit_stats: arr![ExitCounters::new(); 16],
+    guest_restarts: arr![AtomicU64::new(0); 16],
+    reset_requests: arr![AtomicU64::new(0); 16],
+    memory_usage: arr![MemoryUsage::new(); 16],
+    boot_timelines: arr![BootTimeline::new(); 16],
+    events: SpinLock::new("events", EventLog::new()),
+    guest_os: arr![SpinLock::new("guest_os", GuestOs::UNKNOWN); 16],
+    virtio_owners: arr![AtomicU64::new(0); 16],
+    cow_disks: SpinLock::new("cow_disks", None),
+    readonly_disks: SpinLock::new("readonly_disks", [None, None, None, None]),
+    config: SpinLock::new("config", Config::new()),
+    parked_harts: AtomicU64::new(0),
+    park_stacks: arr![ParkStack::new(); 16],
 };
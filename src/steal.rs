@@ -0,0 +1,94 @@
+//! Steal time, reported to guests through the SBI steal-time accounting (STA) extension.
+//!
+//! Each guest has a hart to itself, so it never waits for another guest to be scheduled. What it
+//! does lose is the time its hart spends handling interrupts: host timer events, console input,
+//! device interrupts and requests from the monitor all run on the guest's hart while the guest
+//! stands still. That time is counted as stolen. Time spent emulating instructions and handling
+//! page faults is work the guest asked for, and isn't.
+//!
+//! A guest registers a 64 byte structure with `set_shmem`, and the hypervisor keeps the steal field
+//! in it up to date after every interrupt. Updates are bracketed by increments of the sequence field
+//! as the specification requires, though the guest can't see one half done, since it only runs on
+//! this hart once the update is over.
+
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::Context;
+use crate::sbi::*;
+
+const STA_SET_SHMEM: u64 = 0;
+
+const SHMEM_SIZE: u64 = 64;
+const OFFSET_SEQUENCE: u64 = 0;
+const OFFSET_STEAL: u64 = 8;
+
+pub struct StealTime {
+    /// Guest physical address of the guest's structure, if it has registered one.
+    shmem: Option<u64>,
+    /// Stolen time so far, in ticks of `mtime`.
+    stolen: u64,
+    sequence: u32,
+}
+
+impl StealTime {
+    pub fn new() -> Self {
+        Self { shmem: None, stolen: 0, sequence: 0 }
+    }
+}
+
+pub fn handle_call(state: &mut Context, function: u64) -> (i64, u64) {
+    match function {
+        // set_shmem(shmem_phys_lo, shmem_phys_hi, flags)
+        STA_SET_SHMEM => {
+            let (lo, hi, flags) = (state.saved_registers.get(10), state.saved_registers.get(11),
+                                   state.saved_registers.get(12));
+            if flags != 0 {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+            if lo == u64::max_value() && hi == u64::max_value() {
+                state.steal.shmem = None;
+                return (SBI_SUCCESS, 0);
+            }
+            if lo % SHMEM_SIZE != 0 {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+            if hi != 0 || !state.prepare_guest_access(lo, SHMEM_SIZE, true) {
+                return (SBI_ERR_INVALID_ADDRESS, 0);
+            }
+
+            for byte in state.guest_memory.slice_mut(lo, SHMEM_SIZE) {
+                *byte = 0;
+            }
+            state.steal.shmem = Some(lo);
+            state.steal.sequence = 0;
+            publish(state);
+            (SBI_SUCCESS, 0)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// Count the time since `start` (a value of `mtime`) as stolen from the guest. Called after
+/// handling an interrupt.
+pub fn account(state: &mut Context, start: u64) {
+    let now = state.host_clint.get_mtime();
+    state.steal.stolen += now.saturating_sub(start);
+    publish(state);
+}
+
+/// Write the stolen time into the guest's structure, if it has one.
+fn publish(state: &mut Context) {
+    let addr = match state.steal.shmem {
+        Some(addr) => addr,
+        None => return,
+    };
+    if !state.prepare_guest_access(addr, SHMEM_SIZE, true) {
+        return;
+    }
+
+    let steal_ns = state.steal.stolen * (1_000_000_000 / TIMER_FREQUENCY);
+    let sequence = state.steal.sequence;
+    state.guest_memory.slice_mut(addr + OFFSET_SEQUENCE, 4).copy_from_slice(&sequence.wrapping_add(1).to_le_bytes());
+    state.guest_memory.slice_mut(addr + OFFSET_STEAL, 8).copy_from_slice(&steal_ns.to_le_bytes());
+    state.guest_memory.slice_mut(addr + OFFSET_SEQUENCE, 4).copy_from_slice(&sequence.wrapping_add(2).to_le_bytes());
+    state.steal.sequence = sequence.wrapping_add(2);
+}
@@ -1,10 +1,89 @@
+//! Access to memory through the guest's mappings.
+//!
+//! Guest memory that is reached through user mappings (either the guest's own virtual addresses
+//! under the shadow page tables, or guest physical addresses under the MPA tables during boot) may
+//! not be mapped at all. The copy functions here point `stvec` at a fixup handler for the duration
+//! of the copy, so that a fault makes them return an error instead of trapping into the hypervisor.
 
-// use crate::trap::constants::STATUS_SUM;
+use crate::error::{Error, Result};
+use crate::riscv::bits::STATUS_SIE;
 
-#[inline(always)]
-pub fn access_user_memory<T, F: Fn() -> T>(f: F) -> T {
-//    csrs!(sstatus, STATUS_SUM);
-    let t = f();
-//    csrc!(sstatus, STATUS_SUM);
-    t
+global_asm!("
+.align 2
+.globl guest_access_fault
+guest_access_fault:
+    // Resume at the failure path of the interrupted copy. The faulting address is left in stval.
+    la t0, 2f
+    csrw sepc, t0
+    sret
+2:  li a0, 1
+    ret
+
+// guest_copy(dst, src, len) -> 0 on success
+.globl guest_copy
+guest_copy:
+    beqz a2, 1f
+    lbu t0, 0(a1)
+    sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    j guest_copy
+1:  li a0, 0
+    ret
+
+// guest_fill(dst, value, len) -> 0 on success
+.globl guest_fill
+guest_fill:
+    beqz a2, 1f
+    sb a1, 0(a0)
+    addi a0, a0, 1
+    addi a2, a2, -1
+    j guest_fill
+1:  li a0, 0
+    ret
+");
+
+extern {
+    fn guest_access_fault();
+    fn guest_copy(dst: *mut u8, src: *const u8, len: usize) -> u64;
+    fn guest_fill(dst: *mut u8, value: u8, len: usize) -> u64;
+}
+
+/// Run one of the copy routines with faults redirected to the fixup handler. The trap CSRs are
+/// preserved, since callers are often in the middle of handling a trap themselves.
+unsafe fn with_fixup<F: FnOnce() -> u64>(f: F) -> Result<()> {
+    let saved = (csrr!(stvec), csrr!(sepc), csrr!(scause), csrr!(stval), csrr!(sstatus));
+    csrc!(sstatus, STATUS_SIE);
+    csrw!(stvec, guest_access_fault as *const () as u64);
+
+    let failed = f() != 0;
+    let fault_address = csrr!(stval);
+
+    csrw!(stvec, saved.0);
+    csrw!(sepc, saved.1);
+    csrw!(scause, saved.2);
+    csrw!(stval, saved.3);
+    csrs!(sstatus, saved.4 & STATUS_SIE);
+
+    if failed {
+        Err(Error::InaccessibleGuestMemory(fault_address))
+    } else {
+        Ok(())
+    }
+}
+
+/// Copy from guest memory at `guest_va` (as currently mapped) into `dst`.
+pub fn copy_from_guest(dst: &mut [u8], guest_va: u64) -> Result<()> {
+    unsafe { with_fixup(|| guest_copy(dst.as_mut_ptr(), guest_va as *const u8, dst.len())) }
+}
+
+/// Copy `src` into guest memory at `guest_va` (as currently mapped).
+pub fn copy_to_guest(guest_va: u64, src: &[u8]) -> Result<()> {
+    unsafe { with_fixup(|| guest_copy(guest_va as *mut u8, src.as_ptr(), src.len())) }
+}
+
+/// Fill `len` bytes of guest memory at `guest_va` (as currently mapped) with zeros.
+pub fn zero_guest(guest_va: u64, len: usize) -> Result<()> {
+    unsafe { with_fixup(|| guest_fill(guest_va as *mut u8, 0, len)) }
 }
@@ -10,16 +10,33 @@
 #![feature(start)]
 #![feature(try_blocks)]
 
+use arrayvec::ArrayVec;
 use rvirt::*;
+use rvirt::bootstatus::{BootFailure, BootStatus};
+use rvirt::boottime::Milestone;
 
 // mandatory rust environment setup
 #[lang = "eh_personality"] extern fn eh_personality() {}
-#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! { println!("{}", info); loop {}}
+#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! {
+    print::print_panic(info);
+    bootstatus::panicked();
+    loop {}
+}
 #[start] fn start(_argc: isize, _argv: *const *const u8) -> isize {0}
 #[no_mangle] fn abort() -> ! { println!("Abort!"); loop {}}
 
 static GUEST_DTB: &'static [u8] = include_bytes!("guest.dtb");
 
+/// Device tree overlay applied on top of `GUEST_DTB` for every guest.
+#[cfg(feature = "embed_guest_overlay")]
+static GUEST_DTB_OVERLAY: Option<&'static [u8]> = Some(include_bytes!(env!("RVIRT_GUEST_OVERLAY")));
+
+#[cfg(not(feature = "embed_guest_overlay"))]
+static GUEST_DTB_OVERLAY: Option<&'static [u8]> = None;
+
+/// Space reserved in guest memory for the generated guest device tree.
+const MAX_GUEST_DTB_SIZE: usize = 64 << 10;
+
 #[link_section = ".initrd"]
 #[cfg(feature = "embed_guest_kernel")]
 static GUEST_KERNEL: [u8; include_bytes!(env!("RVIRT_GUEST_KERNEL")).len()] =
@@ -53,22 +70,47 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
     csrw!(stvec, panic_trap_handler as *const () as u64);
 
     // Read and process host FDT.
-    let mut fdt = Fdt::new(pa2va(device_tree_blob));
-    assert!(fdt.magic_valid());
-    assert!(fdt.version() >= 17 && fdt.last_comp_version() <= 17);
-    assert!(fdt.total_size() < 64 * 1024);
-    let machine = fdt.parse();
+    let mut fdt = Fdt::new(pa2va(device_tree_blob)).expect("Invalid host device tree");
+    assert!((fdt.total_size() as usize) < pmap::FDT_SIZE as usize);
+    let mut machine = fdt.parse().expect("Invalid host device tree");
 
     // Initialize UART
     if let Some(ty) = machine.uart_type {
-        SHARED_STATICS.uart_writer.lock().init(machine.uart_address, ty);
+        SHARED_STATICS.uart_writer.lock().init(machine.uart_address, ty, machine.uart_baud);
+    }
+
+    if cfg!(feature = "delegation_audit") {
+        delegaudit::run();
+    }
+
+    let layout = layout::MachineLayout::new(&machine, shared_segments_shift);
+
+    // Settings on the config disk replace those in /chosen, so they have to be read before any of
+    // the others are used.
+    let mut shared_dma = overlay::shared_dma_pool(&machine, &layout);
+    if let Some(dma) = shared_dma.as_mut() {
+        config::load(&mut machine, dma);
+    }
+
+    // The log tail's area isn't looked at, let alone written, if it might hold anything else.
+    let log_tail_end = layout.log_tail + constants::LOG_TAIL_SIZE;
+    if machine.is_reserved(layout.log_tail, constants::LOG_TAIL_SIZE)
+        || (machine.initrd_start < log_tail_end && layout.log_tail < machine.initrd_end) {
+        if machine.log_tail_kb > 0 {
+            println!("WARN: Log tail area overlaps reserved memory or the initrd, not keeping one");
+        }
+    } else {
+        logtail::init(layout.log_tail, machine.log_tail_kb);
     }
 
     // Do some sanity checks now that the UART is initialized and we have a better chance of
     // successfully printing output.
     assert!(machine.initrd_end <= machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE);
-    assert!(machine.initrd_end - machine.initrd_start <= pmap::HEAP_SIZE);
-    assert!(machine.harts.iter().any(|h| h.hartid == hartid));
+    assert!(machine.initrd_end - machine.initrd_start <= pmap::HEAP_SIZE - constants::DMA_POOL_SIZE);
+    // Any hart that makes it to supervisor mode may win the lottery, but it has to be one that
+    // the device tree also describes as usable.
+    assert!(machine.harts.iter().any(|h| h.hartid == hartid),
+            "Boot hart {} is not a usable hart in the device tree", hartid);
     if !cfg!(feature = "embed_guest_kernel") && machine.initrd_end == 0 {
         println!("WARN: No guest kernel provided. Make sure to pass one with `-initrd or compile with --features embed_guest_kernel`");
     }
@@ -76,9 +118,34 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
     // Do not allow the __SHARED_STATICS_IMPL symbol to be optimized out.
     assert_eq!(&__SHARED_STATICS_IMPL as *const _ as u64, constants::SUPERVISOR_SHARED_STATIC_ADDRESS);
 
-    // Program PLIC priorities
-    for i in 1..127 {
-        *(pa2va(machine.plic_address + i*4) as *mut u32) = 1;
+    // Program PLIC priorities, or with AIA, the trigger mode of each APLIC source.
+    let aplic = aia::Aplic::new(machine.plic_address, machine.irqchip == IrqChip::AplicMsi);
+    if machine.irqchip == IrqChip::Plic {
+        for i in 1..127 {
+            *(pa2va(machine.plic_address + i*4) as *mut u32) = 1;
+        }
+    } else {
+        aplic.init(127);
+    }
+
+    // Console input is interrupt driven if possible. See console.rs.
+    SHARED_STATICS.console_input.lock().set_uart_irq(machine.irqchip, machine.plic_address, machine.uart_irq);
+    if machine.uart_irq.is_some() {
+        SHARED_STATICS.uart_writer.lock().enable_rx_interrupt();
+    }
+
+    for (i, hart) in machine.harts.iter().enumerate() {
+        SHARED_STATICS.hart_ids[i].store(hart.hartid, Ordering::SeqCst);
+    }
+
+    // Other firmware only starts the boot hart, and the rest have to be asked for. They come in at
+    // sstart just as they would from rvirt-bare-metal, and wait there for their guests.
+    if cfg!(feature = "smode_only") {
+        for hart in machine.harts.iter().filter(|h| h.hartid != hartid) {
+            if let Err(error) = riscv::sbi::hart_start(hart.hartid, layout.hypervisor_base, device_tree_blob) {
+                println!("WARN: Firmware failed to start hart {} (error {})", hart.hartid, error);
+            }
+        }
     }
 
     let mut guest_harts = machine.harts.clone();
@@ -86,36 +153,96 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
     if !single_hart {
         guest_harts.retain(|h| h.hartid != hartid);
     }
+    // Guests still run one per hart, so any harts beyond the configured number of guests are left
+    // parked rather than each being given a segment of memory.
+    let max_guests = match machine.options.guests.unwrap_or(machine.max_guests) {
+        0 => constants::MAX_GUESTS,
+        n => (n as usize).min(constants::MAX_GUESTS),
+    };
+    while guest_harts.len() > max_guests {
+        guest_harts.pop();
+    }
     let single_guest = guest_harts.len() == 1;
     assert!(guest_harts.len() != 0);
+    SHARED_STATICS.guests_running.store(guest_harts.len() as u64, Ordering::SeqCst);
+    match machine.options.focus {
+        Some(guestid) if guestid >= 1 && guestid <= guest_harts.len() as u64 => {
+            SHARED_STATICS.console_input.lock().set_initial_focus(guestid);
+        }
+        Some(guestid) => println!("WARN: There is no guest {} to send console input to", guestid),
+        None => {}
+    }
 
-    assert!(1 + guest_harts.len() as u64 <= (machine.physical_memory_size >> 30));
+    if let Some(dma) = shared_dma.as_mut() {
+        overlay::init(&machine, guest_harts.len() as u64, dma);
+    }
 
+    // Each guest gets its own segment of memory. Every guest that can be started has its first
+    // segment before any guest gets more, and extra segments that can't all be handed out are
+    // refused to the guests that would otherwise have the most.
+    let mut segments = pmap::SegmentPool::new(&machine, &layout);
     let mut guestid = 1;
+    let mut started = ArrayVec::<[(u64, u64, usize); constants::MAX_HOST_HARTS]>::new();
+    let guests = guest_harts.len() as u64;
+    let starting = guests.min(segments.free_count());
+    let requested: ArrayVec<[u64; constants::MAX_GUESTS]> =
+        (1..=starting).map(|guestid| machine.guest_segments(guestid) - 1).collect();
+    let mut granted = requested.clone();
+    pmap::share_segments(&mut granted, segments.free_count() - starting);
     for hart in guest_harts {
-        let hart_base_pa = machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE * guestid;
+        let hart_base_pa = match segments.allocate(&layout) {
+            Some(pa) => pa,
+            None => {
+                println!("Not enough memory for guests {} to {}, they won't be started", guestid, guests);
+                SHARED_STATICS.guests_running.fetch_sub(guests + 1 - guestid as u64, Ordering::SeqCst);
+                break;
+            }
+        };
+
+        // Large guests continue into more segments, wherever there are free ones.
+        let wanted = requested[guestid as usize - 1];
+        let extra = segments.allocate_extra(granted[guestid as usize - 1]);
+        let refused = wanted - extra.count_ones() as u64;
+        if refused != 0 {
+            println!("WARN: Not enough memory to give guest {} {}GB, it gets {}GB", guestid, wanted + 1,
+                     extra.count_ones() + 1);
+        }
+        let index = SHARED_STATICS.hart_index(hart.hartid).unwrap();
+        SHARED_STATICS.extra_segments[index].store(extra, Ordering::SeqCst);
+        if machine.options.loglevel >= 2 {
+            println!("Guest {} runs on hart {} in the segment at {:#x} and {} more, with PLIC context {}",
+                     guestid, hart.hartid, hart_base_pa, extra.count_ones(), hart.plic_context);
+        }
+        memusage::set_reserved(guestid as u64, (1 + extra.count_ones() as u64) * pmap::HART_SEGMENT_SIZE,
+                               refused * pmap::HART_SEGMENT_SIZE);
 
         let mut irq_mask = 0;
         for j in 0..4 {
             let index = ((guestid-1) * 4 + j) as usize;
-            if index < machine.virtio.len() {
+            if index < machine.virtio.len() && !machine.is_shared_device(index) {
                 let irq = machine.virtio[index].irq;
                 assert!(irq < 32);
                 irq_mask |= 1u32 << irq;
+                SHARED_STATICS.virtio_owners[index].store(guestid as u64, Ordering::SeqCst);
             }
         }
+        irq_mask |= SHARED_STATICS.console_input.lock().register_guest(guestid, hart.plic_context);
 
-        *(pa2va(machine.plic_address + 0x200000 + 0x1000 * hart.plic_context) as *mut u32) = 0;
-        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask;
-        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context + 4) as *mut u32) = 0;
-
-        (*(pa2va(hart_base_pa) as *mut [u64; 1024])) = pmap::make_boot_page_table(hart_base_pa);
-        for i in 512..1024 {
-            *(pa2va(hart_base_pa + i * 8) as *mut u64) += shared_segments_shift >> 2;
+        if machine.irqchip == IrqChip::Plic {
+            *(pa2va(machine.plic_address + 0x200000 + 0x1000 * hart.plic_context) as *mut u32) = 0;
+            *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask;
+            *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context + 4) as *mut u32) = 0;
+        } else {
+            aplic.init_hart(hart.plic_context);
+            for irq in (1..32).filter(|irq| irq_mask & (1u32 << irq) != 0) {
+                aplic.route(irq, hart.plic_context, true);
+            }
         }
 
+        pmap::write_boot_page_table(hart_base_pa, shared_segments_shift);
+
         core::ptr::copy(pa2va(device_tree_blob) as *const u8,
-                        pa2va(hart_base_pa + 4096*2) as *mut u8,
+                        pa2va(hart_base_pa + pmap::FDT_OFFSET) as *mut u8,
                         fdt.total_size() as usize);
         if machine.initrd_start == machine.initrd_end {
             core::ptr::copy(&GUEST_KERNEL as *const _ as *const u8,
@@ -129,7 +256,7 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
 
         let reason = IpiReason::TriggerHartEntry {
             a0: hart.hartid,
-            a1: hart_base_pa + 4096*2,
+            a1: hart_base_pa + pmap::FDT_OFFSET,
             a2: shared_segments_shift,
             a3: hart_base_pa,
             a4: if !single_guest { guestid as u64 } else { u64::max_value() },
@@ -137,28 +264,46 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
             satp: 8 << 60 | (hart_base_pa >> 12),
         };
 
-        *SHARED_STATICS.ipi_reason_array[hart.hartid as usize].lock() = Some(reason);
+        *SHARED_STATICS.ipi_reason_array[index].lock() = Some(reason);
+        boottime::mark_for(guestid as u64, Milestone::IpiSent);
         if single_hart {
             hart_entry2(hartid);
         } else {
             riscv::sbi::send_ipi_to_hart(hart.hartid);
+            started.push((guestid as u64, hart.hartid, index));
         }
 
         guestid += 1;
     }
+    bootstatus::wait(&started);
 
-    loop {}
+    // Harts without a guest, this one included, wait for one to be moved to them.
+    for hart in machine.harts.iter() {
+        let index = SHARED_STATICS.hart_index(hart.hartid).unwrap();
+        if hart.hartid != hartid && !started.iter().any(|&(_, _, i)| i == index) {
+            SHARED_STATICS.parked_harts.fetch_or(1 << index, Ordering::SeqCst);
+        }
+    }
+    handoff::rest(SHARED_STATICS.hart_index(hartid).unwrap())
 }
 
 #[no_mangle]
 unsafe fn hart_entry2(hartid: u64) {
-    let reason = { SHARED_STATICS.ipi_reason_array.get_unchecked(hartid as usize).lock().take() };
+    let index = SHARED_STATICS.hart_index(hartid).expect("IPI received by unknown hart");
+    let reason = { SHARED_STATICS.ipi_reason_array[index].lock().take() };
     if let Some(IpiReason::TriggerHartEntry { a0, a1, a2, a3, a4, sp, satp }) = reason {
+        bootstatus::set(index, BootStatus::Starting);
         csrw!(sie, 0x222);
         csrw!(satp, satp);
+        riscv::sfence_vma();
         hart_entry3(a0, a1, a2, a3, a4, sp);
+    } else if let Some(IpiReason::ResumeGuest { from_index, satp }) = reason {
+        bootstatus::set(index, BootStatus::Starting);
+        handoff::resume(hartid, index, from_index, satp);
     } else {
-        unreachable!();
+        // An IPI that isn't meant for a hart without a guest, like the ones sent on shutdown.
+        riscv::sbi::clear_ipi();
+        handoff::rest(index);
     }
 }
 
@@ -175,9 +320,11 @@ unsafe fn hart_entry3(_hartid: u64, _device_tree_blob: u64, _shared_segments_shi
 unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64,
                       hart_base_pa: u64, guestid: u64) {
     csrw!(stvec, trap::strap_entry as *const () as u64);
+    csrw!(sscratch, 0);
     csrw!(sie, 0x222);
     csrs!(sstatus, riscv::bits::STATUS_SUM);
     csrc!(sstatus, riscv::bits::STATUS_SPP);
+    pmu::trap_counter_reads();
     riscv::sbi::clear_ipi();
 
     let guestid = if guestid == u64::max_value() {
@@ -187,38 +334,183 @@ unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift:
     };
 
     // Read and process host FDT.
-    let mut fdt = Fdt::new(pa2va(device_tree_blob));
-    assert!(fdt.magic_valid());
-    assert!(fdt.version() >= 17 && fdt.last_comp_version() <= 17);
-    let machine = fdt.parse();
+    let mut fdt = Fdt::new(pa2va(device_tree_blob)).expect("Invalid host device tree");
+    let mut machine = fdt.parse().expect("Invalid host device tree");
+    config::apply(&mut machine);
+    if machine.vectored_traps {
+        trap::set_trap_vectoring(trap::TrapVectoring::Vectored);
+    }
+    if machine.sstc {
+        riscv::sbi::enable_sstc();
+    }
 
     // Initialize memory subsystem.
-    let (shadow_page_tables, guest_memory, guest_shift) =
-        pmap::init(hart_base_pa, shared_segments_shift, &machine);
+    let hart_index = SHARED_STATICS.hart_index(hartid).expect("unknown hart");
+    let layout = layout::MachineLayout::new(&machine, shared_segments_shift);
+    let extra_segments = pmap::segment_addresses(
+        &layout, SHARED_STATICS.extra_segments[hart_index].load(Ordering::SeqCst));
+    let (shadow_page_tables, guest_memory, guest_map) =
+        pmap::init(hart_base_pa, &extra_segments, &layout, &machine);
+    hart::init(hartid, hart_index, guestid, hart_base_pa, shared_segments_shift);
+    boottime::mark(Milestone::HartStarted);
 
     // Load guest binary
-    let (entry, max_addr) = sum::access_user_memory(||{
-        elf::load_elf(pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *const u8,
-                      machine.physical_memory_offset as *mut u8)
-    });
-    let guest_dtb = (max_addr | 0x1fffff) + 1;
-    csrw!(sepc, entry);
+    let kernel_size = if machine.initrd_start == machine.initrd_end {
+        GUEST_KERNEL.len() as u64
+    } else {
+        machine.initrd_end - machine.initrd_start
+    };
+    let kernel = core::slice::from_raw_parts(pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *const u8,
+                                             kernel_size as usize);
+    // The guest device tree goes in the 2MB region following the kernel, so that has to fit too.
+    let mut entropy = entropy::Entropy::gather(&machine.rng_seed, hartid);
+    let seed = if machine.no_kaslr { 0 } else { entropy.next_u64() };
+    let loaded = if !elf::is_elf(kernel) {
+        elf::load_flat(kernel, machine.physical_memory_offset as *mut u8, guest_memory.len() - (4 << 20))
+    } else {
+        match elf::Elf64::parse(kernel).and_then(|elf| elf.check_extensions(machine.isa_letters)) {
+            Ok(()) => elf::load_elf(kernel, machine.physical_memory_offset as *mut u8,
+                                    guest_memory.len() - (4 << 20), seed),
+            Err(e) => Err(e),
+        }
+    };
+    let loaded = match loaded {
+        Ok(loaded) => loaded,
+        Err(error::Error::IncompatibleElf(reason)) => {
+            println!("Guest kernel can't run on this machine: {}", reason);
+            bootstatus::fail(hart_index, BootFailure::IncompatibleKernel);
+        }
+        Err(e) => {
+            println!("Failed to load guest kernel: {:?}", e);
+            bootstatus::fail(hart_index, BootFailure::KernelLoad);
+        }
+    };
+    let guest_dtb = (loaded.max_addr | 0x1fffff) + 1;
+    csrw!(sepc, loaded.entry);
+
+    // The end of the heap is set aside for device rings and buffers, and the symbols of the guest
+    // kernel are kept just past its image.
+    let dma_offset = pmap::HEAP_OFFSET + pmap::HEAP_SIZE - constants::DMA_POOL_SIZE;
+    let dma_pool = memory_region::MemoryRegion::new(pa2va(hart_base_pa + dma_offset),
+                                                    constants::DMA_POOL_SIZE);
+    let symbols_offset = pmap::HEAP_OFFSET + ((kernel_size + 0xfff) & !0xfff).min(dma_offset - pmap::HEAP_OFFSET);
+    let (symbols, _) = match elf::Elf64::parse(kernel) {
+        Ok(elf) => {
+            let region = core::slice::from_raw_parts_mut(pa2va(hart_base_pa + symbols_offset) as *mut u8,
+                                                         (dma_offset - symbols_offset) as usize);
+            symbols::SymbolTable::build(&elf, region)
+        }
+        Err(_) => (symbols::SymbolTable::empty(), 0),
+    };
 
     // Load guest FDT.
-    let guest_machine = sum::access_user_memory(||{
-        core::ptr::copy(GUEST_DTB.as_ptr(),
-                        guest_dtb as *mut u8,
-                        GUEST_DTB.len());
-        let mut guest_fdt = Fdt::new(guest_dtb);
-        guest_fdt.initialize_guest(guest_memory.len(), &machine.bootargs);
-        guest_fdt.parse()
-    });
+    // Host memory reservations are passed on for the part of the address space that the guest
+    // sees as its own memory, so that its view of the layout matches the host's.
+    let guest_memory_start = machine.physical_memory_offset;
+    let guest_memory_end = guest_memory_start + guest_memory.len();
+    let reservations: ArrayVec<[(u64, u64); 16]> = machine.reserved_memory.iter()
+        .filter_map(|&(start, size)| {
+            let (start, end) = (start.max(guest_memory_start), (start + size).min(guest_memory_end));
+            if start < end { Some((start, end - start)) } else { None }
+        })
+        .collect();
+
+    // Memory can optionally be split into several emulated NUMA nodes. Unless configured otherwise,
+    // nodes are 10 away from themselves and 20 away from each other.
+    let numa_nodes = pmap::split_numa_nodes(&guest_memory, machine.guest_numa_nodes);
+    let n = numa_nodes.len();
+    if n > 1 && !machine.guest_numa_distances.is_empty() && machine.guest_numa_distances.len() != n * n {
+        println!("WARN: rvirt,numa-distances should have {} entries, using default distances", n * n);
+    }
+    let numa_distances: ArrayVec<[u32; constants::MAX_NUMA_NODES * constants::MAX_NUMA_NODES]> =
+        if n == 1 {
+            ArrayVec::new()
+        } else if machine.guest_numa_distances.len() == n * n {
+            machine.guest_numa_distances.clone()
+        } else {
+            (0..(n * n)).map(|i| if i / n == i % n { 10 } else { 20 }).collect()
+        };
 
-    // Initialize context
-    context::initialize(&machine, &guest_machine, shadow_page_tables, guest_memory, guest_shift, hartid, guestid);
+    let guest_os = guestos::detect(machine.guest_os, kernel);
+    let mut isa_extensions = ArrayVec::<[&str; 8]>::new();
+    if !guest_os.has_quirk(guestos::QUIRK_NO_SSTC) {
+        isa_extensions.push("sstc");
+    }
+    if machine.svpbmt {
+        isa_extensions.push("svpbmt");
+    }
+    if machine.sscofpmf {
+        isa_extensions.push("sscofpmf");
+    }
+    if machine.cbom_block_size.is_some() {
+        isa_extensions.push("zicbom");
+    }
+    if machine.cboz_block_size.is_some() {
+        isa_extensions.push("zicboz");
+    }
 
-    // Jump into the guest kernel.
+    // With rvirt,no-kaslr the guest kernel isn't asked to randomize its own placement either.
+    let mut rng_seed = [0u8; constants::RNG_SEED_SIZE];
+    entropy.fill(&mut rng_seed);
+    let kaslr_seed = if machine.no_kaslr { None } else { Some(entropy.next_u64()) };
+
+    // The guest FDT is assembled in a local buffer and then copied into guest memory.
+    let allowed_devices = machine.fdt_allowlist(guestid.unwrap_or(1));
+    let env = machine.guest_env(guestid.unwrap_or(1));
+    let mut guest_dtb_buffer = [0u8; MAX_GUEST_DTB_SIZE];
+    let config = fdt::GuestFdtConfig {
+        guestid: guestid.unwrap_or(1),
+        overlay: GUEST_DTB_OVERLAY,
+        memory: &numa_nodes,
+        numa_distances: &numa_distances,
+        reservations: &reservations,
+        isa_extensions: &isa_extensions,
+        cbo_block_sizes: (machine.cbom_block_size, machine.cboz_block_size),
+        kernel_base: loaded.load_base,
+        rng_seed: Some(&rng_seed),
+        kaslr_seed,
+        info_page: Some(hvinfo::INFO_PAGE_BASE),
+        test_device: Some(testdev::TEST_DEVICE_BASE),
+        debug_log: if machine.debug_log { Some(constants::DEBUG_LOG_BASE) } else { None },
+        rtc: Some((rtc::RTC_BASE, rtc::RTC_IRQ)),
+        harts: 1,
+        allowed_devices: &allowed_devices,
+        env: &env,
+    };
+    let guest_dtb_size = match fdt::build_guest_fdt(GUEST_DTB, &config, &mut guest_dtb_buffer) {
+        Ok(size) => size,
+        Err(e) => {
+            println!("Failed to build guest device tree: {:?}", e);
+            bootstatus::fail(hart_index, BootFailure::DeviceTree);
+        }
+    };
+    let mut guest_fdt = Fdt::from_slice(&mut guest_dtb_buffer[..guest_dtb_size]).unwrap();
+    guest_fdt.initialize_guest(&machine.bootargs);
+    let guest_machine = match guest_fdt.parse() {
+        Ok(guest_machine) => guest_machine,
+        Err(e) => {
+            println!("Failed to parse guest device tree: {:?}", e);
+            bootstatus::fail(hart_index, BootFailure::DeviceTree);
+        }
+    };
+    if let Err(e) = sum::copy_to_guest(guest_dtb, &guest_dtb_buffer[..guest_dtb_size]) {
+        println!("Failed to load guest device tree: {:?}", e);
+        bootstatus::fail(hart_index, BootFailure::DeviceTree);
+    }
+
+    // Initialize context
+    context::initialize(&machine, &guest_machine, shadow_page_tables, guest_memory, guest_map,
+                        dma_pool, symbols, guest_os);
+    bootstatus::set(hart_index, BootStatus::GuestRunning);
+    boottime::mark(Milestone::GuestEntered);
+
+    // Jump into the guest kernel, with sscratch pointing at the register save area as
+    // `trap::strap_entry` expects. The kernel was just copied into memory that may have held code
+    // before a restart, so instruction fetches are fenced first.
     asm!("mv a1, $0 // dtb = guest_dtb
+          li t0, $1
+          csrw sscratch, t0
+          fence.i
 
           li ra, 0
           li sp, 0
@@ -250,7 +542,7 @@ unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift:
           li t4, 0
           li t5, 0
           li t6, 0
-          sret" :: "r"(guest_dtb) : "memory" : "volatile");
+          sret" :: "r"(guest_dtb), "i"(riscv::bits::SSTACK_BASE) : "memory" : "volatile");
 
     unreachable!();
 }
@@ -0,0 +1,127 @@
+//! Symbols of the guest kernel, so that the monitor can show guest addresses as `function+offset`.
+//!
+//! The symbol table of the guest ELF image is only needed until the image has been loaded, so the
+//! function symbols are copied out of it into a compact table placed just past the image in the
+//! hypervisor's heap. Each entry is an address, a size and an offset into a block of names that
+//! follows the entries. Entries are sorted by address so lookups are a binary search.
+
+use core::fmt;
+use crate::elf::{Elf64, Symbol64};
+
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+/// Longer names are truncated, to keep very long mangled names from taking up space.
+const MAX_NAME_LEN: usize = 63;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Symbol {
+    address: u64,
+    /// Zero for symbols without a size, like labels in assembly code.
+    size: u32,
+    name: u32,
+}
+
+pub struct SymbolTable {
+    symbols: &'static [Symbol],
+    names: &'static [u8],
+}
+
+/// Whether a symbol names code. Local labels and the `$x` mapping symbols emitted by the assembler
+/// are skipped.
+fn is_code(symbol: &Symbol64) -> bool {
+    let named = !symbol.name.is_empty() && symbol.name[0] != b'.' && symbol.name[0] != b'$';
+    let defined = symbol.section != SHN_UNDEF && symbol.section != SHN_ABS;
+    named && defined && (symbol.type_ == STT_FUNC || symbol.type_ == STT_NOTYPE)
+}
+
+impl SymbolTable {
+    pub const fn empty() -> Self {
+        Self { symbols: &[], names: &[] }
+    }
+
+    /// Copy the code symbols of `elf` into `region`, which must be 8 byte aligned. Returns the
+    /// table along with the number of bytes of `region` it occupies. If the table doesn't fit, it
+    /// is left empty.
+    pub unsafe fn build(elf: &Elf64, region: &'static mut [u8]) -> (Self, usize) {
+        let (mut count, mut names_len) = (0, 0);
+        for symbol in elf.symbols().filter(is_code) {
+            count += 1;
+            names_len += symbol.name.len().min(MAX_NAME_LEN) + 1;
+        }
+
+        let entries_len = count * core::mem::size_of::<Symbol>();
+        if count == 0 || entries_len + names_len > region.len() || names_len > u32::max_value() as usize {
+            if count > 0 {
+                println!("WARN: no room for {} guest kernel symbols", count);
+            }
+            return (Self::empty(), 0);
+        }
+
+        let (entries, names) = region.split_at_mut(entries_len);
+        let symbols = core::slice::from_raw_parts_mut(entries.as_mut_ptr() as *mut Symbol, count);
+        let names = &mut names[..names_len];
+        let mut offset = 0;
+        for (entry, symbol) in symbols.iter_mut().zip(elf.symbols().filter(is_code)) {
+            let name = &symbol.name[..symbol.name.len().min(MAX_NAME_LEN)];
+            names[offset..(offset + name.len())].copy_from_slice(name);
+            names[offset + name.len()] = 0;
+            *entry = Symbol {
+                address: symbol.value,
+                size: symbol.size.min(u32::max_value() as u64) as u32,
+                name: offset as u32,
+            };
+            offset += name.len() + 1;
+        }
+        symbols.sort_unstable_by_key(|s| s.address);
+
+        (Self { symbols, names }, entries_len + names_len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// The name of the function containing `address` and the offset into it. Addresses past the end
+    /// of a sized symbol don't match anything, while a symbol without a size is assumed to extend up
+    /// to the next one.
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let index = match self.symbols.binary_search_by_key(&address, |s| s.address) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size as u64 {
+            return None;
+        }
+
+        let name = &self.names[symbol.name as usize..];
+        let name = name.split(|&c| c == 0).next().unwrap_or(&[]);
+        Some((core::str::from_utf8(name).unwrap_or("?"), offset))
+    }
+
+    /// Format `address` along with the symbol it falls in, if any.
+    pub fn symbolize(&self, address: u64) -> Symbolized {
+        Symbolized { table: self, address }
+    }
+}
+
+pub struct Symbolized<'a> {
+    table: &'a SymbolTable,
+    address: u64,
+}
+
+impl<'a> fmt::Display for Symbolized<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.table.lookup(self.address) {
+            Some((name, 0)) => write!(f, "{:#x} <{}>", self.address, name),
+            Some((name, offset)) => write!(f, "{:#x} <{}+{:#x}>", self.address, name, offset),
+            None => write!(f, "{:#x}", self.address),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Emulation of QEMU's test device, which guests use to power off and reset.
+//!
+//! Guest device trees describe a `sifive,test0` device at `TEST_DEVICE_BASE`, together with the
+//! `syscon-poweroff` and `syscon-reboot` nodes that Linux binds its poweroff and restart handlers
+//! to. Writing to its single register stops only the guest that wrote it, never the machine:
+//!
+//!   * `FINISHER_PASS` shuts the guest down with the exit code the SBI shutdown call uses;
+//!   * `FINISHER_FAIL` shuts it down with the exit code in the upper 16 bits, or 1 if that is zero;
+//!   * `FINISHER_RESET` restarts it.
+//!
+//! Each is passed on to `restart::requested`, with the exit code as the reason.
+//!
+//! Any other value is ignored, and reads return zero.
+
+use riscv_decode::Instruction;
+use crate::constants::{FINISHER_FAIL, FINISHER_PASS, FINISHER_RESET};
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::restart::{self, ResetRequest, ResetSource, ResetType};
+use crate::riscv;
+
+/// Guest physical address of the device, where QEMU's virt machine has it.
+pub const TEST_DEVICE_BASE: u64 = 0x100000;
+pub const TEST_DEVICE_SIZE: u64 = 0x1000;
+
+#[inline(always)]
+pub fn is_test_access(guest_pa: u64) -> bool {
+    guest_pa >= TEST_DEVICE_BASE && guest_pa < TEST_DEVICE_BASE + TEST_DEVICE_SIZE
+}
+
+pub fn handle_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<()> {
+    let value = match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Lw(i)) | Some(Instruction::Lwu(i)) | Some(Instruction::Ld(i)) => {
+            state.saved_registers.set(i.rd(), 0);
+            None
+        }
+        Some(Instruction::Sw(i)) | Some(Instruction::Sd(i)) => Some(state.saved_registers.get(i.rs2()) as u32),
+        _ => return Err(Error::UnsupportedDeviceAccess(guest_pa)),
+    };
+
+    if guest_pa == TEST_DEVICE_BASE {
+        let request = |kind, reason| ResetRequest { source: ResetSource::TestDevice, kind, reason };
+        match value.map(|v| (v & 0xffff, v >> 16)) {
+            Some((FINISHER_PASS, _)) => restart::requested(state, request(ResetType::Shutdown, 0)),
+            Some((FINISHER_FAIL, code)) => restart::requested(state, request(ResetType::Shutdown, code.max(1))),
+            Some((FINISHER_RESET, _)) => restart::requested(state, request(ResetType::ColdReboot, 0)),
+            _ => {}
+        }
+    }
+    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+    Ok(())
+}
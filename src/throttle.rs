@@ -0,0 +1,129 @@
+//! Rate limiting of guest I/O.
+//!
+//! Each virtio device assigned to a guest can be limited to a number of requests and a number of
+//! bytes per second. Limits are enforced when the guest rings the device's doorbell: if the
+//! requests it made available since the previous notification don't fit in the token buckets, the
+//! notification is held back and delivered from the timer interrupt once enough tokens have
+//! accumulated. The device only looks at a queue when notified, so to the guest this just appears
+//! as higher latency.
+
+use crate::constants::TIMER_FREQUENCY;
+
+#[derive(Copy, Clone)]
+struct TokenBucket {
+    /// Tokens added per second, or zero for no limit.
+    rate: u64,
+    /// May go negative when a single notification covers more than a second's worth of tokens. The
+    /// debt is paid off before anything else is admitted.
+    tokens: i64,
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self { rate, tokens: rate as i64, last_refill: 0 }
+    }
+
+    fn refill(&mut self, now: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        let elapsed = now.saturating_sub(self.last_refill).min(TIMER_FREQUENCY * 2);
+        let new = (elapsed as u128 * self.rate as u128 / TIMER_FREQUENCY as u128) as i64;
+        if new > 0 {
+            self.tokens = (self.tokens + new).min(self.rate as i64);
+            self.last_refill = now;
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.rate == 0 || self.tokens >= 0
+    }
+
+    fn take(&mut self, n: u64) {
+        if self.rate != 0 {
+            self.tokens -= n as i64;
+        }
+    }
+
+    /// Number of timer ticks until the bucket is no longer in debt.
+    fn wait_time(&self) -> u64 {
+        if self.ready() {
+            0
+        } else {
+            (-self.tokens) as u64 * TIMER_FREQUENCY / self.rate + 1
+        }
+    }
+}
+
+pub struct Throttle {
+    requests: TokenBucket,
+    bytes: TokenBucket,
+    /// Bitmask of queues with a notification being held back.
+    pending: u32,
+
+    pub requests_completed: u64,
+    pub bytes_completed: u64,
+    pub notifications_delayed: u64,
+}
+
+impl Throttle {
+    /// Create a throttle admitting `requests_per_sec` requests and `bytes_per_sec` bytes per
+    /// second. Either may be zero for no limit.
+    pub fn new(requests_per_sec: u64, bytes_per_sec: u64) -> Self {
+        Self {
+            requests: TokenBucket::new(requests_per_sec),
+            bytes: TokenBucket::new(bytes_per_sec),
+            pending: 0,
+            requests_completed: 0,
+            bytes_completed: 0,
+            notifications_delayed: 0,
+        }
+    }
+
+    pub fn set_limits(&mut self, requests_per_sec: u64, bytes_per_sec: u64) {
+        self.requests = TokenBucket::new(requests_per_sec);
+        self.bytes = TokenBucket::new(bytes_per_sec);
+    }
+
+    /// Returns (requests per second, bytes per second), where zero means unlimited.
+    pub fn limits(&self) -> (u64, u64) {
+        (self.requests.rate, self.bytes.rate)
+    }
+
+    /// Decide whether a notification for `queue`, covering `requests` new requests totalling
+    /// `bytes` bytes, may be passed on to the device now. If not, the queue is marked as pending.
+    pub fn admit(&mut self, queue: u32, now: u64, requests: u64, bytes: u64) -> bool {
+        self.requests.refill(now);
+        self.bytes.refill(now);
+
+        if !self.requests.ready() || !self.bytes.ready() {
+            if self.pending & (1 << queue) == 0 {
+                self.notifications_delayed += 1;
+            }
+            self.pending |= 1 << queue;
+            return false;
+        }
+
+        self.requests.take(requests);
+        self.bytes.take(bytes);
+        self.requests_completed += requests;
+        self.bytes_completed += bytes;
+        self.pending &= !(1 << queue);
+        true
+    }
+
+    /// Bitmask of queues with a notification being held back.
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    /// When held back notifications should next be retried, if there are any.
+    pub fn next_retry(&self, now: u64) -> Option<u64> {
+        if self.pending == 0 {
+            None
+        } else {
+            Some(now + self.requests.wait_time().max(self.bytes.wait_time()).max(1))
+        }
+    }
+}
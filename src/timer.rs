@@ -0,0 +1,89 @@
+//! Timer events for a hart.
+//!
+//! Each hart has a single timer, but several parts of the hypervisor need to be woken up at some
+//! point in the future: the guest's own timer, emulated UART transmit interrupts, held back I/O,
+//! console polling and output, devices whose interrupts are masked, and write combined device pages. Each of them registers a deadline in the hart's `TimerQueue`, which keeps
+//! them sorted so that the host timer only ever has to be armed for the earliest one. When the
+//! timer interrupt fires, `trap::timer_tick` dispatches every event that has expired.
+
+use arrayvec::ArrayVec;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimerEvent {
+    /// The deadline the guest set through SBI or stimecmp.
+    GuestTimer,
+    /// The emulated UART has finished sending a byte and may need to raise an interrupt.
+    UartTransmit,
+    /// Queue notifications held back by I/O throttling should be retried.
+    ThrottleRetry,
+    /// Check for console input on a host UART that can't interrupt.
+    ConsolePoll,
+    /// Write out a partial line of guest console output.
+    ConsoleFlush,
+    /// A performance counter the guest is sampling with may have overflowed.
+    CounterOverflow,
+    /// Poll the devices behind host interrupts masked for interrupting too often.
+    IrqPoll,
+    /// The guest has run out of time to power off after a shutdown request.
+    ShutdownDeadline,
+    /// Pass changes to write combined device pages on to their devices.
+    MmioFlush,
+    /// The alarm of the guest's real time clock goes off.
+    RtcAlarm,
+}
+
+/// At most one of each kind of event is queued at a time.
+const MAX_EVENTS: usize = 10;
+
+pub struct TimerQueue {
+    /// (deadline, event) pairs sorted by deadline.
+    events: ArrayVec<[(u64, TimerEvent); MAX_EVENTS]>,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self { events: ArrayVec::new() }
+    }
+
+    /// Dispatch `event` once host time reaches `deadline`, replacing any deadline it already had.
+    pub fn schedule(&mut self, event: TimerEvent, deadline: u64) {
+        self.cancel(event);
+        let index = self.events.iter().position(|&(d, _)| d > deadline).unwrap_or(self.events.len());
+        self.events.insert(index, (deadline, event));
+    }
+
+    /// Remove `event` from the queue. Returns false if it wasn't scheduled.
+    pub fn cancel(&mut self, event: TimerEvent) -> bool {
+        match self.events.iter().position(|&(_, e)| e == event) {
+            Some(index) => {
+                self.events.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn deadline(&self, event: TimerEvent) -> Option<u64> {
+        self.events.iter().find(|&&(_, e)| e == event).map(|&(d, _)| d)
+    }
+
+    /// The earliest deadline of any event, or `u64::max_value()` if the queue is empty.
+    pub fn next_deadline(&self) -> u64 {
+        self.events.first().map(|&(d, _)| d).unwrap_or(u64::max_value())
+    }
+
+    /// Remove and return an event whose deadline is at or before `now`, earliest first.
+    pub fn pop_expired(&mut self, now: u64) -> Option<TimerEvent> {
+        match self.events.first() {
+            Some(&(deadline, event)) if deadline <= now => {
+                self.events.remove(0);
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(u64, TimerEvent)> {
+        self.events.iter()
+    }
+}
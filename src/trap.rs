@@ -1,7 +1,22 @@
-use riscv_decode::Instruction;
-use crate::context::{Context, CONTEXT, IrqMapping};
+use crate::boottime::{self, Milestone};
+use crate::context::{Context, CONTEXT, IrqMapping, PrivilegeEvent};
+use crate::deferred::{self, Work};
+use crate::dispatch::{self, Trap};
+use crate::error::{Error, Result};
+use crate::events::{self, EventKind};
+use crate::exits::ExitReason;
+use crate::options::{TRACE_EXCEPTIONS, TRACE_INTERRUPTS};
 use crate::riscv::bits::*;
-use crate::{pfault, pmap, riscv, sum, virtio};
+use crate::pmu::{self, FirmwareEvent};
+use crate::profile::{self, Probe};
+use crate::statics::SHARED_STATICS;
+use crate::timer::TimerEvent;
+use crate::{handoff, hart, htif, irqlatency, irqrate, mmio, print, restart, riscv, rtc, sbi, semihosting,
+            shutdown, steal, sum, vcsr, virtio};
+use core::sync::atomic::Ordering;
+
+/// How often to check for console input when the host UART's interrupt isn't available.
+const CONSOLE_POLL_INTERVAL: u64 = 1_000_000;
 
 pub trait U64Bits {
     fn get(&self, mask: Self) -> bool;
@@ -22,13 +37,229 @@ impl U64Bits for u64 {
     }
 }
 
+// While a guest runs, sscratch holds the address of the hart's register save area, which is also
+// the top of its stack, so that one swap at entry gets the hypervisor a stack. While the hypervisor
+// runs it holds zero, and the guest's sp is kept in the save area like every other register, so a
+// trap from within the hypervisor can still be told apart and reported.
+//
+// Only the registers that Rust code may clobber are saved straight away. Guest SBI calls are first
+// offered to `strap_fast`, which needs nothing else; callee-saved registers still hold the guest's
+// values when it returns, so if it declines they are saved then and the trap goes to `strap`.
 #[naked]
 #[no_mangle]
 pub unsafe fn strap_entry() -> ! {
     asm!(".align 4
-          csrw sscratch, sp   // Save stack pointer in sscratch
-          li sp, $0           // Set stack pointer
+          csrrw sp, sscratch, sp  // Swap in the save area, keeping the guest's sp in sscratch
+          bnez sp, 1f
+
+          // Trap from within the hypervisor, which `strap` reports. Its sp is now in sscratch.
+          li sp, $0
 
+          // Save the registers Rust code may clobber
+1:        sd ra, 1*8(sp)
+          sd gp, 3*8(sp)
+          sd tp, 4*8(sp)
+          sd t0, 5*8(sp)
+          sd t1, 6*8(sp)
+          sd t2, 7*8(sp)
+          sd a0, 10*8(sp)
+          sd a1, 11*8(sp)
+          sd a2, 12*8(sp)
+          sd a3, 13*8(sp)
+          sd a4, 14*8(sp)
+          sd a5, 15*8(sp)
+          sd a6, 16*8(sp)
+          sd a7, 17*8(sp)
+          sd t3, 28*8(sp)
+          sd t4, 29*8(sp)
+          sd t5, 30*8(sp)
+          sd t6, 31*8(sp)
+          csrr t0, sscratch
+          sd t0, 2*8(sp)
+          csrw sscratch, zero
+
+          li tp, $1           // Point tp at this hart's HartLocal
+
+          csrr t0, scause
+          li t1, $2
+          bne t0, t1, 2f
+          jal ra, strap_fast  // Try the fast path for SBI calls
+          bnez a0, 3f
+
+          // Save the rest of the registers
+2:        sd s0, 8*8(sp)
+          sd s1, 9*8(sp)
+          sd s2, 18*8(sp)
+          sd s3, 19*8(sp)
+          sd s4, 20*8(sp)
+          sd s5, 21*8(sp)
+          sd s6, 22*8(sp)
+          sd s7, 23*8(sp)
+          sd s8, 24*8(sp)
+          sd s9, 25*8(sp)
+          sd s10, 26*8(sp)
+          sd s11, 27*8(sp)
+
+          jal ra, strap       // Call `strap`
+          li sp, $0           // Reset stack pointer, just to be safe
+
+          ld s0, 8*8(sp)
+          ld s1, 9*8(sp)
+          ld s2, 18*8(sp)
+          ld s3, 19*8(sp)
+          ld s4, 20*8(sp)
+          ld s5, 21*8(sp)
+          ld s6, 22*8(sp)
+          ld s7, 23*8(sp)
+          ld s8, 24*8(sp)
+          ld s9, 25*8(sp)
+          ld s10, 26*8(sp)
+          ld s11, 27*8(sp)
+
+          // Restore the remaining registers
+3:        li sp, $0
+          csrw sscratch, sp
+          ld ra, 1*8(sp)
+          ld gp, 3*8(sp)
+          ld tp, 4*8(sp)
+          ld t0, 5*8(sp)
+          ld t1, 6*8(sp)
+          ld t2, 7*8(sp)
+          ld a0, 10*8(sp)
+          ld a1, 11*8(sp)
+          ld a2, 12*8(sp)
+          ld a3, 13*8(sp)
+          ld a4, 14*8(sp)
+          ld a5, 15*8(sp)
+          ld a6, 16*8(sp)
+          ld a7, 17*8(sp)
+          ld t3, 28*8(sp)
+          ld t4, 29*8(sp)
+          ld t5, 30*8(sp)
+          ld t6, 31*8(sp)
+
+          // Restore stack pointer and return
+          ld sp, 2*8(sp)
+          sret" :: "i"(SSTACK_BASE), "i"(hart::HART_LOCAL_VA), "i"(SCAUSE_ENV_CALL) : "memory" : "volatile");
+
+    unreachable!()
+}
+
+/// How traps reach the hypervisor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrapVectoring {
+    /// Every trap enters at `strap_entry`, and `strap` works out what it was.
+    Direct,
+    /// Interrupts enter through a stub for their cause in `strap_vector_table`, which goes
+    /// straight to `strap_interrupt`. Exceptions still take the direct path.
+    Vectored,
+}
+
+impl TrapVectoring {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "direct" => Some(TrapVectoring::Direct),
+            "vectored" => Some(TrapVectoring::Vectored),
+            _ => None,
+        }
+    }
+
+    /// The mode this hart is using.
+    pub fn current() -> Self {
+        match csrr!(stvec) & TVEC_MODE {
+            TVEC_MODE_VECTORED => TrapVectoring::Vectored,
+            _ => TrapVectoring::Direct,
+        }
+    }
+}
+
+/// Point stvec at the hypervisor's trap entry for `mode`, and return the mode in effect. A hart that
+/// doesn't implement vectored mode ignores the request, which still works since the first entry of
+/// the vector table jumps to `strap_entry`, but is reported and put back to direct.
+pub fn set_trap_vectoring(mode: TrapVectoring) -> TrapVectoring {
+    match mode {
+        TrapVectoring::Direct => unsafe { csrw!(stvec, strap_entry as *const () as u64) },
+        TrapVectoring::Vectored => unsafe {
+            csrw!(stvec, strap_vector_table as *const () as u64 | TVEC_MODE_VECTORED)
+        },
+    }
+
+    let current = TrapVectoring::current();
+    if current != mode {
+        println!("WARN: This hart doesn't support vectored traps");
+        unsafe { csrw!(stvec, strap_entry as *const () as u64) }
+    }
+    current
+}
+
+// The vector table for `TrapVectoring::Vectored`. Exceptions, and interrupts that the hypervisor
+// never enables, enter at `strap_entry`. Each interrupt that it does enable has a stub of its own.
+// Compressed instructions are turned off so that every entry is 4 bytes.
+global_asm!("
+.align 8
+.globl strap_vector_table
+strap_vector_table:
+.option push
+.option norvc
+    j strap_entry               // 0: exceptions
+    j strap_software_entry      // 1: supervisor software interrupt
+    j strap_entry
+    j strap_entry
+    j strap_entry
+    j strap_timer_entry         // 5: supervisor timer interrupt
+    j strap_entry
+    j strap_entry
+    j strap_entry
+    j strap_external_entry      // 9: supervisor external interrupt
+    j strap_entry
+    j strap_entry
+    j strap_entry
+    j strap_entry
+    j strap_entry
+    j strap_entry
+.option pop
+");
+
+extern {
+    fn strap_vector_table();
+}
+
+// The per-cause stubs save a0, load the cause into it and enter `strap_interrupt_entry`.
+#[naked]
+#[no_mangle]
+unsafe fn strap_software_entry() -> ! {
+    asm!("csrrw sp, sscratch, sp
+          sd a0, 10*8(sp)
+          li a0, 0x8000000000000001
+          j strap_interrupt_entry" ::: "memory" : "volatile");
+    unreachable!()
+}
+#[naked]
+#[no_mangle]
+unsafe fn strap_timer_entry() -> ! {
+    asm!("csrrw sp, sscratch, sp
+          sd a0, 10*8(sp)
+          li a0, 0x8000000000000005
+          j strap_interrupt_entry" ::: "memory" : "volatile");
+    unreachable!()
+}
+#[naked]
+#[no_mangle]
+unsafe fn strap_external_entry() -> ! {
+    asm!("csrrw sp, sscratch, sp
+          sd a0, 10*8(sp)
+          li a0, 0x8000000000000009
+          j strap_interrupt_entry" ::: "memory" : "volatile");
+    unreachable!()
+}
+
+/// Entry for interrupts from the vector table. Like `strap_entry`, except that the stack pointer
+/// has already been swapped, a0 has been saved and holds the cause, and since interrupts are never
+/// taken from within the hypervisor it saves every register straight away.
+#[naked]
+#[no_mangle]
+pub unsafe fn strap_interrupt_entry() -> ! {
+    asm!(".align 4
           // Save registers
           sd ra, 1*8(sp)
           sd gp, 3*8(sp)
@@ -38,7 +269,6 @@ pub unsafe fn strap_entry() -> ! {
           sd t2, 7*8(sp)
           sd s0, 8*8(sp)
           sd s1, 9*8(sp)
-          sd a0, 10*8(sp)
           sd a1, 11*8(sp)
           sd a2, 12*8(sp)
           sd a3, 13*8(sp)
@@ -60,9 +290,15 @@ pub unsafe fn strap_entry() -> ! {
           sd t4, 29*8(sp)
           sd t5, 30*8(sp)
           sd t6, 31*8(sp)
+          csrr t0, sscratch
+          sd t0, 2*8(sp)
+          csrw sscratch, zero
 
-          jal ra, strap       // Call `strap`
+          li tp, $1           // Point tp at this hart's HartLocal
+
+          jal ra, strap_interrupt
           li sp, $0           // Reset stack pointer, just to be safe
+          csrw sscratch, sp
 
           // Restore registers
           ld ra, 1*8(sp)
@@ -97,12 +333,62 @@ pub unsafe fn strap_entry() -> ! {
           ld t6, 31*8(sp)
 
           // Restore stack pointer and return
-          csrr sp, sscratch
-          sret" :: "i"(SSTACK_BASE) : "memory" : "volatile");
+          ld sp, 2*8(sp)
+          sret" :: "i"(SSTACK_BASE), "i"(hart::HART_LOCAL_VA) : "memory" : "volatile");
 
     unreachable!()
 }
 
+/// Handle an interrupt that came in through the vector table. Interrupts are never enabled while
+/// the hypervisor runs, so unlike `strap` this doesn't need to check where the trap came from, or
+/// to tell interrupts from exceptions.
+#[no_mangle]
+pub fn strap_interrupt(cause: u64) {
+    let start = profile::start();
+    hart::current().count_trap();
+    let mut state = CONTEXT.lock();
+    let mut state = (&mut *state).as_mut().unwrap();
+
+    dispatch_interrupt(&mut state, cause);
+    if deferred::run(&mut state) {
+        maybe_forward_interrupt(&mut state, csrr!(sepc));
+    }
+
+    state.shadow_page_tables.install_root(state.shadow());
+    state.profile.record(Probe::VectoredInterrupt, start);
+    if state.handoff.pending() {
+        handoff::hand_off(&mut state);
+    }
+}
+
+/// Handle the SBI calls that guests make most often, reading and writing only the registers that
+/// `strap_entry` has saved by the time it calls this. Returns false, having done nothing, for any
+/// other ecall, which then goes to `strap`.
+#[no_mangle]
+pub fn strap_fast() -> bool {
+    let start = profile::start();
+    let mut state = CONTEXT.lock();
+    let mut state = (&mut *state).as_mut().unwrap();
+
+    let call = state.saved_registers.get(17);
+    if !state.fast_sbi || !state.smode() || !sbi::is_fast_call(call) {
+        return false;
+    }
+
+    hart::current().count_trap();
+    state.record_exit(sbi::exit_reason(call));
+    boottime::mark(Milestone::FirstSbiCall);
+    sbi::handle_fast_call(&mut state, call);
+    riscv::set_sepc(csrr!(sepc) + 4);
+
+    if deferred::run(&mut state) {
+        maybe_forward_interrupt(&mut state, csrr!(sepc));
+    }
+    state.shadow_page_tables.install_root(state.shadow());
+    state.profile.record(Probe::FastSbiCall, start);
+    true
+}
+
 #[no_mangle]
 pub fn strap() {
     let cause = csrr!(scause);
@@ -129,207 +415,196 @@ pub fn strap() {
         loop {}
     }
 
+    let start = profile::start();
+    hart::current().count_trap();
     let mut state = CONTEXT.lock();
     let mut state = (&mut *state).as_mut().unwrap();
 
     // For the processor to have generated a load/store page fault or an illegal instruction fault,
-    // the processor must have been able to load the relevant instruction (or else an access fault
-    // or instruction page fault would have been triggered). Thus, it is safe to access memory
-    // pointed to by `sepc`.
+    // the processor must have been able to fetch the relevant instruction.
+    let pc = csrr!(sepc);
     let instruction = match cause {
         SCAUSE_LOAD_PAGE_FAULT |
         SCAUSE_STORE_PAGE_FAULT |
-        SCAUSE_ILLEGAL_INSN => unsafe {
-            Some(load_instruction_at_address(&mut state, csrr!(sepc)))
-        }
+        SCAUSE_ILLEGAL_INSN => Some(load_instruction_at_address(&mut state, pc)),
         _ => None,
     };
+    let sbi_call = cause == SCAUSE_ENV_CALL && state.smode() && sbi::is_fast_call(state.saved_registers.get(17));
+    dispatch::dispatch(&mut state, &Trap { cause, pc, instruction });
 
-    if (cause as isize) < 0 {
-        handle_interrupt(&mut state, cause);
-        maybe_forward_interrupt(&mut state, csrr!(sepc));
-    } else if cause == SCAUSE_INSN_PAGE_FAULT || cause == SCAUSE_LOAD_PAGE_FAULT || cause == SCAUSE_STORE_PAGE_FAULT {
-        let pc = csrr!(sepc);
-        if pfault::handle_page_fault(&mut state, cause, instruction.map(|i|i.0)) {
-            maybe_forward_interrupt(&mut state, pc);
-        } else {
-            forward_exception(&mut state, cause, pc);
-        }
-    } else if cause == SCAUSE_ILLEGAL_INSN && state.smode {
-        let pc = csrr!(sepc);
-        let (instruction, len) = instruction.unwrap();
-        let mut advance_pc = true;
-        match riscv_decode::decode(instruction).ok() {
-            Some(Instruction::Sret) => {
-                if !state.csrs.sstatus.get(STATUS_SIE) && state.csrs.sstatus.get(STATUS_SPIE) {
-                    state.no_interrupt = false;
-                }
-                state.csrs.pop_sie();
-                state.smode = state.csrs.sstatus.get(STATUS_SPP);
-                state.csrs.sstatus.set(STATUS_SPP, false);
-                riscv::set_sepc(state.csrs.sepc);
-                advance_pc = false;
-
-                if !state.smode {
-                    state.no_interrupt = false;
-                }
-            }
-            Some(Instruction::SfenceVma(rtype)) => pmap::handle_sfence_vma(&mut state, rtype),
-            Some(Instruction::Csrrw(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let value = state.saved_registers.get(i.rs1());
-                state.set_csr(i.csr(), value);
-                state.saved_registers.set(i.rd(), prev);
-            }
-            Some(Instruction::Csrrs(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = state.saved_registers.get(i.rs1());
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev | mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
-            }
-            Some(Instruction::Csrrc(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = state.saved_registers.get(i.rs1());
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev & !mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
-            }
-            Some(Instruction::Csrrwi(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                state.set_csr(i.csr(), i.zimm() as u64);
-                state.saved_registers.set(i.rd(), prev);
-            }
-            Some(Instruction::Csrrsi(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = i.zimm() as u64;
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev | mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
-            }
-            Some(Instruction::Csrrci(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = i.zimm() as u64;
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev & !mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
-            }
-            Some(Instruction::Wfi) => {}
-            Some(decoded) => {
-                println!("Unrecognized instruction! {:?} @ pc={:#x}", decoded, pc);
-                forward_exception(&mut state, cause, pc);
-                advance_pc = false;
-            }
-            None => {
-                println!("Unrecognized instruction {:#x} @ pc={:#x}", instruction, pc);
-                forward_exception(&mut state, cause, pc);
-                advance_pc = false;
-            }
-        }
-
-        if advance_pc {
-            riscv::set_sepc(pc + len);
-        }
+    if deferred::run(&mut state) {
         maybe_forward_interrupt(&mut state, csrr!(sepc));
-    } else if cause == SCAUSE_ENV_CALL && state.smode {
-        match state.saved_registers.get(17) {
-            0 => {
-                state.csrs.sip.set(IP_STIP, false);
-                state.csrs.mtimecmp = state.saved_registers.get(10);
-                riscv::sbi::set_timer(state.csrs.mtimecmp);
-            }
-            1 => {
-                let value = state.saved_registers.get(10) as u8;
-                state.uart.output_byte(value)
-            }
-            5 => riscv::fence_i(),
-            6 | 7 => {
-                // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
-                // a result, this function ignores the arguments and just does a global fence. This
-                // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
-                pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
-            }
-            8 => {
-                if let Some(ref mut finisher) = state.test_finisher {
-                    finisher.pass();
-                }
-                loop {}
-            }
-            i => {
-                println!("Got ecall from guest function={}!", i);
-                loop {}
-            }
-        }
-        riscv::set_sepc(csrr!(sepc) + 4);
-    } else {
-        if cause != SCAUSE_ENV_CALL { // no need to print anything for guest syscalls...
-            println!("Forward exception (cause = {}, smode={})!", cause, state.smode);
-        }
-        forward_exception(&mut state, cause, csrr!(sepc));
     }
 
     state.shadow_page_tables.install_root(state.shadow());
+    state.profile.record(Probe::TrapDispatch, start);
+    if (cause as isize) < 0 {
+        state.profile.record(Probe::DirectInterrupt, start);
+    } else if sbi_call {
+        state.profile.record(Probe::SbiCall, start);
+    }
+    if state.handoff.pending() {
+        handoff::hand_off(&mut state);
+    }
+}
+
+/// The dispatch table's handler for every interrupt.
+pub fn handle_interrupt_trap(state: &mut Context, trap: &Trap) -> bool {
+    dispatch_interrupt(state, trap.cause);
+    true
+}
+
+/// Handle an interrupt taken while the guest was running, and deliver whatever it raised.
+fn dispatch_interrupt(state: &mut Context, cause: u64) {
+    state.record_exit(match cause & 0xff {
+        0x1 => ExitReason::SoftwareInterrupt,
+        0x5 => ExitReason::TimerInterrupt,
+        _ => ExitReason::ExternalInterrupt,
+    });
+    let interrupt_start = state.host_clint.get_mtime();
+    handle_interrupt(state, cause);
+    steal::account(state, interrupt_start);
+    maybe_forward_interrupt(state, csrr!(sepc));
 }
 
 fn handle_interrupt(state: &mut Context, cause: u64) {
     let interrupt = cause & 0xff;
     match interrupt {
         0x1 => {
-            // Software interrupt
-            unreachable!();
+            // Software interrupt. These are sent to wake a hart up, which has already happened, and
+            // to pass on a shutdown request.
+            riscv::sbi::clear_ipi();
+            shutdown::check(state);
         }
         0x5 => {
             // Timer interrupt
             let time = state.host_clint.get_mtime();
-            let mut next = time + 1_000_000;
+            timer_tick(state, time);
+        }
+        0x9 => {
+            // External
+            let host_irq = state.host_irqchip.claim_and_clear();
+            irqlatency::host_claimed(state);
+            let time = state.host_clint.get_mtime();
+            irqrate::account(state, host_irq, time);
+            deliver_host_irq(state, host_irq);
+        }
+        i => {
+            println!("Got interrupt #{}", i);
+            unreachable!()
+        }
+    }
+}
+
+/// Pass an interrupt from host source `host_irq` on to whatever it is mapped to. Also called when
+/// polling a source that is masked.
+pub fn deliver_host_irq(state: &mut Context, host_irq: u32) {
+    match state.irq_map[host_irq as usize] {
+        IrqMapping::Virtio { device_index, guest_irq } => {
+            match state.virtio.devices[device_index as usize] {
+                virtio::Device::Passthrough { .. } => raise_guest_irq(state, guest_irq),
+                virtio::Device::Macb(..) => state.deferred.raise(Work::DeviceInterrupt {
+                    device_index: device_index as usize,
+                    guest_irq,
+                }),
+                virtio::Device::Unmapped | virtio::Device::Vsock(..) | virtio::Device::Blk(..) => {}
+            }
+        }
+        IrqMapping::Console => {
+            let time = state.host_clint.get_mtime();
+            crate::context::Uart::poll(state, time);
+        }
+        IrqMapping::Ignored => {}
+    }
+}
+
+/// Process the used buffers of an emulated device after it interrupted, forwarding the interrupt to
+/// the guest if the device model asks for it. Called from deferred work.
+pub fn process_device_interrupt(state: &mut Context, device_index: usize, guest_irq: u16) {
+    let forward = match state.virtio.devices[device_index] {
+        virtio::Device::Macb(ref mut macb) => macb.interrupt(&mut state.guest_memory),
+        _ => false,
+    };
+    if forward {
+        raise_guest_irq(state, guest_irq);
+    }
+}
 
-            crate::context::Uart::timer(state, time);
-            if state.csrs.mtimecmp <= time {
+fn raise_guest_irq(state: &mut Context, guest_irq: u16) {
+    state.plic.set_pending(guest_irq as u32, true);
+    irqlatency::raised(state, guest_irq);
+
+    // Guest might have masked out this interrupt
+    if state.plic.interrupt_pending() {
+        state.no_interrupt = false;
+        state.csrs.sip |= IP_SEIP;
+    } else {
+        assert_eq!(state.csrs.sip & IP_SEIP, 0);
+    }
+}
+
+/// Dispatch every timer event that is due by `time`, then rearm the host timer for the earliest one
+/// left. With nothing outstanding no timer is armed at all.
+fn timer_tick(state: &mut Context, time: u64) {
+    while let Some(event) = state.timers.pop_expired(time) {
+        match event {
+            TimerEvent::GuestTimer => {
                 state.csrs.sip |= IP_STIP;
                 state.no_interrupt = false;
-            } else {
-                next = next.min(state.csrs.mtimecmp);
             }
-
-            if state.uart.next_interrupt_time > time {
-                next = next.min(state.uart.next_interrupt_time);
+            TimerEvent::UartTransmit => crate::context::Uart::poll(state, time),
+            TimerEvent::ThrottleRetry => state.deferred.raise(Work::ThrottledNotifications),
+            TimerEvent::ConsolePoll => {
+                crate::context::Uart::poll(state, time);
+                state.timers.schedule(TimerEvent::ConsolePoll, time + CONSOLE_POLL_INTERVAL);
             }
-            riscv::sbi::set_timer(next);
+            TimerEvent::ConsoleFlush => state.uart.flush_output(),
+            TimerEvent::CounterOverflow => pmu::check_overflow(state, time),
+            TimerEvent::IrqPoll => irqrate::poll(state, time),
+            TimerEvent::ShutdownDeadline => shutdown::deadline_passed(state),
+            TimerEvent::MmioFlush => mmio::flush_tick(state, time),
+            TimerEvent::RtcAlarm => rtc::alarm_expired(state),
         }
-        0x9 => {
-            // External
-            let host_irq = state.host_plic.claim_and_clear();
-            let guest_irq = state.irq_map[host_irq as usize];
-            match guest_irq {
-                IrqMapping::Virtio { device_index, guest_irq } => {
-                    let forward = match state.virtio.devices[device_index as usize] {
-                        virtio::Device::Passthrough { .. } => true,
-                        virtio::Device::Unmapped => false,
-                        virtio::Device::Macb(ref mut macb) => macb.interrupt(&mut state.guest_memory),
-                    };
-
-                    if forward {
-                        state.plic.set_pending(guest_irq as u32, true);
-
-                        // Guest might have masked out this interrupt
-                        if state.plic.interrupt_pending() {
-                            state.no_interrupt = false;
-                            state.csrs.sip |= IP_SEIP;
-                        } else {
-                            assert_eq!(state.csrs.sip & IP_SEIP, 0);
-                        }
-                    }
-                }
-                IrqMapping::Ignored => {}
-            }
+    }
+    state.set_host_timer(state.timers.next_deadline());
+}
+
+/// Whether the guest would wake up from WFI. As on real hardware this ignores sstatus.SIE.
+fn guest_wakeup_pending(state: &Context) -> bool {
+    let mut sip = state.csrs.sip;
+    if state.plic.interrupt_pending() {
+        sip |= IP_SEIP;
+    }
+    state.csrs.sie & sip != 0
+}
 
+/// Called when the guest executes WFI. Returning to the guest straight away would leave it spinning
+/// in its idle loop, so instead the hart sleeps in a real WFI until one of the guest's interrupts
+/// becomes pending. Host interrupts that arrive meanwhile are handled here, and only the next event
+/// that actually needs attention is put on the timer.
+pub fn idle(state: &mut Context) {
+    loop {
+        let time = state.host_clint.get_mtime();
+        timer_tick(state, time);
+        deferred::run(state);
+        if guest_wakeup_pending(state) {
+            state.no_interrupt = false;
+            return;
         }
-        i => {
-            println!("Got interrupt #{}", i);
-            unreachable!()
+
+        riscv::wfi();
+
+        let pending = csrr!(sip);
+        if pending & IP_SEIP != 0 {
+            handle_interrupt(state, 0x9);
+        }
+        if pending & IP_SSIP != 0 {
+            handle_interrupt(state, 0x1);
         }
     }
 }
 
-fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
+pub fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
     if state.no_interrupt {
         return;
     }
@@ -338,25 +613,24 @@ fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
         state.csrs.sip.set(IP_SEIP, true);
     }
 
-    if (!state.smode || state.csrs.sstatus.get(STATUS_SIE)) && (state.csrs.sie & state.csrs.sip != 0) {
+    if (!state.smode() || state.csrs.sstatus.get(STATUS_SIE)) && (state.csrs.sie & state.csrs.sip != 0) {
         let cause = if state.csrs.sip.get(IP_SEIP) {
             9
         } else if state.csrs.sip.get(IP_STIP) {
             5
         } else if state.csrs.sip.get(IP_SSIP) {
             1
+        } else if state.csrs.sip.get(IP_LCOFIP) {
+            13
         } else {
             unreachable!()
         };
 
-        // println!("||> Forwarding timer interrupt! (state.smode={}, sepc={:#x})", state.smode, sepc);
-        // forward interrupt
-        state.csrs.push_sie();
-        state.csrs.sepc = sepc;
-        state.csrs.scause = (1 << 63) | cause;
-        state.csrs.sstatus.set(STATUS_SPP, state.smode);
-        state.csrs.stval = 0;
-        state.smode = true;
+        trace!(state, TRACE_INTERRUPTS, "interrupt {} at {:#x}", cause, sepc);
+        state.trap_to_guest(PrivilegeEvent::Interrupt, (1 << 63) | cause, sepc, 0);
+        if cause == 9 {
+            irqlatency::delivered(state);
+        }
 
         match state.csrs.stvec & TVEC_MODE {
             0 => riscv::set_sepc(state.csrs.stvec & TVEC_BASE),
@@ -368,25 +642,73 @@ fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
     }
 }
 
-fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
-    // println!("||> Forward exception sepc={:#x}", sepc);
-    state.csrs.push_sie();
-    state.csrs.sepc = sepc;
-    state.csrs.scause = cause;
-    state.csrs.sstatus.set(STATUS_SPP, state.smode);
-    state.csrs.stval = csrr!(stval);
-    state.smode = true;
+/// Stop running the guest after an error it can't recover from, unless its crash policy says to
+/// restart it. Only the current hart is affected unless this was the last guest running (see
+/// `guest_exited`).
+pub fn terminate_guest(state: &mut Context, error: Error) -> ! {
+    state.uart.flush_output();
+    println!("Terminating guest: {:?} (sepc={:#x})", error, csrr!(sepc));
+    state.dump_privilege_history();
+    println!("recent CSR writes:");
+    vcsr::dump_history(state);
+    if let Error::UnsupportedDeviceAccess(addr) = error {
+        events::record(state, EventKind::DeviceError, addr);
+    }
+    events::record(state, EventKind::Crashed, csrr!(sepc));
+    print::flush();
+    restart::handle_crash(state);
+    guest_exited(state, 1)
+}
+
+/// Stop running the guest on this hart, which finished with the given exit code. Once every guest
+/// has stopped, the machine is shut down reporting the first non-zero exit code (if any), through
+/// the test finisher device, Spike's HTIF or semihosting.
+pub fn guest_exited(state: &mut Context, code: u64) -> ! {
+    state.uart.flush_output();
+    virtio::flush_backends(state);
+    events::record(state, EventKind::Exited, code);
+    SHARED_STATICS.exit_code.compare_and_swap(0, code, Ordering::SeqCst);
+    if SHARED_STATICS.guests_running.fetch_sub(1, Ordering::SeqCst) == 1 {
+        let code = SHARED_STATICS.exit_code.load(Ordering::SeqCst);
+        println!("All guests have stopped (exit code {})", code);
+        print::flush();
+        if let Some(ref mut finisher) = state.test_finisher {
+            match code {
+                0 => finisher.pass(),
+                _ => finisher.fail(code as u16),
+            }
+        }
+        if let Some(tohost) = state.htif_tohost {
+            unsafe { htif::exit(tohost as *mut u64, code) }
+        }
+        semihosting::exit(code);
+    }
+
+    unsafe { csrw!(sie, 0) }
+    loop {
+        riscv::wfi();
+    }
+}
+
+pub fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
+    trace!(state, TRACE_EXCEPTIONS, "exception {} at {:#x}, stval {:#x}", cause, sepc, csrr!(stval));
+    if cause == SCAUSE_ILLEGAL_INSN {
+        state.pmu.record(FirmwareEvent::IllegalInsn);
+    }
+    state.trap_to_guest(PrivilegeEvent::Exception, cause, sepc, csrr!(stval));
     riscv::set_sepc(state.csrs.stvec & TVEC_BASE);
 }
 
-pub unsafe fn load_instruction_at_address(_state: &mut Context, guest_va: u64) -> (u32, u64) {
-    let pc_ptr = guest_va as *const u16;
-    sum::access_user_memory(||{
-        let il: u16 = *pc_ptr;
-        match riscv_decode::instruction_length(il) {
-            2 => (il as u32, 2),
-            4 => (il as u32 | ((*pc_ptr.offset(1) as u32) << 16), 4),
-            _ => unreachable!(),
+pub fn load_instruction_at_address(_state: &mut Context, guest_va: u64) -> Result<(u32, u64)> {
+    let mut parcel = [0; 2];
+    sum::copy_from_guest(&mut parcel, guest_va)?;
+    let il = u16::from_le_bytes(parcel);
+    match riscv_decode::instruction_length(il) {
+        2 => Ok((il as u32, 2)),
+        4 => {
+            sum::copy_from_guest(&mut parcel, guest_va + 2)?;
+            Ok((il as u32 | (u16::from_le_bytes(parcel) as u32) << 16, 4))
         }
-    })
+        _ => Err(Error::UnsupportedInstruction(il as u32)),
+    }
 }
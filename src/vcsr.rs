@@ -0,0 +1,459 @@
+//! Virtualized supervisor CSRs.
+//!
+//! Every CSR the guest can access is described by an entry in `CSRS`: where its value lives, which
+//! bits read back and which bits the guest may change, plus optional hooks for CSRs whose accesses
+//! have side effects. Writes are WARL: bits outside the write mask keep their previous value, and a
+//! `legalize` hook can replace an unsupported value with a supported one. Adding a new virtualized
+//! CSR is then usually a matter of adding a field to `ControlRegisters` and a row to the table.
+
+use arrayvec::ArrayVec;
+use crate::constants::TIMER_FREQUENCY;
+use crate::context::{Context, ControlRegisters};
+use crate::options::TRACE_CSR;
+use crate::riscv::bits::*;
+use crate::riscv::csr;
+use crate::satp::{self, SatpMode};
+use crate::trap::U64Bits;
+use crate::{dirty, pmap, riscv};
+
+pub enum Storage {
+    /// Hard-wired to zero. Writes are accepted and ignored.
+    Zero,
+    /// Held in a field of the guest's `ControlRegisters`.
+    Field(fn(&mut ControlRegisters) -> &mut u64),
+    /// Computed on every read. Only valid for read-only CSRs.
+    Computed(fn(&mut Context) -> u64),
+    /// Not stored directly; reads and writes are translated by the given functions.
+    Emulated(fn(&mut Context) -> u64, fn(&mut Context, u64)),
+}
+
+pub struct CsrDescriptor {
+    pub number: u64,
+    pub name: &'static str,
+    /// Bits returned by reads. Everything else reads as zero.
+    pub read_mask: u64,
+    /// Bits the guest may change.
+    pub write_mask: u64,
+    pub storage: Storage,
+    /// Called before the stored value is read.
+    pub before_read: Option<fn(&mut Context)>,
+    /// Given the old value and the masked new value, returns the value to actually store.
+    pub legalize: Option<fn(&mut Context, u64, u64) -> u64>,
+    /// Called with the old and new value after a write has been stored.
+    pub after_write: Option<fn(&mut Context, u64, u64)>,
+}
+
+impl CsrDescriptor {
+    /// CSRs with both of the top two address bits set are read-only.
+    pub fn read_only(&self) -> bool {
+        (self.number >> 10) & 0x3 == 0x3
+    }
+
+    pub fn read(&self, state: &mut Context) -> u64 {
+        if let Some(before_read) = self.before_read {
+            before_read(state);
+        }
+        let value = match self.storage {
+            Storage::Zero => 0,
+            Storage::Field(field) => *field(&mut state.csrs),
+            Storage::Computed(compute) | Storage::Emulated(compute, _) => compute(state),
+        };
+        value & self.read_mask
+    }
+
+    /// Returns false if the CSR can't be written.
+    pub fn write(&self, state: &mut Context, value: u64) -> bool {
+        let field = match self.storage {
+            _ if self.read_only() => return false,
+            Storage::Zero => return true,
+            Storage::Field(field) => field,
+            Storage::Computed(_) => return false,
+            Storage::Emulated(read, write) => {
+                let old = read(state);
+                let new = (old & !self.write_mask) | (value & self.write_mask);
+                trace!(state, TRACE_CSR, "{} {:#x} -> {:#x} at {:#x}", self.name, old, new, csrr!(sepc));
+                write(state, new);
+                return true;
+            }
+        };
+
+        let old = *field(&mut state.csrs);
+        let mut new = (old & !self.write_mask) | (value & self.write_mask);
+        if let Some(legalize) = self.legalize {
+            new = legalize(state, old, new);
+        }
+        *field(&mut state.csrs) = new;
+        trace!(state, TRACE_CSR, "{} {:#x} -> {:#x} at {:#x}", self.name, old, new, csrr!(sepc));
+        if let Some(ring) = HISTORY_CSRS.iter().position(|&n| n == self.number) {
+            let write = CsrWrite { old, new, time: state.host_clint.get_mtime(), pc: csrr!(sepc) };
+            state.csr_history.push(ring, write);
+        }
+
+        if let Some(after_write) = self.after_write {
+            after_write(state, old, new);
+        }
+        true
+    }
+}
+
+macro_rules! field {
+    ($name:ident) => {{
+        fn access(csrs: &mut ControlRegisters) -> &mut u64 { &mut csrs.$name }
+        Storage::Field(access)
+    }}
+}
+
+pub static CSRS: [CsrDescriptor; 18] = [
+    CsrDescriptor {
+        number: csr::sstatus,
+        name: "sstatus",
+        read_mask: !0,
+        write_mask: SSTATUS_WRITABLE_MASK,
+        storage: field!(sstatus),
+        before_read: Some(sstatus_refresh),
+        legalize: None,
+        after_write: Some(sstatus_written),
+    },
+    CsrDescriptor {
+        number: csr::sie,
+        name: "sie",
+        read_mask: !0,
+        // User interrupts not supported
+        write_mask: IE_SEIE | IE_STIE | IE_SSIE | IE_LCOFIE,
+        storage: field!(sie),
+        before_read: None,
+        legalize: Some(counter_overflow_legalize),
+        after_write: Some(sie_written),
+    },
+    CsrDescriptor {
+        number: csr::stvec,
+        name: "stvec",
+        read_mask: !0,
+        write_mask: !0x2,
+        storage: field!(stvec),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::sscratch,
+        name: "sscratch",
+        read_mask: !0,
+        write_mask: !0,
+        storage: field!(sscratch),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::sepc,
+        name: "sepc",
+        read_mask: !0,
+        write_mask: !0x1,
+        storage: field!(sepc),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::scause,
+        name: "scause",
+        read_mask: !0,
+        write_mask: !0,
+        storage: field!(scause),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::stval,
+        name: "stval",
+        read_mask: !0,
+        write_mask: !0,
+        storage: field!(stval),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::sip,
+        name: "sip",
+        read_mask: !0,
+        // Only the software and counter overflow interrupts are writable; the others reflect the
+        // interrupt sources.
+        write_mask: IP_SSIP | IP_LCOFIP,
+        storage: field!(sip),
+        before_read: None,
+        legalize: Some(counter_overflow_legalize),
+        after_write: Some(sip_written),
+    },
+    CsrDescriptor {
+        number: csr::satp,
+        name: "satp",
+        read_mask: !0,
+        // ASIDs aren't supported, so the field reads as zero.
+        write_mask: !SATP_ASID,
+        storage: field!(satp),
+        before_read: None,
+        legalize: Some(satp_legalize),
+        after_write: Some(satp_written),
+    },
+    CsrDescriptor {
+        number: csr::stimecmp,
+        name: "stimecmp",
+        read_mask: !0,
+        write_mask: !0,
+        storage: Storage::Emulated(stimecmp_read, stimecmp_write),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::sedeleg,
+        name: "sedeleg",
+        read_mask: 0,
+        write_mask: 0,
+        storage: Storage::Zero,
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::sideleg,
+        name: "sideleg",
+        read_mask: 0,
+        write_mask: 0,
+        storage: Storage::Zero,
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::scounteren,
+        name: "scounteren",
+        read_mask: 0,
+        write_mask: 0,
+        storage: Storage::Zero,
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::senvcfg,
+        name: "senvcfg",
+        read_mask: !0,
+        write_mask: ENVCFG_FIOM | ENVCFG_CBIE | ENVCFG_CBCFE | ENVCFG_CBZE,
+        storage: field!(senvcfg),
+        before_read: None,
+        legalize: Some(senvcfg_legalize),
+        after_write: Some(senvcfg_written),
+    },
+    CsrDescriptor {
+        number: csr::scountovf,
+        name: "scountovf",
+        read_mask: !0,
+        write_mask: 0,
+        storage: Storage::Computed(scountovf_read),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::time,
+        name: "time",
+        read_mask: !0,
+        write_mask: 0,
+        storage: Storage::Computed(time_read),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::cycle,
+        name: "cycle",
+        read_mask: !0,
+        write_mask: 0,
+        storage: Storage::Computed(cycle_read),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+    CsrDescriptor {
+        number: csr::instret,
+        name: "instret",
+        read_mask: !0,
+        write_mask: 0,
+        storage: Storage::Computed(instret_read),
+        before_read: None,
+        legalize: None,
+        after_write: None,
+    },
+];
+
+pub fn lookup(number: u64) -> Option<&'static CsrDescriptor> {
+    CSRS.iter().find(|d| d.number == number)
+}
+
+/// CSRs whose recent writes are kept, since what a guest last wrote to them often explains why it
+/// crashed.
+const HISTORY_CSRS: [u64; 4] = [csr::satp, csr::stvec, csr::sstatus, csr::sie];
+/// Writes kept for each of them. Each CSR has a ring of its own, so that a guest busily toggling
+/// sstatus.SIE can't push its last write to satp out of the history.
+const HISTORY_DEPTH: usize = 8;
+const HISTORY_RINGS: usize = HISTORY_CSRS.len();
+const HISTORY_WRITES: usize = HISTORY_RINGS * HISTORY_DEPTH;
+
+#[derive(Copy, Clone)]
+struct CsrWrite {
+    old: u64,
+    new: u64,
+    /// Host `mtime` when the write was made.
+    time: u64,
+    /// Guest pc of the instruction that made it.
+    pc: u64,
+}
+
+/// The guest's last few writes to each of `HISTORY_CSRS`, shown when it crashes.
+pub struct CsrHistory {
+    writes: [[Option<CsrWrite>; HISTORY_DEPTH]; HISTORY_RINGS],
+    next: [usize; HISTORY_RINGS],
+}
+
+impl CsrHistory {
+    pub fn new() -> Self {
+        Self { writes: [[None; HISTORY_DEPTH]; HISTORY_RINGS], next: [0; HISTORY_RINGS] }
+    }
+
+    fn push(&mut self, ring: usize, write: CsrWrite) {
+        self.writes[ring][self.next[ring]] = Some(write);
+        self.next[ring] = (self.next[ring] + 1) % HISTORY_DEPTH;
+    }
+}
+
+/// Print the guest's recent writes to satp, stvec, sstatus and sie, oldest first, with how long ago
+/// each was made and where.
+pub fn dump_history(state: &Context) {
+    let mut writes = ArrayVec::<[(u64, CsrWrite); HISTORY_WRITES]>::new();
+    for (ring, &number) in state.csr_history.writes.iter().zip(HISTORY_CSRS.iter()) {
+        writes.extend(ring.iter().filter_map(|w| *w).map(|w| (number, w)));
+    }
+    if writes.is_empty() {
+        println!("no CSR writes recorded");
+        return;
+    }
+    writes.sort_unstable_by_key(|&(_, w)| w.time);
+
+    let now = state.host_clint.get_mtime();
+    for (number, w) in writes {
+        let name = lookup(number).map(|d| d.name).unwrap_or("?");
+        let age = (now - w.time) / (TIMER_FREQUENCY / 1_000_000);
+        println!("  {:>10}us ago {:<7} {:#x} -> {:#x} at {}", age, name, w.old, w.new, state.symbols.symbolize(w.pc));
+    }
+}
+
+/// The FS, VS and SD bits are tracked by the hardware, so pick them up from the real sstatus.
+fn sstatus_refresh(state: &mut Context) {
+    let real = csrr!(sstatus);
+    state.csrs.sstatus = (state.csrs.sstatus & !SSTATUS_DYNAMIC_MASK) | (real & SSTATUS_DYNAMIC_MASK);
+}
+
+fn sstatus_written(state: &mut Context, old: u64, new: u64) {
+    let changed = old ^ new;
+    if changed & STATUS_FS != 0 {
+        riscv::set_sstatus_fs(new);
+    }
+    if changed & STATUS_VS != 0 {
+        riscv::set_sstatus_vs(new);
+    }
+
+    if changed.get(STATUS_SIE) && new.get(STATUS_SIE) {
+        // Enabling interrupts might cause one to happen right away.
+        state.no_interrupt = false;
+    }
+}
+
+fn sie_written(state: &mut Context, old: u64, new: u64) {
+    if !old & new != 0 {
+        state.no_interrupt = false;
+    }
+}
+
+fn sip_written(state: &mut Context, _old: u64, new: u64) {
+    if new & (IP_SSIP | IP_LCOFIP) != 0 {
+        state.no_interrupt = false;
+    }
+}
+
+/// The counter overflow bits of sie and sip are read-only zero unless the guest was offered
+/// Sscofpmf.
+fn counter_overflow_legalize(state: &mut Context, _old: u64, new: u64) -> u64 {
+    if state.pmu.overflow_interrupts() {
+        new
+    } else {
+        new & !IE_LCOFIE
+    }
+}
+
+/// Only the fields for cache block operations the host implements are writable, and the reserved
+/// CBIE encoding leaves the field unchanged.
+fn senvcfg_legalize(state: &mut Context, old: u64, new: u64) -> u64 {
+    let new = (old & !state.host_envcfg) | (new & state.host_envcfg);
+    if new & ENVCFG_CBIE == 0x2 << 4 {
+        (new & !ENVCFG_CBIE) | (old & ENVCFG_CBIE)
+    } else {
+        new
+    }
+}
+
+fn senvcfg_written(state: &mut Context, _old: u64, _new: u64) {
+    state.update_host_envcfg();
+}
+
+/// Writes selecting a mode that isn't supported have no effect at all (see satp.rs), and each mode
+/// refused is reported once.
+fn satp_legalize(state: &mut Context, old: u64, new: u64) -> u64 {
+    let legal = satp::legalize(old, new);
+    if legal != new && satp::refuse(&mut state.satp_modes_refused, new) {
+        println!("Guest asked for {:?} translation, which is not supported; staying in {:?}",
+                 SatpMode::from_satp(new), SatpMode::from_satp(old));
+    }
+    legal
+}
+
+fn satp_written(state: &mut Context, _old: u64, new: u64) {
+    if new & SATP_MODE == 0 {
+        // Without paging, the guest runs on huge page mappings that never fault.
+        dirty::paging_disabled(state);
+    }
+    // This should not be necessary. However, currently QEMU doesn't trap when
+    // sfence.vma is executed from user mode so flush here to compensate.
+    pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+}
+
+/// The guest's timer is kept in host time, so stimecmp has to be converted to and from the guest's
+/// view of time. Sstc is always advertised to guests: with hardware support the hypervisor programs
+/// the host's stimecmp directly, and otherwise uses SBI calls as it would for sbi_set_timer.
+fn stimecmp_read(state: &mut Context) -> u64 {
+    state.csrs.mtimecmp.wrapping_sub(state.csrs.time_offset)
+}
+
+fn stimecmp_write(state: &mut Context, value: u64) {
+    state.set_guest_timer(value);
+}
+
+fn time_read(state: &mut Context) -> u64 {
+    state.guest_time()
+}
+
+/// Counters can be stopped or preset by the guest through the SBI PMU extension. See pmu.rs.
+fn cycle_read(state: &mut Context) -> u64 {
+    state.pmu.cycle()
+}
+
+fn instret_read(state: &mut Context) -> u64 {
+    state.pmu.instret()
+}
+
+fn scountovf_read(state: &mut Context) -> u64 {
+    state.pmu.overflowed()
+}
@@ -1,59 +1,221 @@
 use byteorder::{NativeEndian, ByteOrder};
 use riscv_decode::Instruction;
-use crate::context::Context;
+use core::sync::atomic::Ordering;
+use crate::boottime::{self, Milestone};
+use crate::context::{Context, IrqMapping, SavedRegisters};
+use crate::deferred::Work;
+use crate::error::{Error, Result};
+use crate::events::{self, EventKind};
 use crate::memory_region::MemoryRegion;
+use crate::profile::{self, Probe};
+use crate::report::Record;
+use crate::drivers::blk::BlkDriver;
 use crate::drivers::macb::MacbDriver;
-use crate::{pmap, riscv, drivers};
+use crate::drivers::vsock::VsockDriver;
+use crate::riscv::bits::IP_SEIP;
+use crate::statics::SHARED_STATICS;
+use crate::throttle::Throttle;
+use crate::timer::TimerEvent;
+use crate::virtqueue::{self, Queue, is_ring_address, legacy_queue_size, new_requests, ring_address};
+use crate::{hart, pmap, riscv, drivers};
 
-pub const MAX_QUEUES: usize = 4;
+/// Most queues of a passthrough device the guest can use. Queues past these are hidden by reporting
+/// a QueueNumMax of zero for them, which is how virtio-mmio says a queue doesn't exist.
+pub const MAX_QUEUES: usize = 16;
 pub const MAX_DEVICES: usize = 4;
 
-#[derive(Copy, Clone)]
-pub struct Queue {
-    /// Address guest thinks queue is mapped at
-    guest_pa: u64,
-    /// Address queue is actually mapped at
-    host_pa: u64,
-    /// Number of entries in queue
-    size: u64,
+/// Transport features never offered to guests: indirect descriptors (28), whose tables would need
+/// their addresses translated too, and the packed ring layout (34), which the queue handling here
+/// doesn't understand.
+const HIDDEN_TRANSPORT_FEATURES: u64 = 1 << 28 | 1 << 34;
+
+/// VIRTIO_BLK_F_CONFIG_WCE. A guest could use it to switch the host disk's cache to writethrough,
+/// and the setting would outlive the guest's hold on the device.
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11;
+
+const VIRTIO_ID_FS: u32 = 26;
+/// Offset of `num_request_queues` in the config space of a virtio-fs device, after its 36 byte tag.
+const VIRTIO_FS_NUM_REQUEST_QUEUES: u64 = 0x100 + 36;
+
+/// Features of a passthrough device that the guest doesn't get to see: those of every device,
+/// those of its type, and any hidden by `config`, the `rvirt,virtio-hide-features` entries.
+pub fn hidden_features(config: &[(u32, u32, u32)], device_id: u32) -> u64 {
+    let mut hidden = HIDDEN_TRANSPORT_FEATURES;
+    if device_id == 2 {
+        hidden |= VIRTIO_BLK_F_CONFIG_WCE;
+    }
+    for &(id, low, high) in config {
+        if id == 0 || id == device_id {
+            hidden |= (high as u64) << 32 | low as u64;
+        }
+    }
+    hidden
 }
 
 pub enum Device {
     Passthrough {
         /// Virtual Queue Index, offset=0x30
         queue_sel: u32,
+        /// Which half of the features the guest is reading and writing, offset=0x14 and 0x24
+        host_features_sel: u32,
+        guest_features_sel: u32,
+        /// Features the device has that are kept from the guest
+        hidden_features: u64,
         queues: [Queue; MAX_QUEUES],
         device_registers: MemoryRegion<u32>,
+        throttle: Throttle,
     },
     Unmapped,
     Macb(drivers::GuestDevice<MacbDriver>),
+    /// Emulated vsock device, and the guest interrupt it raises.
+    Vsock(drivers::GuestDevice<VsockDriver>, u16),
+    /// Emulated block device backed by a host disk, and the guest interrupt it raises.
+    Blk(drivers::GuestDevice<BlkDriver>, u16),
 }
 impl Device {
-    pub unsafe fn new(host_base_address: u64) -> Self {
+    pub unsafe fn new(host_base_address: u64, requests_per_sec: u64, bytes_per_sec: u64,
+                      hide_features: &[(u32, u32, u32)]) -> Self {
+        let device_registers = MemoryRegion::with_base_address(pmap::pa2va(host_base_address), 0, 0x1000);
+        let hidden_features = hidden_features(hide_features, device_registers[drivers::REG_DEVICE_ID]);
         Device::Passthrough {
             queue_sel: 0,
-            queues: [Queue {guest_pa: 0, host_pa: 0, size: 0}; MAX_QUEUES],
-            device_registers: MemoryRegion::with_base_address(pmap::pa2va(host_base_address), 0, 0x1000),
+            host_features_sel: 0,
+            guest_features_sel: 0,
+            hidden_features,
+            queues: [Queue::default(); MAX_QUEUES],
+            device_registers,
+            throttle: Throttle::new(requests_per_sec, bytes_per_sec),
+        }
+    }
+
+    /// Whether the device may have something for the guest while its host interrupt is masked. Only
+    /// passthrough devices can say for sure; polling the others does no harm.
+    pub fn interrupt_pending(&self) -> bool {
+        match *self {
+            Device::Passthrough { ref device_registers, .. } => device_registers[0x60] != 0,
+            Device::Macb(..) => true,
+            Device::Unmapped | Device::Vsock(..) | Device::Blk(..) => false,
+        }
+    }
+
+    pub fn throttle(&mut self) -> Option<&mut Throttle> {
+        match *self {
+            Device::Passthrough { ref mut throttle, .. } => Some(throttle),
+            _ => None,
         }
     }
 }
 
+/// Give the guest host virtio device `host_index`, which no guest is using, in its first empty
+/// slot. Returns the slot, or why the device couldn't be added.
+///
+/// Empty slots are already in the guest's device tree, where they read as a device with ID zero
+/// that drivers skip. Once a device is added, the guest has to probe the slot again, for instance
+/// on Linux by writing the slot's platform device name to the `bind` file of the virtio-mmio
+/// driver. The control guest can find out about the new device from the event log.
+pub fn hot_add(state: &mut Context, host_index: usize) -> core::result::Result<usize, &'static str> {
+    let host = state.virtio.host_devices.get(host_index).cloned().ok_or("no such host device")?;
+    let slot = (0..state.virtio.devices.len()).find(|&i| match state.virtio.devices[i] {
+        Device::Unmapped => state.virtio.slot_irqs[i].is_some(),
+        _ => false,
+    }).ok_or("no free virtio slot in this guest")?;
+    let guest_irq = state.virtio.slot_irqs[slot].unwrap();
+    if state.irq_map.get(host.irq as usize) != Some(&IrqMapping::Ignored) {
+        return Err("host interrupt already in use");
+    }
+
+    let guestid = hart::current().guest_index();
+    if SHARED_STATICS.virtio_owners[host_index].compare_and_swap(0, guestid, Ordering::SeqCst) != 0 {
+        return Err("device already belongs to a guest");
+    }
+
+    let (requests_per_sec, bytes_per_sec) = state.virtio.io_limits;
+    state.virtio.devices[slot] = unsafe {
+        Device::new(host.base_address, requests_per_sec, bytes_per_sec, &state.virtio.hide_features)
+    };
+    state.irq_map[host.irq as usize] = IrqMapping::Virtio { device_index: slot as u8, guest_irq };
+    state.host_irqchip.enable(host.irq as u32);
+    events::record(state, EventKind::DeviceAdded, (slot as u64) << 32 | host_index as u64);
+    Ok(slot)
+}
+
+/// The half of a feature set selected by a write of `sel` to a features select register.
+fn feature_word(features: u64, sel: u32) -> u32 {
+    match sel {
+        0 => features as u32,
+        1 => (features >> 32) as u32,
+        _ => 0,
+    }
+}
+
+/// Deliver any queue notifications held back by I/O throttling that are now allowed through.
+/// Returns when to try again, if some are still being held back.
+pub fn poll_throttled(state: &mut Context, now: u64) -> Option<u64> {
+    let mut next: Option<u64> = None;
+    for device in &mut state.virtio.devices {
+        if let Device::Passthrough { ref mut queues, ref mut device_registers, ref mut throttle, .. } = *device {
+            for (i, queue) in queues.iter_mut().enumerate() {
+                if throttle.pending() & (1 << i) == 0 {
+                    continue;
+                }
+                let (avail_idx, requests, bytes) = new_requests(&state.guest_memory, queue);
+                if throttle.admit(i as u32, now, requests, bytes) {
+                    queue.last_avail = avail_idx;
+                    device_registers[0x50] = i as u32;
+                }
+            }
+            if let Some(retry) = throttle.next_retry(now) {
+                next = Some(next.map_or(retry, |n| n.min(retry)));
+            }
+        }
+    }
+    next
+}
+
 #[inline(always)]
 pub fn is_device_access(state: &mut Context, guest_pa: u64) -> bool {
     guest_pa >= 0x10001000 && guest_pa < 0x10001000 + 0x1000 * state.virtio.devices.len() as u64
 }
 
-pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<()> {
     let device = ((guest_pa - 0x10001000) / 0x1000) as usize;
     let offset = guest_pa & 0xfff;
+    let mut retry = None;
+    let mut throttled = None;
+    let mut ready = None;
+    if offset == 0x50 {
+        boottime::mark(Milestone::FirstVirtioRequest);
+    }
 
     match state.virtio.devices[device] {
-        Device::Passthrough { ref mut queue_sel, ref mut queues, ref mut device_registers } => {
+        Device::Passthrough { ref mut queue_sel, ref mut host_features_sel, ref mut guest_features_sel,
+                              hidden_features, ref mut queues, ref mut device_registers, ref mut throttle } => {
             let mut current = device_registers[offset & !0x3];
-            if offset == 0x10 {
-                current = current & !(1 << 28); // No VIRTIO_F_INDIRECT_DESC
+            if offset == 0xc {
+                // QEMU's vendor ID has changed between versions, so guests always see the one
+                // emulated devices have.
+                current = drivers::VENDOR_ID;
+            } else if offset == 0x10 {
+                current &= !feature_word(hidden_features, *host_features_sel);
             } else if offset == 0x34 {
                 current = current.min(256); // ensure queues take up at most one page
+                if *queue_sel as usize >= MAX_QUEUES {
+                    current = 0;
+                }
+            } else if is_ring_address(offset) {
+                // The device has host addresses, so give back the ones the driver wrote.
+                current = queues.get_mut(*queue_sel as usize)
+                    .map_or(0, |queue| (*ring_address(queue, offset) >> (8 * (offset & 0x4))) as u32);
+            } else if offset == 0xb0 || offset == 0xb4 {
+                // SHMLen. Shared memory regions, like the DAX window of virtio-fs, are host
+                // memory the guest can't be given, so every region reads as missing.
+                current = u32::max_value();
+            } else if offset == 0xb8 || offset == 0xbc {
+                current = 0;
+            } else if offset & !0x3 == VIRTIO_FS_NUM_REQUEST_QUEUES
+                && device_registers[drivers::REG_DEVICE_ID] == VIRTIO_ID_FS {
+                // Queue 0 is the high priority queue, and the rest carry requests.
+                current = current.min(MAX_QUEUES as u32 - 1);
             }
 
             match riscv_decode::decode(instruction).ok() {
@@ -61,53 +223,127 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                     state.saved_registers.set(i.rd(), current as u64)
                 }
                 Some(Instruction::Lb(i)) => {
-                    assert!(offset >= 0x100);
+                    if offset < 0x100 {
+                        return Err(Error::UnsupportedDeviceAccess(guest_pa));
+                    }
                     let value = (current >> (8*(offset & 0x3))) & 0xff;
                     state.saved_registers.set(i.rd(), value as u64)
                 }
                 Some(Instruction::Sw(i)) => {
+                    let start = profile::start();
                     let mut value = state.saved_registers.get(i.rs2()) as u32;
-                    if offset == 0x30 { // QueueSel
-                        assert!(value < 4);
+                    let mut deliver = true;
+                    if offset == 0x14 { // HostFeaturesSel
+                        *host_features_sel = value;
+                    } else if offset == 0x20 { // GuestFeatures
+                        // Drivers shouldn't accept features that weren't offered, but make sure.
+                        value &= !feature_word(hidden_features, *guest_features_sel);
+                    } else if offset == 0x24 { // GuestFeaturesSel
+                        *guest_features_sel = value;
+                    } else if offset == 0x30 { // QueueSel
+                        // Drivers probe for queues by selecting them in turn, so selecting one
+                        // past MAX_QUEUES is fine as long as it isn't then set up.
                         *queue_sel = value;
                     } else if offset == 0x38 { // QueueNum
-                        let queue = &mut queues[*queue_sel as usize];
+                        let queue = queues.get_mut(*queue_sel as usize)
+                            .ok_or(Error::UnsupportedDeviceAccess(guest_pa))?;
                         queue.size = value as u64;
 
                         // Linux never changes queue sizes, so this isn't supported.
-                        assert_eq!(queue.host_pa, 0);
+                        if queue.host_pa != 0 {
+                            return Err(Error::UnsupportedDeviceAccess(guest_pa));
+                        }
                     } else if offset == 0x40 { // QueuePFN
-                        let queue = &mut queues[*queue_sel as usize];
+                        let queue = queues.get_mut(*queue_sel as usize)
+                            .ok_or(Error::UnsupportedDeviceAccess(guest_pa))?;
 
                         // Linux never releases queues, so this is currently unimplemented.
-                        assert_eq!(queue.host_pa, 0);
+                        if queue.host_pa != 0 || value == 0 {
+                            return Err(Error::UnsupportedDeviceAccess(guest_pa));
+                        }
 
-                        if value != 0 {
-                            queue.guest_pa = (value as u64) << 12;
-                            value += (state.guest_shift >> 12) as u32;
-                            queue.host_pa = (value as u64) << 12;
-                        } else {
-                            unimplemented!();
+                        // The device sees the whole queue as one block of host memory, so it
+                        // mustn't straddle two of the guest's segments.
+                        queue.guest_pa = (value as u64) << 12;
+                        queue.host_pa = state.guest_map.host_range(queue.guest_pa, legacy_queue_size(queue.size))
+                            .ok_or(Error::UnsupportedDeviceAccess(guest_pa))?;
+                        value = (queue.host_pa >> 12) as u32;
+                        queue.avail = queue.guest_pa + queue.size * 16;
+                        ready = Some(*queue);
+                    } else if is_ring_address(offset) { // QueueDesc, QueueAvail and QueueUsed
+                        let queue = queues.get_mut(*queue_sel as usize)
+                            .ok_or(Error::UnsupportedDeviceAccess(guest_pa))?;
+                        if queue.host_pa != 0 {
+                            return Err(Error::UnsupportedDeviceAccess(guest_pa));
                         }
 
-                        // Sad, but necessary because we don't know all the places this page is mapped.
-                        pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+                        // The device is only told the addresses once the queue is ready, when
+                        // they can be translated all together.
+                        let address = ring_address(queue, offset);
+                        *address = if offset & 0x4 == 0 {
+                            *address & !0xffffffff | value as u64
+                        } else {
+                            *address & 0xffffffff | (value as u64) << 32
+                        };
+                        deliver = false;
+                    } else if offset == 0x44 { // QueueReady
+                        let queue = queues.get_mut(*queue_sel as usize)
+                            .ok_or(Error::UnsupportedDeviceAccess(guest_pa))?;
+
+                        // As with QueuePFN, queues are never released.
+                        if queue.host_pa != 0 || value == 0 {
+                            return Err(Error::UnsupportedDeviceAccess(guest_pa));
+                        }
 
-                        state.virtio.queue_guest_pages.push(queue.guest_pa);
-                        for i in 0..queue.size {
-                            let value = &mut state.guest_memory[queue.guest_pa + i * 16];
-                            *value = (*value).wrapping_add(state.guest_shift);
+                        // Each of the three parts of the queue has to be in one block of host
+                        // memory, but they needn't be next to each other.
+                        let size = queue.size;
+                        let map = &state.guest_map;
+                        let host_range = |address, len| map.host_range(address, len)
+                            .ok_or(Error::UnsupportedDeviceAccess(guest_pa));
+                        let parts = [
+                            (0x80, host_range(queue.guest_pa, size * 16)?),
+                            (0x90, host_range(queue.avail, 6 + size * 2)?),
+                            (0xa0, host_range(queue.used, 6 + size * 8)?),
+                        ];
+                        for &(register, address) in &parts {
+                            device_registers[register] = address as u32;
+                            device_registers[register + 4] = (address >> 32) as u32;
+                        }
+                        queue.host_pa = parts[0].1;
+                        ready = Some(*queue);
+                    } else if offset == 0x50 { // QueueNotify
+                        // With VIRTIO_F_NOTIFICATION_DATA the upper half holds the avail index.
+                        let index = value & 0xffff;
+                        if let Some(queue) = queues.get_mut(index as usize).filter(|q| q.host_pa != 0) {
+                            let (avail_idx, requests, bytes) = new_requests(&state.guest_memory, queue);
+                            let now = state.host_clint.get_mtime();
+                            let already_pending = throttle.pending() & (1 << index) != 0;
+                            deliver = throttle.admit(index, now, requests, bytes);
+                            if deliver {
+                                queue.last_avail = avail_idx;
+                            } else {
+                                retry = throttle.next_retry(now);
+                                if !already_pending {
+                                    throttled = Some(index as u64);
+                                }
+                            }
                         }
                     }
-                    device_registers[offset] = value;
+                    if deliver {
+                        device_registers[offset] = value;
+                    }
+                    if offset == 0x50 {
+                        state.profile.record(Probe::VirtioNotify, start);
+                    }
                 }
                 Some(instr) => {
                     println!("VIRTIO: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-                    loop {}
+                    return Err(Error::UnsupportedInstruction(instruction));
                 }
                 None => {
                     println!("Unrecognized instruction targetting VIRTIO {:#x} at {:#x}!", instruction, csrr!(sepc));
-                    loop {}
+                    return Err(Error::UnsupportedInstruction(instruction));
                 }
             }
         }
@@ -118,24 +354,130 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                 Some(Instruction::Sw(_)) => {}
                 Some(instr) => {
                     println!("VIRTIO: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-                    loop {}
+                    return Err(Error::UnsupportedInstruction(instruction));
                 }
                 None => {
                     println!("Unrecognized instruction targetting VIRTIO {:#x} at {:#x}!", instruction, csrr!(sepc));
-                    loop {}
+                    return Err(Error::UnsupportedInstruction(instruction));
                 }
             }
         }
-        Device::Macb(ref mut macb) => match riscv_decode::decode(instruction).ok() {
-            Some(Instruction::Lb(i)) => state.saved_registers.set(i.rd(), macb.read_u8(&mut state.guest_memory, offset) as u64),
-            Some(Instruction::Lw(i)) => state.saved_registers.set(i.rd(), macb.read_u32(&mut state.guest_memory, offset) as u64),
-            Some(Instruction::Sb(i)) => macb.write_u8(&mut state.guest_memory, offset, state.saved_registers.get(i.rs2()) as u8),
-            Some(Instruction::Sw(i)) => macb.write_u32(&mut state.guest_memory, offset, state.saved_registers.get(i.rs2()) as u32),
-            Some(_) | None => {}
-        }
+        Device::Macb(ref mut macb) => emulated_device_access(
+            macb, &mut state.saved_registers, &mut state.guest_memory, offset, instruction),
+        Device::Vsock(ref mut vsock, _) => emulated_device_access(
+            vsock, &mut state.saved_registers, &mut state.guest_memory, offset, instruction),
+        Device::Blk(ref mut blk, _) => emulated_device_access(
+            blk, &mut state.saved_registers, &mut state.guest_memory, offset, instruction),
     }
+    if let Some(queue) = ready {
+        track_descriptors(state, &queue, guest_pa)?;
+    }
+    if let Some(queue) = throttled {
+        events::record(state, EventKind::Throttled, (device as u64) << 32 | queue);
+    }
+    if let Some(retry) = retry {
+        // Other devices may need to be retried sooner.
+        let deadline = state.timers.deadline(TimerEvent::ThrottleRetry).map_or(retry, |d| d.min(retry));
+        state.schedule_timer(TimerEvent::ThrottleRetry, deadline);
+    }
+    state.deferred.raise(Work::EmulatedInterrupts);
     riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
+    Ok(())
+}
+
+/// Start translating the addresses in the descriptor table of a queue the guest has just set up.
+/// Accesses to the pages holding the table trap from now on, and the entries already there are
+/// translated in place.
+fn track_descriptors(state: &mut Context, queue: &Queue, guest_pa: u64) -> Result<()> {
+    // Sad, but necessary because we don't know all the places this page is mapped.
+    pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+
+    let pages = &mut state.virtio.queue_guest_pages;
+    let mut page = queue.guest_pa & !0xfff;
+    while page < queue.guest_pa + queue.size * 16 {
+        if !pages.contains(&page) {
+            pages.try_push(page).map_err(|_| Error::UnsupportedDeviceAccess(guest_pa))?;
+        }
+        page += 0x1000;
+    }
+    virtqueue::translate_descriptors(&mut state.guest_memory, &state.guest_map, queue);
+    Ok(())
+}
+
+fn emulated_device_access<D: drivers::Driver>(device: &mut drivers::GuestDevice<D>,
+                                              registers: &mut SavedRegisters,
+                                              guest_memory: &mut MemoryRegion,
+                                              offset: u64, instruction: u32) {
+    match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Lb(i)) => registers.set(i.rd(), device.read_u8(guest_memory, offset) as u64),
+        Some(Instruction::Lw(i)) => registers.set(i.rd(), device.read_u32(guest_memory, offset) as u64),
+        Some(Instruction::Sb(i)) => device.write_u8(guest_memory, offset, registers.get(i.rs2()) as u8),
+        Some(Instruction::Sw(i)) => device.write_u32(guest_memory, offset, registers.get(i.rs2()) as u32),
+        Some(_) | None => {}
+    }
+}
+
+/// Print a record of the `report` command for each device slot, and one for each queue that a
+/// passthrough device has set up.
+pub fn add_to_report(state: &Context) {
+    for (slot, device) in state.virtio.devices.iter().enumerate() {
+        let mut record = Record::new("virtio");
+        record.number("slot", slot as u64).hex("base", 0x10001000 + 0x1000 * slot as u64);
+        match device {
+            Device::Passthrough { queue_sel, hidden_features, device_registers, queues, .. } => {
+                record.string("kind", "passthrough")
+                    .number("device_id", device_registers[drivers::REG_DEVICE_ID] as u64)
+                    .hex("status", device_registers[0x70] as u64)
+                    .hex("interrupt_status", device_registers[0x60] as u64)
+                    .number("queue_sel", *queue_sel as u64)
+                    .hex("hidden_features", *hidden_features)
+                    .end();
+                for (index, queue) in queues.iter().enumerate().filter(|q| q.1.size != 0) {
+                    Record::new("virtqueue")
+                        .number("slot", slot as u64)
+                        .number("queue", index as u64)
+                        .hex("guest_pa", queue.guest_pa)
+                        .hex("host_pa", queue.host_pa)
+                        .number("size", queue.size)
+                        .number("last_avail", queue.last_avail as u64)
+                        .end();
+                }
+            }
+            Device::Unmapped => record.string("kind", "none").end(),
+            Device::Macb(..) => record.string("kind", "macb").end(),
+            Device::Vsock(_, irq) => record.string("kind", "vsock").number("irq", *irq as u64).end(),
+            Device::Blk(_, irq) => record.string("kind", "blk").number("irq", *irq as u64).end(),
+        }
+    }
+}
+
+/// Make everything written to the guest's emulated block devices durable, before the guest stops.
+pub fn flush_backends(state: &mut Context) {
+    for device in &mut state.virtio.devices {
+        if let Device::Blk(ref mut blk, _) = *device {
+            if let Err(e) = blk.host_driver().flush() {
+                println!("WARN: Unable to flush block device: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Raise the guest interrupt of any emulated device that has one outstanding.
+pub fn update_emulated_interrupts(state: &mut Context) {
+    for device in &state.virtio.devices {
+        let (pending, guest_irq) = match *device {
+            Device::Vsock(ref vsock, guest_irq) => (vsock.interrupt_pending(), guest_irq),
+            Device::Blk(ref blk, guest_irq) => (blk.interrupt_pending(), guest_irq),
+            _ => continue,
+        };
+        if pending {
+            state.plic.set_pending(guest_irq as u32, true);
+            if state.plic.interrupt_pending() {
+                state.no_interrupt = false;
+                state.csrs.sip |= IP_SEIP;
+            }
+        }
+    }
 }
 
 pub fn is_queue_access(state: &mut Context, guest_page: u64) -> bool {
@@ -147,51 +489,59 @@ pub fn is_queue_access(state: &mut Context, guest_page: u64) -> bool {
     false
 }
 
-pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> bool {
+pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> Result<()> {
     let mut hit_queue = false;
     for d in &state.virtio.devices {
         if let Device::Passthrough { ref queues, .. } = d {
-            for q in queues {
-                if guest_pa >= q.guest_pa && guest_pa < q.guest_pa + q.size * 16 && guest_pa & 0xf < 8 {
-                    hit_queue = true;
-                }
-            }
+            hit_queue |= queues.iter().any(|q| q.holds_descriptor_address(guest_pa));
         }
     }
 
-    let decoded = riscv_decode::decode(instruction);
-    if let Err(err) = decoded {
-        println!("Unrecognized instruction targetting VQUEUE {:#x} at {:#x} (error: {:?})!",
-                 instruction, csrr!(sepc), err);
-        loop {}
+    let decoded = match riscv_decode::decode(instruction) {
+        Ok(decoded) => decoded,
+        Err(err) => {
+            println!("Unrecognized instruction targetting VQUEUE {:#x} at {:#x} (error: {:?})!",
+                     instruction, csrr!(sepc), err);
+            return Err(Error::UnsupportedInstruction(instruction));
+        }
+    };
+
+    // Stores here are the guest's own, even though they never go through a writable mapping.
+    let store = match decoded {
+        Instruction::Sd(_) | Instruction::Sw(_) | Instruction::Sh(_) | Instruction::Sb(_) => true,
+        _ => false,
+    };
+    if store {
+        state.dirty.mark(&state.guest_memory, guest_pa);
     }
 
     if hit_queue {
-        match decoded.unwrap() {
+        match decoded {
             Instruction::Ld(i) => {
-                state.saved_registers.set(i.rd(), state.guest_memory[guest_pa].wrapping_sub(state.guest_shift));
+                let value = state.guest_memory[guest_pa];
+                state.saved_registers.set(i.rd(), virtqueue::guest_descriptor_address(&state.guest_map, value));
             }
             Instruction::Sd(i) => {
                 let value = state.saved_registers.get(i.rs2());
-                if value == 0 {
-                    state.guest_memory[guest_pa] = 0;
-                } else if state.guest_memory.in_region(value) {
-                    state.guest_memory[guest_pa] = value.wrapping_add(state.guest_shift);
-                } else {
-                    loop {}
+                match virtqueue::host_descriptor_address(&state.guest_map, value) {
+                    Some(host_pa) => state.guest_memory[guest_pa] = host_pa,
+                    None => {
+                        println!("VQUEUE: Descriptor points outside of guest memory ({:#x})", value);
+                        return Err(Error::UnsupportedDeviceAccess(guest_pa));
+                    }
                 }
             }
             instr => {
                 println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
                          instr, host_pa, csrr!(sepc));
-                loop {}
+                return Err(Error::UnsupportedInstruction(instruction));
             }
         }
     } else {
         let index = guest_pa & !0x7;
         let offset = (guest_pa % 8) as usize;
         let mut current = state.guest_memory[index].to_ne_bytes();
-        match decoded.as_ref().unwrap() {
+        match decoded {
             Instruction::Ld(i) => state.saved_registers.set(i.rd(), u64::from_ne_bytes(current)),
             Instruction::Lwu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u32(&current[offset..]) as u64),
             Instruction::Lhu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u16(&current[offset..]) as u64),
@@ -215,11 +565,11 @@ pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, ins
             instr => {
                 println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
                          instr, host_pa, csrr!(sepc));
-                loop {}
+                return Err(Error::UnsupportedInstruction(instruction));
             }
         }
     }
 
     riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
+    Ok(())
 }
@@ -0,0 +1,267 @@
+//! The virtqueues of passthrough devices, as the guest lays them out in its own memory.
+//!
+//! The device reads the queues through host physical addresses, so the addresses in the
+//! descriptor table are translated as the guest writes them (see `virtio::handle_queue_access`).
+//! Everything here works on guest memory alone, without the guest's `Context`.
+
+use crate::drivers::VIRTQ_DESC_F_NEXT;
+use crate::memory_region::MemoryRegion;
+use crate::pmap::GuestMap;
+
+#[derive(Copy, Clone, Default)]
+pub struct Queue {
+    /// Address guest thinks queue is mapped at
+    pub guest_pa: u64,
+    /// Address queue is actually mapped at
+    pub host_pa: u64,
+    /// Number of entries in queue
+    pub size: u64,
+    /// Guest addresses of the available and used rings. For a legacy device they follow the
+    /// descriptor table, while a modern one has the driver write each address on its own.
+    pub avail: u64,
+    pub used: u64,
+    /// Value of the available ring index when the device was last notified
+    pub last_avail: u16,
+}
+
+impl Queue {
+    /// Whether `guest_pa` is in the address field of one of the queue's descriptors. Always false
+    /// until the queue has been set up.
+    pub fn holds_descriptor_address(&self, guest_pa: u64) -> bool {
+        self.host_pa != 0 && guest_pa >= self.guest_pa && guest_pa < self.guest_pa + self.size * 16
+            && guest_pa & 0xf < 8
+    }
+}
+
+/// Size of a legacy virtqueue with `size` entries: the descriptor table and available ring,
+/// followed by the used ring on the next page boundary.
+pub fn legacy_queue_size(size: u64) -> u64 {
+    ((size * 16 + 6 + size * 2 + 0xfff) & !0xfff) + 6 + size * 8
+}
+
+/// The address a modern driver sets through `register`, one of the halves of QueueDesc, QueueAvail
+/// or QueueUsed.
+pub fn ring_address(queue: &mut Queue, register: u64) -> &mut u64 {
+    match register & !0xf {
+        0x80 => &mut queue.guest_pa,
+        0x90 => &mut queue.avail,
+        _ => &mut queue.used,
+    }
+}
+
+pub fn is_ring_address(offset: u64) -> bool {
+    offset >= 0x80 && offset < 0xb0 && offset & 0xf < 8
+}
+
+fn read_u16(guest_memory: &MemoryRegion, addr: u64) -> Option<u16> {
+    guest_memory.get(addr & !0x7).map(|v| (v >> (8 * (addr & 0x7))) as u16)
+}
+
+/// Count the requests the guest has made available on a queue since the device was last notified,
+/// and the number of bytes they cover. Returns (available index, requests, bytes).
+pub fn new_requests(guest_memory: &MemoryRegion, queue: &Queue) -> (u16, u64, u64) {
+    let avail = queue.avail;
+    let avail_idx = match read_u16(guest_memory, avail + 2) {
+        Some(idx) => idx,
+        None => return (queue.last_avail, 0, 0),
+    };
+
+    let requests = avail_idx.wrapping_sub(queue.last_avail) as u64;
+    let mut bytes = 0;
+    for i in 0..requests.min(queue.size) {
+        let slot = (queue.last_avail as u64 + i) % queue.size;
+        let mut desc = match read_u16(guest_memory, avail + 4 + slot * 2) {
+            Some(head) => head as u64,
+            None => break,
+        };
+        // Chains can't be longer than the queue, so that bounds the walk even if the guest has
+        // made a loop.
+        for _ in 0..queue.size {
+            let addr = queue.guest_pa + (desc % queue.size) * 16;
+            let (len, flags, next) = match guest_memory.get(addr + 8) {
+                Some(v) => (v & 0xffffffff, (v >> 32) as u16, (v >> 48) as u16),
+                None => break,
+            };
+            bytes += len;
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            desc = next as u64;
+        }
+    }
+    (avail_idx, requests, bytes)
+}
+
+/// Translate the addresses already in the descriptor table of a queue the guest has just set up
+/// into host physical addresses, in place. Those outside guest memory are left as they are.
+pub fn translate_descriptors(guest_memory: &mut MemoryRegion, guest_map: &GuestMap, queue: &Queue) {
+    for i in 0..queue.size {
+        let value = &mut guest_memory[queue.guest_pa + i * 16];
+        *value = guest_map.host_pa(*value).unwrap_or(*value);
+    }
+}
+
+/// What to store in a descriptor's address field when the guest writes `value` to it, or None if
+/// the address is outside guest memory. Zero is kept, since drivers clear descriptors they free.
+pub fn host_descriptor_address(guest_map: &GuestMap, value: u64) -> Option<u64> {
+    if value == 0 {
+        Some(0)
+    } else {
+        guest_map.host_pa(value)
+    }
+}
+
+/// What the guest reads back from a descriptor's address field holding `value`.
+pub fn guest_descriptor_address(guest_map: &GuestMap, value: u64) -> u64 {
+    guest_map.guest_pa(value).unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pmap::VM_RESERVATION_SIZE;
+
+    const BASE: u64 = 0x8000_0000;
+    const HOST_SEGMENT: u64 = 0x1_0000_0000;
+
+    fn memory(buffer: &mut Vec<u64>) -> MemoryRegion {
+        unsafe { MemoryRegion::with_base_address(buffer.as_mut_ptr() as u64, BASE, buffer.len() as u64 * 8) }
+    }
+
+    fn write_u16(guest_memory: &mut MemoryRegion, addr: u64, value: u16) {
+        guest_memory.slice_mut(addr, 2).copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_descriptor(guest_memory: &mut MemoryRegion, queue: &Queue, index: u64, addr: u64, len: u32,
+                      next: Option<u16>) {
+        let desc = queue.guest_pa + index * 16;
+        let flags = if next.is_some() { VIRTQ_DESC_F_NEXT } else { 0 };
+        guest_memory[desc] = addr;
+        guest_memory[desc + 8] = len as u64 | (flags as u64) << 32 | (next.unwrap_or(0) as u64) << 48;
+    }
+
+    /// Make `heads` available, starting at ring slot `first`.
+    fn make_available(guest_memory: &mut MemoryRegion, queue: &Queue, first: u16, heads: &[u16]) {
+        for (i, &head) in heads.iter().enumerate() {
+            let slot = (first as u64 + i as u64) % queue.size;
+            write_u16(guest_memory, queue.avail + 4 + slot * 2, head);
+        }
+        write_u16(guest_memory, queue.avail + 2, first.wrapping_add(heads.len() as u16));
+    }
+
+    fn legacy_queue(size: u64) -> Queue {
+        Queue { guest_pa: BASE, host_pa: HOST_SEGMENT, size, avail: BASE + size * 16, used: 0, last_avail: 0 }
+    }
+
+    #[test]
+    fn legacy_layout() {
+        assert_eq!(legacy_queue_size(8), 0x1000 + 6 + 8 * 8);
+        assert_eq!(legacy_queue_size(256), 0x2000 + 6 + 256 * 8);
+    }
+
+    #[test]
+    fn ring_address_registers() {
+        let mut queue = Queue::default();
+        *ring_address(&mut queue, 0x84) = 1;
+        *ring_address(&mut queue, 0x90) = 2;
+        *ring_address(&mut queue, 0xa4) = 3;
+        assert_eq!((queue.guest_pa, queue.avail, queue.used), (1, 2, 3));
+
+        for &offset in &[0x80, 0x84, 0x90, 0x94, 0xa0, 0xa4] {
+            assert!(is_ring_address(offset));
+        }
+        for &offset in &[0x7c, 0x88, 0x8c, 0x98, 0xa8, 0xb0] {
+            assert!(!is_ring_address(offset));
+        }
+    }
+
+    #[test]
+    fn count_new_requests() {
+        let mut buffer = vec![0u64; 512];
+        let mut guest_memory = memory(&mut buffer);
+        let mut queue = legacy_queue(8);
+        set_descriptor(&mut guest_memory, &queue, 0, BASE + 0x800, 100, Some(1));
+        set_descriptor(&mut guest_memory, &queue, 1, BASE + 0x900, 200, None);
+        set_descriptor(&mut guest_memory, &queue, 2, BASE + 0xa00, 50, None);
+        make_available(&mut guest_memory, &queue, 0, &[0, 2]);
+
+        assert_eq!(new_requests(&guest_memory, &queue), (2, 2, 350));
+        queue.last_avail = 1;
+        assert_eq!(new_requests(&guest_memory, &queue), (2, 1, 50));
+        queue.last_avail = 2;
+        assert_eq!(new_requests(&guest_memory, &queue), (2, 0, 0));
+    }
+
+    #[test]
+    fn available_index_wraps() {
+        let mut buffer = vec![0u64; 512];
+        let mut guest_memory = memory(&mut buffer);
+        let mut queue = legacy_queue(8);
+        queue.last_avail = 0xffff;
+        set_descriptor(&mut guest_memory, &queue, 3, BASE + 0x800, 10, None);
+        set_descriptor(&mut guest_memory, &queue, 4, BASE + 0x900, 20, None);
+        make_available(&mut guest_memory, &queue, 0xffff, &[3, 4]);
+
+        assert_eq!(new_requests(&guest_memory, &queue), (1, 2, 30));
+    }
+
+    #[test]
+    fn looped_chain_is_bounded() {
+        let mut buffer = vec![0u64; 512];
+        let mut guest_memory = memory(&mut buffer);
+        let queue = legacy_queue(8);
+        set_descriptor(&mut guest_memory, &queue, 0, BASE + 0x800, 10, Some(0));
+        make_available(&mut guest_memory, &queue, 0, &[0]);
+
+        assert_eq!(new_requests(&guest_memory, &queue), (1, 1, 80));
+    }
+
+    #[test]
+    fn ring_outside_guest_memory() {
+        let mut buffer = vec![0u64; 512];
+        let guest_memory = memory(&mut buffer);
+        let mut queue = legacy_queue(8);
+        queue.avail = BASE + 0x1000;
+        queue.last_avail = 7;
+
+        assert_eq!(new_requests(&guest_memory, &queue), (7, 0, 0));
+    }
+
+    #[test]
+    fn descriptor_addresses() {
+        let mut buffer = vec![0u64; 512];
+        let mut guest_memory = memory(&mut buffer);
+        let guest_map = GuestMap::new(BASE, HOST_SEGMENT, &[]);
+        let queue = legacy_queue(4);
+        let host = HOST_SEGMENT + VM_RESERVATION_SIZE;
+        set_descriptor(&mut guest_memory, &queue, 0, BASE + 0x800, 1, None);
+        set_descriptor(&mut guest_memory, &queue, 1, 0x1000, 1, None);
+        set_descriptor(&mut guest_memory, &queue, 3, BASE + 0x1234, 1, None);
+
+        translate_descriptors(&mut guest_memory, &guest_map, &queue);
+        assert_eq!(guest_memory[BASE], host + 0x800);
+        assert_eq!(guest_memory[BASE + 16], 0x1000);
+        assert_eq!(guest_memory[BASE + 32], 0);
+        assert_eq!(guest_memory[BASE + 48], host + 0x1234);
+        assert_eq!(guest_memory[BASE + 8], 1);
+
+        assert_eq!(host_descriptor_address(&guest_map, 0), Some(0));
+        assert_eq!(host_descriptor_address(&guest_map, BASE + 0x10), Some(host + 0x10));
+        assert_eq!(host_descriptor_address(&guest_map, 0x1000), None);
+        assert_eq!(guest_descriptor_address(&guest_map, host + 0x10), BASE + 0x10);
+        assert_eq!(guest_descriptor_address(&guest_map, 0x1000), 0x1000);
+    }
+
+    #[test]
+    fn descriptor_address_fields() {
+        let mut queue = legacy_queue(4);
+        assert!(queue.holds_descriptor_address(BASE));
+        assert!(queue.holds_descriptor_address(BASE + 48 + 4));
+        assert!(!queue.holds_descriptor_address(BASE + 8));
+        assert!(!queue.holds_descriptor_address(BASE + 64));
+        assert!(!queue.holds_descriptor_address(BASE - 16));
+
+        queue.host_pa = 0;
+        assert!(!queue.holds_descriptor_address(BASE));
+    }
+}
@@ -0,0 +1,242 @@
+//! Write watches on guest memory, for finding out which guest code corrupts a structure.
+//!
+//! A watch covers a range of guest physical memory. Shadow mappings of the pages it touches are
+//! kept read-only, so that every store to them traps, and the hypervisor then makes the store
+//! itself. Stores that land in a watched range are logged with the value written and the guest's
+//! pc at the time; those elsewhere on the same pages are made without being logged.
+//!
+//! Watches are set with the monitor's `watch` command, which also prints the log, or by the guest
+//! itself with the `watch_add(pa, len)`, `watch_remove(index)` and `watch_read(sequence, addr)`
+//! functions of the RVirt SBI extension. They last until the guest restarts.
+//!
+//! Only stores made through the shadow page tables are seen, so not those the guest makes with
+//! paging off, nor those of devices. Stores the hypervisor can't make for the guest, such as
+//! floating point ones, are logged without a value and leave the page writable until its shadow
+//! mapping goes away.
+
+use byteorder::{ByteOrder, LittleEndian};
+use riscv_decode::Instruction;
+use crate::context::Context;
+use crate::pmap;
+use crate::riscv;
+use crate::sbi::{SBI_ERR_FAILED, SBI_ERR_INVALID_ADDRESS, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED,
+                 SBI_SUCCESS, RVIRT_WATCH_ADD, RVIRT_WATCH_READ, RVIRT_WATCH_REMOVE};
+
+pub const MAX_WATCHES: usize = 4;
+/// Stores remembered, beyond which the oldest are forgotten.
+const LOG_SIZE: usize = 32;
+/// Size of a store as copied to the guest by `watch_read`.
+const HIT_SIZE: u64 = 32;
+
+#[derive(Copy, Clone)]
+pub struct Hit {
+    pub guest_pa: u64,
+    pub value: u64,
+    /// Bytes written, or zero if the store couldn't be decoded and the value is unknown.
+    pub width: u64,
+    pub sepc: u64,
+}
+
+const EMPTY_HIT: Hit = Hit { guest_pa: 0, value: 0, width: 0, sepc: 0 };
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddError {
+    EmptyRange,
+    NotGuestMemory,
+    NoFreeWatch,
+}
+
+pub struct Watches {
+    /// Start and length of each watched range.
+    ranges: [Option<(u64, u64)>; MAX_WATCHES],
+    log: [Hit; LOG_SIZE],
+    /// Stores ever logged, of which the last `LOG_SIZE` are in `log`.
+    hits: u64,
+}
+
+impl Watches {
+    pub fn new() -> Self {
+        Self { ranges: [None; MAX_WATCHES], log: [EMPTY_HIT; LOG_SIZE], hits: 0 }
+    }
+
+    fn overlaps(&self, start: u64, len: u64) -> bool {
+        self.ranges.iter().filter_map(|r| *r).any(|(s, l)| start < s + l && s < start + len)
+    }
+
+    /// Whether the page holding `guest_pa` has any watched bytes, and so mustn't be mapped writable.
+    #[inline(always)]
+    pub fn covers_page(&self, guest_pa: u64) -> bool {
+        self.ranges.iter().any(Option::is_some) && self.overlaps(guest_pa & !0xfff, 0x1000)
+    }
+
+    fn record(&mut self, hit: Hit) {
+        self.log[(self.hits % LOG_SIZE as u64) as usize] = hit;
+        self.hits += 1;
+    }
+
+    /// The store numbered `sequence`, counting from zero, if it is still in the log.
+    fn hit(&self, sequence: u64) -> Option<Hit> {
+        if sequence >= self.hits || self.hits - sequence > LOG_SIZE as u64 {
+            return None;
+        }
+        Some(self.log[(sequence % LOG_SIZE as u64) as usize])
+    }
+}
+
+/// Start watching `len` bytes of guest memory at `guest_pa`. Returns the index of the watch.
+pub fn add(state: &mut Context, guest_pa: u64, len: u64) -> Result<usize, AddError> {
+    if len == 0 {
+        return Err(AddError::EmptyRange);
+    }
+    match guest_pa.checked_add(len - 1) {
+        Some(end) if state.guest_memory.in_region(guest_pa) && state.guest_memory.in_region(end) => {}
+        _ => return Err(AddError::NotGuestMemory),
+    }
+    let index = state.watches.ranges.iter().position(Option::is_none).ok_or(AddError::NoFreeWatch)?;
+    state.watches.ranges[index] = Some((guest_pa, len));
+
+    // Any writable mapping of the pages has to go.
+    pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+    riscv::sfence_vma();
+    Ok(index)
+}
+
+/// Stop watching with watch `index`. The pages become writable again as they fault back in.
+pub fn remove(state: &mut Context, index: usize) -> bool {
+    match state.watches.ranges.get_mut(index) {
+        Some(range) if range.is_some() => {
+            *range = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Make a store to `guest_pa`, on a page with watched bytes, for the guest, and log it if it hits
+/// a watched range. Returns false if the store couldn't be made, in which case the caller maps the
+/// page writable so that the guest can make it itself.
+pub fn emulate_write(state: &mut Context, guest_pa: u64, instruction: Option<u32>) -> bool {
+    let sepc = csrr!(sepc);
+    let decoded = instruction.and_then(|i| riscv_decode::decode(i).ok());
+    let (width, rs2, rd) = match decoded {
+        Some(Instruction::Sb(i)) => (1, i.rs2(), None),
+        Some(Instruction::Sh(i)) => (2, i.rs2(), None),
+        Some(Instruction::Sw(i)) => (4, i.rs2(), None),
+        Some(Instruction::Sd(i)) => (8, i.rs2(), None),
+        Some(Instruction::ScW(i)) | Some(Instruction::AmoswapW(i)) | Some(Instruction::AmoaddW(i))
+        | Some(Instruction::AmoandW(i)) | Some(Instruction::AmoorW(i)) | Some(Instruction::AmoxorW(i)) => {
+            (4, i.rs2(), Some(i.rd()))
+        }
+        Some(Instruction::ScD(i)) | Some(Instruction::AmoswapD(i)) | Some(Instruction::AmoaddD(i))
+        | Some(Instruction::AmoandD(i)) | Some(Instruction::AmoorD(i)) | Some(Instruction::AmoxorD(i)) => {
+            (8, i.rs2(), Some(i.rd()))
+        }
+        _ => (0, 0, None),
+    };
+    if width == 0 || guest_pa % width != 0 || !state.prepare_guest_access(guest_pa, width, true) {
+        if state.watches.overlaps(guest_pa, width.max(1)) {
+            state.watches.record(Hit { guest_pa, value: 0, width: 0, sepc });
+        }
+        return false;
+    }
+
+    let word = guest_pa & !0x7;
+    let offset = (guest_pa % 8) as usize;
+    let mut bytes = state.guest_memory[word].to_le_bytes();
+    let old = match width {
+        8 => LittleEndian::read_u64(&bytes),
+        4 => LittleEndian::read_u32(&bytes[offset..]) as u64,
+        _ => 0,
+    };
+    let operand = state.saved_registers.get(rs2);
+    let value = match decoded {
+        Some(Instruction::AmoaddW(_)) | Some(Instruction::AmoaddD(_)) => old.wrapping_add(operand),
+        Some(Instruction::AmoandW(_)) | Some(Instruction::AmoandD(_)) => old & operand,
+        Some(Instruction::AmoorW(_)) | Some(Instruction::AmoorD(_)) => old | operand,
+        Some(Instruction::AmoxorW(_)) | Some(Instruction::AmoxorD(_)) => old ^ operand,
+        _ => operand,
+    };
+    bytes[offset..offset + width as usize].copy_from_slice(&value.to_le_bytes()[..width as usize]);
+    state.guest_memory[word] = u64::from_le_bytes(bytes);
+
+    // Guests have a single hart, so nothing can have broken the reservation of a store
+    // conditional, which always succeeds.
+    if let Some(rd) = rd {
+        let result = match decoded {
+            Some(Instruction::ScW(_)) | Some(Instruction::ScD(_)) => 0,
+            _ if width == 4 => old as u32 as i32 as i64 as u64,
+            _ => old,
+        };
+        state.saved_registers.set(rd, result);
+    }
+
+    // Shadow mappings derived from a guest page table on this page may now be stale.
+    if state.shadow_page_tables.sync.tracks(guest_pa) {
+        pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+    }
+
+    if state.watches.overlaps(guest_pa, width) {
+        let mask = if width == 8 { u64::max_value() } else { (1 << (8 * width)) - 1 };
+        state.watches.record(Hit { guest_pa, value: value & mask, width, sepc });
+    }
+    riscv::set_sepc(sepc + riscv_decode::instruction_length(instruction.unwrap() as u16) as u64);
+    true
+}
+
+/// Handle a call to one of the watch functions of the RVirt SBI extension, returning (error,
+/// value). A guest can only watch its own memory, so any guest may use them.
+pub fn handle_call(state: &mut Context, function: u64) -> (i64, u64) {
+    let (a0, a1) = (state.saved_registers.get(10), state.saved_registers.get(11));
+    match function {
+        // watch_add(pa, len): start watching, returning the index of the watch.
+        RVIRT_WATCH_ADD => match add(state, a0, a1) {
+            Ok(index) => (SBI_SUCCESS, index as u64),
+            Err(AddError::EmptyRange) => (SBI_ERR_INVALID_PARAM, 0),
+            Err(AddError::NotGuestMemory) => (SBI_ERR_INVALID_ADDRESS, 0),
+            Err(AddError::NoFreeWatch) => (SBI_ERR_FAILED, 0),
+        },
+        RVIRT_WATCH_REMOVE => match remove(state, a0 as usize) {
+            true => (SBI_SUCCESS, 0),
+            false => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        // watch_read(sequence, addr): copy store number `sequence` into guest memory as its
+        // address, value, width and pc, returning how many stores have been logged.
+        RVIRT_WATCH_READ => {
+            let hit = match state.watches.hit(a0) {
+                Some(hit) => hit,
+                None => return (SBI_ERR_INVALID_PARAM, state.watches.hits),
+            };
+            if !state.prepare_guest_access(a1, HIT_SIZE, true) {
+                return (SBI_ERR_INVALID_ADDRESS, 0);
+            }
+            let dst = state.guest_memory.slice_mut(a1, HIT_SIZE);
+            for (i, value) in [hit.guest_pa, hit.value, hit.width, hit.sepc].iter().enumerate() {
+                dst[i * 8..i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+            }
+            (SBI_SUCCESS, state.watches.hits)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// Print the watches and the stores logged, for the monitor's `watch` command.
+pub fn report(state: &Context) {
+    let watches = &state.watches;
+    for (index, range) in watches.ranges.iter().enumerate() {
+        if let Some((start, len)) = *range {
+            println!("watch {}: {:#x}..{:#x}", index, start, start + len);
+        }
+    }
+    let first = watches.hits.saturating_sub(LOG_SIZE as u64);
+    if first > 0 {
+        println!("({} earlier stores forgotten)", first);
+    }
+    for sequence in first..watches.hits {
+        let hit = watches.hit(sequence).unwrap();
+        let pc = state.symbols.symbolize(hit.sepc);
+        match hit.width {
+            0 => println!("{:#x} <- ? at {}", hit.guest_pa, pc),
+            width => println!("{:#x} <- {:#0w$x} at {}", hit.guest_pa, hit.value, pc, w = 2 + 2 * width as usize),
+        }
+    }
+}